@@ -1,10 +1,14 @@
+use crate::utils::{parse_duration, parse_tag};
+
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{ArgGroup, Parser, ValueEnum};
 use is_terminal::IsTerminal;
-use std::io::{stdin, Read};
+use std::io::{stdin, BufRead, Read};
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(group(ArgGroup::new("force_target").args(["refresh_models", "rebuild_rag"]).multiple(true)))]
 pub struct Cli {
     /// Select a LLM model
     #[clap(short, long)]
@@ -24,18 +28,48 @@ pub struct Cli {
     /// Ensure the new conversation is saved to the session
     #[clap(long)]
     pub save_session: bool,
+    /// Open the session for reading only
+    #[clap(long, requires = "session", conflicts_with_all = ["empty_session", "save_session"])]
+    pub read_only: bool,
+    /// Create a session from a conversation template
+    #[clap(long, value_name = "TEMPLATE", conflicts_with_all = ["role", "prompt", "agent"])]
+    pub new_from_template: Option<String>,
     /// Start a agent
     #[clap(short = 'a', long)]
     pub agent: Option<String>,
     /// Set agent variables
     #[clap(long, value_names = ["NAME", "VALUE"], num_args = 2)]
     pub agent_variable: Vec<String>,
-    /// Start a RAG
+    /// Start a RAG, optionally with search settings for this run, e.g. docs:top_k=12:rerank=off
     #[clap(long)]
     pub rag: Option<String>,
-    /// Rebuild the RAG to sync document changes
+    /// Only retrieve RAG documents matching a filter, e.g. `path:docs/api/** ext:md`
+    #[clap(long, value_name = "FILTER")]
+    pub rag_filter: Option<String>,
+    /// Rebuild the RAG to sync document changes, skipping local files that haven't changed
     #[clap(long)]
     pub rebuild_rag: bool,
+    /// Score the RAG on a YAML file of questions and expected sources, reporting recall@k
+    #[clap(long, value_name = "PATH", requires = "rag")]
+    pub eval: Option<String>,
+    /// Write the RAG, with its chunks and vectors, to a .ragpack file to share
+    #[clap(long, value_name = "PATH", requires = "rag")]
+    pub export: Option<String>,
+    /// Import a .ragpack file as a RAG, named after the original or --rag
+    #[clap(long, value_name = "PATH", conflicts_with = "export")]
+    pub import: Option<String>,
+    /// Watch the local documents of the RAG and rebuild it whenever they change
+    #[clap(long, requires = "rag")]
+    pub watch: bool,
+    /// Show embeddings cache statistics
+    #[clap(long)]
+    pub embeddings_cache_stats: bool,
+    /// Remove cached embeddings not used by any saved RAG
+    #[clap(long)]
+    pub prune_embeddings_cache: bool,
+    /// Remove attachment blobs not used by any saved session
+    #[clap(long)]
+    pub prune_blobs: bool,
     /// Execute a macro
     #[clap(long = "macro", value_name = "MACRO")]
     pub macro_name: Option<String>,
@@ -45,15 +79,29 @@ pub struct Cli {
     /// Execute commands in natural language
     #[clap(short = 'e', long)]
     pub execute: bool,
-    /// Yolo mode - execute commands without confirmation. Use -y for safe mode (blocks root, warns on sudo), -yy for root with warning, -yyy for full yolo
+    /// Try the command of -e in a throwaway container first and show what it would change
+    #[clap(long, requires = "execute", conflicts_with = "yolo")]
+    pub verify: bool,
+    /// Edit files with search/replace blocks from the model, previewing them before they're applied
+    #[clap(long)]
+    pub edit_files: bool,
+    /// Yolo mode - execute commands or apply file edits without confirmation. Use -y for safe mode (blocks root, warns on sudo), -yy for root with warning, -yyy for full yolo
     #[clap(short = 'y', action = clap::ArgAction::Count)]
     pub yolo: u8,
     /// Use distrobox/docker/podman mode
     #[clap(short = 'd', long)]
     pub distrobox: bool,
-    /// Output code only
-    #[clap(short = 'c', long)]
-    pub code: bool,
+    /// Output code only; pick blocks by language, position or `all` after an `=`, e.g. --code=rust
+    /// or -c=1 (in `--code rust`, `rust` is part of the prompt)
+    #[clap(
+        short = 'c',
+        long,
+        value_name = "SELECT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = ""
+    )]
+    pub code: Option<String>,
     /// Convert output to JSON format
     #[clap(long)]
     pub json: bool,
@@ -63,27 +111,84 @@ pub struct Cli {
     /// Output plain text without markdown rendering
     #[clap(long)]
     pub plain: bool,
-    /// Include files, directories, or URLs
+    /// Request token log probabilities, optionally with the top N alternatives
+    #[clap(long, value_name = "TOP_N", num_args = 0..=1, default_missing_value = "0")]
+    pub logprobs: Option<usize>,
+    /// Set a seed for deterministic sampling
+    #[clap(long)]
+    pub seed: Option<u64>,
+    /// Ground Gemini answers with Google Search
+    #[clap(long)]
+    pub google_search: bool,
+    /// Include files, directories, URLs, or command output (`cmd:<command>`)
     #[clap(short = 'f', long, value_name = "FILE")]
     pub file: Vec<String>,
+    /// How to use piped stdin: `auto` attaches it as data when TEXT is given, `text` appends it
+    /// to the prompt, `file` always attaches it, `ignore` leaves stdin unread
+    #[clap(long, value_name = "MODE", default_value = "auto")]
+    pub stdin_as: StdinMode,
+    /// Read the prompt from stdin line by line until a line equal to MARKER, keeping blank lines;
+    /// works when stdin is a terminal too
+    #[clap(long, value_name = "MARKER")]
+    pub input_terminator: Option<String>,
     /// Turn off stream mode
     #[clap(short = 'S', long)]
     pub no_stream: bool,
+    /// Give up on each request after this many seconds, 0 to wait indefinitely
+    #[clap(long, value_name = "SECS")]
+    pub timeout: Option<u64>,
+    /// Stop generating after this long in total, tool calls included, and keep the partial output, e.g. 30s or 2m
+    #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub max_time: Option<Duration>,
+    /// When --max-time cuts the reply off, ask the model for a one-sentence wrap-up
+    #[clap(long, requires = "max_time")]
+    pub wrap_up: bool,
+    /// Write a report of the run's steps, tool calls, tokens and cost (markdown if *.md, else JSON)
+    #[clap(long, value_name = "PATH")]
+    pub report: Option<String>,
+    /// Label the run's calls in the audit log and report, e.g. --tag project=billing (repeatable)
+    #[clap(long, value_name = "KEY=VALUE", value_parser = parse_tag)]
+    pub tag: Vec<(String, String)>,
+    /// Also send the reply to a sink named under `output_sinks` (repeatable)
+    #[clap(long, value_name = "NAME")]
+    pub sink: Vec<String>,
+    /// Send all requests through a proxy, or '-' to bypass proxies
+    #[clap(long, value_name = "URL")]
+    pub proxy: Option<String>,
     /// Display the message without sending it
     #[clap(long)]
     pub dry_run: bool,
+    /// Record the provider calls of this run, with secrets redacted, to a cassette file
+    #[clap(long, value_name = "PATH", conflicts_with = "replay_cassette")]
+    pub record_cassette: Option<String>,
+    /// Answer provider calls from a recorded cassette file instead of the network
+    #[clap(long, value_name = "PATH")]
+    pub replay_cassette: Option<String>,
     /// Hide thinking content from output
     #[clap(long)]
     pub hide_thinking: bool,
+    /// Run the REPL full-screen, with a scrollable conversation pane
+    #[clap(long)]
+    pub tui: bool,
+    /// Show time-to-first-token, total latency and tokens/s after streamed replies
+    #[clap(long)]
+    pub stats: bool,
     /// Display information
     #[clap(long)]
     pub info: bool,
-    /// Sync models updates
-    #[clap(long)]
-    pub sync_models: bool,
+    /// Sync models from a URL or file (models.yaml or models.dev schema), default `sync_models_url`
+    #[clap(long, value_name = "SOURCE")]
+    pub sync_models: Option<Option<String>>,
     /// Refresh model lists for configured clients
     #[clap(long)]
     pub refresh_models: bool,
+    /// Use the cached models.dev data or the bundled models, never download them
+    #[clap(long)]
+    pub offline_models: bool,
+    /// With --refresh-models, also re-download the cached models.dev data; with --rebuild-rag,
+    /// reload unchanged files too
+    #[clap(long, requires = "force_target")]
+    pub force: bool,
     /// List all available chat models
     #[clap(long)]
     pub list_models: bool,
@@ -102,25 +207,39 @@ pub struct Cli {
     /// List all macros
     #[clap(long)]
     pub list_macros: bool,
+    /// Run as a git hook (prepare-commit-msg or commit-msg), passing on the hook's arguments
+    #[clap(long, value_name = "HOOK")]
+    pub hook: Option<String>,
+    /// List all conversation templates
+    #[clap(long)]
+    pub list_templates: bool,
     /// Input text
     #[clap(trailing_var_arg = true)]
     text: Vec<String>,
 }
 
 impl Cli {
+    /// The arguments git passed to the hook, without touching stdin.
+    pub fn hook_args(&self) -> &[String] {
+        &self.text
+    }
+
     pub fn text(&self) -> Result<Option<String>> {
         let mut stdin_text = String::new();
-        if !stdin().is_terminal() {
+        if let Some(marker) = &self.input_terminator {
+            stdin_text = read_until_terminator(stdin().lock(), marker)?;
+        } else if self.stdin_as != StdinMode::Ignore && !stdin().is_terminal() {
             let _ = stdin()
                 .read_to_string(&mut stdin_text)
                 .context("Invalid stdin pipe")?;
         };
         match self.text.is_empty() {
             true => {
-                if stdin_text.is_empty() {
+                let text = self.stdin_as.combine("", &stdin_text);
+                if text.is_empty() {
                     Ok(None)
                 } else {
-                    Ok(Some(stdin_text))
+                    Ok(Some(text))
                 }
             }
             false => {
@@ -137,14 +256,103 @@ impl Cli {
                         Ok(Some(format!("{text} -- {stdin_text}")))
                     }
                 } else {
-                    let text = self.text.join(" ");
-                    if stdin_text.is_empty() {
-                        Ok(Some(text))
-                    } else {
-                        Ok(Some(format!("{text}\n{stdin_text}")))
-                    }
+                    Ok(Some(
+                        self.stdin_as.combine(&self.text.join(" "), &stdin_text),
+                    ))
                 }
             }
         }
     }
 }
+
+/// Reads lines up to the one equal to `marker`, or to the end of input, and leaves the rest
+/// unread. The marker line and the final line break are dropped, blank lines are kept.
+fn read_until_terminator(reader: impl BufRead, marker: &str) -> Result<String> {
+    let mut lines = vec![];
+    for line in reader.lines() {
+        let line = line.context("Invalid stdin pipe")?;
+        let line = line.strip_suffix('\r').unwrap_or(&line);
+        if line == marker {
+            break;
+        }
+        lines.push(line.to_string());
+    }
+    Ok(lines.join("\n"))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum StdinMode {
+    #[default]
+    Auto,
+    Text,
+    File,
+    Ignore,
+}
+
+impl StdinMode {
+    /// Joins the TEXT argument with piped stdin. Attached stdin goes in a labelled block after
+    /// the instruction so the model can tell the data from what to do with it.
+    fn combine(self, text: &str, stdin_text: &str) -> String {
+        if stdin_text.is_empty() || self == StdinMode::Ignore {
+            return text.to_string();
+        }
+        if text.is_empty() && self != StdinMode::File {
+            return stdin_text.to_string();
+        }
+        match self {
+            StdinMode::Text => format!("{text}\n{stdin_text}"),
+            _ => {
+                let block = format!("============ STDIN ============\n{}", stdin_text.trim_end());
+                if text.is_empty() {
+                    block
+                } else {
+                    format!("{text}\n\n{block}")
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stdin_combine() {
+        let stdin_text = "error: linker failed\n";
+        assert_eq!(
+            StdinMode::Auto.combine("explain this", stdin_text),
+            "explain this\n\n============ STDIN ============\nerror: linker failed"
+        );
+        assert_eq!(
+            StdinMode::Text.combine("explain this", stdin_text),
+            "explain this\nerror: linker failed\n"
+        );
+        assert_eq!(StdinMode::Auto.combine("", stdin_text), stdin_text);
+        assert_eq!(
+            StdinMode::File.combine("", stdin_text),
+            "============ STDIN ============\nerror: linker failed"
+        );
+        assert_eq!(
+            StdinMode::Ignore.combine("explain this", stdin_text),
+            "explain this"
+        );
+    }
+
+    #[test]
+    fn test_read_until_terminator() {
+        let input = "Summarize:\r\n\n  - a\n\nEOF\nignored\n";
+        assert_eq!(
+            read_until_terminator(input.as_bytes(), "EOF").unwrap(),
+            "Summarize:\n\n  - a\n"
+        );
+        assert_eq!(
+            read_until_terminator("one\n\ntwo\n".as_bytes(), "EOF").unwrap(),
+            "one\n\ntwo"
+        );
+        assert_eq!(
+            read_until_terminator("EOF\n".as_bytes(), "EOF").unwrap(),
+            ""
+        );
+    }
+}
@@ -0,0 +1,301 @@
+use super::{ChatCompletionsOutput, Model, RequestData};
+
+use crate::config::GlobalConfig;
+use crate::utils::{now, resolve_home_dir};
+
+use anyhow::{Context, Result};
+use reqwest::RequestBuilder;
+use serde_json::{json, Map, Value};
+use std::cell::RefCell;
+use std::future::Future;
+use std::io::Write;
+use std::time::Instant;
+
+tokio::task_local! {
+    static AUDIT_REQUEST: RefCell<Option<RequestData>>;
+}
+
+const SECRET_KEYWORDS: [&str; 6] = ["auth", "key", "token", "secret", "password", "signature"];
+
+/// One line of the `audit_log` JSONL file, describing a single API call. Chat calls also
+/// become steps of the `--report` run report.
+#[derive(Debug)]
+pub struct AuditEntry {
+    enabled: bool,
+    api: &'static str,
+    stream: bool,
+    started: Instant,
+    latency_ms: u128,
+    request: Option<RequestData>,
+    response: Value,
+    error: Option<String>,
+    input_tokens: Option<u64>,
+    output_tokens: Option<u64>,
+    tokens_estimated: bool,
+}
+
+impl AuditEntry {
+    pub fn new(config: &GlobalConfig, api: &'static str, stream: bool) -> Self {
+        Self {
+            enabled: {
+                let config = config.read();
+                config.audit_log.is_some() || config.run_report.is_some()
+            },
+            api,
+            stream,
+            started: Instant::now(),
+            latency_ms: 0,
+            request: None,
+            response: Value::Null,
+            error: None,
+            input_tokens: None,
+            output_tokens: None,
+            tokens_estimated: false,
+        }
+    }
+
+    /// Runs the call, remembering the request it sends and how long it took.
+    pub async fn capture<F: Future>(&mut self, fut: F) -> F::Output {
+        if !self.enabled {
+            return fut.await;
+        }
+        self.started = Instant::now();
+        let (ret, request) = AUDIT_REQUEST
+            .scope(RefCell::new(None), async {
+                let ret = fut.await;
+                (ret, AUDIT_REQUEST.with(|v| v.take()))
+            })
+            .await;
+        self.latency_ms = self.started.elapsed().as_millis();
+        self.request = request;
+        ret
+    }
+
+    pub fn set_response(&mut self, response: Value) {
+        self.response = response;
+    }
+
+    pub fn set_error(&mut self, err: &anyhow::Error) {
+        self.error = Some(format!("{err:#}"));
+    }
+
+    pub fn set_tokens(&mut self, input: Option<u64>, output: Option<u64>, estimated: bool) {
+        self.input_tokens = input;
+        self.output_tokens = output;
+        self.tokens_estimated = estimated;
+    }
+
+    pub fn set_chat_output(&mut self, ret: &Result<ChatCompletionsOutput>) {
+        match ret {
+            Ok(output) => {
+                self.set_response(json!({
+                    "text": output.text,
+                    "tool_calls": output.tool_calls,
+                }));
+                self.set_tokens(output.input_tokens, output.output_tokens, false);
+            }
+            Err(err) => self.set_error(err),
+        }
+    }
+
+    pub fn write(self, config: &GlobalConfig, model: &Model) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let cost = estimate_cost(model, self.input_tokens, self.output_tokens);
+        if self.api == "chat_completions" {
+            if let Some(report) = config.write().run_report.as_mut() {
+                report.add_step(
+                    model,
+                    self.latency_ms,
+                    self.input_tokens,
+                    self.output_tokens,
+                    self.tokens_estimated,
+                    cost,
+                    self.error.clone(),
+                );
+            }
+        }
+        let (path, max_body, tags) = {
+            let config = config.read();
+            match config.audit_log.clone() {
+                Some(path) => (
+                    resolve_home_dir(&path),
+                    config.audit_log_max_body,
+                    config.tags.clone(),
+                ),
+                None => return Ok(()),
+            }
+        };
+        let truncate = |value: Value| match max_body {
+            Some(max_body) => truncate_value(value, max_body),
+            None => value,
+        };
+        let (url, headers, body) = match self.request {
+            Some(RequestData { url, headers, body }) => {
+                let headers: Map<String, Value> = headers
+                    .into_iter()
+                    .map(|(k, v)| {
+                        let v = if is_secret(&k) { "***".into() } else { v };
+                        (k, v.into())
+                    })
+                    .collect();
+                (redact_url(&url).into(), headers.into(), truncate(body))
+            }
+            None => (Value::Null, Value::Null, Value::Null),
+        };
+        let entry = json!({
+            "timestamp": now(),
+            "api": self.api,
+            "client": model.client_name(),
+            "model": model.name(),
+            "stream": self.stream,
+            "latency_ms": self.latency_ms,
+            "url": url,
+            "headers": headers,
+            "request": body,
+            "response": truncate(self.response),
+            "error": self.error,
+            "input_tokens": self.input_tokens,
+            "output_tokens": self.output_tokens,
+            "tokens_estimated": self.tokens_estimated,
+            "cost": cost,
+            "tags": tags,
+        });
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open audit log at '{path}'"))?;
+        file.write_all(format!("{entry}\n").as_bytes())
+            .with_context(|| format!("Failed to write audit log at '{path}'"))?;
+        Ok(())
+    }
+}
+
+/// The price of a call from the model's per-million-token prices, when all are known.
+fn estimate_cost(
+    model: &Model,
+    input_tokens: Option<u64>,
+    output_tokens: Option<u64>,
+) -> Option<f64> {
+    let data = model.data();
+    match (
+        data.input_price,
+        data.output_price,
+        input_tokens,
+        output_tokens,
+    ) {
+        (Some(input_price), Some(output_price), Some(input_tokens), Some(output_tokens)) => Some(
+            (input_tokens as f64 * input_price + output_tokens as f64 * output_price) / 1_000_000.0,
+        ),
+        _ => None,
+    }
+}
+
+/// Hands the request about to be sent to the `AuditEntry::capture` running this call, if any.
+/// It is read from the final builder, so signed and patched requests are logged as sent.
+pub fn record_audit_request(builder: &RequestBuilder) {
+    if AUDIT_REQUEST.try_with(|_| ()).is_err() {
+        return;
+    }
+    let Some(request) = builder.try_clone().and_then(|v| v.build().ok()) else {
+        return;
+    };
+    let headers = request
+        .headers()
+        .iter()
+        .map(|(k, v)| (k.to_string(), String::from_utf8_lossy(v.as_bytes()).into()))
+        .collect();
+    let body = match request.body().and_then(|v| v.as_bytes()) {
+        Some(bytes) => serde_json::from_slice(bytes)
+            .unwrap_or_else(|_| String::from_utf8_lossy(bytes).into_owned().into()),
+        None => Value::Null,
+    };
+    let request_data = RequestData {
+        url: request.url().to_string(),
+        headers,
+        body,
+    };
+    let _ = AUDIT_REQUEST.try_with(|v| *v.borrow_mut() = Some(request_data));
+}
+
+pub(super) fn is_secret(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    SECRET_KEYWORDS.iter().any(|v| name.contains(v))
+}
+
+fn redact_url(url: &str) -> String {
+    let Some((base, query)) = url.split_once('?') else {
+        return url.to_string();
+    };
+    let query: Vec<String> = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if is_secret(name) => format!("{name}=***"),
+            _ => pair.to_string(),
+        })
+        .collect();
+    format!("{base}?{}", query.join("&"))
+}
+
+/// Cuts long strings inside `value` down to `max` characters.
+fn truncate_value(value: Value, max: usize) -> Value {
+    match value {
+        Value::String(text) if text.chars().count() > max => {
+            let kept: String = text.chars().take(max).collect();
+            let dropped = text.chars().count() - max;
+            format!("{kept}...[{dropped} chars truncated]").into()
+        }
+        Value::Array(list) => list.into_iter().map(|v| truncate_value(v, max)).collect(),
+        Value::Object(map) => map
+            .into_iter()
+            .map(|(k, v)| (k, truncate_value(v, max)))
+            .collect::<Map<_, _>>()
+            .into(),
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_and_truncate() {
+        assert_eq!(
+            redact_url("https://x.ai/v1/models:generate?alt=sse&key=abc"),
+            "https://x.ai/v1/models:generate?alt=sse&key=***"
+        );
+        assert!(is_secret("Authorization"));
+        assert!(is_secret("x-api-key"));
+        assert!(!is_secret("content-type"));
+        let value = truncate_value(json!({"messages": [{"content": "abcdef"}], "n": 1}), 3);
+        assert_eq!(
+            value,
+            json!({"messages": [{"content": "abc...[3 chars truncated]"}], "n": 1})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_audit_request() {
+        let builder = reqwest::Client::new()
+            .post("https://bedrock-runtime.us-east-1.amazonaws.com/model/m/converse")
+            .header("authorization", "AWS4-HMAC-SHA256 Signature=abc")
+            .body(r#"{"messages":[]}"#);
+        record_audit_request(&builder);
+        let request = AUDIT_REQUEST
+            .scope(RefCell::new(None), async {
+                record_audit_request(&builder);
+                AUDIT_REQUEST.with(|v| v.take())
+            })
+            .await
+            .unwrap();
+        assert!(request.url.ends_with("/model/m/converse"));
+        assert_eq!(
+            request.headers["authorization"],
+            "AWS4-HMAC-SHA256 Signature=abc"
+        );
+        assert_eq!(request.body, json!({"messages": []}));
+    }
+}
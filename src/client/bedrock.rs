@@ -38,7 +38,7 @@ impl BedrockClient {
         ("region", "AWS Region", None),
     ];
 
-    fn chat_completions_builder(
+    async fn chat_completions_builder(
         &self,
         client: &ReqwestClient,
         data: ChatCompletionsData,
@@ -60,7 +60,7 @@ impl BedrockClient {
         let body = build_chat_completions_body(data, &self.model)?;
 
         let mut request_data = RequestData::new("", body);
-        self.patch_request_data(&mut request_data);
+        self.patch_request_data(&mut request_data).await?;
         let RequestData {
             url: _,
             headers,
@@ -89,7 +89,7 @@ impl BedrockClient {
         Ok(builder)
     }
 
-    fn embeddings_builder(
+    async fn embeddings_builder(
         &self,
         client: &ReqwestClient,
         data: &EmbeddingsData,
@@ -113,7 +113,7 @@ impl BedrockClient {
         });
 
         let mut request_data = RequestData::new("", body);
-        self.patch_request_data(&mut request_data);
+        self.patch_request_data(&mut request_data).await?;
         let RequestData {
             url: _,
             headers,
@@ -152,7 +152,7 @@ impl Client for BedrockClient {
         client: &ReqwestClient,
         data: ChatCompletionsData,
     ) -> Result<ChatCompletionsOutput> {
-        let builder = self.chat_completions_builder(client, data)?;
+        let builder = self.chat_completions_builder(client, data).await?;
         chat_completions(builder).await
     }
 
@@ -162,7 +162,7 @@ impl Client for BedrockClient {
         handler: &mut SseHandler,
         data: ChatCompletionsData,
     ) -> Result<()> {
-        let builder = self.chat_completions_builder(client, data)?;
+        let builder = self.chat_completions_builder(client, data).await?;
         chat_completions_streaming(builder, handler).await
     }
 
@@ -171,12 +171,13 @@ impl Client for BedrockClient {
         client: &ReqwestClient,
         data: &EmbeddingsData,
     ) -> Result<EmbeddingsOutput> {
-        let builder = self.embeddings_builder(client, data)?;
+        let builder = self.embeddings_builder(client, data).await?;
         embeddings(builder).await
     }
 }
 
 async fn chat_completions(builder: RequestBuilder) -> Result<ChatCompletionsOutput> {
+    record_audit_request(&builder);
     let res = builder.send().await?;
     let status = res.status();
     let data: Value = res.json().await?;
@@ -193,6 +194,7 @@ async fn chat_completions_streaming(
     builder: RequestBuilder,
     handler: &mut SseHandler,
 ) -> Result<()> {
+    record_audit_request(&builder);
     let res = builder.send().await?;
     let status = res.status();
     if !status.is_success() {
@@ -210,6 +212,7 @@ async fn chat_completions_streaming(
     let mut buffer = BytesMut::new();
     let mut decoder = MessageFrameDecoder::new();
     while let Some(chunk) = stream.next().await {
+        mark_stream_activity();
         let chunk = chunk?;
         buffer.extend_from_slice(&chunk);
         while let DecodedFrame::Complete(message) = decoder.decode_frame(&mut buffer)? {
@@ -300,6 +303,7 @@ async fn chat_completions_streaming(
 }
 
 async fn embeddings(builder: RequestBuilder) -> Result<EmbeddingsOutput> {
+    record_audit_request(&builder);
     let res = builder.send().await?;
     let status = res.status();
     let data: Value = res.json().await?;
@@ -325,8 +329,15 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
         top_p,
         frequency_penalty: _,
         presence_penalty: _,
+        logprobs: _,
+        top_logprobs: _,
+        seed: _,
+        stop,
         functions,
         stream: _,
+        google_search: _,
+        guided: _,
+        documents: _,
     } = data;
 
     let system_message = extract_system_message(&mut messages);
@@ -457,6 +468,9 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
     if let Some(v) = top_p {
         body["inferenceConfig"]["topP"] = v.into();
     }
+    if let Some(v) = stop {
+        body["inferenceConfig"]["stopSequences"] = v.into();
+    }
     if let Some(functions) = functions {
         let tools: Vec<_> = functions
             .iter()
@@ -526,6 +540,10 @@ fn extract_chat_completions(data: &Value) -> Result<ChatCompletionsOutput> {
         id: None,
         input_tokens: data["usage"]["inputTokens"].as_u64(),
         output_tokens: data["usage"]["outputTokens"].as_u64(),
+        logprobs: None,
+        system_fingerprint: None,
+        images: vec![],
+        sources: None,
     };
     Ok(output)
 }
@@ -0,0 +1,382 @@
+use super::{
+    is_secret, ChatCompletionsData, ChatCompletionsOutput, EmbeddingsData, Model, RerankData,
+    RerankResult, SseHandler, ToolCall,
+};
+
+use crate::config::{ensure_parent_exists, Config, GlobalConfig};
+use crate::utils::resolve_home_dir;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs;
+use std::path::PathBuf;
+
+const CASSETTE_VERSION: u32 = 1;
+/// Shorter secrets are left alone, they would redact ordinary words.
+const MIN_SECRET_LEN: usize = 8;
+
+/// A file of recorded provider calls, VCR-style. Recording keeps what each chat, embeddings or
+/// rerank call sent and got back, with secrets redacted; replaying answers the calls from the
+/// file without touching the network, so runs are deterministic and need no API keys.
+#[derive(Debug, Clone)]
+pub struct Cassette {
+    path: PathBuf,
+    mode: CassetteMode,
+    secrets: Vec<String>,
+    interactions: Vec<Interaction>,
+    used: Vec<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+    Record,
+    Replay,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CassetteFile {
+    version: u32,
+    interactions: Vec<Interaction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Interaction {
+    api: String,
+    model: String,
+    request: Value,
+    response: Value,
+}
+
+impl Cassette {
+    /// Starts a new cassette at `path`, replacing any earlier recording.
+    pub fn record(config: &Config, path: &str) -> Result<Self> {
+        let cassette = Self {
+            path: PathBuf::from(resolve_home_dir(path)),
+            mode: CassetteMode::Record,
+            secrets: config_secrets(config),
+            interactions: vec![],
+            used: vec![],
+        };
+        cassette.save()?;
+        Ok(cassette)
+    }
+
+    /// Loads the cassette at `path`. Requests are redacted the same way as when recording,
+    /// so they still match when the keys are set.
+    pub fn replay(config: &Config, path: &str) -> Result<Self> {
+        let path = PathBuf::from(resolve_home_dir(path));
+        let err = || format!("Failed to load the cassette at '{}'", path.display());
+        let content = fs::read_to_string(&path).with_context(err)?;
+        let file: CassetteFile = serde_yaml::from_str(&content).with_context(err)?;
+        if file.version != CASSETTE_VERSION {
+            bail!(
+                "Unsupported cassette version {} in '{}'",
+                file.version,
+                path.display()
+            );
+        }
+        let used = vec![false; file.interactions.len()];
+        Ok(Self {
+            path,
+            mode: CassetteMode::Replay,
+            secrets: config_secrets(config),
+            interactions: file.interactions,
+            used,
+        })
+    }
+
+    /// The response of the first unplayed interaction that made the same request.
+    fn play(&mut self, api: &str, model: &str, request: &Value) -> Result<Value> {
+        let index = self.interactions.iter().enumerate().position(|(i, v)| {
+            !self.used[i] && v.api == api && v.model == model && &v.request == request
+        });
+        match index {
+            Some(index) => {
+                self.used[index] = true;
+                Ok(self.interactions[index].response.clone())
+            }
+            None => bail!(
+                "No recorded {api} call of '{model}' matches this request in the cassette '{}'",
+                self.path.display()
+            ),
+        }
+    }
+
+    fn add(&mut self, api: &str, model: &str, request: Value, response: Value) -> Result<()> {
+        self.interactions.push(Interaction {
+            api: api.to_string(),
+            model: model.to_string(),
+            request: redact(request, &self.secrets),
+            response: redact(response, &self.secrets),
+        });
+        self.used.push(false);
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let file = CassetteFile {
+            version: CASSETTE_VERSION,
+            interactions: self.interactions.clone(),
+        };
+        let content = serde_yaml::to_string(&file)?;
+        ensure_parent_exists(&self.path)?;
+        fs::write(&self.path, content)
+            .with_context(|| format!("Failed to write the cassette at '{}'", self.path.display()))
+    }
+}
+
+/// One provider call going through the cassette of the config, if there is one.
+pub struct CassetteCall {
+    api: &'static str,
+    request: Option<Value>,
+    replayed: Option<Value>,
+}
+
+impl CassetteCall {
+    /// Looks the call up when replaying; `request` is only built when a cassette is in use.
+    pub fn start(
+        config: &GlobalConfig,
+        api: &'static str,
+        model: &Model,
+        request: impl FnOnce() -> Value,
+    ) -> Result<Self> {
+        let mut call = Self {
+            api,
+            request: None,
+            replayed: None,
+        };
+        let mut config = config.write();
+        let Some(cassette) = config.cassette.as_mut() else {
+            return Ok(call);
+        };
+        let request = redact(request(), &cassette.secrets);
+        if cassette.mode == CassetteMode::Replay {
+            call.replayed = Some(cassette.play(api, &model.id(), &request)?);
+        }
+        call.request = Some(request);
+        Ok(call)
+    }
+
+    pub fn replayed(&self) -> Option<&Value> {
+        self.replayed.as_ref()
+    }
+
+    pub fn record(
+        self,
+        config: &GlobalConfig,
+        model: &Model,
+        response: impl FnOnce() -> Value,
+    ) -> Result<()> {
+        let Some(request) = self.request else {
+            return Ok(());
+        };
+        let mut config = config.write();
+        match config.cassette.as_mut() {
+            Some(cassette) if cassette.mode == CassetteMode::Record => {
+                cassette.add(self.api, &model.id(), request, response())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn chat_request_value(data: &ChatCompletionsData) -> Value {
+    json!({
+        "messages": data.messages,
+        "temperature": data.temperature,
+        "top_p": data.top_p,
+        "seed": data.seed,
+        "stop": data.stop,
+        "functions": data.functions,
+    })
+}
+
+pub fn chat_output_value(output: &ChatCompletionsOutput) -> Value {
+    json!({
+        "text": output.text,
+        "tool_calls": output.tool_calls,
+        "input_tokens": output.input_tokens,
+        "output_tokens": output.output_tokens,
+    })
+}
+
+pub fn chat_output_from_value(value: &Value) -> Result<ChatCompletionsOutput> {
+    Ok(ChatCompletionsOutput {
+        text: value["text"].as_str().unwrap_or_default().to_string(),
+        tool_calls: tool_calls_from_value(value)?,
+        input_tokens: value["input_tokens"].as_u64(),
+        output_tokens: value["output_tokens"].as_u64(),
+        ..Default::default()
+    })
+}
+
+pub fn stream_output_value(chunks: Vec<String>, tool_calls: &[ToolCall]) -> Value {
+    json!({
+        "chunks": chunks,
+        "tool_calls": tool_calls,
+    })
+}
+
+/// Feeds a recorded stream to the handler, chunk by chunk as it arrived.
+pub fn replay_stream(value: &Value, handler: &mut SseHandler) -> Result<()> {
+    for chunk in value["chunks"].as_array().into_iter().flatten() {
+        handler.text(chunk.as_str().unwrap_or_default())?;
+    }
+    for tool_call in tool_calls_from_value(value)? {
+        handler.tool_call(tool_call)?;
+    }
+    Ok(())
+}
+
+pub fn embeddings_request_value(data: &EmbeddingsData) -> Value {
+    json!({
+        "texts": data.texts,
+        "query": data.query,
+    })
+}
+
+pub fn rerank_request_value(data: &RerankData) -> Value {
+    json!({
+        "query": data.query,
+        "documents": data.documents,
+        "top_n": data.top_n,
+    })
+}
+
+pub fn rerank_output_value(output: &[RerankResult]) -> Value {
+    output
+        .iter()
+        .map(|v| json!({ "index": v.index, "relevance_score": v.relevance_score }))
+        .collect()
+}
+
+fn tool_calls_from_value(value: &Value) -> Result<Vec<ToolCall>> {
+    match value.get("tool_calls") {
+        Some(Value::Null) | None => Ok(vec![]),
+        Some(tool_calls) => {
+            serde_json::from_value(tool_calls.clone()).context("Invalid tool calls in the cassette")
+        }
+    }
+}
+
+/// The API keys and other secrets of the client configs and the environment, longest first.
+fn config_secrets(config: &Config) -> Vec<String> {
+    let mut secrets = vec![];
+    let clients = serde_json::to_value(&config.clients).unwrap_or_default();
+    collect_secrets(&clients, false, &mut secrets);
+    for (name, value) in std::env::vars() {
+        if is_secret(&name) && value.len() >= MIN_SECRET_LEN {
+            secrets.push(value);
+        }
+    }
+    secrets.sort_by_key(|v| std::cmp::Reverse(v.len()));
+    secrets.dedup();
+    secrets
+}
+
+/// Collects the values of secret-looking fields, e.g. `api_key`, from the client configs.
+fn collect_secrets(value: &Value, secret: bool, output: &mut Vec<String>) {
+    match value {
+        Value::String(text) if secret && text.len() >= MIN_SECRET_LEN => output.push(text.clone()),
+        Value::Array(list) => {
+            for item in list {
+                collect_secrets(item, secret, output);
+            }
+        }
+        Value::Object(map) => {
+            for (key, item) in map {
+                collect_secrets(item, secret || is_secret(key), output);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn redact(value: Value, secrets: &[String]) -> Value {
+    match value {
+        Value::String(mut text) => {
+            for secret in secrets {
+                if text.contains(secret.as_str()) {
+                    text = text.replace(secret.as_str(), "***");
+                }
+            }
+            Value::String(text)
+        }
+        Value::Array(list) => list.into_iter().map(|v| redact(v, secrets)).collect(),
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(k, v)| (k, redact(v, secrets)))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::SseEvent;
+    use crate::utils::{create_abort_signal, temp_file};
+
+    #[test]
+    fn test_record_and_replay() {
+        let path = temp_file("-cassette", ".yaml");
+        let mut cassette = Cassette {
+            path: path.clone(),
+            mode: CassetteMode::Record,
+            secrets: vec!["sk-secret-123456".into()],
+            interactions: vec![],
+            used: vec![],
+        };
+        let request =
+            json!({ "messages": [{ "role": "user", "content": "key sk-secret-123456" }] });
+        let chunks = vec!["Hel".to_string(), "lo".to_string()];
+        let tool_calls = vec![ToolCall::new(
+            "get_time".into(),
+            json!({}),
+            Some("call_1".into()),
+        )];
+        let response = stream_output_value(chunks, &tool_calls);
+        cassette
+            .add(
+                "chat_completions_streaming",
+                "openai:gpt-4o",
+                request.clone(),
+                response,
+            )
+            .unwrap();
+        let saved = fs::read_to_string(&path).unwrap();
+        assert!(saved.contains("key ***") && !saved.contains("sk-secret"));
+
+        let mut cassette =
+            Cassette::replay(&Config::default(), &path.display().to_string()).unwrap();
+        let _ = fs::remove_file(&path);
+        let api = "chat_completions_streaming";
+        assert!(cassette.play(api, "openai:gpt-4o", &request).is_err());
+        let request = redact(request, &["sk-secret-123456".into()]);
+        let response = cassette.play(api, "openai:gpt-4o", &request).unwrap();
+        assert!(cassette.play(api, "openai:gpt-4o", &request).is_err());
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = SseHandler::new(tx, create_abort_signal());
+        replay_stream(&response, &mut handler).unwrap();
+        let mut texts = vec![];
+        while let Ok(SseEvent::Text(text)) = rx.try_recv() {
+            texts.push(text);
+        }
+        assert_eq!(texts, ["Hel", "lo"]);
+        assert_eq!(handler.tool_calls()[0].name, "get_time");
+    }
+
+    #[test]
+    fn test_collect_secrets() {
+        let clients = json!([
+            { "type": "openai", "api_key": "sk-abcdefgh", "api_base": "https://api.openai.com/v1" },
+            { "type": "claude", "api_key": "short", "extra": { "proxy": null } },
+        ]);
+        let mut secrets = vec![];
+        collect_secrets(&clients, false, &mut secrets);
+        assert_eq!(secrets, ["sk-abcdefgh"]);
+    }
+}
@@ -62,6 +62,7 @@ pub async fn claude_chat_completions(
     builder: RequestBuilder,
     _model: &Model,
 ) -> Result<ChatCompletionsOutput> {
+    record_audit_request(&builder);
     let res = builder.send().await?;
     let status = res.status();
     let data: Value = res.json().await?;
@@ -77,59 +78,85 @@ pub async fn claude_chat_completions_streaming(
     handler: &mut SseHandler,
     _model: &Model,
 ) -> Result<()> {
-    let mut function_name = String::new();
-    let mut function_arguments = String::new();
-    let mut function_id = String::new();
-    let mut reasoning_state = 0;
+    let mut parser = ClaudeStreamParser::default();
     let handle = |message: SseMmessage| -> Result<bool> {
         let data: Value = serde_json::from_str(&message.data)?;
         debug!("stream-data: {data}");
-        if let Some(typ) = data["type"].as_str() {
-            match typ {
-                "content_block_start" => {
-                    if let (Some("tool_use"), Some(name), Some(id)) = (
-                        data["content_block"]["type"].as_str(),
-                        data["content_block"]["name"].as_str(),
-                        data["content_block"]["id"].as_str(),
-                    ) {
-                        if !function_name.is_empty() {
-                            let arguments: Value =
-                                function_arguments.parse().with_context(|| {
-                                    format!("Tool call '{function_name}' have non-JSON arguments '{function_arguments}'")
-                                })?;
-                            handler.tool_call(ToolCall::new(
-                                function_name.clone(),
-                                arguments,
-                                Some(function_id.clone()),
-                            ))?;
-                        }
-                        function_name = name.into();
-                        function_arguments.clear();
-                        function_id = id.into();
-                    }
+        parser.handle(&data, handler)?;
+        Ok(false)
+    };
+
+    sse_stream(builder, handle).await
+}
+
+/// The state kept across the events of a streamed Claude response: the open content block,
+/// the tool call arguments so far and the thinking blocks to hand back with the next tool call.
+#[derive(Debug, Default)]
+struct ClaudeStreamParser {
+    block_type: String,
+    function_name: String,
+    function_arguments: String,
+    function_id: String,
+    thinking: String,
+    signature: String,
+    thinking_blocks: Vec<Value>,
+    in_reasoning: bool,
+}
+
+impl ClaudeStreamParser {
+    fn handle(&mut self, data: &Value, handler: &mut SseHandler) -> Result<()> {
+        let Some(typ) = data["type"].as_str() else {
+            return Ok(());
+        };
+        match typ {
+            "content_block_start" => {
+                let block = &data["content_block"];
+                self.block_type = block["type"].as_str().unwrap_or_default().to_string();
+                match self.block_type.as_str() {
+                    "thinking" => {}
+                    "redacted_thinking" => self.thinking_blocks.push(block.clone()),
+                    _ => self.end_reasoning(handler)?,
                 }
-                "content_block_delta" => {
-                    if let Some(text) = data["delta"]["text"].as_str() {
-                        handler.text(text)?;
-                    } else if let Some(text) = data["delta"]["thinking"].as_str() {
-                        if reasoning_state == 0 {
-                            handler.text("<think>\n")?;
-                            reasoning_state = 1;
-                        }
-                        handler.text(text)?;
-                    } else if let (true, Some(partial_json)) = (
-                        !function_name.is_empty(),
-                        data["delta"]["partial_json"].as_str(),
-                    ) {
-                        function_arguments.push_str(partial_json);
-                    }
+                if let (Some("tool_use"), Some(name), Some(id)) = (
+                    block["type"].as_str(),
+                    block["name"].as_str(),
+                    block["id"].as_str(),
+                ) {
+                    self.function_name = name.into();
+                    self.function_arguments.clear();
+                    self.function_id = id.into();
                 }
-                "content_block_stop" => {
-                    if reasoning_state == 1 {
-                        handler.text("\n</think>\n\n")?;
-                        reasoning_state = 0;
+            }
+            "content_block_delta" => {
+                let delta = &data["delta"];
+                if let Some(text) = delta["text"].as_str() {
+                    handler.text(text)?;
+                } else if let Some(text) = delta["thinking"].as_str() {
+                    if !self.in_reasoning {
+                        handler.text("<think>\n")?;
+                        self.in_reasoning = true;
                     }
-                    if !function_name.is_empty() {
+                    self.thinking.push_str(text);
+                    handler.text(text)?;
+                } else if let Some(text) = delta["signature"].as_str() {
+                    self.signature.push_str(text);
+                } else if let (true, Some(partial_json)) = (
+                    self.block_type == "tool_use",
+                    delta["partial_json"].as_str(),
+                ) {
+                    self.function_arguments.push_str(partial_json);
+                }
+            }
+            "content_block_stop" => {
+                match self.block_type.as_str() {
+                    "thinking" => self.thinking_blocks.push(json!({
+                        "type": "thinking",
+                        "thinking": std::mem::take(&mut self.thinking),
+                        "signature": std::mem::take(&mut self.signature),
+                    })),
+                    "tool_use" => {
+                        let function_name = std::mem::take(&mut self.function_name);
+                        let function_arguments = std::mem::take(&mut self.function_arguments);
                         let arguments: Value = if function_arguments.is_empty() {
                             json!({})
                         } else {
@@ -137,20 +164,32 @@ pub async fn claude_chat_completions_streaming(
                                 format!("Tool call '{function_name}' have non-JSON arguments '{function_arguments}'")
                             })?
                         };
-                        handler.tool_call(ToolCall::new(
-                            function_name.clone(),
-                            arguments,
-                            Some(function_id.clone()),
-                        ))?;
+                        handler.tool_call(
+                            ToolCall::new(
+                                function_name,
+                                arguments,
+                                Some(std::mem::take(&mut self.function_id)),
+                            )
+                            .with_thinking(std::mem::take(&mut self.thinking_blocks)),
+                        )?;
                     }
+                    _ => {}
                 }
-                _ => {}
+                self.block_type.clear();
             }
+            "message_stop" => self.end_reasoning(handler)?,
+            _ => {}
         }
-        Ok(false)
-    };
+        Ok(())
+    }
 
-    sse_stream(builder, handle).await
+    fn end_reasoning(&mut self, handler: &mut SseHandler) -> Result<()> {
+        if self.in_reasoning {
+            handler.text("\n</think>\n\n")?;
+            self.in_reasoning = false;
+        }
+        Ok(())
+    }
 }
 
 pub fn claude_build_chat_completions_body(
@@ -163,8 +202,15 @@ pub fn claude_build_chat_completions_body(
         top_p,
         frequency_penalty: _,
         presence_penalty: _,
+        logprobs: _,
+        top_logprobs: _,
+        seed: _,
+        stop,
         functions,
         stream,
+        google_search: _,
+        guided: _,
+        documents: _,
     } = data;
 
     let system_message = extract_system_message(&mut messages);
@@ -220,39 +266,61 @@ pub fn claude_build_chat_completions_body(
                     })]
                 }
                 MessageContent::ToolCalls(MessageContentToolCalls {
-                    tool_results, text, ..
+                    tool_results,
+                    text,
+                    sequence,
                 }) => {
-                    let mut assistant_parts = vec![];
-                    let mut user_parts = vec![];
-                    if !text.is_empty() {
-                        assistant_parts.push(json!({
-                            "type": "text",
-                            "text": text,
-                        }))
-                    }
+                    // Each round's reasoning must lead its own assistant turn, so a merged
+                    // sequence is split again wherever a call carries thinking blocks.
+                    let mut rounds: Vec<(Vec<Value>, Vec<Value>)> = vec![];
+                    let text = strip_think_tag(&text);
                     for tool_result in tool_results {
+                        let ToolCall {
+                            name,
+                            arguments,
+                            id,
+                            thinking,
+                        } = tool_result.call;
+                        if rounds.is_empty() || (sequence && !thinking.is_empty()) {
+                            rounds.push((vec![], vec![]));
+                        }
+                        let first_round = rounds.len() == 1;
+                        let (assistant_parts, user_parts) = rounds.last_mut().unwrap();
+                        let with_text = first_round && assistant_parts.is_empty();
+                        assistant_parts.extend(thinking);
+                        if with_text && !text.is_empty() {
+                            assistant_parts.push(json!({
+                                "type": "text",
+                                "text": text,
+                            }))
+                        }
                         assistant_parts.push(json!({
                             "type": "tool_use",
-                            "id": tool_result.call.id,
-                            "name": tool_result.call.name,
-                            "input": tool_result.call.arguments,
+                            "id": id,
+                            "name": name,
+                            "input": arguments,
                         }));
                         user_parts.push(json!({
                             "type": "tool_result",
-                            "tool_use_id": tool_result.call.id,
+                            "tool_use_id": id,
                             "content": tool_result.output.to_string(),
                         }));
                     }
-                    vec![
-                        json!({
-                            "role": "assistant",
-                            "content": assistant_parts,
-                        }),
-                        json!({
-                            "role": "user",
-                            "content": user_parts,
-                        }),
-                    ]
+                    rounds
+                        .into_iter()
+                        .flat_map(|(assistant_parts, user_parts)| {
+                            vec![
+                                json!({
+                                    "role": "assistant",
+                                    "content": assistant_parts,
+                                }),
+                                json!({
+                                    "role": "user",
+                                    "content": user_parts,
+                                }),
+                            ]
+                        })
+                        .collect()
                 }
             }
         })
@@ -272,15 +340,16 @@ pub fn claude_build_chat_completions_body(
     if let Some(v) = system_message {
         body["system"] = v.into();
     }
-    if let Some(v) = model.max_tokens_param() {
-        body["max_tokens"] = v.into();
-    }
+    body["max_tokens"] = model.required_max_tokens_param().into();
     if let Some(v) = temperature {
         body["temperature"] = v.into();
     }
     if let Some(v) = top_p {
         body["top_p"] = v.into();
     }
+    if let Some(v) = stop {
+        body["stop_sequences"] = v.into();
+    }
     if stream {
         body["stream"] = true.into();
     }
@@ -303,6 +372,7 @@ pub fn claude_extract_chat_completions(data: &Value) -> Result<ChatCompletionsOu
     let mut text = String::new();
     let mut reasoning = None;
     let mut tool_calls = vec![];
+    let mut thinking_blocks = vec![];
     if let Some(list) = data["content"].as_array() {
         for item in list {
             match item["type"].as_str() {
@@ -310,7 +380,9 @@ pub fn claude_extract_chat_completions(data: &Value) -> Result<ChatCompletionsOu
                     if let Some(v) = item["thinking"].as_str() {
                         reasoning = Some(v.to_string());
                     }
+                    thinking_blocks.push(item.clone());
                 }
+                Some("redacted_thinking") => thinking_blocks.push(item.clone()),
                 Some("text") => {
                     if let Some(v) = item["text"].as_str() {
                         if !text.is_empty() {
@@ -325,11 +397,10 @@ pub fn claude_extract_chat_completions(data: &Value) -> Result<ChatCompletionsOu
                         item.get("input"),
                         item["id"].as_str(),
                     ) {
-                        tool_calls.push(ToolCall::new(
-                            name.to_string(),
-                            input.clone(),
-                            Some(id.to_string()),
-                        ));
+                        tool_calls.push(
+                            ToolCall::new(name.to_string(), input.clone(), Some(id.to_string()))
+                                .with_thinking(std::mem::take(&mut thinking_blocks)),
+                        );
                     }
                 }
                 _ => {}
@@ -350,6 +421,73 @@ pub fn claude_extract_chat_completions(data: &Value) -> Result<ChatCompletionsOu
         id: data["id"].as_str().map(|v| v.to_string()),
         input_tokens: data["usage"]["input_tokens"].as_u64(),
         output_tokens: data["usage"]["output_tokens"].as_u64(),
+        logprobs: None,
+        system_fingerprint: None,
+        images: vec![],
+        sources: None,
     };
     Ok(output)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::create_abort_signal;
+
+    #[test]
+    fn test_stream_parser() {
+        let events = [
+            json!({"type": "content_block_start", "content_block": {"type": "thinking"}}),
+            json!({"type": "content_block_delta", "delta": {"thinking": "Need the time."}}),
+            json!({"type": "content_block_delta", "delta": {"signature": "sig"}}),
+            json!({"type": "content_block_stop"}),
+            json!({"type": "content_block_start", "content_block": {"type": "text"}}),
+            json!({"type": "content_block_delta", "delta": {"text": "Checking."}}),
+            json!({"type": "content_block_stop"}),
+            json!({"type": "content_block_start", "content_block": {"type": "tool_use", "name": "get_time", "id": "toolu_1"}}),
+            json!({"type": "content_block_delta", "delta": {"partial_json": "{\"tz\":"}}),
+            json!({"type": "content_block_delta", "delta": {"partial_json": "\"UTC\"}"}}),
+            json!({"type": "content_block_stop"}),
+            json!({"type": "message_stop"}),
+        ];
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = SseHandler::new(tx, create_abort_signal());
+        let mut parser = ClaudeStreamParser::default();
+        for data in &events {
+            parser.handle(data, &mut handler).unwrap();
+        }
+        assert_eq!(
+            handler.buffer(),
+            "<think>\nNeed the time.\n</think>\n\nChecking."
+        );
+        let calls = handler.tool_calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "get_time");
+        assert_eq!(calls[0].arguments, json!({"tz": "UTC"}));
+        assert_eq!(calls[0].id.as_deref(), Some("toolu_1"));
+        assert_eq!(
+            calls[0].thinking,
+            [json!({"type": "thinking", "thinking": "Need the time.", "signature": "sig"})]
+        );
+    }
+
+    #[test]
+    fn test_stream_parser_closes_thinking() {
+        let events = [
+            json!({"type": "content_block_start", "content_block": {"type": "thinking"}}),
+            json!({"type": "content_block_delta", "delta": {"thinking": "Hmm"}}),
+            json!({"type": "content_block_stop"}),
+            json!({"type": "content_block_start", "content_block": {"type": "tool_use", "name": "ls", "id": "toolu_2"}}),
+            json!({"type": "content_block_stop"}),
+            json!({"type": "message_stop"}),
+        ];
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = SseHandler::new(tx, create_abort_signal());
+        let mut parser = ClaudeStreamParser::default();
+        for data in &events {
+            parser.handle(data, &mut handler).unwrap();
+        }
+        assert_eq!(handler.buffer(), "<think>\nHmm\n</think>\n\n");
+        assert_eq!(handler.tool_calls()[0].arguments, json!({}));
+    }
+}
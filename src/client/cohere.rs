@@ -48,11 +48,27 @@ fn prepare_chat_completions(
         .unwrap_or_else(|_| API_BASE.to_string());
 
     let url = format!("{}/chat", api_base.trim_end_matches('/'));
+    let documents = data.documents.clone();
     let mut body = openai_build_chat_completions_body(data, &self_.model);
+    if let Some(documents) = documents {
+        body["documents"] = documents
+            .into_iter()
+            .map(|v| {
+                let mut data = json!({ "text": v.text });
+                if let Some(title) = v.title {
+                    data["title"] = title.into();
+                }
+                json!({ "id": v.id, "data": data })
+            })
+            .collect();
+    }
     if let Some(obj) = body.as_object_mut() {
         if let Some(top_p) = obj.remove("top_p") {
             obj.insert("p".to_string(), top_p);
         }
+        if let Some(stop) = obj.remove("stop") {
+            obj.insert("stop_sequences".to_string(), stop);
+        }
     }
 
     let mut request_data = RequestData::new(url, body);
@@ -109,6 +125,7 @@ async fn chat_completions(
     builder: RequestBuilder,
     _model: &Model,
 ) -> Result<ChatCompletionsOutput> {
+    record_audit_request(&builder);
     let res = builder.send().await?;
     let status = res.status();
     let data: Value = res.json().await?;
@@ -128,6 +145,7 @@ async fn chat_completions_streaming(
     let mut function_name = String::new();
     let mut function_arguments = String::new();
     let mut function_id = String::new();
+    let mut citations = vec![];
     let handle = |message: SseMmessage| -> Result<bool> {
         if message.data == "[DONE]" {
             return Ok(true);
@@ -164,6 +182,15 @@ async fn chat_completions_streaming(
                         function_arguments.push_str(text);
                     }
                 }
+                "citation-start" => {
+                    let citation = &data["delta"]["message"]["citations"];
+                    if citation.is_object() {
+                        citations.push(citation.clone());
+                    }
+                }
+                "message-end" if !citations.is_empty() => {
+                    handler.notice(&format!("\n\n{}", render_citations(&citations)))?;
+                }
                 "tool-call-end" => {
                     if !function_name.is_empty() {
                         let arguments: Value = function_arguments.parse().with_context(|| {
@@ -189,6 +216,7 @@ async fn chat_completions_streaming(
 }
 
 async fn embeddings(builder: RequestBuilder, _model: &Model) -> Result<EmbeddingsOutput> {
+    record_audit_request(&builder);
     let res = builder.send().await?;
     let status = res.status();
     let data: Value = res.json().await?;
@@ -254,6 +282,61 @@ fn extract_chat_completions(data: &Value) -> Result<ChatCompletionsOutput> {
         id: data["id"].as_str().map(|v| v.to_string()),
         input_tokens: data["usage"]["billed_units"]["input_tokens"].as_u64(),
         output_tokens: data["usage"]["billed_units"]["output_tokens"].as_u64(),
+        logprobs: None,
+        system_fingerprint: None,
+        images: vec![],
+        sources: data["message"]["citations"]
+            .as_array()
+            .filter(|v| !v.is_empty())
+            .map(|v| render_citations(v)),
     };
     Ok(output)
 }
+
+/// Lists the cited spans under the reply, each with the documents or tools backing it.
+fn render_citations(citations: &[Value]) -> String {
+    let lines: Vec<String> = citations
+        .iter()
+        .enumerate()
+        .map(|(i, citation)| {
+            let mut sources: Vec<&str> = vec![];
+            for source in citation["sources"].as_array().into_iter().flatten() {
+                let name = source["document"]["title"]
+                    .as_str()
+                    .or_else(|| source["id"].as_str());
+                if let Some(name) = name {
+                    if !sources.contains(&name) {
+                        sources.push(name);
+                    }
+                }
+            }
+            let text = citation["text"].as_str().unwrap_or_default();
+            format!("[{}] \"{text}\" ({})", i + 1, sources.join(", "))
+        })
+        .collect();
+    format!("Citations:\n{}", lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_citations_stay_out_of_the_reply() {
+        let data = json!({
+            "message": {
+                "content": [{ "type": "text", "text": "Rust was first released in 2015." }],
+                "citations": [{
+                    "text": "2015",
+                    "sources": [{ "id": "doc_0", "document": { "title": "Rust history" } }]
+                }]
+            }
+        });
+        let output = extract_chat_completions(&data).unwrap();
+        assert_eq!(output.text, "Rust was first released in 2015.");
+        assert_eq!(
+            output.sources.as_deref(),
+            Some("Citations:\n[1] \"2015\" (Rust history)")
+        );
+    }
+}
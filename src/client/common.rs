@@ -7,7 +7,7 @@ use crate::{
     utils::*,
 };
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use fancy_regex::Regex;
 use indexmap::IndexMap;
 use inquire::{
@@ -17,10 +17,13 @@ use reqwest::{Client as ReqwestClient, RequestBuilder};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::sync::LazyLock;
-use std::time::Duration;
-use tokio::sync::mpsc::unbounded_channel;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
 
 const MODELS_YAML: &str = include_str!("../../models.yaml");
+const MAX_STALL_RETRIES: usize = 2;
+const CONTINUE_PROMPT: &str =
+    "Your reply was cut off. Continue it exactly where it stopped, without repeating anything.";
 
 pub static ALL_PROVIDER_MODELS: LazyLock<Vec<ProviderModels>> = LazyLock::new(|| {
     // First, try to load from local override (highest priority)
@@ -33,82 +36,45 @@ pub static ALL_PROVIDER_MODELS: LazyLock<Vec<ProviderModels>> = LazyLock::new(||
 });
 
 fn load_models_with_fallback() -> Vec<ProviderModels> {
-    // Check if models.dev is enabled (default: true)
-    // Use AICHAT_ prefix directly since get_env_name is private
-    let models_dev_enabled = std::env::var("AICHAT_MODELS_DEV_ENABLED")
-        .ok()
-        .and_then(|v| v.parse::<bool>().ok())
-        .unwrap_or(true);
-    
-    if !models_dev_enabled {
-        log::debug!("models.dev is disabled, using embedded models.yaml");
-        return serde_yaml::from_str(MODELS_YAML)
-            .unwrap_or_else(|e| {
-                log::error!("Failed to parse embedded models.yaml: {}", e);
-                Vec::new()
-            });
-    }
-    
-    // Get models.dev URL from environment or use default
-    let models_dev_url = std::env::var("AICHAT_MODELS_DEV_URL")
-        .ok();
-    
-    // Try to get a tokio runtime handle to run async code
-    match tokio::runtime::Handle::try_current() {
-        Ok(handle) => {
-            // We're in a tokio runtime, can use block_on
-            handle.block_on(async {
-                match crate::client::models_dev::get_models_dev(models_dev_url.as_deref()).await {
-                    Ok(models) => {
-                        log::info!("Loaded {} providers from models.dev", models.len());
-                        return models;
-                    }
-                    Err(e) => {
-                        log::warn!("Failed to load from models.dev: {}. Falling back to models.yaml", e);
-                        log::debug!("models.dev error details: {:?}", e);
-                    }
-                }
-                // Fallback to embedded models.yaml
-                serde_yaml::from_str(MODELS_YAML)
-                    .unwrap_or_else(|e| {
-                        log::error!("Failed to parse embedded models.yaml: {}", e);
-                        Vec::new()
-                    })
-            })
-        }
-        Err(_) => {
-            // No tokio runtime available, try to create a temporary one
-            match tokio::runtime::Runtime::new() {
-                Ok(rt) => rt.block_on(async {
-                    match crate::client::models_dev::get_models_dev(models_dev_url.as_deref()).await {
-                        Ok(models) => {
-                            log::info!("Loaded {} providers from models.dev", models.len());
-                            return models;
-                        }
-                        Err(e) => {
-                            log::warn!("Failed to load from models.dev: {}. Falling back to models.yaml", e);
-                            log::debug!("models.dev error details: {:?}", e);
-                        }
-                    }
-                    // Fallback to embedded models.yaml
-                    serde_yaml::from_str(MODELS_YAML)
-                        .unwrap_or_else(|e| {
-                            log::error!("Failed to parse embedded models.yaml: {}", e);
-                            Vec::new()
-                        })
-                }),
-                Err(_) => {
-                    // Can't create runtime, fallback to models.yaml
-                    log::warn!("No tokio runtime available, using embedded models.yaml");
-                    serde_yaml::from_str(MODELS_YAML)
-                        .unwrap_or_else(|e| {
-                            log::error!("Failed to parse embedded models.yaml: {}", e);
-                            Vec::new()
-                        })
-                }
+    let (models_dev_enabled, models_dev_url) = models_dev_source();
+    if models_dev_enabled {
+        // The models are first needed from within the tokio runtime, where blocking on a
+        // future panics, so the load runs on a thread with a runtime of its own
+        let loaded = std::thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(get_models_dev(models_dev_url.as_deref()))
+        })
+        .join();
+        match loaded {
+            Ok(Ok(models)) => {
+                log::info!("Loaded {} providers from models.dev", models.len());
+                return models;
+            }
+            Ok(Err(e)) if is_models_offline() => {
+                log::debug!("{}. Using the bundled models.yaml", e);
+            }
+            Ok(Err(e)) => {
+                eprintln!(
+                    "{}",
+                    warning_text(&format!(
+                        "⚠️ Failed to load models.dev: {e:#}. Using the bundled models"
+                    ))
+                );
+                log::debug!("models.dev error details: {:?}", e);
             }
+            Err(_) => log::warn!("Failed to load from models.dev. Falling back to models.yaml"),
         }
+    } else {
+        log::debug!("models.dev is disabled, using embedded models.yaml");
     }
+    
+    serde_yaml::from_str(MODELS_YAML)
+        .unwrap_or_else(|e| {
+            log::error!("Failed to parse embedded models.yaml: {}", e);
+            Vec::new()
+        })
 }
 
 pub static EMBEDDING_MODEL_RE: LazyLock<Regex> = LazyLock::new(|| {
@@ -134,15 +100,16 @@ pub trait Client: Sync + Send {
     fn build_client(&self) -> Result<ReqwestClient> {
         let mut builder = ReqwestClient::builder();
         let extra = self.extra_config();
-        let timeout = extra.and_then(|v| v.connect_timeout).unwrap_or(10);
-        if let Some(proxy) = extra.and_then(|v| v.proxy.as_deref()) {
-            builder = set_proxy(builder, proxy)?;
-        }
+        let timeouts = self.timeouts();
+        builder = apply_proxy(builder, extra.and_then(|v| v.proxy.as_deref()))?;
         if let Some(user_agent) = self.global_config().read().user_agent.as_ref() {
             builder = builder.user_agent(user_agent);
         }
+        if timeouts.read > 0 {
+            builder = builder.read_timeout(Duration::from_secs(timeouts.read));
+        }
         let client = builder
-            .connect_timeout(Duration::from_secs(timeout))
+            .connect_timeout(Duration::from_secs(timeouts.connect))
             .build()
             .with_context(|| "Failed to build client")?;
         Ok(client)
@@ -155,9 +122,19 @@ pub trait Client: Sync + Send {
         }
         let client = self.build_client()?;
         let data = input.prepare_completion_data(self.model(), false)?;
-        self.chat_completions_inner(&client, data)
+        let mut output = self
+            .chat_completions_with_timeout(&client, data)
             .await
-            .with_context(|| "Failed to call chat-completions api")
+            .map_err(|err| explain_meta_role_error(err, self.model()))
+            .with_context(|| "Failed to call chat-completions api")?;
+        apply_response_middleware(self.global_config(), self.model(), &mut output).await?;
+        attach_output_images(self.global_config(), &mut output)?;
+        if let Some(system_fingerprint) = &output.system_fingerprint {
+            self.global_config()
+                .write()
+                .set_system_fingerprint(&input, system_fingerprint);
+        }
+        Ok(output)
     }
 
     async fn chat_completions_streaming(
@@ -175,11 +152,38 @@ pub trait Client: Sync + Send {
                     return Ok(());
                 }
                 let client = self.build_client()?;
+                if has_response_middleware(self.global_config(), self.model()) {
+                    // Response middleware must see the whole reply before any of it is shown
+                    let data = input.prepare_completion_data(self.model(), false)?;
+                    let mut output = self.chat_completions_with_timeout(&client, data).await?;
+                    apply_response_middleware(self.global_config(), self.model(), &mut output).await?;
+                    attach_output_images(self.global_config(), &mut output)?;
+                    handler.text(&output.text)?;
+                    if let Some(sources) = &output.sources {
+                        handler.notice(&format!("\n\n{sources}"))?;
+                    }
+                    for tool_call in output.tool_calls {
+                        handler.tool_call(tool_call)?;
+                    }
+                    return Ok(());
+                }
                 let data = input.prepare_completion_data(self.model(), true)?;
-                self.chat_completions_streaming_inner(&client, handler, data).await
+                self.chat_completions_streaming_with_timeout(&client, handler, data).await?;
+                let references = save_output_images(self.global_config(), handler.take_images())?;
+                if !references.is_empty() && !handler.buffer().is_empty() {
+                    handler.text("\n\n")?;
+                }
+                handler.text(&references)?;
+                if let Some(system_fingerprint) = handler.system_fingerprint() {
+                    self.global_config()
+                        .write()
+                        .set_system_fingerprint(&input, system_fingerprint);
+                }
+                Ok::<_, anyhow::Error>(())
             } => {
                 handler.done();
-                ret.with_context(|| "Failed to call chat-completions api")
+                ret.map_err(|err| explain_meta_role_error(err, self.model()))
+                    .with_context(|| "Failed to call chat-completions api")
             }
             _ = wait_abort_signal(&abort_signal) => {
                 handler.done();
@@ -189,17 +193,203 @@ pub trait Client: Sync + Send {
     }
 
     async fn embeddings(&self, data: &EmbeddingsData) -> Result<Vec<Vec<f32>>> {
+        let cassette =
+            CassetteCall::start(self.global_config(), "embeddings", self.model(), || {
+                embeddings_request_value(data)
+            })?;
+        if let Some(value) = cassette.replayed() {
+            return serde_json::from_value(value["embeddings"].clone())
+                .context("Invalid embeddings in the cassette");
+        }
         let client = self.build_client()?;
-        self.embeddings_inner(&client, data)
-            .await
-            .context("Failed to call embeddings api")
+        let mut audit = AuditEntry::new(self.global_config(), "embeddings", false);
+        let ret = audit.capture(self.embeddings_inner(&client, data)).await;
+        match &ret {
+            Ok(output) => audit.set_response(json!({ "embeddings": output.len() })),
+            Err(err) => audit.set_error(err),
+        }
+        audit.write(self.global_config(), self.model())?;
+        if let Ok(output) = &ret {
+            cassette.record(
+                self.global_config(),
+                self.model(),
+                || json!({ "embeddings": output }),
+            )?;
+        }
+        ret.context("Failed to call embeddings api")
     }
 
     async fn rerank(&self, data: &RerankData) -> Result<RerankOutput> {
+        let cassette = CassetteCall::start(self.global_config(), "rerank", self.model(), || {
+            rerank_request_value(data)
+        })?;
+        if let Some(value) = cassette.replayed() {
+            return serde_json::from_value(value.clone())
+                .context("Invalid rerank results in the cassette");
+        }
         let client = self.build_client()?;
-        self.rerank_inner(&client, data)
-            .await
-            .context("Failed to call rerank api")
+        let mut audit = AuditEntry::new(self.global_config(), "rerank", false);
+        let ret = audit.capture(self.rerank_inner(&client, data)).await;
+        match &ret {
+            Ok(output) => audit.set_response(rerank_output_value(output)),
+            Err(err) => audit.set_error(err),
+        }
+        audit.write(self.global_config(), self.model())?;
+        if let Ok(output) = &ret {
+            cassette.record(self.global_config(), self.model(), || {
+                rerank_output_value(output)
+            })?;
+        }
+        ret.context("Failed to call rerank api")
+    }
+
+    fn timeouts(&self) -> RequestTimeouts {
+        let mut timeouts = RequestTimeouts::new(self.model(), self.extra_config());
+        // `--timeout` bounds the whole request and lifts the narrower limits with it
+        if let Some(secs) = self.global_config().read().request_timeout {
+            timeouts.first_token = secs;
+            timeouts.read = secs;
+            timeouts.total = secs;
+        }
+        timeouts
+    }
+
+    async fn chat_completions_with_timeout(
+        &self,
+        client: &ReqwestClient,
+        data: ChatCompletionsData,
+    ) -> Result<ChatCompletionsOutput> {
+        let cassette = CassetteCall::start(
+            self.global_config(),
+            "chat_completions",
+            self.model(),
+            || chat_request_value(&data),
+        )?;
+        if let Some(value) = cassette.replayed() {
+            return chat_output_from_value(value);
+        }
+        let total = self.timeouts().total;
+        let mut audit = AuditEntry::new(self.global_config(), "chat_completions", false);
+        let ret = audit
+            .capture(async {
+                if total == 0 {
+                    return self.chat_completions_inner(client, data).await;
+                }
+                match tokio::time::timeout(
+                    Duration::from_secs(total),
+                    self.chat_completions_inner(client, data),
+                )
+                .await
+                {
+                    Ok(ret) => ret,
+                    Err(_) => bail!(
+                        "Timed out after {total}s waiting for '{}' to finish (set `timeout` to allow longer)",
+                        self.model().id()
+                    ),
+                }
+            })
+            .await;
+        audit.set_chat_output(&ret);
+        audit.write(self.global_config(), self.model())?;
+        if let Ok(output) = &ret {
+            cassette.record(self.global_config(), self.model(), || {
+                chat_output_value(output)
+            })?;
+        }
+        ret
+    }
+
+    async fn chat_completions_streaming_with_timeout(
+        &self,
+        client: &ReqwestClient,
+        handler: &mut SseHandler,
+        data: ChatCompletionsData,
+    ) -> Result<()> {
+        let cassette = CassetteCall::start(
+            self.global_config(),
+            "chat_completions_streaming",
+            self.model(),
+            || chat_request_value(&data),
+        )?;
+        if let Some(value) = cassette.replayed() {
+            return replay_stream(value, handler);
+        }
+        handler.record_chunks();
+        let RequestTimeouts {
+            first_token,
+            stall,
+            total,
+            ..
+        } = self.timeouts();
+        let on_stall = self
+            .extra_config()
+            .and_then(|v| v.on_stall)
+            .unwrap_or_default();
+        let first_token_signal = handler.first_token();
+        let activity = std::sync::Arc::new(tokio::sync::Notify::new());
+        let model_id = self.model().id();
+        let input_tokens = self.model().total_tokens(&data.messages) as u64;
+        let mut audit = AuditEntry::new(self.global_config(), "chat_completions", true);
+        let ret = audit
+            .capture(async {
+                let deadline = wait_timeout(total);
+                tokio::pin!(deadline);
+                let mut data = data;
+                let mut stalls = 0;
+                loop {
+                    // A resumed reply already has its first token
+                    let first_token_signal = (handler.buffer().is_empty()
+                        && handler.tool_calls().is_empty())
+                    .then(|| first_token_signal.clone());
+                    tokio::select! {
+                        ret = watch_stream_activity(
+                            activity.clone(),
+                            self.chat_completions_streaming_inner(client, handler, data.clone()),
+                        ) => return ret,
+                        timeout = wait_stream_timeout(first_token_signal, first_token, activity.clone(), stall) => match timeout {
+                            StreamTimeout::FirstToken => bail!(
+                                "Timed out after {first_token}s waiting for the first token from '{model_id}' (set `first_token_timeout` to allow longer)"
+                            ),
+                            StreamTimeout::Stalled => {
+                                if on_stall == StallAction::Fail || stalls >= MAX_STALL_RETRIES {
+                                    bail!(
+                                        "The stream from '{model_id}' stalled, nothing arrived for {stall}s (set `stall_timeout` to allow longer)"
+                                    )
+                                }
+                                stalls += 1;
+                                // Text already shown can't be taken back, so it is continued
+                                if on_stall == StallAction::Resume || !handler.buffer().is_empty() {
+                                    data = continuation_data(data, handler.buffer());
+                                } else {
+                                    handler.restart("⚠️ The stream stalled, retrying…\n\n")?;
+                                }
+                            }
+                        },
+                        _ = &mut deadline => bail!(
+                            "Timed out after {total}s waiting for '{model_id}' to finish (set `timeout` to allow longer)"
+                        ),
+                    }
+                }
+            })
+            .await;
+        // Streams don't report usage, so the token counts are estimated
+        let output_tokens = estimate_token_length(handler.buffer()) as u64;
+        audit.set_tokens(Some(input_tokens), Some(output_tokens), true);
+        audit.set_response(json!({
+            "text": handler.buffer(),
+            "tool_calls": handler.tool_calls(),
+        }));
+        if let Err(err) = &ret {
+            audit.set_error(err);
+        }
+        audit.write(self.global_config(), self.model())?;
+        let chunks = handler.take_chunks();
+        if ret.is_ok() {
+            cassette.record(self.global_config(), self.model(), || {
+                stream_output_value(chunks, handler.tool_calls())
+            })?;
+        }
+        ret
     }
 
     async fn chat_completions_inner(
@@ -231,16 +421,21 @@ pub trait Client: Sync + Send {
         bail!("The client doesn't support rerank api")
     }
 
-    fn request_builder(
+    async fn request_builder(
         &self,
         client: &reqwest::Client,
         mut request_data: RequestData,
-    ) -> RequestBuilder {
-        self.patch_request_data(&mut request_data);
-        request_data.into_builder(client)
+    ) -> Result<RequestBuilder> {
+        self.patch_request_data(&mut request_data).await?;
+        Ok(request_data.into_builder(client))
+    }
+
+    async fn patch_request_data(&self, request_data: &mut RequestData) -> Result<()> {
+        self.apply_patches(request_data);
+        apply_request_middleware(self.global_config(), self.model(), request_data).await
     }
 
-    fn patch_request_data(&self, request_data: &mut RequestData) {
+    fn apply_patches(&self, request_data: &mut RequestData) {
         let model_type = self.model().model_type();
         if let Some(patch) = self.model().patch() {
             request_data.apply_patch(patch.clone());
@@ -284,6 +479,70 @@ impl Default for ClientConfig {
 pub struct ExtraConfig {
     pub proxy: Option<String>,
     pub connect_timeout: Option<u64>,
+    pub first_token_timeout: Option<u64>,
+    pub read_timeout: Option<u64>,
+    pub timeout: Option<u64>,
+    pub stall_timeout: Option<u64>,
+    pub on_stall: Option<StallAction>,
+}
+
+/// What to do when a streamed reply stops sending data midway.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StallAction {
+    /// Give up with an error
+    Fail,
+    /// Send the request again, or continue the partial reply if some text of it was
+    /// already shown
+    #[default]
+    Retry,
+    /// Keep the partial reply and ask the model to continue it
+    Resume,
+}
+
+/// Seconds to wait for the connection, the first streamed token, any single read, a stream
+/// that went quiet after its first token and the whole response. Zero disables the
+/// first-token, read, stall and total limits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RequestTimeouts {
+    pub connect: u64,
+    pub first_token: u64,
+    pub read: u64,
+    pub stall: u64,
+    pub total: u64,
+}
+
+impl RequestTimeouts {
+    pub const DEFAULT_CONNECT: u64 = 10;
+    pub const DEFAULT_FIRST_TOKEN: u64 = 300;
+    pub const DEFAULT_READ: u64 = 0;
+    pub const DEFAULT_STALL: u64 = 60;
+    pub const DEFAULT_TOTAL: u64 = 3600;
+
+    pub fn new(model: &Model, extra: Option<&ExtraConfig>) -> Self {
+        let data = model.data();
+        Self {
+            connect: data
+                .connect_timeout
+                .or_else(|| extra.and_then(|v| v.connect_timeout))
+                .unwrap_or(Self::DEFAULT_CONNECT),
+            first_token: data
+                .first_token_timeout
+                .or_else(|| extra.and_then(|v| v.first_token_timeout))
+                .unwrap_or(Self::DEFAULT_FIRST_TOKEN),
+            read: data
+                .read_timeout
+                .or_else(|| extra.and_then(|v| v.read_timeout))
+                .unwrap_or(Self::DEFAULT_READ),
+            stall: extra
+                .and_then(|v| v.stall_timeout)
+                .unwrap_or(Self::DEFAULT_STALL),
+            total: data
+                .timeout
+                .or_else(|| extra.and_then(|v| v.timeout))
+                .unwrap_or(Self::DEFAULT_TOTAL),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, Default)]
@@ -295,6 +554,7 @@ pub struct RequestPatch {
 
 pub type ApiPatch = IndexMap<String, Value>;
 
+#[derive(Debug, Clone)]
 pub struct RequestData {
     pub url: String,
     pub headers: IndexMap<String, String>,
@@ -360,15 +620,75 @@ impl RequestData {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ChatCompletionsData {
     pub messages: Vec<Message>,
     pub temperature: Option<f64>,
     pub top_p: Option<f64>,
     pub frequency_penalty: Option<f64>,
     pub presence_penalty: Option<f64>,
+    pub logprobs: bool,
+    pub top_logprobs: Option<usize>,
+    pub seed: Option<u64>,
+    pub stop: Option<Vec<String>>,
     pub functions: Option<Vec<FunctionDeclaration>>,
     pub stream: bool,
+    pub google_search: bool,
+    pub guided: Option<GuidedDecoding>,
+    pub documents: Option<Vec<ChatDocument>>,
+}
+
+/// A grounding document for clients that cite sources natively.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ChatDocument {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    pub text: String,
+}
+
+/// Constrained generation parameters, named after vLLM's `guided_*` request extras.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct GuidedDecoding {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guided_json: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guided_regex: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guided_choice: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guided_grammar: Option<String>,
+}
+
+impl GuidedDecoding {
+    pub fn is_empty(&self) -> bool {
+        self.guided_json.is_none()
+            && self.guided_regex.is_none()
+            && self.guided_choice.is_none()
+            && self.guided_grammar.is_none()
+    }
+}
+
+/// Checks the provider scoping fields of the clients, such as OpenAI organization and project
+/// ids, so usage can't silently land in the wrong billing bucket.
+pub fn validate_client_configs(clients: &[ClientConfig]) -> Result<()> {
+    for client in clients {
+        if let ClientConfig::OpenAIConfig(config) = client {
+            config.validate()?;
+        }
+    }
+    Ok(())
+}
+
+/// Resolves a client config field, `<NAME>_<FIELD>` env first; the lookup behind `config_get_fn!`.
+pub fn client_config_value(
+    client_name: &str,
+    field: &str,
+    value: &Option<String>,
+) -> Option<String> {
+    std::env::var(format!("{client_name}_{field}").to_ascii_uppercase())
+        .ok()
+        .or_else(|| value.clone())
 }
 
 pub fn model_data_from_names(model_names: &[String]) -> Vec<ModelData> {
@@ -399,6 +719,11 @@ pub struct ChatCompletionsOutput {
     pub id: Option<String>,
     pub input_tokens: Option<u64>,
     pub output_tokens: Option<u64>,
+    pub logprobs: Option<Value>,
+    pub system_fingerprint: Option<String>,
+    pub images: Vec<OutputImage>,
+    /// Where a grounded answer comes from, shown after the reply but not part of it
+    pub sources: Option<String>,
 }
 
 impl ChatCompletionsOutput {
@@ -516,31 +841,62 @@ pub async fn call_chat_completions(
     client: &dyn Client,
     abort_signal: AbortSignal,
 ) -> Result<(String, Vec<ToolResult>)> {
+    let (output, tool_results) =
+        call_chat_completions_with_output(input, print, extract_code, client, abort_signal).await?;
+    Ok((output.text, tool_results))
+}
+
+pub async fn call_chat_completions_with_output(
+    input: &Input,
+    print: bool,
+    extract_code: bool,
+    client: &dyn Client,
+    abort_signal: AbortSignal,
+) -> Result<(ChatCompletionsOutput, Vec<ToolResult>)> {
+    let deadline = client.global_config().read().max_time_deadline;
     let ret = abortable_run_with_spinner(
-        client.chat_completions(input.clone()),
+        async {
+            tokio::select! {
+                ret = client.chat_completions(input.clone()) => ret.map(Some),
+                _ = wait_max_time(deadline) => Ok(None),
+            }
+        },
         "Generating",
-        abort_signal,
+        abort_signal.clone(),
     )
     .await;
 
     match ret {
-        Ok(ret) => {
-            let ChatCompletionsOutput {
-                mut text,
-                tool_calls,
-                ..
-            } = ret;
-            if !text.is_empty() {
+        Ok(None) => {
+            // Nothing arrived before the cutoff, so the wrap-up is all there is to show
+            let mut output = ChatCompletionsOutput::default();
+            let note =
+                close_truncated_output(input, client, &mut output.text, abort_signal).await?;
+            if print && !output.text.is_empty() {
+                client.global_config().read().print_markdown(&output.text)?;
+            }
+            eprintln!("{}", dimmed_text(&note));
+            Ok((output, vec![]))
+        }
+        Ok(Some(mut output)) => {
+            if !output.text.is_empty() {
                 if extract_code {
-                    text = extract_code_block(&strip_think_tag(&text)).to_string();
+                    output.text = extract_code_block(&strip_think_tag(&output.text)).to_string();
                 } else if client.global_config().read().hide_thinking {
-                    text = strip_think_tag(&text).to_string();
+                    output.text = strip_think_tag(&output.text).to_string();
                 }
                 if print {
-                    client.global_config().read().print_markdown(&text)?;
+                    client.global_config().read().print_markdown(&output.text)?;
+                    preview_output_images(client.global_config(), &output.text)?;
                 }
             }
-            Ok((text, eval_tool_calls(client.global_config(), tool_calls)?))
+            if let (true, false, Some(sources)) = (print, extract_code, &output.sources) {
+                println!("{}", dimmed_text(sources));
+            }
+            let tool_calls = std::mem::take(&mut output.tool_calls);
+            let tool_results =
+                eval_tool_calls(client.global_config(), tool_calls, abort_signal).await?;
+            Ok((output, tool_results))
         }
         Err(err) => Err(err),
     }
@@ -551,35 +907,129 @@ pub async fn call_chat_completions_streaming(
     client: &dyn Client,
     abort_signal: AbortSignal,
 ) -> Result<(String, Vec<ToolResult>)> {
+    let (output, ret) = stream_chat_completions(input, client, abort_signal.clone(), |rx| {
+        render_stream(rx, client.global_config(), abort_signal.clone())
+    })
+    .await;
+    let StreamedOutput {
+        mut text,
+        tool_calls,
+        stats,
+        timed_out,
+    } = output;
+    if let Err(err) = ret {
+        if !text.is_empty() && !abort_signal.aborted() {
+            println!();
+        }
+        return Err(err);
+    }
+    if !text.is_empty() && !text.ends_with('\n') {
+        println!();
+    }
+    if timed_out {
+        // The tool calls of a cut-off reply may be incomplete, so they are dropped
+        let output_len = text.len();
+        let note = close_truncated_output(input, client, &mut text, abort_signal).await?;
+        let wrap_up = text[output_len..].trim_start();
+        if !wrap_up.is_empty() {
+            client.global_config().read().print_markdown(wrap_up)?;
+        }
+        eprintln!("{}", dimmed_text(&note));
+        return Ok((text, vec![]));
+    }
+    preview_output_images(client.global_config(), &text)?;
+    if client.global_config().read().stream_stats {
+        eprintln!("{}", dimmed_text(&stats.to_string()));
+    }
+    let tool_results = eval_tool_calls(client.global_config(), tool_calls, abort_signal).await?;
+    Ok((text, tool_results))
+}
+
+/// A reply received through [`stream_chat_completions`].
+#[derive(Debug)]
+pub struct StreamedOutput {
+    pub text: String,
+    pub tool_calls: Vec<ToolCall>,
+    pub stats: StreamStats,
+    /// Whether `--max-time` cut the reply off
+    pub timed_out: bool,
+}
+
+/// Sends the request and hands the reply to `render` as it arrives, all at once without
+/// stream, until it ends or `--max-time` passes. The partial reply is kept on errors.
+pub async fn stream_chat_completions<F, Fut>(
+    input: &Input,
+    client: &dyn Client,
+    abort_signal: AbortSignal,
+    render: F,
+) -> (StreamedOutput, Result<()>)
+where
+    F: FnOnce(UnboundedReceiver<SseEvent>) -> Fut,
+    Fut: std::future::Future<Output = Result<()>>,
+{
     let (tx, rx) = unbounded_channel();
-    let mut handler = SseHandler::new(tx, abort_signal.clone());
+    let mut handler = SseHandler::new(tx, abort_signal);
+    let deadline = client.global_config().read().max_time_deadline;
 
     let (send_ret, render_ret) = tokio::join!(
-        client.chat_completions_streaming(input, &mut handler),
-        render_stream(rx, client.global_config(), abort_signal.clone()),
+        async {
+            let ret = tokio::select! {
+                ret = send_to_handler(input, client, &mut handler) => ret.map(|_| false),
+                _ = wait_max_time(deadline) => Ok(true),
+            };
+            if let Ok(true) = ret {
+                handler.done();
+            }
+            ret
+        },
+        render(rx),
     );
 
-    if handler.abort().aborted() {
-        bail!("Aborted.");
-    }
-
-    render_ret?;
-
+    let aborted = handler.abort().aborted();
+    let stats = handler.stats();
     let (text, tool_calls) = handler.take();
-    match send_ret {
-        Ok(_) => {
-            if !text.is_empty() && !text.ends_with('\n') {
-                println!();
-            }
-            Ok((text, eval_tool_calls(client.global_config(), tool_calls)?))
+    let mut output = StreamedOutput {
+        text,
+        tool_calls,
+        stats,
+        timed_out: false,
+    };
+    let ret = if aborted {
+        Err(anyhow!("Aborted."))
+    } else {
+        render_ret.and(send_ret).map(|timed_out| {
+            output.timed_out = timed_out;
+        })
+    };
+    (output, ret)
+}
+
+/// Sends the request, handing the reply to `handler` as it arrives, or at once without stream.
+async fn send_to_handler(
+    input: &Input,
+    client: &dyn Client,
+    handler: &mut SseHandler,
+) -> Result<()> {
+    if input.stream() {
+        return client.chat_completions_streaming(input, handler).await;
+    }
+    let abort_signal = handler.abort();
+    let ret = tokio::select! {
+        ret = client.chat_completions(input.clone()) => ret,
+        _ = wait_abort_signal(&abort_signal) => {
+            handler.done();
+            return Ok(());
         }
-        Err(err) => {
-            if !text.is_empty() {
-                println!();
-            }
-            Err(err)
+    };
+    let ret = ret.and_then(|output| {
+        handler.text(&output.text)?;
+        for tool_call in output.tool_calls {
+            handler.tool_call(tool_call)?;
         }
-    }
+        Ok(())
+    });
+    handler.done();
+    ret
 }
 
 pub fn noop_prepare_embeddings<T>(_client: &T, _data: &EmbeddingsData) -> Result<RequestData> {
@@ -598,6 +1048,136 @@ pub async fn noop_rerank(_builder: RequestBuilder, _model: &Model) -> Result<Rer
     bail!("The client doesn't support rerank api")
 }
 
+/// The request again with the partial reply and a prompt to continue it.
+fn continuation_data(mut data: ChatCompletionsData, partial: &str) -> ChatCompletionsData {
+    data.messages.push(Message::new(
+        MessageRole::Assistant,
+        MessageContent::Text(partial.to_string()),
+    ));
+    data.messages.push(Message::new(
+        MessageRole::User,
+        MessageContent::Text(CONTINUE_PROMPT.into()),
+    ));
+    data
+}
+
+enum StreamTimeout {
+    FirstToken,
+    Stalled,
+}
+
+/// Resolves when no first token arrives within `first_token` seconds, or when, after it did,
+/// the stream receives nothing for `stall` seconds. Zero disables either limit.
+async fn wait_stream_timeout(
+    first_token_signal: Option<std::sync::Arc<tokio::sync::Notify>>,
+    first_token: u64,
+    activity: std::sync::Arc<tokio::sync::Notify>,
+    stall: u64,
+) -> StreamTimeout {
+    if let Some(signal) = first_token_signal {
+        if first_token == 0 {
+            signal.notified().await;
+        } else if tokio::time::timeout(Duration::from_secs(first_token), signal.notified())
+            .await
+            .is_err()
+        {
+            return StreamTimeout::FirstToken;
+        }
+    }
+    if stall == 0 {
+        return std::future::pending().await;
+    }
+    loop {
+        let notified = activity.notified();
+        if tokio::time::timeout(Duration::from_secs(stall), notified)
+            .await
+            .is_err()
+        {
+            return StreamTimeout::Stalled;
+        }
+    }
+}
+
+/// Resolves after `secs`; never resolves when `secs` is 0.
+async fn wait_timeout(secs: u64) {
+    if secs > 0 {
+        tokio::time::sleep(Duration::from_secs(secs)).await;
+        return;
+    }
+    std::future::pending().await
+}
+
+/// Resolves at the `--max-time` deadline of the run; never resolves without one.
+async fn wait_max_time(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Ends a reply cut off by `--max-time` with a one-sentence wrap-up from the model when
+/// `--wrap-up` asks for it, returning the note to show outside the reply.
+pub async fn close_truncated_output(
+    input: &Input,
+    client: &dyn Client,
+    text: &mut String,
+    abort_signal: AbortSignal,
+) -> Result<String> {
+    let (max_time, wrap_up) = {
+        let config = client.global_config().read();
+        (config.max_time.unwrap_or_default(), config.max_time_wrap_up)
+    };
+    if wrap_up {
+        let prompt = format!(
+            "You ran out of time while answering the request below. Reply with only one short sentence that wraps up the partial answer.\n\n<request>\n{}\n</request>\n\n<partial_answer>\n{}\n</partial_answer>",
+            input.text(),
+            text.trim()
+        );
+        let wrap_up_input =
+            Input::from_str(client.global_config(), &prompt, Some(input.role().clone()));
+        let output = abortable_run_with_spinner(
+            client.chat_completions(wrap_up_input),
+            "Wrapping up",
+            abort_signal,
+        )
+        .await?;
+        let sentence = strip_think_tag(&output.text).trim().to_string();
+        if !sentence.is_empty() {
+            if !text.is_empty() {
+                text.push_str(if text.ends_with('\n') { "\n" } else { "\n\n" });
+            }
+            text.push_str(&sentence);
+        }
+    }
+    Ok(format!("[truncated after {}]", format_duration(max_time)))
+}
+
+fn format_duration(duration: Duration) -> String {
+    if duration.subsec_millis() == 0 {
+        format!("{}s", duration.as_secs())
+    } else {
+        format!("{}ms", duration.as_millis())
+    }
+}
+
+/// Points at `meta_role` when a provider rejects the role the system prompt was sent as.
+fn explain_meta_role_error(err: anyhow::Error, model: &Model) -> anyhow::Error {
+    let message = format!("{err:#}").to_lowercase();
+    let role = match model.meta_role() {
+        MetaRole::System => "system",
+        MetaRole::Developer => "developer",
+        MetaRole::None => return err,
+    };
+    if message.contains("role") && message.contains(role) {
+        let hint = format!(
+            "'{}' may not accept the '{role}' role, set `meta_role` (system, developer or none) on the model",
+            model.id()
+        );
+        return err.context(hint);
+    }
+    err
+}
+
 pub fn catch_error(data: &Value, status: u16) -> Result<()> {
     if (200..300).contains(&status) {
         return Ok(());
@@ -762,3 +1342,53 @@ fn prompt_input_string(
     let text = text.prompt()?;
     Ok(text)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::sync::Notify;
+
+    #[tokio::test]
+    async fn test_wait_max_time() {
+        let deadline = Instant::now() + Duration::from_millis(200);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let started = Instant::now();
+        wait_max_time(Some(deadline)).await;
+        assert!(started.elapsed() < Duration::from_millis(150));
+        // Later requests of the run get no fresh budget
+        let started = Instant::now();
+        wait_max_time(Some(deadline)).await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+        let never = tokio::time::timeout(Duration::from_millis(50), wait_max_time(None));
+        assert!(never.await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wait_stream_timeout() {
+        let first_token = Arc::new(Notify::new());
+        let ret = wait_stream_timeout(Some(first_token.clone()), 1, Arc::new(Notify::new()), 0);
+        assert!(matches!(ret.await, StreamTimeout::FirstToken));
+
+        let activity = Arc::new(Notify::new());
+        let feed = {
+            let (first_token, activity) = (first_token.clone(), activity.clone());
+            async move {
+                first_token.notify_one();
+                for _ in 0..3 {
+                    tokio::time::sleep(Duration::from_millis(400)).await;
+                    activity.notify_one();
+                }
+                std::future::pending::<()>().await
+            }
+        };
+        let started = tokio::time::Instant::now();
+        tokio::select! {
+            ret = wait_stream_timeout(Some(first_token.clone()), 1, activity, 1) => {
+                assert!(matches!(ret, StreamTimeout::Stalled));
+            }
+            _ = feed => unreachable!(),
+        }
+        assert!(started.elapsed() >= Duration::from_millis(2000));
+    }
+}
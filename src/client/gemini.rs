@@ -1,50 +1,338 @@
 use super::vertexai::*;
 use super::*;
 
+use crate::{
+    config::{ensure_parent_exists, Config},
+    utils::{estimate_token_length, now_timestamp, sha256},
+};
+
 use anyhow::{Context, Result};
-use reqwest::RequestBuilder;
+use indexmap::IndexMap;
+use reqwest::Method;
+use reqwest::{Client as ReqwestClient, RequestBuilder};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
 
 const API_BASE: &str = "https://generativelanguage.googleapis.com/v1beta";
 
+const CONTEXT_CACHES_FILE_NAME: &str = "gemini-caches.yaml";
+const CONTEXT_CACHE_MIN_TOKENS: usize = 4096;
+const CONTEXT_CACHE_TTL: u64 = 3600;
+
 #[derive(Debug, Clone, Deserialize, Serialize, Default)]
 pub struct GeminiConfig {
     pub name: Option<String>,
     pub api_key: Option<String>,
     pub api_base: Option<String>,
+    pub context_cache: Option<GeminiContextCache>,
     #[serde(default)]
     pub models: Vec<ModelData>,
     pub patch: Option<RequestPatch>,
     pub extra: Option<ExtraConfig>,
 }
 
+/// Moves the conversation prefix into a `cachedContents` entry once it is large enough,
+/// so follow-up requests only pay for it at the cached rate.
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
+pub struct GeminiContextCache {
+    /// Smallest uncached prefix, in estimated tokens, worth creating a cache for
+    pub min_tokens: Option<usize>,
+    /// Seconds a cache lives after its last use
+    pub ttl: Option<u64>,
+}
+
 impl GeminiClient {
     config_get_fn!(api_key, get_api_key);
     config_get_fn!(api_base, get_api_base);
 
     pub const PROMPTS: [PromptAction<'static>; 1] = [("api_key", "API Key", None)];
+
+    fn api_base(&self) -> String {
+        self.get_api_base()
+            .unwrap_or_else(|_| API_BASE.to_string())
+            .trim_end_matches('/')
+            .to_string()
+    }
+
+    /// Builds the chat request as sent, so the cached prefix is the patched and redacted one.
+    async fn prepare_cached_chat_completions(
+        &self,
+        client: &ReqwestClient,
+        data: ChatCompletionsData,
+    ) -> Result<RequestBuilder> {
+        let mut request_data = prepare_chat_completions(self, data)?;
+        self.patch_request_data(&mut request_data).await?;
+        if let Some(cache_config) = &self.config.context_cache {
+            if let Err(err) = self
+                .apply_context_cache(client, cache_config, &mut request_data.body)
+                .await
+            {
+                warn!("Failed to use gemini context cache: {err}");
+            }
+        }
+        Ok(request_data.into_builder(client))
+    }
+
+    /// Replaces the longest cached prefix of `body` with a reference to its cache,
+    /// creating a cache for everything but the last message when enough of it is uncached.
+    async fn apply_context_cache(
+        &self,
+        client: &ReqwestClient,
+        cache_config: &GeminiContextCache,
+        body: &mut Value,
+    ) -> Result<()> {
+        let contents = match body["contents"].as_array() {
+            Some(v) if !v.is_empty() => v.clone(),
+            _ => return Ok(()),
+        };
+        let min_tokens = cache_config.min_tokens.unwrap_or(CONTEXT_CACHE_MIN_TOKENS);
+        let ttl = cache_config.ttl.unwrap_or(CONTEXT_CACHE_TTL);
+        let prefix_of = |len: usize| {
+            json!({
+                "systemInstruction": body.get("systemInstruction"),
+                "tools": body.get("tools"),
+                "contents": &contents[..len],
+            })
+        };
+        let key_of = |len: usize| {
+            sha256(&format!(
+                "{}:{}",
+                self.model.id(),
+                serde_json::to_string(&prefix_of(len)).unwrap_or_default()
+            ))
+        };
+
+        let caches_path = Config::local_path(CONTEXT_CACHES_FILE_NAME);
+        let caches = ContextCaches::load(&caches_path);
+        let now = now_timestamp();
+        let full_len = contents.len() - 1;
+        let hit = (0..=full_len)
+            .rev()
+            .find_map(|len| caches.0.get(&key_of(len)).map(|v| (len, v.clone())));
+        let cached_len = hit.as_ref().map(|(len, _)| *len).unwrap_or_default();
+        let uncached = if hit.is_some() {
+            serde_json::to_string(&contents[cached_len..full_len])?
+        } else {
+            serde_json::to_string(&prefix_of(full_len))?
+        };
+
+        let (len, entry) = if estimate_token_length(&uncached) >= min_tokens {
+            let entry = self
+                .create_context_cache(client, prefix_of(full_len), ttl)
+                .await?;
+            (full_len, entry)
+        } else if let Some((len, mut entry)) = hit {
+            if entry.expire_at - now < ttl as i64 / 2 {
+                self.refresh_context_cache(client, &entry.name, ttl).await?;
+                entry.expire_at = now + ttl as i64;
+            }
+            (len, entry)
+        } else {
+            return Ok(());
+        };
+        let key = key_of(len);
+        let saved_entry = entry.clone();
+        ContextCaches::update(&caches_path, |caches| {
+            caches.0.insert(key, saved_entry);
+        })?;
+
+        use_context_cache(body, &entry.name, len);
+        Ok(())
+    }
+
+    async fn create_context_cache(
+        &self,
+        client: &ReqwestClient,
+        prefix: Value,
+        ttl: u64,
+    ) -> Result<ContextCacheEntry> {
+        let body = context_cache_body(self.model.real_name(), prefix, ttl);
+        let url = format!("{}/cachedContents", self.api_base());
+        let data = self
+            .send_context_cache_request(client, Method::POST, RequestData::new(url, body))
+            .await?;
+        let name = data["name"]
+            .as_str()
+            .context("Invalid cachedContents response")?
+            .to_string();
+        debug!("Created gemini context cache {name}");
+        Ok(ContextCacheEntry {
+            name,
+            expire_at: now_timestamp() + ttl as i64,
+        })
+    }
+
+    async fn refresh_context_cache(
+        &self,
+        client: &ReqwestClient,
+        name: &str,
+        ttl: u64,
+    ) -> Result<()> {
+        let url = format!("{}/{name}?updateMask=ttl", self.api_base());
+        let body = json!({ "ttl": format!("{ttl}s") });
+        self.send_context_cache_request(client, Method::PATCH, RequestData::new(url, body))
+            .await?;
+        Ok(())
+    }
+
+    /// Sends a `cachedContents` call, recording it in the audit log as its own entry.
+    async fn send_context_cache_request(
+        &self,
+        client: &ReqwestClient,
+        method: Method,
+        mut request_data: RequestData,
+    ) -> Result<Value> {
+        request_data.header("x-goog-api-key", self.get_api_key()?);
+        let mut audit = AuditEntry::new(self.global_config(), "context_cache", false);
+        let ret = audit
+            .capture(async {
+                let RequestData { url, headers, body } = request_data;
+                debug!("Request {method} {url} {body}");
+                let mut builder = client.request(method, url).json(&body);
+                for (key, value) in headers {
+                    builder = builder.header(key, value);
+                }
+                record_audit_request(&builder);
+                let res = builder.send().await?;
+                let status = res.status();
+                let data: Value = res.json().await.unwrap_or_default();
+                if !status.is_success() {
+                    catch_error(&data, status.as_u16())?;
+                }
+                Ok(data)
+            })
+            .await;
+        match &ret {
+            Ok(data) => audit.set_response(data.clone()),
+            Err(err) => audit.set_error(err),
+        }
+        audit.write(self.global_config(), self.model())?;
+        ret
+    }
 }
 
-impl_client_trait!(
-    GeminiClient,
-    (
-        prepare_chat_completions,
-        gemini_chat_completions,
-        gemini_chat_completions_streaming
-    ),
-    (prepare_embeddings, embeddings),
-    (noop_prepare_rerank, noop_rerank),
-);
+#[async_trait::async_trait]
+impl Client for GeminiClient {
+    client_common_fns!();
+
+    async fn chat_completions_inner(
+        &self,
+        client: &ReqwestClient,
+        data: ChatCompletionsData,
+    ) -> Result<ChatCompletionsOutput> {
+        let builder = self.prepare_cached_chat_completions(client, data).await?;
+        gemini_chat_completions(builder, self.model()).await
+    }
+
+    async fn chat_completions_streaming_inner(
+        &self,
+        client: &ReqwestClient,
+        handler: &mut SseHandler,
+        data: ChatCompletionsData,
+    ) -> Result<()> {
+        let builder = self.prepare_cached_chat_completions(client, data).await?;
+        gemini_chat_completions_streaming(builder, handler, self.model()).await
+    }
+
+    async fn embeddings_inner(
+        &self,
+        client: &ReqwestClient,
+        data: &EmbeddingsData,
+    ) -> Result<EmbeddingsOutput> {
+        let request_data = prepare_embeddings(self, data)?;
+        let builder = self.request_builder(client, request_data).await?;
+        embeddings(builder, self.model()).await
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct ContextCacheEntry {
+    name: String,
+    expire_at: i64,
+}
+
+/// Caches created by earlier runs, keyed by a hash of the model and the cached prefix.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct ContextCaches(IndexMap<String, ContextCacheEntry>);
+
+impl ContextCaches {
+    fn load(path: &Path) -> Self {
+        let mut caches = Self::parse(&fs::read_to_string(path).unwrap_or_default());
+        caches.retain_unexpired();
+        caches
+    }
+
+    /// Changes the caches on disk under a file lock, so concurrent runs keep each other's.
+    fn update(path: &Path, f: impl FnOnce(&mut Self)) -> Result<()> {
+        ensure_parent_exists(path)?;
+        let write = || -> std::io::Result<()> {
+            let mut file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            file.lock()?;
+            let mut content = String::new();
+            file.read_to_string(&mut content)?;
+            let mut caches = Self::parse(&content);
+            f(&mut caches);
+            caches.retain_unexpired();
+            let content = serde_yaml::to_string(&caches).map_err(std::io::Error::other)?;
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(content.as_bytes())
+        };
+        write().with_context(|| format!("Failed to write to '{}'", path.display()))
+    }
+
+    fn parse(content: &str) -> Self {
+        serde_yaml::from_str(content).unwrap_or_default()
+    }
+
+    fn retain_unexpired(&mut self) {
+        // Leave a minute of slack so a cache doesn't expire mid-request
+        let now = now_timestamp() + 60;
+        self.0.retain(|_, v| v.expire_at > now);
+    }
+}
+
+/// The `cachedContents` body for a prefix, leaving out its empty parts.
+fn context_cache_body(model_name: &str, prefix: Value, ttl: u64) -> Value {
+    let mut body = json!({
+        "model": format!("models/{model_name}"),
+        "ttl": format!("{ttl}s"),
+    });
+    for (key, value) in prefix.as_object().into_iter().flatten() {
+        if !value.is_null() && value.as_array().map(|v| !v.is_empty()).unwrap_or(true) {
+            body[key] = value.clone();
+        }
+    }
+    body
+}
+
+/// Points the request at the cache holding its first `len` contents.
+fn use_context_cache(body: &mut Value, name: &str, len: usize) {
+    if let Some(obj) = body.as_object_mut() {
+        obj.remove("systemInstruction");
+        obj.remove("tools");
+    }
+    let contents: Vec<Value> = body["contents"]
+        .as_array()
+        .map(|v| v[len.min(v.len())..].to_vec())
+        .unwrap_or_default();
+    body["cachedContent"] = name.into();
+    body["contents"] = contents.into();
+}
 
 fn prepare_chat_completions(
     self_: &GeminiClient,
     data: ChatCompletionsData,
 ) -> Result<RequestData> {
     let api_key = self_.get_api_key()?;
-    let api_base = self_
-        .get_api_base()
-        .unwrap_or_else(|_| API_BASE.to_string());
 
     let func = match data.stream {
         true => "streamGenerateContent",
@@ -53,7 +341,7 @@ fn prepare_chat_completions(
 
     let url = format!(
         "{}/models/{}:{}",
-        api_base.trim_end_matches('/'),
+        self_.api_base(),
         self_.model.real_name(),
         func
     );
@@ -69,13 +357,10 @@ fn prepare_chat_completions(
 
 fn prepare_embeddings(self_: &GeminiClient, data: &EmbeddingsData) -> Result<RequestData> {
     let api_key = self_.get_api_key()?;
-    let api_base = self_
-        .get_api_base()
-        .unwrap_or_else(|_| API_BASE.to_string());
 
     let url = format!(
         "{}/models/{}:batchEmbedContents?key={}",
-        api_base.trim_end_matches('/'),
+        self_.api_base(),
         self_.model.real_name(),
         api_key
     );
@@ -109,6 +394,7 @@ fn prepare_embeddings(self_: &GeminiClient, data: &EmbeddingsData) -> Result<Req
 }
 
 async fn embeddings(builder: RequestBuilder, _model: &Model) -> Result<EmbeddingsOutput> {
+    record_audit_request(&builder);
     let res = builder.send().await?;
     let status = res.status();
     let data: Value = res.json().await?;
@@ -134,3 +420,66 @@ struct EmbeddingsResBody {
 struct EmbeddingsResBodyEmbedding {
     values: Vec<f32>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_use_context_cache() {
+        let prefix = json!({
+            "systemInstruction": { "parts": [{ "text": "Be brief" }] },
+            "tools": null,
+            "contents": [],
+        });
+        assert_eq!(
+            context_cache_body("gemini-2.0-flash", prefix, 600),
+            json!({
+                "model": "models/gemini-2.0-flash",
+                "ttl": "600s",
+                "systemInstruction": { "parts": [{ "text": "Be brief" }] },
+            })
+        );
+
+        let mut body = json!({
+            "systemInstruction": { "parts": [{ "text": "Be brief" }] },
+            "tools": [{ "functionDeclarations": [] }],
+            "contents": [{ "role": "user" }, { "role": "model" }, { "role": "user" }],
+            "generationConfig": { "temperature": 0.5 },
+        });
+        use_context_cache(&mut body, "cachedContents/abc", 2);
+        assert_eq!(
+            body,
+            json!({
+                "cachedContent": "cachedContents/abc",
+                "contents": [{ "role": "user" }],
+                "generationConfig": { "temperature": 0.5 },
+            })
+        );
+    }
+
+    #[test]
+    fn test_context_caches_update() {
+        let path =
+            std::env::temp_dir().join(format!("aichat-gemini-caches-{}.yaml", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let entry = |name: &str, expire_at: i64| ContextCacheEntry {
+            name: name.into(),
+            expire_at,
+        };
+        let later = now_timestamp() + 3600;
+        ContextCaches::update(&path, |v| {
+            v.0.insert("a".into(), entry("cachedContents/a", later));
+            v.0.insert("old".into(), entry("cachedContents/old", 0));
+        })
+        .unwrap();
+        ContextCaches::update(&path, |v| {
+            v.0.insert("b".into(), entry("cachedContents/b", later));
+        })
+        .unwrap();
+        let caches = ContextCaches::load(&path);
+        let keys: Vec<&str> = caches.0.keys().map(|v| v.as_str()).collect();
+        assert_eq!(keys, ["a", "b"]);
+        fs::remove_file(&path).unwrap();
+    }
+}
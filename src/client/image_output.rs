@@ -0,0 +1,169 @@
+use super::ChatCompletionsOutput;
+
+use crate::config::GlobalConfig;
+use crate::utils::{base64_decode, base64_encode, sha256, IS_STDOUT_TERMINAL};
+
+use anyhow::{Context, Result};
+use fancy_regex::Regex;
+use std::env;
+use std::io::Write;
+use std::path::Path;
+use std::sync::LazyLock;
+
+static IMAGE_REF_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"!\[generated image\]\(([^)]+)\)").unwrap());
+
+/// Kitty takes the image payload in chunks of at most this many bytes.
+const KITTY_CHUNK_SIZE: usize = 4096;
+
+/// An image a model returned as part of its reply, base64 encoded.
+#[derive(Debug, Clone)]
+pub struct OutputImage {
+    pub mime_type: String,
+    pub data: String,
+}
+
+impl OutputImage {
+    pub fn new(mime_type: &str, data: &str) -> Self {
+        Self {
+            mime_type: mime_type.to_string(),
+            data: data.to_string(),
+        }
+    }
+
+    /// Parses a `data:image/png;base64,...` url.
+    pub fn from_data_url(url: &str) -> Option<Self> {
+        let (meta, data) = url.strip_prefix("data:")?.split_once(',')?;
+        let mime_type = meta.strip_suffix(";base64")?;
+        Some(Self::new(mime_type, data))
+    }
+
+    fn extension(&self) -> &str {
+        match self.mime_type.as_str() {
+            "image/jpeg" => "jpg",
+            "image/webp" => "webp",
+            "image/gif" => "gif",
+            _ => "png",
+        }
+    }
+}
+
+/// Saves the images of a reply to `image_output_dir` and references them in its text, so they
+/// show up in the output and stay in the session history.
+pub fn attach_output_images(
+    config: &GlobalConfig,
+    output: &mut ChatCompletionsOutput,
+) -> Result<()> {
+    let images = std::mem::take(&mut output.images);
+    let references = save_output_images(config, images)?;
+    if !references.is_empty() && !output.text.is_empty() {
+        output.text.push_str("\n\n");
+    }
+    output.text.push_str(&references);
+    Ok(())
+}
+
+/// Saves the images and returns the markdown referencing them, empty without images.
+pub fn save_output_images(config: &GlobalConfig, images: Vec<OutputImage>) -> Result<String> {
+    if images.is_empty() {
+        return Ok(String::new());
+    }
+    let dir = config.read().images_dir();
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("Failed to create image directory '{}'", dir.display()))?;
+    let timestamp = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    let mut references = vec![];
+    for image in images {
+        let bytes = base64_decode(&image.data).context("Invalid image data in the response")?;
+        let hash = sha256(&image.data);
+        let path = dir.join(format!("{timestamp}-{}.{}", &hash[..8], image.extension()));
+        std::fs::write(&path, bytes)
+            .with_context(|| format!("Failed to save image to '{}'", path.display()))?;
+        references.push(format!("![generated image]({})", path.display()));
+    }
+    Ok(references.join("\n\n"))
+}
+
+/// Draws the images saved for a reply inline, in terminals that support it.
+pub fn preview_output_images(config: &GlobalConfig, text: &str) -> Result<()> {
+    if !*IS_STDOUT_TERMINAL || !config.read().image_preview {
+        return Ok(());
+    }
+    let Some(protocol) = ImageProtocol::detect() else {
+        return Ok(());
+    };
+    for captures in IMAGE_REF_RE.captures_iter(text).flatten() {
+        let path = Path::new(&captures[1]);
+        let Ok(bytes) = std::fs::read(path) else {
+            continue;
+        };
+        // Kitty only decodes PNG itself
+        if protocol == ImageProtocol::Kitty && path.extension().is_none_or(|v| v != "png") {
+            continue;
+        }
+        protocol.draw(&bytes)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ImageProtocol {
+    ITerm,
+    Kitty,
+}
+
+impl ImageProtocol {
+    fn detect() -> Option<Self> {
+        if env::var("KITTY_WINDOW_ID").is_ok() || env::var("TERM").is_ok_and(|v| v == "xterm-kitty")
+        {
+            return Some(Self::Kitty);
+        }
+        match env::var("TERM_PROGRAM").ok()?.as_str() {
+            "iTerm.app" | "WezTerm" => Some(Self::ITerm),
+            _ => None,
+        }
+    }
+
+    fn draw(&self, bytes: &[u8]) -> Result<()> {
+        let data = base64_encode(bytes);
+        let mut stdout = std::io::stdout();
+        match self {
+            Self::ITerm => {
+                write!(
+                    stdout,
+                    "\x1b]1337;File=inline=1;size={};preserveAspectRatio=1:{data}\x07",
+                    bytes.len()
+                )?;
+            }
+            Self::Kitty => {
+                let chunks: Vec<&[u8]> = data.as_bytes().chunks(KITTY_CHUNK_SIZE).collect();
+                for (i, chunk) in chunks.iter().enumerate() {
+                    let more = (i + 1 < chunks.len()) as u8;
+                    let chunk = std::str::from_utf8(chunk)?;
+                    if i == 0 {
+                        write!(stdout, "\x1b_Gf=100,a=T,m={more};{chunk}\x1b\\")?;
+                    } else {
+                        write!(stdout, "\x1b_Gm={more};{chunk}\x1b\\")?;
+                    }
+                }
+            }
+        }
+        writeln!(stdout)?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_data_url() {
+        let image = OutputImage::from_data_url("data:image/jpeg;base64,/9j/4AAQ").unwrap();
+        assert_eq!(image.mime_type, "image/jpeg");
+        assert_eq!(image.data, "/9j/4AAQ");
+        assert_eq!(image.extension(), "jpg");
+        assert!(OutputImage::from_data_url("https://example.com/a.png").is_none());
+    }
+}
@@ -0,0 +1,468 @@
+use super::*;
+
+use anyhow::{anyhow, Context};
+use candle_core::{quantized::gguf_file, DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::{
+    generation::LogitsProcessor,
+    models::{bert, quantized_llama, quantized_qwen2, quantized_qwen3},
+    utils::apply_repeat_penalty,
+};
+use minijinja::{context, Environment, Error as JinjaError, ErrorKind};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    fs::{read_to_string, File},
+    sync::{Arc, LazyLock, Once},
+};
+use tokenizers::{PaddingParams, Tokenizer, TruncationParams};
+use tokio::sync::mpsc::unbounded_channel;
+
+const DEFAULT_MAX_TOKENS: usize = 2048;
+const DEFAULT_TEMPERATURE: f64 = 0.8;
+const DEFAULT_SEED: u64 = 299792458;
+const REPEAT_PENALTY: f32 = 1.1;
+const REPEAT_LAST_N: usize = 64;
+const CHATML_TEMPLATE: &str = "{% for message in messages %}<|im_start|>{{ message.role }}\n{{ message.content }}<|im_end|>\n{% endfor %}{% if add_generation_prompt %}<|im_start|>assistant\n{% endif %}";
+const END_OF_TURN_TOKENS: [&str; 4] = ["<|im_end|>", "<|eot_id|>", "<|end|>", "<end_of_turn>"];
+
+type Shared<T> = LazyLock<Mutex<HashMap<PathBuf, Arc<Mutex<T>>>>>;
+
+static CHAT_MODELS: Shared<ChatModel> = LazyLock::new(Default::default);
+static EMBEDDING_MODELS: Shared<EmbeddingModel> = LazyLock::new(Default::default);
+
+pub fn init_threads(threads: Option<usize>) {
+    static INIT: Once = Once::new();
+    if let Some(threads) = threads {
+        INIT.call_once(|| {
+            let _ = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build_global();
+        });
+    }
+}
+
+pub async fn chat_completions(
+    path: PathBuf,
+    data: ChatCompletionsData,
+    max_tokens: Option<usize>,
+    handler: Option<&mut SseHandler>,
+) -> Result<ChatCompletionsOutput> {
+    let ChatCompletionsData {
+        messages,
+        temperature,
+        top_p,
+        seed,
+        stop,
+        ..
+    } = data;
+    let messages = convert_messages(messages)?;
+    let params = GenerateParams {
+        temperature: temperature.or(Some(DEFAULT_TEMPERATURE)),
+        top_p,
+        seed: seed.unwrap_or(DEFAULT_SEED),
+        stop: stop.unwrap_or_default(),
+        max_tokens: max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+    };
+    let (tx, mut rx) = unbounded_channel::<String>();
+    let task = tokio::task::spawn_blocking(move || {
+        let model = load_shared(&CHAT_MODELS, &path, ChatModel::load)?;
+        let mut model = model.lock();
+        // Generation stops as soon as the receiver is gone, e.g. when the request is aborted.
+        model.generate(&messages, &params, |text| tx.send(text.to_string()).is_ok())
+    });
+    if let Some(handler) = handler {
+        while let Some(text) = rx.recv().await {
+            handler.text(&text)?;
+        }
+    }
+    task.await?
+}
+
+pub async fn embeddings(path: PathBuf, texts: Vec<String>) -> Result<EmbeddingsOutput> {
+    tokio::task::spawn_blocking(move || {
+        let model = load_shared(&EMBEDDING_MODELS, &path, EmbeddingModel::load)?;
+        let model = model.lock();
+        model.embed(&texts)
+    })
+    .await?
+}
+
+fn load_shared<T>(
+    models: &Shared<T>,
+    path: &Path,
+    load: impl FnOnce(&Path) -> Result<T>,
+) -> Result<Arc<Mutex<T>>> {
+    let mut models = models.lock();
+    if let Some(model) = models.get(path) {
+        return Ok(model.clone());
+    }
+    let model = Arc::new(Mutex::new(load(path)?));
+    models.insert(path.to_path_buf(), model.clone());
+    Ok(model)
+}
+
+fn convert_messages(messages: Vec<Message>) -> Result<Vec<Value>> {
+    messages
+        .into_iter()
+        .map(|message| {
+            let Message { role, content } = message;
+            let content = match content {
+                MessageContent::Text(text) => text,
+                MessageContent::Array(list) => {
+                    let mut parts = vec![];
+                    for item in list {
+                        match item {
+                            MessageContentPart::Text { text } => parts.push(text),
+                            MessageContentPart::ImageUrl { .. } => {
+                                bail!("The local backend does not support images")
+                            }
+                        }
+                    }
+                    parts.join("\n\n")
+                }
+                MessageContent::ToolCalls(_) => {
+                    bail!("The local backend does not support function calling")
+                }
+            };
+            Ok(json!({ "role": role, "content": content }))
+        })
+        .collect()
+}
+
+/// Load `tokenizer.json`, or for a GGUF file, `<name>.tokenizer.json` or `tokenizer.json` beside it.
+fn load_tokenizer(path: &Path) -> Result<Tokenizer> {
+    let candidates = if path.extension().map(|v| v == "json").unwrap_or_default() {
+        vec![path.to_path_buf()]
+    } else {
+        let mut candidates = vec![path.with_extension("tokenizer.json")];
+        if let Some(parent) = path.parent() {
+            candidates.push(parent.join("tokenizer.json"));
+        }
+        candidates
+    };
+    let path = candidates
+        .iter()
+        .find(|v| v.exists())
+        .ok_or_else(|| anyhow!("No tokenizer found at '{}'", candidates[0].display()))?;
+    Tokenizer::from_file(path)
+        .map_err(|err| anyhow!("Invalid tokenizer at '{}': {err}", path.display()))
+}
+
+struct GenerateParams {
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    seed: u64,
+    stop: Vec<String>,
+    max_tokens: usize,
+}
+
+enum ChatWeights {
+    Llama(quantized_llama::ModelWeights),
+    Qwen2(quantized_qwen2::ModelWeights),
+    Qwen3(quantized_qwen3::ModelWeights),
+}
+
+impl ChatWeights {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> candle_core::Result<Tensor> {
+        match self {
+            ChatWeights::Llama(v) => v.forward(input, index_pos),
+            ChatWeights::Qwen2(v) => v.forward(input, index_pos),
+            ChatWeights::Qwen3(v) => v.forward(input, index_pos),
+        }
+    }
+
+    fn clear_kv_cache(&mut self) {
+        // llama and qwen2 reset their cache whenever a sequence starts at position 0
+        if let ChatWeights::Qwen3(v) = self {
+            v.clear_kv_cache()
+        }
+    }
+}
+
+struct ChatModel {
+    weights: ChatWeights,
+    tokenizer: Tokenizer,
+    template: String,
+    bos_token: String,
+    eos_token: String,
+    eos_token_ids: Vec<u32>,
+    context_length: Option<usize>,
+    device: Device,
+}
+
+impl ChatModel {
+    fn load(path: &Path) -> Result<Self> {
+        let device = Device::Cpu;
+        let mut file = File::open(path)
+            .with_context(|| format!("Failed to open local model at '{}'", path.display()))?;
+        let content = gguf_file::Content::read(&mut file)
+            .map_err(|err| anyhow!("Invalid GGUF file at '{}': {err}", path.display()))?;
+        let metadata_str = |key: &str| {
+            content
+                .metadata
+                .get(key)
+                .and_then(|v| v.to_string().ok())
+                .cloned()
+        };
+        let metadata_u32 = |key: &str| content.metadata.get(key).and_then(|v| v.to_u32().ok());
+        let arch = metadata_str("general.architecture").unwrap_or_default();
+        let template =
+            metadata_str("tokenizer.chat_template").unwrap_or_else(|| CHATML_TEMPLATE.into());
+        let bos_token_id = metadata_u32("tokenizer.ggml.bos_token_id");
+        let eos_token_id = metadata_u32("tokenizer.ggml.eos_token_id");
+        let context_length = metadata_u32(&format!("{arch}.context_length")).map(|v| v as usize);
+        let weights = match arch.as_str() {
+            "llama" => ChatWeights::Llama(quantized_llama::ModelWeights::from_gguf(
+                content, &mut file, &device,
+            )?),
+            "qwen2" => ChatWeights::Qwen2(quantized_qwen2::ModelWeights::from_gguf(
+                content, &mut file, &device,
+            )?),
+            "qwen3" => ChatWeights::Qwen3(quantized_qwen3::ModelWeights::from_gguf(
+                content, &mut file, &device,
+            )?),
+            _ => bail!("Unsupported local model architecture '{arch}'"),
+        };
+        let tokenizer = load_tokenizer(path)?;
+        let token = |id: Option<u32>| {
+            id.and_then(|id| tokenizer.id_to_token(id))
+                .unwrap_or_default()
+        };
+        let (bos_token, eos_token) = (token(bos_token_id), token(eos_token_id));
+        let mut eos_token_ids: Vec<u32> = eos_token_id.into_iter().collect();
+        eos_token_ids.extend(
+            END_OF_TURN_TOKENS
+                .iter()
+                .filter_map(|v| tokenizer.token_to_id(v)),
+        );
+        Ok(Self {
+            weights,
+            tokenizer,
+            template,
+            bos_token,
+            eos_token,
+            eos_token_ids,
+            context_length,
+            device,
+        })
+    }
+
+    fn render_prompt(&self, messages: &[Value]) -> Result<String> {
+        let mut env = Environment::new();
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function("raise_exception", |message: String| -> Result<String, JinjaError> {
+            Err(JinjaError::new(ErrorKind::InvalidOperation, message))
+        });
+        env.add_function("strftime_now", |format: String| {
+            chrono::Local::now().format(&format).to_string()
+        });
+        let template = env
+            .template_from_str(&self.template)
+            .context("Invalid chat template")?;
+        let prompt = template
+            .render(context! {
+                messages => messages,
+                add_generation_prompt => true,
+                bos_token => &self.bos_token,
+                eos_token => &self.eos_token,
+            })
+            .context("Failed to render chat template")?;
+        Ok(prompt)
+    }
+
+    fn generate(
+        &mut self,
+        messages: &[Value],
+        params: &GenerateParams,
+        mut on_text: impl FnMut(&str) -> bool,
+    ) -> Result<ChatCompletionsOutput> {
+        let prompt = self.render_prompt(messages)?;
+        let prompt_tokens = self
+            .tokenizer
+            .encode(prompt, false)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .to_vec();
+        let mut max_tokens = params.max_tokens;
+        if let Some(context_length) = self.context_length {
+            if prompt_tokens.len() >= context_length {
+                bail!("The prompt exceeds the model context length ({context_length} tokens)");
+            }
+            max_tokens = max_tokens.min(context_length - prompt_tokens.len());
+        }
+
+        let mut logits_processor =
+            LogitsProcessor::new(params.seed, params.temperature, params.top_p);
+        let mut stop_matcher = StopMatcher::new(&params.stop);
+        let mut tokens = prompt_tokens.clone();
+        let mut generated = vec![];
+        let mut text = String::new();
+        let mut index_pos = 0;
+        self.weights.clear_kv_cache();
+        for _ in 0..max_tokens {
+            let input = &tokens[index_pos..];
+            let input_len = input.len();
+            let input = Tensor::new(input, &self.device)?.unsqueeze(0)?;
+            let logits = self
+                .weights
+                .forward(&input, index_pos)?
+                .squeeze(0)?
+                .to_dtype(DType::F32)?;
+            index_pos += input_len;
+            let start = tokens.len().saturating_sub(REPEAT_LAST_N);
+            let logits = apply_repeat_penalty(&logits, REPEAT_PENALTY, &tokens[start..])?;
+            let next_token = logits_processor.sample(&logits)?;
+            if self.eos_token_ids.contains(&next_token) {
+                break;
+            }
+            tokens.push(next_token);
+            generated.push(next_token);
+            text = self
+                .tokenizer
+                .decode(&generated, true)
+                .map_err(anyhow::Error::msg)?;
+            let (delta, stopped) = stop_matcher.feed(&text, false);
+            if !delta.is_empty() && !on_text(delta) {
+                break;
+            }
+            if stopped {
+                break;
+            }
+        }
+        let (delta, _) = stop_matcher.feed(&text, true);
+        if !delta.is_empty() {
+            on_text(delta);
+        }
+        Ok(ChatCompletionsOutput {
+            text: text[..stop_matcher.emitted].to_string(),
+            tool_calls: vec![],
+            id: None,
+            input_tokens: Some(prompt_tokens.len() as u64),
+            output_tokens: Some(generated.len() as u64),
+            logprobs: None,
+            system_fingerprint: None,
+            images: vec![],
+            sources: None,
+        })
+    }
+}
+
+/// Tracks how much generated text is safe to emit, holding back anything that
+/// may turn out to be the start of a stop sequence.
+struct StopMatcher<'a> {
+    stop: &'a [String],
+    emitted: usize,
+    stopped: bool,
+}
+
+impl<'a> StopMatcher<'a> {
+    fn new(stop: &'a [String]) -> Self {
+        Self {
+            stop,
+            emitted: 0,
+            stopped: false,
+        }
+    }
+
+    fn feed<'b>(&mut self, text: &'b str, finished: bool) -> (&'b str, bool) {
+        if self.stopped || (!finished && text.ends_with('\u{FFFD}')) {
+            return ("", self.stopped);
+        }
+        let mut end = text.len();
+        if let Some(pos) = self
+            .stop
+            .iter()
+            .filter(|v| !v.is_empty())
+            .filter_map(|v| text.find(v.as_str()))
+            .min()
+        {
+            end = pos;
+            self.stopped = true;
+        } else if !finished {
+            let holdback = self
+                .stop
+                .iter()
+                .filter_map(|v| {
+                    (1..v.len())
+                        .rev()
+                        .filter(|i| v.is_char_boundary(*i))
+                        .find(|i| text.ends_with(&v[..*i]))
+                })
+                .max()
+                .unwrap_or_default();
+            end -= holdback;
+        }
+        if end <= self.emitted {
+            return ("", self.stopped);
+        }
+        let delta = &text[self.emitted..end];
+        self.emitted = end;
+        (delta, self.stopped)
+    }
+}
+
+struct EmbeddingModel {
+    model: bert::BertModel,
+    tokenizer: Tokenizer,
+    device: Device,
+}
+
+impl EmbeddingModel {
+    fn load(path: &Path) -> Result<Self> {
+        let device = Device::Cpu;
+        let config_path = path.join("config.json");
+        let config = read_to_string(&config_path)
+            .with_context(|| format!("Failed to read '{}'", config_path.display()))?;
+        let config: bert::Config = serde_json::from_str(&config)
+            .with_context(|| format!("Invalid model config at '{}'", config_path.display()))?;
+        let weights_path = path.join("model.safetensors");
+        let vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(&[weights_path], DType::F32, &device)?
+        };
+        let model = bert::BertModel::load(vb, &config)?;
+        let mut tokenizer = load_tokenizer(&path.join("tokenizer.json"))?;
+        tokenizer
+            .with_padding(Some(PaddingParams::default()))
+            .with_truncation(Some(TruncationParams {
+                max_length: config.max_position_embeddings,
+                ..Default::default()
+            }))
+            .map_err(anyhow::Error::msg)?;
+        Ok(Self {
+            model,
+            tokenizer,
+            device,
+        })
+    }
+
+    fn embed(&self, texts: &[String]) -> Result<EmbeddingsOutput> {
+        let encodings = self
+            .tokenizer
+            .encode_batch(texts.to_vec(), true)
+            .map_err(anyhow::Error::msg)?;
+        let to_tensor = |f: fn(&tokenizers::Encoding) -> &[u32]| -> Result<Tensor> {
+            let rows = encodings
+                .iter()
+                .map(|v| Ok(Tensor::new(f(v), &self.device)?))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Tensor::stack(&rows, 0)?)
+        };
+        let input_ids = to_tensor(|v| v.get_ids())?;
+        let type_ids = to_tensor(|v| v.get_type_ids())?;
+        let attention_mask = to_tensor(|v| v.get_attention_mask())?;
+        let hidden = self
+            .model
+            .forward(&input_ids, &type_ids, Some(&attention_mask))?;
+
+        // Mean pooling over non-padding tokens, then L2 normalization
+        let mask = attention_mask.to_dtype(DType::F32)?.unsqueeze(2)?;
+        let summed = hidden.broadcast_mul(&mask)?.sum(1)?;
+        let counts = mask.sum(1)?;
+        let pooled = summed.broadcast_div(&counts)?;
+        let norm = pooled.sqr()?.sum_keepdim(1)?.sqrt()?;
+        let output = pooled.broadcast_div(&norm)?.to_vec2::<f32>()?;
+        Ok(output)
+    }
+}
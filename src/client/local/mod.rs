@@ -0,0 +1,155 @@
+#[cfg(feature = "local")]
+mod engine;
+
+use super::*;
+
+use crate::config::Config;
+
+use anyhow::{bail, Result};
+use reqwest::Client as ReqwestClient;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+const MODELS_DIR_NAME: &str = "models";
+const RERANKERS_DIR_NAME: &str = "rerankers";
+
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
+pub struct LocalConfig {
+    pub name: Option<String>,
+    pub models_dir: Option<String>,
+    pub threads: Option<usize>,
+    #[serde(default)]
+    pub models: Vec<ModelData>,
+    pub patch: Option<RequestPatch>,
+    pub extra: Option<ExtraConfig>,
+}
+
+impl LocalClient {
+    config_get_fn!(models_dir, get_models_dir);
+
+    pub const PROMPTS: [PromptAction<'static>; 1] =
+        [("models_dir", "Models Dir (optional):", None)];
+
+    fn models_dir(&self) -> PathBuf {
+        match self.get_models_dir() {
+            Ok(v) => PathBuf::from(v),
+            Err(_) => Config::local_path(MODELS_DIR_NAME),
+        }
+    }
+
+    /// Chat models are GGUF files, `<models_dir>/<name>.gguf` unless `real_name` points elsewhere.
+    fn chat_model_path(&self) -> PathBuf {
+        let name = self.model.real_name();
+        let path = self.models_dir().join(name);
+        if path.extension().is_some() {
+            path
+        } else {
+            path.with_extension("gguf")
+        }
+    }
+
+    /// Embedding models are directories with `config.json`, `model.safetensors` and `tokenizer.json`.
+    fn embedding_model_path(&self) -> PathBuf {
+        self.models_dir().join(self.model.real_name())
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for LocalClient {
+    client_common_fns!();
+
+    async fn chat_completions_inner(
+        &self,
+        _client: &ReqwestClient,
+        data: ChatCompletionsData,
+    ) -> Result<ChatCompletionsOutput> {
+        let path = self.chat_model_path();
+        check_model_path(&path)?;
+        local_chat_completions(self, path, data, None).await
+    }
+
+    async fn chat_completions_streaming_inner(
+        &self,
+        _client: &ReqwestClient,
+        handler: &mut SseHandler,
+        data: ChatCompletionsData,
+    ) -> Result<()> {
+        let path = self.chat_model_path();
+        check_model_path(&path)?;
+        local_chat_completions(self, path, data, Some(handler)).await?;
+        Ok(())
+    }
+
+    async fn embeddings_inner(
+        &self,
+        _client: &ReqwestClient,
+        data: &EmbeddingsData,
+    ) -> Result<EmbeddingsOutput> {
+        let path = self.embedding_model_path();
+        check_model_path(&path)?;
+        local_embeddings(self, path, data).await
+    }
+
+    /// Rerankers are ONNX cross-encoders, downloaded into `<models_dir>/rerankers` on first use.
+    async fn rerank_inner(
+        &self,
+        _client: &ReqwestClient,
+        data: &RerankData,
+    ) -> Result<RerankOutput> {
+        let name = self.model.real_name().to_string();
+        let cache_dir = self.models_dir().join(RERANKERS_DIR_NAME);
+        local_embedding::onnx_rerank(name, cache_dir, self.config.threads, data).await
+    }
+}
+
+fn check_model_path(path: &Path) -> Result<()> {
+    if !path.exists() {
+        bail!("Local model not found at '{}'", path.display());
+    }
+    Ok(())
+}
+
+#[cfg(feature = "local")]
+async fn local_chat_completions(
+    client: &LocalClient,
+    path: PathBuf,
+    data: ChatCompletionsData,
+    handler: Option<&mut SseHandler>,
+) -> Result<ChatCompletionsOutput> {
+    engine::init_threads(client.config.threads);
+    let max_tokens = client
+        .model
+        .max_tokens_param()
+        .or(client.model.max_output_tokens())
+        .and_then(|v| usize::try_from(v).ok());
+    engine::chat_completions(path, data, max_tokens, handler).await
+}
+
+#[cfg(not(feature = "local"))]
+async fn local_chat_completions(
+    _client: &LocalClient,
+    _path: PathBuf,
+    _data: ChatCompletionsData,
+    _handler: Option<&mut SseHandler>,
+) -> Result<ChatCompletionsOutput> {
+    bail!("Local inference is unavailable; rebuild aichat with `--features local`")
+}
+
+#[cfg(feature = "local")]
+async fn local_embeddings(
+    client: &LocalClient,
+    path: PathBuf,
+    data: &EmbeddingsData,
+) -> Result<EmbeddingsOutput> {
+    engine::init_threads(client.config.threads);
+    engine::embeddings(path, data.texts.clone()).await
+}
+
+#[cfg(not(feature = "local"))]
+async fn local_embeddings(
+    _client: &LocalClient,
+    _path: PathBuf,
+    _data: &EmbeddingsData,
+) -> Result<EmbeddingsOutput> {
+    bail!("Local inference is unavailable; rebuild aichat with `--features local`")
+}
@@ -0,0 +1,235 @@
+use super::*;
+
+use crate::config::Config;
+
+use anyhow::{bail, Result};
+use reqwest::Client as ReqwestClient;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+const CACHE_DIR_NAME: &str = "models/embeddings";
+
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
+pub struct LocalEmbeddingConfig {
+    pub name: Option<String>,
+    pub cache_dir: Option<String>,
+    pub threads: Option<usize>,
+    #[serde(default)]
+    pub models: Vec<ModelData>,
+    pub patch: Option<RequestPatch>,
+    pub extra: Option<ExtraConfig>,
+}
+
+impl LocalEmbeddingClient {
+    config_get_fn!(cache_dir, get_cache_dir);
+
+    pub const PROMPTS: [PromptAction<'static>; 1] =
+        [("cache_dir", "Models Cache Dir (optional):", None)];
+
+    /// Where ONNX models are downloaded to on first use.
+    fn cache_dir(&self) -> PathBuf {
+        match self.get_cache_dir() {
+            Ok(v) => PathBuf::from(v),
+            Err(_) => Config::local_path(CACHE_DIR_NAME),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for LocalEmbeddingClient {
+    client_common_fns!();
+
+    async fn chat_completions_inner(
+        &self,
+        _client: &ReqwestClient,
+        _data: ChatCompletionsData,
+    ) -> Result<ChatCompletionsOutput> {
+        bail!("The client doesn't support chat-completions api")
+    }
+
+    async fn chat_completions_streaming_inner(
+        &self,
+        _client: &ReqwestClient,
+        _handler: &mut SseHandler,
+        _data: ChatCompletionsData,
+    ) -> Result<()> {
+        bail!("The client doesn't support chat-completions api")
+    }
+
+    async fn embeddings_inner(
+        &self,
+        _client: &ReqwestClient,
+        data: &EmbeddingsData,
+    ) -> Result<EmbeddingsOutput> {
+        let name = self.model.real_name().to_string();
+        onnx_embeddings(name, self.cache_dir(), self.config.threads, data).await
+    }
+
+    async fn rerank_inner(
+        &self,
+        _client: &ReqwestClient,
+        data: &RerankData,
+    ) -> Result<RerankOutput> {
+        let name = self.model.real_name().to_string();
+        onnx_rerank(name, self.cache_dir(), self.config.threads, data).await
+    }
+}
+
+#[cfg(feature = "local-embedding")]
+async fn onnx_embeddings(
+    name: String,
+    cache_dir: PathBuf,
+    threads: Option<usize>,
+    data: &EmbeddingsData,
+) -> Result<EmbeddingsOutput> {
+    use crate::utils::IS_STDOUT_TERMINAL;
+
+    use anyhow::{anyhow, Context};
+    use fastembed::{EmbeddingModel, TextEmbedding, TextInitOptions};
+    use parking_lot::Mutex;
+    use std::{
+        collections::HashMap,
+        sync::{Arc, LazyLock},
+    };
+
+    type Models = LazyLock<Mutex<HashMap<String, Arc<Mutex<TextEmbedding>>>>>;
+    static MODELS: Models = LazyLock::new(Default::default);
+
+    let texts = data.texts.clone();
+    tokio::task::spawn_blocking(move || {
+        let model = {
+            let mut models = MODELS.lock();
+            match models.get(&name) {
+                Some(model) => model.clone(),
+                None => {
+                    let info = TextEmbedding::list_supported_models()
+                        .into_iter()
+                        .find(|v| {
+                            let short_name = v.model_code.rsplit('/').next().unwrap_or_default();
+                            v.model_code.eq_ignore_ascii_case(&name)
+                                || short_name.eq_ignore_ascii_case(&name)
+                        })
+                        .ok_or_else(|| anyhow!("Unsupported local embedding model '{name}'"))?;
+                    let model: EmbeddingModel = info.model;
+                    let mut options = TextInitOptions::new(model)
+                        .with_cache_dir(cache_dir.clone())
+                        .with_show_download_progress(*IS_STDOUT_TERMINAL);
+                    if let Some(threads) = threads {
+                        options = options.with_intra_threads(threads);
+                    }
+                    let model = TextEmbedding::try_new(options).with_context(|| {
+                        format!(
+                            "Failed to load '{}' into '{}'",
+                            info.model_code,
+                            cache_dir.display()
+                        )
+                    })?;
+                    let model = Arc::new(Mutex::new(model));
+                    models.insert(name, model.clone());
+                    model
+                }
+            }
+        };
+        let mut model = model.lock();
+        model.embed(&texts, None)
+    })
+    .await?
+}
+
+#[cfg(not(feature = "local-embedding"))]
+async fn onnx_embeddings(
+    _name: String,
+    _cache_dir: PathBuf,
+    _threads: Option<usize>,
+    _data: &EmbeddingsData,
+) -> Result<EmbeddingsOutput> {
+    bail!("Local embeddings are unavailable; rebuild aichat with `--features local-embedding`")
+}
+
+/// Scores the documents with an ONNX cross-encoder such as `bge-reranker-base`, downloading it
+/// into `cache_dir` on first use.
+#[cfg(feature = "local-embedding")]
+pub(super) async fn onnx_rerank(
+    name: String,
+    cache_dir: PathBuf,
+    threads: Option<usize>,
+    data: &RerankData,
+) -> Result<RerankOutput> {
+    use crate::utils::IS_STDOUT_TERMINAL;
+
+    use anyhow::{anyhow, Context};
+    use fastembed::{RerankInitOptions, RerankerModel, TextRerank};
+    use parking_lot::Mutex;
+    use std::{
+        collections::HashMap,
+        sync::{Arc, LazyLock},
+    };
+
+    type Models = LazyLock<Mutex<HashMap<String, Arc<Mutex<TextRerank>>>>>;
+    static MODELS: Models = LazyLock::new(Default::default);
+
+    let (query, documents, top_n) = (data.query.clone(), data.documents.clone(), data.top_n);
+    tokio::task::spawn_blocking(move || {
+        let model = {
+            let mut models = MODELS.lock();
+            match models.get(&name) {
+                Some(model) => model.clone(),
+                None => {
+                    let info = TextRerank::list_supported_models()
+                        .into_iter()
+                        .find(|v| {
+                            let short_name = v.model_code.rsplit('/').next().unwrap_or_default();
+                            v.model_code.eq_ignore_ascii_case(&name)
+                                || short_name.eq_ignore_ascii_case(&name)
+                        })
+                        .ok_or_else(|| anyhow!("Unsupported local reranker model '{name}'"))?;
+                    let model: RerankerModel = info.model;
+                    let mut options = RerankInitOptions::new(model)
+                        .with_cache_dir(cache_dir.clone())
+                        .with_show_download_progress(*IS_STDOUT_TERMINAL);
+                    if let Some(threads) = threads {
+                        options = options.with_intra_threads(threads);
+                    }
+                    let model = TextRerank::try_new(options).with_context(|| {
+                        format!(
+                            "Failed to load '{}' into '{}'",
+                            info.model_code,
+                            cache_dir.display()
+                        )
+                    })?;
+                    let model = Arc::new(Mutex::new(model));
+                    models.insert(name, model.clone());
+                    model
+                }
+            }
+        };
+        let mut model = model.lock();
+        let results = model.rerank(
+            query.as_str(),
+            documents.iter().map(|v| v.as_str()).collect::<Vec<_>>(),
+            false,
+            None,
+        )?;
+        // The logits of the cross-encoder, squashed into 0..1 like rerank APIs return
+        let output = results
+            .into_iter()
+            .take(top_n)
+            .map(|v| RerankResult {
+                index: v.index,
+                relevance_score: 1.0 / (1.0 + (-v.score as f64).exp()),
+            })
+            .collect();
+        Ok(output)
+    })
+    .await?
+}
+
+#[cfg(not(feature = "local-embedding"))]
+pub(super) async fn onnx_rerank(
+    _name: String,
+    _cache_dir: PathBuf,
+    _threads: Option<usize>,
+    _data: &RerankData,
+) -> Result<RerankOutput> {
+    bail!("Local rerankers are unavailable; rebuild aichat with `--features local-embedding`")
+}
@@ -83,7 +83,11 @@ macro_rules! register_client {
 
         pub fn list_client_types() -> Vec<&'static str> {
             let mut client_types: Vec<_> = vec![$($client::NAME,)+];
-            client_types.extend($crate::client::OPENAI_COMPATIBLE_PROVIDERS.iter().map(|(name, _)| *name));
+            for (name, _) in $crate::client::OPENAI_COMPATIBLE_PROVIDERS.iter() {
+                if !client_types.contains(name) {
+                    client_types.push(name);
+                }
+            }
             client_types
         }
 
@@ -115,6 +119,22 @@ macro_rules! register_client {
             names.iter().collect()
         }
 
+        pub fn client_proxy(config: &$crate::config::Config, client_name: &str) -> Option<String> {
+            config.clients.iter().find_map(|v| match v {
+                $(ClientConfig::$config(c) if $client::name(c) == client_name => {
+                    Some(c.extra.as_ref().and_then(|v| v.proxy.clone()))
+                })+
+                _ => None,
+            })?
+        }
+
+        pub fn client_type(config: &$crate::config::Config, client_name: &str) -> Option<&'static str> {
+            config.clients.iter().find_map(|v| match v {
+                $(ClientConfig::$config(c) if $client::name(c) == client_name => Some($client::NAME),)+
+                _ => None,
+            })
+        }
+
         static ALL_MODELS: std::sync::OnceLock<Vec<$crate::client::Model>> = std::sync::OnceLock::new();
 
         pub fn list_all_models(config: &$crate::config::Config) -> Vec<&'static $crate::client::Model> {
@@ -126,6 +146,10 @@ macro_rules! register_client {
                         $(ClientConfig::$config(c) => $client::list_models(c),)+
                         ClientConfig::Unknown => vec![],
                     })
+                    .map(|mut model| {
+                        model.apply_config_overrides(config);
+                        model
+                    })
                     .collect()
             });
             models.iter().collect()
@@ -184,7 +208,7 @@ macro_rules! impl_client_trait {
                 data: $crate::client::ChatCompletionsData,
             ) -> anyhow::Result<$crate::client::ChatCompletionsOutput> {
                 let request_data = $prepare_chat_completions(self, data)?;
-                let builder = self.request_builder(client, request_data);
+                let builder = self.request_builder(client, request_data).await?;
                 $chat_completions(builder, self.model()).await
             }
 
@@ -195,7 +219,7 @@ macro_rules! impl_client_trait {
                 data: $crate::client::ChatCompletionsData,
             ) -> Result<()> {
                 let request_data = $prepare_chat_completions(self, data)?;
-                let builder = self.request_builder(client, request_data);
+                let builder = self.request_builder(client, request_data).await?;
                 $chat_completions_streaming(builder, handler, self.model()).await
             }
 
@@ -205,7 +229,7 @@ macro_rules! impl_client_trait {
                 data: &$crate::client::EmbeddingsData,
             ) -> Result<$crate::client::EmbeddingsOutput> {
                 let request_data = $prepare_embeddings(self, data)?;
-                let builder = self.request_builder(client, request_data);
+                let builder = self.request_builder(client, request_data).await?;
                 $embeddings(builder, self.model()).await
             }
 
@@ -215,7 +239,7 @@ macro_rules! impl_client_trait {
                 data: &$crate::client::RerankData,
             ) -> Result<$crate::client::RerankOutput> {
                 let request_data = $prepare_rerank(self, data)?;
-                let builder = self.request_builder(client, request_data);
+                let builder = self.request_builder(client, request_data).await?;
                 $rerank(builder, self.model()).await
             }
         }
@@ -226,13 +250,12 @@ macro_rules! impl_client_trait {
 macro_rules! config_get_fn {
     ($field_name:ident, $fn_name:ident) => {
         fn $fn_name(&self) -> anyhow::Result<String> {
-            let env_prefix = Self::name(&self.config);
-            let env_name =
-                format!("{}_{}", env_prefix, stringify!($field_name)).to_ascii_uppercase();
-            std::env::var(&env_name)
-                .ok()
-                .or_else(|| self.config.$field_name.clone())
-                .ok_or_else(|| anyhow::anyhow!("Miss '{}'", stringify!($field_name)))
+            $crate::client::client_config_value(
+                Self::name(&self.config),
+                stringify!($field_name),
+                &self.config.$field_name,
+            )
+            .ok_or_else(|| anyhow::anyhow!("Miss '{}'", stringify!($field_name)))
         }
     };
 }
@@ -1,4 +1,4 @@
-use super::Model;
+use super::{MetaRole, Model};
 
 use crate::{function::ToolResult, multiline_text, utils::dimmed_text};
 
@@ -56,6 +56,8 @@ impl Message {
 #[serde(rename_all = "snake_case")]
 pub enum MessageRole {
     System,
+    /// The system role as OpenAI's o-series models name it
+    Developer,
     Assistant,
     User,
     Tool,
@@ -64,7 +66,7 @@ pub enum MessageRole {
 #[allow(dead_code)]
 impl MessageRole {
     pub fn is_system(&self) -> bool {
-        matches!(self, MessageRole::System)
+        matches!(self, MessageRole::System | MessageRole::Developer)
     }
 
     pub fn is_user(&self) -> bool {
@@ -218,11 +220,22 @@ pub fn patch_messages(messages: &mut Vec<Message>, model: &Model) {
             );
         }
     }
-    if model.no_system_message() && messages[0].role.is_system() {
-        let system_message = messages.remove(0);
-        if let (Some(message), system) = (messages.get_mut(0), system_message.content) {
-            message.merge_system(system);
+    // Sessions keep whichever meta-role they were written with, so translate on every request
+    let role = match model.meta_role() {
+        MetaRole::System => MessageRole::System,
+        MetaRole::Developer => MessageRole::Developer,
+        MetaRole::None => {
+            if messages[0].role.is_system() {
+                let system_message = messages.remove(0);
+                if let (Some(message), system) = (messages.get_mut(0), system_message.content) {
+                    message.merge_system(system);
+                }
+            }
+            return;
         }
+    };
+    for message in messages.iter_mut().filter(|v| v.role.is_system()) {
+        message.role = role;
     }
 }
 
@@ -233,3 +246,102 @@ pub fn extract_system_message(messages: &mut Vec<Message>) -> Option<String> {
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model_with(data: serde_json::Value) -> Model {
+        let mut model = Model::new("openai", "my-model");
+        let mut value = serde_json::json!({ "name": "my-model" });
+        value
+            .as_object_mut()
+            .unwrap()
+            .extend(data.as_object().unwrap().clone());
+        *model.data_mut() = serde_json::from_value(value).unwrap();
+        model
+    }
+
+    fn patched(data: serde_json::Value, system: Option<&str>) -> Vec<(MessageRole, String)> {
+        let mut messages = vec![];
+        if let Some(system) = system {
+            messages.push(Message::new(
+                MessageRole::System,
+                MessageContent::Text(system.into()),
+            ));
+        }
+        messages.push(Message::new(
+            MessageRole::User,
+            MessageContent::Text("hi".into()),
+        ));
+        patch_messages(&mut messages, &model_with(data));
+        messages
+            .into_iter()
+            .map(|v| (v.role, v.content.to_text()))
+            .collect()
+    }
+
+    #[test]
+    fn test_patch_messages_default() {
+        assert_eq!(
+            patched(serde_json::json!({}), Some("Be brief")),
+            [
+                (MessageRole::System, "Be brief".into()),
+                (MessageRole::User, "hi".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_patch_messages_system_prompt_prefix() {
+        let data = serde_json::json!({ "system_prompt_prefix": "detailed thinking on" });
+        assert_eq!(
+            patched(data.clone(), Some("Be brief")),
+            [
+                (
+                    MessageRole::System,
+                    "detailed thinking on\n\nBe brief".into()
+                ),
+                (MessageRole::User, "hi".into())
+            ]
+        );
+        assert_eq!(
+            patched(data, None),
+            [
+                (MessageRole::System, "detailed thinking on".into()),
+                (MessageRole::User, "hi".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_patch_messages_meta_role() {
+        let data = serde_json::json!({ "meta_role": "developer" });
+        assert_eq!(
+            patched(data, Some("Be brief"))[0],
+            (MessageRole::Developer, "Be brief".into())
+        );
+
+        // A session written for a developer-role model still works with a system-role one
+        let mut messages = vec![
+            Message::new(
+                MessageRole::Developer,
+                MessageContent::Text("Be brief".into()),
+            ),
+            Message::new(MessageRole::User, MessageContent::Text("hi".into())),
+        ];
+        patch_messages(&mut messages, &model_with(serde_json::json!({})));
+        assert_eq!(messages[0].role, MessageRole::System);
+    }
+
+    #[test]
+    fn test_patch_messages_no_system() {
+        let expected = [(MessageRole::User, "Be brief\n\nhi".to_string())];
+        let data = serde_json::json!({ "meta_role": "none" });
+        assert_eq!(patched(data, Some("Be brief")), expected);
+        let data = serde_json::json!({ "no_system_message": true, "meta_role": "developer" });
+        assert_eq!(patched(data, Some("Be brief")), expected);
+        let data = serde_json::json!({ "no_system_message": true });
+        assert_eq!(patched(data, None), [(MessageRole::User, "hi".to_string())]);
+    }
+}
@@ -0,0 +1,224 @@
+use super::{ChatCompletionsOutput, Model, RequestData, ToolCall};
+
+use crate::config::GlobalConfig;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::process::Stdio;
+use tokio::{io::AsyncWriteExt, process::Command};
+
+/// Commands that inspect or rewrite API traffic, e.g. to redact PII before it leaves the machine.
+///
+/// Each command reads a JSON document on stdin and prints the (possibly modified) document on
+/// stdout; empty output keeps it unchanged and a non-zero exit aborts the call.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Middleware {
+    /// Receives `{client, model, url, headers, body}` before the request is sent
+    pub request: Option<String>,
+    /// Receives `{client, model, text, tool_calls}` of a chat completion
+    pub response: Option<String>,
+    /// Only apply to these clients, all when empty
+    #[serde(default)]
+    pub clients: Vec<String>,
+}
+
+impl Middleware {
+    fn matches(&self, model: &Model) -> bool {
+        self.clients.is_empty() || self.clients.iter().any(|v| v == model.client_name())
+    }
+}
+
+pub fn has_response_middleware(config: &GlobalConfig, model: &Model) -> bool {
+    config
+        .read()
+        .middleware
+        .iter()
+        .any(|v| v.response.is_some() && v.matches(model))
+}
+
+pub async fn apply_request_middleware(
+    config: &GlobalConfig,
+    model: &Model,
+    request_data: &mut RequestData,
+) -> Result<()> {
+    let commands = middleware_commands(config, model, |v| v.request.as_deref());
+    for command in commands {
+        let input = json!({
+            "client": model.client_name(),
+            "model": model.name(),
+            "url": request_data.url,
+            "headers": request_data.headers,
+            "body": request_data.body,
+        });
+        let Some(output) = run_middleware(&command, &input).await? else {
+            continue;
+        };
+        if let Some(url) = output["url"].as_str() {
+            request_data.url = url.to_string();
+        }
+        if let Some(headers) = output.get("headers") {
+            request_data.headers = serde_json::from_value(headers.clone())
+                .with_context(|| format!("Middleware `{command}` returned invalid headers"))?;
+        }
+        if let Some(body) = output.get("body") {
+            request_data.body = body.clone();
+        }
+    }
+    Ok(())
+}
+
+pub async fn apply_response_middleware(
+    config: &GlobalConfig,
+    model: &Model,
+    output: &mut ChatCompletionsOutput,
+) -> Result<()> {
+    let commands = middleware_commands(config, model, |v| v.response.as_deref());
+    for command in commands {
+        let input = json!({
+            "client": model.client_name(),
+            "model": model.name(),
+            "text": output.text,
+            "tool_calls": output.tool_calls,
+        });
+        let Some(value) = run_middleware(&command, &input).await? else {
+            continue;
+        };
+        if let Some(text) = value["text"].as_str() {
+            output.text = text.to_string();
+        }
+        if let Some(tool_calls) = value.get("tool_calls") {
+            output.tool_calls = serde_json::from_value::<Vec<ToolCall>>(tool_calls.clone())
+                .with_context(|| format!("Middleware `{command}` returned invalid tool calls"))?;
+        }
+    }
+    Ok(())
+}
+
+fn middleware_commands(
+    config: &GlobalConfig,
+    model: &Model,
+    select: impl Fn(&Middleware) -> Option<&str>,
+) -> Vec<String> {
+    config
+        .read()
+        .middleware
+        .iter()
+        .filter(|v| v.matches(model))
+        .filter_map(|v| select(v).map(|v| v.to_string()))
+        .collect()
+}
+
+async fn run_middleware(command: &str, input: &Value) -> Result<Option<Value>> {
+    let args = shell_words::split(command)
+        .with_context(|| format!("Invalid middleware command `{command}`"))?;
+    let (cmd, args) = args
+        .split_first()
+        .ok_or_else(|| anyhow!("Empty middleware command"))?;
+    let mut child = Command::new(cmd)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to run middleware `{command}`"))?;
+    // Feed stdin alongside reading stdout so a large body can't deadlock against a full pipe
+    let mut stdin = child.stdin.take();
+    let input = input.to_string();
+    let write = async {
+        if let Some(stdin) = stdin.as_mut() {
+            let _ = stdin.write_all(input.as_bytes()).await;
+        }
+        drop(stdin);
+    };
+    let (_, output) = tokio::join!(write, child.wait_with_output());
+    let output = output?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "Middleware `{command}` rejected the call with exit code {}: {}",
+            output.status.code().unwrap_or_default(),
+            stderr.trim()
+        );
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if stdout.trim().is_empty() {
+        return Ok(None);
+    }
+    let value = serde_json::from_str(&stdout)
+        .with_context(|| format!("Middleware `{command}` printed invalid JSON"))?;
+    Ok(Some(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    use parking_lot::RwLock;
+    use std::sync::Arc;
+
+    fn config_with(middleware: Middleware) -> GlobalConfig {
+        let config = Config {
+            middleware: vec![middleware],
+            ..Default::default()
+        };
+        Arc::new(RwLock::new(config))
+    }
+
+    #[tokio::test]
+    async fn test_request_middleware() {
+        let config = config_with(Middleware {
+            request: Some("sed s/secret/REDACTED/g".into()),
+            ..Default::default()
+        });
+        let model = Model::new("openai-compatible", "my-model");
+        let mut request_data = RequestData::new(
+            "https://example.com/v1/chat/completions",
+            json!({ "messages": [{ "role": "user", "content": "my secret" }] }),
+        );
+        apply_request_middleware(&config, &model, &mut request_data)
+            .await
+            .unwrap();
+        assert_eq!(request_data.body["messages"][0]["content"], "my REDACTED");
+        assert_eq!(request_data.url, "https://example.com/v1/chat/completions");
+
+        let config = config_with(Middleware {
+            request: Some("sh -c 'echo blocked >&2; exit 3'".into()),
+            ..Default::default()
+        });
+        let err = apply_request_middleware(&config, &model, &mut request_data)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("exit code 3: blocked"));
+    }
+
+    #[tokio::test]
+    async fn test_response_middleware() {
+        let config = config_with(Middleware {
+            response: Some("sed s/foo/bar/".into()),
+            clients: vec!["openai-compatible".into()],
+            ..Default::default()
+        });
+        let model = Model::new("openai-compatible", "my-model");
+        assert!(has_response_middleware(&config, &model));
+        let mut output = ChatCompletionsOutput::new("foo");
+        apply_response_middleware(&config, &model, &mut output)
+            .await
+            .unwrap();
+        assert_eq!(output.text, "bar");
+
+        let other = Model::new("ollama", "my-model");
+        assert!(!has_response_middleware(&config, &other));
+    }
+
+    #[tokio::test]
+    async fn test_run_middleware() {
+        assert!(run_middleware("true", &json!({})).await.unwrap().is_none());
+        // A body larger than a pipe buffer must not deadlock
+        let input = json!({ "text": "x".repeat(1 << 20) });
+        assert_eq!(run_middleware("cat", &input).await.unwrap(), Some(input));
+        assert!(run_middleware("echo not-json", &json!({})).await.is_err());
+    }
+}
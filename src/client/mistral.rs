@@ -0,0 +1,163 @@
+use super::openai::*;
+use super::*;
+
+use crate::utils::sha256;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+const API_BASE: &str = "https://api.mistral.ai/v1";
+
+/// Marks the cursor in a prompt; text around it goes to the fill-in-the-middle endpoint.
+pub const FIM_MARKER: &str = "<|cursor|>";
+
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
+pub struct MistralConfig {
+    pub name: Option<String>,
+    pub api_base: Option<String>,
+    pub api_key: Option<String>,
+    pub parallel_tool_calls: Option<bool>,
+    #[serde(default)]
+    pub models: Vec<ModelData>,
+    pub patch: Option<RequestPatch>,
+    pub extra: Option<ExtraConfig>,
+}
+
+impl MistralClient {
+    config_get_fn!(api_base, get_api_base);
+    config_get_fn!(api_key, get_api_key);
+
+    pub const PROMPTS: [PromptAction<'static>; 1] = [("api_key", "API Key:", None)];
+
+    fn api_url(&self, path: &str) -> String {
+        let api_base = self
+            .get_api_base()
+            .unwrap_or_else(|_| API_BASE.to_string());
+        format!("{}{path}", api_base.trim_end_matches('/'))
+    }
+}
+
+impl_client_trait!(
+    MistralClient,
+    (
+        prepare_chat_completions,
+        openai_chat_completions,
+        openai_chat_completions_streaming
+    ),
+    (prepare_embeddings, openai_embeddings),
+    (noop_prepare_rerank, noop_rerank),
+);
+
+fn prepare_chat_completions(
+    self_: &MistralClient,
+    data: ChatCompletionsData,
+) -> Result<RequestData> {
+    let api_key = self_.get_api_key()?;
+
+    let (url, body) = match build_fim_body(&data, &self_.model) {
+        Some(body) => (self_.api_url("/fim/completions"), body),
+        None => {
+            let has_tools = data.functions.is_some();
+            let mut body = openai_build_chat_completions_body(data, &self_.model);
+            if let Some(obj) = body.as_object_mut() {
+                if let Some(seed) = obj.remove("seed") {
+                    obj.insert("random_seed".into(), seed);
+                }
+                obj.remove("logprobs");
+                obj.remove("top_logprobs");
+            }
+            if has_tools {
+                if let Some(v) = self_.config.parallel_tool_calls {
+                    body["parallel_tool_calls"] = v.into();
+                }
+            }
+            normalize_tool_call_ids(&mut body);
+            (self_.api_url("/chat/completions"), body)
+        }
+    };
+
+    let mut request_data = RequestData::new(url, body);
+
+    request_data.bearer_auth(api_key);
+
+    Ok(request_data)
+}
+
+fn prepare_embeddings(self_: &MistralClient, data: &EmbeddingsData) -> Result<RequestData> {
+    let api_key = self_.get_api_key()?;
+
+    let url = self_.api_url("/embeddings");
+
+    let body = openai_build_embeddings_body(data, &self_.model);
+
+    let mut request_data = RequestData::new(url, body);
+
+    request_data.bearer_auth(api_key);
+
+    Ok(request_data)
+}
+
+/// Builds a `/fim/completions` body when the last user message contains [`FIM_MARKER`].
+fn build_fim_body(data: &ChatCompletionsData, model: &Model) -> Option<Value> {
+    let message = data.messages.last().filter(|v| v.role.is_user())?;
+    let MessageContent::Text(text) = &message.content else {
+        return None;
+    };
+    let (prompt, suffix) = text.split_once(FIM_MARKER)?;
+    let mut body = json!({
+        "model": model.real_name(),
+        "prompt": prompt,
+        "suffix": suffix,
+    });
+    if let Some(v) = model.max_tokens_param() {
+        body["max_tokens"] = v.into();
+    }
+    if let Some(v) = data.temperature {
+        body["temperature"] = v.into();
+    }
+    if let Some(v) = data.top_p {
+        body["top_p"] = v.into();
+    }
+    if let Some(v) = data.seed {
+        body["random_seed"] = v.into();
+    }
+    if let Some(v) = &data.stop {
+        body["stop"] = v.clone().into();
+    }
+    if data.stream {
+        body["stream"] = true.into();
+    }
+    Some(body)
+}
+
+/// Mistral only accepts tool call ids made of 9 alphanumeric characters, so ids from other
+/// providers (or missing ones) are rewritten. Tool results pick up the ids of their calls in order.
+fn normalize_tool_call_ids(body: &mut Value) {
+    let Some(messages) = body["messages"].as_array_mut() else {
+        return;
+    };
+    let mut pending = std::collections::VecDeque::new();
+    for (i, message) in messages.iter_mut().enumerate() {
+        if let Some(tool_calls) = message["tool_calls"].as_array_mut() {
+            for (j, tool_call) in tool_calls.iter_mut().enumerate() {
+                let id = match tool_call["id"].as_str() {
+                    Some(id) if is_valid_tool_call_id(id) => id.to_string(),
+                    Some(id) => sha256(id)[..9].to_string(),
+                    None => sha256(&format!("{i}-{j}-{}", tool_call["function"]))[..9].to_string(),
+                };
+                tool_call["id"] = id.clone().into();
+                pending.push_back((id, tool_call["function"]["name"].clone()));
+            }
+        } else if message["role"] == "tool" {
+            if let Some((id, name)) = pending.pop_front() {
+                message["tool_call_id"] = id.into();
+                message["name"] = name;
+            }
+        }
+    }
+}
+
+fn is_valid_tool_call_id(id: &str) -> bool {
+    id.len() == 9 && id.chars().all(|c| c.is_ascii_alphanumeric())
+}
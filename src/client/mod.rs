@@ -1,16 +1,28 @@
 mod access_token;
+mod audit;
+mod cassette;
 mod common;
+mod image_output;
 mod message;
 #[macro_use]
 mod macros;
+mod middleware;
 mod model;
 mod models_dev;
 mod stream;
 
 pub use crate::function::ToolCall;
+pub use audit::*;
+pub use cassette::*;
 pub use common::*;
+pub use image_output::*;
 pub use message::*;
+pub use middleware::*;
 pub use model::*;
+pub use models_dev::{
+    get_models_dev, is_models_offline, models_dev_source, parse_models, read_models_source,
+    refresh_models_dev, set_models_dev_source, set_models_offline,
+};
 pub use stream::*;
 
 register_client!(
@@ -24,7 +36,16 @@ register_client!(
     (gemini, "gemini", GeminiConfig, GeminiClient),
     (claude, "claude", ClaudeConfig, ClaudeClient),
     (cohere, "cohere", CohereConfig, CohereClient),
+    (mistral, "mistral", MistralConfig, MistralClient),
     (ollama, "ollama", OllamaConfig, OllamaClient),
+    (vllm, "vllm", VllmConfig, VllmClient),
+    (local, "local", LocalConfig, LocalClient),
+    (
+        local_embedding,
+        "local-embedding",
+        LocalEmbeddingConfig,
+        LocalEmbeddingClient
+    ),
     (
         azure_openai,
         "azure-openai",
@@ -35,6 +56,8 @@ register_client!(
     (bedrock, "bedrock", BedrockConfig, BedrockClient),
 );
 
+pub use self::ollama::list_installed_ollama_models;
+
 pub const OPENAI_COMPATIBLE_PROVIDERS: [(&str, &str); 18] = [
     ("ai21", "https://api.ai21.com/studio/v1"),
     (
@@ -5,20 +5,24 @@ use super::{
 };
 
 use crate::config::Config;
-use crate::utils::{estimate_token_length, strip_think_tag};
+use crate::utils::{did_you_mean, estimate_token_length, strip_think_tag};
 
 use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 use std::fmt::Display;
 
 const PER_MESSAGES_TOKENS: usize = 5;
 const BASIS_TOKENS: usize = 2;
+/// Sent to APIs that refuse requests without `max_tokens` when the model's limit is unknown.
+const DEFAULT_REQUIRED_MAX_TOKENS: isize = 4096;
 
 #[derive(Debug, Clone)]
 pub struct Model {
     client_name: String,
     data: ModelData,
+    /// Set with `.set max_output_tokens` or by an API request, kept within `max_output_tokens`
+    max_tokens: Option<isize>,
 }
 
 impl Default for Model {
@@ -32,6 +36,7 @@ impl Model {
         Self {
             client_name: client_name.into(),
             data: ModelData::new(name),
+            max_tokens: None,
         }
     }
 
@@ -41,6 +46,7 @@ impl Model {
             .map(|v| Model {
                 client_name: client_name.to_string(),
                 data: v.clone(),
+                max_tokens: None,
             })
             .collect()
     }
@@ -73,6 +79,7 @@ impl Model {
                 {
                     let mut new_model = Self::new(client_name, model_name);
                     new_model.data.model_type = model_type.to_string();
+                    new_model.apply_config_overrides(config);
                     return Ok(new_model);
                 }
             }
@@ -85,7 +92,14 @@ impl Model {
                 }
             }
         };
-        bail!("Unknown {model_type} model '{model_id}'")
+        let suggestion = did_you_mean(
+            model_id,
+            models
+                .iter()
+                .filter(|v| v.model_type() == model_type)
+                .map(|v| v.id()),
+        );
+        bail!("Unknown {model_type} model '{model_id}'{suggestion}")
     }
 
     pub fn id(&self) -> String {
@@ -118,6 +132,20 @@ impl Model {
         }
     }
 
+    pub fn export_value(&self) -> Value {
+        let mut value = json!({
+            "id": self.id(),
+            "client": self.client_name,
+        });
+        if let (Some(output), Value::Object(data)) = (value.as_object_mut(), json!(self.data)) {
+            output.extend(data);
+        }
+        if let Some(max_tokens) = self.max_tokens_param() {
+            value["max_tokens_param"] = max_tokens.into();
+        }
+        value
+    }
+
     pub fn data(&self) -> &ModelData {
         &self.data
     }
@@ -190,14 +218,22 @@ impl Model {
         self.data.no_stream
     }
 
-    pub fn no_system_message(&self) -> bool {
-        self.data.no_system_message
+    /// The role the system prompt is sent as; `no_system_message` still means `none`.
+    pub fn meta_role(&self) -> MetaRole {
+        if self.data.no_system_message {
+            return MetaRole::None;
+        }
+        self.data.meta_role.unwrap_or_default()
     }
 
     pub fn system_prompt_prefix(&self) -> Option<&str> {
         self.data.system_prompt_prefix.as_deref()
     }
 
+    pub fn stop(&self) -> Option<&[String]> {
+        self.data.stop.as_deref()
+    }
+
     pub fn max_tokens_per_chunk(&self) -> Option<usize> {
         self.data.max_tokens_per_chunk
     }
@@ -210,27 +246,48 @@ impl Model {
         self.data.max_batch_size
     }
 
+    /// The `max_tokens` to send: the value set by the user, or the model's limit when the model
+    /// requires the parameter.
     pub fn max_tokens_param(&self) -> Option<isize> {
-        if self.data.require_max_tokens {
-            self.data.max_output_tokens
-        } else {
-            None
+        match self.max_tokens {
+            Some(value) => Some(self.clamp_max_tokens(value)),
+            None if self.data.require_max_tokens => self.data.max_output_tokens,
+            None => None,
         }
     }
 
-    pub fn set_max_tokens(
-        &mut self,
-        max_output_tokens: Option<isize>,
-        require_max_tokens: bool,
-    ) -> &mut Self {
-        match max_output_tokens {
-            None | Some(0) => self.data.max_output_tokens = None,
-            _ => self.data.max_output_tokens = max_output_tokens,
+    /// The `max_tokens` for APIs that reject requests without it.
+    pub fn required_max_tokens_param(&self) -> isize {
+        self.max_tokens_param()
+            .or(self.data.max_output_tokens)
+            .unwrap_or(DEFAULT_REQUIRED_MAX_TOKENS)
+    }
+
+    /// Caps `value` at the model's output limit, when known.
+    pub fn clamp_max_tokens(&self, value: isize) -> isize {
+        match self.data.max_output_tokens {
+            Some(limit) if limit > 0 && value > limit => limit,
+            _ => value,
         }
-        self.data.require_max_tokens = require_max_tokens;
+    }
+
+    /// Sets the `max_tokens` to send, `None` or 0 to go back to the model's default.
+    pub fn set_max_tokens(&mut self, value: Option<isize>) -> &mut Self {
+        self.max_tokens = value.filter(|v| *v > 0);
         self
     }
 
+    /// Applies the config's `require_max_tokens` overrides, keyed by model id or client name.
+    pub fn apply_config_overrides(&mut self, config: &Config) {
+        let overrides = &config.require_max_tokens;
+        if let Some(value) = overrides
+            .get(&self.id())
+            .or_else(|| overrides.get(&self.client_name))
+        {
+            self.data.require_max_tokens = *value;
+        }
+    }
+
     pub fn messages_tokens(&self, messages: &[Message]) -> usize {
         let messages_len = messages.len();
         messages
@@ -282,14 +339,19 @@ impl Model {
     }
 
     pub fn guard_max_input_tokens(&self, messages: &[Message]) -> Result<()> {
-        let total_tokens = self.total_tokens(messages) + BASIS_TOKENS;
-        if let Some(max_input_tokens) = self.data.max_input_tokens {
-            if total_tokens >= max_input_tokens {
-                bail!("Exceed max_input_tokens limit")
-            }
+        if self.exceeds_max_input_tokens(messages) {
+            bail!("Exceed max_input_tokens limit")
         }
         Ok(())
     }
+
+    pub fn exceeds_max_input_tokens(&self, messages: &[Message]) -> bool {
+        let total_tokens = self.total_tokens(messages) + BASIS_TOKENS;
+        match self.data.max_input_tokens {
+            Some(max_input_tokens) => total_tokens >= max_input_tokens,
+            None => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -307,6 +369,14 @@ pub struct ModelData {
     pub output_price: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub patch: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_token_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
 
     // chat-only properties
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -322,7 +392,11 @@ pub struct ModelData {
     #[serde(default, skip_serializing_if = "std::ops::Not::not")]
     no_system_message: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
+    meta_role: Option<MetaRole>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     system_prompt_prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<Vec<String>>,
 
     // embedding-only properties
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -343,6 +417,17 @@ impl ModelData {
     }
 }
 
+/// How a model takes the system prompt.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetaRole {
+    #[default]
+    System,
+    Developer,
+    /// Merged into the first user message
+    None,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProviderModels {
     pub provider: String,
@@ -405,3 +490,71 @@ where
         None => "-".to_string(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model_with_limit(limit: Option<isize>) -> Model {
+        let mut model = Model::new("openai", "gpt-x");
+        model.data_mut().max_output_tokens = limit;
+        model
+    }
+
+    #[test]
+    fn test_clamp_max_tokens() {
+        let model = model_with_limit(Some(1000));
+        assert_eq!(model.clamp_max_tokens(999), 999);
+        assert_eq!(model.clamp_max_tokens(1000), 1000);
+        assert_eq!(model.clamp_max_tokens(1001), 1000);
+        assert_eq!(model_with_limit(None).clamp_max_tokens(5000), 5000);
+        // A zero or negative limit means none is known
+        assert_eq!(model_with_limit(Some(0)).clamp_max_tokens(5000), 5000);
+        assert_eq!(model_with_limit(Some(-1)).clamp_max_tokens(5000), 5000);
+    }
+
+    #[test]
+    fn test_max_tokens_param() {
+        let mut model = model_with_limit(Some(1000));
+        assert_eq!(model.max_tokens_param(), None);
+        assert_eq!(model.required_max_tokens_param(), 1000);
+
+        model.data_mut().require_max_tokens = true;
+        assert_eq!(model.max_tokens_param(), Some(1000));
+
+        model.set_max_tokens(Some(200));
+        assert_eq!(model.max_tokens_param(), Some(200));
+        assert_eq!(model.required_max_tokens_param(), 200);
+        model.set_max_tokens(Some(5000));
+        assert_eq!(model.max_tokens_param(), Some(1000));
+        model.set_max_tokens(Some(0));
+        assert_eq!(model.max_tokens_param(), Some(1000));
+
+        let model = model_with_limit(None);
+        assert_eq!(
+            model.required_max_tokens_param(),
+            DEFAULT_REQUIRED_MAX_TOKENS
+        );
+    }
+
+    #[test]
+    fn test_apply_config_overrides() {
+        let mut config = Config::default();
+        config.require_max_tokens.insert("openai".into(), true);
+        let mut model = model_with_limit(Some(1000));
+        model.apply_config_overrides(&config);
+        assert!(model.data().require_max_tokens);
+
+        // The model id wins over the client name
+        config
+            .require_max_tokens
+            .insert("openai:gpt-x".into(), false);
+        model.apply_config_overrides(&config);
+        assert!(!model.data().require_max_tokens);
+
+        let mut other = Model::new("claude", "claude-x");
+        other.data_mut().require_max_tokens = true;
+        other.apply_config_overrides(&config);
+        assert!(other.data().require_max_tokens);
+    }
+}
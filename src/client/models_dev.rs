@@ -1,12 +1,23 @@
 use crate::client::model::{ModelData, ProviderModels};
-use anyhow::{Context, Result};
+use crate::config::{ensure_parent_exists, Config};
+use crate::utils::{apply_proxy, sha256, warning_text};
+use anyhow::{bail, Context, Result};
 use serde::Deserialize;
 use serde_json::Value;
 use std::collections::HashMap;
-use std::time::{Duration, SystemTime};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    OnceLock,
+};
+use std::time::Duration;
 
 const MODELS_DEV_API_URL: &str = "https://models.dev/api.json";
-const CACHE_TTL_SECONDS: u64 = 3600; // 1 hour
+const CACHE_FILE_NAME: &str = "models-dev.json";
+const DEFAULT_CACHE_TTL_SECONDS: u64 = 86400; // 1 day
+
+static MODELS_OFFLINE: AtomicBool = AtomicBool::new(false);
+static MODELS_DEV_SOURCE: OnceLock<(bool, Option<String>)> = OnceLock::new();
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ModelsDevResponse {
@@ -101,15 +112,6 @@ pub struct ModelLimit {
     pub output: Option<u64>,
 }
 
-#[derive(Debug, Clone)]
-struct CachedData {
-    data: Vec<ProviderModels>,
-    fetched_at: SystemTime,
-}
-
-static CACHE: std::sync::LazyLock<std::sync::Mutex<Option<CachedData>>> =
-    std::sync::LazyLock::new(|| std::sync::Mutex::new(None));
-
 /// Provider name mapping from models.dev IDs to aichat provider names
 fn map_provider_name(models_dev_id: &str) -> String {
     match models_dev_id {
@@ -247,76 +249,238 @@ pub fn convert_models_dev_to_provider_models(
     result
 }
 
-/// Fetch models from models.dev API
-pub async fn fetch_models_dev(url: &str) -> Result<ModelsDevResponse> {
-    let client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(30))
+/// Read a model list from an http(s) URL, a `file://` URL or a local path
+pub async fn read_models_source(source: &str) -> Result<String> {
+    if !source.starts_with("http://") && !source.starts_with("https://") {
+        let path = source.strip_prefix("file://").unwrap_or(source);
+        return std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read models from '{}'", path));
+    }
+    let builder = reqwest::Client::builder().timeout(Duration::from_secs(30));
+    let client = apply_proxy(builder, None)?
         .build()
         .context("Failed to create HTTP client")?;
     
     let response = client
-        .get(url)
+        .get(source)
         .send()
         .await
-        .with_context(|| format!("Failed to fetch models from '{}'", url))?;
+        .with_context(|| format!("Failed to fetch models from '{}'", source))?;
     
     if !response.status().is_success() {
         anyhow::bail!(
             "HTTP error {} when fetching models from '{}'",
             response.status(),
-            url
+            source
         );
     }
     
-    let json: Value = response
-        .json()
+    response
+        .text()
         .await
-        .context("Failed to parse JSON response from models.dev")?;
-    
+        .with_context(|| format!("Failed to read the response from '{}'", source))
+}
+
+/// Parse a model list in either the models.yaml format or the models.dev schema
+pub fn parse_models(content: &str) -> Result<Vec<ProviderModels>> {
+    if let Ok(list) = serde_yaml::from_str::<Vec<ProviderModels>>(content) {
+        return Ok(list);
+    }
     // The API returns a flat object with provider IDs as keys
-    // We need to deserialize it properly
-    let providers: HashMap<String, ProviderData> = serde_json::from_value(json)
-        .context("Failed to deserialize models.dev response")?;
-    
-    Ok(ModelsDevResponse { providers })
+    let json: Value =
+        serde_json::from_str(content).context("Expected a models.yaml list or models.dev JSON")?;
+    let providers: HashMap<String, ProviderData> =
+        serde_json::from_value(json).context("Failed to deserialize models.dev data")?;
+
+    Ok(convert_models_dev_to_provider_models(&ModelsDevResponse {
+        providers,
+    }))
 }
 
-/// Get cached models or fetch fresh ones
+/// Get the models.dev data from the disk cache, downloading it when missing or older than
+/// `AICHAT_MODELS_DEV_CACHE_TTL` seconds. A stale cache beats no data when the download fails.
 pub async fn get_models_dev(url: Option<&str>) -> Result<Vec<ProviderModels>> {
     let url = url.unwrap_or(MODELS_DEV_API_URL);
+    if !is_remote(url) {
+        return parse_models(&read_models_source(url).await?);
+    }
     
-    // Check cache first
-    {
-        let cache_guard = CACHE.lock().unwrap();
-        if let Some(cached) = cache_guard.as_ref() {
-            if let Ok(elapsed) = cached.fetched_at.elapsed() {
-                if elapsed.as_secs() < CACHE_TTL_SECONDS {
-                    return Ok(cached.data.clone());
-                }
+    load_cached_models_dev(url, &cache_file(url), cache_ttl()).await
+}
+
+async fn load_cached_models_dev(
+    url: &str,
+    cache_path: &Path,
+    ttl: Duration,
+) -> Result<Vec<ProviderModels>> {
+    let cached = std::fs::read_to_string(cache_path).ok();
+    if is_models_offline() {
+        return match &cached {
+            Some(content) => parse_models(content),
+            None => bail!("No cached models.dev data to use offline"),
+        };
+    }
+    if let Some(content) = &cached {
+        if cache_age(cache_path) < ttl {
+            match parse_models(content) {
+                Ok(list) => return Ok(list),
+                Err(e) => log::warn!("Ignoring invalid models.dev cache: {}", e),
             }
         }
     }
     
-    // Fetch fresh data
-    let response = fetch_models_dev(url).await?;
-    let provider_models = convert_models_dev_to_provider_models(&response);
-    
-    // Update cache
-    {
-        let mut cache_guard = CACHE.lock().unwrap();
-        *cache_guard = Some(CachedData {
-            data: provider_models.clone(),
-            fetched_at: SystemTime::now(),
-        });
+    match refresh_models_dev(Some(url)).await {
+        Ok(list) => Ok(list),
+        Err(e) => match cached.as_deref().map(parse_models) {
+            Some(Ok(list)) => {
+                eprintln!(
+                    "{}",
+                    warning_text(&format!(
+                        "⚠️ {e:#}. Using the models.dev data cached {} ago",
+                        format_age(cache_age(cache_path))
+                    ))
+                );
+                Ok(list)
+            }
+            _ => Err(e),
+        },
+    }
+}
+
+/// Download the models.dev data and overwrite the disk cache, regardless of its age
+pub async fn refresh_models_dev(url: Option<&str>) -> Result<Vec<ProviderModels>> {
+    let url = url.unwrap_or(MODELS_DEV_API_URL);
+    let content = read_models_source(url).await?;
+    let list = parse_models(&content)?;
+    if is_remote(url) {
+        let cache_path = cache_file(url);
+        ensure_parent_exists(&cache_path)?;
+        std::fs::write(&cache_path, content).with_context(|| {
+            format!(
+                "Failed to write models.dev cache to '{}'",
+                cache_path.display()
+            )
+        })?;
+    }
+    Ok(list)
+}
+
+/// The cache file under the config dir; non-default URLs get a file of their own
+pub fn cache_file(url: &str) -> PathBuf {
+    let name = if url == MODELS_DEV_API_URL {
+        CACHE_FILE_NAME.to_string()
+    } else {
+        CACHE_FILE_NAME.replace(".json", &format!("-{}.json", &sha256(url)[..8]))
+    };
+    Config::local_path(&name)
+}
+
+/// Whether to load models.dev and from which URL, set from the config before the models are
+/// first needed
+pub fn set_models_dev_source(enabled: bool, url: Option<String>) {
+    let _ = MODELS_DEV_SOURCE.set((enabled, url));
+}
+
+/// The source set from the config, or `AICHAT_MODELS_DEV_ENABLED` and `AICHAT_MODELS_DEV_URL`
+/// when the models are needed before the config is loaded
+pub fn models_dev_source() -> (bool, Option<String>) {
+    if let Some(source) = MODELS_DEV_SOURCE.get() {
+        return source.clone();
+    }
+    let enabled = std::env::var("AICHAT_MODELS_DEV_ENABLED")
+        .ok()
+        .and_then(|v| v.parse::<bool>().ok())
+        .unwrap_or(true);
+    (enabled, std::env::var("AICHAT_MODELS_DEV_URL").ok())
+}
+
+/// Never download the models.dev data, set by `--offline-models` or `AICHAT_OFFLINE_MODELS`
+pub fn set_models_offline() {
+    MODELS_OFFLINE.store(true, Ordering::Relaxed);
+}
+
+pub fn is_models_offline() -> bool {
+    MODELS_OFFLINE.load(Ordering::Relaxed)
+        || std::env::var("AICHAT_OFFLINE_MODELS")
+            .ok()
+            .and_then(|v| v.parse::<bool>().ok())
+            .unwrap_or_default()
+}
+
+fn is_remote(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+fn cache_age(path: &Path) -> Duration {
+    std::fs::metadata(path)
+        .and_then(|v| v.modified())
+        .ok()
+        .and_then(|v| v.elapsed().ok())
+        .unwrap_or(Duration::MAX)
+}
+
+fn format_age(age: Duration) -> String {
+    match age.as_secs() {
+        v if v < 3600 => format!("{} minutes", v / 60),
+        v if v < 86400 => format!("{} hours", v / 3600),
+        v => format!("{} days", v / 86400),
     }
-    
-    Ok(provider_models)
 }
 
-/// Clear the cache (useful for testing or manual refresh)
-#[allow(dead_code)] // May be used by external code or future features
-pub fn clear_cache() {
-    let mut cache_guard = CACHE.lock().unwrap();
-    *cache_guard = None;
+fn cache_ttl() -> Duration {
+    let seconds = std::env::var("AICHAT_MODELS_DEV_CACHE_TTL")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_CACHE_TTL_SECONDS);
+    Duration::from_secs(seconds)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_models() {
+        let yaml = "- provider: openai\n  models:\n    - name: gpt-y\n";
+        assert_eq!(parse_models(yaml).unwrap()[0].models[0].name, "gpt-y");
+        let json = r#"{"openai":{"id":"openai","name":"OpenAI","npm":"@ai-sdk/openai","models":{"gpt-x":{"id":"gpt-x","name":"GPT X","limit":{"context":1000}}}}}"#;
+        let list = parse_models(json).unwrap();
+        assert_eq!(list[0].provider, "openai");
+        assert_eq!(list[0].models[0].name, "gpt-x");
+        assert!(parse_models("not: [models").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_load_cached_models_dev() {
+        // Nothing listens there, so any download fails
+        let url = "http://127.0.0.1:9/api.json";
+        let cache_path = crate::utils::temp_file("-models-dev-", ".json");
+        let day = Duration::from_secs(86400);
+        assert!(load_cached_models_dev(url, &cache_path, day).await.is_err());
+
+        let content = "- provider: openai\n  models:\n    - name: gpt-cached\n";
+        std::fs::write(&cache_path, content).unwrap();
+        let fresh = load_cached_models_dev(url, &cache_path, day).await.unwrap();
+        assert_eq!(fresh[0].models[0].name, "gpt-cached");
+
+        // A stale cache is still used when the download fails
+        let stale = load_cached_models_dev(url, &cache_path, Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(stale[0].models[0].name, "gpt-cached");
+
+        std::fs::write(&cache_path, "not: [models").unwrap();
+        assert!(load_cached_models_dev(url, &cache_path, day).await.is_err());
+        let _ = std::fs::remove_file(&cache_path);
+    }
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(Duration::from_secs(150)), "2 minutes");
+        assert_eq!(format_age(Duration::from_secs(7300)), "2 hours");
+        assert_eq!(
+            format_age(Duration::MAX),
+            format!("{} days", u64::MAX / 86400)
+        );
+    }
+}
@@ -5,10 +5,14 @@
 
 use super::*;
 
+use crate::config::GlobalConfig;
+use crate::utils::apply_proxy;
+
 use anyhow::{bail, Context, Result};
-use reqwest::RequestBuilder;
+use reqwest::{Client as ReqwestClient, Response};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::time::Duration;
 
 const API_BASE: &str = "http://localhost:11434";
 
@@ -17,6 +21,8 @@ pub struct OllamaConfig {
     pub name: Option<String>,
     pub api_base: Option<String>,
     pub api_key: Option<String>,
+    pub keep_alive: Option<Value>,
+    pub auto_pull: Option<bool>,
     #[serde(default)]
     pub models: Vec<ModelData>,
     pub patch: Option<RequestPatch>,
@@ -28,31 +34,171 @@ impl OllamaClient {
     config_get_fn!(api_key, get_api_key);
 
     pub const PROMPTS: [PromptAction<'static>; 1] = [("api_base", "API Base:", Some(API_BASE))];
+
+    /// Models installed on the Ollama server, as reported by `/api/tags`.
+    pub async fn list_installed_models(local_config: &OllamaConfig) -> Result<Vec<String>> {
+        let api_base = local_config.api_base.as_deref().unwrap_or(API_BASE);
+        let extra = local_config.extra.as_ref();
+        let builder = ReqwestClient::builder()
+            .connect_timeout(Duration::from_secs(
+                extra.and_then(|v| v.connect_timeout).unwrap_or(3),
+            ))
+            .timeout(Duration::from_secs(10));
+        let client = apply_proxy(builder, extra.and_then(|v| v.proxy.as_deref()))?.build()?;
+        let mut builder = client.get(format!("{}/api/tags", api_base.trim_end_matches('/')));
+        if let Some(api_key) = &local_config.api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+        record_audit_request(&builder);
+        let res = builder.send().await?;
+        let status = res.status();
+        let data: Value = res.json().await?;
+        if !status.is_success() {
+            catch_error(&data, status.as_u16())?;
+        }
+        let models = data["models"]
+            .as_array()
+            .map(|list| {
+                list.iter()
+                    .filter(|v| !is_embedding_model(v))
+                    .filter_map(|v| v["name"].as_str())
+                    .map(|v| v.strip_suffix(":latest").unwrap_or(v).to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(models)
+    }
+
+    fn api_url(&self, path: &str) -> String {
+        let api_base = self
+            .get_api_base()
+            .unwrap_or_else(|_| API_BASE.to_string());
+        format!("{}{path}", api_base.trim_end_matches('/'))
+    }
+
+    /// Sends the request, pulling the model and retrying once if the server doesn't have it yet.
+    async fn send(&self, client: &ReqwestClient, request_data: RequestData) -> Result<Response> {
+        let builder = self.request_builder(client, request_data).await?;
+        let retry_builder = builder.try_clone();
+        record_audit_request(&builder);
+        let res = builder.send().await?;
+        let status = res.status();
+        if status.as_u16() != 404 {
+            return Ok(res);
+        }
+        let Some(retry_builder) = retry_builder else {
+            return Ok(res);
+        };
+        let data: Value = res.json().await?;
+        let missing = data["error"]
+            .as_str()
+            .map(|v| v.contains("not found"))
+            .unwrap_or_default();
+        if !missing {
+            catch_error(&data, status.as_u16())?;
+        }
+        let model_name = self.model.name();
+        if !self.config.auto_pull.unwrap_or_default() {
+            bail!("Model '{model_name}' is not installed in ollama, run `ollama pull {model_name}` or set `auto_pull: true`");
+        }
+        self.pull_model(client).await?;
+        record_audit_request(&retry_builder);
+        Ok(retry_builder.send().await?)
+    }
+
+    async fn pull_model(&self, client: &ReqwestClient) -> Result<()> {
+        let model_name = self.model.name();
+        eprintln!("Pulling model '{model_name}' into ollama...");
+        let mut builder = client.post(self.api_url("/api/pull")).json(&json!({
+            "model": model_name,
+            "stream": false,
+        }));
+        if let Ok(api_key) = self.get_api_key() {
+            builder = builder.bearer_auth(api_key);
+        }
+        let res = builder.send().await?;
+        let status = res.status();
+        let data: Value = res.json().await?;
+        if !status.is_success() {
+            catch_error(&data, status.as_u16())
+                .with_context(|| format!("Failed to pull model '{model_name}'"))?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for OllamaClient {
+    client_common_fns!();
+
+    async fn chat_completions_inner(
+        &self,
+        client: &ReqwestClient,
+        data: ChatCompletionsData,
+    ) -> Result<ChatCompletionsOutput> {
+        let request_data = prepare_chat_completions(self, data)?;
+        let res = self.send(client, request_data).await?;
+        ollama_chat_completions(res).await
+    }
+
+    async fn chat_completions_streaming_inner(
+        &self,
+        client: &ReqwestClient,
+        handler: &mut SseHandler,
+        data: ChatCompletionsData,
+    ) -> Result<()> {
+        let request_data = prepare_chat_completions(self, data)?;
+        let res = self.send(client, request_data).await?;
+        ollama_chat_completions_streaming(res, handler).await
+    }
+
+    async fn embeddings_inner(
+        &self,
+        client: &ReqwestClient,
+        data: &EmbeddingsData,
+    ) -> Result<EmbeddingsOutput> {
+        let request_data = prepare_embeddings(self, data)?;
+        let res = self.send(client, request_data).await?;
+        ollama_embeddings(res).await
+    }
 }
 
-impl_client_trait!(
-    OllamaClient,
-    (
-        prepare_chat_completions,
-        ollama_chat_completions,
-        ollama_chat_completions_streaming
-    ),
-    (prepare_embeddings, ollama_embeddings),
-    (noop_prepare_rerank, noop_rerank),
-);
+/// Installed models of every configured ollama client, as `client:model` ids.
+pub async fn list_installed_ollama_models(config: &GlobalConfig) -> Vec<String> {
+    let clients: Vec<OllamaConfig> = config
+        .read()
+        .clients
+        .iter()
+        .filter_map(|v| match v {
+            ClientConfig::OllamaConfig(c) => Some(c.clone()),
+            _ => None,
+        })
+        .collect();
+    let mut model_ids = vec![];
+    for local_config in clients {
+        let client_name = OllamaClient::name(&local_config);
+        match OllamaClient::list_installed_models(&local_config).await {
+            Ok(names) => {
+                model_ids.extend(names.into_iter().map(|v| format!("{client_name}:{v}")))
+            }
+            Err(err) => debug!("Failed to list installed models of {client_name}: {err}"),
+        }
+    }
+    model_ids
+}
 
 fn prepare_chat_completions(
     self_: &OllamaClient,
     data: ChatCompletionsData,
 ) -> Result<RequestData> {
-    let api_base = self_
-        .get_api_base()
-        .unwrap_or_else(|_| API_BASE.to_string());
     let api_key = self_.get_api_key().ok();
 
-    let url = format!("{}/api/chat", api_base.trim_end_matches('/'));
+    let url = self_.api_url("/api/chat");
 
-    let body = build_chat_completions_body(data, &self_.model)?;
+    let mut body = build_chat_completions_body(data, &self_.model)?;
+    if let Some(v) = &self_.config.keep_alive {
+        body["keep_alive"] = v.clone();
+    }
 
     let mut request_data = RequestData::new(url, body);
 
@@ -60,23 +206,21 @@ fn prepare_chat_completions(
         request_data.bearer_auth(api_key);
     }
 
-    self_.patch_request_data(&mut request_data);
-
     Ok(request_data)
 }
 
 fn prepare_embeddings(self_: &OllamaClient, data: &EmbeddingsData) -> Result<RequestData> {
-    let api_base = self_
-        .get_api_base()
-        .unwrap_or_else(|_| API_BASE.to_string());
     let api_key = self_.get_api_key().ok();
 
-    let url = format!("{}/api/embed", api_base.trim_end_matches('/'));
+    let url = self_.api_url("/api/embed");
 
-    let body = json!({
+    let mut body = json!({
         "model": self_.model.name(),
         "input": data.texts,
     });
+    if let Some(v) = &self_.config.keep_alive {
+        body["keep_alive"] = v.clone();
+    }
 
     let mut request_data = RequestData::new(url, body);
 
@@ -87,11 +231,7 @@ fn prepare_embeddings(self_: &OllamaClient, data: &EmbeddingsData) -> Result<Req
     Ok(request_data)
 }
 
-async fn ollama_chat_completions(
-    builder: RequestBuilder,
-    _model: &Model,
-) -> Result<ChatCompletionsOutput> {
-    let res = builder.send().await?;
+async fn ollama_chat_completions(res: Response) -> Result<ChatCompletionsOutput> {
     let status = res.status();
     let data: Value = res.json().await?;
     if !status.is_success() {
@@ -102,12 +242,7 @@ async fn ollama_chat_completions(
     extract_chat_completions(&data)
 }
 
-async fn ollama_chat_completions_streaming(
-    builder: RequestBuilder,
-    handler: &mut SseHandler,
-    _model: &Model,
-) -> Result<()> {
-    let res = builder.send().await?;
+async fn ollama_chat_completions_streaming(res: Response, handler: &mut SseHandler) -> Result<()> {
     let status = res.status();
     if !status.is_success() {
         let data: Value = res.json().await?;
@@ -134,8 +269,7 @@ async fn ollama_chat_completions_streaming(
     Ok(())
 }
 
-async fn ollama_embeddings(builder: RequestBuilder, _model: &Model) -> Result<EmbeddingsOutput> {
-    let res = builder.send().await?;
+async fn ollama_embeddings(res: Response) -> Result<EmbeddingsOutput> {
     let status = res.status();
     let data: Value = res.json().await?;
     if !status.is_success() {
@@ -146,6 +280,14 @@ async fn ollama_embeddings(builder: RequestBuilder, _model: &Model) -> Result<Em
     Ok(res_body.embeddings)
 }
 
+fn is_embedding_model(data: &Value) -> bool {
+    let details = &data["details"];
+    std::iter::once(&details["family"])
+        .chain(details["families"].as_array().into_iter().flatten())
+        .filter_map(|v| v.as_str())
+        .any(|v| v.contains("bert"))
+}
+
 #[derive(Deserialize)]
 struct EmbeddingsResBody {
     embeddings: Vec<Vec<f32>>,
@@ -156,6 +298,8 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
         messages,
         temperature,
         top_p,
+        seed,
+        stop,
         functions,
         stream,
         ..
@@ -248,6 +392,12 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
     if let Some(v) = top_p {
         body["options"]["top_p"] = v.into();
     }
+    if let Some(v) = seed {
+        body["options"]["seed"] = v.into();
+    }
+    if let Some(v) = stop {
+        body["options"]["stop"] = v.into();
+    }
     if let Some(functions) = functions {
         body["tools"] = functions
             .iter()
@@ -292,6 +442,10 @@ fn extract_chat_completions(data: &Value) -> Result<ChatCompletionsOutput> {
         id: None,
         input_tokens: data["prompt_eval_count"].as_u64(),
         output_tokens: data["eval_count"].as_u64(),
+        logprobs: None,
+        system_fingerprint: None,
+        images: vec![],
+        sources: None,
     };
     Ok(output)
 }
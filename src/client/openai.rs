@@ -15,6 +15,7 @@ pub struct OpenAIConfig {
     pub api_key: Option<String>,
     pub api_base: Option<String>,
     pub organization_id: Option<String>,
+    pub project_id: Option<String>,
     #[serde(default)]
     pub models: Vec<ModelData>,
     pub patch: Option<RequestPatch>,
@@ -24,8 +25,38 @@ pub struct OpenAIConfig {
 impl OpenAIClient {
     config_get_fn!(api_key, get_api_key);
     config_get_fn!(api_base, get_api_base);
+    config_get_fn!(organization_id, get_organization_id);
+    config_get_fn!(project_id, get_project_id);
 
     pub const PROMPTS: [PromptAction<'static>; 1] = [("api_key", "API Key", None)];
+
+    /// Bills the request to the configured organization and project.
+    fn set_scope_headers(&self, request_data: &mut RequestData) {
+        if let Ok(organization_id) = self.get_organization_id() {
+            request_data.header("OpenAI-Organization", organization_id);
+        }
+        if let Ok(project_id) = self.get_project_id() {
+            request_data.header("OpenAI-Project", project_id);
+        }
+    }
+}
+
+impl OpenAIConfig {
+    /// Checks the organization and project ids, including `<NAME>_ORGANIZATION_ID` and
+    /// `<NAME>_PROJECT_ID` overrides, so a mistyped id fails at startup rather than billing
+    /// the key's default project.
+    pub fn validate(&self) -> Result<()> {
+        let name = self.name.as_deref().unwrap_or(OpenAIClient::NAME);
+        let organization_id = client_config_value(name, "organization_id", &self.organization_id);
+        if let Some(id) = organization_id.filter(|v| !v.starts_with("org-")) {
+            bail!("Invalid organization_id '{id}' for client '{name}', expected 'org-...'");
+        }
+        let project_id = client_config_value(name, "project_id", &self.project_id);
+        if let Some(id) = project_id.filter(|v| !v.starts_with("proj_")) {
+            bail!("Invalid project_id '{id}' for client '{name}', expected 'proj_...'");
+        }
+        Ok(())
+    }
 }
 
 impl_client_trait!(
@@ -55,9 +86,7 @@ fn prepare_chat_completions(
     let mut request_data = RequestData::new(url, body);
 
     request_data.bearer_auth(api_key);
-    if let Some(organization_id) = &self_.config.organization_id {
-        request_data.header("OpenAI-Organization", organization_id);
-    }
+    self_.set_scope_headers(&mut request_data);
 
     Ok(request_data)
 }
@@ -75,9 +104,7 @@ fn prepare_embeddings(self_: &OpenAIClient, data: &EmbeddingsData) -> Result<Req
     let mut request_data = RequestData::new(url, body);
 
     request_data.bearer_auth(api_key);
-    if let Some(organization_id) = &self_.config.organization_id {
-        request_data.header("OpenAI-Organization", organization_id);
-    }
+    self_.set_scope_headers(&mut request_data);
 
     Ok(request_data)
 }
@@ -86,6 +113,7 @@ pub async fn openai_chat_completions(
     builder: RequestBuilder,
     _model: &Model,
 ) -> Result<ChatCompletionsOutput> {
+    record_audit_request(&builder);
     let res = builder.send().await?;
     let status = res.status();
     let data: Value = res.json().await?;
@@ -126,6 +154,9 @@ pub async fn openai_chat_completions_streaming(
         }
         let data: Value = serde_json::from_str(&message.data)?;
         debug!("stream-data: {data}");
+        if let Some(system_fingerprint) = data["system_fingerprint"].as_str() {
+            handler.set_system_fingerprint(system_fingerprint);
+        }
         if let Some(choices) = data.get("choices").and_then(|v| v.as_array()) {
             if let Some(choice) = choices.first() {
                 if let Some(delta) = choice.get("delta") {
@@ -149,6 +180,11 @@ pub async fn openai_chat_completions_streaming(
                         }
                         handler.text(text)?;
                     }
+                    if let Some(images) = delta.get("images").and_then(|v| v.as_array()) {
+                        for image in images.iter().filter_map(openai_extract_image) {
+                            handler.image(image)?;
+                        }
+                    }
                     if let Some(tool_calls) = delta.get("tool_calls").and_then(|v| v.as_array()) {
                         if let Some(tool_call) = tool_calls.first() {
                             if let (Some(function), index, Some(id)) = (
@@ -209,6 +245,7 @@ pub async fn openai_embeddings(
     builder: RequestBuilder,
     _model: &Model,
 ) -> Result<EmbeddingsOutput> {
+    record_audit_request(&builder);
     let res = builder.send().await?;
     let status = res.status();
     let data: Value = res.json().await?;
@@ -238,8 +275,15 @@ pub fn openai_build_chat_completions_body(data: ChatCompletionsData, model: &Mod
         top_p,
         frequency_penalty,
         presence_penalty,
+        logprobs,
+        top_logprobs,
+        seed,
+        stop,
         functions,
         stream,
+        google_search: _,
+        guided: _,
+        documents: _,
     } = data;
 
     let messages_len = messages.len();
@@ -342,6 +386,18 @@ pub fn openai_build_chat_completions_body(data: ChatCompletionsData, model: &Mod
     if let Some(v) = presence_penalty {
         body["presence_penalty"] = v.into();
     }
+    if logprobs || top_logprobs.is_some() {
+        body["logprobs"] = true.into();
+    }
+    if let Some(v) = top_logprobs {
+        body["top_logprobs"] = v.into();
+    }
+    if let Some(v) = seed {
+        body["seed"] = v.into();
+    }
+    if let Some(v) = stop {
+        body["stop"] = v.into();
+    }
     if stream {
         body["stream"] = true.into();
     }
@@ -418,7 +474,12 @@ pub fn openai_extract_chat_completions(data: &Value) -> Result<ChatCompletionsOu
         }
     };
 
-    if text.is_empty() && tool_calls.is_empty() {
+    let images: Vec<OutputImage> = data["choices"][0]["message"]["images"]
+        .as_array()
+        .map(|v| v.iter().filter_map(openai_extract_image).collect())
+        .unwrap_or_default();
+
+    if text.is_empty() && tool_calls.is_empty() && images.is_empty() {
         bail!("Invalid response data: {data}");
     }
     let text = if !reasoning.is_empty() {
@@ -432,10 +493,22 @@ pub fn openai_extract_chat_completions(data: &Value) -> Result<ChatCompletionsOu
         id: data["id"].as_str().map(|v| v.to_string()),
         input_tokens: data["usage"]["prompt_tokens"].as_u64(),
         output_tokens: data["usage"]["completion_tokens"].as_u64(),
+        logprobs: data["choices"][0]["logprobs"]["content"]
+            .as_array()
+            .map(|v| Value::Array(v.clone())),
+        system_fingerprint: data["system_fingerprint"].as_str().map(|v| v.to_string()),
+        images,
+        sources: None,
     };
     Ok(output)
 }
 
+/// Reads an `{"type": "image_url", "image_url": {"url": "data:..."}}` entry of `images`,
+/// the way OpenRouter returns generated images.
+fn openai_extract_image(value: &Value) -> Option<OutputImage> {
+    OutputImage::from_data_url(value["image_url"]["url"].as_str()?)
+}
+
 fn normalize_function_id(value: &str) -> Option<String> {
     if value.is_empty() {
         None
@@ -443,3 +516,48 @@ fn normalize_function_id(value: &str) -> Option<String> {
         Some(value.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    use parking_lot::RwLock;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_validate() {
+        let mut config = OpenAIConfig {
+            name: Some("scopecheck".into()),
+            organization_id: Some("org-abc".into()),
+            project_id: Some("proj_abc".into()),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        config.organization_id = Some("abc".into());
+        assert!(config.validate().is_err());
+        config.organization_id = None;
+        config.project_id = Some("abc".into());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_scope_env_override() {
+        let config = OpenAIConfig {
+            name: Some("scopeenv".into()),
+            project_id: Some("proj_abc".into()),
+            ..Default::default()
+        };
+        std::env::set_var("SCOPEENV_PROJECT_ID", "abc");
+        let client = OpenAIClient {
+            global_config: Arc::new(RwLock::new(Config::default())),
+            config: config.clone(),
+            model: Model::new("scopeenv", "gpt-4o"),
+        };
+        assert_eq!(client.get_project_id().unwrap(), "abc");
+        assert!(config.validate().is_err());
+        std::env::remove_var("SCOPEENV_PROJECT_ID");
+        assert_eq!(client.get_project_id().unwrap(), "proj_abc");
+        assert!(config.validate().is_ok());
+    }
+}
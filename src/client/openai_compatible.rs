@@ -11,12 +11,42 @@ pub struct OpenAICompatibleConfig {
     pub name: Option<String>,
     pub api_base: Option<String>,
     pub api_key: Option<String>,
+    pub provider: Option<ProviderRouting>,
     #[serde(default)]
     pub models: Vec<ModelData>,
     pub patch: Option<RequestPatch>,
     pub extra: Option<ExtraConfig>,
 }
 
+/// OpenRouter's `provider` routing preferences, sent as is with chat requests.
+/// See https://openrouter.ai/docs/features/provider-routing
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ProviderRouting {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub only: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ignore: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_fallbacks: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub require_parameters: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_collection: Option<DataCollection>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sort: Option<String>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DataCollection {
+    Allow,
+    Deny,
+}
+
 impl OpenAICompatibleClient {
     config_get_fn!(api_base, get_api_base);
     config_get_fn!(api_key, get_api_key);
@@ -44,7 +74,10 @@ fn prepare_chat_completions(
 
     let url = format!("{api_base}/chat/completions");
 
-    let body = openai_build_chat_completions_body(data, &self_.model);
+    let mut body = openai_build_chat_completions_body(data, &self_.model);
+    if let Some(provider) = &self_.config.provider {
+        body["provider"] = json!(provider);
+    }
 
     let mut request_data = RequestData::new(url, body);
 
@@ -118,6 +151,7 @@ fn get_api_base_ext(self_: &OpenAICompatibleClient) -> Result<String> {
 }
 
 pub async fn generic_rerank(builder: RequestBuilder, _model: &Model) -> Result<RerankOutput> {
+    record_audit_request(&builder);
     let res = builder.send().await?;
     let status = res.status();
     let mut data: Value = res.json().await?;
@@ -1,18 +1,31 @@
-use super::{catch_error, ToolCall};
-use crate::utils::AbortSignal;
+use super::{catch_error, record_audit_request, OutputImage, ToolCall};
+use crate::utils::{estimate_token_length, AbortSignal};
 
 use anyhow::{anyhow, bail, Context, Result};
 use futures_util::{Stream, StreamExt};
 use reqwest::RequestBuilder;
 use reqwest_eventsource::{Error as EventSourceError, Event, RequestBuilderExt};
 use serde_json::Value;
-use tokio::sync::mpsc::UnboundedSender;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc::UnboundedSender, Notify};
+
+tokio::task_local! {
+    static STREAM_ACTIVITY: Arc<Notify>;
+}
 
 pub struct SseHandler {
     sender: UnboundedSender<SseEvent>,
     abort_signal: AbortSignal,
     buffer: String,
     tool_calls: Vec<ToolCall>,
+    images: Vec<OutputImage>,
+    system_fingerprint: Option<String>,
+    first_token: Arc<Notify>,
+    started: Instant,
+    first_token_at: Option<Instant>,
+    chunks: Option<Vec<String>>,
 }
 
 impl SseHandler {
@@ -22,6 +35,12 @@ impl SseHandler {
             abort_signal,
             buffer: String::new(),
             tool_calls: Vec::new(),
+            images: Vec::new(),
+            system_fingerprint: None,
+            first_token: Arc::new(Notify::new()),
+            started: Instant::now(),
+            first_token_at: None,
+            chunks: None,
         }
     }
 
@@ -30,7 +49,13 @@ impl SseHandler {
         if text.is_empty() {
             return Ok(());
         }
+        if self.buffer.is_empty() {
+            self.mark_first_token();
+        }
         self.buffer.push_str(text);
+        if let Some(chunks) = self.chunks.as_mut() {
+            chunks.push(text.to_string());
+        }
         let ret = self
             .sender
             .send(SseEvent::Text(text.to_string()))
@@ -57,18 +82,93 @@ impl SseHandler {
 
     pub fn tool_call(&mut self, call: ToolCall) -> Result<()> {
         // debug!("HandleCall: {:?}", call);
+        self.mark_first_token();
         self.tool_calls.push(call);
         Ok(())
     }
 
+    /// Holds an image of the reply until the stream ends and it can be saved.
+    pub fn image(&mut self, image: OutputImage) -> Result<()> {
+        self.mark_first_token();
+        self.images.push(image);
+        Ok(())
+    }
+
+    /// Drops what the reply has streamed so far, to start it over after a stall. Only meant
+    /// for replies that sent no text downstream yet; the notice is shown out of band.
+    pub fn restart(&mut self, notice: &str) -> Result<()> {
+        self.buffer.clear();
+        self.tool_calls.clear();
+        self.images.clear();
+        if let Some(chunks) = self.chunks.as_mut() {
+            chunks.clear();
+        }
+        self.notice(notice)
+    }
+
+    /// Shows text along with the reply without making it part of it, so it isn't saved,
+    /// piped along with the reply or sent back to the model.
+    pub fn notice(&mut self, text: &str) -> Result<()> {
+        self.sender
+            .send(SseEvent::Notice(text.to_string()))
+            .with_context(|| "Failed to send SseEvent:Notice")
+    }
+
+    pub fn take_images(&mut self) -> Vec<OutputImage> {
+        std::mem::take(&mut self.images)
+    }
+
+    /// Keeps each text chunk as it arrives, so a cassette can replay the stream as it came.
+    pub fn record_chunks(&mut self) {
+        self.chunks.get_or_insert_with(Vec::new);
+    }
+
+    pub fn take_chunks(&mut self) -> Vec<String> {
+        self.chunks.take().unwrap_or_default()
+    }
+
     pub fn abort(&self) -> AbortSignal {
         self.abort_signal.clone()
     }
 
+    /// Notified once the first text or tool call arrives.
+    pub fn first_token(&self) -> Arc<Notify> {
+        self.first_token.clone()
+    }
+
+    /// Timings of the reply so far, counted from when the handler was created.
+    pub fn stats(&self) -> StreamStats {
+        let total = self.started.elapsed();
+        let first_token = self.first_token_at.map(|v| v - self.started);
+        let output_tokens = estimate_token_length(&self.buffer);
+        StreamStats {
+            first_token,
+            total,
+            output_tokens,
+        }
+    }
+
+    fn mark_first_token(&mut self) {
+        self.first_token.notify_one();
+        self.first_token_at.get_or_insert_with(Instant::now);
+    }
+
+    pub fn buffer(&self) -> &str {
+        &self.buffer
+    }
+
     pub fn tool_calls(&self) -> &[ToolCall] {
         &self.tool_calls
     }
 
+    pub fn set_system_fingerprint(&mut self, value: &str) {
+        self.system_fingerprint = Some(value.to_string());
+    }
+
+    pub fn system_fingerprint(&self) -> Option<&str> {
+        self.system_fingerprint.as_deref()
+    }
+
     pub fn take(self) -> (String, Vec<ToolCall>) {
         let Self {
             buffer, tool_calls, ..
@@ -77,12 +177,61 @@ impl SseHandler {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct StreamStats {
+    pub first_token: Option<Duration>,
+    pub total: Duration,
+    /// Estimated, streams don't report usage
+    pub output_tokens: usize,
+}
+
+impl StreamStats {
+    /// Output rate over the time spent generating, after the first token arrived.
+    pub fn tokens_per_second(&self) -> Option<f64> {
+        let generating = self.total.checked_sub(self.first_token?)?.as_secs_f64();
+        if generating <= 0.0 || self.output_tokens == 0 {
+            return None;
+        }
+        Some(self.output_tokens as f64 / generating)
+    }
+}
+
+impl std::fmt::Display for StreamStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(first_token) = self.first_token {
+            write!(f, "first token {:.2}s · ", first_token.as_secs_f64())?;
+        }
+        write!(
+            f,
+            "total {:.2}s · ~{} tokens",
+            self.total.as_secs_f64(),
+            self.output_tokens
+        )?;
+        if let Some(rate) = self.tokens_per_second() {
+            write!(f, " · {rate:.1} tokens/s")?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub enum SseEvent {
     Text(String),
+    /// Text for the user that isn't part of the reply
+    Notice(String),
     Done,
 }
 
+/// Runs `fut` with `activity` notified whenever the stream readers below receive data, so a
+/// watchdog can tell a stalled stream from a slow one.
+pub async fn watch_stream_activity<F: Future>(activity: Arc<Notify>, fut: F) -> F::Output {
+    STREAM_ACTIVITY.scope(activity, fut).await
+}
+
+pub fn mark_stream_activity() {
+    let _ = STREAM_ACTIVITY.try_with(|v| v.notify_one());
+}
+
 #[derive(Debug)]
 pub struct SseMmessage {
     #[allow(unused)]
@@ -94,8 +243,10 @@ pub async fn sse_stream<F>(builder: RequestBuilder, mut handle: F) -> Result<()>
 where
     F: FnMut(SseMmessage) -> Result<bool>,
 {
+    record_audit_request(&builder);
     let mut es = builder.eventsource()?;
     while let Some(event) = es.next().await {
+        mark_stream_activity();
         match event {
             Ok(Event::Open) => {}
             Ok(Event::Message(message)) => {
@@ -150,6 +301,7 @@ where
     let mut parser = JsonStreamParser::default();
     let mut unparsed_bytes = vec![];
     while let Some(chunk_bytes) = stream.next().await {
+        mark_stream_activity();
         let chunk_bytes =
             chunk_bytes.map_err(|err| anyhow!("Failed to read json stream, {err}"))?;
         unparsed_bytes.extend(chunk_bytes);
@@ -212,10 +364,8 @@ impl JsonStreamParser {
                     }
                     self.balances.push(ch);
                 }
-                '[' => {
-                    if self.start.is_some() {
-                        self.balances.push(ch);
-                    }
+                '[' if self.start.is_some() => {
+                    self.balances.push(ch);
                 }
                 '}' => {
                     self.balances.pop();
@@ -293,4 +443,32 @@ mod tests {
 {"key": "value3"}"#;
         assert_json_stream!(input, output);
     }
+
+    #[test]
+    fn test_notices_stay_out_of_the_reply() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = SseHandler::new(tx, crate::utils::create_abort_signal());
+        handler.text("Hello").unwrap();
+        handler.notice("Sources: ...").unwrap();
+        handler.restart("Retrying").unwrap();
+        handler.text("Hi").unwrap();
+        assert_eq!(handler.buffer(), "Hi");
+        let mut events = vec![];
+        while let Ok(event) = rx.try_recv() {
+            events.push(match event {
+                SseEvent::Text(v) => format!("text:{v}"),
+                SseEvent::Notice(v) => format!("notice:{v}"),
+                SseEvent::Done => "done".into(),
+            });
+        }
+        assert_eq!(
+            events,
+            [
+                "text:Hello",
+                "notice:Sources: ...",
+                "notice:Retrying",
+                "text:Hi"
+            ]
+        );
+    }
 }
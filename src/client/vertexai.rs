@@ -45,7 +45,7 @@ impl Client for VertexAIClient {
         let model = self.model();
         let model_category = ModelCategory::from_str(model.real_name())?;
         let request_data = prepare_chat_completions(self, data, &model_category)?;
-        let builder = self.request_builder(client, request_data);
+        let builder = self.request_builder(client, request_data).await?;
         match model_category {
             ModelCategory::Gemini => gemini_chat_completions(builder, model).await,
             ModelCategory::Claude => claude_chat_completions(builder, model).await,
@@ -63,7 +63,7 @@ impl Client for VertexAIClient {
         let model = self.model();
         let model_category = ModelCategory::from_str(model.real_name())?;
         let request_data = prepare_chat_completions(self, data, &model_category)?;
-        let builder = self.request_builder(client, request_data);
+        let builder = self.request_builder(client, request_data).await?;
         match model_category {
             ModelCategory::Gemini => {
                 gemini_chat_completions_streaming(builder, handler, model).await
@@ -84,7 +84,7 @@ impl Client for VertexAIClient {
     ) -> Result<Vec<Vec<f32>>> {
         prepare_gcloud_access_token(client, self.name(), &self.config.adc_file).await?;
         let request_data = prepare_embeddings(self, data)?;
-        let builder = self.request_builder(client, request_data);
+        let builder = self.request_builder(client, request_data).await?;
         embeddings(builder, self.model()).await
     }
 }
@@ -184,6 +184,7 @@ pub async fn gemini_chat_completions(
     builder: RequestBuilder,
     _model: &Model,
 ) -> Result<ChatCompletionsOutput> {
+    record_audit_request(&builder);
     let res = builder.send().await?;
     let status = res.status();
     let data: Value = res.json().await?;
@@ -199,17 +200,22 @@ pub async fn gemini_chat_completions_streaming(
     handler: &mut SseHandler,
     _model: &Model,
 ) -> Result<()> {
+    record_audit_request(&builder);
     let res = builder.send().await?;
     let status = res.status();
     if !status.is_success() {
         let data: Value = res.json().await?;
         catch_error(&data, status.as_u16())?;
     } else {
+        let mut grounding = None;
         let handle = |value: &str| -> Result<()> {
             let data: Value = serde_json::from_str(value)?;
             debug!("stream-data: {data}");
             if let Some(candidates) = data.get("candidates").and_then(|v| v.as_array()) {
                 if let Some(candidate) = candidates.first() {
+                    if let Some(metadata) = candidate.get("groundingMetadata") {
+                        grounding = Some(metadata.clone());
+                    }
                     if let Some(parts) = candidate.get("content")
                         .and_then(|v| v.get("parts"))
                         .and_then(|v| v.as_array())
@@ -229,6 +235,8 @@ pub async fn gemini_chat_completions_streaming(
                                     .and_then(|v| v.as_object()),
                             ) {
                                 handler.tool_call(ToolCall::new(name.to_string(), json!(args), None))?;
+                            } else if let Some(image) = gemini_extract_image(part) {
+                                handler.image(image)?;
                             }
                         }
                     }
@@ -245,11 +253,15 @@ pub async fn gemini_chat_completions_streaming(
             Ok(())
         };
         json_stream(res.bytes_stream(), handle).await?;
+        if let Some(text) = grounding.as_ref().and_then(gemini_render_grounding) {
+            handler.notice(&format!("\n\n{text}"))?;
+        }
     }
     Ok(())
 }
 
 async fn embeddings(builder: RequestBuilder, _model: &Model) -> Result<EmbeddingsOutput> {
+    record_audit_request(&builder);
     let res = builder.send().await?;
     let status = res.status();
     let data: Value = res.json().await?;
@@ -284,6 +296,7 @@ struct EmbeddingsResBodyPredictionEmbeddings {
 fn gemini_extract_chat_completions_text(data: &Value) -> Result<ChatCompletionsOutput> {
     let mut text_parts = vec![];
     let mut tool_calls = vec![];
+    let mut images = vec![];
     if let Some(candidates) = data.get("candidates").and_then(|v| v.as_array()) {
         if let Some(candidate) = candidates.first() {
             if let Some(parts) = candidate.get("content")
@@ -304,13 +317,16 @@ fn gemini_extract_chat_completions_text(data: &Value) -> Result<ChatCompletionsO
                     ) {
                         tool_calls.push(ToolCall::new(name.to_string(), json!(args), None));
                     }
+                    if let Some(image) = gemini_extract_image(part) {
+                        images.push(image);
+                    }
                 }
             }
         }
     }
 
     let text = text_parts.join("\n\n");
-    if text.is_empty() && tool_calls.is_empty() {
+    if text.is_empty() && tool_calls.is_empty() && images.is_empty() {
         if let Some(candidates) = data.get("candidates").and_then(|v| v.as_array()) {
             if let Some(candidate) = candidates.first() {
                 if let Some("SAFETY") = data.get("promptFeedback")
@@ -335,10 +351,96 @@ fn gemini_extract_chat_completions_text(data: &Value) -> Result<ChatCompletionsO
         id: None,
         input_tokens: data["usageMetadata"]["promptTokenCount"].as_u64(),
         output_tokens: data["usageMetadata"]["candidatesTokenCount"].as_u64(),
+        logprobs: gemini_extract_logprobs(&data["candidates"][0]["logprobsResult"]),
+        system_fingerprint: None,
+        images,
+        sources: gemini_render_grounding(&data["candidates"][0]["groundingMetadata"]),
     };
     Ok(output)
 }
 
+/// Reads an `inlineData` part, which is how image output models return their images.
+fn gemini_extract_image(part: &Value) -> Option<OutputImage> {
+    let inline_data = part.get("inlineData")?;
+    Some(OutputImage::new(
+        inline_data["mimeType"].as_str()?,
+        inline_data["data"].as_str()?,
+    ))
+}
+
+/// Lists the web sources of a Google Search grounded answer, followed by the
+/// claims they support with the model's confidence in each.
+fn gemini_render_grounding(metadata: &Value) -> Option<String> {
+    let chunks = metadata["groundingChunks"].as_array()?;
+    if chunks.is_empty() {
+        return None;
+    }
+    let mut output = String::from("Sources:");
+    for (i, chunk) in chunks.iter().enumerate() {
+        let web = &chunk["web"];
+        let uri = web["uri"].as_str().unwrap_or_default();
+        match web["title"].as_str() {
+            Some(title) => output.push_str(&format!("\n[{}] {title} ({uri})", i + 1)),
+            None => output.push_str(&format!("\n[{}] {uri}", i + 1)),
+        }
+    }
+    let supports = metadata["groundingSupports"].as_array();
+    let lines: Vec<String> = supports
+        .into_iter()
+        .flatten()
+        .filter_map(|support| {
+            let text = support["segment"]["text"].as_str()?;
+            let indices: Vec<String> = support["groundingChunkIndices"]
+                .as_array()?
+                .iter()
+                .filter_map(|v| v.as_u64().map(|v| format!("[{}]", v + 1)))
+                .collect();
+            let confidence = support["confidenceScores"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|v| v.as_f64())
+                .reduce(f64::max);
+            Some(match confidence {
+                Some(v) => format!("\"{text}\" {} (confidence {v:.2})", indices.join("")),
+                None => format!("\"{text}\" {}", indices.join("")),
+            })
+        })
+        .collect();
+    if !lines.is_empty() {
+        output.push_str("\n\nGrounding:\n");
+        output.push_str(&lines.join("\n"));
+    }
+    Some(output)
+}
+
+/// Convert gemini `logprobsResult` into the OpenAI `logprobs.content` shape.
+fn gemini_extract_logprobs(data: &Value) -> Option<Value> {
+    let chosen = data["chosenCandidates"].as_array()?;
+    let top = data["topCandidates"].as_array();
+    let list = chosen
+        .iter()
+        .enumerate()
+        .map(|(i, candidate)| {
+            let top_logprobs: Vec<Value> = top
+                .and_then(|v| v.get(i))
+                .and_then(|v| v["candidates"].as_array())
+                .map(|list| {
+                    list.iter()
+                        .map(|v| json!({ "token": v["token"], "logprob": v["logProbability"] }))
+                        .collect()
+                })
+                .unwrap_or_default();
+            json!({
+                "token": candidate["token"],
+                "logprob": candidate["logProbability"],
+                "top_logprobs": top_logprobs,
+            })
+        })
+        .collect();
+    Some(Value::Array(list))
+}
+
 pub fn gemini_build_chat_completions_body(
     data: ChatCompletionsData,
     model: &Model,
@@ -349,8 +451,15 @@ pub fn gemini_build_chat_completions_body(
         top_p,
         frequency_penalty: _,
         presence_penalty: _,
+        logprobs,
+        top_logprobs,
+        seed,
+        stop,
         functions,
         stream: _,
+        google_search,
+        guided: _,
+        documents: _,
     } = data;
 
     let system_message = extract_system_message(&mut messages);
@@ -437,6 +546,18 @@ pub fn gemini_build_chat_completions_body(
     if let Some(v) = top_p {
         body["generationConfig"]["topP"] = v.into();
     }
+    if logprobs || top_logprobs.is_some() {
+        body["generationConfig"]["responseLogprobs"] = true.into();
+    }
+    if let Some(v) = top_logprobs {
+        body["generationConfig"]["logprobs"] = v.into();
+    }
+    if let Some(v) = seed {
+        body["generationConfig"]["seed"] = v.into();
+    }
+    if let Some(v) = stop {
+        body["generationConfig"]["stopSequences"] = v.into();
+    }
 
     if let Some(functions) = functions {
         // Gemini doesn't support functions with parameters that have empty properties, so we need to patch it.
@@ -456,6 +577,13 @@ pub fn gemini_build_chat_completions_body(
         body["tools"] = json!([{ "functionDeclarations": function_declarations }]);
     }
 
+    if google_search {
+        match body["tools"].as_array_mut() {
+            Some(tools) => tools.push(json!({ "google_search": {} })),
+            None => body["tools"] = json!([{ "google_search": {} }]),
+        }
+    }
+
     Ok(body)
 }
 
@@ -570,3 +698,31 @@ fn strip_model_version(name: &str) -> &str {
         None => name,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grounding_stays_out_of_text() {
+        let data = json!({
+            "candidates": [{
+                "content": { "parts": [{ "text": "Rust 1.0 shipped in 2015." }] },
+                "groundingMetadata": {
+                    "groundingChunks": [{ "web": { "uri": "https://blog.rust-lang.org", "title": "Rust Blog" } }],
+                    "groundingSupports": [{
+                        "segment": { "text": "Rust 1.0 shipped in 2015." },
+                        "groundingChunkIndices": [0],
+                        "confidenceScores": [0.9],
+                    }],
+                },
+            }],
+        });
+        let output = gemini_extract_chat_completions_text(&data).unwrap();
+        assert_eq!(output.text, "Rust 1.0 shipped in 2015.");
+        assert_eq!(
+            output.sources.as_deref(),
+            Some("Sources:\n[1] Rust Blog (https://blog.rust-lang.org)\n\nGrounding:\n\"Rust 1.0 shipped in 2015.\" [1] (confidence 0.90)")
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use super::openai::*;
+use super::openai_compatible::{generic_build_rerank_body, generic_rerank};
+use super::*;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+const API_BASE: &str = "http://localhost:8000/v1";
+
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
+pub struct VllmConfig {
+    pub name: Option<String>,
+    pub api_base: Option<String>,
+    pub api_key: Option<String>,
+    pub guided_decoding_backend: Option<String>,
+    #[serde(default)]
+    pub models: Vec<ModelData>,
+    pub patch: Option<RequestPatch>,
+    pub extra: Option<ExtraConfig>,
+}
+
+impl VllmClient {
+    config_get_fn!(api_base, get_api_base);
+    config_get_fn!(api_key, get_api_key);
+
+    pub const PROMPTS: [PromptAction<'static>; 1] = [("api_base", "API Base:", Some(API_BASE))];
+
+    fn api_url(&self, path: &str) -> String {
+        let api_base = self
+            .get_api_base()
+            .unwrap_or_else(|_| API_BASE.to_string());
+        format!("{}{path}", api_base.trim_end_matches('/'))
+    }
+}
+
+impl_client_trait!(
+    VllmClient,
+    (
+        prepare_chat_completions,
+        openai_chat_completions,
+        openai_chat_completions_streaming
+    ),
+    (prepare_embeddings, openai_embeddings),
+    (prepare_rerank, generic_rerank),
+);
+
+fn prepare_chat_completions(self_: &VllmClient, data: ChatCompletionsData) -> Result<RequestData> {
+    let api_key = self_.get_api_key().ok();
+
+    let url = self_.api_url("/chat/completions");
+
+    let guided = data.guided.clone();
+    let mut body = openai_build_chat_completions_body(data, &self_.model);
+    if let Some(Value::Object(guided)) = guided.map(|v| json!(v)) {
+        for (key, value) in guided {
+            body[key] = value;
+        }
+        if let Some(backend) = &self_.config.guided_decoding_backend {
+            body["guided_decoding_backend"] = backend.clone().into();
+        }
+    }
+
+    let mut request_data = RequestData::new(url, body);
+
+    if let Some(api_key) = api_key {
+        request_data.bearer_auth(api_key);
+    }
+
+    Ok(request_data)
+}
+
+fn prepare_embeddings(self_: &VllmClient, data: &EmbeddingsData) -> Result<RequestData> {
+    let api_key = self_.get_api_key().ok();
+
+    let url = self_.api_url("/embeddings");
+
+    let body = openai_build_embeddings_body(data, &self_.model);
+
+    let mut request_data = RequestData::new(url, body);
+
+    if let Some(api_key) = api_key {
+        request_data.bearer_auth(api_key);
+    }
+
+    Ok(request_data)
+}
+
+fn prepare_rerank(self_: &VllmClient, data: &RerankData) -> Result<RequestData> {
+    let api_key = self_.get_api_key().ok();
+
+    let url = self_.api_url("/rerank");
+
+    let body = generic_build_rerank_body(data, &self_.model);
+
+    let mut request_data = RequestData::new(url, body);
+
+    if let Some(api_key) = api_key {
+        request_data.bearer_auth(api_key);
+    }
+
+    Ok(request_data)
+}
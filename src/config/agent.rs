@@ -75,12 +75,19 @@ impl Agent {
                     if agent_config.presence_penalty.is_none() {
                         agent_config.presence_penalty = config.presence_penalty;
                     }
+                    if agent_config.stop.is_none() {
+                        agent_config.stop = config.stop.clone();
+                    }
                     config.current_model().clone()
                 }
             }
         };
 
-        let rag = if rag_path.exists() {
+        let rag = if let Some(value) = &definition.rag {
+            let rag = Config::load_bound_rag(config, value)
+                .with_context(|| format!("Invalid rag of agent `{name}`"))?;
+            Some(Arc::new(rag))
+        } else if rag_path.exists() {
             Some(Arc::new(Rag::load(config, DEFAULT_AGENT_NAME, &rag_path)?))
         } else if !definition.documents.is_empty() && !config.read().info_flag {
             let mut ans = false;
@@ -151,7 +158,7 @@ impl Agent {
                     }
                     if *IS_STDOUT_TERMINAL {
                         if !printed {
-                            println!("⚙ Init agent variables...");
+                            println!("⚙ Init variables...");
                             printed = true;
                         }
                         let value = Text::new(&format!(
@@ -175,7 +182,7 @@ impl Agent {
         }
         if !unset_variables.is_empty() {
             bail!(
-                "The following agent variables are required:\n{}",
+                "The following variables are required:\n{}",
                 unset_variables
                     .iter()
                     .map(|v| format!("  - {}: {}", v.name, v.description))
@@ -187,6 +194,12 @@ impl Agent {
     }
 
     pub fn export(&self) -> Result<String> {
+        let value = self.export_value()?;
+        let data = serde_yaml::to_string(&value)?;
+        Ok(data)
+    }
+
+    pub fn export_value(&self) -> Result<Value> {
         let mut value = json!({});
         value["name"] = json!(self.name());
         let variables = self.variables();
@@ -209,8 +222,7 @@ impl Agent {
             .display()
             .to_string()
             .into();
-        let data = serde_yaml::to_string(&value)?;
-        Ok(data)
+        Ok(value)
     }
 
     pub fn banner(&self) -> String {
@@ -362,6 +374,10 @@ impl RoleLike for Agent {
         self.config.use_tools.clone()
     }
 
+    fn stop(&self) -> Option<Vec<String>> {
+        self.config.stop.clone()
+    }
+
     fn set_model(&mut self, model: Model) {
         self.config.model_id = Some(model.id());
         self.model = model;
@@ -386,6 +402,10 @@ impl RoleLike for Agent {
     fn set_use_tools(&mut self, value: Option<String>) {
         self.config.use_tools = value;
     }
+
+    fn set_stop(&mut self, value: Option<Vec<String>>) {
+        self.config.stop = value;
+    }
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -403,6 +423,8 @@ pub struct AgentConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub use_tools: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub agent_prelude: Option<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub instructions: Option<String>,
@@ -448,6 +470,9 @@ impl AgentConfig {
         if let Some(v) = read_env_value::<String>(&with_prefix("use_tools")) {
             self.use_tools = v;
         }
+        if let Some(v) = read_env_value::<String>(&with_prefix("stop")) {
+            self.stop = v.map(|v| split_stop_value(&v));
+        }
         if let Some(v) = read_env_value::<String>(&with_prefix("agent_prelude")) {
             self.agent_prelude = v;
         }
@@ -479,6 +504,9 @@ pub struct AgentDefinition {
     pub conversation_starters: Vec<String>,
     #[serde(default)]
     pub documents: Vec<String>,
+    /// A saved RAG to use instead of one built from `documents`
+    #[serde(default)]
+    pub rag: Option<String>,
 }
 
 impl AgentDefinition {
@@ -546,7 +574,7 @@ pub struct AgentVariable {
     pub description: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub default: Option<String>,
-    #[serde(skip_deserializing, default)]
+    #[serde(skip_deserializing, default, skip_serializing_if = "String::is_empty")]
     pub value: String,
 }
 
@@ -0,0 +1,129 @@
+use super::*;
+
+use crate::client::{init_client, EmbeddingsData};
+
+/// What to do when a session question nearly repeats an earlier one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupAction {
+    /// Show the earlier answer without calling the model
+    Reuse,
+    /// Tell the model the question was answered before
+    #[default]
+    Reference,
+}
+
+/// An earlier turn the new question closely matches.
+#[derive(Debug, Clone)]
+pub struct RepeatedTurn {
+    pub answer: String,
+    pub similarity: f32,
+}
+
+impl Config {
+    /// Compares the question with the earlier ones of the session using `dedup_model`.
+    /// Under `reference` the prompt sent to the model gets a note; under `reuse` the match is
+    /// returned so the caller can answer locally.
+    pub async fn dedup_question(
+        config: &GlobalConfig,
+        input: &mut Input,
+    ) -> Result<Option<RepeatedTurn>> {
+        let Some(repeated) = Self::find_repeated_turn(config, input).await? else {
+            return Ok(None);
+        };
+        match config.read().dedup_action {
+            DedupAction::Reuse => Ok(Some(repeated)),
+            DedupAction::Reference => {
+                let similarity = repeated.similarity;
+                debug!("Repeated question with similarity {similarity:.3}");
+                input.set_note("(This repeats a question answered earlier in this conversation. Point back to that answer and add only what is new, rather than repeating it in full.)");
+                Ok(None)
+            }
+        }
+    }
+
+    async fn find_repeated_turn(
+        config: &GlobalConfig,
+        input: &Input,
+    ) -> Result<Option<RepeatedTurn>> {
+        let (model_id, threshold, pairs) = {
+            let config = config.read();
+            let (Some(model_id), Some(session)) = (config.dedup_model.clone(), &config.session)
+            else {
+                return Ok(None);
+            };
+            let pairs: Vec<(String, String)> = session
+                .question_answer_pairs()
+                .into_iter()
+                .map(|(q, a)| (q.to_string(), a.to_string()))
+                .collect();
+            (model_id, config.dedup_threshold, pairs)
+        };
+        let question = input.text();
+        if pairs.is_empty()
+            || question.trim().is_empty()
+            || input.tool_calls().is_some()
+            || input.continue_output().is_some()
+            || input.regenerate()
+        {
+            return Ok(None);
+        }
+        let model = Model::retrieve_model(&config.read(), &model_id, ModelType::Embedding)?;
+        let mut texts = vec![question];
+        texts.extend(pairs.iter().map(|(q, _)| q.clone()));
+        let mut cache = EmbeddingCache::load(model.id().as_str())?;
+        let missing: Vec<String> = texts
+            .iter()
+            .filter(|v| cache.get(v).is_none())
+            .cloned()
+            .collect();
+        if !missing.is_empty() {
+            let client = init_client(config, Some(model))?;
+            let embeddings = client
+                .embeddings(&EmbeddingsData::new(missing.clone(), false))
+                .await
+                .context("Failed to embed the question for deduplication")?;
+            for (text, vector) in missing.iter().zip(embeddings) {
+                cache.insert(text, vector);
+            }
+            cache.save()?;
+        }
+        let Some(question) = cache.get(&texts[0]) else {
+            return Ok(None);
+        };
+        let best = pairs
+            .iter()
+            .filter_map(|(q, a)| Some((cosine_similarity(question, cache.get(q)?), a)))
+            .max_by(|a, b| a.0.total_cmp(&b.0));
+        match best {
+            Some((similarity, answer)) if similarity >= threshold => Ok(Some(RepeatedTurn {
+                answer: strip_think_tag(answer).to_string(),
+                similarity,
+            })),
+            _ => Ok(None),
+        }
+    }
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+}
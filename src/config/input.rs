@@ -1,19 +1,51 @@
 use super::*;
 
 use crate::client::{
-    init_client, patch_messages, ChatCompletionsData, Client, ImageUrl, Message, MessageContent,
-    MessageContentPart, MessageContentToolCalls, MessageRole, Model,
+    client_type, init_client, patch_messages, ChatCompletionsData, ChatDocument, Client,
+    CohereClient, ImageUrl, Message, MessageContent, MessageContentPart, MessageContentToolCalls,
+    MessageRole, Model,
+};
+use crate::function::{FunctionDeclaration, ToolResult};
+use crate::utils::{
+    base64_encode, build_repo_map, is_loader_protocol, is_repo_map_path, sha256, warning_text,
+    AbortSignal,
 };
-use crate::function::ToolResult;
-use crate::utils::{base64_encode, is_loader_protocol, sha256, AbortSignal};
 
 use anyhow::{bail, Context, Result};
 use indexmap::IndexSet;
 use std::{collections::HashMap, fs::File, io::Read};
 use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
-const IMAGE_EXTS: [&str; 5] = ["png", "jpeg", "jpg", "webp", "gif"];
+pub const IMAGE_EXTS: [&str; 5] = ["png", "jpeg", "jpg", "webp", "gif"];
 const SUMMARY_MAX_WIDTH: usize = 80;
+/// Output of `cmd:` and backtick input sources beyond this many chars is dropped.
+const MAX_CMD_OUTPUT_CHARS: usize = 100_000;
+
+/// What to do when a request needs a capability the model's metadata doesn't list. Ad-hoc
+/// models list none, so by default the request is only flagged, never changed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CapabilityCheck {
+    /// Fail before sending the request
+    Error,
+    /// Drop the images or tools and warn
+    Strip,
+    /// Warn and send the request as is
+    #[default]
+    Warn,
+    /// Send the request as is
+    Off,
+}
+
+/// A part of the composed input, see [`Input::token_breakdown`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputPart {
+    System,
+    History,
+    Attachment(usize),
+    Rag,
+    Prompt,
+}
 
 #[derive(Debug, Clone)]
 pub struct Input {
@@ -29,8 +61,15 @@ pub struct Input {
     tool_calls: Option<MessageContentToolCalls>,
     role: Role,
     rag_name: Option<String>,
+    documents: Vec<ChatDocument>,
+    /// The label and text of each file, URL or last reply appended to the prompt
+    attachments: Vec<(String, String)>,
     with_session: bool,
     with_agent: bool,
+    /// Fixed tools that replace the ones selected from the role, e.g. for a delegated sub-agent
+    functions: Option<Vec<FunctionDeclaration>>,
+    /// A note for the model added to the prompt when it is sent but never saved with it
+    note: Option<String>,
 }
 
 impl Input {
@@ -49,8 +88,12 @@ impl Input {
             tool_calls: None,
             role,
             rag_name: None,
+            documents: Default::default(),
+            attachments: Default::default(),
             with_session,
             with_agent,
+            functions: None,
+            note: None,
         }
     }
 
@@ -60,12 +103,16 @@ impl Input {
         paths: Vec<String>,
         role: Option<Role>,
     ) -> Result<Self> {
-        let loaders = config.read().document_loaders.clone();
+        let (loaders, repo_map_max_tokens) = {
+            let config = config.read();
+            (config.document_loaders.clone(), config.repo_map_max_tokens)
+        };
         let (raw_paths, local_paths, remote_urls, external_cmds, protocol_paths, with_last_reply) =
             resolve_paths(&loaders, paths)?;
         let mut last_reply = None;
         let (documents, medias, data_urls) = load_documents(
             &loaders,
+            repo_map_max_tokens,
             local_paths,
             remote_urls,
             external_cmds,
@@ -74,6 +121,7 @@ impl Input {
         .await
         .context("Failed to load files")?;
         let mut texts = vec![];
+        let mut attachments = vec![];
         if !raw_text.is_empty() {
             texts.push(raw_text.to_string());
         };
@@ -85,7 +133,9 @@ impl Input {
                     last_reply = Some(v.clone());
                 }
                 if let Some(v) = last_reply.clone() {
-                    texts.push(format!("\n{v}"));
+                    let block = format!("\n{v}");
+                    attachments.push(("last reply".to_string(), block.clone()));
+                    texts.push(block);
                 }
             }
             if last_reply.is_none() && documents.is_empty() && medias.is_empty() {
@@ -94,13 +144,13 @@ impl Input {
         }
         let documents_len = documents.len();
         for (kind, path, contents) in documents {
-            if documents_len == 1 && raw_text.is_empty() {
-                texts.push(format!("\n{contents}"));
+            let block = if documents_len == 1 && raw_text.is_empty() {
+                format!("\n{contents}")
             } else {
-                texts.push(format!(
-                    "\n============ {kind}: {path} ============\n{contents}"
-                ));
-            }
+                format!("\n============ {kind}: {path} ============\n{contents}")
+            };
+            attachments.push((format!("{kind}: {path}"), block.clone()));
+            texts.push(block);
         }
         let (role, with_session, with_agent) = resolve_role(&config.read(), role);
         Ok(Self {
@@ -116,8 +166,12 @@ impl Input {
             tool_calls: Default::default(),
             role,
             rag_name: None,
+            documents: Default::default(),
+            attachments,
             with_session,
             with_agent,
+            functions: None,
+            note: None,
         })
     }
 
@@ -163,6 +217,20 @@ impl Input {
         self.text = text;
     }
 
+    pub fn set_patched_text(&mut self, text: String) {
+        self.patched_text = Some(text);
+    }
+
+    /// Adds a note to the prompt sent to the model, leaving the prompt kept in the session as is.
+    pub fn set_note(&mut self, note: &str) {
+        self.note = Some(note.to_string());
+    }
+
+    /// Attaches images, given as data URLs.
+    pub fn set_medias(&mut self, medias: Vec<String>) {
+        self.medias = medias;
+    }
+
     pub fn stream(&self) -> bool {
         self.config.read().stream && !self.role().model().no_stream()
     }
@@ -183,6 +251,10 @@ impl Input {
         self.regenerate
     }
 
+    pub fn set_model(&mut self, model: Model) {
+        self.role.set_model(model);
+    }
+
     pub fn set_regenerate(&mut self) {
         let role = self.config.read().extract_role();
         if role.name() == self.role().name() {
@@ -198,17 +270,104 @@ impl Input {
         }
         let rag = self.config.read().rag.clone();
         if let Some(rag) = rag {
-            let result = Config::search_rag(&self.config, &rag, &self.text, abort_signal).await?;
-            self.patched_text = Some(result);
+            let client_name = self.role().model().client_name().to_string();
+            if client_type(&self.config.read(), &client_name) == Some(CohereClient::NAME) {
+                self.documents =
+                    Config::search_rag_documents(&self.config, &rag, &self.text, abort_signal)
+                        .await?;
+            } else {
+                let result =
+                    Config::search_rag(&self.config, &rag, &self.text, abort_signal).await?;
+                self.patched_text = Some(result);
+            }
             self.rag_name = Some(rag.name().to_string());
         }
         Ok(())
     }
 
+    /// Leaves out the RAG context found for the input.
+    pub fn drop_rag(&mut self) {
+        self.patched_text = None;
+        self.rag_name = None;
+        self.documents.clear();
+    }
+
+    pub fn attachments(&self) -> &[(String, String)] {
+        &self.attachments
+    }
+
+    /// Puts a summary in place of an attachment, or drops it when `contents` is `None`.
+    pub fn replace_attachment(&mut self, index: usize, contents: Option<&str>) {
+        if index >= self.attachments.len() {
+            return;
+        }
+        let (label, block) = self.attachments.remove(index);
+        let new_block = match contents {
+            Some(contents) => {
+                let label = format!("{label} (summary)");
+                let new_block = format!("\n============ {label} ============\n{contents}");
+                self.attachments.insert(index, (label, new_block.clone()));
+                new_block
+            }
+            None => String::new(),
+        };
+        self.text = self.text.replacen(&block, &new_block, 1);
+        if let Some(text) = self.patched_text.as_mut() {
+            *text = text.replacen(&block, &new_block, 1);
+        }
+    }
+
+    /// Estimates the tokens of each part of the composed input. Returns the total and the
+    /// system prompt, history, attachments, RAG context and prompt, leaving out empty parts.
+    pub fn token_breakdown(&self) -> Result<(usize, Vec<(InputPart, usize)>)> {
+        let model = self.role().model();
+        let messages = self.build_messages()?;
+        let total = model.total_tokens(&messages);
+        let (system, rest): (Vec<Message>, Vec<Message>) =
+            messages.into_iter().partition(|v| v.role.is_system());
+        let system = model.messages_tokens(&system);
+        let last = match rest.last() {
+            Some(message) if message.role.is_user() => {
+                model.messages_tokens(std::slice::from_ref(message))
+            }
+            _ => 0,
+        };
+        let history = model.messages_tokens(&rest).saturating_sub(last);
+        let rag = match &self.patched_text {
+            Some(text) => {
+                estimate_token_length(text).saturating_sub(estimate_token_length(&self.text))
+            }
+            None => 0,
+        };
+        let mut parts = vec![(InputPart::System, system), (InputPart::History, history)];
+        let mut attached = 0;
+        for (index, (_, block)) in self.attachments.iter().enumerate() {
+            let tokens = estimate_token_length(block);
+            attached += tokens;
+            parts.push((InputPart::Attachment(index), tokens));
+        }
+        parts.push((InputPart::Rag, rag));
+        parts.push((InputPart::Prompt, last.saturating_sub(rag + attached)));
+        parts.retain(|(_, tokens)| *tokens > 0);
+        Ok((total, parts))
+    }
+
     pub fn rag_name(&self) -> Option<&str> {
         self.rag_name.as_deref()
     }
 
+    /// The numbered sources to list under a reply grounded on cited RAG context.
+    pub fn rag_citation_sources(&self) -> Option<String> {
+        if self.rag_name.is_none() || !self.documents.is_empty() {
+            return None;
+        }
+        let config = self.config.read();
+        if !config.rag_citations {
+            return None;
+        }
+        config.rag.as_ref()?.get_last_sources()
+    }
+
     pub fn merge_tool_results(mut self, output: String, tool_results: Vec<ToolResult>) -> Self {
         match self.tool_calls.as_mut() {
             Some(exist_tool_results) => {
@@ -223,9 +382,13 @@ impl Input {
         init_client(&self.config, Some(self.role().model().clone()))
     }
 
+    /// Asks for a reply that aichat uses itself, such as a summary or a title, so no tools
+    /// are offered.
     pub async fn fetch_chat_text(&self) -> Result<String> {
-        let client = self.create_client()?;
-        let text = client.chat_completions(self.clone()).await?.text;
+        let mut input = self.clone();
+        input.set_functions(vec![]);
+        let client = input.create_client()?;
+        let text = client.chat_completions(input).await?.text;
         let text = strip_think_tag(&text).to_string();
         Ok(text)
     }
@@ -237,21 +400,52 @@ impl Input {
     ) -> Result<ChatCompletionsData> {
         let mut messages = self.build_messages()?;
         patch_messages(&mut messages, model);
+        let mut functions = match &self.functions {
+            Some(functions) => (!functions.is_empty()).then(|| functions.clone()),
+            None => {
+                let mut functions = self.config.read().select_functions(self.role());
+                if model.data().supports_function_calling && !self.role().is_builtin() {
+                    self.config.read().append_builtin_functions(&mut functions);
+                }
+                functions
+            }
+        };
+        let capability_check = self.config.read().capability_check;
+        check_capabilities(model, capability_check, &mut messages, &mut functions)?;
         model.guard_max_input_tokens(&messages)?;
         let (temperature, top_p) = (self.role().temperature(), self.role().top_p());
         let (frequency_penalty, presence_penalty) = (
             self.role().frequency_penalty(),
             self.role().presence_penalty(),
         );
-        let functions = self.config.read().select_functions(self.role());
+        let (logprobs, top_logprobs, seed, google_search) = {
+            let config = self.config.read();
+            (
+                config.logprobs,
+                config.top_logprobs,
+                config.seed,
+                self.role().google_search().unwrap_or(config.google_search),
+            )
+        };
+        let stop = self
+            .role()
+            .stop()
+            .or_else(|| model.stop().map(|v| v.to_vec()));
         Ok(ChatCompletionsData {
             messages,
             temperature,
             top_p,
             frequency_penalty,
             presence_penalty,
+            logprobs,
+            top_logprobs,
+            seed,
+            stop,
             functions,
             stream,
+            google_search,
+            guided: self.role().guided(),
+            documents: (!self.documents.is_empty()).then(|| self.documents.clone()),
         })
     }
 
@@ -261,6 +455,17 @@ impl Input {
         } else {
             self.role().build_messages(self)
         };
+        if let Some(note) = &self.note {
+            if let Some(message) = messages.last_mut().filter(|v| v.role.is_user()) {
+                match &mut message.content {
+                    MessageContent::Text(text) => text.push_str(&format!("\n\n{note}")),
+                    MessageContent::Array(list) => list.push(MessageContentPart::Text {
+                        text: note.to_string(),
+                    }),
+                    MessageContent::ToolCalls(_) => {}
+                }
+            }
+        }
         if let Some(tool_calls) = &self.tool_calls {
             messages.push(Message::new(
                 MessageRole::Assistant,
@@ -298,6 +503,14 @@ impl Input {
         }
     }
 
+    pub fn set_functions(&mut self, functions: Vec<FunctionDeclaration>) {
+        self.functions = Some(functions);
+    }
+
+    pub fn with_session(&self) -> bool {
+        self.with_session
+    }
+
     pub fn with_agent(&self) -> bool {
         self.with_agent
     }
@@ -417,13 +630,19 @@ fn resolve_paths(
         } else if path.starts_with('`') && path.len() > 2 && path.ends_with('`') {
             external_cmds.insert(path[1..path.len() - 1].to_string());
             raw_paths.insert(path);
+        } else if let Some(cmd) = path.strip_prefix("cmd:") {
+            if cmd.trim().is_empty() {
+                bail!("Invalid command '{path}'");
+            }
+            external_cmds.insert(cmd.trim().to_string());
+            raw_paths.insert(path);
         } else if is_url(&path) {
             if path.strip_suffix("**").is_some() {
                 bail!("Invalid website '{path}'");
             }
             remote_urls.insert(path.clone());
             raw_paths.insert(path);
-        } else if is_loader_protocol(loaders, &path) {
+        } else if is_repo_map_path(&path) || is_loader_protocol(loaders, &path) {
             protocol_paths.insert(path.clone());
             raw_paths.insert(path);
         } else {
@@ -446,6 +665,7 @@ fn resolve_paths(
 
 async fn load_documents(
     loaders: &HashMap<String, String>,
+    repo_map_max_tokens: usize,
     local_paths: Vec<String>,
     remote_urls: Vec<String>,
     external_cmds: Vec<String>,
@@ -460,11 +680,7 @@ async fn load_documents(
     let mut data_urls = HashMap::new();
 
     for cmd in external_cmds {
-        let output = duct::cmd(&SHELL.cmd, &[&SHELL.arg, &cmd])
-            .stderr_to_stdout()
-            .unchecked()
-            .read()
-            .unwrap_or_else(|err| err.to_string());
+        let output = run_input_command(&cmd);
         files.push(("CMD", cmd, output));
     }
 
@@ -496,6 +712,12 @@ async fn load_documents(
     }
 
     for protocol_path in protocol_paths {
+        if is_repo_map_path(&protocol_path) {
+            let contents = build_repo_map(&protocol_path, repo_map_max_tokens)
+                .with_context(|| format!("Failed to build repo map from '{protocol_path}'"))?;
+            files.push(("REPO-MAP", protocol_path, contents));
+            continue;
+        }
         let documents = load_protocol_path(loaders, &protocol_path)
             .with_context(|| format!("Failed to load from '{protocol_path}'"))?;
         files.extend(
@@ -508,6 +730,126 @@ async fn load_documents(
     Ok((files, medias, data_urls))
 }
 
+/// Runs a command input source, capturing stdout and stderr together. Output past the cap
+/// isn't read; the command is killed instead, so endless output can't hang the input.
+fn run_input_command(cmd: &str) -> String {
+    let reader = match duct::cmd(&SHELL.cmd, &[&SHELL.arg, cmd])
+        .stderr_to_stdout()
+        .unchecked()
+        .reader()
+    {
+        Ok(reader) => reader,
+        Err(err) => return err.to_string(),
+    };
+    let max_bytes = MAX_CMD_OUTPUT_CHARS * 4;
+    let mut data = vec![];
+    if let Err(err) = (&reader).take(max_bytes as u64 + 1).read_to_end(&mut data) {
+        return err.to_string();
+    }
+    let mut truncated = data.len() > max_bytes;
+    let status = if truncated {
+        let _ = reader.kill();
+        None
+    } else {
+        reader.try_wait().ok().flatten().map(|v| v.status)
+    };
+    let stdout = String::from_utf8_lossy(&data);
+    let mut stdout = stdout.as_ref();
+    if let Some((index, _)) = stdout.char_indices().nth(MAX_CMD_OUTPUT_CHARS) {
+        stdout = &stdout[..index];
+        truncated = true;
+    }
+    let mut text = stdout.trim_end().to_string();
+    if truncated {
+        text.push_str("\n[output truncated]");
+    }
+    if let Some(status) = status.filter(|v| !v.success()) {
+        let code = status
+            .code()
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| "signal".into());
+        text.push_str(&format!("\n[exit status: {code}]"));
+    }
+    text
+}
+
+/// Holds the request to what the model's metadata says it supports, failing, stripping or
+/// warning according to `capability_check`.
+fn check_capabilities(
+    model: &Model,
+    check: CapabilityCheck,
+    messages: &mut [Message],
+    functions: &mut Option<Vec<FunctionDeclaration>>,
+) -> Result<()> {
+    if check == CapabilityCheck::Off {
+        return Ok(());
+    }
+    let model_id = model.id();
+    let has_images = messages.iter().any(|message| match &message.content {
+        MessageContent::Array(parts) => parts
+            .iter()
+            .any(|part| matches!(part, MessageContentPart::ImageUrl { .. })),
+        _ => false,
+    });
+    if has_images && !model.data().supports_vision {
+        match check {
+            CapabilityCheck::Error => bail!("Model '{model_id}' doesn't support images. If it does, set `supports_vision: true` for it in the config, or set `capability_check: strip` to send the text only."),
+            CapabilityCheck::Strip => {
+                for message in messages.iter_mut() {
+                    strip_images(&mut message.content);
+                }
+                eprintln!(
+                    "{}",
+                    warning_text(&format!(
+                        "⚠️ Model '{model_id}' doesn't support images, sending the text only"
+                    ))
+                );
+            }
+            _ => eprintln!(
+                "{}",
+                warning_text(&format!(
+                    "⚠️ Model '{model_id}' isn't marked with `supports_vision`, sending the images anyway"
+                ))
+            ),
+        }
+    }
+    if functions.is_some() && !model.data().supports_function_calling {
+        match check {
+            CapabilityCheck::Error => bail!("Model '{model_id}' doesn't support function calling. If it does, set `supports_function_calling: true` for it in the config, or set `capability_check: strip` to send the request without tools."),
+            CapabilityCheck::Strip => {
+                *functions = None;
+                eprintln!(
+                    "{}",
+                    warning_text(&format!(
+                        "⚠️ Model '{model_id}' doesn't support function calling, sending without tools"
+                    ))
+                );
+            }
+            _ => eprintln!(
+                "{}",
+                warning_text(&format!(
+                    "⚠️ Model '{model_id}' isn't marked with `supports_function_calling`, sending the tools anyway"
+                ))
+            ),
+        }
+    }
+    Ok(())
+}
+
+fn strip_images(content: &mut MessageContent) {
+    let MessageContent::Array(parts) = content else {
+        return;
+    };
+    let texts: Vec<&str> = parts
+        .iter()
+        .filter_map(|part| match part {
+            MessageContentPart::Text { text } => Some(text.as_str()),
+            MessageContentPart::ImageUrl { .. } => None,
+        })
+        .collect();
+    *content = MessageContent::Text(texts.join("\n\n"));
+}
+
 pub fn resolve_data_url(data_urls: &HashMap<String, String>, data_url: String) -> String {
     if data_url.starts_with("data:") {
         let hash = sha256(&data_url);
@@ -528,19 +870,150 @@ fn is_image(path: &str) -> bool {
 
 fn read_media_to_data_url(image_path: &str) -> Result<String> {
     let extension = get_patch_extension(image_path).unwrap_or_default();
-    let mime_type = match extension.as_str() {
+    let mut file = File::open(image_path)?;
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+    image_data_url(&extension, &buffer)
+}
+
+/// Encodes an image of a format models accept as a data URL.
+pub fn image_data_url(extension: &str, bytes: &[u8]) -> Result<String> {
+    let mime_type = match extension {
         "png" => "image/png",
         "jpg" | "jpeg" => "image/jpeg",
         "webp" => "image/webp",
         "gif" => "image/gif",
         _ => bail!("Unexpected media type"),
     };
-    let mut file = File::open(image_path)?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
+    let encoded_image = base64_encode(bytes);
+    Ok(format!("data:{mime_type};base64,{encoded_image}"))
+}
 
-    let encoded_image = base64_encode(buffer);
-    let data_url = format!("data:{mime_type};base64,{encoded_image}");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_capabilities() {
+        let model = Model::new("openai-compatible", "my-model");
+        let image = MessageContentPart::ImageUrl {
+            image_url: ImageUrl {
+                url: "data:image/png;base64,AA==".into(),
+            },
+        };
+        let mut messages = vec![Message::new(
+            MessageRole::User,
+            MessageContent::Array(vec![image]),
+        )];
+        let mut functions = Some(vec![]);
+        check_capabilities(
+            &model,
+            CapabilityCheck::default(),
+            &mut messages,
+            &mut functions,
+        )
+        .unwrap();
+        assert!(functions.is_some());
+        assert!(matches!(&messages[0].content, MessageContent::Array(v) if v.len() == 1));
+        check_capabilities(&model, CapabilityCheck::Off, &mut messages, &mut functions).unwrap();
+        assert!(functions.is_some());
+        assert!(check_capabilities(
+            &model,
+            CapabilityCheck::Error,
+            &mut messages,
+            &mut functions
+        )
+        .is_err());
+        check_capabilities(
+            &model,
+            CapabilityCheck::Strip,
+            &mut messages,
+            &mut functions,
+        )
+        .unwrap();
+        assert!(functions.is_none());
+        assert!(matches!(&messages[0].content, MessageContent::Text(v) if v.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_input_attachments() {
+        let config: GlobalConfig = Arc::new(RwLock::new(Config::default()));
+        let dir = temp_file("-input-attachments", "");
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        std::fs::write(&a, "alpha ".repeat(100)).unwrap();
+        std::fs::write(&b, "beta").unwrap();
+        let paths = vec![a.display().to_string(), b.display().to_string()];
+        let mut input = Input::from_files(&config, "compare", paths, None)
+            .await
+            .unwrap();
+        assert_eq!(input.attachments().len(), 2);
+        let (total, parts) = input.token_breakdown().unwrap();
+        let kinds: Vec<InputPart> = parts.iter().map(|(part, _)| *part).collect();
+        assert_eq!(
+            kinds,
+            [
+                InputPart::Attachment(0),
+                InputPart::Attachment(1),
+                InputPart::Prompt
+            ]
+        );
+        assert!(parts[0].1 > parts[1].1);
+
+        input.replace_attachment(0, Some("a hundred alphas"));
+        assert!(input
+            .text()
+            .contains("(summary) ============\na hundred alphas"));
+        input.replace_attachment(1, None);
+        assert!(!input.text().contains("beta"));
+        assert!(input.token_breakdown().unwrap().0 < total);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_run_input_command() {
+        assert_eq!(run_input_command("echo hi"), "hi");
+        assert_eq!(
+            run_input_command("echo oops; exit 3"),
+            "oops\n[exit status: 3]"
+        );
+        let output = run_input_command("yes");
+        assert!(output.ends_with("y\n[output truncated]"));
+        assert_eq!(output.lines().count(), MAX_CMD_OUTPUT_CHARS / 2 + 1);
+    }
+
+    #[test]
+    fn test_builtin_tools_only_for_user_turns() {
+        let config: GlobalConfig = Arc::new(RwLock::new(Config::default()));
+        let mut model = Model::new("openai-compatible", "my-model");
+        model.data_mut().supports_function_calling = true;
+        let tools = |role: Option<Role>, functions: Option<Vec<FunctionDeclaration>>| {
+            let mut role = role.unwrap_or_else(|| config.read().extract_role());
+            role.set_model(model.clone());
+            let mut input = Input::from_str(&config, "hello", Some(role));
+            if let Some(functions) = functions {
+                input.set_functions(functions);
+            }
+            let data = input.prepare_completion_data(&model, false).unwrap();
+            data.functions.map(|v| v.len()).unwrap_or_default()
+        };
+        assert!(tools(None, None) > 0);
+        assert_eq!(
+            tools(Some(Role::new(CREATE_TITLE_ROLE, "Title it")), None),
+            0
+        );
+        assert_eq!(tools(None, Some(vec![])), 0);
+    }
 
-    Ok(data_url)
+    #[test]
+    fn test_input_note() {
+        let config: GlobalConfig = Arc::new(RwLock::new(Config::default()));
+        let mut input = Input::from_str(&config, "hello", None);
+        input.set_note("(asked before)");
+        let messages = input.build_messages().unwrap();
+        assert!(
+            matches!(&messages.last().unwrap().content, MessageContent::Text(v) if v == "hello\n\n(asked before)")
+        );
+        assert!(matches!(input.message_content(), MessageContent::Text(v) if v == "hello"));
+    }
 }
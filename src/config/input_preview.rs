@@ -0,0 +1,183 @@
+use super::*;
+
+use super::input::InputPart;
+
+const SUMMARIZE_ATTACHMENT_PROMPT: &str = "Summarize the content you're given, keeping the facts, names, numbers and code that questions about it would need. Reply with the summary only.";
+
+/// What to do with an input over the preview threshold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PreviewAction {
+    Send,
+    Drop,
+    Summarize,
+    Cancel,
+}
+
+impl std::fmt::Display for PreviewAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            PreviewAction::Send => "Send as is",
+            PreviewAction::Drop => "Drop parts",
+            PreviewAction::Summarize => "Summarize parts",
+            PreviewAction::Cancel => "Cancel",
+        };
+        write!(f, "{text}")
+    }
+}
+
+impl Config {
+    /// Shows where the tokens of an input over `input_preview_threshold` (or the model's
+    /// `max_input_tokens`) come from, and lets the user drop or summarize parts before sending.
+    /// Returns `false` when the user cancels.
+    pub async fn preview_input(
+        config: &GlobalConfig,
+        input: &mut Input,
+        abort_signal: AbortSignal,
+    ) -> Result<bool> {
+        if !*IS_STDOUT_TERMINAL || config.read().dry_run || input.tool_calls().is_some() {
+            return Ok(true);
+        }
+        let threshold = config
+            .read()
+            .input_preview_threshold
+            .or_else(|| input.role().model().max_input_tokens());
+        let Some(threshold) = threshold else {
+            return Ok(true);
+        };
+        loop {
+            let (total, parts) = input.token_breakdown()?;
+            if total <= threshold {
+                return Ok(true);
+            }
+            println!(
+                "The input is about {total} tokens, over the threshold of {threshold}:\n{}",
+                render_input_parts(input, &parts)
+            );
+            let droppable: Vec<(InputPart, usize)> = parts
+                .iter()
+                .copied()
+                .filter(|(part, _)| matches!(part, InputPart::Attachment(_) | InputPart::Rag))
+                .collect();
+            let summarizable: Vec<(InputPart, usize)> = parts
+                .iter()
+                .copied()
+                .filter(|(part, _)| match part {
+                    InputPart::Attachment(_) => true,
+                    InputPart::History => config
+                        .read()
+                        .session
+                        .as_ref()
+                        .map(|v| v.has_user_messages())
+                        .unwrap_or_default(),
+                    _ => false,
+                })
+                .collect();
+            let mut actions = vec![PreviewAction::Send];
+            if !droppable.is_empty() {
+                actions.push(PreviewAction::Drop);
+            }
+            if !summarizable.is_empty() {
+                actions.push(PreviewAction::Summarize);
+            }
+            actions.push(PreviewAction::Cancel);
+            let action = Select::new("What to do?", actions).prompt()?;
+            let (parts, verb) = match action {
+                PreviewAction::Send => return Ok(true),
+                PreviewAction::Cancel => return Ok(false),
+                PreviewAction::Drop => (droppable, "drop"),
+                PreviewAction::Summarize => (summarizable, "summarize"),
+            };
+            let options: Vec<String> = parts
+                .iter()
+                .map(|(part, tokens)| format!("{} ({tokens} tokens)", part_label(input, *part)))
+                .collect();
+            let selected = MultiSelect::new(&format!("Parts to {verb}:"), options)
+                .raw_prompt()?
+                .into_iter()
+                .map(|v| parts[v.index].0);
+            // Later attachments first, so the indexes of the others stay valid
+            let mut selected: Vec<InputPart> = selected.collect();
+            selected.sort_by_key(|part| match part {
+                InputPart::Attachment(index) => std::cmp::Reverse(*index),
+                _ => std::cmp::Reverse(usize::MAX),
+            });
+            for part in selected {
+                match (action, part) {
+                    (PreviewAction::Drop, InputPart::Rag) => input.drop_rag(),
+                    (PreviewAction::Drop, InputPart::Attachment(index)) => {
+                        input.replace_attachment(index, None)
+                    }
+                    (PreviewAction::Summarize, InputPart::History) => {
+                        abortable_run_with_spinner(
+                            Self::compress_session(config),
+                            "Summarizing the history",
+                            abort_signal.clone(),
+                        )
+                        .await?
+                    }
+                    (PreviewAction::Summarize, InputPart::Attachment(index)) => {
+                        let label = part_label(input, part);
+                        let summary = abortable_run_with_spinner(
+                            summarize_attachment(config, input, index),
+                            &format!("Summarizing {label}"),
+                            abort_signal.clone(),
+                        )
+                        .await?;
+                        input.replace_attachment(index, Some(&summary));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+async fn summarize_attachment(
+    config: &GlobalConfig,
+    input: &Input,
+    index: usize,
+) -> Result<String> {
+    let Some((_, block)) = input.attachments().get(index) else {
+        bail!("No attachment to summarize");
+    };
+    let mut role = Role::new("", SUMMARIZE_ATTACHMENT_PROMPT);
+    role.set_model(input.role().model().clone());
+    Input::from_str(config, block.trim(), Some(role))
+        .fetch_chat_text()
+        .await
+}
+
+fn part_label(input: &Input, part: InputPart) -> String {
+    match part {
+        InputPart::System => "system prompt".into(),
+        InputPart::History => "history".into(),
+        InputPart::Attachment(index) => input
+            .attachments()
+            .get(index)
+            .map(|(label, _)| label.clone())
+            .unwrap_or_default(),
+        InputPart::Rag => match input.rag_name() {
+            Some(name) => format!("rag: {name}"),
+            None => "rag".into(),
+        },
+        InputPart::Prompt => "prompt".into(),
+    }
+}
+
+fn render_input_parts(input: &Input, parts: &[(InputPart, usize)]) -> String {
+    let labels: Vec<String> = parts
+        .iter()
+        .map(|(part, _)| part_label(input, *part))
+        .collect();
+    let width = labels
+        .iter()
+        .map(|v| v.chars().count())
+        .max()
+        .unwrap_or_default();
+    labels
+        .iter()
+        .zip(parts)
+        .map(|(label, (_, tokens))| format!("  {label:<width$}  {tokens:>8}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,296 @@
+use super::*;
+
+/// A top-level config key of an older version and where it lives now, `section.key` for a key
+/// that moved into a section.
+pub struct ConfigMigration {
+    pub from: &'static str,
+    pub to: &'static str,
+    /// Rewrites a scalar value on the way
+    pub convert: Option<fn(&str) -> String>,
+}
+
+pub const CONFIG_MIGRATIONS: &[ConfigMigration] = &[
+    ConfigMigration {
+        from: "light_theme",
+        to: "theme",
+        convert: Some(light_theme_to_theme),
+    },
+    ConfigMigration {
+        from: "buffer_editor",
+        to: "editor",
+        convert: None,
+    },
+    ConfigMigration {
+        from: "prelude",
+        to: "repl_prelude",
+        convert: None,
+    },
+];
+
+impl Config {
+    /// Shows how the keys of an older version would move in the config file and rewrites it
+    /// once confirmed, keeping the previous file as a backup.
+    pub(super) fn migrate_config_file(config_path: &Path) -> Result<()> {
+        let Ok(content) = read_to_string(config_path) else {
+            return Ok(());
+        };
+        let Some((migrated, keys)) = migrate_config(&content, CONFIG_MIGRATIONS) else {
+            return Ok(());
+        };
+        if !*IS_STDOUT_TERMINAL {
+            eprintln!(
+                "{}",
+                warning_text(&format!(
+                    "⚠️ The config at '{}' has keys of an older version that are ignored: {}. Run aichat in a terminal to migrate them.",
+                    config_path.display(),
+                    keys.join(", ")
+                ))
+            );
+            return Ok(());
+        }
+        let name = config_path
+            .file_name()
+            .map(|v| v.to_string_lossy().to_string())
+            .unwrap_or_else(|| CONFIG_FILE_NAME.into());
+        println!(
+            "The config at '{}' has keys of an older version:\n",
+            config_path.display()
+        );
+        print!(
+            "{}",
+            render_file_changes(&[FileChange {
+                path: name,
+                old: Some(content),
+                new: migrated.clone(),
+            }])
+        );
+        let ans = Confirm::new("Migrate the config?")
+            .with_default(true)
+            .prompt()?;
+        if !ans {
+            return Ok(());
+        }
+        let backup_path = PathBuf::from(format!("{}.bak", config_path.display()));
+        std::fs::copy(config_path, &backup_path).with_context(|| {
+            format!(
+                "Failed to back up the config to '{}'",
+                backup_path.display()
+            )
+        })?;
+        std::fs::write(config_path, migrated)
+            .with_context(|| format!("Failed to write to '{}'", config_path.display()))?;
+        println!(
+            "✓ Migrated the config, the previous one is at '{}'.",
+            backup_path.display()
+        );
+        Ok(())
+    }
+}
+
+/// Moves the keys of older versions in a config file to where they live now, keeping comments
+/// and layout. A key that is already set at its new place is dropped. Returns the new contents
+/// and the keys that moved, or `None` when there is nothing to migrate.
+pub fn migrate_config(
+    content: &str,
+    migrations: &[ConfigMigration],
+) -> Option<(String, Vec<&'static str>)> {
+    let mut lines: Vec<String> = content.lines().map(String::from).collect();
+    let mut keys = vec![];
+    for migration in migrations {
+        let blocks = top_level_blocks(&lines);
+        let Some(old) = blocks.iter().find(|v| v.key == migration.from) else {
+            continue;
+        };
+        let (section, key) = match migration.to.split_once('.') {
+            Some((section, key)) => (Some(section), key),
+            None => (None, migration.to),
+        };
+        let mut moved: Vec<String> = lines[old.start..old.end].to_vec();
+        let rest = &moved[0][migration.from.len() + 1..];
+        let rest = match migration.convert {
+            Some(convert) => convert_value(rest, convert),
+            None => rest.to_string(),
+        };
+        moved[0] = format!("{key}:{rest}");
+        let (old_start, old_end) = (old.start, old.end);
+        match section {
+            None => {
+                if blocks.iter().any(|v| v.key == key) {
+                    moved.clear();
+                }
+                lines.splice(old_start..old_end, moved);
+            }
+            Some(section) => match blocks.iter().find(|v| v.key == section) {
+                Some(target) => {
+                    let (value, _) = split_comment(&lines[target.start][section.len() + 1..]);
+                    if !value.trim().is_empty() {
+                        continue;
+                    }
+                    let children = &lines[target.start + 1..target.end];
+                    let indent = children
+                        .iter()
+                        .find(|v| !v.trim().is_empty() && !v.trim_start().starts_with('#'))
+                        .map(|v| v[..v.len() - v.trim_start().len()].to_string())
+                        .unwrap_or_else(|| "  ".into());
+                    let key_prefix = format!("{indent}{key}:");
+                    if children.iter().any(|v| v.starts_with(&key_prefix)) {
+                        moved.clear();
+                    }
+                    let moved = indent_lines(&moved, &indent);
+                    let at = if old_start < target.start {
+                        target.end - (old_end - old_start)
+                    } else {
+                        target.end
+                    };
+                    lines.drain(old_start..old_end);
+                    lines.splice(at..at, moved);
+                }
+                None => {
+                    lines.drain(old_start..old_end);
+                    lines.push(format!("{section}:"));
+                    lines.extend(indent_lines(&moved, "  "));
+                }
+            },
+        }
+        keys.push(migration.from);
+    }
+    if keys.is_empty() {
+        return None;
+    }
+    let mut output = lines.join("\n");
+    if content.ends_with('\n') {
+        output.push('\n');
+    }
+    Some((output, keys))
+}
+
+/// A top-level key and the range of lines holding it and its value.
+struct ConfigBlock<'a> {
+    key: &'a str,
+    start: usize,
+    end: usize,
+}
+
+fn top_level_blocks(lines: &[String]) -> Vec<ConfigBlock<'_>> {
+    let mut blocks: Vec<ConfigBlock> = vec![];
+    let mut in_block = false;
+    for (index, line) in lines.iter().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if line.starts_with(char::is_whitespace) || line.starts_with('-') {
+            if let Some(block) = blocks.last_mut().filter(|_| in_block) {
+                block.end = index + 1;
+            }
+            continue;
+        }
+        in_block = false;
+        if let Some(key) = top_level_key(line) {
+            blocks.push(ConfigBlock {
+                key,
+                start: index,
+                end: index + 1,
+            });
+            in_block = true;
+        }
+    }
+    blocks
+}
+
+fn top_level_key(line: &str) -> Option<&str> {
+    let (key, rest) = line.split_once(':')?;
+    let is_key = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        && (rest.is_empty() || rest.starts_with(char::is_whitespace));
+    is_key.then_some(key)
+}
+
+fn split_comment(text: &str) -> (&str, &str) {
+    match text.find(" #") {
+        Some(index) => text.split_at(index),
+        None => (text, ""),
+    }
+}
+
+fn convert_value(rest: &str, convert: fn(&str) -> String) -> String {
+    let (value, comment) = split_comment(rest);
+    let trimmed = value.trim();
+    if trimmed.is_empty() {
+        return rest.to_string();
+    }
+    format!("{}{comment}", value.replacen(trimmed, &convert(trimmed), 1))
+}
+
+fn indent_lines(lines: &[String], indent: &str) -> Vec<String> {
+    lines
+        .iter()
+        .map(|v| match v.is_empty() {
+            true => v.clone(),
+            false => format!("{indent}{v}"),
+        })
+        .collect()
+}
+
+fn light_theme_to_theme(value: &str) -> String {
+    match value {
+        "true" => "light".into(),
+        _ => "dark".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_migrate_config() {
+        let content = r#"model: openai:gpt-4o
+light_theme: true                # Activates a light color theme
+prelude: role:coder
+# ---- rag ----
+crawler_depth: 3
+rag_crawler:
+  concurrency: 5
+clients:
+- type: openai
+"#;
+        let migrations = [
+            ConfigMigration {
+                from: "light_theme",
+                to: "theme",
+                convert: Some(light_theme_to_theme),
+            },
+            ConfigMigration {
+                from: "prelude",
+                to: "model",
+                convert: None,
+            },
+            ConfigMigration {
+                from: "crawler_depth",
+                to: "rag_crawler.max_depth",
+                convert: None,
+            },
+        ];
+        let (output, keys) = migrate_config(content, &migrations).unwrap();
+        assert_eq!(
+            output,
+            r#"model: openai:gpt-4o
+theme: light                # Activates a light color theme
+# ---- rag ----
+rag_crawler:
+  concurrency: 5
+  max_depth: 3
+clients:
+- type: openai
+"#
+        );
+        assert_eq!(keys, ["light_theme", "prelude", "crawler_depth"]);
+        assert_eq!(migrate_config(&output, &migrations), None);
+
+        let (output, _) = migrate_config("crawler_depth: 3\n", &migrations).unwrap();
+        assert_eq!(output, "rag_crawler:\n  max_depth: 3\n");
+    }
+}
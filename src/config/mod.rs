@@ -1,22 +1,42 @@
 mod agent;
+mod dedup;
 mod input;
+mod input_preview;
+mod migration;
+mod report;
 mod role;
 mod session;
+mod sink;
+mod template;
 
+use self::agent::AgentVariable;
 pub use self::agent::{complete_agent_variables, list_agents, Agent, AgentVariables};
-pub use self::input::Input;
+pub use self::dedup::{DedupAction, RepeatedTurn};
+pub use self::input::{image_data_url, CapabilityCheck, Input, IMAGE_EXTS};
+pub use self::report::RunReport;
 pub use self::role::{
-    Role, RoleLike, CODE_ROLE, CREATE_TITLE_ROLE, DISTROBOX_ROLE, EXPLAIN_SHELL_ROLE, SHELL_ROLE,
+    Role, RoleLike, CODE_ROLE, COMMIT_MESSAGE_ROLE, CREATE_TITLE_ROLE, DISTROBOX_ROLE, EDIT_ROLE,
+    EXPLAIN_SHELL_ROLE, IMPROVE_PROMPT_ROLE, SHELL_ROLE,
 };
-use self::session::Session;
+pub use self::session::Session;
+pub use self::sink::OutputSink;
+use self::template::ConversationTemplate;
 
 use crate::client::{
-    create_client_config, list_client_types, list_models, model_data_from_names, ClientConfig,
-    MessageContentToolCalls, Model, ModelType, OpenAICompatibleClient, ProviderModels,
+    client_proxy, create_client_config, is_models_offline, list_client_types, list_models,
+    model_data_from_names, parse_models, read_models_source, refresh_models_dev,
+    set_models_dev_source, validate_client_configs, Cassette, ChatDocument, ClientConfig,
+    MessageContentToolCalls, Middleware, Model, ModelType, OpenAICompatibleClient, ProviderModels,
     OPENAI_COMPATIBLE_PROVIDERS,
 };
-use crate::function::{FunctionDeclaration, Functions, ToolResult};
-use crate::rag::Rag;
+use crate::function::{
+    FunctionDeclaration, Functions, ToolResult, BUILTIN_TOOLS, DELEGATE_FUNCTION_NAME,
+    SCRATCHPAD_FUNCTION_NAME,
+};
+use crate::rag::{
+    EmbeddingCache, QueryExpansion, Rag, RagFilter, RagOverrides, RagPruneOptions, RagRefresh,
+    RagWatcher, VectorStoreConfig,
+};
 use crate::render::{MarkdownRender, RenderOptions};
 use crate::repl::{run_repl_command, split_args_text};
 use crate::utils::*;
@@ -26,7 +46,7 @@ use indexmap::IndexMap;
 use inquire::{list_option::ListOption, validator::Validation, Confirm, MultiSelect, Select, Text};
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{json, Value};
 use simplelog::LevelFilter;
 use std::collections::{HashMap, HashSet};
 use std::{
@@ -37,7 +57,11 @@ use std::{
     io::Write,
     path::{Path, PathBuf},
     process,
-    sync::{Arc, OnceLock},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
 };
 use syntect::highlighting::ThemeSet;
 use terminal_colorsaurus::{color_scheme, ColorScheme, QueryOptions};
@@ -53,10 +77,15 @@ const LIGHT_THEME: &[u8] = include_bytes!("../../assets/monokai-extended-light.t
 const CONFIG_FILE_NAME: &str = "config.yaml";
 const ROLES_DIR_NAME: &str = "roles";
 const MACROS_DIR_NAME: &str = "macros";
+const CONVERSATION_TEMPLATES_DIR_NAME: &str = "conversation_templates";
 const ENV_FILE_NAME: &str = ".env";
 const MESSAGES_FILE_NAME: &str = "messages.md";
 const SESSIONS_DIR_NAME: &str = "sessions";
 const RAGS_DIR_NAME: &str = "rags";
+const EMBEDDINGS_CACHE_DIR_NAME: &str = "embeddings-cache";
+const BLOBS_DIR_NAME: &str = "blobs";
+const GIT_REPOS_DIR_NAME: &str = "git-repos";
+const IMAGES_DIR_NAME: &str = "images";
 const FUNCTIONS_DIR_NAME: &str = "functions";
 const FUNCTIONS_FILE_NAME: &str = "functions.json";
 const FUNCTIONS_BIN_DIR_NAME: &str = "bin";
@@ -92,9 +121,38 @@ __CONTEXT__
 __INPUT__
 </user_query>"#;
 
-const LEFT_PROMPT: &str = "{color.green}{?session {?agent {agent}>}{session}{?role /}}{!session {?agent {agent}>}}{role}{?rag @{rag}}{color.cyan}{?session )}{!session >}{color.reset} ";
+const LEFT_PROMPT: &str = "{color.green}{?session {?agent {agent}>}{session}{?read_only 🔒}{?role /}}{!session {?agent {agent}>}}{role}{?rag @{rag}}{color.cyan}{?session )}{!session >}{color.reset} ";
 const RIGHT_PROMPT: &str = "{color.purple}{?session {?consume_tokens {consume_tokens}({consume_percent}%)}{!consume_tokens {consume_tokens}}}{color.reset}";
 
+/// Named REPL prompt segments: (name, variable that must be set, template, default color).
+/// Any other segment name renders the prompt variable of the same name.
+const PROMPT_SEGMENTS: [(&str, &str, &str, &str); 9] = [
+    ("model", "model", "{model}", "yellow"),
+    ("client", "client_name", "{client_name}", "yellow"),
+    ("agent", "agent", "{agent}", "green"),
+    ("role", "role", "{role}", "green"),
+    (
+        "session",
+        "session",
+        "{session}{?dirty *}{?read_only 🔒}",
+        "green",
+    ),
+    ("rag", "rag", "@{rag}", "cyan"),
+    (
+        "consume_tokens",
+        "session",
+        "{consume_tokens}{?consume_percent ({consume_percent}%)}",
+        "purple",
+    ),
+    (
+        "pending_tokens",
+        "pending_tokens",
+        "+{pending_tokens}",
+        "dark_gray",
+    ),
+    ("git_branch", "git_branch", "({git_branch})", "magenta"),
+];
+
 static EDITOR: OnceLock<Option<String>> = OnceLock::new();
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -107,59 +165,132 @@ pub struct Config {
     pub top_p: Option<f64>,
     pub frequency_penalty: Option<f64>,
     pub presence_penalty: Option<f64>,
+    pub logprobs: bool,
+    pub top_logprobs: Option<usize>,
+    pub seed: Option<u64>,
+    pub google_search: bool,
 
     pub dry_run: bool,
     pub stream: bool,
+    pub stream_stats: bool,
     pub save: bool,
     pub hide_thinking: bool,
     pub keybindings: String,
+    pub tui: bool,
     pub editor: Option<String>,
     pub wrap: Option<String>,
     pub wrap_code: bool,
+    pub improve_prompt_model: Option<String>,
+    pub capability_check: CapabilityCheck,
+    pub input_preview_threshold: Option<usize>,
+    pub image_output_dir: Option<String>,
+    pub image_preview: bool,
+    pub require_max_tokens: IndexMap<String, bool>,
+    pub output_sinks: IndexMap<String, OutputSink>,
 
     pub function_calling: bool,
     pub mapping_tools: IndexMap<String, String>,
     pub use_tools: Option<String>,
+    pub builtin_tools: Vec<String>,
+    pub stop: Option<Vec<String>>,
 
     pub repl_prelude: Option<String>,
     pub cmd_prelude: Option<String>,
     pub agent_prelude: Option<String>,
 
     pub save_session: Option<bool>,
+    pub autoname_sessions: bool,
     pub compress_threshold: usize,
+    pub compress_model: Option<String>,
     pub summarize_prompt: Option<String>,
     pub summary_prompt: Option<String>,
+    pub dedup_model: Option<String>,
+    pub dedup_threshold: f32,
+    pub dedup_action: DedupAction,
 
     pub rag_embedding_model: Option<String>,
     pub rag_reranker_model: Option<String>,
     pub rag_top_k: usize,
+    pub rag_query_expansion: Option<QueryExpansion>,
+    pub rag_query_expansion_model: Option<String>,
+    pub rag_compression_model: Option<String>,
+    pub rag_recency_half_life: Option<u32>,
     pub rag_chunk_size: Option<usize>,
     pub rag_chunk_overlap: Option<usize>,
     pub rag_template: Option<String>,
+    pub rag_citations: bool,
+    pub rag_embeddings_cache: bool,
+    pub rag_embedding_concurrency: usize,
+    pub rag_ocr_model: Option<String>,
+    pub rag_summary_model: Option<String>,
+    pub rag_include: Vec<String>,
+    pub rag_exclude: Vec<String>,
+    pub rag_gitignore: bool,
+    pub rag_vector_store: Option<VectorStoreConfig>,
+    pub rag_crawler: CrawlerConfig,
 
     #[serde(default)]
     pub document_loaders: HashMap<String, String>,
+    pub repo_map_max_tokens: usize,
 
     pub highlight: bool,
     pub theme: Option<String>,
     pub left_prompt: Option<String>,
     pub right_prompt: Option<String>,
+    pub left_prompt_segments: Option<Vec<String>>,
+    pub right_prompt_segments: Option<Vec<String>>,
+    #[serde(default)]
+    pub prompt_colors: HashMap<String, String>,
 
     pub serve_addr: Option<String>,
+    #[serde(default)]
+    pub serve_upstreams: HashMap<String, ServeUpstream>,
+    #[serde(default)]
+    pub serve_rules: Vec<ServeRule>,
     pub user_agent: Option<String>,
+    #[serde(default)]
+    pub middleware: Vec<Middleware>,
+    pub audit_log: Option<String>,
+    pub audit_log_max_body: Option<usize>,
+    pub hook_timeout: u64,
     pub save_shell_history: bool,
+    pub shell_sandbox: ShellSandboxConfig,
     pub sync_models_url: Option<String>,
     pub models_dev_url: Option<String>,
     pub models_dev_enabled: bool,
 
     pub clients: Vec<ClientConfig>,
 
+    #[serde(skip)]
+    pub request_timeout: Option<u64>,
+    #[serde(skip)]
+    pub max_time: Option<Duration>,
+    /// When `max_time` runs out, shared by every request made for the current prompt
+    #[serde(skip)]
+    pub max_time_deadline: Option<Instant>,
+    #[serde(skip)]
+    pub max_time_wrap_up: bool,
     #[serde(skip)]
     pub macro_flag: bool,
     #[serde(skip)]
     pub info_flag: bool,
     #[serde(skip)]
     pub agent_variables: Option<AgentVariables>,
+    #[serde(skip)]
+    pub rag_filter: Option<RagFilter>,
+    #[serde(skip)]
+    pub run_report: Option<RunReport>,
+    #[serde(skip)]
+    pub cassette: Option<Cassette>,
+    /// `--tag` labels recorded with each call in the audit log and run report
+    #[serde(skip)]
+    pub tags: IndexMap<String, String>,
+    /// `--sink` names, used on top of the role's `sinks`
+    #[serde(skip)]
+    pub cli_sinks: Vec<String>,
+    /// The `scratchpad` notes of a run without a session, never written to disk
+    #[serde(skip)]
+    pub scratchpad: String,
 
     #[serde(skip)]
     pub model: Model,
@@ -169,6 +300,9 @@ pub struct Config {
     pub working_mode: WorkingMode,
     #[serde(skip)]
     pub last_message: Option<LastMessage>,
+    /// Tokens of the input being typed, shared with the REPL highlighter so it needs no lock
+    #[serde(skip)]
+    pub pending_input_tokens: Arc<AtomicUsize>,
 
     #[serde(skip)]
     pub role: Option<Role>,
@@ -188,60 +322,116 @@ impl Default for Config {
             top_p: None,
             frequency_penalty: None,
             presence_penalty: None,
+            logprobs: false,
+            top_logprobs: None,
+            seed: None,
+            google_search: false,
 
             dry_run: false,
             stream: true,
+            stream_stats: false,
             save: false,
             hide_thinking: false,
             keybindings: "emacs".into(),
+            tui: false,
             editor: None,
             wrap: None,
             wrap_code: false,
+            improve_prompt_model: None,
+            capability_check: CapabilityCheck::default(),
+            input_preview_threshold: None,
+            image_output_dir: None,
+            image_preview: true,
+            require_max_tokens: Default::default(),
+            output_sinks: Default::default(),
 
             function_calling: true,
             mapping_tools: Default::default(),
             use_tools: None,
+            builtin_tools: BUILTIN_TOOLS.iter().map(|v| v.to_string()).collect(),
+            stop: None,
 
             repl_prelude: None,
             cmd_prelude: None,
             agent_prelude: None,
 
             save_session: None,
+            autoname_sessions: false,
             compress_threshold: 4000,
+            compress_model: None,
             summarize_prompt: None,
             summary_prompt: None,
+            dedup_model: None,
+            dedup_threshold: 0.95,
+            dedup_action: DedupAction::default(),
 
             rag_embedding_model: None,
             rag_reranker_model: None,
             rag_top_k: 5,
+            rag_query_expansion: None,
+            rag_query_expansion_model: None,
+            rag_compression_model: None,
+            rag_recency_half_life: None,
             rag_chunk_size: None,
             rag_chunk_overlap: None,
             rag_template: None,
+            rag_citations: false,
+            rag_embeddings_cache: true,
+            rag_embedding_concurrency: 4,
+            rag_ocr_model: None,
+            rag_summary_model: None,
+            rag_include: vec![],
+            rag_exclude: vec![],
+            rag_gitignore: true,
+            rag_vector_store: None,
+            rag_crawler: Default::default(),
 
             document_loaders: Default::default(),
+            repo_map_max_tokens: 4096,
 
             highlight: true,
             theme: None,
             left_prompt: None,
             right_prompt: None,
+            left_prompt_segments: None,
+            right_prompt_segments: None,
+            prompt_colors: Default::default(),
 
             serve_addr: None,
+            serve_upstreams: Default::default(),
+            serve_rules: vec![],
             user_agent: None,
+            middleware: vec![],
+            audit_log: None,
+            audit_log_max_body: None,
+            hook_timeout: 20,
             save_shell_history: true,
+            shell_sandbox: Default::default(),
             sync_models_url: None,
             models_dev_url: None,
             models_dev_enabled: true,
 
             clients: vec![],
 
+            request_timeout: None,
+            max_time: None,
+            max_time_deadline: None,
+            max_time_wrap_up: false,
             macro_flag: false,
             info_flag: false,
             agent_variables: None,
+            rag_filter: None,
+            run_report: None,
+            cassette: None,
+            tags: Default::default(),
+            cli_sinks: vec![],
+            scratchpad: String::new(),
 
             model: Default::default(),
             functions: Default::default(),
             working_mode: WorkingMode::Cmd,
             last_message: None,
+            pending_input_tokens: Default::default(),
 
             role: None,
             session: None,
@@ -270,6 +460,7 @@ impl Config {
                 }
             }
         } else {
+            Self::migrate_config_file(&config_path)?;
             Self::load_from_file(&config_path)?
         };
 
@@ -278,6 +469,7 @@ impl Config {
 
         let setup = |config: &mut Self| -> Result<()> {
             config.load_envs();
+            set_models_dev_source(config.models_dev_enabled, config.models_dev_url.clone());
 
             if let Some(wrap) = config.wrap.clone() {
                 config.set_wrap(&wrap)?;
@@ -285,8 +477,8 @@ impl Config {
 
             config.load_functions()?;
 
+            validate_client_configs(&config.clients)?;
             config.setup_model()?;
-            config.setup_document_loaders();
             config.setup_user_agent();
             Ok(())
         };
@@ -341,6 +533,17 @@ impl Config {
         Self::macros_dir().join(format!("{name}.yaml"))
     }
 
+    pub fn templates_dir() -> PathBuf {
+        match env::var(get_env_name("templates_dir")) {
+            Ok(value) => PathBuf::from(value),
+            Err(_) => Self::local_path(CONVERSATION_TEMPLATES_DIR_NAME),
+        }
+    }
+
+    pub fn conversation_template_file(name: &str) -> PathBuf {
+        Self::templates_dir().join(format!("{name}.yaml"))
+    }
+
     pub fn env_file() -> PathBuf {
         match env::var(get_env_name("env_file")) {
             Ok(value) => PathBuf::from(value),
@@ -375,6 +578,36 @@ impl Config {
         }
     }
 
+    pub fn embeddings_cache_dir() -> PathBuf {
+        match env::var(get_env_name("embeddings_cache_dir")) {
+            Ok(value) => PathBuf::from(value),
+            Err(_) => Self::local_path(EMBEDDINGS_CACHE_DIR_NAME),
+        }
+    }
+
+    /// Where `git:` RAG sources are cloned, and kept to be updated on the next rebuild.
+    pub fn git_repos_dir() -> PathBuf {
+        match env::var(get_env_name("git_repos_dir")) {
+            Ok(value) => PathBuf::from(value),
+            Err(_) => Self::local_path(GIT_REPOS_DIR_NAME),
+        }
+    }
+
+    pub fn blobs_dir() -> PathBuf {
+        match env::var(get_env_name("blobs_dir")) {
+            Ok(value) => PathBuf::from(value),
+            Err(_) => Self::local_path(BLOBS_DIR_NAME),
+        }
+    }
+
+    /// Where images returned by models are saved.
+    pub fn images_dir(&self) -> PathBuf {
+        match &self.image_output_dir {
+            Some(dir) => PathBuf::from(resolve_home_dir(dir)),
+            None => Self::local_path(IMAGES_DIR_NAME),
+        }
+    }
+
     pub fn functions_dir() -> PathBuf {
         match env::var(get_env_name("functions_dir")) {
             Ok(value) => PathBuf::from(value),
@@ -397,6 +630,17 @@ impl Config {
         }
     }
 
+    /// The scratchpad of the current session, `None` without one as the notes stay in memory.
+    pub fn scratchpad_file(&self) -> Option<PathBuf> {
+        let name = self.session.as_ref()?.name();
+        Some(self.scratchpad_path(name))
+    }
+
+    fn scratchpad_path(&self, session_name: &str) -> PathBuf {
+        self.session_file(session_name)
+            .with_extension("scratchpad.md")
+    }
+
     pub fn rag_file(&self, name: &str) -> PathBuf {
         match &self.agent {
             Some(agent) => Self::agent_rag_file(agent.name(), name),
@@ -551,6 +795,7 @@ impl Config {
                 self.presence_penalty,
                 self.use_tools.clone(),
             );
+            role.set_stop(self.stop.clone());
             role
         }
     }
@@ -581,7 +826,25 @@ impl Config {
     }
 
     pub fn sysinfo(&self) -> Result<String> {
-        let display_path = |path: &Path| path.display().to_string();
+        let output = self
+            .sysinfo_items()
+            .into_iter()
+            .map(|(name, value)| {
+                let value = match value {
+                    Value::Null => "null".to_string(),
+                    Value::String(v) => v,
+                    v if name == "max_output_tokens" => format!("{v} (current model)"),
+                    v => v.to_string(),
+                };
+                format!("{name:<24}{value}\n")
+            })
+            .collect::<Vec<String>>()
+            .join("");
+        Ok(output)
+    }
+
+    fn sysinfo_items(&self) -> Vec<(&'static str, Value)> {
+        let display_path = |path: &Path| json!(path.display().to_string());
         let wrap = self
             .wrap
             .clone()
@@ -590,53 +853,129 @@ impl Config {
             Some(rag) => rag.get_config(),
             None => (self.rag_reranker_model.clone(), self.rag_top_k),
         };
+        let (rag_query_expansion, rag_query_expansion_model) = match &self.rag {
+            Some(rag) => rag.query_expansion_config(),
+            None => (
+                self.rag_query_expansion,
+                self.rag_query_expansion_model.clone(),
+            ),
+        };
+        let rag_recency_half_life = match &self.rag {
+            Some(rag) => rag.recency_half_life(),
+            None => self.rag_recency_half_life,
+        };
         let role = self.extract_role();
         let mut items = vec![
-            ("model", role.model().id()),
-            ("temperature", format_option_value(&role.temperature())),
-            ("top_p", format_option_value(&role.top_p())),
-            ("use_tools", format_option_value(&role.use_tools())),
+            ("model", json!(role.model().id())),
+            ("temperature", json!(role.temperature())),
+            ("top_p", json!(role.top_p())),
+            ("use_tools", json!(role.use_tools())),
+            ("stop", json!(role.stop())),
+            ("logprobs", json!(self.logprobs)),
+            ("top_logprobs", json!(self.top_logprobs)),
+            ("seed", json!(self.seed)),
+            ("google_search", json!(self.google_search)),
+            ("max_output_tokens", json!(role.model().max_tokens_param())),
+            ("save_session", json!(self.save_session)),
+            ("autoname_sessions", json!(self.autoname_sessions)),
+            ("compress_threshold", json!(self.compress_threshold)),
+            ("compress_model", json!(self.compress_model)),
+            ("rag_reranker_model", json!(rag_reranker_model)),
+            ("rag_top_k", json!(rag_top_k)),
+            ("rag_query_expansion", json!(rag_query_expansion)),
             (
-                "max_output_tokens",
-                role.model()
-                    .max_tokens_param()
-                    .map(|v| format!("{v} (current model)"))
-                    .unwrap_or_else(|| "null".into()),
+                "rag_query_expansion_model",
+                json!(rag_query_expansion_model),
             ),
-            ("save_session", format_option_value(&self.save_session)),
-            ("compress_threshold", self.compress_threshold.to_string()),
+            ("rag_compression_model", json!(self.rag_compression_model)),
+            ("rag_recency_half_life", json!(rag_recency_half_life)),
+            ("rag_citations", json!(self.rag_citations)),
             (
-                "rag_reranker_model",
-                format_option_value(&rag_reranker_model),
+                "rag_filter",
+                json!(self.rag_filter.as_ref().map(|v| v.to_string())),
+            ),
+            ("dry_run", json!(self.dry_run)),
+            ("function_calling", json!(self.function_calling)),
+            ("stream", json!(self.stream)),
+            ("stream_stats", json!(self.stream_stats)),
+            ("save", json!(self.save)),
+            ("keybindings", json!(self.keybindings)),
+            ("tui", json!(self.tui)),
+            ("wrap", json!(wrap)),
+            ("wrap_code", json!(self.wrap_code)),
+            ("highlight", json!(self.highlight)),
+            ("theme", json!(self.theme)),
+            (
+                "proxy",
+                json!(effective_proxy(
+                    client_proxy(self, role.model().client_name()).as_deref()
+                )),
             ),
-            ("rag_top_k", rag_top_k.to_string()),
-            ("dry_run", self.dry_run.to_string()),
-            ("function_calling", self.function_calling.to_string()),
-            ("stream", self.stream.to_string()),
-            ("save", self.save.to_string()),
-            ("keybindings", self.keybindings.clone()),
-            ("wrap", wrap),
-            ("wrap_code", self.wrap_code.to_string()),
-            ("highlight", self.highlight.to_string()),
-            ("theme", format_option_value(&self.theme)),
             ("config_file", display_path(&Self::config_file())),
             ("env_file", display_path(&Self::env_file())),
             ("roles_dir", display_path(&Self::roles_dir())),
             ("sessions_dir", display_path(&self.sessions_dir())),
             ("rags_dir", display_path(&Self::rags_dir())),
+            ("blobs_dir", display_path(&Self::blobs_dir())),
             ("macros_dir", display_path(&Self::macros_dir())),
+            ("templates_dir", display_path(&Self::templates_dir())),
             ("functions_dir", display_path(&Self::functions_dir())),
             ("messages_file", display_path(&self.messages_file())),
         ];
         if let Ok((_, Some(log_path))) = Self::log_config(self.working_mode.is_serve()) {
             items.push(("log_path", display_path(&log_path)));
         }
-        let output = items
-            .iter()
-            .map(|(name, value)| format!("{name:<24}{value}\n"))
-            .collect::<Vec<String>>()
-            .join("");
-        Ok(output)
+        items
+    }
+
+    /// Structured info about the model, role, session, RAG, agent or config, for `--json` output.
+    pub fn info_value(&self, target: &str) -> Result<Value> {
+        let value = match target {
+            "model" => self.current_model().export_value(),
+            "role" => self.role_info_value()?,
+            "session" => match &self.session {
+                Some(session) => session.export_value(),
+                None => bail!("No session"),
+            },
+            "rag" => match &self.rag {
+                Some(rag) => rag.export_value(),
+                None => bail!("No RAG"),
+            },
+            "agent" => match &self.agent {
+                Some(agent) => {
+                    let mut value = agent.export_value()?;
+                    if let Some(session) = &self.session {
+                        value["session"] = session.export_value();
+                    }
+                    value
+                }
+                None => bail!("No agent"),
+            },
+            "embeddings-cache" => EmbeddingCache::stats_value()?,
+            "config" => Value::Object(
+                self.sysinfo_items()
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v))
+                    .collect(),
+            ),
+            _ => bail!("Unknown info target '{target}'"),
+        };
+        Ok(value)
+    }
+
+    /// The `info_value` target matching what `--info` shows for the current state.
+    pub fn default_info_target(&self) -> &'static str {
+        if self.agent.is_some() {
+            "agent"
+        } else if self.session.is_some() {
+            "session"
+        } else if self.role.is_some() {
+            "role"
+        } else if self.rag.is_some() {
+            "rag"
+        } else {
+            "config"
+        }
     }
 
     pub fn update(config: &GlobalConfig, data: &str) -> Result<()> {
@@ -667,26 +1006,91 @@ impl Config {
                 let value = parse_value(value)?;
                 config.write().set_use_tools(value);
             }
+            "stop" => {
+                let value = parse_value::<String>(value)?;
+                config.write().set_stop(value.map(|v| split_stop_value(&v)));
+            }
             "max_output_tokens" => {
                 let value = parse_value(value)?;
                 config.write().set_max_output_tokens(value);
             }
+            "logprobs" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                config.write().logprobs = value;
+            }
+            "top_logprobs" => {
+                let value = parse_value(value)?;
+                config.write().top_logprobs = value;
+            }
+            "google_search" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                config.write().google_search = value;
+            }
+            "seed" => {
+                let value = parse_value(value)?;
+                config.write().seed = value;
+            }
             "save_session" => {
                 let value = parse_value(value)?;
                 config.write().set_save_session(value);
             }
+            "autoname_sessions" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                config.write().set_autoname_sessions(value);
+            }
             "compress_threshold" => {
                 let value = parse_value(value)?;
                 config.write().set_compress_threshold(value);
             }
+            "compress_model" => {
+                let value: Option<String> = parse_value(value)?;
+                if let Some(model_id) = &value {
+                    Model::retrieve_model(&config.read(), model_id, ModelType::Chat)?;
+                }
+                config.write().compress_model = value;
+            }
             "rag_reranker_model" => {
                 let value = parse_value(value)?;
                 Self::set_rag_reranker_model(config, value)?;
             }
+            "improve_prompt_model" => {
+                let value: Option<String> = parse_value(value)?;
+                if let Some(model_id) = &value {
+                    Model::retrieve_model(&config.read(), model_id, ModelType::Chat)?;
+                }
+                config.write().improve_prompt_model = value;
+            }
             "rag_top_k" => {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 Self::set_rag_top_k(config, value)?;
             }
+            "rag_query_expansion" => {
+                let value = QueryExpansion::parse(value)?;
+                Self::set_rag_query_expansion(config, value)?;
+            }
+            "rag_query_expansion_model" => {
+                let value = parse_value(value)?;
+                Self::set_rag_query_expansion_model(config, value)?;
+            }
+            "rag_compression_model" => {
+                let value: Option<String> = parse_value(value)?;
+                if let Some(model_id) = &value {
+                    Model::retrieve_model(&config.read(), model_id, ModelType::Chat)?;
+                }
+                config.write().rag_compression_model = value;
+            }
+            "rag_recency_half_life" => {
+                let value = parse_value(value)?;
+                Self::set_rag_recency_half_life(config, value)?;
+            }
+            "rag_citations" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                config.write().rag_citations = value;
+            }
+            "rag_filter" => {
+                let value: Option<String> = parse_value(value)?;
+                config.write().rag_filter = value.as_deref().map(RagFilter::parse).transpose()?;
+            }
             "dry_run" => {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 config.write().dry_run = value;
@@ -702,6 +1106,10 @@ impl Config {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 config.write().stream = value;
             }
+            "stream_stats" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                config.write().stream_stats = value;
+            }
             "save" => {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 config.write().save = value;
@@ -812,6 +1220,13 @@ impl Config {
         }
     }
 
+    pub fn set_stop(&mut self, value: Option<Vec<String>>) {
+        match self.role_like_mut() {
+            Some(role_like) => role_like.set_stop(value),
+            None => self.stop = value,
+        }
+    }
+
     pub fn set_use_tools(&mut self, value: Option<String>) {
         match self.role_like_mut() {
             Some(role_like) => role_like.set_use_tools(value),
@@ -827,6 +1242,13 @@ impl Config {
         }
     }
 
+    pub fn set_autoname_sessions(&mut self, value: bool) {
+        if let Some(session) = self.session.as_mut() {
+            session.set_autoname_sessions(value);
+        }
+        self.autoname_sessions = value;
+    }
+
     pub fn set_compress_threshold(&mut self, value: Option<usize>) {
         if let Some(session) = self.session.as_mut() {
             session.set_compress_threshold(value);
@@ -862,6 +1284,54 @@ impl Config {
         Ok(())
     }
 
+    pub fn set_rag_query_expansion(
+        config: &GlobalConfig,
+        value: Option<QueryExpansion>,
+    ) -> Result<()> {
+        let has_rag = config.read().rag.is_some();
+        match has_rag {
+            true => update_rag(config, |rag| {
+                rag.set_query_expansion(value)?;
+                Ok(())
+            })?,
+            false => config.write().rag_query_expansion = value,
+        }
+        Ok(())
+    }
+
+    pub fn set_rag_query_expansion_model(
+        config: &GlobalConfig,
+        value: Option<String>,
+    ) -> Result<()> {
+        if let Some(id) = &value {
+            Model::retrieve_model(&config.read(), id, ModelType::Chat)?;
+        }
+        let has_rag = config.read().rag.is_some();
+        match has_rag {
+            true => update_rag(config, |rag| {
+                rag.set_query_expansion_model(value)?;
+                Ok(())
+            })?,
+            false => config.write().rag_query_expansion_model = value,
+        }
+        Ok(())
+    }
+
+    pub fn set_rag_recency_half_life(config: &GlobalConfig, value: Option<u32>) -> Result<()> {
+        if value == Some(0) {
+            bail!("The half-life is in days and must be at least 1");
+        }
+        let has_rag = config.read().rag.is_some();
+        match has_rag {
+            true => update_rag(config, |rag| {
+                rag.set_recency_half_life(value)?;
+                Ok(())
+            })?,
+            false => config.write().rag_recency_half_life = value,
+        }
+        Ok(())
+    }
+
     pub fn set_wrap(&mut self, value: &str) -> Result<()> {
         if value == "no" {
             self.wrap = None;
@@ -877,16 +1347,30 @@ impl Config {
     }
 
     pub fn set_max_output_tokens(&mut self, value: Option<isize>) {
-        match self.role_like_mut() {
+        let model = match self.role_like_mut() {
             Some(role_like) => {
                 let mut model = role_like.model().clone();
-                model.set_max_tokens(value, true);
-                role_like.set_model(model);
+                model.set_max_tokens(value);
+                role_like.set_model(model.clone());
+                model
             }
             None => {
-                self.model.set_max_tokens(value, true);
+                self.model.set_max_tokens(value);
+                self.model.clone()
             }
         };
+        if let Some(value) = value {
+            let clamped = model.clamp_max_tokens(value);
+            if clamped < value {
+                eprintln!(
+                    "{}",
+                    warning_text(&format!(
+                        "⚠️ Model '{}' outputs at most {clamped} tokens, sending max_tokens {clamped}",
+                        model.id()
+                    ))
+                );
+            }
+        }
     }
 
     pub fn set_model(&mut self, model_id: &str) -> Result<()> {
@@ -911,10 +1395,18 @@ impl Config {
         self.use_role_obj(role)
     }
 
-    pub fn use_role_obj(&mut self, role: Role) -> Result<()> {
+    pub fn use_role_obj(&mut self, mut role: Role) -> Result<()> {
         if self.agent.is_some() {
             bail!("Cannot perform this operation because you are using a agent")
         }
+        if !role.defined_variables().is_empty() {
+            let variables = Agent::init_agent_variables(
+                role.defined_variables(),
+                role.variable_values(),
+                self.info_flag,
+            )?;
+            role.set_variable_values(variables);
+        }
         if let Some(session) = self.session.as_mut() {
             session.guard_empty()?;
             session.set_role(role);
@@ -924,6 +1416,106 @@ impl Config {
         Ok(())
     }
 
+    pub fn variables_info(&self) -> Result<String> {
+        let (defined_variables, values) = self.current_variables()?;
+        let mut output = vec![];
+        for variable in &defined_variables {
+            let value = values.get(&variable.name).cloned().unwrap_or_default();
+            output.push(format!(
+                "{}={}  # {}",
+                variable.name, value, variable.description
+            ));
+        }
+        for (key, value) in &values {
+            if !defined_variables.iter().any(|v| &v.name == key) {
+                output.push(format!("{key}={value}"));
+            }
+        }
+        if output.is_empty() {
+            bail!("No variables")
+        }
+        Ok(output.join("\n"))
+    }
+
+    pub fn update_variables(&mut self, new_variables: AgentVariables) -> Result<()> {
+        let (defined_variables, mut values) = self.current_variables()?;
+        for key in new_variables.keys() {
+            if !defined_variables.iter().any(|v| &v.name == key) {
+                bail!("Unknown variable '{key}'")
+            }
+        }
+        values.extend(new_variables);
+        if let Some(agent) = self.agent.as_mut() {
+            match self.session.as_mut() {
+                Some(session) => {
+                    agent.set_session_variables(values);
+                    agent.update_session_dynamic_instructions(None)?;
+                    session.update_agent_variables(agent);
+                }
+                None => {
+                    agent.set_shared_variables(values);
+                    agent.update_shared_dynamic_instructions(true)?;
+                }
+            }
+        } else if let Some(session) = self.session.as_mut() {
+            session.set_role_variables(values);
+        } else if let Some(role) = self.role.as_mut() {
+            role.set_variable_values(values);
+        }
+        Ok(())
+    }
+
+    fn current_variables(&self) -> Result<(Vec<AgentVariable>, AgentVariables)> {
+        if let Some(agent) = &self.agent {
+            Ok((
+                agent.defined_variables().to_vec(),
+                agent.variables().clone(),
+            ))
+        } else if let Some(session) = &self.session {
+            // Sessions keep only the role name and prompt, so the definitions come from the role file
+            let defined_variables = match session.role_name() {
+                Some(name) => self
+                    .retrieve_role(name)
+                    .map(|v| v.defined_variables().to_vec())
+                    .unwrap_or_default(),
+                None => vec![],
+            };
+            Ok((
+                defined_variables,
+                session.to_role().variable_values().clone(),
+            ))
+        } else if let Some(role) = &self.role {
+            Ok((
+                role.defined_variables().to_vec(),
+                role.variable_values().clone(),
+            ))
+        } else {
+            bail!("No role or agent")
+        }
+    }
+
+    fn role_info_value(&self) -> Result<Value> {
+        let role = match &self.session {
+            Some(session) if session.role_name().is_some() => session.to_role(),
+            Some(_) => bail!("No session role"),
+            None => match &self.role {
+                Some(role) => role.clone(),
+                None => bail!("No role"),
+            },
+        };
+        let mut value = json!(role);
+        value["model"] = role.model().id().into();
+        value["interpolated_prompt"] = role.interpolated_prompt().into();
+        if !role.variable_values().is_empty() {
+            value["variable_values"] = json!(role.variable_values());
+        }
+        let path = Self::role_file(role.name());
+        if !role.name().is_empty() && path.exists() {
+            value["path"] = path.display().to_string().into();
+        }
+        Ok(value)
+    }
+
     pub fn role_info(&self) -> Result<String> {
         if let Some(session) = &self.session {
             if session.role_name().is_some() {
@@ -940,12 +1532,22 @@ impl Config {
     }
 
     pub fn exit_role(&mut self) -> Result<()> {
+        let role_rag = self.role_rag();
         if let Some(session) = self.session.as_mut() {
             session.guard_empty()?;
             session.clear_role();
         } else if self.role.is_some() {
             self.role = None;
         }
+        // Leave the RAG the role brought along, unless another one has been picked since
+        if let Some((name, _)) = role_rag
+            .as_deref()
+            .and_then(|v| RagOverrides::parse(v).ok())
+        {
+            if self.rag.as_ref().is_some_and(|rag| rag.name() == name) {
+                self.rag = None;
+            }
+        }
         Ok(())
     }
 
@@ -956,8 +1558,14 @@ impl Config {
             let content = read_to_string(&path)?;
             Role::new(name, &content)
         } else {
-            Role::builtin(name)?
+            Role::builtin(name).map_err(|_| {
+                anyhow!(
+                    "Unknown role `{name}`{}",
+                    did_you_mean(name, Self::list_roles(true))
+                )
+            })?
         };
+        role.resolve_output_schema()?;
         let current_model = self.current_model().clone();
         match role.model_id() {
             Some(model_id) => {
@@ -982,6 +1590,9 @@ impl Config {
                 if role.presence_penalty().is_none() {
                     role.set_presence_penalty(self.presence_penalty);
                 }
+                if role.stop().is_none() {
+                    role.set_stop(self.stop.clone());
+                }
             }
         }
         Ok(role)
@@ -1121,8 +1732,17 @@ impl Config {
         match session_name {
             None | Some(TEMP_SESSION_NAME) => {
                 let session_file = self.session_file(TEMP_SESSION_NAME);
-                if session_file.exists() {
-                    remove_file(session_file).with_context(|| {
+                let scratchpad_file = self.scratchpad_path(TEMP_SESSION_NAME);
+                for path in [session_file, scratchpad_file] {
+                    if path.exists() {
+                        remove_file(path).with_context(|| {
+                            format!("Failed to cleanup previous '{TEMP_SESSION_NAME}' session")
+                        })?;
+                    }
+                }
+                let checkpoints_dir = self.checkpoints_dir(TEMP_SESSION_NAME);
+                if checkpoints_dir.exists() {
+                    remove_dir_all(checkpoints_dir).with_context(|| {
                         format!("Failed to cleanup previous '{TEMP_SESSION_NAME}' session")
                     })?;
                 }
@@ -1167,6 +1787,52 @@ impl Config {
         Ok(())
     }
 
+    pub async fn use_conversation_template(
+        config: &GlobalConfig,
+        name: &str,
+        session_name: Option<&str>,
+        abort_signal: AbortSignal,
+    ) -> Result<()> {
+        let template = Self::load_conversation_template(name)?;
+        if let Some(model_id) = &template.model {
+            config.write().set_model(model_id)?;
+        }
+        let session_name = match session_name {
+            Some(v) => v.to_string(),
+            None => format!("{name}-{}", chrono::Local::now().format("%Y%m%d")),
+        };
+        if config.read().session_file(&session_name).exists() {
+            bail!("Session '{session_name}' already exists, use '--session <NAME>' to pick another name");
+        }
+        let (messages, data_urls) = template.build_messages(config, name, abort_signal).await?;
+        let mut config = config.write();
+        config.use_session(Some(&session_name))?;
+        if let Some(session) = config.session.as_mut() {
+            session.seed_messages(messages, data_urls);
+            session.set_save_session_this_time();
+        }
+        config.save_session(None)
+    }
+
+    /// Critiques and rewrites a draft prompt, returning the critique and the new prompt.
+    pub async fn improve_prompt(config: &GlobalConfig, draft: &str) -> Result<(String, String)> {
+        let mut role = config.read().retrieve_role(IMPROVE_PROMPT_ROLE)?;
+        if let Some(model_id) = config.read().improve_prompt_model.clone() {
+            role.set_model(Model::retrieve_model(
+                &config.read(),
+                &model_id,
+                ModelType::Chat,
+            )?);
+        }
+        let input = Input::from_str(config, draft, Some(role));
+        let output = input.fetch_chat_text().await?;
+        let (critique, prompt) = parse_improved_prompt(&output);
+        if prompt.is_empty() {
+            bail!("The model returned no improved prompt");
+        }
+        Ok((critique, prompt))
+    }
+
     pub fn session_info(&self) -> Result<String> {
         if let Some(session) = &self.session {
             let render_options = self.render_options()?;
@@ -1197,6 +1863,7 @@ impl Config {
 
     pub fn save_session(&mut self, name: Option<&str>) -> Result<()> {
         let session_name = match &self.session {
+            Some(session) if session.is_read_only() => bail!("The session is read-only"),
             Some(session) => match name {
                 Some(v) => v.to_string(),
                 None => session
@@ -1213,6 +1880,30 @@ impl Config {
         Ok(())
     }
 
+    /// Locks or unlocks the session, persisting the flag unless it is the temp session.
+    pub fn lock_session(&mut self, locked: bool) -> Result<()> {
+        let name = match &self.session {
+            Some(session) => session.name().to_string(),
+            None => bail!("No session"),
+        };
+        let session_path = self.session_file(&name);
+        if let Some(session) = self.session.as_mut() {
+            session.set_locked(locked);
+            if name != TEMP_SESSION_NAME {
+                session.save(&name, &session_path, false)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_session_read_only(&mut self) -> Result<()> {
+        match self.session.as_mut() {
+            Some(session) => session.set_read_only(),
+            None => bail!("No session"),
+        }
+        Ok(())
+    }
+
     pub fn edit_session(&mut self) -> Result<()> {
         let name = match &self.session {
             Some(session) => session.name().to_string(),
@@ -1234,6 +1925,7 @@ impl Config {
 
     pub fn empty_session(&mut self) -> Result<()> {
         if let Some(session) = self.session.as_mut() {
+            session.guard_writable()?;
             if let Some(agent) = self.agent.as_ref() {
                 session.sync_agent(agent);
             }
@@ -1245,6 +1937,82 @@ impl Config {
         Ok(())
     }
 
+    /// Copies the session into a new one named `name`, or `<session>-fork`, saves it and
+    /// switches to it. The original is exited as usual, so it keeps its messages up to here.
+    pub fn fork_session(&mut self, name: Option<&str>) -> Result<String> {
+        let Some(session) = self.session.as_ref() else {
+            bail!("No session")
+        };
+        if session.is_empty() {
+            bail!("The session has no messages to fork")
+        }
+        let name = match name {
+            Some(TEMP_SESSION_NAME) => bail!("The session name '{TEMP_SESSION_NAME}' is reserved"),
+            Some(name) if self.session_file(name).exists() => {
+                bail!("Session '{name}' already exists")
+            }
+            Some(name) => name.to_string(),
+            None => {
+                let base = session.autoname().unwrap_or(session.name());
+                let base = base.rsplit('/').next().unwrap_or(base);
+                let mut name = format!("{base}-fork");
+                let mut index = 2;
+                while self.session_file(&name).exists() {
+                    name = format!("{base}-fork-{index}");
+                    index += 1;
+                }
+                name
+            }
+        };
+        let mut fork = session.fork(&name);
+        self.exit_session()?;
+        fork.save(&name, &self.session_file(&name), false)?;
+        self.session = Some(fork);
+        Ok(name)
+    }
+
+    /// Where the checkpoints of a session are saved, next to its file.
+    fn checkpoints_dir(&self, session_name: &str) -> PathBuf {
+        self.session_file(session_name)
+            .with_extension("checkpoints")
+    }
+
+    /// Snapshots the session under `name`, replacing an older checkpoint of that name.
+    pub fn checkpoint_session(&mut self, name: &str) -> Result<()> {
+        let Some(session) = self.session.as_ref() else {
+            bail!("No session")
+        };
+        session.save_checkpoint(&self.checkpoints_dir(session.name()), name)
+    }
+
+    /// Rolls the session back to a checkpoint: messages, role, model and settings.
+    pub fn restore_checkpoint(&mut self, name: &str) -> Result<()> {
+        let Some(session) = self.session.as_ref() else {
+            bail!("No session")
+        };
+        session.guard_writable()?;
+        session::guard_checkpoint_name(name)?;
+        let path = self
+            .checkpoints_dir(session.name())
+            .join(format!("{name}.yaml"));
+        if !path.exists() {
+            bail!("Unknown checkpoint '{name}'")
+        }
+        let checkpoint = Session::load(self, session.name(), &path)?;
+        if let Some(session) = self.session.as_mut() {
+            session.restore(&checkpoint);
+        }
+        self.discontinuous_last_message();
+        Ok(())
+    }
+
+    pub fn list_checkpoints(&self) -> Vec<String> {
+        match self.session.as_ref() {
+            Some(session) => session::list_checkpoints(&self.checkpoints_dir(session.name())),
+            None => vec![],
+        }
+    }
+
     pub fn set_save_session_this_time(&mut self) -> Result<()> {
         if let Some(session) = self.session.as_mut() {
             session.set_save_session_this_time();
@@ -1262,6 +2030,17 @@ impl Config {
         list_file_names(self.sessions_dir().join("_"), ".yaml")
     }
 
+    /// Removes the attachment blobs no saved session, of the REPL or of an agent, refers to.
+    pub fn prune_blobs(&self) -> Result<usize> {
+        let mut session_dirs = vec![self.sessions_dir()];
+        if let Ok(entries) = read_dir(Self::agents_data_dir()) {
+            for entry in entries.flatten() {
+                session_dirs.push(entry.path().join(SESSIONS_DIR_NAME));
+            }
+        }
+        session::prune_blobs(&session_dirs, &Self::blobs_dir())
+    }
+
     pub fn maybe_compress_session(config: GlobalConfig) {
         let mut need_compress = false;
         {
@@ -1277,15 +2056,7 @@ impl Config {
         if !need_compress {
             return;
         }
-        let color = if config.read().light_theme() {
-            nu_ansi_term::Color::LightGray
-        } else {
-            nu_ansi_term::Color::DarkGray
-        };
-        print!(
-            "\n📢 {}\n",
-            color.italic().paint("Compressing the session."),
-        );
+        config.read().print_notice("Compressing the session.");
         tokio::spawn(async move {
             if let Err(err) = Config::compress_session(&config).await {
                 warn!("Failed to compress the session: {err}");
@@ -1299,6 +2070,7 @@ impl Config {
     pub async fn compress_session(config: &GlobalConfig) -> Result<()> {
         match config.read().session.as_ref() {
             Some(session) => {
+                session.guard_writable()?;
                 if !session.has_user_messages() {
                     bail!("No need to compress since there are no messages in the session")
                 }
@@ -1311,7 +2083,14 @@ impl Config {
             .summarize_prompt
             .clone()
             .unwrap_or_else(|| SUMMARIZE_PROMPT.into());
-        let input = Input::from_str(config, &prompt, None);
+        let mut input = Input::from_str(config, &prompt, None);
+        if let Some(model_id) = config.read().compress_model.clone() {
+            input.set_model(Model::retrieve_model(
+                &config.read(),
+                &model_id,
+                ModelType::Chat,
+            )?);
+        }
         let summary = input.fetch_chat_text().await?;
         let summary_prompt = config
             .read()
@@ -1325,6 +2104,37 @@ impl Config {
         Ok(())
     }
 
+    /// Compresses the session before sending the input when it's over `compress_threshold`,
+    /// which REPL replies otherwise leave to the background, or when the request wouldn't fit
+    /// into `max_input_tokens` of the model.
+    pub async fn compress_session_to_fit(config: &GlobalConfig, input: &Input) -> Result<()> {
+        if !input.with_session() {
+            return Ok(());
+        }
+        let over_threshold = {
+            let config = config.read();
+            match config.session.as_ref() {
+                Some(session) if session.has_user_messages() && !session.is_read_only() => {
+                    session.need_compress(config.compress_threshold)
+                }
+                _ => return Ok(()),
+            }
+        };
+        let messages = input.build_messages()?;
+        if !over_threshold && !input.role().model().exceeds_max_input_tokens(&messages) {
+            return Ok(());
+        }
+        config.read().print_notice("Compressing the session.");
+        if let Some(session) = config.write().session.as_mut() {
+            session.set_compressing(true);
+        }
+        let ret = Config::compress_session(config).await;
+        if let Some(session) = config.write().session.as_mut() {
+            session.set_compressing(false);
+        }
+        ret.context("Failed to compress the session")
+    }
+
     pub fn is_compressing_session(&self) -> bool {
         self.session
             .as_ref()
@@ -1343,12 +2153,7 @@ impl Config {
         if !need_autoname {
             return;
         }
-        let color = if config.read().light_theme() {
-            nu_ansi_term::Color::LightGray
-        } else {
-            nu_ansi_term::Color::DarkGray
-        };
-        print!("\n📢 {}\n", color.italic().paint("Autonaming the session."),);
+        config.read().print_notice("Autonaming the session.");
         tokio::spawn(async move {
             if let Err(err) = Config::autoname_session(&config).await {
                 warn!("Failed to autonaming the session: {err}");
@@ -1359,6 +2164,19 @@ impl Config {
         });
     }
 
+    /// Names the temp session before a CMD-mode exit saves it, as no background task does there.
+    pub async fn autoname_session_before_exit(config: &GlobalConfig) {
+        let need_autoname = match config.read().session.as_ref() {
+            Some(session) => session.need_autoname() && session.saves_on_exit(),
+            None => false,
+        };
+        if need_autoname {
+            if let Err(err) = Config::autoname_session(config).await {
+                warn!("Failed to autonaming the session: {err}");
+            }
+        }
+    }
+
     pub async fn autoname_session(config: &GlobalConfig) -> Result<()> {
         let text = match config
             .read()
@@ -1397,65 +2215,233 @@ impl Config {
                 Rag::init(config, TEMP_RAG_NAME, &rag_path, &[], abort_signal).await?
             }
             Some(name) => {
+                let (name, overrides) = RagOverrides::parse(name)?;
                 let rag_path = config.read().rag_file(name);
-                if !rag_path.exists() {
+                let mut rag = if !rag_path.exists() && !name.contains(',') {
                     if config.read().working_mode.is_cmd() {
                         bail!("Unknown RAG '{name}'")
                     }
                     Rag::init(config, name, &rag_path, &[], abort_signal).await?
                 } else {
                     Rag::load(config, name, &rag_path)?
+                };
+                rag.set_overrides(overrides)?;
+                rag
+            }
+        };
+        config.write().rag = Some(Arc::new(rag));
+        Ok(())
+    }
+
+    /// Activates the RAG the current role declares with `rag:`, replacing the active one.
+    pub fn use_role_rag(config: &GlobalConfig) -> Result<()> {
+        let Some(value) = config.read().role_rag() else {
+            return Ok(());
+        };
+        let rag = Self::load_bound_rag(config, &value)?;
+        config.write().rag = Some(Arc::new(rag));
+        Ok(())
+    }
+
+    /// Loads a saved RAG given as `name[:key=value...]`, as roles and agents declare it.
+    pub fn load_bound_rag(config: &GlobalConfig, value: &str) -> Result<Rag> {
+        let (name, overrides) = RagOverrides::parse(value)?;
+        let rag_path = config.read().rag_file(name);
+        if !rag_path.exists() && !name.contains(',') {
+            bail!("Unknown RAG '{name}'")
+        }
+        let mut rag = Rag::load(config, name, &rag_path)?;
+        rag.set_overrides(overrides)?;
+        Ok(rag)
+    }
+
+    /// The `rag` of the current role. Sessions keep only the role name, so it comes from the
+    /// role file.
+    fn role_rag(&self) -> Option<String> {
+        if self.agent.is_some() {
+            return None;
+        }
+        match &self.session {
+            Some(session) => {
+                let role = self.retrieve_role(session.role_name()?).ok()?;
+                role.rag().map(|v| v.to_string())
+            }
+            None => self.role.as_ref()?.rag().map(|v| v.to_string()),
+        }
+    }
+
+    pub async fn edit_rag_docs(config: &GlobalConfig, abort_signal: AbortSignal) -> Result<()> {
+        let mut rag = match config.read().rag.clone() {
+            Some(v) => v.as_ref().clone(),
+            None => bail!("No RAG"),
+        };
+        rag.ensure_documents()?;
+
+        let document_paths = rag.document_paths();
+        let temp_file = temp_file(&format!("-rag-{}", rag.name()), ".txt");
+        tokio::fs::write(&temp_file, &document_paths.join("\n"))
+            .await
+            .with_context(|| format!("Failed to write to '{}'", temp_file.display()))?;
+        let editor = config.read().editor()?;
+        edit_file(&editor, &temp_file)?;
+        let new_document_paths = tokio::fs::read_to_string(&temp_file)
+            .await
+            .with_context(|| format!("Failed to read '{}'", temp_file.display()))?;
+        let new_document_paths = new_document_paths
+            .split('\n')
+            .filter_map(|v| {
+                let v = v.trim();
+                if v.is_empty() {
+                    None
+                } else {
+                    Some(v.to_string())
                 }
+            })
+            .collect::<Vec<_>>();
+        if new_document_paths.is_empty() || new_document_paths == document_paths {
+            bail!("No changes")
+        }
+        rag.refresh_document_paths(&new_document_paths, RagRefresh::Off, config, abort_signal)
+            .await?;
+        config.write().rag = Some(Arc::new(rag));
+        Ok(())
+    }
+
+    pub fn rag_document_paths(config: &GlobalConfig) -> Result<String> {
+        match config.read().rag.as_ref() {
+            Some(rag) => Ok(rag.document_paths().join("\n")),
+            None => bail!("No RAG"),
+        }
+    }
+
+    /// Indexes the documents at `paths` and keeps them among the RAG's document paths,
+    /// leaving the documents already indexed alone.
+    pub async fn add_rag_documents(
+        config: &GlobalConfig,
+        paths: &[String],
+        abort_signal: AbortSignal,
+    ) -> Result<()> {
+        let mut rag = match config.read().rag.clone() {
+            Some(v) => v.as_ref().clone(),
+            None => bail!("No RAG"),
+        };
+        rag.ensure_documents()?;
+        let mut document_paths = rag.document_paths().to_vec();
+        for path in paths {
+            let source = rag.document_source(path)?;
+            if document_paths.contains(&source) {
+                bail!("'{path}' is already in rag '{}'", rag.name());
             }
+            document_paths.push(source);
+        }
+        rag.refresh_document_paths(&document_paths, RagRefresh::Off, config, abort_signal)
+            .await?;
+        config.write().rag = Some(Arc::new(rag));
+        Ok(())
+    }
+
+    /// Drops `sources` from the RAG's document paths along with the documents only they
+    /// provided.
+    pub async fn remove_rag_documents(
+        config: &GlobalConfig,
+        sources: &[String],
+        abort_signal: AbortSignal,
+    ) -> Result<()> {
+        let mut rag = match config.read().rag.clone() {
+            Some(v) => v.as_ref().clone(),
+            None => bail!("No RAG"),
+        };
+        rag.ensure_documents()?;
+        let mut document_paths = rag.document_paths().to_vec();
+        for source in sources {
+            let index = match document_paths.iter().position(|v| v == source) {
+                Some(index) => index,
+                None => {
+                    let resolved = rag.document_source(source)?;
+                    match document_paths.iter().position(|v| *v == resolved) {
+                        Some(index) => index,
+                        None => bail!("'{source}' isn't in rag '{}'", rag.name()),
+                    }
+                }
+            };
+            document_paths.remove(index);
+        }
+        if document_paths.is_empty() {
+            bail!("Cannot remove every document path of rag '{}'", rag.name());
+        }
+        rag.refresh_document_paths(&document_paths, RagRefresh::Off, config, abort_signal)
+            .await?;
+        config.write().rag = Some(Arc::new(rag));
+        Ok(())
+    }
+
+    /// Syncs the RAG with its document paths. Local files unchanged since they were indexed
+    /// are skipped unless `force` is set.
+    pub async fn rebuild_rag(
+        config: &GlobalConfig,
+        force: bool,
+        abort_signal: AbortSignal,
+    ) -> Result<()> {
+        let mut rag = match config.read().rag.clone() {
+            Some(v) => v.as_ref().clone(),
+            None => bail!("No RAG"),
+        };
+        let document_paths = rag.document_paths().to_vec();
+        let refresh = if force {
+            RagRefresh::All
+        } else {
+            RagRefresh::Changed
         };
+        rag.refresh_document_paths(&document_paths, refresh, config, abort_signal)
+            .await?;
         config.write().rag = Some(Arc::new(rag));
         Ok(())
     }
 
-    pub async fn edit_rag_docs(config: &GlobalConfig, abort_signal: AbortSignal) -> Result<()> {
-        let mut rag = match config.read().rag.clone() {
-            Some(v) => v.as_ref().clone(),
+    pub async fn watch_rag(config: &GlobalConfig, abort_signal: AbortSignal) -> Result<()> {
+        let (name, document_paths) = match config.read().rag.as_ref() {
+            Some(rag) => {
+                rag.ensure_documents()?;
+                (rag.name().to_string(), rag.document_paths().to_vec())
+            }
             None => bail!("No RAG"),
         };
-
-        let document_paths = rag.document_paths();
-        let temp_file = temp_file(&format!("-rag-{}", rag.name()), ".txt");
-        tokio::fs::write(&temp_file, &document_paths.join("\n"))
-            .await
-            .with_context(|| format!("Failed to write to '{}'", temp_file.display()))?;
-        let editor = config.read().editor()?;
-        edit_file(&editor, &temp_file)?;
-        let new_document_paths = tokio::fs::read_to_string(&temp_file)
-            .await
-            .with_context(|| format!("Failed to read '{}'", temp_file.display()))?;
-        let new_document_paths = new_document_paths
-            .split('\n')
-            .filter_map(|v| {
-                let v = v.trim();
-                if v.is_empty() {
-                    None
-                } else {
-                    Some(v.to_string())
+        let mut watcher = RagWatcher::new(&document_paths, &config.read().document_loaders)?;
+        println!("Watching the documents of rag '{name}', press Ctrl+C to stop.");
+        loop {
+            tokio::select! {
+                ret = watcher.changed() => ret?,
+                _ = tokio::signal::ctrl_c() => return Ok(()),
+            }
+            abort_signal.reset();
+            if let Err(err) = Self::rebuild_rag(config, false, abort_signal.clone()).await {
+                if abort_signal.aborted() {
+                    return Ok(());
                 }
-            })
-            .collect::<Vec<_>>();
-        if new_document_paths.is_empty() || new_document_paths == document_paths {
-            bail!("No changes")
+                eprintln!("{}", warning_text(&format!("⚠️ {err}")));
+            }
         }
-        rag.refresh_document_paths(&new_document_paths, false, config, abort_signal)
-            .await?;
-        config.write().rag = Some(Arc::new(rag));
-        Ok(())
     }
 
-    pub async fn rebuild_rag(config: &GlobalConfig, abort_signal: AbortSignal) -> Result<()> {
+    pub async fn prune_rag(config: &GlobalConfig, options: &RagPruneOptions) -> Result<()> {
         let mut rag = match config.read().rag.clone() {
             Some(v) => v.as_ref().clone(),
             None => bail!("No RAG"),
         };
-        let document_paths = rag.document_paths().to_vec();
-        rag.refresh_document_paths(&document_paths, true, config, abort_signal)
-            .await?;
+        let (paths, num_chunks) = rag.prune(options).await?;
+        if paths.is_empty() {
+            println!("Nothing to prune.");
+            return Ok(());
+        }
+        for path in &paths {
+            println!("- {path}");
+        }
+        rag.save()?;
+        println!(
+            "✓ Pruned {} files ({num_chunks} chunks) from rag '{}'.",
+            paths.len(),
+            rag.name()
+        );
         config.write().rag = Some(Arc::new(rag));
         Ok(())
     }
@@ -1470,6 +2456,25 @@ impl Config {
         }
     }
 
+    /// The `n`th most recent assistant reply, 1 being the last one. Outside a session only the
+    /// last reply is known.
+    pub fn nth_response(&self, n: usize) -> Result<String> {
+        let replies = match self.session.as_ref().filter(|v| !v.is_empty()) {
+            Some(session) => session.assistant_replies(),
+            None => self
+                .last_message
+                .as_ref()
+                .filter(|v| !v.output.is_empty())
+                .map(|v| vec![v.output.clone()])
+                .unwrap_or_default(),
+        };
+        match n.checked_sub(1).and_then(|v| replies.iter().rev().nth(v)) {
+            Some(reply) => Ok(reply.clone()),
+            None if replies.is_empty() => bail!("No chat response"),
+            None => bail!("No response #{n}, there are {} responses", replies.len()),
+        }
+    }
+
     pub fn rag_info(&self) -> Result<String> {
         if let Some(rag) = &self.rag {
             rag.export()
@@ -1478,6 +2483,13 @@ impl Config {
         }
     }
 
+    pub fn rag_stats(&self) -> Result<String> {
+        match &self.rag {
+            Some(rag) => Ok(rag.stats()),
+            None => bail!("No RAG"),
+        }
+    }
+
     pub fn exit_rag(&mut self) -> Result<()> {
         self.rag.take();
         Ok(())
@@ -1490,14 +2502,67 @@ impl Config {
         abort_signal: AbortSignal,
     ) -> Result<String> {
         let (reranker_model, top_k) = rag.get_config();
+        let filter = config.read().rag_filter.clone();
+        if config.read().rag_citations {
+            let results = rag
+                .search_documents(
+                    text,
+                    top_k,
+                    reranker_model.as_deref(),
+                    filter.as_ref(),
+                    abort_signal,
+                )
+                .await?;
+            let context = rag.cited_context(&results);
+            let ids: Vec<_> = results.iter().map(|(id, _)| *id).collect();
+            rag.set_last_citations(&ids);
+            return Ok(config.read().rag_template(rag, &context, text));
+        }
         let (embeddings, ids) = rag
-            .search(text, top_k, reranker_model.as_deref(), abort_signal)
+            .search(
+                text,
+                top_k,
+                reranker_model.as_deref(),
+                filter.as_ref(),
+                abort_signal,
+            )
             .await?;
-        let text = config.read().rag_template(&embeddings, text);
+        let text = config.read().rag_template(rag, &embeddings, text);
         rag.set_last_sources(&ids);
         Ok(text)
     }
 
+    /// Like `search_rag`, but returns the chunks as documents for clients that ground on them natively.
+    pub async fn search_rag_documents(
+        config: &GlobalConfig,
+        rag: &Rag,
+        text: &str,
+        abort_signal: AbortSignal,
+    ) -> Result<Vec<ChatDocument>> {
+        let (reranker_model, top_k) = rag.get_config();
+        let filter = config.read().rag_filter.clone();
+        let results = rag
+            .search_documents(
+                text,
+                top_k,
+                reranker_model.as_deref(),
+                filter.as_ref(),
+                abort_signal,
+            )
+            .await?;
+        let ids: Vec<_> = results.iter().map(|(id, _)| *id).collect();
+        rag.set_last_sources(&ids);
+        let documents = results
+            .into_iter()
+            .map(|(id, text)| ChatDocument {
+                id: format!("{id:?}"),
+                title: rag.document_path(id).map(|v| v.to_string()),
+                text,
+            })
+            .collect();
+        Ok(documents)
+    }
+
     pub fn list_rags() -> Vec<String> {
         match read_dir(Self::rags_dir()) {
             Ok(rd) => {
@@ -1515,12 +2580,12 @@ impl Config {
         }
     }
 
-    pub fn rag_template(&self, embeddings: &str, text: &str) -> String {
+    pub fn rag_template(&self, rag: &Rag, embeddings: &str, text: &str) -> String {
         if embeddings.is_empty() {
             return text.to_string();
         }
-        self.rag_template
-            .as_deref()
+        rag.template_override()
+            .or(self.rag_template.as_deref())
             .unwrap_or(RAG_TEMPLATE)
             .replace("__CONTEXT__", embeddings)
             .replace("__INPUT__", text)
@@ -1627,6 +2692,19 @@ impl Config {
         Ok(value)
     }
 
+    pub fn list_conversation_templates() -> Vec<String> {
+        list_file_names(Self::templates_dir(), ".yaml")
+    }
+
+    pub fn load_conversation_template(name: &str) -> Result<ConversationTemplate> {
+        let path = Self::conversation_template_file(name);
+        let err = || format!("Failed to load template '{name}' at '{}'", path.display());
+        let content = read_to_string(&path).with_context(err)?;
+        let value: ConversationTemplate = serde_yaml::from_str(&content).with_context(err)?;
+        value.validate(name)?;
+        Ok(value)
+    }
+
     pub fn has_macro(name: &str) -> bool {
         let names = Self::list_macros();
         names.contains(&name.to_string())
@@ -1650,23 +2728,24 @@ impl Config {
         Ok(())
     }
 
-    pub fn apply_prelude(&mut self) -> Result<()> {
+    /// Returns whether a prelude was applied.
+    pub fn apply_prelude(&mut self) -> Result<bool> {
         if self.macro_flag || !self.state().is_empty() {
-            return Ok(());
+            return Ok(false);
         }
         let prelude = match self.working_mode {
             WorkingMode::Repl => self.repl_prelude.as_ref(),
             WorkingMode::Cmd => self.cmd_prelude.as_ref(),
-            WorkingMode::Serve => return Ok(()),
+            WorkingMode::Serve => return Ok(false),
         };
         let prelude = match prelude {
             Some(v) => {
                 if v.is_empty() {
-                    return Ok(());
+                    return Ok(false);
                 }
                 v.to_string()
             }
-            None => return Ok(()),
+            None => return Ok(false),
         };
 
         let err_msg = || format!("Invalid prelude '{prelude}");
@@ -1687,7 +2766,7 @@ impl Config {
                 bail!("{}", err_msg())
             }
         }
-        Ok(())
+        Ok(true)
     }
 
     pub fn select_functions(&self, role: &Role) -> Option<Vec<FunctionDeclaration>> {
@@ -1695,6 +2774,8 @@ impl Config {
         if self.function_calling {
             if let Some(use_tools) = role.use_tools() {
                 let mut tool_names: HashSet<String> = Default::default();
+                let mut with_delegate = false;
+                let mut with_scratchpad = false;
                 let declaration_names: HashSet<String> = self
                     .functions
                     .declarations()
@@ -1706,7 +2787,11 @@ impl Config {
                 } else {
                     for item in use_tools.split(',') {
                         let item = item.trim();
-                        if let Some(values) = self.mapping_tools.get(item) {
+                        if item == DELEGATE_FUNCTION_NAME {
+                            with_delegate = true;
+                        } else if item == SCRATCHPAD_FUNCTION_NAME {
+                            with_scratchpad = true;
+                        } else if let Some(values) = self.mapping_tools.get(item) {
                             tool_names.extend(
                                 values
                                     .split(',')
@@ -1730,6 +2815,12 @@ impl Config {
                         }
                     })
                     .collect();
+                if with_delegate {
+                    functions.push(FunctionDeclaration::delegate());
+                }
+                if with_scratchpad {
+                    functions.push(FunctionDeclaration::scratchpad());
+                }
             }
 
             if let Some(agent) = &self.agent {
@@ -1759,6 +2850,22 @@ impl Config {
         }
     }
 
+    /// Adds the enabled `builtin_tools` that aren't among the selected functions already.
+    pub fn append_builtin_functions(&self, functions: &mut Option<Vec<FunctionDeclaration>>) {
+        if !self.function_calling {
+            return;
+        }
+        let builtins: Vec<FunctionDeclaration> = self
+            .builtin_tools
+            .iter()
+            .filter(|name| !functions.iter().flatten().any(|v| &v.name == *name))
+            .filter_map(|name| FunctionDeclaration::builtin(name))
+            .collect();
+        if !builtins.is_empty() {
+            functions.get_or_insert_with(Vec::new).extend(builtins);
+        }
+    }
+
     pub fn editor(&self) -> Result<String> {
         EDITOR.get_or_init(move || {
             let editor = self.editor.clone()
@@ -1805,6 +2912,7 @@ impl Config {
                     }
                 }
                 ".rag" => map_completion_values(Self::list_rags()),
+                ".restore" => map_completion_values(self.list_checkpoints()),
                 ".agent" => map_completion_values(list_agents()),
                 ".macro" => map_completion_values(Self::list_macros()),
                 ".starter" => match &self.agent {
@@ -1821,14 +2929,29 @@ impl Config {
                         "temperature",
                         "top_p",
                         "use_tools",
+                        "stop",
                         "save_session",
+                        "autoname_sessions",
                         "compress_threshold",
+                        "compress_model",
                         "rag_reranker_model",
                         "rag_top_k",
+                        "rag_query_expansion",
+                        "rag_query_expansion_model",
+                        "rag_compression_model",
+                        "rag_recency_half_life",
+                        "rag_filter",
+                        "rag_citations",
+                        "improve_prompt_model",
                         "max_output_tokens",
+                        "logprobs",
+                        "top_logprobs",
+                        "seed",
+                        "google_search",
                         "dry_run",
                         "function_calling",
                         "stream",
+                        "stream_stats",
                         "save",
                         "highlight",
                     ];
@@ -1850,7 +2973,11 @@ impl Config {
                     None => vec![],
                 },
                 "dry_run" => complete_bool(self.dry_run),
+                "autoname_sessions" => complete_bool(self.autoname_sessions),
+                "logprobs" => complete_bool(self.logprobs),
+                "google_search" => complete_bool(self.google_search),
                 "stream" => complete_bool(self.stream),
+                "stream_stats" => complete_bool(self.stream_stats),
                 "save" => complete_bool(self.save),
                 "function_calling" => complete_bool(self.function_calling),
                 "use_tools" => {
@@ -1884,7 +3011,16 @@ impl Config {
                     .iter()
                     .map(|v| v.id())
                     .collect(),
+                "improve_prompt_model"
+                | "compress_model"
+                | "rag_query_expansion_model"
+                | "rag_compression_model" => list_models(self, ModelType::Chat)
+                    .iter()
+                    .map(|v| v.id())
+                    .collect(),
+                "rag_query_expansion" => vec!["hyde".into(), "variants".into(), "null".into()],
                 "highlight" => complete_bool(self.highlight),
+                "rag_citations" => complete_bool(self.rag_citations),
                 _ => vec![],
             };
             values = candidates.into_iter().map(|v| (v, None)).collect();
@@ -1897,6 +3033,17 @@ impl Config {
                     .collect();
             }
             values.extend(complete_agent_variables(args[0]));
+        } else if cmd == ".rag" && args.len() == 2 && args[0] == "remove" {
+            if let Some(rag) = &self.rag {
+                values = map_completion_values(rag.document_paths().to_vec());
+            }
+        } else if cmd == ".vars" {
+            if let Ok((defined_variables, _)) = self.current_variables() {
+                values = defined_variables
+                    .into_iter()
+                    .map(|v| (format!("{}=", v.name), Some(v.description)))
+                    .collect();
+            }
         };
         fuzzy_filter(values, |v| v.0.as_str(), filter)
     }
@@ -1907,13 +3054,39 @@ impl Config {
             .unwrap_or_else(|| SYNC_MODELS_URL.into())
     }
 
-    pub async fn sync_models(url: &str, abort_signal: AbortSignal) -> Result<()> {
-        let content = abortable_run_with_spinner(fetch(url), "Fetching models.yaml", abort_signal)
-            .await
-            .with_context(|| format!("Failed to fetch '{url}'"))?;
-        println!("✓ Fetched '{url}'");
-        let list = serde_yaml::from_str::<Vec<ProviderModels>>(&content)
-            .with_context(|| "Failed to parse models.yaml")?;
+    /// Syncs models from a URL or a local file, in the models.yaml format or the models.dev
+    /// schema. The synced list takes precedence over models.dev and the bundled models.yaml.
+    /// When a URL can't be reached, the current models stay in place.
+    pub async fn sync_models(source: &str, abort_signal: AbortSignal) -> Result<()> {
+        if is_url(source) && is_models_offline() {
+            eprintln!(
+                "{}",
+                warning_text(&format!(
+                    "⚠️ Not fetching '{source}' offline, keeping the current models"
+                ))
+            );
+            return Ok(());
+        }
+        let content = match abortable_run_with_spinner(
+            read_models_source(source),
+            "Fetching models",
+            abort_signal.clone(),
+        )
+        .await
+        {
+            Ok(v) => v,
+            Err(err) if is_url(source) && !abort_signal.aborted() => {
+                eprintln!(
+                    "{}",
+                    warning_text(&format!("⚠️ {err:#}, keeping the current models"))
+                );
+                return Ok(());
+            }
+            Err(err) => return Err(err).with_context(|| format!("Failed to fetch '{source}'")),
+        };
+        println!("✓ Fetched '{source}'");
+        let list = parse_models(&content)
+            .with_context(|| format!("Failed to parse models from '{source}'"))?;
         let models_override = ModelsOverride {
             version: env!("CARGO_PKG_VERSION").to_string(),
             list,
@@ -1929,13 +3102,55 @@ impl Config {
         Ok(())
     }
 
-    pub async fn refresh_client_models(abort_signal: AbortSignal) -> Result<()> {
+    pub async fn refresh_client_models(force: bool, abort_signal: AbortSignal) -> Result<()> {
         let config_path = Self::config_file();
-        let mut config = Self::load_from_file(&config_path)?;
+        let config = Self::load_from_file(&config_path)?;
+        // What gets saved keeps the `${VAR}` placeholders instead of their values
+        let mut saved_config = read_to_string(&config_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| Self::parse_config(&content))
+            .with_context(|| {
+                format!(
+                    "Failed to load config at '{}' without resolving environment variables",
+                    config_path.display()
+                )
+            })?;
+        if force && is_models_offline() {
+            eprintln!(
+                "{}",
+                warning_text("⚠️ Not refreshing models.dev offline, keeping the cached data")
+            );
+        } else if force {
+            let url = env::var(get_env_name("models_dev_url"))
+                .ok()
+                .or_else(|| config.models_dev_url.clone());
+            match abortable_run_with_spinner(
+                refresh_models_dev(url.as_deref()),
+                "Fetching models.dev",
+                abort_signal.clone(),
+            )
+            .await
+            {
+                Ok(list) => {
+                    println!("✓ Refreshed models.dev data for {} providers.", list.len())
+                }
+                Err(err) if abort_signal.aborted() => return Err(err),
+                Err(err) => eprintln!(
+                    "{}",
+                    warning_text(&format!(
+                        "⚠️ Failed to refresh models.dev: {err:#}, keeping the cached data"
+                    ))
+                ),
+            }
+        }
         let mut updated = false;
 
-        for client in config.clients.iter_mut() {
-            let ClientConfig::OpenAICompatibleConfig(client_config) = client else {
+        for (client, saved_client) in config.clients.iter().zip(saved_config.clients.iter_mut()) {
+            let (
+                ClientConfig::OpenAICompatibleConfig(client_config),
+                ClientConfig::OpenAICompatibleConfig(saved_client_config),
+            ) = (client, saved_client)
+            else {
                 continue;
             };
 
@@ -1967,7 +3182,7 @@ impl Config {
             .await
             {
                 Ok(model_names) if !model_names.is_empty() => {
-                    client_config.models = model_data_from_names(&model_names);
+                    saved_client_config.models = model_data_from_names(&model_names);
                     updated = true;
                 }
                 Ok(_) => eprintln!("✗ Skip {client_name}: no models returned"),
@@ -1980,7 +3195,7 @@ impl Config {
             return Ok(());
         }
 
-        config.save_to_file(&config_path)?;
+        saved_config.save_to_file(&config_path)?;
         println!(
             "✓ Updated client models and saved to '{}'.",
             config_path.display()
@@ -2008,6 +3223,20 @@ impl Config {
         matches!(self.theme.as_deref(), Some("light"))
     }
 
+    /// Prints a dimmed notice, to stderr outside the REPL to keep it out of piped replies.
+    pub fn print_notice(&self, text: &str) {
+        let color = if self.light_theme() {
+            nu_ansi_term::Color::LightGray
+        } else {
+            nu_ansi_term::Color::DarkGray
+        };
+        let notice = format!("\n📢 {}\n", color.italic().paint(text));
+        match self.working_mode.is_repl() {
+            true => print!("{notice}"),
+            false => eprint!("{notice}"),
+        }
+    }
+
     pub fn render_options(&self) -> Result<RenderOptions> {
         let theme = if self.highlight {
             let theme_mode = if self.light_theme() { "light" } else { "dark" };
@@ -2042,14 +3271,52 @@ impl Config {
 
     pub fn render_prompt_left(&self) -> String {
         let variables = self.generate_prompt_context();
-        let left_prompt = self.left_prompt.as_deref().unwrap_or(LEFT_PROMPT);
-        render_prompt(left_prompt, &variables)
+        match &self.left_prompt_segments {
+            Some(segments) if !segments.is_empty() => {
+                let template = self.build_segments_prompt(segments);
+                let output = render_prompt(&template, &variables);
+                let arrow = render_prompt("{color.cyan}>{color.reset} ", &variables);
+                format!("{}{arrow}", output.trim_end())
+            }
+            _ => {
+                let left_prompt = self.left_prompt.as_deref().unwrap_or(LEFT_PROMPT);
+                render_prompt(left_prompt, &variables)
+            }
+        }
     }
 
     pub fn render_prompt_right(&self) -> String {
         let variables = self.generate_prompt_context();
-        let right_prompt = self.right_prompt.as_deref().unwrap_or(RIGHT_PROMPT);
-        render_prompt(right_prompt, &variables)
+        match &self.right_prompt_segments {
+            Some(segments) if !segments.is_empty() => {
+                let template = self.build_segments_prompt(segments);
+                render_prompt(&template, &variables).trim_end().to_string()
+            }
+            _ => {
+                let right_prompt = self.right_prompt.as_deref().unwrap_or(RIGHT_PROMPT);
+                render_prompt(right_prompt, &variables)
+            }
+        }
+    }
+
+    /// Joins prompt segments into a template, each in its own color and followed by a space.
+    fn build_segments_prompt(&self, segments: &[String]) -> String {
+        let mut template = String::new();
+        for name in segments {
+            let (guard, body, color) = match PROMPT_SEGMENTS.iter().find(|v| v.0 == name) {
+                Some((_, guard, body, color)) => (*guard, body.to_string(), *color),
+                None => (name.as_str(), format!("{{{name}}}"), "reset"),
+            };
+            let color = self
+                .prompt_colors
+                .get(name)
+                .map(|v| v.as_str())
+                .unwrap_or(color);
+            template.push_str(&format!(
+                "{{?{guard} {{color.{color}}}{body}{{color.reset}} }}"
+            ));
+        }
+        template
     }
 
     pub fn print_markdown(&self, text: &str) -> Result<()> {
@@ -2109,6 +3376,9 @@ impl Config {
                 output.insert("session_autoname", autoname.to_string());
             }
             output.insert("dirty", session.dirty().to_string());
+            if session.is_read_only() {
+                output.insert("read_only", "true".to_string());
+            }
             let (tokens, percent) = session.tokens_usage();
             output.insert("consume_tokens", tokens.to_string());
             output.insert("consume_percent", percent.to_string());
@@ -2120,6 +3390,13 @@ impl Config {
         if let Some(agent) = &self.agent {
             output.insert("agent", agent.name().to_string());
         }
+        let pending_input_tokens = self.pending_input_tokens.load(Ordering::Relaxed);
+        if pending_input_tokens > 0 {
+            output.insert("pending_tokens", pending_input_tokens.to_string());
+        }
+        if let Some(branch) = git_branch() {
+            output.insert("git_branch", branch);
+        }
 
         if self.highlight {
             output.insert("color.reset", "\u{1b}[0m".to_string());
@@ -2146,7 +3423,15 @@ impl Config {
         output
     }
 
+    /// Starts the `max_time` budget of a prompt, which its tool calls then share.
+    pub fn start_max_time(&mut self) {
+        self.max_time_deadline = self.max_time.map(|v| Instant::now() + v);
+    }
+
     pub fn before_chat_completion(&mut self, input: &Input) -> Result<()> {
+        if let Some(session) = input.session(&self.session) {
+            session.guard_writable()?;
+        }
         self.last_message = Some(LastMessage::new(input.clone(), String::new()));
         Ok(())
     }
@@ -2167,6 +3452,13 @@ impl Config {
         Ok(())
     }
 
+    pub fn set_system_fingerprint(&mut self, input: &Input, value: &str) {
+        let seed = self.seed;
+        if let Some(session) = input.session_mut(&mut self.session) {
+            session.set_system_fingerprint(seed, value);
+        }
+    }
+
     fn discontinuous_last_message(&mut self) {
         if let Some(last_message) = self.last_message.as_mut() {
             last_message.continuous = false;
@@ -2301,23 +3593,16 @@ impl Config {
     fn load_from_file(config_path: &Path) -> Result<Self> {
         let err = || format!("Failed to load config at '{}'", config_path.display());
         let content = read_to_string(config_path).with_context(err)?;
-        let config: Self = serde_yaml::from_str(&content)
-            .map_err(|err| {
-                let err_msg = err.to_string();
-                let err_msg = if err_msg.starts_with(&format!("{CLIENTS_FIELD}: ")) {
-                    // location is incorrect, get rid of it
-                    err_msg
-                        .split_once(" at line")
-                        .map(|(v, _)| {
-                            format!("{v} (Sorry for being unable to provide an exact location)")
-                        })
-                        .unwrap_or_else(|| "clients: invalid value".into())
-                } else {
-                    err_msg
-                };
-                anyhow!("{err_msg}")
-            })
+        let mut value: serde_yaml::Value = serde_yaml::from_str(&content).with_context(err)?;
+        interpolate_env_variables(&mut value, &|name| env::var(name).ok()).with_context(err)?;
+        let config: Self = serde_yaml::from_value(value)
+            .map_err(config_error)
             .with_context(err)?;
+        Ok(config)
+    }
+
+    fn parse_config(content: &str) -> Result<Self> {
+        let config: Self = serde_yaml::from_str(content).map_err(config_error)?;
 
         Ok(config)
     }
@@ -2335,9 +3620,14 @@ impl Config {
             Some((v, _)) => v,
             _ => model_id,
         };
-        let is_openai_compatible = OPENAI_COMPATIBLE_PROVIDERS
-            .into_iter()
-            .any(|(name, _)| provider == name);
+        let no_native_client = matches!(
+            serde_json::from_value(json!({ "type": provider })),
+            Ok(ClientConfig::Unknown) | Err(_)
+        );
+        let is_openai_compatible = no_native_client
+            && OPENAI_COMPATIBLE_PROVIDERS
+                .into_iter()
+                .any(|(name, _)| provider == name);
         let client = if is_openai_compatible {
             json!({ "type": "openai-compatible", "name": provider })
         } else {
@@ -2369,6 +3659,18 @@ impl Config {
         if let Some(v) = read_env_value::<f64>(&get_env_name("presence_penalty")) {
             self.presence_penalty = v;
         }
+        if let Some(Some(v)) = read_env_bool(&get_env_name("logprobs")) {
+            self.logprobs = v;
+        }
+        if let Some(v) = read_env_value::<usize>(&get_env_name("top_logprobs")) {
+            self.top_logprobs = v;
+        }
+        if let Some(Some(v)) = read_env_bool(&get_env_name("google_search")) {
+            self.google_search = v;
+        }
+        if let Some(v) = read_env_value::<u64>(&get_env_name("seed")) {
+            self.seed = v;
+        }
 
         if let Some(Some(v)) = read_env_bool(&get_env_name("dry_run")) {
             self.dry_run = v;
@@ -2376,9 +3678,15 @@ impl Config {
         if let Some(Some(v)) = read_env_bool(&get_env_name("stream")) {
             self.stream = v;
         }
+        if let Some(Some(v)) = read_env_bool(&get_env_name("stream_stats")) {
+            self.stream_stats = v;
+        }
         if let Some(Some(v)) = read_env_bool(&get_env_name("save")) {
             self.save = v;
         }
+        if let Some(Some(v)) = read_env_bool(&get_env_name("tui")) {
+            self.tui = v;
+        }
         if let Ok(v) = env::var(get_env_name("keybindings")) {
             if v == "vi" {
                 self.keybindings = v;
@@ -2393,10 +3701,28 @@ impl Config {
         if let Some(Some(v)) = read_env_bool(&get_env_name("wrap_code")) {
             self.wrap_code = v;
         }
+        if let Some(v) = read_env_value::<String>(&get_env_name("improve_prompt_model")) {
+            self.improve_prompt_model = v;
+        }
 
         if let Some(Some(v)) = read_env_bool(&get_env_name("function_calling")) {
             self.function_calling = v;
         }
+        if let Some(Some(v)) = read_env_value::<String>(&get_env_name("capability_check")) {
+            match v.as_str() {
+                "error" => self.capability_check = CapabilityCheck::Error,
+                "strip" => self.capability_check = CapabilityCheck::Strip,
+                "warn" => self.capability_check = CapabilityCheck::Warn,
+                "off" => self.capability_check = CapabilityCheck::Off,
+                _ => {}
+            }
+        }
+        if let Some(v) = read_env_value::<String>(&get_env_name("image_output_dir")) {
+            self.image_output_dir = v;
+        }
+        if let Some(Some(v)) = read_env_bool(&get_env_name("image_preview")) {
+            self.image_preview = v;
+        }
         if let Ok(v) = env::var(get_env_name("mapping_tools")) {
             if let Ok(v) = serde_json::from_str(&v) {
                 self.mapping_tools = v;
@@ -2405,6 +3731,19 @@ impl Config {
         if let Some(v) = read_env_value::<String>(&get_env_name("use_tools")) {
             self.use_tools = v;
         }
+        if let Some(v) = read_env_value::<String>(&get_env_name("builtin_tools")) {
+            self.builtin_tools = v
+                .map(|v| {
+                    v.split(',')
+                        .map(|v| v.trim().to_string())
+                        .filter(|v| !v.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default();
+        }
+        if let Some(v) = read_env_value::<String>(&get_env_name("stop")) {
+            self.stop = v.map(|v| split_stop_value(&v));
+        }
 
         if let Some(v) = read_env_value::<String>(&get_env_name("repl_prelude")) {
             self.repl_prelude = v;
@@ -2419,15 +3758,37 @@ impl Config {
         if let Some(v) = read_env_bool(&get_env_name("save_session")) {
             self.save_session = v;
         }
+        if let Some(Some(v)) = read_env_bool(&get_env_name("autoname_sessions")) {
+            self.autoname_sessions = v;
+        }
         if let Some(Some(v)) = read_env_value::<usize>(&get_env_name("compress_threshold")) {
             self.compress_threshold = v;
         }
+        if let Some(v) = read_env_value::<String>(&get_env_name("compress_model")) {
+            self.compress_model = v;
+        }
         if let Some(v) = read_env_value::<String>(&get_env_name("summarize_prompt")) {
             self.summarize_prompt = v;
         }
         if let Some(v) = read_env_value::<String>(&get_env_name("summary_prompt")) {
             self.summary_prompt = v;
         }
+        if let Some(v) = read_env_value::<usize>(&get_env_name("input_preview_threshold")) {
+            self.input_preview_threshold = v;
+        }
+        if let Some(v) = read_env_value::<String>(&get_env_name("dedup_model")) {
+            self.dedup_model = v;
+        }
+        if let Some(Some(v)) = read_env_value::<f32>(&get_env_name("dedup_threshold")) {
+            self.dedup_threshold = v;
+        }
+        if let Some(Some(v)) = read_env_value::<String>(&get_env_name("dedup_action")) {
+            match v.as_str() {
+                "reuse" => self.dedup_action = DedupAction::Reuse,
+                "reference" => self.dedup_action = DedupAction::Reference,
+                _ => {}
+            }
+        }
 
         if let Some(v) = read_env_value::<String>(&get_env_name("rag_embedding_model")) {
             self.rag_embedding_model = v;
@@ -2438,6 +3799,20 @@ impl Config {
         if let Some(Some(v)) = read_env_value::<usize>(&get_env_name("rag_top_k")) {
             self.rag_top_k = v;
         }
+        if let Ok(v) = env::var(get_env_name("rag_query_expansion")) {
+            if let Ok(v) = QueryExpansion::parse(&v) {
+                self.rag_query_expansion = v;
+            }
+        }
+        if let Some(v) = read_env_value::<String>(&get_env_name("rag_query_expansion_model")) {
+            self.rag_query_expansion_model = v;
+        }
+        if let Some(v) = read_env_value::<String>(&get_env_name("rag_compression_model")) {
+            self.rag_compression_model = v;
+        }
+        if let Some(v) = read_env_value::<u32>(&get_env_name("rag_recency_half_life")) {
+            self.rag_recency_half_life = v;
+        }
         if let Some(v) = read_env_value::<usize>(&get_env_name("rag_chunk_size")) {
             self.rag_chunk_size = v;
         }
@@ -2447,12 +3822,48 @@ impl Config {
         if let Some(v) = read_env_value::<String>(&get_env_name("rag_template")) {
             self.rag_template = v;
         }
+        if let Some(Some(v)) = read_env_bool(&get_env_name("rag_citations")) {
+            self.rag_citations = v;
+        }
+        if let Some(Some(v)) = read_env_bool(&get_env_name("rag_embeddings_cache")) {
+            self.rag_embeddings_cache = v;
+        }
+        if let Some(Some(v)) = read_env_value::<usize>(&get_env_name("rag_embedding_concurrency")) {
+            self.rag_embedding_concurrency = v;
+        }
+        if let Some(v) = read_env_value::<String>(&get_env_name("rag_ocr_model")) {
+            self.rag_ocr_model = v;
+        }
+        if let Some(v) = read_env_value::<String>(&get_env_name("rag_summary_model")) {
+            self.rag_summary_model = v;
+        }
+        for (key, list) in [
+            ("rag_include", &mut self.rag_include),
+            ("rag_exclude", &mut self.rag_exclude),
+        ] {
+            if let Some(v) = read_env_value::<String>(&get_env_name(key)) {
+                *list = v
+                    .map(|v| {
+                        v.split(',')
+                            .map(|v| v.trim().to_string())
+                            .filter(|v| !v.is_empty())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+            }
+        }
+        if let Some(Some(v)) = read_env_bool(&get_env_name("rag_gitignore")) {
+            self.rag_gitignore = v;
+        }
 
         if let Ok(v) = env::var(get_env_name("document_loaders")) {
             if let Ok(v) = serde_json::from_str(&v) {
                 self.document_loaders = v;
             }
         }
+        if let Some(Some(v)) = read_env_value::<usize>(&get_env_name("repo_map_max_tokens")) {
+            self.repo_map_max_tokens = v;
+        }
 
         if let Some(Some(v)) = read_env_bool(&get_env_name("highlight")) {
             self.highlight = v;
@@ -2486,6 +3897,15 @@ impl Config {
         if let Some(v) = read_env_value::<String>(&get_env_name("user_agent")) {
             self.user_agent = v;
         }
+        if let Some(v) = read_env_value::<String>(&get_env_name("audit_log")) {
+            self.audit_log = v;
+        }
+        if let Some(v) = read_env_value::<usize>(&get_env_name("audit_log_max_body")) {
+            self.audit_log_max_body = v;
+        }
+        if let Some(Some(v)) = read_env_value::<u64>(&get_env_name("hook_timeout")) {
+            self.hook_timeout = v;
+        }
         if let Some(Some(v)) = read_env_bool(&get_env_name("save_shell_history")) {
             self.save_shell_history = v;
         }
@@ -2519,15 +3939,6 @@ impl Config {
         Ok(())
     }
 
-    fn setup_document_loaders(&mut self) {
-        [("pdf", "pdftotext $1 -"), ("docx", "pandoc --to plain $1")]
-            .into_iter()
-            .for_each(|(k, v)| {
-                let (k, v) = (k.to_string(), v.to_string());
-                self.document_loaders.entry(k).or_insert(v);
-            });
-    }
-
     fn setup_user_agent(&mut self) {
         if let Some("auto") = self.user_agent.as_deref() {
             self.user_agent = Some(format!(
@@ -2577,6 +3988,39 @@ impl WorkingMode {
     }
 }
 
+/// Previews the file edits in a response and writes them once confirmed.
+pub fn apply_file_edits(text: &str, yes: bool) -> Result<()> {
+    let edits = parse_file_edits(text)?;
+    if edits.is_empty() {
+        bail!("No file edits in the response");
+    }
+    let root = env::current_dir()?;
+    let changes = plan_file_edits(&edits, &root)?;
+    if changes.is_empty() {
+        println!("No changes");
+        return Ok(());
+    }
+    print!("{}", render_file_changes(&changes));
+    if !yes {
+        if !*IS_STDOUT_TERMINAL {
+            eprintln!(
+                "{}",
+                dimmed_text("Not applied, pass -y to apply the changes")
+            );
+            return Ok(());
+        }
+        let apply = Confirm::new(&format!("Apply the changes to {} files?", changes.len()))
+            .with_default(true)
+            .prompt()?;
+        if !apply {
+            return Ok(());
+        }
+    }
+    write_file_changes(&changes, &root)?;
+    println!("✓ Applied the changes to {} files.", changes.len());
+    Ok(())
+}
+
 #[async_recursion::async_recursion]
 pub async fn macro_execute(
     config: &GlobalConfig,
@@ -2597,6 +4041,7 @@ pub async fn macro_execute(
     config.temperature = role.temperature();
     config.top_p = role.top_p();
     config.use_tools = role.use_tools().clone();
+    config.stop = role.stop();
     config.macro_flag = true;
     config.model = role.model().clone();
     config.role = None;
@@ -2677,6 +4122,29 @@ pub struct MacroVariable {
     pub default: Option<String>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServeUpstream {
+    pub clients: Vec<String>,
+    pub max_failures: Option<usize>,
+    pub cooldown: Option<u64>,
+}
+
+/// Rewrites serve mode applies to incoming API requests before forwarding them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServeRule {
+    /// Regex the whole requested model must match; the rule applies to every request without it
+    #[serde(rename = "match", skip_serializing_if = "Option::is_none")]
+    pub model_match: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<isize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub strip: Vec<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModelsOverride {
     pub version: String,
@@ -2794,6 +4262,20 @@ pub(crate) fn ensure_parent_exists(path: &Path) -> Result<()> {
     Ok(())
 }
 
+fn config_error(err: serde_yaml::Error) -> anyhow::Error {
+    let err_msg = err.to_string();
+    let err_msg = if err_msg.starts_with(&format!("{CLIENTS_FIELD}: ")) {
+        // location is incorrect, get rid of it
+        err_msg
+            .split_once(" at line")
+            .map(|(v, _)| format!("{v} (Sorry for being unable to provide an exact location)"))
+            .unwrap_or_else(|| "clients: invalid value".into())
+    } else {
+        err_msg
+    };
+    anyhow!("{err_msg}")
+}
+
 fn read_env_value<T>(key: &str) -> Option<Option<T>>
 where
     T: std::str::FromStr,
@@ -2803,6 +4285,27 @@ where
     Some(value)
 }
 
+fn parse_stop_value(value: &Value) -> Option<Vec<String>> {
+    match value {
+        Value::String(v) => Some(vec![v.clone()]),
+        Value::Array(list) => Some(
+            list.iter()
+                .filter_map(|v| v.as_str().map(|v| v.to_string()))
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// Split comma-separated stop sequences, unescaping `\n` and `\t`.
+fn split_stop_value(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .filter(|v| !v.is_empty())
+        .map(|v| v.replace("\\n", "\n").replace("\\t", "\t"))
+        .collect()
+}
+
 fn parse_value<T>(value: &str) -> Result<Option<T>>
 where
     T: std::str::FromStr,
@@ -2853,12 +4356,232 @@ where
     Ok(())
 }
 
-fn format_option_value<T>(value: &Option<T>) -> String
-where
-    T: std::fmt::Display,
-{
-    match value {
-        Some(value) => value.to_string(),
-        None => "null".to_string(),
+/// Splits the reply of the improve-prompt role at its first `PROMPT:` header line, allowing
+/// for markdown around the headers. A reply without one is taken as the prompt whole.
+fn parse_improved_prompt(output: &str) -> (String, String) {
+    let header = |line: &str, name: &str| -> Option<String> {
+        let line = line.trim().trim_start_matches(['#', '*', ' ']);
+        if !line.get(..name.len())?.eq_ignore_ascii_case(name) {
+            return None;
+        }
+        Some(
+            line[name.len()..]
+                .trim_start_matches('*')
+                .trim()
+                .to_string(),
+        )
+    };
+    let output = output.trim();
+    let lines: Vec<&str> = output.lines().collect();
+    let Some(index) = lines.iter().position(|v| header(v, "PROMPT:").is_some()) else {
+        return (String::new(), strip_code_fence(output));
+    };
+    let mut prompt = header(lines[index], "PROMPT:").unwrap_or_default();
+    for line in &lines[index + 1..] {
+        prompt.push('\n');
+        prompt.push_str(line);
+    }
+    let critique: Vec<String> = lines[..index]
+        .iter()
+        .map(|line| header(line, "CRITIQUE:").unwrap_or_else(|| line.to_string()))
+        .collect();
+    let critique = critique.join("\n").trim().to_string();
+    (critique, strip_code_fence(&prompt))
+}
+
+/// Drops a code fence wrapped around the whole text.
+fn strip_code_fence(text: &str) -> String {
+    let text = text.trim();
+    match text
+        .strip_prefix("```")
+        .and_then(|v| v.split_once('\n'))
+        .and_then(|(_, v)| v.trim_end().strip_suffix("```"))
+    {
+        Some(inner) => inner.trim().to_string(),
+        None => text.to_string(),
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Answers chat completions on a local port with a fixed summary, noting the model asked.
+    async fn serve_summaries(models: Arc<RwLock<Vec<String>>>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut data = vec![];
+                let mut buf = [0; 4096];
+                let body = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    data.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&data).to_string();
+                    let Some((head, body)) = text.split_once("\r\n\r\n") else {
+                        continue;
+                    };
+                    let len: usize = head
+                        .to_lowercase()
+                        .lines()
+                        .find_map(|v| v.strip_prefix("content-length:")?.trim().parse().ok())
+                        .unwrap_or_default();
+                    if n == 0 || body.len() >= len {
+                        break body.to_string();
+                    }
+                };
+                let body: Value = serde_json::from_str(&body).unwrap();
+                models
+                    .write()
+                    .push(body["model"].as_str().unwrap().to_string());
+                let message = json!({ "role": "assistant", "content": "Summary." });
+                let reply = json!({ "choices": [{ "index": 0, "message": message }] }).to_string();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{reply}",
+                    reply.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        format!("http://{addr}/v1")
+    }
+
+    async fn compress_with(compress_model: Option<&str>) -> Vec<String> {
+        let models = Arc::new(RwLock::new(vec![]));
+        let api_base = serve_summaries(models.clone()).await;
+        let mut config = Config {
+            compress_threshold: 1,
+            compress_model: compress_model.map(|v| v.to_string()),
+            ..Default::default()
+        };
+        config.clients = serde_json::from_value(json!([{
+            "type": "openai-compatible",
+            "name": "local",
+            "api_base": api_base,
+            "models": [{ "name": "chat" }, { "name": "small" }],
+        }]))
+        .unwrap();
+        config.set_model("local:chat").unwrap();
+        config.session = Some(Session::new(&config, "work"));
+        let config = Arc::new(RwLock::new(config));
+        let input = Input::from_str(&config, "What is a monad?", None);
+        if let Some(session) = config.write().session.as_mut() {
+            session
+                .add_message(&input, "A monoid in the category of endofunctors.")
+                .unwrap();
+        }
+        let input = Input::from_str(&config, "Explain it simpler.", None);
+        Config::compress_session_to_fit(&config, &input)
+            .await
+            .unwrap();
+        let session = config.read().session.clone().unwrap();
+        assert!(!session.has_user_messages());
+        assert!(!session.compressing());
+        let models = models.read().clone();
+        models
+    }
+
+    #[tokio::test]
+    async fn test_compress_session_to_fit() {
+        assert_eq!(compress_with(None).await, ["chat"]);
+        assert_eq!(compress_with(Some("local:small")).await, ["small"]);
+    }
+
+    #[tokio::test]
+    async fn test_compress_session_to_fit_read_only() {
+        let models = Arc::new(RwLock::new(vec![]));
+        let api_base = serve_summaries(models.clone()).await;
+        let mut config = Config {
+            compress_threshold: 1,
+            ..Default::default()
+        };
+        config.clients = serde_json::from_value(json!([{
+            "type": "openai-compatible",
+            "name": "local",
+            "api_base": api_base,
+            "models": [{ "name": "chat" }],
+        }]))
+        .unwrap();
+        config.set_model("local:chat").unwrap();
+        config.session = Some(Session::new(&config, "work"));
+        let config = Arc::new(RwLock::new(config));
+        let input = Input::from_str(&config, "What is a monad?", None);
+        if let Some(session) = config.write().session.as_mut() {
+            session.add_message(&input, "A monoid.").unwrap();
+            session.set_read_only();
+        }
+        Config::compress_session_to_fit(&config, &input)
+            .await
+            .unwrap();
+        assert!(config.read().session.as_ref().unwrap().has_user_messages());
+        assert!(models.read().is_empty());
+    }
+
+    #[test]
+    fn test_start_max_time() {
+        let mut config = Config::default();
+        config.start_max_time();
+        assert!(config.max_time_deadline.is_none());
+        config.max_time = Some(Duration::from_secs(60));
+        config.start_max_time();
+        let first = config.max_time_deadline.unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        // Each REPL prompt gets the full budget again
+        config.start_max_time();
+        assert!(config.max_time_deadline.unwrap() > first);
+    }
+
+    #[test]
+    fn test_fork_session() {
+        let dir = temp_file("-fork-sessions", "");
+        env::set_var(get_env_name("sessions_dir"), &dir);
+        let global: GlobalConfig = Arc::new(RwLock::new(Config::default()));
+        for (name, locked) in [("notes", false), ("journal", true)] {
+            let mut session = Session::new(&global.read(), name);
+            let input = Input::from_str(&global, "hello", None);
+            session.add_message(&input, "hi").unwrap();
+            if locked {
+                session.set_locked(true);
+            } else {
+                session.set_read_only();
+            }
+            global.write().session = Some(session);
+            let fork = global.write().fork_session(None).unwrap();
+            assert_eq!(fork, format!("{name}-fork"));
+            let config = global.read();
+            let session = config.session.as_ref().unwrap();
+            assert_eq!(session.name(), fork);
+            assert!(!session.is_read_only());
+            assert!(session.has_user_messages());
+            assert!(config.session_file(&fork).exists());
+            assert!(!config.session_file(name).exists());
+        }
+        assert!(global.write().fork_session(Some("notes-fork")).is_err());
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_parse_improved_prompt() {
+        let parse = parse_improved_prompt;
+        assert_eq!(
+            parse("CRITIQUE:\n- Vague\n\nPROMPT:\nList three facts.\nPROMPT: keep it short."),
+            (
+                "- Vague".into(),
+                "List three facts.\nPROMPT: keep it short.".into()
+            )
+        );
+        assert_eq!(
+            parse("**CRITIQUE:**\n- Vague\n\n## PROMPT:\n```\nList three facts.\n```"),
+            ("- Vague".into(), "List three facts.".into())
+        );
+        assert_eq!(
+            parse("Prompt: List three facts."),
+            (String::new(), "List three facts.".into())
+        );
+        assert_eq!(
+            parse("List three facts about Rust."),
+            (String::new(), "List three facts about Rust.".into())
+        );
+        assert_eq!(parse("CRITIQUE:\n- Vague\nPROMPT:").1, "");
     }
 }
@@ -0,0 +1,348 @@
+use super::*;
+
+use crate::function::ToolCall;
+
+use std::fs;
+use std::process::Command;
+use std::time::{Instant, SystemTime};
+
+/// A machine-readable account of a cmd-mode run, written by `--report` so runs under CI or
+/// cron can be reviewed afterwards.
+#[derive(Debug, Clone)]
+pub struct RunReport {
+    path: String,
+    started: Instant,
+    started_at: String,
+    git_snapshot: Option<IndexMap<String, FileStamp>>,
+    steps: Vec<ReportStep>,
+    output: Option<String>,
+}
+
+/// One chat-completions call and the tools it asked for.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportStep {
+    model: String,
+    latency_ms: u128,
+    input_tokens: Option<u64>,
+    output_tokens: Option<u64>,
+    tokens_estimated: bool,
+    cost: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    tool_calls: Vec<ReportToolCall>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportToolCall {
+    name: String,
+    arguments: Value,
+    duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// The size and modification time of a changed file in the git work tree.
+type FileStamp = (u64, Option<SystemTime>);
+
+impl RunReport {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            started: Instant::now(),
+            started_at: now(),
+            git_snapshot: git_changed_files(),
+            steps: vec![],
+            output: None,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_step(
+        &mut self,
+        model: &Model,
+        latency_ms: u128,
+        input_tokens: Option<u64>,
+        output_tokens: Option<u64>,
+        tokens_estimated: bool,
+        cost: Option<f64>,
+        error: Option<String>,
+    ) {
+        self.steps.push(ReportStep {
+            model: model.id(),
+            latency_ms,
+            input_tokens,
+            output_tokens,
+            tokens_estimated,
+            cost,
+            error,
+            tool_calls: vec![],
+        });
+    }
+
+    /// The step whose reply is asking for tools right now.
+    pub fn current_step(&self) -> Option<usize> {
+        self.steps.len().checked_sub(1)
+    }
+
+    /// Records a tool call under `step`, which is taken before the call runs since delegated
+    /// agents add steps of their own.
+    pub fn add_tool_call(
+        &mut self,
+        step: Option<usize>,
+        call: &ToolCall,
+        duration_ms: u128,
+        error: Option<String>,
+    ) {
+        if let Some(step) = step.and_then(|v| self.steps.get_mut(v)) {
+            step.tool_calls.push(ReportToolCall {
+                name: call.name.clone(),
+                arguments: call.arguments.clone(),
+                duration_ms,
+                error,
+            });
+        }
+    }
+
+    pub fn set_output(&mut self, output: &str) {
+        self.output = Some(output.to_string());
+    }
+
+    /// Writes the report, as markdown when the path ends with `.md` and as JSON otherwise.
+    pub fn write(self, config: &Config, status: &str, error: Option<String>) -> Result<()> {
+        let path = resolve_home_dir(&self.path);
+        let value = self.to_value(config, status, error);
+        let contents = if path.ends_with(".md") || path.ends_with(".markdown") {
+            render_markdown(&value)
+        } else {
+            serde_json::to_string_pretty(&value)?
+        };
+        ensure_parent_exists(Path::new(&path))?;
+        fs::write(&path, contents)
+            .with_context(|| format!("Failed to write the run report to '{path}'"))
+    }
+
+    fn to_value(&self, config: &Config, status: &str, error: Option<String>) -> Value {
+        let sum_tokens =
+            |f: fn(&ReportStep) -> Option<u64>| -> u64 { self.steps.iter().filter_map(f).sum() };
+        let cost = self
+            .steps
+            .iter()
+            .try_fold(0.0, |acc, v| Some(acc + v.cost?));
+        let tool_calls: usize = self.steps.iter().map(|v| v.tool_calls.len()).sum();
+        let files_modified = self.git_snapshot.as_ref().map(|before| {
+            git_changed_files()
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|(path, stamp)| before.get(path) != Some(stamp))
+                .map(|(path, _)| path)
+                .collect::<Vec<_>>()
+        });
+        json!({
+            "status": status,
+            "error": error,
+            "started_at": self.started_at,
+            "finished_at": now(),
+            "duration_ms": self.started.elapsed().as_millis(),
+            "model": config.current_model().id(),
+            "agent": config.agent.as_ref().map(|v| v.name()),
+            "role": config.role.as_ref().map(|v| v.name()),
+            "session": config.session.as_ref().map(|v| v.name()),
+            "tags": config.tags,
+            "input_tokens": sum_tokens(|v| v.input_tokens),
+            "output_tokens": sum_tokens(|v| v.output_tokens),
+            "cost": cost,
+            "tool_calls": tool_calls,
+            "files_modified": files_modified,
+            "steps": self.steps,
+            "output": self.output,
+        })
+    }
+}
+
+fn render_markdown(value: &Value) -> String {
+    let text = |v: &Value| match v {
+        Value::Null => "-".to_string(),
+        Value::String(v) => v.clone(),
+        v => v.to_string(),
+    };
+    let cost = |v: &Value| match v.as_f64() {
+        Some(v) => format!("${v:.4}"),
+        None => "-".to_string(),
+    };
+    let mut lines = vec!["# Agent Run Report".to_string(), String::new()];
+    lines.push(format!("- Status: {}", text(&value["status"])));
+    for key in ["agent", "role", "session", "model"] {
+        if !value[key].is_null() {
+            lines.push(format!("- {}: {}", capitalize(key), text(&value[key])));
+        }
+    }
+    if let Some(tags) = value["tags"].as_object().filter(|v| !v.is_empty()) {
+        let tags: Vec<String> = tags
+            .iter()
+            .map(|(k, v)| format!("{k}={}", text(v)))
+            .collect();
+        lines.push(format!("- Tags: {}", tags.join(", ")));
+    }
+    lines.push(format!("- Started: {}", text(&value["started_at"])));
+    lines.push(format!(
+        "- Duration: {:.1}s",
+        value["duration_ms"].as_u64().unwrap_or_default() as f64 / 1000.0
+    ));
+    lines.push(format!(
+        "- Tokens: {} in / {} out",
+        value["input_tokens"], value["output_tokens"]
+    ));
+    lines.push(format!("- Cost: {}", cost(&value["cost"])));
+    if let Some(error) = value["error"].as_str() {
+        lines.extend([
+            String::new(),
+            "## Error".into(),
+            String::new(),
+            error.into(),
+        ]);
+    }
+
+    lines.extend([String::new(), "## Steps".into(), String::new()]);
+    lines.push("| # | Model | Latency | Input tokens | Output tokens | Cost | Tool calls |".into());
+    lines.push("|---|-------|---------|--------------|---------------|------|------------|".into());
+    let steps = value["steps"].as_array().cloned().unwrap_or_default();
+    let mut tool_lines = vec![];
+    for (index, step) in steps.iter().enumerate() {
+        let mut names = vec![];
+        for call in step["tool_calls"].as_array().into_iter().flatten() {
+            names.push(text(&call["name"]));
+            let status = match call["error"].as_str() {
+                Some(err) => format!("failed: {}", err.replace('|', "\\|")),
+                None => "ok".into(),
+            };
+            tool_lines.push(format!(
+                "| {} | `{}` | {}ms | {status} |",
+                index + 1,
+                text(&call["name"]),
+                call["duration_ms"]
+            ));
+        }
+        let estimated = if step["tokens_estimated"].as_bool() == Some(true) {
+            "~"
+        } else {
+            ""
+        };
+        lines.push(format!(
+            "| {} | {} | {}ms | {estimated}{} | {estimated}{} | {} | {} |",
+            index + 1,
+            text(&step["model"]),
+            step["latency_ms"],
+            text(&step["input_tokens"]),
+            text(&step["output_tokens"]),
+            cost(&step["cost"]),
+            names.join(", ")
+        ));
+    }
+    if !tool_lines.is_empty() {
+        lines.extend([String::new(), "## Tool Calls".into(), String::new()]);
+        lines.push("| Step | Tool | Duration | Status |".into());
+        lines.push("|------|------|----------|--------|".into());
+        lines.extend(tool_lines);
+    }
+    if let Some(files) = value["files_modified"].as_array() {
+        lines.extend([String::new(), "## Files Modified".into(), String::new()]);
+        if files.is_empty() {
+            lines.push("None".into());
+        }
+        lines.extend(files.iter().map(|v| format!("- {}", text(v))));
+    }
+    if let Some(output) = value["output"].as_str() {
+        lines.extend([String::new(), "## Output".into(), String::new()]);
+        lines.push(output.trim_end().to_string());
+    }
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// The files `git status` lists as changed or untracked, relative to the root of the work
+/// tree, or None outside one.
+fn git_changed_files() -> Option<IndexMap<String, FileStamp>> {
+    let git = |args: &[&str]| {
+        let output = Command::new("git").args(args).output().ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).to_string())
+    };
+    let root = git(&["rev-parse", "--show-toplevel"])?;
+    let root = root.trim();
+    let status = git(&["-C", root, "status", "--porcelain", "--untracked-files=all"])?;
+    let files = parse_git_status(&status)
+        .into_iter()
+        .map(|path| {
+            let stamp = match fs::metadata(Path::new(root).join(&path)) {
+                Ok(metadata) => (metadata.len(), metadata.modified().ok()),
+                Err(_) => (0, None),
+            };
+            (path, stamp)
+        })
+        .collect();
+    Some(files)
+}
+
+fn parse_git_status(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.get(3..))
+        .map(|path| {
+            let path = path.rsplit(" -> ").next().unwrap_or(path);
+            path.trim_matches('"').to_string()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_git_status() {
+        let output = " M src/main.rs\n?? notes/todo.md\nR  old.rs -> new.rs\n D \"a b.txt\"\n";
+        assert_eq!(
+            parse_git_status(output),
+            ["src/main.rs", "notes/todo.md", "new.rs", "a b.txt"]
+        );
+    }
+
+    #[test]
+    fn test_render_markdown() {
+        let value = json!({
+            "status": "success",
+            "model": "openai:gpt-4o",
+            "started_at": "2024-06-01T08:00:00+00:00",
+            "duration_ms": 2500,
+            "input_tokens": 120,
+            "output_tokens": 40,
+            "cost": 0.0015,
+            "files_modified": ["src/main.rs"],
+            "steps": [{
+                "model": "openai:gpt-4o",
+                "latency_ms": 900,
+                "input_tokens": 120,
+                "output_tokens": 40,
+                "tokens_estimated": false,
+                "cost": 0.0015,
+                "tool_calls": [{"name": "fs_write", "arguments": {}, "duration_ms": 12}],
+            }],
+            "output": "Done.",
+        });
+        let markdown = render_markdown(&value);
+        assert!(markdown.contains("- Duration: 2.5s"));
+        assert!(markdown.contains("| 1 | openai:gpt-4o | 900ms | 120 | 40 | $0.0015 | fs_write |"));
+        assert!(markdown.contains("| 1 | `fs_write` | 12ms | ok |"));
+        assert!(markdown.contains("## Files Modified\n\n- src/main.rs"));
+    }
+}
@@ -1,12 +1,13 @@
+use super::agent::AgentVariable;
 use super::*;
 
-use crate::client::{Message, MessageContent, MessageRole, Model};
+use crate::client::{GuidedDecoding, Message, MessageContent, MessageRole, Model};
 
 use anyhow::Result;
 use fancy_regex::Regex;
 use rust_embed::Embed;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 use std::sync::LazyLock;
 
 pub const SHELL_ROLE: &str = "%shell%";
@@ -14,6 +15,9 @@ pub const EXPLAIN_SHELL_ROLE: &str = "%explain-shell%";
 pub const CODE_ROLE: &str = "%code%";
 pub const CREATE_TITLE_ROLE: &str = "%create-title%";
 pub const DISTROBOX_ROLE: &str = "%distrobox%";
+pub const IMPROVE_PROMPT_ROLE: &str = "%improve-prompt%";
+pub const COMMIT_MESSAGE_ROLE: &str = "%commit-message%";
+pub const EDIT_ROLE: &str = "%edit%";
 
 pub const INPUT_PLACEHOLDER: &str = "__INPUT__";
 
@@ -32,12 +36,14 @@ pub trait RoleLike {
     fn frequency_penalty(&self) -> Option<f64>;
     fn presence_penalty(&self) -> Option<f64>;
     fn use_tools(&self) -> Option<String>;
+    fn stop(&self) -> Option<Vec<String>>;
     fn set_model(&mut self, model: Model);
     fn set_temperature(&mut self, value: Option<f64>);
     fn set_top_p(&mut self, value: Option<f64>);
     fn set_frequency_penalty(&mut self, value: Option<f64>);
     fn set_presence_penalty(&mut self, value: Option<f64>);
     fn set_use_tools(&mut self, value: Option<String>);
+    fn set_stop(&mut self, value: Option<Vec<String>>);
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -60,9 +66,32 @@ pub struct Role {
     presence_penalty: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     use_tools: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    variables: Vec<AgentVariable>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    google_search: Option<bool>,
+    #[serde(flatten)]
+    guided: GuidedDecoding,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_format: Option<String>,
+    /// A JSON schema, or the path of a JSON/YAML file holding one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_schema: Option<Value>,
+    /// Names of `output_sinks` the final reply also goes to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    sinks: Vec<String>,
+    /// A saved RAG the role uses, e.g. `docs` or `docs:top_k=8`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rag: Option<String>,
 
+    #[serde(skip)]
+    resolved_output_schema: Option<Value>,
     #[serde(skip)]
     model: Model,
+    #[serde(skip)]
+    variable_values: AgentVariables,
 }
 
 impl Role {
@@ -93,6 +122,29 @@ impl Role {
                             "frequency_penalty" => role.frequency_penalty = value.as_f64(),
                             "presence_penalty" => role.presence_penalty = value.as_f64(),
                             "use_tools" => role.use_tools = value.as_str().map(|v| v.to_string()),
+                            "stop" => role.stop = parse_stop_value(value),
+                            "google_search" => role.google_search = value.as_bool(),
+                            "variables" => {
+                                role.variables =
+                                    serde_json::from_value(value.clone()).unwrap_or_default()
+                            }
+                            "guided_json" => role.guided.guided_json = Some(value.clone()),
+                            "guided_regex" => {
+                                role.guided.guided_regex = value.as_str().map(|v| v.to_string())
+                            }
+                            "guided_choice" => {
+                                role.guided.guided_choice =
+                                    serde_json::from_value(value.clone()).ok()
+                            }
+                            "guided_grammar" => {
+                                role.guided.guided_grammar = value.as_str().map(|v| v.to_string())
+                            }
+                            "output_format" => {
+                                role.output_format = value.as_str().map(|v| v.to_string())
+                            }
+                            "output_schema" => role.output_schema = Some(value.clone()),
+                            "sinks" => role.sinks = parse_sinks_value(value),
+                            "rag" => role.rag = value.as_str().map(|v| v.to_string()),
                             _ => (),
                         }
                     }
@@ -145,6 +197,45 @@ impl Role {
         if let Some(use_tools) = self.use_tools() {
             metadata.push(format!("use_tools: {use_tools}"));
         }
+        if let Some(stop) = self.stop() {
+            metadata.push(format!("stop: {}", json!(stop)));
+        }
+        if let Some(google_search) = self.google_search {
+            metadata.push(format!("google_search: {google_search}"));
+        }
+        if !self.variables.is_empty() {
+            let variables: Vec<Value> = self
+                .variables
+                .iter()
+                .map(|v| {
+                    let mut value = json!({ "name": v.name, "description": v.description });
+                    if let Some(default) = &v.default {
+                        value["default"] = default.clone().into();
+                    }
+                    value
+                })
+                .collect();
+            metadata.push(format!("variables: {}", json!(variables)));
+        }
+        if !self.guided.is_empty() {
+            if let Value::Object(guided) = json!(self.guided) {
+                for (key, value) in guided {
+                    metadata.push(format!("{key}: {value}"));
+                }
+            }
+        }
+        if let Some(output_format) = &self.output_format {
+            metadata.push(format!("output_format: {output_format}"));
+        }
+        if let Some(output_schema) = &self.output_schema {
+            metadata.push(format!("output_schema: {output_schema}"));
+        }
+        if !self.sinks.is_empty() {
+            metadata.push(format!("sinks: {}", json!(self.sinks)));
+        }
+        if let Some(rag) = &self.rag {
+            metadata.push(format!("rag: {rag}"));
+        }
         if metadata.is_empty() {
             format!("{}\n", self.prompt)
         } else if self.prompt.is_empty() {
@@ -184,6 +275,7 @@ impl Role {
         let frequency_penalty = role_like.frequency_penalty();
         let presence_penalty = role_like.presence_penalty();
         let use_tools = role_like.use_tools();
+        let stop = role_like.stop();
         self.batch_set(
             model,
             temperature,
@@ -192,6 +284,9 @@ impl Role {
             presence_penalty,
             use_tools,
         );
+        if stop.is_some() {
+            self.set_stop(stop);
+        }
     }
 
     pub fn batch_set(
@@ -225,6 +320,11 @@ impl Role {
         self.name.is_empty()
     }
 
+    /// Whether it's one of the `%name%` roles behind aichat's own modes and helpers.
+    pub fn is_builtin(&self) -> bool {
+        self.name.len() > 1 && self.name.starts_with('%') && self.name.ends_with('%')
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -233,12 +333,104 @@ impl Role {
         self.model_id.as_deref()
     }
 
-    pub fn prompt(&self) -> &str {
-        &self.prompt
+    /// The prompt with `{{name}}` placeholders replaced by the variable values.
+    pub fn interpolated_prompt(&self) -> String {
+        let mut output = self.prompt.clone();
+        for (k, v) in &self.variable_values {
+            output = output.replace(&format!("{{{{{k}}}}}"), v)
+        }
+        output
+    }
+
+    /// Whether the role turns Google Search grounding on or off, overriding `google_search`.
+    pub fn google_search(&self) -> Option<bool> {
+        self.google_search
+    }
+
+    pub fn guided(&self) -> Option<GuidedDecoding> {
+        let mut guided = self.guided.clone();
+        if guided.guided_json.is_none() {
+            guided.guided_json = self.resolved_output_schema.clone();
+        }
+        (!guided.is_empty()).then_some(guided)
+    }
+
+    /// The format the reply is printed in when no output flag is given on the command line.
+    pub fn output_format(&self) -> Option<&str> {
+        self.output_format.as_deref()
+    }
+
+    pub fn sinks(&self) -> &[String] {
+        &self.sinks
+    }
+
+    pub fn rag(&self) -> Option<&str> {
+        self.rag.as_deref()
+    }
+
+    /// The JSON schema replies must follow, once loaded by [`Role::resolve_output_schema`].
+    pub fn output_schema(&self) -> Option<&Value> {
+        self.resolved_output_schema.as_ref()
+    }
+
+    /// Checks `output_format` and loads `output_schema`, reading it from a file when it's a path.
+    /// Relative paths are resolved against the roles directory.
+    pub fn resolve_output_schema(&mut self) -> Result<()> {
+        if let Some(output_format) = &self.output_format {
+            let valid = matches!(
+                output_format.as_str(),
+                "markdown" | "json" | "yaml" | "plain" | "code"
+            ) || output_format.starts_with("code=");
+            if !valid {
+                bail!(
+                    "Invalid output_format '{output_format}' in role '{}', expected markdown, json, yaml, plain, code or code=<SELECT>",
+                    self.name
+                );
+            }
+        }
+        self.resolved_output_schema = match &self.output_schema {
+            Some(Value::String(path)) => {
+                let path = Config::roles_dir().join(resolve_home_dir(path));
+                let content = std::fs::read_to_string(&path).with_context(|| {
+                    format!("Failed to read the output schema at '{}'", path.display())
+                })?;
+                let schema: Value = serde_yaml::from_str(&content)
+                    .with_context(|| format!("Invalid output schema at '{}'", path.display()))?;
+                Some(schema)
+            }
+            Some(schema) => Some(schema.clone()),
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// The prompt plus, when the role has an output schema, the instruction to follow it.
+    fn full_prompt(&self) -> String {
+        let prompt = self.interpolated_prompt();
+        let Some(schema) = &self.resolved_output_schema else {
+            return prompt;
+        };
+        let schema = serde_json::to_string_pretty(schema).unwrap_or_default();
+        let instruction = format!(
+            "Reply with a single JSON value that conforms to this JSON schema, and nothing else:\n```json\n{schema}\n```"
+        );
+        if prompt.is_empty() {
+            instruction
+        } else {
+            format!("{prompt}\n\n{instruction}")
+        }
+    }
+
+    pub fn defined_variables(&self) -> &[AgentVariable] {
+        &self.variables
+    }
+
+    pub fn variable_values(&self) -> &AgentVariables {
+        &self.variable_values
     }
 
-    pub fn is_empty_prompt(&self) -> bool {
-        self.prompt.is_empty()
+    pub fn set_variable_values(&mut self, values: AgentVariables) {
+        self.variable_values = values;
     }
 
     pub fn is_embedded_prompt(&self) -> bool {
@@ -247,25 +439,27 @@ impl Role {
 
     pub fn echo_messages(&self, input: &Input) -> String {
         let input_markdown = input.render();
-        if self.is_empty_prompt() {
+        let prompt = self.full_prompt();
+        if prompt.is_empty() {
             input_markdown
         } else if self.is_embedded_prompt() {
-            self.prompt.replace(INPUT_PLACEHOLDER, &input_markdown)
+            prompt.replace(INPUT_PLACEHOLDER, &input_markdown)
         } else {
-            format!("{}\n\n{}", self.prompt, input_markdown)
+            format!("{prompt}\n\n{input_markdown}")
         }
     }
 
     pub fn build_messages(&self, input: &Input) -> Vec<Message> {
         let mut content = input.message_content();
-        let mut messages = if self.is_empty_prompt() {
+        let prompt = self.full_prompt();
+        let mut messages = if prompt.is_empty() {
             vec![Message::new(MessageRole::User, content)]
         } else if self.is_embedded_prompt() {
-            content.merge_prompt(|v: &str| self.prompt.replace(INPUT_PLACEHOLDER, v));
+            content.merge_prompt(|v: &str| prompt.replace(INPUT_PLACEHOLDER, v));
             vec![Message::new(MessageRole::User, content)]
         } else {
             let mut messages = vec![];
-            let (system, cases) = parse_structure_prompt(&self.prompt);
+            let (system, cases) = parse_structure_prompt(&prompt);
             if !system.is_empty() {
                 messages.push(Message::new(
                     MessageRole::System,
@@ -322,6 +516,10 @@ impl RoleLike for Role {
         self.use_tools.clone()
     }
 
+    fn stop(&self) -> Option<Vec<String>> {
+        self.stop.clone()
+    }
+
     fn set_model(&mut self, model: Model) {
         if !self.model().id().is_empty() {
             self.model_id = Some(model.id().to_string());
@@ -348,6 +546,27 @@ impl RoleLike for Role {
     fn set_use_tools(&mut self, value: Option<String>) {
         self.use_tools = value;
     }
+
+    fn set_stop(&mut self, value: Option<Vec<String>>) {
+        self.stop = value;
+    }
+}
+
+/// Reads `sinks: [notes, clip]` or `sinks: notes,clip`.
+fn parse_sinks_value(value: &Value) -> Vec<String> {
+    match value {
+        Value::String(v) => v
+            .split(',')
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .map(|v| v.to_string())
+            .collect(),
+        Value::Array(list) => list
+            .iter()
+            .filter_map(|v| v.as_str().map(|v| v.to_string()))
+            .collect(),
+        _ => vec![],
+    }
 }
 
 fn parse_structure_prompt(prompt: &str) -> (&str, Vec<(&str, &str)>) {
@@ -440,4 +659,119 @@ Input 1
 "#;
         assert_eq!(parse_structure_prompt(prompt), (prompt, vec![]));
     }
+
+    #[test]
+    fn test_role_stop() {
+        let role = Role::new("test", "---\nstop: [\"###\", \"END\"]\n---\nPrompt");
+        assert_eq!(
+            role.stop(),
+            Some(vec!["###".to_string(), "END".to_string()])
+        );
+        assert_eq!(
+            role.export(),
+            "---\nstop: [\"###\",\"END\"]\n---\n\nPrompt\n"
+        );
+
+        let role = Role::new("test", "---\nstop: \"###\"\n---\nPrompt");
+        assert_eq!(role.stop(), Some(vec!["###".to_string()]));
+    }
+
+    #[test]
+    fn test_role_variables() {
+        let content = "---\nvariables:\n  - name: lang\n    description: Target language\n    default: English\n---\nTranslate to {{lang}}";
+        let mut role = Role::new("test", content);
+        assert_eq!(role.defined_variables().len(), 1);
+        assert_eq!(
+            role.defined_variables()[0].default.as_deref(),
+            Some("English")
+        );
+        assert_eq!(role.interpolated_prompt(), "Translate to {{lang}}");
+        role.set_variable_values(IndexMap::from([("lang".into(), "French".into())]));
+        assert_eq!(role.interpolated_prompt(), "Translate to French");
+        assert_eq!(
+            role.export(),
+            "---\nvariables: [{\"name\":\"lang\",\"description\":\"Target language\",\"default\":\"English\"}]\n---\n\nTranslate to {{lang}}\n"
+        );
+    }
+
+    #[test]
+    fn test_role_guided() {
+        let content =
+            "---\nguided_json: {\"type\": \"object\"}\nguided_regex: '\\d+'\n---\nExtract";
+        let role = Role::new("test", content);
+        let guided = role.guided().unwrap();
+        assert_eq!(guided.guided_json, Some(json!({"type": "object"})));
+        assert_eq!(guided.guided_regex.as_deref(), Some("\\d+"));
+        assert_eq!(
+            role.export(),
+            "---\nguided_json: {\"type\":\"object\"}\nguided_regex: \"\\\\d+\"\n---\n\nExtract\n"
+        );
+        assert_eq!(Role::new("test", &role.export()).guided(), Some(guided));
+        assert!(Role::new("test", "Extract").guided().is_none());
+    }
+
+    #[test]
+    fn test_role_google_search() {
+        let role = Role::new("test", "---\ngoogle_search: true\n---\nResearch");
+        assert_eq!(role.google_search(), Some(true));
+        assert_eq!(role.export(), "---\ngoogle_search: true\n---\n\nResearch\n");
+        assert_eq!(Role::new("test", "Research").google_search(), None);
+        let role = Role::new("test", "---\ngoogle_search: false\n---\nResearch");
+        assert_eq!(role.google_search(), Some(false));
+    }
+
+    #[test]
+    fn test_role_output_schema() {
+        let content = "---\noutput_format: yaml\noutput_schema: {\"type\": \"object\"}\n---\nExtract the invoice";
+        let mut role = Role::new("test", content);
+        role.resolve_output_schema().unwrap();
+        assert_eq!(role.output_format(), Some("yaml"));
+        assert_eq!(role.output_schema(), Some(&json!({"type": "object"})));
+        assert_eq!(
+            role.guided().unwrap().guided_json,
+            Some(json!({"type": "object"}))
+        );
+        let prompt = role.full_prompt();
+        assert!(prompt.starts_with("Extract the invoice\n\nReply with a single JSON value"));
+        assert_eq!(
+            role.export(),
+            "---\noutput_format: yaml\noutput_schema: {\"type\":\"object\"}\n---\n\nExtract the invoice\n"
+        );
+
+        let mut role = Role::new("test", "---\noutput_format: xml\n---\nExtract");
+        assert!(role.resolve_output_schema().is_err());
+    }
+
+    #[test]
+    fn test_role_sinks() {
+        let role = Role::new("test", "---\nsinks: notes, clip\n---\nSummarize");
+        assert_eq!(role.sinks(), ["notes", "clip"]);
+        assert_eq!(
+            role.export(),
+            "---\nsinks: [\"notes\",\"clip\"]\n---\n\nSummarize\n"
+        );
+        let role = Role::new("test", &role.export());
+        assert_eq!(role.sinks(), ["notes", "clip"]);
+    }
+
+    #[test]
+    fn test_role_rag() {
+        let role = Role::new(
+            "test",
+            "---
+rag: docs:top_k=8
+---
+Answer from the docs",
+        );
+        assert_eq!(role.rag(), Some("docs:top_k=8"));
+        assert_eq!(
+            role.export(),
+            "---
+rag: docs:top_k=8
+---
+
+Answer from the docs
+"
+        );
+    }
 }
@@ -1,21 +1,30 @@
 use super::input::*;
 use super::*;
 
-use crate::client::{Message, MessageContent, MessageRole};
+use crate::client::{Message, MessageContent, MessageContentPart, MessageRole};
 use crate::render::MarkdownRender;
 
 use anyhow::{bail, Context, Result};
 use fancy_regex::Regex;
 use inquire::{validator::Validation, Confirm, Text};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::collections::HashMap;
-use std::fs::{read_to_string, write};
-use std::path::Path;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs::{read_to_string, remove_file, write};
+use std::path::{Path, PathBuf};
 use std::sync::LazyLock;
 
 static RE_AUTONAME_PREFIX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\d{8}T\d{6}-").unwrap());
 
+static RE_ATTACHMENT_HEADER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\n============ [^\n]+ ============\n").unwrap());
+static RE_BLOB_REF: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"blob:([0-9a-f]{64})").unwrap());
+
+const BLOB_URL_PREFIX: &str = "blob:";
+/// Text attachments shorter than this stay inline in the session file
+const MIN_TEXT_BLOB_LEN: usize = 1024;
+const MAX_TITLE_CHARS: usize = 60;
+
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct Session {
     #[serde(rename(serialize = "model", deserialize = "model"))]
@@ -31,13 +40,25 @@ pub struct Session {
     #[serde(skip_serializing_if = "Option::is_none")]
     use_tools: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     save_session: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     compress_threshold: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_fingerprint: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    locked: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     role_name: Option<String>,
     #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    role_variables: AgentVariables,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
     agent_variables: AgentVariables,
     #[serde(default, skip_serializing_if = "String::is_empty")]
     agent_instructions: String,
@@ -50,8 +71,9 @@ pub struct Session {
 
     #[serde(skip)]
     model: Model,
+    /// The role as loaded, so its metadata such as `guided` or `sinks` survives
     #[serde(skip)]
-    role_prompt: String,
+    role: Role,
     #[serde(skip)]
     name: String,
     #[serde(skip)]
@@ -59,12 +81,16 @@ pub struct Session {
     #[serde(skip)]
     dirty: bool,
     #[serde(skip)]
+    read_only: bool,
+    #[serde(skip)]
     save_session_this_time: bool,
     #[serde(skip)]
     compressing: bool,
     #[serde(skip)]
     autoname: Option<AutoName>,
     #[serde(skip)]
+    autoname_sessions: bool,
+    #[serde(skip)]
     tokens: usize,
 }
 
@@ -74,6 +100,7 @@ impl Session {
         let mut session = Self {
             name: name.to_string(),
             save_session: config.save_session,
+            autoname_sessions: config.autoname_sessions,
             ..Default::default()
         };
         session.set_role(role);
@@ -86,11 +113,15 @@ impl Session {
             .with_context(|| format!("Failed to load session {} at {}", name, path.display()))?;
         let mut session: Self =
             serde_yaml::from_str(&content).with_context(|| format!("Invalid session {name}"))?;
+        let blobs_dir = Config::blobs_dir();
+        restore_blobs(&mut session.messages, &blobs_dir)?;
+        restore_blobs(&mut session.compressed_messages, &blobs_dir)?;
 
         session.model = Model::retrieve_model(config, &session.model_id, ModelType::Chat)?;
 
         if let Some(autoname) = name.strip_prefix("_/") {
             session.name = TEMP_SESSION_NAME.to_string();
+            session.autoname_sessions = config.autoname_sessions;
             session.path = None;
             if let Ok(true) = RE_AUTONAME_PREFIX.is_match(autoname) {
                 session.autoname = Some(AutoName::new(autoname[16..].to_string()));
@@ -101,9 +132,9 @@ impl Session {
         }
 
         if let Some(role_name) = &session.role_name {
-            if let Ok(role) = config.retrieve_role(role_name) {
-                session.role_prompt = role.prompt().to_string();
-            }
+            session.role = config
+                .retrieve_role(role_name)
+                .unwrap_or_else(|_| Role::new(role_name, ""));
         }
 
         session.update_tokens();
@@ -127,10 +158,38 @@ impl Session {
         self.dirty
     }
 
+    /// Locked sessions stay read-only across runs, `--read-only` only for this one.
+    pub fn is_read_only(&self) -> bool {
+        self.locked || self.read_only
+    }
+
+    pub fn set_read_only(&mut self) {
+        self.read_only = true;
+    }
+
+    pub fn set_locked(&mut self, value: bool) {
+        self.locked = value;
+        if !value {
+            self.read_only = false;
+        }
+    }
+
+    pub fn guard_writable(&self) -> Result<()> {
+        if self.is_read_only() {
+            bail!("The session is read-only, run '.unlock session' to modify it");
+        }
+        Ok(())
+    }
+
     pub fn save_session(&self) -> Option<bool> {
         self.save_session
     }
 
+    /// Whether exiting saves the session without asking, as it does in CMD mode.
+    pub fn saves_on_exit(&self) -> bool {
+        !self.is_read_only() && (self.save_session == Some(true) || self.save_session_this_time)
+    }
+
     pub fn tokens(&self) -> usize {
         self.tokens
     }
@@ -147,11 +206,52 @@ impl Session {
         self.messages.iter().filter(|v| v.role.is_user()).count()
     }
 
+    /// The text of every assistant reply, oldest first.
+    pub fn assistant_replies(&self) -> Vec<String> {
+        self.messages
+            .iter()
+            .filter(|v| v.role.is_assistant())
+            .map(|v| v.content.to_text())
+            .filter(|v| !v.is_empty())
+            .collect()
+    }
+
+    /// Plain-text user questions with the assistant reply that directly followed each.
+    pub fn question_answer_pairs(&self) -> Vec<(&str, &str)> {
+        self.messages
+            .windows(2)
+            .filter_map(|pair| match (&pair[0], &pair[1]) {
+                (
+                    Message {
+                        role: MessageRole::User,
+                        content: MessageContent::Text(question),
+                    },
+                    Message {
+                        role: MessageRole::Assistant,
+                        content: MessageContent::Text(answer),
+                    },
+                ) => Some((question.as_str(), answer.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+
     pub fn export(&self) -> Result<String> {
+        let data = self.export_value();
+        let output = serde_yaml::to_string(&data)
+            .with_context(|| format!("Unable to show info about session '{}'", &self.name))?;
+        Ok(output)
+    }
+
+    pub fn export_value(&self) -> Value {
         let mut data = json!({
+            "name": self.name,
             "path": self.path,
             "model": self.model().id(),
         });
+        if let Some(role_name) = &self.role_name {
+            data["role_name"] = role_name.clone().into();
+        }
         if let Some(temperature) = self.temperature() {
             data["temperature"] = temperature.into();
         }
@@ -167,9 +267,21 @@ impl Session {
         if let Some(use_tools) = self.use_tools() {
             data["use_tools"] = use_tools.into();
         }
+        if let Some(stop) = self.stop() {
+            data["stop"] = stop.into();
+        }
         if let Some(save_session) = self.save_session() {
             data["save_session"] = save_session.into();
         }
+        if let Some(seed) = self.seed {
+            data["seed"] = seed.into();
+        }
+        if let Some(system_fingerprint) = &self.system_fingerprint {
+            data["system_fingerprint"] = system_fingerprint.clone().into();
+        }
+        if self.is_read_only() {
+            data["read_only"] = true.into();
+        }
         let (tokens, percent) = self.tokens_usage();
         data["total_tokens"] = tokens.into();
         if let Some(max_input_tokens) = self.model().max_input_tokens() {
@@ -179,10 +291,7 @@ impl Session {
             data["total/max"] = format!("{percent}%").into();
         }
         data["messages"] = json!(self.messages);
-
-        let output = serde_yaml::to_string(&data)
-            .with_context(|| format!("Unable to show info about session '{}'", &self.name))?;
-        Ok(output)
+        data
     }
 
     pub fn render(
@@ -202,6 +311,10 @@ impl Session {
 
         items.push(("model", self.model().id()));
 
+        if self.is_read_only() {
+            items.push(("read_only", "true".into()));
+        }
+
         if let Some(temperature) = self.temperature() {
             items.push(("temperature", temperature.to_string()));
         }
@@ -219,6 +332,10 @@ impl Session {
             items.push(("use_tools", use_tools));
         }
 
+        if let Some(stop) = self.stop() {
+            items.push(("stop", json!(stop).to_string()));
+        }
+
         if let Some(save_session) = self.save_session() {
             items.push(("save_session", save_session.to_string()));
         }
@@ -227,6 +344,14 @@ impl Session {
             items.push(("compress_threshold", compress_threshold.to_string()));
         }
 
+        if let Some(seed) = self.seed {
+            items.push(("seed", seed.to_string()));
+        }
+
+        if let Some(system_fingerprint) = &self.system_fingerprint {
+            items.push(("system_fingerprint", system_fingerprint.to_string()));
+        }
+
         if let Some(max_input_tokens) = self.model().max_input_tokens() {
             items.push(("max_input_tokens", max_input_tokens.to_string()));
         }
@@ -243,7 +368,7 @@ impl Session {
 
             for message in &self.messages {
                 match message.role {
-                    MessageRole::System => {
+                    MessageRole::System | MessageRole::Developer => {
                         lines.push(
                             render
                                 .render(&message.content.render_input(resolve_url_fn, agent_info)),
@@ -271,6 +396,14 @@ impl Session {
         Ok(lines.join("\n"))
     }
 
+    pub fn set_system_fingerprint(&mut self, seed: Option<u64>, value: &str) {
+        if self.seed != seed || self.system_fingerprint.as_deref() != Some(value) {
+            self.seed = seed;
+            self.system_fingerprint = Some(value.to_string());
+            self.dirty = true;
+        }
+    }
+
     pub fn tokens_usage(&self) -> (usize, f32) {
         let tokens = self.tokens();
         let max_input_tokens = self.model().max_input_tokens().unwrap_or_default();
@@ -290,23 +423,36 @@ impl Session {
         self.frequency_penalty = role.frequency_penalty();
         self.presence_penalty = role.presence_penalty();
         self.use_tools = role.use_tools();
+        self.stop = role.stop();
         self.model = role.model().clone();
         self.role_name = convert_option_string(role.name());
-        self.role_prompt = role.prompt().to_string();
+        self.role_variables = role.variable_values().clone();
+        self.role = role;
         self.dirty = true;
         self.update_tokens();
     }
 
     pub fn clear_role(&mut self) {
         self.role_name = None;
-        self.role_prompt.clear();
+        self.role = Role::default();
+        self.role_variables.clear();
+    }
+
+    pub fn set_role_variables(&mut self, variables: AgentVariables) {
+        self.role_variables = variables;
+        self.dirty = true;
     }
 
     pub fn sync_agent(&mut self, agent: &Agent) {
         self.role_name = None;
-        self.role_prompt = agent.interpolated_instructions();
+        self.agent_instructions = agent.interpolated_instructions();
+        self.role = Role::new("", &self.agent_instructions);
         self.agent_variables = agent.variables().clone();
-        self.agent_instructions = self.role_prompt.clone();
+    }
+
+    pub fn update_agent_variables(&mut self, agent: &Agent) {
+        self.sync_agent(agent);
+        self.dirty = true;
     }
 
     pub fn agent_variables(&self) -> &AgentVariables {
@@ -336,7 +482,7 @@ impl Session {
     }
 
     pub fn need_compress(&self, global_compress_threshold: usize) -> bool {
-        if self.compressing {
+        if self.compressing || self.is_read_only() {
             return false;
         }
         let threshold = self.compress_threshold.unwrap_or(global_compress_threshold);
@@ -356,7 +502,7 @@ impl Session {
 
     pub fn compress(&mut self, mut prompt: String) {
         if let Some(system_prompt) = self.messages.first().and_then(|v| {
-            if MessageRole::System == v.role {
+            if v.role.is_system() {
                 let content = v.content.to_text();
                 if !content.is_empty() {
                     return Some(content);
@@ -393,6 +539,25 @@ impl Session {
         self.autoname.as_ref().and_then(|v| v.name.as_deref())
     }
 
+    pub fn set_autoname_sessions(&mut self, value: bool) {
+        self.autoname_sessions = value;
+    }
+
+    /// The title to save the temp session as, made unique within `session_dir`.
+    fn title(&self, session_dir: &Path) -> Option<String> {
+        if !self.autoname_sessions {
+            return None;
+        }
+        let title = title_slug(self.autoname()?)?;
+        let mut name = title.clone();
+        let mut index = 2;
+        while session_dir.join(format!("{name}.yaml")).exists() {
+            name = format!("{title}-{index}");
+            index += 1;
+        }
+        Some(name)
+    }
+
     pub fn set_autoname(&mut self, value: &str) {
         let name = value
             .chars()
@@ -406,7 +571,7 @@ impl Session {
         if self.save_session_this_time {
             save_session = Some(true);
         }
-        if self.dirty && save_session != Some(false) {
+        if self.dirty && save_session != Some(false) && !self.is_read_only() {
             let mut session_dir = session_dir.to_path_buf();
             let mut session_name = self.name().to_string();
             if save_session.is_none() {
@@ -417,7 +582,9 @@ impl Session {
                 if !ans {
                     return Ok(());
                 }
-                if session_name == TEMP_SESSION_NAME {
+                if let Some(title) = self.title(&session_dir) {
+                    session_name = title;
+                } else if session_name == TEMP_SESSION_NAME {
                     session_name = Text::new("Session name:")
                         .with_validator(|input: &str| {
                             let input = input.trim();
@@ -431,6 +598,8 @@ impl Session {
                         })
                         .prompt()?;
                 }
+            } else if let (Some(true), Some(title)) = (save_session, self.title(&session_dir)) {
+                session_name = title;
             } else if save_session == Some(true) && session_name == TEMP_SESSION_NAME {
                 session_dir = session_dir.join("_");
                 ensure_parent_exists(&session_dir).with_context(|| {
@@ -454,7 +623,11 @@ impl Session {
 
         self.path = Some(session_path.display().to_string());
 
-        let content = serde_yaml::to_string(&self)
+        let mut session = self.clone();
+        let blobs_dir = Config::blobs_dir();
+        store_blobs(&mut session.messages, &blobs_dir)?;
+        store_blobs(&mut session.compressed_messages, &blobs_dir)?;
+        let content = serde_yaml::to_string(&session)
             .with_context(|| format!("Failed to serde session '{}'", self.name))?;
         write(session_path, content).with_context(|| {
             format!(
@@ -477,6 +650,40 @@ impl Session {
         Ok(())
     }
 
+    /// Saves a snapshot of the session as checkpoint `name` in `dir`, replacing an older one.
+    pub fn save_checkpoint(&self, dir: &Path, name: &str) -> Result<()> {
+        guard_checkpoint_name(name)?;
+        let mut checkpoint = self.clone();
+        checkpoint.save(&self.name, &dir.join(format!("{name}.yaml")), false)
+    }
+
+    /// Takes over the state of a checkpoint, keeping the name of the session and how and where
+    /// it is saved.
+    pub fn restore(&mut self, checkpoint: &Session) {
+        let mut session = checkpoint.clone();
+        session.name = std::mem::take(&mut self.name);
+        session.path = self.path.take();
+        session.read_only = self.read_only;
+        session.save_session_this_time = self.save_session_this_time;
+        session.autoname = self.autoname.take();
+        session.autoname_sessions = self.autoname_sessions;
+        session.dirty = true;
+        *self = session;
+    }
+
+    /// A copy of the conversation so far under a new name, writable and not saved anywhere yet.
+    pub fn fork(&self, name: &str) -> Self {
+        let mut session = self.clone();
+        session.name = name.to_string();
+        session.path = None;
+        session.locked = false;
+        session.read_only = false;
+        session.save_session_this_time = false;
+        session.autoname = None;
+        session.dirty = true;
+        session
+    }
+
     pub fn guard_empty(&self) -> Result<()> {
         if !self.is_empty() {
             bail!("Cannot perform this operation because the session has messages, please `.empty session` first.");
@@ -499,7 +706,11 @@ impl Session {
             }
         } else {
             if self.messages.is_empty() {
-                if self.name == TEMP_SESSION_NAME && self.save_session == Some(true) {
+                let autoname_sessions = self.autoname_sessions
+                    && (self.save_session != Some(false) || self.save_session_this_time);
+                if self.name == TEMP_SESSION_NAME
+                    && (self.save_session == Some(true) || autoname_sessions)
+                {
                     let raw_input = input.raw();
                     let chat_history = format!("USER: {raw_input}\nASSISTANT: {output}\n");
                     self.autoname = Some(AutoName::new_from_chat_history(chat_history));
@@ -526,6 +737,13 @@ impl Session {
         Ok(())
     }
 
+    pub fn seed_messages(&mut self, messages: Vec<Message>, data_urls: HashMap<String, String>) {
+        self.messages = messages;
+        self.data_urls = data_urls;
+        self.dirty = true;
+        self.update_tokens();
+    }
+
     pub fn clear_messages(&mut self) {
         self.messages.clear();
         self.compressed_messages.clear();
@@ -577,8 +795,8 @@ impl Session {
 
 impl RoleLike for Session {
     fn to_role(&self) -> Role {
-        let role_name = self.role_name.as_deref().unwrap_or_default();
-        let mut role = Role::new(role_name, &self.role_prompt);
+        let mut role = self.role.clone();
+        role.set_variable_values(self.role_variables.clone());
         role.sync(self);
         role
     }
@@ -607,6 +825,10 @@ impl RoleLike for Session {
         self.use_tools.clone()
     }
 
+    fn stop(&self) -> Option<Vec<String>> {
+        self.stop.clone()
+    }
+
     fn set_model(&mut self, model: Model) {
         if self.model().id() != model.id() {
             self.model_id = model.id();
@@ -650,6 +872,13 @@ impl RoleLike for Session {
             self.dirty = true;
         }
     }
+
+    fn set_stop(&mut self, value: Option<Vec<String>>) {
+        if self.stop != value {
+            self.stop = value;
+            self.dirty = true;
+        }
+    }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -676,3 +905,356 @@ impl AutoName {
         !self.naming && self.chat_history.is_some() && self.name.is_none()
     }
 }
+
+/// Turns a generated title into a lowercase file name, e.g. `Stock Market Trends` into
+/// `stock-market-trends`.
+fn title_slug(title: &str) -> Option<String> {
+    let slug: String = title
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|v| !v.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    let slug = match slug.char_indices().nth(MAX_TITLE_CHARS) {
+        Some((index, _)) => slug[..index].trim_end_matches('-').to_string(),
+        None => slug,
+    };
+    (!slug.is_empty()).then_some(slug)
+}
+
+fn attachment_urls(messages: &mut [Message]) -> impl Iterator<Item = &mut String> {
+    messages
+        .iter_mut()
+        .filter_map(|message| match &mut message.content {
+            MessageContent::Array(list) => Some(list),
+            _ => None,
+        })
+        .flatten()
+        .filter_map(|part| match part {
+            MessageContentPart::ImageUrl { image_url } => Some(&mut image_url.url),
+            _ => None,
+        })
+}
+
+/// The text parts of the messages, where text attachments are inlined.
+fn message_texts(messages: &mut [Message]) -> impl Iterator<Item = &mut String> {
+    messages
+        .iter_mut()
+        .flat_map(|message| -> Box<dyn Iterator<Item = &mut String>> {
+            match &mut message.content {
+                MessageContent::Text(text) => Box::new(std::iter::once(text)),
+                MessageContent::Array(list) => {
+                    Box::new(list.iter_mut().filter_map(|part| match part {
+                        MessageContentPart::Text { text } => Some(text),
+                        _ => None,
+                    }))
+                }
+                MessageContent::ToolCalls(_) => Box::new(std::iter::empty()),
+            }
+        })
+}
+
+/// Moves inline data urls and large text attachments into content-addressed blob files,
+/// leaving `blob:<sha256>` references.
+fn store_blobs(messages: &mut [Message], blobs_dir: &Path) -> Result<()> {
+    for url in attachment_urls(messages) {
+        if url.starts_with("data:") {
+            *url = store_blob(blobs_dir, url)?;
+        }
+    }
+    for text in message_texts(messages) {
+        *text = map_text_attachments(text, |contents| {
+            if contents.len() < MIN_TEXT_BLOB_LEN || blob_hash(contents).is_some() {
+                return Ok(None);
+            }
+            store_blob(blobs_dir, contents).map(Some)
+        })?;
+    }
+    Ok(())
+}
+
+fn restore_blobs(messages: &mut [Message], blobs_dir: &Path) -> Result<()> {
+    for url in attachment_urls(messages) {
+        if url.starts_with(BLOB_URL_PREFIX) {
+            let hash = blob_hash(url).with_context(|| format!("Invalid blob reference '{url}'"))?;
+            *url = read_blob(blobs_dir, hash)?;
+        }
+    }
+    for text in message_texts(messages) {
+        *text = map_text_attachments(text, |contents| {
+            blob_hash(contents)
+                .map(|hash| read_blob(blobs_dir, hash))
+                .transpose()
+        })?;
+    }
+    Ok(())
+}
+
+fn store_blob(blobs_dir: &Path, contents: &str) -> Result<String> {
+    let hash = sha256(contents);
+    let blob_path = blobs_dir.join(&hash);
+    if !blob_path.exists() {
+        ensure_parent_exists(&blob_path)?;
+        write(&blob_path, contents.as_bytes())
+            .with_context(|| format!("Failed to write blob '{}'", blob_path.display()))?;
+    }
+    Ok(format!("{BLOB_URL_PREFIX}{hash}"))
+}
+
+fn read_blob(blobs_dir: &Path, hash: &str) -> Result<String> {
+    let blob_path = blobs_dir.join(hash);
+    read_to_string(&blob_path)
+        .with_context(|| format!("Failed to read blob '{}'", blob_path.display()))
+}
+
+/// The sha256 of a `blob:<sha256>` reference. Anything but a hex digest is rejected, so a
+/// crafted session can't point outside the blobs dir.
+fn blob_hash(value: &str) -> Option<&str> {
+    value
+        .strip_prefix(BLOB_URL_PREFIX)
+        .filter(|v| is_blob_name(v))
+}
+
+fn is_blob_name(value: &str) -> bool {
+    value.len() == 64
+        && value
+            .bytes()
+            .all(|v| matches!(v, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Rewrites the contents of each `============ <kind>: <path> ============` attachment
+/// block in `text`, keeping those `f` returns `None` for.
+fn map_text_attachments(
+    text: &str,
+    mut f: impl FnMut(&str) -> Result<Option<String>>,
+) -> Result<String> {
+    let mut headers = vec![];
+    for ret in RE_ATTACHMENT_HEADER.find_iter(text) {
+        let m = ret?;
+        headers.push((m.start(), m.end()));
+    }
+    if headers.is_empty() {
+        return Ok(text.to_string());
+    }
+    let mut output = String::with_capacity(text.len());
+    output.push_str(&text[..headers[0].0]);
+    for (index, (start, end)) in headers.iter().enumerate() {
+        let next = headers.get(index + 1).map(|v| v.0).unwrap_or(text.len());
+        let contents = &text[*end..next];
+        output.push_str(&text[*start..*end]);
+        match f(contents)? {
+            Some(value) => output.push_str(&value),
+            None => output.push_str(contents),
+        }
+    }
+    Ok(output)
+}
+
+/// Removes blobs no session file under `session_dirs` refers to, returning how many. Nothing
+/// is removed when a session file can't be read, as its blobs may still be in use.
+pub fn prune_blobs(session_dirs: &[PathBuf], blobs_dir: &Path) -> Result<usize> {
+    let mut paths = vec![];
+    for dir in session_dirs {
+        list_session_files(dir, &mut paths)?;
+    }
+    let mut used = HashSet::new();
+    for path in paths {
+        let content = read_to_string(&path).with_context(|| {
+            format!("Failed to read '{}', not pruning the blobs", path.display())
+        })?;
+        for ret in RE_BLOB_REF.captures_iter(&content) {
+            if let Some(hash) = ret?.get(1) {
+                used.insert(hash.as_str().to_string());
+            }
+        }
+    }
+    let Ok(entries) = std::fs::read_dir(blobs_dir) else {
+        return Ok(0);
+    };
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if is_blob_name(&name) && !used.contains(&name) {
+            remove_file(entry.path())
+                .with_context(|| format!("Failed to remove blob '{}'", entry.path().display()))?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// The checkpoints saved in `dir`, oldest first.
+pub fn list_checkpoints(dir: &Path) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut checkpoints: Vec<_> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry
+                .file_name()
+                .to_string_lossy()
+                .strip_suffix(".yaml")?
+                .to_string();
+            let modified = entry.metadata().and_then(|v| v.modified()).ok()?;
+            Some((modified, name))
+        })
+        .collect();
+    checkpoints.sort();
+    checkpoints.into_iter().map(|(_, name)| name).collect()
+}
+
+/// Rejects names that would put the checkpoint file outside its dir.
+pub fn guard_checkpoint_name(name: &str) -> Result<()> {
+    let path = Path::new(name);
+    if name.is_empty() || path.file_name() != Some(path.as_os_str()) || name.contains(['/', '\\']) {
+        bail!("Invalid checkpoint name '{name}'");
+    }
+    Ok(())
+}
+
+fn list_session_files(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(v) => v,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => {
+            return Err(err).with_context(|| {
+                format!("Failed to read '{}', not pruning the blobs", dir.display())
+            })
+        }
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            list_session_files(&path, paths)?;
+        } else if path.extension().is_some_and(|v| v == "yaml") {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ImageUrl;
+
+    #[test]
+    fn test_checkpoints() {
+        let dir = temp_file("-checkpoints", "");
+        let mut session = Session {
+            name: "work".into(),
+            path: Some("work.yaml".into()),
+            ..Default::default()
+        };
+        let message =
+            |text: &str| Message::new(MessageRole::User, MessageContent::Text(text.into()));
+        session.messages.push(message("first"));
+        session.save_checkpoint(&dir, "a").unwrap();
+        session.messages.push(message("second"));
+        session.save_checkpoint(&dir, "b").unwrap();
+        assert!(session.save_checkpoint(&dir, "../a").is_err());
+        assert_eq!(list_checkpoints(&dir), ["a", "b"]);
+
+        let content = read_to_string(dir.join("a.yaml")).unwrap();
+        let checkpoint: Session = serde_yaml::from_str(&content).unwrap();
+        session.restore(&checkpoint);
+        assert_eq!(session.messages.len(), 1);
+        assert_eq!(session.name(), "work");
+        assert_eq!(session.path.as_deref(), Some("work.yaml"));
+        assert!(session.dirty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_role_roundtrip() {
+        let role = Role::new(
+            "coder",
+            r#"---
+model: openai:gpt-4o
+temperature: 0.2
+stop: ["END"]
+google_search: false
+guided_choice: ["yes", "no"]
+output_format: json
+sinks: ["notes"]
+rag: docs
+---
+You are a coder."#,
+        );
+        let mut session = Session::default();
+        session.set_role(role.clone());
+        assert_eq!(
+            serde_json::to_value(session.to_role()).unwrap(),
+            serde_json::to_value(&role).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_blobs() {
+        let blobs_dir = temp_file("-blobs", "");
+        let attachment = "fn main() {}\n".repeat(100);
+        let text = format!("explain\n\n============ file: main.rs ============\n{attachment}\n============ file: a.txt ============\nshort");
+        let image = "data:image/png;base64,AAAA";
+        let content = MessageContent::Array(vec![
+            MessageContentPart::Text { text: text.clone() },
+            MessageContentPart::ImageUrl {
+                image_url: ImageUrl { url: image.into() },
+            },
+        ]);
+        let mut messages = vec![Message::new(MessageRole::User, content)];
+        store_blobs(&mut messages, &blobs_dir).unwrap();
+        let stored = serde_json::to_string(&messages).unwrap();
+        assert!(!stored.contains("fn main"));
+        assert!(!stored.contains("base64"));
+        assert!(stored.contains("short"));
+        assert_eq!(RE_BLOB_REF.find_iter(&stored).count(), 2);
+        restore_blobs(&mut messages, &blobs_dir).unwrap();
+        let MessageContent::Array(parts) = &messages[0].content else {
+            panic!("Expected an array");
+        };
+        assert!(matches!(&parts[0], MessageContentPart::Text { text: v } if v == &text));
+        assert!(
+            matches!(&parts[1], MessageContentPart::ImageUrl { image_url } if image_url.url == image)
+        );
+
+        let session_dir = temp_file("-blob-sessions", "");
+        std::fs::create_dir_all(&session_dir).unwrap();
+        std::fs::write(session_dir.join("a.yaml"), stored).unwrap();
+        write(blobs_dir.join("f".repeat(64)), "unused").unwrap();
+        write(blobs_dir.join("notes.txt"), "not a blob").unwrap();
+        assert_eq!(
+            prune_blobs(std::slice::from_ref(&session_dir), &blobs_dir).unwrap(),
+            1
+        );
+        assert_eq!(std::fs::read_dir(&blobs_dir).unwrap().count(), 3);
+        let _ = std::fs::remove_dir_all(&blobs_dir);
+        let _ = std::fs::remove_dir_all(&session_dir);
+    }
+
+    #[test]
+    fn test_restore_blobs_rejects_paths() {
+        let blobs_dir = temp_file("-blobs-paths", "");
+        let url = "blob:../../config.yaml";
+        let content = MessageContent::Array(vec![MessageContentPart::ImageUrl {
+            image_url: ImageUrl { url: url.into() },
+        }]);
+        let mut messages = vec![Message::new(MessageRole::User, content)];
+        assert!(restore_blobs(&mut messages, &blobs_dir).is_err());
+        assert_eq!(blob_hash(&format!("blob:{}", "A".repeat(64))), None);
+    }
+
+    #[test]
+    fn test_title_slug() {
+        assert_eq!(
+            title_slug("Stock Market Trends\n").as_deref(),
+            Some("stock-market-trends")
+        );
+        assert_eq!(
+            title_slug("\"perfect--chocolate chip recipe\"").as_deref(),
+            Some("perfect-chocolate-chip-recipe")
+        );
+        assert_eq!(title_slug(" -- ").as_deref(), None);
+        assert_eq!(title_slug(&"ab-".repeat(40)).unwrap().len(), 59);
+    }
+}
@@ -0,0 +1,217 @@
+use super::*;
+
+use indexmap::IndexSet;
+use std::process::{Command, Stdio};
+
+/// Somewhere the final reply goes besides stdout, named under `output_sinks` and picked by a
+/// role's `sinks` or `--sink`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum OutputSink {
+    /// Writes the reply to a file whose path may use `{{date}}`, `{{time}}`, `{{role}}`,
+    /// `{{session}}` and `{{model}}`
+    File {
+        path: String,
+        #[serde(default = "default_append")]
+        append: bool,
+    },
+    Clipboard,
+    /// POSTs `{role, model, session, input, output}` as JSON
+    Webhook {
+        url: String,
+        #[serde(default)]
+        headers: IndexMap<String, String>,
+    },
+    /// Runs a shell command with the reply on stdin
+    Command {
+        command: String,
+    },
+}
+
+fn default_append() -> bool {
+    true
+}
+
+/// What the reply was an answer to, for sink templates and webhook payloads.
+#[derive(Debug, Clone, Default)]
+pub struct SinkContext {
+    pub role: String,
+    pub model: String,
+    pub session: Option<String>,
+    pub input: String,
+}
+
+impl Config {
+    /// Sends the reply to the sinks of the role and of `--sink`, returning what failed so a
+    /// broken sink doesn't cost the reply.
+    pub async fn send_to_sinks(config: &GlobalConfig, input: &Input, output: &str) -> Vec<String> {
+        let (sinks, context) = {
+            let config = config.read();
+            if config.dry_run || output.is_empty() {
+                return vec![];
+            }
+            let mut names: IndexSet<&str> =
+                input.role().sinks().iter().map(|v| v.as_str()).collect();
+            names.extend(config.cli_sinks.iter().map(|v| v.as_str()));
+            let sinks: Vec<(String, Option<OutputSink>)> = names
+                .into_iter()
+                .map(|name| (name.to_string(), config.output_sinks.get(name).cloned()))
+                .collect();
+            let role = match input.role().name() {
+                "" => TEMP_ROLE_NAME.to_string(),
+                name => name.to_string(),
+            };
+            let context = SinkContext {
+                role,
+                model: input.role().model().id(),
+                session: input.session(&config.session).map(|v| v.name().to_string()),
+                input: input.text(),
+            };
+            (sinks, context)
+        };
+        let mut failures = vec![];
+        for (name, sink) in sinks {
+            let ret = match sink {
+                Some(sink) => sink.send(output, &context).await,
+                None => Err(anyhow!("Unknown output sink")),
+            };
+            if let Err(err) = ret {
+                failures.push(format!(
+                    "Failed to send the reply to sink '{name}': {err:#}"
+                ));
+            }
+        }
+        failures
+    }
+
+    pub fn warn_sink_failures(failures: Vec<String>) {
+        for failure in failures {
+            eprintln!("{}", warning_text(&format!("⚠️ {failure}")));
+        }
+    }
+}
+
+impl OutputSink {
+    pub async fn send(&self, output: &str, context: &SinkContext) -> Result<()> {
+        match self {
+            OutputSink::File { path, append } => {
+                let path = PathBuf::from(resolve_home_dir(&render_sink_path(path, context)));
+                ensure_parent_exists(&path)?;
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .append(*append)
+                    .truncate(!*append)
+                    .open(&path)
+                    .with_context(|| format!("Failed to open '{}'", path.display()))?;
+                let separator = match *append && file.metadata()?.len() > 0 {
+                    true => "\n",
+                    false => "",
+                };
+                writeln!(file, "{separator}{}", output.trim_end())
+                    .with_context(|| format!("Failed to write to '{}'", path.display()))?;
+            }
+            OutputSink::Clipboard => set_text(output)?,
+            OutputSink::Webhook { url, headers } => {
+                let builder = reqwest::Client::builder().timeout(Duration::from_secs(30));
+                let client = apply_proxy(builder, None)?
+                    .build()
+                    .context("Failed to create HTTP client")?;
+                let mut request = client.post(url).json(&json!({
+                    "role": context.role,
+                    "model": context.model,
+                    "session": context.session,
+                    "input": context.input,
+                    "output": output,
+                }));
+                for (key, value) in headers {
+                    request = request.header(key, value);
+                }
+                let response = request.send().await?;
+                let status = response.status();
+                if !status.is_success() {
+                    bail!("{url} responded with {status}");
+                }
+            }
+            OutputSink::Command { command } => {
+                let mut child = Command::new(&SHELL.cmd)
+                    .args([&SHELL.arg, command])
+                    .stdin(Stdio::piped())
+                    .spawn()
+                    .with_context(|| format!("Failed to run `{command}`"))?;
+                if let Some(mut stdin) = child.stdin.take() {
+                    // The command may exit without reading all of it
+                    let _ = stdin.write_all(output.as_bytes());
+                }
+                let status = child.wait()?;
+                if !status.success() {
+                    bail!(
+                        "`{command}` exited with code {}",
+                        status.code().unwrap_or_default()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fills in the variables of a file sink's path. Names that end up in the path have their
+/// path separators replaced, e.g. the `/` of a model id.
+pub fn render_sink_path(path: &str, context: &SinkContext) -> String {
+    let now = chrono::Local::now();
+    RE_VARIABLE
+        .replace_all(path, |caps: &fancy_regex::Captures<'_>| {
+            let value = match &caps[1] {
+                "date" => now.format("%Y-%m-%d").to_string(),
+                "time" => now.format("%H%M%S").to_string(),
+                "role" => context.role.clone(),
+                "model" => context.model.clone(),
+                "session" => context
+                    .session
+                    .clone()
+                    .unwrap_or_else(|| TEMP_SESSION_NAME.into()),
+                _ => return caps[0].to_string(),
+            };
+            value.replace(['/', '\\'], "_")
+        })
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_sink_path() {
+        let context = SinkContext {
+            role: "coder".into(),
+            model: "openrouter:meta/llama-3".into(),
+            session: None,
+            input: "hi".into(),
+        };
+        let date = chrono::Local::now().format("%Y-%m-%d").to_string();
+        assert_eq!(
+            render_sink_path("~/notes/{{date}}-{{role}}-{{session}}.md", &context),
+            format!("~/notes/{date}-coder-temp.md")
+        );
+        assert_eq!(
+            render_sink_path("{{model}}/{{other}}.md", &context),
+            "openrouter:meta_llama-3/{{other}}.md"
+        );
+    }
+
+    #[test]
+    fn test_output_sink_config() {
+        let sinks: IndexMap<String, OutputSink> = serde_yaml::from_str(
+            "notes:\n  type: file\n  path: notes.md\nclip:\n  type: clipboard\nspeak:\n  type: command\n  command: say",
+        )
+        .unwrap();
+        assert!(matches!(
+            sinks["notes"],
+            OutputSink::File { append: true, .. }
+        ));
+        assert!(matches!(sinks["clip"], OutputSink::Clipboard));
+        assert!(serde_yaml::from_str::<OutputSink>("type: webhook").is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use super::*;
+
+use crate::client::{Message, MessageContent, MessageRole};
+
+/// A reusable conversation scaffold. Unlike a role, which only supplies a system prompt,
+/// a template seeds a new session with turns and attachments.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ConversationTemplate {
+    pub model: Option<String>,
+    #[serde(default)]
+    pub prompt: String,
+    /// How replies should be laid out, appended to the system prompt
+    pub output_format: Option<String>,
+    /// Pre-seeded user/assistant turns
+    #[serde(default)]
+    pub messages: Vec<Message>,
+    /// Files, URLs or commands that must load, attached to the first user turn
+    #[serde(default)]
+    pub attachments: Vec<String>,
+}
+
+impl ConversationTemplate {
+    pub fn validate(&self, name: &str) -> Result<()> {
+        for message in &self.messages {
+            if !message.role.is_user() && !message.role.is_assistant() {
+                bail!("Template '{name}' can only seed user and assistant turns");
+            }
+        }
+        Ok(())
+    }
+
+    pub fn system_prompt(&self) -> String {
+        let prompt = self.prompt.trim();
+        match self.output_format.as_deref().map(|v| v.trim()) {
+            Some(format) if !format.is_empty() => {
+                format!("{prompt}\n\nFormat your replies as follows:\n{format}")
+                    .trim_start()
+                    .to_string()
+            }
+            _ => prompt.to_string(),
+        }
+    }
+
+    /// Builds the session history: the system prompt followed by the seeded turns, with the
+    /// attachments merged into the first user turn or, if there is none, forming one.
+    pub async fn build_messages(
+        &self,
+        config: &GlobalConfig,
+        name: &str,
+        abort_signal: AbortSignal,
+    ) -> Result<(Vec<Message>, HashMap<String, String>)> {
+        let mut messages = vec![];
+        let system_prompt = self.system_prompt();
+        if !system_prompt.is_empty() {
+            messages.push(Message::new(
+                MessageRole::System,
+                MessageContent::Text(system_prompt),
+            ));
+        }
+        messages.extend(self.messages.iter().cloned());
+        let mut data_urls = HashMap::new();
+        if !self.attachments.is_empty() {
+            let index = messages.iter().position(|v| v.role.is_user());
+            let text = match index.map(|i| &messages[i].content) {
+                Some(MessageContent::Text(text)) => text.clone(),
+                Some(_) => bail!("Template '{name}' must seed its first user turn as plain text"),
+                None => String::new(),
+            };
+            let input = Input::from_files_with_spinner(
+                config,
+                &text,
+                self.attachments.clone(),
+                None,
+                abort_signal,
+            )
+            .await
+            .with_context(|| format!("Failed to load the attachments of template '{name}'"))?;
+            let content = input.message_content();
+            match index {
+                Some(i) => messages[i].content = content,
+                None => messages.push(Message::new(MessageRole::User, content)),
+            }
+            data_urls = input.data_urls();
+        }
+        Ok((messages, data_urls))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversation_template() {
+        let template: ConversationTemplate = serde_yaml::from_str(
+            "prompt: Run the review.\noutput_format: A table\nmessages:\n  - role: user\n    content: Ready?\n  - role: assistant\n    content: Yes.\n",
+        )
+        .unwrap();
+        assert!(template.validate("review").is_ok());
+        assert_eq!(
+            template.system_prompt(),
+            "Run the review.\n\nFormat your replies as follows:\nA table"
+        );
+        let template: ConversationTemplate =
+            serde_yaml::from_str("messages:\n  - role: system\n    content: x\n").unwrap();
+        assert!(template.validate("bad").is_err());
+    }
+}
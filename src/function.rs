@@ -1,5 +1,6 @@
 use crate::{
-    config::{Agent, Config, GlobalConfig},
+    client::call_chat_completions,
+    config::{ensure_parent_exists, Agent, Config, GlobalConfig, Input, Role, RoleLike},
     utils::*,
 };
 
@@ -11,6 +12,7 @@ use std::{
     collections::{HashMap, HashSet},
     fs,
     path::{Path, PathBuf},
+    time::Instant,
 };
 
 #[cfg(windows)]
@@ -18,7 +20,33 @@ const PATH_SEP: &str = ";";
 #[cfg(not(windows))]
 const PATH_SEP: &str = ":";
 
-pub fn eval_tool_calls(config: &GlobalConfig, mut calls: Vec<ToolCall>) -> Result<Vec<ToolResult>> {
+/// Built-in tool that hands a task to a sub-conversation, offered when `use_tools` names it.
+pub const DELEGATE_FUNCTION_NAME: &str = "delegate";
+const DELEGATE_MAX_STEPS: usize = 16;
+const DELEGATE_PROMPT: &str = r#"You are a sub-agent working on a single task delegated to you, starting from a fresh context.
+Use the available tools as needed. When the task is done, reply with a concise summary of what you did and found;
+the summary is all the caller will see, so include every detail it needs."#;
+
+/// Built-in tool for notes the model keeps in a markdown file beside the session, offered when
+/// `use_tools` names it. The file outlives session compression and is there for the user to review.
+pub const SCRATCHPAD_FUNCTION_NAME: &str = "scratchpad";
+
+/// Built-in tools answered in-process, offered to models that support function calling unless
+/// `builtin_tools` leaves them out, so arithmetic, dates and units aren't guessed.
+pub const CALCULATE_FUNCTION_NAME: &str = "calculate";
+pub const CURRENT_DATETIME_FUNCTION_NAME: &str = "current_datetime";
+pub const UNIT_CONVERT_FUNCTION_NAME: &str = "unit_convert";
+pub const BUILTIN_TOOLS: [&str; 3] = [
+    CALCULATE_FUNCTION_NAME,
+    CURRENT_DATETIME_FUNCTION_NAME,
+    UNIT_CONVERT_FUNCTION_NAME,
+];
+
+pub async fn eval_tool_calls(
+    config: &GlobalConfig,
+    mut calls: Vec<ToolCall>,
+    abort_signal: AbortSignal,
+) -> Result<Vec<ToolResult>> {
     let mut output = vec![];
     if calls.is_empty() {
         return Ok(output);
@@ -28,8 +56,27 @@ pub fn eval_tool_calls(config: &GlobalConfig, mut calls: Vec<ToolCall>) -> Resul
         bail!("The request was aborted because an infinite loop of function calls was detected.")
     }
     let mut is_all_null = true;
+    let report_step = config
+        .read()
+        .run_report
+        .as_ref()
+        .and_then(|v| v.current_step());
     for call in calls {
-        let mut result = call.eval(config)?;
+        let started = Instant::now();
+        let ret = if call.name == DELEGATE_FUNCTION_NAME {
+            Box::pin(call.delegate(config, abort_signal.clone())).await
+        } else if call.name == SCRATCHPAD_FUNCTION_NAME {
+            call.scratchpad(config)
+        } else if BUILTIN_TOOLS.contains(&call.name.as_str()) {
+            call.builtin()
+        } else {
+            call.eval(config)
+        };
+        if let Some(report) = config.write().run_report.as_mut() {
+            let error = ret.as_ref().err().map(|err| format!("{err:#}"));
+            report.add_tool_call(report_step, &call, started.elapsed().as_millis(), error);
+        }
+        let mut result = ret?;
         if result.is_null() {
             result = json!("DONE");
         } else {
@@ -124,6 +171,121 @@ pub struct JsonSchema {
     pub required: Option<Vec<String>>,
 }
 
+impl FunctionDeclaration {
+    pub fn delegate() -> Self {
+        let parameters = json!({
+            "type": "object",
+            "properties": {
+                "task": {
+                    "type": "string",
+                    "description": "The self-contained task for the sub-agent"
+                },
+                "context": {
+                    "type": "string",
+                    "description": "Facts from this conversation the sub-agent needs"
+                },
+                "tools": {
+                    "type": "string",
+                    "description": "Comma-separated names of the tools the sub-agent may use, defaults to all of yours"
+                }
+            },
+            "required": ["task"]
+        });
+        Self::new_builtin(
+            DELEGATE_FUNCTION_NAME,
+            "Hand a self-contained task to a sub-agent with a fresh context and get back only its summary. Use it for work that needs many tool calls whose raw output you don't need.",
+            parameters,
+        )
+    }
+
+    pub fn scratchpad() -> Self {
+        let parameters = json!({
+            "type": "object",
+            "properties": {
+                "action": {
+                    "type": "string",
+                    "enum": ["read", "append", "write"],
+                    "description": "read the notes, append to them, or replace them"
+                },
+                "content": {
+                    "type": "string",
+                    "description": "Markdown to append or write"
+                }
+            },
+            "required": ["action"]
+        });
+        Self::new_builtin(
+            SCRATCHPAD_FUNCTION_NAME,
+            "Keep notes and plans in a markdown scratchpad for this conversation. It persists when earlier messages are compressed, so read it back before continuing a long task.",
+            parameters,
+        )
+    }
+
+    pub fn builtin(name: &str) -> Option<Self> {
+        let (description, parameters) = match name {
+            CALCULATE_FUNCTION_NAME => (
+                "Evaluate an arithmetic expression exactly. Use it for any calculation instead of doing math in your head.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "expression": {
+                            "type": "string",
+                            "description": "e.g. '(3.5 + 2) * 4^2 / sqrt(2)'; supports + - * / % ^, parentheses, pi, e, sqrt, abs, ln, log, log2, exp, sin, cos, tan, floor, ceil, round, min, max, pow"
+                        }
+                    },
+                    "required": ["expression"]
+                }),
+            ),
+            CURRENT_DATETIME_FUNCTION_NAME => (
+                "Get the current date, time and weekday, optionally shifted by a number of days. Use it for questions about today or relative dates.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "timezone": {
+                            "type": "string",
+                            "description": "'local' (default), 'UTC' or an offset like '+05:30'"
+                        },
+                        "offset_days": {
+                            "type": "integer",
+                            "description": "Days to add, negative for the past"
+                        }
+                    }
+                }),
+            ),
+            UNIT_CONVERT_FUNCTION_NAME => (
+                "Convert a value between units of length, mass, time, volume, area, speed, data, energy, pressure or temperature.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "value": {
+                            "type": "number"
+                        },
+                        "from": {
+                            "type": "string",
+                            "description": "e.g. 'mi', 'kg', 'F', 'GiB', 'km/h'"
+                        },
+                        "to": {
+                            "type": "string"
+                        }
+                    },
+                    "required": ["value", "from", "to"]
+                }),
+            ),
+            _ => return None,
+        };
+        Some(Self::new_builtin(name, description, parameters))
+    }
+
+    fn new_builtin(name: &str, description: &str, parameters: Value) -> Self {
+        Self {
+            name: name.into(),
+            description: description.into(),
+            parameters: serde_json::from_value(parameters).expect("valid schema"),
+            agent: false,
+        }
+    }
+}
+
 impl JsonSchema {
     pub fn is_empty_properties(&self) -> bool {
         match &self.properties {
@@ -138,6 +300,10 @@ pub struct ToolCall {
     pub name: String,
     pub arguments: Value,
     pub id: Option<String>,
+    /// Signed reasoning blocks that preceded this call, which Claude requires to be sent
+    /// back ahead of the `tool_use` block.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub thinking: Vec<Value>,
 }
 
 type CallConfig = (String, String, Vec<String>, HashMap<String, String>);
@@ -167,29 +333,170 @@ impl ToolCall {
             name,
             arguments,
             id,
+            thinking: vec![],
         }
     }
 
-    pub fn eval(&self, config: &GlobalConfig) -> Result<Value> {
-        let (call_name, cmd_name, mut cmd_args, envs) = match &config.read().agent {
-            Some(agent) => self.extract_call_config_from_agent(config, agent)?,
-            None => self.extract_call_config_from_config(config)?,
+    pub fn with_thinking(mut self, thinking: Vec<Value>) -> Self {
+        self.thinking = thinking;
+        self
+    }
+
+    /// Runs the `delegate` tool: a sub-conversation with its own context and a subset of the
+    /// caller's tools, of which only the final summary goes back to the caller. Aborting the
+    /// caller's turn aborts the sub-agent too.
+    async fn delegate(&self, config: &GlobalConfig, abort_signal: AbortSignal) -> Result<Value> {
+        let arguments = self.parse_arguments(DELEGATE_FUNCTION_NAME)?;
+        let Some(task) = arguments["task"].as_str() else {
+            bail!("The call '{DELEGATE_FUNCTION_NAME}' misses the 'task' argument");
+        };
+        let parent_role = config.read().extract_role();
+        let mut functions = config.read().select_functions(&parent_role);
+        if parent_role.model().data().supports_function_calling {
+            config.read().append_builtin_functions(&mut functions);
+        }
+        let functions =
+            delegate_functions(functions.unwrap_or_default(), arguments["tools"].as_str());
+
+        let mut role = Role::new(DELEGATE_FUNCTION_NAME, DELEGATE_PROMPT);
+        role.set_model(parent_role.model().clone());
+        let text = match arguments["context"].as_str() {
+            Some(context) => format!("{task}\n\nContext:\n{context}"),
+            None => task.to_string(),
         };
+        if *IS_STDOUT_TERMINAL {
+            println!("{}", dimmed_text(&format!("Delegate {task}")));
+        }
+
+        let mut input = Input::from_str(config, &text, Some(role));
+        input.set_functions(functions);
+        for _ in 0..DELEGATE_MAX_STEPS {
+            let client = input.create_client()?;
+            let (output, tool_results) =
+                call_chat_completions(&input, false, false, client.as_ref(), abort_signal.clone())
+                    .await?;
+            if tool_results.is_empty() {
+                return Ok(json!({ "summary": output }));
+            }
+            input = input.merge_tool_results(output, tool_results);
+        }
+        bail!("The delegated task didn't finish within {DELEGATE_MAX_STEPS} steps")
+    }
 
-        let json_data = if self.arguments.is_object() {
-            self.arguments.clone()
+    /// Runs the `scratchpad` tool against the file of the current session, or against notes
+    /// kept in memory without one.
+    fn scratchpad(&self, config: &GlobalConfig) -> Result<Value> {
+        let arguments = self.parse_arguments(SCRATCHPAD_FUNCTION_NAME)?;
+        let path = config.read().scratchpad_file();
+        let read = || match &path {
+            Some(path) => fs::read_to_string(path).unwrap_or_default(),
+            None => config.read().scratchpad.clone(),
+        };
+        let action = arguments["action"].as_str().unwrap_or_default();
+        if action == "read" {
+            return Ok(json!({ "content": read() }));
+        }
+        if !matches!(action, "append" | "write") {
+            bail!("The call '{SCRATCHPAD_FUNCTION_NAME}' needs an action of read, append or write");
+        }
+        let Some(content) = arguments["content"].as_str() else {
+            bail!("The call '{SCRATCHPAD_FUNCTION_NAME}' misses the 'content' argument");
+        };
+        if let Some(session) = &config.read().session {
+            session.guard_writable()?;
+        }
+        let mut text = match action {
+            "append" => read(),
+            _ => String::new(),
+        };
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        text.push_str(content);
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        let Some(path) = path else {
+            let length = text.len();
+            config.write().scratchpad = text;
+            return Ok(json!({ "length": length }));
+        };
+        ensure_parent_exists(&path)?;
+        fs::write(&path, &text)
+            .with_context(|| format!("Failed to write the scratchpad '{}'", path.display()))?;
+        debug!("Wrote the scratchpad '{}'", path.display());
+        Ok(json!({ "path": path.display().to_string(), "length": text.len() }))
+    }
+
+    /// Runs one of the `BUILTIN_TOOLS`.
+    fn builtin(&self) -> Result<Value> {
+        let name = self.name.as_str();
+        let arguments = self.parse_arguments(name)?;
+        let string_arg = |key: &str| {
+            arguments[key]
+                .as_str()
+                .ok_or_else(|| anyhow!("The call '{name}' misses the '{key}' argument"))
+        };
+        match name {
+            CALCULATE_FUNCTION_NAME => {
+                let expression = string_arg("expression")?;
+                let result = evaluate_expression(expression)?;
+                Ok(json!({ "expression": expression, "result": format_number(result) }))
+            }
+            CURRENT_DATETIME_FUNCTION_NAME => {
+                let timezone = arguments["timezone"].as_str().unwrap_or("local");
+                let offset = match timezone.to_lowercase().as_str() {
+                    "local" | "" => *chrono::Local::now().offset(),
+                    "utc" | "z" | "gmt" => chrono::FixedOffset::east_opt(0).expect("valid offset"),
+                    _ => parse_utc_offset(timezone)?,
+                };
+                let days = arguments["offset_days"].as_i64().unwrap_or_default();
+                let now = chrono::Utc::now().with_timezone(&offset) + chrono::Duration::days(days);
+                Ok(json!({
+                    "datetime": now.to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+                    "date": now.format("%Y-%m-%d").to_string(),
+                    "time": now.format("%H:%M:%S").to_string(),
+                    "weekday": now.format("%A").to_string(),
+                    "utc_offset": now.format("%:z").to_string(),
+                }))
+            }
+            UNIT_CONVERT_FUNCTION_NAME => {
+                let Some(value) = arguments["value"]
+                    .as_f64()
+                    .or_else(|| arguments["value"].as_str().and_then(|v| v.parse().ok()))
+                else {
+                    bail!("The call '{name}' misses the 'value' argument");
+                };
+                let (from, to) = (string_arg("from")?, string_arg("to")?);
+                let result = convert_unit(value, from, to)?;
+                Ok(json!({ "result": format_number(result), "unit": to }))
+            }
+            _ => bail!("Unexpected call: {name} {}", self.arguments),
+        }
+    }
+
+    fn parse_arguments(&self, call_name: &str) -> Result<Value> {
+        if self.arguments.is_object() {
+            Ok(self.arguments.clone())
         } else if let Some(arguments) = self.arguments.as_str() {
-            let arguments: Value = serde_json::from_str(arguments).map_err(|_| {
-                anyhow!("The call '{call_name}' has invalid arguments: {arguments}")
-            })?;
-            arguments
+            serde_json::from_str(arguments)
+                .map_err(|_| anyhow!("The call '{call_name}' has invalid arguments: {arguments}"))
         } else {
             bail!(
                 "The call '{call_name}' has invalid arguments: {}",
                 self.arguments
             );
+        }
+    }
+
+    pub fn eval(&self, config: &GlobalConfig) -> Result<Value> {
+        let (call_name, cmd_name, mut cmd_args, envs) = match &config.read().agent {
+            Some(agent) => self.extract_call_config_from_agent(config, agent)?,
+            None => self.extract_call_config_from_config(config)?,
         };
 
+        let json_data = self.parse_arguments(&call_name)?;
+
         cmd_args.push(json_data.to_string());
 
         let output = match run_llm_function(cmd_name, cmd_args, envs)? {
@@ -292,6 +599,46 @@ pub fn run_llm_function(
     Ok(output)
 }
 
+/// Picks the caller's tools a delegated sub-agent may use, never `delegate` itself.
+fn delegate_functions(
+    functions: Vec<FunctionDeclaration>,
+    allowed: Option<&str>,
+) -> Vec<FunctionDeclaration> {
+    let allowed: Option<Vec<&str>> = allowed.map(|v| v.split(',').map(|v| v.trim()).collect());
+    functions
+        .into_iter()
+        .filter(|v| v.name != DELEGATE_FUNCTION_NAME)
+        .filter(|v| match &allowed {
+            Some(allowed) => allowed.contains(&v.name.as_str()),
+            None => true,
+        })
+        .collect()
+}
+
+/// Parses a UTC offset such as `+05:30`, `-0800` or `UTC+2`.
+fn parse_utc_offset(value: &str) -> Result<chrono::FixedOffset> {
+    let err =
+        || anyhow!("Invalid timezone '{value}', use 'local', 'UTC' or an offset like '+05:30'");
+    let text = value.trim();
+    let text = text
+        .strip_prefix("UTC")
+        .or_else(|| text.strip_prefix("GMT"))
+        .unwrap_or(text);
+    let (sign, digits) = match text.chars().next() {
+        Some('+') => (1, &text[1..]),
+        Some('-') => (-1, &text[1..]),
+        _ => return Err(err()),
+    };
+    let (hours, minutes) = match digits.split_once(':') {
+        Some((h, m)) => (h, m),
+        None if digits.len() > 2 => digits.split_at(digits.len() - 2),
+        None => (digits, "0"),
+    };
+    let hours: i32 = hours.parse().map_err(|_| err())?;
+    let minutes: i32 = minutes.parse().map_err(|_| err())?;
+    chrono::FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).ok_or_else(err)
+}
+
 #[cfg(windows)]
 fn polyfill_cmd_name<T: AsRef<Path>>(cmd_name: &str, bin_dir: &[T]) -> String {
     let cmd_name = cmd_name.to_string();
@@ -307,3 +654,95 @@ fn polyfill_cmd_name<T: AsRef<Path>>(cmd_name: &str, bin_dir: &[T]) -> String {
     }
     cmd_name
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Session;
+
+    use parking_lot::RwLock;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_builtin_declarations() {
+        for name in [
+            CALCULATE_FUNCTION_NAME,
+            CURRENT_DATETIME_FUNCTION_NAME,
+            UNIT_CONVERT_FUNCTION_NAME,
+        ] {
+            let declaration = FunctionDeclaration::builtin(name).unwrap();
+            assert_eq!(declaration.name, name);
+            assert!(!declaration.agent);
+        }
+        assert!(FunctionDeclaration::builtin("unknown").is_none());
+        assert_eq!(FunctionDeclaration::delegate().name, DELEGATE_FUNCTION_NAME);
+        assert_eq!(
+            FunctionDeclaration::scratchpad().parameters.required,
+            Some(vec!["action".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_delegate_functions() {
+        let functions = vec![
+            FunctionDeclaration::delegate(),
+            FunctionDeclaration::scratchpad(),
+            FunctionDeclaration::builtin(CALCULATE_FUNCTION_NAME).unwrap(),
+            FunctionDeclaration {
+                agent: true,
+                ..FunctionDeclaration::builtin(UNIT_CONVERT_FUNCTION_NAME).unwrap()
+            },
+        ];
+        let names = |functions: Vec<FunctionDeclaration>| {
+            functions.into_iter().map(|v| v.name).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(delegate_functions(functions.clone(), None)),
+            [
+                SCRATCHPAD_FUNCTION_NAME,
+                CALCULATE_FUNCTION_NAME,
+                UNIT_CONVERT_FUNCTION_NAME
+            ]
+        );
+        assert_eq!(
+            names(delegate_functions(
+                functions.clone(),
+                Some(&format!(
+                    "{CALCULATE_FUNCTION_NAME}, {DELEGATE_FUNCTION_NAME}"
+                ))
+            )),
+            [CALCULATE_FUNCTION_NAME]
+        );
+        assert!(delegate_functions(functions, Some("")).is_empty());
+    }
+
+    #[test]
+    fn test_scratchpad_without_session() {
+        let config: GlobalConfig = Arc::new(RwLock::new(Config::default()));
+        assert!(config.read().scratchpad_file().is_none());
+        let call = |arguments: Value| {
+            ToolCall::new(SCRATCHPAD_FUNCTION_NAME.into(), arguments, None).scratchpad(&config)
+        };
+        call(json!({ "action": "write", "content": "- plan" })).unwrap();
+        call(json!({ "action": "append", "content": "- step" })).unwrap();
+        let output = call(json!({ "action": "read" })).unwrap();
+        assert_eq!(output["content"], "- plan\n- step\n");
+        assert!(call(json!({ "action": "append" })).is_err());
+    }
+
+    #[test]
+    fn test_scratchpad_read_only_session() {
+        let config: GlobalConfig = Arc::new(RwLock::new(Config::default()));
+        let mut session = Session::new(&config.read(), "scratchpad-read-only");
+        session.set_read_only();
+        config.write().session = Some(session);
+        let path = config.read().scratchpad_file().unwrap();
+        let call = |arguments: Value| {
+            ToolCall::new(SCRATCHPAD_FUNCTION_NAME.into(), arguments, None).scratchpad(&config)
+        };
+        let err = call(json!({ "action": "write", "content": "- plan" })).unwrap_err();
+        assert!(err.to_string().contains("read-only"));
+        assert!(!path.exists());
+        assert_eq!(call(json!({ "action": "read" })).unwrap()["content"], "");
+    }
+}
@@ -0,0 +1,200 @@
+use crate::config::{Config, GlobalConfig, Input, WorkingMode, COMMIT_MESSAGE_ROLE};
+use crate::utils::{get_env_name, run_command_with_output};
+
+use anyhow::{bail, Context, Result};
+use parking_lot::RwLock;
+use std::{
+    env, fs,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// The staged diff is cut at this many characters to keep the request small.
+const MAX_DIFF_CHARS: usize = 30_000;
+/// Git drops this line and everything below it, like the diff of `git commit -v`.
+const SCISSORS: &str = " ------------------------ >8 ------------------------";
+
+/// Git hooks `--hook` can run as.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GitHook {
+    /// Drafts a message for `git commit` without `-m`
+    PrepareCommitMsg,
+    /// Polishes the message the user wrote
+    CommitMsg,
+}
+
+impl GitHook {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "prepare-commit-msg" => Ok(Self::PrepareCommitMsg),
+            "commit-msg" => Ok(Self::CommitMsg),
+            _ => bail!("Unsupported hook '{value}', expected prepare-commit-msg or commit-msg"),
+        }
+    }
+}
+
+/// Runs a git hook non-interactively. Anything short of a usable reply from the model, like a
+/// missing config, no network or the `hook_timeout` running out, leaves the message untouched.
+pub async fn run_git_hook(hook: GitHook, args: &[String], model_id: Option<&str>) -> Result<()> {
+    let Some(path) = args.first() else {
+        bail!("The hook needs the commit message file git passes as its first argument");
+    };
+    let source = args.get(1).map(|v| v.as_str());
+    if let Err(err) = write_commit_message(hook, path, source, model_id).await {
+        debug!("Skipped the git hook: {err:#}");
+    }
+    Ok(())
+}
+
+async fn write_commit_message(
+    hook: GitHook,
+    path: &str,
+    source: Option<&str>,
+    model_id: Option<&str>,
+) -> Result<()> {
+    // A message given with -m/-F, a merge, a squash or an amend already has its message
+    if hook == GitHook::PrepareCommitMsg && !matches!(source, None | Some("template")) {
+        return Ok(());
+    }
+    let content = fs::read_to_string(path).with_context(|| format!("Failed to read '{path}'"))?;
+    let (draft, comments) = split_comments(&content, &comment_prefix());
+    if hook == GitHook::CommitMsg && draft.is_empty() {
+        return Ok(());
+    }
+    let diff = staged_diff()?;
+    if diff.is_empty() {
+        return Ok(());
+    }
+    // Creating a missing config file would prompt, which a hook must never do
+    if !Config::config_file().exists() && env::var(get_env_name("provider")).is_err() {
+        bail!("No config file");
+    }
+    // The config's own `hook_timeout` isn't known before it loads, which may fetch models
+    let started = Instant::now();
+    let init_timeout = env::var(get_env_name("hook_timeout"))
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(Config::default().hook_timeout);
+    let config = tokio::time::timeout(
+        Duration::from_secs(init_timeout),
+        Config::init(WorkingMode::Cmd, false),
+    )
+    .await
+    .with_context(|| format!("The config didn't load within {init_timeout}s"))??;
+    let timeout = config.hook_timeout;
+    let config: GlobalConfig = Arc::new(RwLock::new(config));
+    if let Some(model_id) = model_id {
+        config.write().set_model(model_id)?;
+    }
+    let mut text = String::new();
+    if hook == GitHook::CommitMsg || source == Some("template") {
+        text.push_str(&format!("Draft message:\n{draft}\n\n"));
+    }
+    text.push_str(&format!("Staged diff:\n{diff}"));
+    let role = config.read().retrieve_role(COMMIT_MESSAGE_ROLE)?;
+    let input = Input::from_str(&config, &text, Some(role));
+    let remaining = Duration::from_secs(timeout).saturating_sub(started.elapsed());
+    let message = tokio::time::timeout(remaining, input.fetch_chat_text())
+        .await
+        .with_context(|| format!("No reply within {timeout}s"))??;
+    let message = message.trim().trim_matches('`').trim();
+    if message.is_empty() {
+        bail!("Empty commit message");
+    }
+    let mut output = format!("{message}\n");
+    if !comments.is_empty() {
+        output.push('\n');
+        output.push_str(&comments);
+    }
+    fs::write(path, output).with_context(|| format!("Failed to write '{path}'"))?;
+    Ok(())
+}
+
+/// Splits a commit message file into the message and the lines git strips from it: those
+/// starting with `prefix` and everything from the scissors line on.
+fn split_comments(content: &str, prefix: &str) -> (String, String) {
+    let scissors = format!("{prefix}{SCISSORS}");
+    let (content, below_scissors) = match content.lines().position(|v| v == scissors) {
+        Some(index) => {
+            let lines: Vec<&str> = content.lines().collect();
+            (lines[..index].join("\n"), lines[index..].join("\n") + "\n")
+        }
+        None => (content.to_string(), String::new()),
+    };
+    let (comments, message): (Vec<&str>, Vec<&str>) =
+        content.lines().partition(|line| line.starts_with(prefix));
+    let mut comments: String = comments.iter().map(|v| format!("{v}\n")).collect();
+    comments.push_str(&below_scissors);
+    (message.join("\n").trim().to_string(), comments)
+}
+
+/// The comment prefix of the repo, from `core.commentString` or `core.commentChar`.
+fn comment_prefix() -> String {
+    ["core.commentString", "core.commentChar"]
+        .iter()
+        .find_map(|key| {
+            let (success, stdout, _) =
+                run_command_with_output("git", &["config", "--get", key], None).ok()?;
+            success.then(|| parse_comment_prefix(&stdout)).flatten()
+        })
+        .unwrap_or_else(|| "#".into())
+}
+
+/// `auto` picks a prefix the message doesn't use, which is `#` for a fresh message.
+fn parse_comment_prefix(value: &str) -> Option<String> {
+    let value = value.trim_end_matches(['\r', '\n']);
+    (!value.is_empty() && value != "auto").then(|| value.to_string())
+}
+
+fn staged_diff() -> Result<String> {
+    let (success, stdout, stderr) = run_command_with_output(
+        "git",
+        &["diff", "--cached", "--no-color", "--no-ext-diff"],
+        None,
+    )?;
+    if !success {
+        bail!("git diff failed: {}", stderr.trim());
+    }
+    let diff = stdout.trim();
+    match diff.char_indices().nth(MAX_DIFF_CHARS) {
+        Some((index, _)) => Ok(format!("{}\n[diff truncated]", &diff[..index])),
+        None => Ok(diff.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_comments() {
+        let content = "fix: typo\n\nBody\n# Please enter the commit message\n#\tmodified: a.rs\n";
+        let (message, comments) = split_comments(content, "#");
+        assert_eq!(message, "fix: typo\n\nBody");
+        assert_eq!(
+            comments,
+            "# Please enter the commit message\n#\tmodified: a.rs\n"
+        );
+    }
+
+    #[test]
+    fn test_split_comments_scissors() {
+        let content = "fix: typo\n# Please enter the commit message\n# ------------------------ >8 ------------------------\n# Do not modify or remove the line above.\ndiff --git a/a.rs b/a.rs\n+fn a() {}\n";
+        let (message, comments) = split_comments(content, "#");
+        assert_eq!(message, "fix: typo");
+        assert!(comments.ends_with("diff --git a/a.rs b/a.rs\n+fn a() {}\n"));
+
+        let content = "fix: typo\n\n#123 is fixed\n; Please enter the commit message\n";
+        let (message, comments) = split_comments(content, ";");
+        assert_eq!(message, "fix: typo\n\n#123 is fixed");
+        assert_eq!(comments, "; Please enter the commit message\n");
+    }
+
+    #[test]
+    fn test_parse_comment_prefix() {
+        assert_eq!(parse_comment_prefix(";\n").as_deref(), Some(";"));
+        assert_eq!(parse_comment_prefix("//\n").as_deref(), Some("//"));
+        assert_eq!(parse_comment_prefix("auto\n"), None);
+        assert_eq!(parse_comment_prefix(""), None);
+    }
+}
@@ -2,6 +2,7 @@ mod cli;
 mod client;
 mod config;
 mod function;
+mod hook;
 mod rag;
 mod render;
 mod repl;
@@ -14,29 +15,34 @@ extern crate log;
 
 use crate::cli::Cli;
 use crate::client::{
-    call_chat_completions, call_chat_completions_streaming, list_models, ModelType,
+    call_chat_completions, call_chat_completions_streaming, call_chat_completions_with_output,
+    list_installed_ollama_models, list_models, set_models_offline, Cassette, ModelType,
 };
 use crate::config::{
-    ensure_parent_exists, list_agents, load_env_file, macro_execute, Config, GlobalConfig, Input,
-    WorkingMode, CODE_ROLE, DISTROBOX_ROLE, EXPLAIN_SHELL_ROLE, SHELL_ROLE, TEMP_SESSION_NAME,
+    apply_file_edits, ensure_parent_exists, list_agents, load_env_file, macro_execute, Config,
+    GlobalConfig, Input, RepeatedTurn, RunReport, WorkingMode, CODE_ROLE, DISTROBOX_ROLE,
+    EDIT_ROLE, EXPLAIN_SHELL_ROLE, SHELL_ROLE, TEMP_SESSION_NAME,
 };
+use crate::hook::{run_git_hook, GitHook};
+use crate::rag::{EmbeddingCache, Rag, RagFilter};
 use crate::render::render_error;
-use crate::repl::Repl;
+use crate::repl::{Repl, Tui};
 use crate::utils::*;
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use clap::Parser;
 use inquire::{Confirm, Text};
 use parking_lot::RwLock;
 use simplelog::{format_description, ConfigBuilder, LevelFilter, SimpleLogger, WriteLogger};
-use std::{env, process, sync::{Arc, LazyLock}};
-use serde_json::json;
+use std::{env, path::Path, process, sync::{Arc, LazyLock}};
+use serde_json::{json, Value};
 use fancy_regex::Regex;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 enum OutputFormat {
     Default,
-    Code,
+    /// Carries the blocks picked with `--code=<SELECT>`
+    Code(Option<CodeSelector>),
     Json,
     Yaml,
     Plain,
@@ -51,20 +57,23 @@ static ITALIC_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\*(.+?)\*|_(.+
 static HEADER_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^#{1,6}\s+").unwrap());
 static LINK_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\[([^\]]+)\]\([^\)]+\)").unwrap());
 
-fn convert_output_format(text: &str, format: OutputFormat) -> Result<String> {
-    match format {
-        OutputFormat::Json => {
-            let output = json!({
-                "output": text
-            });
-            Ok(serde_json::to_string_pretty(&output)?)
-        }
-        OutputFormat::Yaml => {
-            let output = json!({
-                "output": text
-            });
-            Ok(serde_yaml::to_string(&output)?)
+fn convert_output_format(
+    text: &str,
+    logprobs: Option<&Value>,
+    format: OutputFormat,
+) -> Result<String> {
+    let build_output = || {
+        let mut output = json!({
+            "output": text
+        });
+        if let Some(logprobs) = logprobs {
+            output["logprobs"] = logprobs.clone();
         }
+        output
+    };
+    match format {
+        OutputFormat::Json => Ok(serde_json::to_string_pretty(&build_output())?),
+        OutputFormat::Yaml => Ok(serde_yaml::to_string(&build_output())?),
         OutputFormat::Plain => {
             // Strip markdown formatting for plain text
             Ok(strip_markdown(text))
@@ -73,6 +82,40 @@ fn convert_output_format(text: &str, format: OutputFormat) -> Result<String> {
     }
 }
 
+/// Parses the `output_format` of a role: markdown, json, yaml, plain, code or code=<SELECT>.
+fn parse_output_format(value: &str) -> Result<OutputFormat> {
+    let output_format = match value {
+        "markdown" => OutputFormat::Default,
+        "json" => OutputFormat::Json,
+        "yaml" => OutputFormat::Yaml,
+        "plain" => OutputFormat::Plain,
+        "code" => OutputFormat::Code(None),
+        _ => match value.strip_prefix("code=") {
+            Some(selector) => OutputFormat::Code(Some(selector.parse()?)),
+            None => bail!("Invalid output format '{value}'"),
+        },
+    };
+    Ok(output_format)
+}
+
+/// Prints a reply that should be a JSON value, as pretty JSON or YAML. Code fences and
+/// thinking around the value are dropped.
+fn convert_structured_output(text: &str, format: &OutputFormat) -> Result<String> {
+    let text = strip_think_tag(text);
+    let text = text.trim();
+    let text = text
+        .strip_prefix("```json")
+        .or_else(|| text.strip_prefix("```"))
+        .and_then(|v| v.strip_suffix("```"))
+        .unwrap_or(text);
+    let value: Value = serde_json::from_str(text.trim())
+        .with_context(|| format!("The reply isn't valid JSON:\n{text}"))?;
+    match format {
+        OutputFormat::Yaml => Ok(serde_yaml::to_string(&value)?.trim_end().to_string()),
+        _ => Ok(serde_json::to_string_pretty(&value)?),
+    }
+}
+
 fn strip_markdown(text: &str) -> String {
     // Simple markdown stripping - remove common markdown syntax
     let mut result = text.to_string();
@@ -135,6 +178,17 @@ fn strip_markdown(text: &str) -> String {
 async fn main() -> Result<()> {
     load_env_file()?;
     let cli = Cli::parse();
+    if let Some(proxy) = &cli.proxy {
+        set_proxy_override(proxy);
+    }
+    if cli.offline_models {
+        set_models_offline();
+    }
+    if let Some(hook) = &cli.hook {
+        let hook = GitHook::parse(hook)?;
+        setup_logger(false)?;
+        return run_git_hook(hook, cli.hook_args(), cli.model.as_deref()).await;
+    }
     let text = cli.text()?;
     let working_mode = if cli.serve.is_some() {
         WorkingMode::Serve
@@ -144,14 +198,17 @@ async fn main() -> Result<()> {
         WorkingMode::Cmd
     };
     let info_flag = cli.info
-        || cli.sync_models
+        || cli.sync_models.is_some()
         || cli.refresh_models
         || cli.list_models
         || cli.list_roles
         || cli.list_agents
         || cli.list_rags
         || cli.list_macros
-        || cli.list_sessions;
+        || cli.list_templates
+        || cli.list_sessions
+        || cli.embeddings_cache_stats
+        || cli.prune_embeddings_cache;
     setup_logger(working_mode.is_serve())?;
     let config = Arc::new(RwLock::new(Config::init(working_mode, info_flag).await?));
     if let Err(err) = run(config, cli, text).await {
@@ -165,13 +222,16 @@ async fn run(config: GlobalConfig, cli: Cli, text: Option<String>) -> Result<()>
     let abort_signal = create_abort_signal();
 
     // Determine output format
-    let format_flags = [cli.code, cli.json, cli.yaml, cli.plain];
+    let format_flags = [cli.code.is_some(), cli.json, cli.yaml, cli.plain];
     let format_count = format_flags.iter().filter(|&&f| f).count();
     if format_count > 1 {
         bail!("Only one output format flag can be specified at a time (--code, --json, --yaml, --plain)");
     }
-    let output_format = if cli.code {
-        OutputFormat::Code
+    let output_format = if let Some(code) = &cli.code {
+        match code.as_str() {
+            "" => OutputFormat::Code(None),
+            v => OutputFormat::Code(Some(v.parse()?)),
+        }
     } else if cli.json {
         OutputFormat::Json
     } else if cli.yaml {
@@ -182,18 +242,29 @@ async fn run(config: GlobalConfig, cli: Cli, text: Option<String>) -> Result<()>
         OutputFormat::Default
     };
 
-    if cli.sync_models {
-        let url = config.read().sync_models_url();
-        return Config::sync_models(&url, abort_signal.clone()).await;
+    if let Some(source) = &cli.sync_models {
+        let source = source
+            .clone()
+            .unwrap_or_else(|| config.read().sync_models_url());
+        return Config::sync_models(&source, abort_signal.clone()).await;
     }
 
     if cli.refresh_models {
-        return Config::refresh_client_models(abort_signal.clone()).await;
+        return Config::refresh_client_models(cli.force, abort_signal.clone()).await;
     }
 
     if cli.list_models {
-        for model in list_models(&config.read(), ModelType::Chat) {
-            println!("{}", model.id());
+        let mut model_ids: Vec<String> = list_models(&config.read(), ModelType::Chat)
+            .into_iter()
+            .map(|v| v.id())
+            .collect();
+        for model_id in list_installed_ollama_models(&config).await {
+            if !model_ids.contains(&model_id) {
+                model_ids.push(model_id);
+            }
+        }
+        for model_id in model_ids {
+            println!("{model_id}");
         }
         return Ok(());
     }
@@ -212,18 +283,94 @@ async fn run(config: GlobalConfig, cli: Cli, text: Option<String>) -> Result<()>
         println!("{rags}");
         return Ok(());
     }
+    if cli.embeddings_cache_stats {
+        let stats = EmbeddingCache::stats()?;
+        print!("{stats}");
+        return Ok(());
+    }
+    if cli.prune_embeddings_cache {
+        let removed = EmbeddingCache::prune()?;
+        println!("✓ Removed {removed} cached embeddings.");
+        return Ok(());
+    }
+    if cli.prune_blobs {
+        let removed = config.read().prune_blobs()?;
+        println!("✓ Removed {removed} unused blobs.");
+        return Ok(());
+    }
+    if let Some(path) = &cli.import {
+        let (name, save_path) =
+            Rag::import_pack(&config, Path::new(path), cli.rag.as_deref()).await?;
+        println!("✓ Imported RAG '{name}' to '{}'.", save_path.display());
+        return Ok(());
+    }
     if cli.list_macros {
         let macros = Config::list_macros().join("\n");
         println!("{macros}");
         return Ok(());
     }
+    if cli.list_templates {
+        let templates = Config::list_conversation_templates().join("\n");
+        println!("{templates}");
+        return Ok(());
+    }
 
     if cli.dry_run {
         config.write().dry_run = true;
     }
+    if let Some(path) = &cli.record_cassette {
+        let cassette = Cassette::record(&config.read(), path)?;
+        config.write().cassette = Some(cassette);
+    }
+    if let Some(path) = &cli.replay_cassette {
+        let cassette = Cassette::replay(&config.read(), path)?;
+        config.write().cassette = Some(cassette);
+    }
     if cli.hide_thinking {
         config.write().hide_thinking = true;
     }
+    if cli.stats {
+        config.write().stream_stats = true;
+    }
+    if cli.tui {
+        config.write().tui = true;
+    }
+    if let Some(timeout) = cli.timeout {
+        config.write().request_timeout = Some(timeout);
+    }
+    if let Some(max_time) = cli.max_time.filter(|v| !v.is_zero()) {
+        let mut config = config.write();
+        config.max_time = Some(max_time);
+        config.start_max_time();
+        config.max_time_wrap_up = cli.wrap_up;
+    }
+    if let Some(seed) = cli.seed {
+        config.write().seed = Some(seed);
+    }
+    if !cli.tag.is_empty() {
+        config.write().tags.extend(cli.tag.clone());
+    }
+    if let Some(name) = cli
+        .sink
+        .iter()
+        .find(|v| !config.read().output_sinks.contains_key(*v))
+    {
+        bail!("Unknown output sink '{name}', add it under `output_sinks` in the config");
+    }
+    config.write().cli_sinks = cli.sink.clone();
+    if let Some(filter) = &cli.rag_filter {
+        config.write().rag_filter = Some(RagFilter::parse(filter)?);
+    }
+    if cli.google_search {
+        config.write().google_search = true;
+    }
+    if let Some(top_logprobs) = cli.logprobs {
+        let mut config = config.write();
+        config.logprobs = true;
+        if top_logprobs > 0 {
+            config.top_logprobs = Some(top_logprobs);
+        }
+    }
 
     if let Some(agent) = &cli.agent {
         let session = cli.session.as_ref().map(|v| match v {
@@ -251,16 +398,23 @@ async fn run(config: GlobalConfig, cli: Cli, text: Option<String>) -> Result<()>
             config.write().use_role(DISTROBOX_ROLE)?;
         } else if cli.execute {
             config.write().use_role(SHELL_ROLE)?;
-        } else if cli.code {
+        } else if cli.code.is_some() {
             config.write().use_role(CODE_ROLE)?;
+        } else if cli.edit_files {
+            config.write().use_role(EDIT_ROLE)?;
         }
-        if let Some(session) = &cli.session {
+        if let Some(name) = &cli.new_from_template {
+            let session = cli.session.as_ref().and_then(|v| v.as_deref());
+            Config::use_conversation_template(&config, name, session, abort_signal.clone()).await?;
+        } else if let Some(session) = &cli.session {
             config
                 .write()
                 .use_session(session.as_ref().map(|v| v.as_str()))?;
         }
         if let Some(rag) = &cli.rag {
             Config::use_rag(&config, Some(rag), abort_signal.clone()).await?;
+        } else {
+            Config::use_role_rag(&config)?;
         }
     }
     if cli.list_sessions {
@@ -280,36 +434,114 @@ async fn run(config: GlobalConfig, cli: Cli, text: Option<String>) -> Result<()>
     if cli.save_session {
         config.write().set_save_session_this_time()?;
     }
+    if cli.read_only {
+        config.write().set_session_read_only()?;
+    }
     if cli.info {
-        let info = config.read().info()?;
+        let info = if cli.json {
+            let config = config.read();
+            let value = config.info_value(config.default_info_target())?;
+            serde_json::to_string_pretty(&value)?
+        } else {
+            config.read().info()?
+        };
         println!("{info}");
         return Ok(());
     }
     if let Some(addr) = cli.serve {
+        if cli.watch {
+            tokio::try_join!(
+                serve::run(config.clone(), addr),
+                Config::watch_rag(&config, abort_signal.clone())
+            )?;
+            return Ok(());
+        }
         return serve::run(config, addr).await;
     }
     let is_repl = config.read().working_mode.is_repl();
     if cli.rebuild_rag {
-        Config::rebuild_rag(&config, abort_signal.clone()).await?;
+        Config::rebuild_rag(&config, cli.force, abort_signal.clone()).await?;
         if is_repl {
             return Ok(());
         }
     }
+    if cli.watch {
+        return Config::watch_rag(&config, abort_signal.clone()).await;
+    }
+    if let Some(path) = &cli.export {
+        let rag = config.read().rag.clone();
+        let Some(rag) = rag else {
+            bail!("No RAG");
+        };
+        rag.export_pack(Path::new(path), abort_signal.clone())
+            .await?;
+        println!("✓ Exported RAG '{}' to '{path}'.", rag.name());
+        return Ok(());
+    }
+    if let Some(path) = &cli.eval {
+        let rag = config.read().rag.clone();
+        let Some(rag) = rag else {
+            bail!("No RAG");
+        };
+        let report = rag.evaluate(&config, path, abort_signal.clone()).await?;
+        match cli.json {
+            true => println!("{}", serde_json::to_string_pretty(&report)?),
+            false => println!("{}", report.render()),
+        }
+        return Ok(());
+    }
     if let Some(name) = &cli.macro_name {
         macro_execute(&config, name, text.as_deref(), abort_signal.clone()).await?;
         return Ok(());
     }
     if cli.execute && !is_repl {
         let input = create_input(&config, text, &cli.file, abort_signal.clone()).await?;
-        shell_execute(&config, &SHELL, input, cli.yolo, abort_signal.clone()).await?;
+        shell_execute(
+            &config,
+            &SHELL,
+            input,
+            cli.yolo,
+            cli.verify,
+            abort_signal.clone(),
+        )
+        .await?;
+        return Ok(());
+    }
+    if cli.edit_files && !is_repl {
+        let input = create_input(&config, text, &cli.file, abort_signal.clone()).await?;
+        edit_files(&config, input, cli.yolo > 0, abort_signal.clone()).await?;
         return Ok(());
     }
-    config.write().apply_prelude()?;
+    if config.write().apply_prelude()? {
+        Config::use_role_rag(&config)?;
+    }
     match is_repl {
         false => {
             let mut input = create_input(&config, text, &cli.file, abort_signal.clone()).await?;
             input.use_embeddings(abort_signal.clone()).await?;
-            start_directive(&config, input, output_format, abort_signal).await
+            let output_format = match (output_format, input.role().output_format()) {
+                (OutputFormat::Default, Some(value)) => parse_output_format(value)?,
+                (output_format, _) => output_format,
+            };
+            if let Some(repeated) = Config::dedup_question(&config, &mut input).await? {
+                let RepeatedTurn { answer, similarity } = repeated;
+                return reuse_answer(&config, &input, &answer, similarity, output_format).await;
+            }
+            if let Some(path) = &cli.report {
+                config.write().run_report = Some(RunReport::new(path));
+            }
+            let ret = start_directive(&config, input, output_format, abort_signal.clone()).await;
+            let report = config.write().run_report.take();
+            if let Some(report) = report {
+                let (status, error) = match &ret {
+                    Ok(_) => ("success", None),
+                    Err(_) if abort_signal.aborted() => ("aborted", None),
+                    Err(err) => ("error", Some(format!("{err:#}"))),
+                };
+                let written = report.write(&config.read(), status, error);
+                return ret.and(written);
+            }
+            ret
         }
         true => {
             if !*IS_STDOUT_TERMINAL {
@@ -320,28 +552,77 @@ async fn run(config: GlobalConfig, cli: Cli, text: Option<String>) -> Result<()>
     }
 }
 
+/// Answers a question repeating an earlier turn of the session without calling the model.
+async fn reuse_answer(
+    config: &GlobalConfig,
+    input: &Input,
+    answer: &str,
+    similarity: f32,
+    output_format: OutputFormat,
+) -> Result<()> {
+    config.write().before_chat_completion(input)?;
+    eprintln!(
+        "{}",
+        dimmed_text(&format!(
+            "Answered before (similarity {similarity:.2}), reusing that answer:"
+        ))
+    );
+    match output_format {
+        OutputFormat::Default => config.read().print_markdown(answer)?,
+        OutputFormat::Code(selector) => {
+            println!(
+                "{}",
+                select_code_blocks(answer, &selector.unwrap_or_default())?
+            )
+        }
+        _ => println!("{}", convert_output_format(answer, None, output_format)?),
+    }
+    config.write().after_chat_completion(input, answer, &[])?;
+    Config::warn_sink_failures(Config::send_to_sinks(config, input, answer).await);
+    Config::autoname_session_before_exit(config).await;
+    config.write().exit_session()
+}
+
 #[async_recursion::async_recursion]
 async fn start_directive(
     config: &GlobalConfig,
-    input: Input,
+    mut input: Input,
     output_format: OutputFormat,
     abort_signal: AbortSignal,
 ) -> Result<()> {
+    Config::compress_session_to_fit(config, &input).await?;
+    if !Config::preview_input(config, &mut input, abort_signal.clone()).await? {
+        return Ok(());
+    }
     let client = input.create_client()?;
-    let extract_code = !*IS_STDOUT_TERMINAL && output_format == OutputFormat::Code;
+    let code_selector = match &output_format {
+        OutputFormat::Code(Some(selector)) => Some(selector.clone()),
+        OutputFormat::Code(None) if !*IS_STDOUT_TERMINAL => Some(CodeSelector::First),
+        _ => None,
+    };
+    let extract_code = code_selector.is_some();
     let requires_full_output = output_format != OutputFormat::Default;
     config.write().before_chat_completion(&input)?;
     
+    let mut logprobs = None;
     let (mut output, tool_results) = if !input.stream() || extract_code || requires_full_output {
         // Use non-streaming mode for format conversion (need complete output)
-        call_chat_completions(
+        let (output, tool_results) = call_chat_completions_with_output(
             &input,
             false,  // Don't print yet - we'll handle printing after format conversion
-            extract_code,
+            false,
             client.as_ref(),
             abort_signal.clone(),
         )
-        .await?
+        .await?;
+        logprobs = output.logprobs;
+        let text = match &code_selector {
+            Some(selector) if !output.text.is_empty() => {
+                select_code_blocks(&strip_think_tag(&output.text), selector)?
+            }
+            _ => output.text,
+        };
+        (text, tool_results)
     } else {
         call_chat_completions_streaming(&input, client.as_ref(), abort_signal.clone()).await?
     };
@@ -352,14 +633,26 @@ async fn start_directive(
             OutputFormat::Default => {
                 // Default: use markdown rendering
                 config.read().print_markdown(&output)?;
+                if tool_results.is_empty() {
+                    if let Some(sources) = input.rag_citation_sources() {
+                        println!("\n{}", dimmed_text(&format!("Sources:\n{sources}")));
+                    }
+                }
             }
-            OutputFormat::Code => {
-                // Code mode already extracted in call_chat_completions
+            OutputFormat::Code(_) => {
+                // Code blocks were already picked above
                 println!("{}", output);
             }
+            OutputFormat::Json | OutputFormat::Yaml
+                if input.role().output_schema().is_some() && !config.read().dry_run =>
+            {
+                // The reply is the structured value itself
+                let text = convert_structured_output(&output, &output_format)?;
+                println!("{text}");
+            }
             _ => {
                 // JSON, YAML, or Plain: convert and print
-                output = convert_output_format(&output, output_format)?;
+                output = convert_output_format(&output, logprobs.as_ref(), output_format.clone())?;
                 println!("{}", output);
             }
         }
@@ -368,6 +661,12 @@ async fn start_directive(
     config
         .write()
         .after_chat_completion(&input, &output, &tool_results)?;
+    if tool_results.is_empty() {
+        Config::warn_sink_failures(Config::send_to_sinks(config, &input, &output).await);
+        if let Some(report) = config.write().run_report.as_mut() {
+            report.set_output(&output);
+        }
+    }
 
     if !tool_results.is_empty() {
         start_directive(
@@ -379,11 +678,16 @@ async fn start_directive(
         .await?;
     }
 
+    Config::autoname_session_before_exit(config).await;
     config.write().exit_session()?;
     Ok(())
 }
 
 async fn start_interactive(config: &GlobalConfig) -> Result<()> {
+    if config.read().tui {
+        let mut tui = Tui::init(config)?;
+        return tui.run().await;
+    }
     let mut repl: Repl = Repl::init(config)?;
     repl.run().await
 }
@@ -486,12 +790,34 @@ fn is_extremely_dangerous_command(cmd: &str) -> bool {
     extremely_dangerous_patterns.iter().any(|pattern| cmd_lower.contains(pattern))
 }
 
+async fn edit_files(
+    config: &GlobalConfig,
+    input: Input,
+    yes: bool,
+    abort_signal: AbortSignal,
+) -> Result<()> {
+    let client = input.create_client()?;
+    config.write().before_chat_completion(&input)?;
+    let (output, _) = if input.stream() {
+        call_chat_completions_streaming(&input, client.as_ref(), abort_signal.clone()).await?
+    } else {
+        call_chat_completions(&input, true, false, client.as_ref(), abort_signal.clone()).await?
+    };
+    config.write().after_chat_completion(&input, &output, &[])?;
+    config.write().exit_session()?;
+    if config.read().dry_run {
+        return Ok(());
+    }
+    apply_file_edits(&output, yes)
+}
+
 #[async_recursion::async_recursion]
 async fn shell_execute(
     config: &GlobalConfig,
     shell: &Shell,
     mut input: Input,
     yolo_level: u8,
+    verify: bool,
     abort_signal: AbortSignal,
 ) -> Result<()> {
     let client = input.create_client()?;
@@ -509,6 +835,15 @@ async fn shell_execute(
         config.read().print_markdown(&eval_str)?;
         return Ok(());
     }
+    if verify {
+        let report = verify_in_sandbox(config, &eval_str, abort_signal.clone()).await?;
+        if !*IS_STDOUT_TERMINAL {
+            eprintln!("{report}");
+            println!("{eval_str}");
+            return Ok(());
+        }
+        println!("{report}");
+    }
 
     // Yolo mode handling
     if yolo_level > 0 {
@@ -575,7 +910,7 @@ async fn shell_execute(
 
     // Non-yolo mode: interactive prompt
     if *IS_STDOUT_TERMINAL {
-        let options = ["execute", "revise", "describe", "copy", "quit"];
+        let options = ["execute", "verify", "revise", "describe", "copy", "quit"];
         let command = color_text(eval_str.trim(), nu_ansi_term::Color::Rgb(255, 165, 0));
         let first_letter_color = nu_ansi_term::Color::Cyan;
         let prompt_text = options
@@ -585,8 +920,11 @@ async fn shell_execute(
             .join(&dimmed_text(" | "));
         loop {
             println!("{command}");
-            let answer_char =
-                read_single_key(&['e', 'r', 'd', 'c', 'q'], 'e', &format!("{prompt_text}: "))?;
+            let answer_char = read_single_key(
+                &['e', 'v', 'r', 'd', 'c', 'q'],
+                'e',
+                &format!("{prompt_text}: "),
+            )?;
 
             match answer_char {
                 'e' => {
@@ -597,11 +935,24 @@ async fn shell_execute(
                     }
                     process::exit(code);
                 }
+                'v' => {
+                    let report = verify_in_sandbox(config, &eval_str, abort_signal.clone()).await?;
+                    println!("{report}");
+                    continue;
+                }
                 'r' => {
                     let revision = Text::new("Enter your revision:").prompt()?;
                     let text = format!("{}\n{revision}", input.text());
                     input.set_text(text);
-                    return shell_execute(config, shell, input, yolo_level, abort_signal.clone()).await;
+                    return shell_execute(
+                        config,
+                        shell,
+                        input,
+                        yolo_level,
+                        verify,
+                        abort_signal.clone(),
+                    )
+                    .await;
                 }
                 'd' => {
                     let role = config.read().retrieve_role(EXPLAIN_SHELL_ROLE)?;
@@ -640,6 +991,20 @@ async fn shell_execute(
     Ok(())
 }
 
+/// Runs the command in the `shell_sandbox` container against a copy of the current directory
+/// and renders what it printed and which files it would change.
+async fn verify_in_sandbox(
+    config: &GlobalConfig,
+    command: &str,
+    abort_signal: AbortSignal,
+) -> Result<String> {
+    let sandbox = config.read().shell_sandbox.clone();
+    let dir = env::current_dir()?;
+    let task = run_in_sandbox(&sandbox, command, &dir);
+    let report = abortable_run_with_spinner(task, "Verifying in the sandbox", abort_signal).await?;
+    Ok(report.render())
+}
+
 async fn create_input(
     config: &GlobalConfig,
     text: Option<String>,
@@ -701,10 +1066,25 @@ fn setup_logger(is_serve: bool) -> Result<()> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_convert_structured_output() {
+        let text = "```json\n{\"total\": 42, \"items\": [\"a\"]}\n```";
+        let result = convert_structured_output(text, &OutputFormat::Yaml).unwrap();
+        assert_eq!(result, "total: 42\nitems:\n- a");
+        let result = convert_structured_output("{\"total\": 42}", &OutputFormat::Json).unwrap();
+        assert_eq!(result, "{\n  \"total\": 42\n}");
+        assert!(convert_structured_output("Sure! The total is 42.", &OutputFormat::Json).is_err());
+        assert_eq!(
+            parse_output_format("code=rust").unwrap(),
+            OutputFormat::Code(Some("rust".parse().unwrap()))
+        );
+        assert!(parse_output_format("xml").is_err());
+    }
+
     #[test]
     fn test_convert_output_format_json() {
         let text = "Hello, World!";
-        let result = convert_output_format(text, OutputFormat::Json).unwrap();
+        let result = convert_output_format(text, None, OutputFormat::Json).unwrap();
         assert!(result.contains("\"output\""));
         assert!(result.contains("Hello, World!"));
     }
@@ -712,11 +1092,20 @@ mod tests {
     #[test]
     fn test_convert_output_format_yaml() {
         let text = "Hello, World!";
-        let result = convert_output_format(text, OutputFormat::Yaml).unwrap();
+        let result = convert_output_format(text, None, OutputFormat::Yaml).unwrap();
         assert!(result.contains("output:"));
         assert!(result.contains("Hello, World!"));
     }
 
+    #[test]
+    fn test_convert_output_format_json_logprobs() {
+        let logprobs = json!([{ "token": "Hi", "logprob": -0.25, "top_logprobs": [] }]);
+        let result = convert_output_format("Hi", Some(&logprobs), OutputFormat::Json).unwrap();
+        let value: Value = serde_json::from_str(&result).unwrap();
+        assert_eq!(value["output"], "Hi");
+        assert_eq!(value["logprobs"][0]["logprob"], -0.25);
+    }
+
     #[test]
     fn test_strip_markdown_bold() {
         let text = "This is **bold** text";
@@ -0,0 +1,203 @@
+use super::*;
+
+/// A RAG file that indexes no documents of its own and searches other RAGs instead:
+///
+/// ```yaml
+/// rags: [docs, code]
+/// top_k: 8                  # optional, defaults to `rag_top_k`
+/// reranker_model: null      # optional, reranks the merged results
+/// ```
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CompositeRagData {
+    pub rags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reranker_model: Option<String>,
+}
+
+/// One of the RAGs a composite RAG searches.
+#[derive(Debug, Clone)]
+pub(super) struct RagMember {
+    pub(super) rag: Rag,
+    /// Maps the member's file ids to the ids of the same files in the composite RAG
+    file_ids: HashMap<FileId, FileId>,
+}
+
+impl Rag {
+    /// Combines the RAGs saved next to `path`, e.g. for `.rag docs,code` or a file listing
+    /// them under `rags`.
+    pub fn combine(
+        config: &GlobalConfig,
+        name: &str,
+        path: &Path,
+        definition: CompositeRagData,
+    ) -> Result<Self> {
+        if definition.rags.is_empty() {
+            bail!("RAG '{name}' doesn't list any RAGs to combine");
+        }
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut rags = vec![];
+        for member in &definition.rags {
+            let member_path = dir.join(format!("{member}.yaml"));
+            if !member_path.exists() {
+                bail!("Unknown RAG '{member}'");
+            }
+            let rag = Rag::load(config, member, &member_path)?;
+            if rag.is_composite() {
+                bail!("RAG '{member}' combines other RAGs, it can't be part of '{name}'");
+            }
+            rags.push(rag);
+        }
+        Ok(Self::combine_members(config, name, path, definition, rags))
+    }
+
+    /// Builds the composite RAG over loaded members, mapping their file ids into its own.
+    pub(super) fn combine_members(
+        config: &GlobalConfig,
+        name: &str,
+        path: &Path,
+        definition: CompositeRagData,
+        rags: Vec<Rag>,
+    ) -> Self {
+        let (reranker_model, top_k) = {
+            let config = config.read();
+            (
+                definition
+                    .reranker_model
+                    .or_else(|| config.rag_reranker_model.clone()),
+                definition.top_k.unwrap_or(config.rag_top_k),
+            )
+        };
+        let first = &rags[0];
+        let mut data = RagData::new(
+            first.data.embedding_model.clone(),
+            first.data.chunk_size,
+            first.data.chunk_overlap,
+            reranker_model,
+            top_k,
+            first.data.batch_size,
+        );
+        let bm25 = empty_keyword_index();
+        let store = Box::new(FileStore::new(&data));
+        let embedding_model = first.embedding_model.clone();
+        let members = rags
+            .into_iter()
+            .map(|rag| {
+                let mut file_ids = HashMap::new();
+                for (file_id, file) in &rag.data.files {
+                    file_ids.insert(*file_id, data.next_file_id);
+                    data.files.insert(data.next_file_id, file.clone());
+                    data.next_file_id += 1;
+                }
+                data.document_paths
+                    .extend(rag.data.document_paths.iter().cloned());
+                RagMember { rag, file_ids }
+            })
+            .collect();
+        Rag {
+            config: config.clone(),
+            name: name.to_string(),
+            path: path.display().to_string(),
+            embedding_model,
+            store,
+            bm25,
+            data,
+            last_sources: RwLock::new(None),
+            members,
+            overrides: RagOverrides::default(),
+        }
+    }
+
+    pub fn is_composite(&self) -> bool {
+        !self.members.is_empty()
+    }
+
+    /// Fails for a composite RAG, whose documents belong to the RAGs it combines.
+    pub fn ensure_documents(&self) -> Result<()> {
+        if self.is_composite() {
+            bail!(
+                "RAG '{}' combines other RAGs, manage the documents of {} instead",
+                self.name,
+                self.member_names().join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    pub(super) fn member_names(&self) -> Vec<&str> {
+        self.members.iter().map(|v| v.rag.name()).collect()
+    }
+
+    pub(super) fn composite_data(&self) -> CompositeRagData {
+        CompositeRagData {
+            rags: self.member_names().into_iter().map(String::from).collect(),
+            top_k: Some(self.data.top_k),
+            reranker_model: self.data.reranker_model.clone(),
+        }
+    }
+
+    /// Searches every member, then merges their results by rank or with the reranker.
+    pub(super) async fn composite_search(
+        &self,
+        query: &str,
+        top_k: usize,
+        rerank_model: Option<&str>,
+        filter: Option<&RagFilter>,
+    ) -> Result<Vec<(DocumentId, f32)>> {
+        let searches = self
+            .members
+            .iter()
+            .map(|member| member.rag.scored_search(query, top_k, None, filter));
+        let results = futures_util::future::try_join_all(searches).await?;
+        let lists: Vec<Vec<DocumentId>> = self
+            .members
+            .iter()
+            .zip(results)
+            .map(|(member, results)| {
+                results
+                    .into_iter()
+                    .filter_map(|(id, _)| {
+                        let (file_index, document_index) = id.split();
+                        let file_index = member.file_ids.get(&file_index)?;
+                        Some(DocumentId::new(*file_index, document_index))
+                    })
+                    .collect()
+            })
+            .collect();
+        debug!("composite_search_ids: {lists:?}");
+        match rerank_model {
+            Some(model_id) => {
+                let ids: IndexSet<DocumentId> = lists.into_iter().flatten().collect();
+                self.rerank(query, ids, model_id, top_k).await
+            }
+            None => {
+                let weights = vec![1.0; lists.len()];
+                let mut scores = reciprocal_rank_fusion_scores(lists, weights, top_k);
+                scores.truncate(top_k);
+                Ok(scores)
+            }
+        }
+    }
+}
+
+/// A composite RAG searches the keyword indexes of its members, not one of its own.
+pub(super) fn empty_keyword_index() -> KeywordIndex {
+    let documents: Vec<bm25::Document<DocumentId>> = vec![];
+    SearchEngineBuilder::with_tokenizer_and_documents(KeywordTokenizer::default(), documents)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_composite_rag_data() {
+        let data: CompositeRagData = serde_yaml::from_str("rags: [docs, code]\ntop_k: 8").unwrap();
+        assert_eq!(data.rags, ["docs", "code"]);
+        assert_eq!(data.top_k, Some(8));
+        assert_eq!(data.reranker_model, None);
+        assert!(serde_yaml::from_str::<CompositeRagData>("embedding_model: x\ntop_k: 4").is_err());
+    }
+}
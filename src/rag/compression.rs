@@ -0,0 +1,87 @@
+use super::*;
+
+const COMPRESSION_PROMPT: &str = "You're given a question and a passage retrieved for it. Copy the sentences of the passage that help answer the question, word for word and in their order, leaving out the rest. Reply with NONE when nothing in the passage is relevant.";
+
+const MAX_CONCURRENT_COMPRESSIONS: usize = 4;
+
+impl Rag {
+    /// Strips each retrieved chunk down to the sentences relevant to the question with the
+    /// `rag_compression_model`, dropping chunks with none. Returns the chunks unchanged when no
+    /// model is set.
+    pub(super) async fn compress_documents(
+        &self,
+        query: &str,
+        documents: Vec<(DocumentId, String)>,
+    ) -> Result<Vec<(DocumentId, String)>> {
+        let Some(model_id) = self.config.read().rag_compression_model.clone() else {
+            return Ok(documents);
+        };
+        if documents.is_empty() {
+            return Ok(documents);
+        }
+        let model = Model::retrieve_model(&self.config.read(), &model_id, ModelType::Chat)?;
+        let tasks: Vec<_> = documents
+            .into_iter()
+            .map(|(id, content)| {
+                let mut role = Role::new("", COMPRESSION_PROMPT);
+                role.set_model(model.clone());
+                let input = Input::from_str(
+                    &self.config,
+                    &format!("Question: {query}\n\nPassage:\n{content}"),
+                    Some(role),
+                );
+                async move {
+                    let reply = input
+                        .fetch_chat_text()
+                        .await
+                        .context("Failed to compress the retrieved chunks")?;
+                    Ok::<_, anyhow::Error>((id, compressed_chunk(&content, &reply)))
+                }
+            })
+            .collect();
+        let results: Vec<_> = stream::iter(tasks)
+            .buffered(MAX_CONCURRENT_COMPRESSIONS)
+            .collect()
+            .await;
+        let mut output = vec![];
+        for ret in results {
+            if let (id, Some(content)) = ret? {
+                output.push((id, content));
+            }
+        }
+        debug!("compressed_chunks: {}", output.len());
+        Ok(output)
+    }
+}
+
+/// What is left of a chunk after compression: `None` when the model found nothing relevant,
+/// the chunk itself when the reply is no shorter.
+fn compressed_chunk(content: &str, reply: &str) -> Option<String> {
+    let reply = reply.trim();
+    if reply.is_empty() || reply.trim_end_matches('.').eq_ignore_ascii_case("none") {
+        return None;
+    }
+    match reply.len() < content.len() {
+        true => Some(reply.to_string()),
+        false => Some(content.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compressed_chunk() {
+        let content = "Set `stream: false` to turn off streaming. The logo is blue.";
+        assert_eq!(
+            compressed_chunk(content, "Set `stream: false` to turn off streaming.\n"),
+            Some("Set `stream: false` to turn off streaming.".into())
+        );
+        assert_eq!(compressed_chunk(content, "NONE."), None);
+        assert_eq!(
+            compressed_chunk(content, &format!("{content} Also more.")),
+            Some(content.into())
+        );
+    }
+}
@@ -0,0 +1,267 @@
+use super::serde_vectors::{decode_vector, encode_vector};
+use super::*;
+
+use std::{
+    collections::HashSet,
+    fs::{create_dir_all, read_dir, remove_file},
+    path::PathBuf,
+};
+
+/// Embeddings of document chunks, keyed by the sha256 of the chunk text.
+///
+/// One cache file is kept per embedding model and shared by every RAG, so
+/// rebuilding a RAG or indexing overlapping documents never re-embeds a chunk.
+#[derive(Debug)]
+pub struct EmbeddingCache {
+    path: PathBuf,
+    model: String,
+    vectors: IndexMap<String, Vec<f32>>,
+    dirty: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EmbeddingCacheFile {
+    model: String,
+    vectors: IndexMap<String, String>,
+}
+
+impl EmbeddingCache {
+    pub fn load(model: &str) -> Result<Self> {
+        Self::open(
+            Config::embeddings_cache_dir().join(cache_file_name(model)),
+            model,
+        )
+    }
+
+    fn open(path: PathBuf, model: &str) -> Result<Self> {
+        let vectors = if path.exists() {
+            read_cache_file(&path)?.1
+        } else {
+            IndexMap::new()
+        };
+        Ok(Self {
+            path,
+            model: model.to_string(),
+            vectors,
+            dirty: false,
+        })
+    }
+
+    pub fn get(&self, text: &str) -> Option<&Vec<f32>> {
+        self.vectors.get(&sha256(text))
+    }
+
+    pub fn insert(&mut self, text: &str, vector: Vec<f32>) {
+        self.vectors.insert(sha256(text), vector);
+        self.dirty = true;
+    }
+
+    pub fn save(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        write_cache_file(&self.path, &self.model, &self.vectors)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub fn stats() -> Result<String> {
+        let data = Self::stats_value()?;
+        let output = serde_yaml::to_string(&data)
+            .with_context(|| "Unable to show info about the embeddings cache")?;
+        Ok(output)
+    }
+
+    pub fn stats_value() -> Result<Value> {
+        let dir = Config::embeddings_cache_dir();
+        let mut models = vec![];
+        let mut total_entries = 0;
+        let mut total_bytes = 0;
+        for path in list_yaml_files(&dir) {
+            let (model, vectors) = read_cache_file(&path)?;
+            let bytes = fs::metadata(&path).map(|v| v.len()).unwrap_or_default();
+            total_entries += vectors.len();
+            total_bytes += bytes;
+            models.push(json!({
+                "model": model,
+                "entries": vectors.len(),
+                "bytes": bytes,
+            }));
+        }
+        Ok(json!({
+            "path": dir.display().to_string(),
+            "entries": total_entries,
+            "bytes": total_bytes,
+            "models": models,
+        }))
+    }
+
+    /// Drop cached embeddings that are no longer referenced by any saved RAG.
+    /// Returns the number of removed entries, or fails without removing any when a saved RAG
+    /// can't be read.
+    pub fn prune() -> Result<usize> {
+        let referenced = referenced_hashes(&rag_paths())
+            .context("Nothing was pruned as the embeddings in use are unknown")?;
+        let mut removed = 0;
+        for path in list_yaml_files(&Config::embeddings_cache_dir()) {
+            let (model, mut vectors) = read_cache_file(&path)?;
+            let size = vectors.len();
+            match referenced.get(&model) {
+                Some(hashes) => vectors.retain(|hash, _| hashes.contains(hash)),
+                None => vectors.clear(),
+            }
+            if vectors.len() == size {
+                continue;
+            }
+            removed += size - vectors.len();
+            if vectors.is_empty() {
+                remove_file(&path).with_context(|| {
+                    format!("Failed to delete embeddings cache at '{}'", path.display())
+                })?;
+            } else {
+                write_cache_file(&path, &model, &vectors)?;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Saves what was added when the cache goes away unsaved, e.g. because embedding failed or was
+/// aborted halfway, so the chunks embedded so far aren't paid for again.
+impl Drop for EmbeddingCache {
+    fn drop(&mut self) {
+        if let Err(err) = self.save() {
+            debug!("{err:#}");
+        }
+    }
+}
+
+fn cache_file_name(model: &str) -> String {
+    let name: String = model
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{name}.yaml")
+}
+
+fn list_yaml_files(dir: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = match read_dir(dir) {
+        Ok(rd) => rd
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().map(|v| v == "yaml").unwrap_or_default())
+            .collect(),
+        Err(_) => vec![],
+    };
+    paths.sort_unstable();
+    paths
+}
+
+fn read_cache_file(path: &Path) -> Result<(String, IndexMap<String, Vec<f32>>)> {
+    let err = || format!("Failed to load embeddings cache at '{}'", path.display());
+    let content = fs::read_to_string(path).with_context(err)?;
+    let data: EmbeddingCacheFile = serde_yaml::from_str(&content).with_context(err)?;
+    let mut vectors = IndexMap::new();
+    for (hash, value) in data.vectors {
+        let vector = decode_vector(&value)
+            .ok_or_else(|| anyhow!("Invalid vector at '{hash}'"))
+            .with_context(err)?;
+        vectors.insert(hash, vector);
+    }
+    Ok((data.model, vectors))
+}
+
+fn write_cache_file(path: &Path, model: &str, vectors: &IndexMap<String, Vec<f32>>) -> Result<()> {
+    let data = EmbeddingCacheFile {
+        model: model.to_string(),
+        vectors: vectors
+            .iter()
+            .map(|(hash, vector)| (hash.clone(), encode_vector(vector)))
+            .collect(),
+    };
+    let content = serde_yaml::to_string(&data)?;
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    fs::write(path, content)
+        .with_context(|| format!("Failed to save embeddings cache to '{}'", path.display()))?;
+    Ok(())
+}
+
+fn rag_paths() -> Vec<PathBuf> {
+    let mut rag_paths = list_yaml_files(&Config::rags_dir());
+    if let Ok(rd) = read_dir(Config::agents_data_dir()) {
+        for entry in rd.flatten() {
+            rag_paths.extend(list_yaml_files(&entry.path()));
+        }
+    }
+    rag_paths
+}
+
+/// The hashes of the chunks each embedding model has in the saved RAGs. Fails when a RAG can't
+/// be read, as pruning without it would drop embeddings still in use. YAML files that aren't
+/// RAGs, such as agent configs, are skipped.
+fn referenced_hashes(rag_paths: &[PathBuf]) -> Result<HashMap<String, HashSet<String>>> {
+    let mut output: HashMap<String, HashSet<String>> = HashMap::new();
+    for path in rag_paths {
+        let err = || format!("Failed to load RAG at '{}'", path.display());
+        let content = fs::read_to_string(path).with_context(err)?;
+        let value: serde_yaml::Value = serde_yaml::from_str(&content).with_context(err)?;
+        if value.get("embedding_model").is_none() {
+            continue;
+        }
+        let data: RagData = serde_yaml::from_value(value).with_context(err)?;
+        let hashes = output.entry(data.embedding_model).or_default();
+        for file in data.files.values() {
+            for document in &file.documents {
+                hashes.insert(sha256(&document.page_content));
+            }
+        }
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embedding_cache_saves_on_drop() {
+        let path = temp_file("-embedding-cache", ".yaml");
+        let mut cache = EmbeddingCache::open(path.clone(), "fake:e1").unwrap();
+        cache.insert("chunk", vec![0.5, 0.25]);
+        drop(cache);
+        let cache = EmbeddingCache::open(path.clone(), "fake:e1").unwrap();
+        assert_eq!(cache.get("chunk"), Some(&vec![0.5, 0.25]));
+        assert_eq!(cache.get("other"), None);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_referenced_hashes() {
+        let dir = temp_file("-rags", "");
+        fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, content: &str| {
+            let path = dir.join(name);
+            fs::write(&path, content).unwrap();
+            path
+        };
+        let agent_config = write("config.yaml", "model: openai:gpt-4o\n");
+        let rag = write(
+            "docs.yaml",
+            "embedding_model: fake:e1\nchunk_size: 100\nchunk_overlap: 0\nreranker_model: null\ntop_k: 4\nbatch_size: null\nnext_file_id: 0\ndocument_paths: []\nfiles: {}\nvectors: {}\n",
+        );
+        let referenced = referenced_hashes(&[agent_config.clone(), rag.clone()]).unwrap();
+        assert!(referenced["fake:e1"].is_empty());
+        let broken = write("broken.yaml", "embedding_model: fake:e1\nfiles: [\n");
+        assert!(referenced_hashes(&[agent_config, rag, broken]).is_err());
+        assert!(referenced_hashes(&[dir.join("missing.yaml")]).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,313 @@
+use super::*;
+
+/// Questions with the documents that answer them, read from the `--eval` file:
+///
+/// ```yaml
+/// top_k: [1, 3, 5]                   # optional, the cutoffs to report recall at
+/// questions:
+///   - question: How do I turn off streaming?
+///     sources: [docs/config.md]      # the files holding the answer, or the end of their paths
+///     answer: "stream: false"        # optional, text the reply has to contain
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct RagEvalSet {
+    #[serde(default)]
+    pub top_k: Vec<usize>,
+    pub questions: Vec<RagEvalCase>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RagEvalCase {
+    pub question: String,
+    #[serde(default)]
+    pub sources: Vec<String>,
+    pub answer: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RagEvalReport {
+    pub rag: String,
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+    pub top_k: usize,
+    pub reranker_model: Option<String>,
+    pub questions: usize,
+    /// The share of expected sources found in the first `k` results, averaged over questions
+    pub recall: Vec<RagRecall>,
+    pub answer_hits: usize,
+    pub answers: usize,
+    /// Questions with expected sources or answers missing at the RAG's `top_k`
+    pub misses: Vec<RagEvalMiss>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RagRecall {
+    pub k: usize,
+    pub recall: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RagEvalMiss {
+    pub question: String,
+    pub missing_sources: Vec<String>,
+    pub retrieved: Vec<String>,
+    pub answer_missed: bool,
+}
+
+const DEFAULT_EVAL_TOP_K: [usize; 4] = [1, 3, 5, 10];
+
+impl Rag {
+    /// Runs the questions of an eval file against the RAG, checking which expected sources come
+    /// back at each cutoff and, for questions with an `answer`, whether the reply contains it.
+    pub async fn evaluate(
+        &self,
+        config: &GlobalConfig,
+        path: &str,
+        abort_signal: AbortSignal,
+    ) -> Result<RagEvalReport> {
+        let content =
+            fs::read_to_string(path).with_context(|| format!("Failed to read '{path}'"))?;
+        let set: RagEvalSet = serde_yaml::from_str(&content)
+            .with_context(|| format!("Invalid eval file '{path}'"))?;
+        if set.questions.is_empty() {
+            bail!("No questions in '{path}'");
+        }
+        abortable_run_with_spinner(self.evaluate_set(config, set), "Evaluating", abort_signal).await
+    }
+
+    async fn evaluate_set(&self, config: &GlobalConfig, set: RagEvalSet) -> Result<RagEvalReport> {
+        let (reranker_model, top_k) = self.get_config();
+        let filter = config.read().rag_filter.clone();
+        let cutoffs = eval_cutoffs(&set.top_k, top_k);
+        let max_k = cutoffs.last().copied().unwrap_or(top_k);
+        let mut recall_sums = vec![0.0; cutoffs.len()];
+        let mut recall_count = 0;
+        let (mut answer_hits, mut answers) = (0, 0);
+        let mut misses = vec![];
+        for case in &set.questions {
+            let results = self
+                .hybird_search(
+                    &case.question,
+                    max_k,
+                    reranker_model.as_deref(),
+                    filter.as_ref(),
+                )
+                .await?;
+            let paths: Vec<&str> = results
+                .iter()
+                .filter_map(|(id, _)| self.document_path(*id))
+                .collect();
+            if !case.sources.is_empty() {
+                recall_count += 1;
+                for (sum, k) in recall_sums.iter_mut().zip(&cutoffs) {
+                    let found = found_sources(&case.sources, &paths[..paths.len().min(*k)]);
+                    *sum += found.len() as f64 / case.sources.len() as f64;
+                }
+            }
+            let retrieved = &paths[..paths.len().min(top_k)];
+            let found = found_sources(&case.sources, retrieved);
+            let missing_sources: Vec<String> = case
+                .sources
+                .iter()
+                .filter(|v| !found.contains(v.as_str()))
+                .cloned()
+                .collect();
+            let mut answer_missed = false;
+            if let Some(answer) = &case.answer {
+                let context: Vec<&str> = results
+                    .iter()
+                    .take(top_k)
+                    .map(|(_, content)| content.as_str())
+                    .collect();
+                let mut input = Input::from_str(config, &case.question, None);
+                input.set_patched_text(config.read().rag_template(
+                    self,
+                    &context.join("\n\n"),
+                    &case.question,
+                ));
+                let reply = input.fetch_chat_text().await?;
+                answers += 1;
+                if reply.to_lowercase().contains(&answer.to_lowercase()) {
+                    answer_hits += 1;
+                } else {
+                    answer_missed = true;
+                }
+            }
+            if !missing_sources.is_empty() || answer_missed {
+                let retrieved: IndexSet<String> = retrieved.iter().map(|v| v.to_string()).collect();
+                misses.push(RagEvalMiss {
+                    question: case.question.clone(),
+                    missing_sources,
+                    retrieved: retrieved.into_iter().collect(),
+                    answer_missed,
+                });
+            }
+        }
+        let recall = cutoffs
+            .into_iter()
+            .zip(recall_sums)
+            .map(|(k, sum)| RagRecall {
+                k,
+                recall: match recall_count {
+                    0 => 0.0,
+                    _ => sum / recall_count as f64,
+                },
+            })
+            .collect();
+        Ok(RagEvalReport {
+            rag: self.name.clone(),
+            chunk_size: self.data.chunk_size,
+            chunk_overlap: self.data.chunk_overlap,
+            top_k,
+            reranker_model,
+            questions: set.questions.len(),
+            recall,
+            answer_hits,
+            answers,
+            misses,
+        })
+    }
+}
+
+impl RagEvalReport {
+    pub fn render(&self) -> String {
+        let mut lines = vec![
+            format!(
+                "RAG '{}': chunk_size {}, chunk_overlap {}, top_k {}, reranker {}",
+                self.rag,
+                self.chunk_size,
+                self.chunk_overlap,
+                self.top_k,
+                self.reranker_model.as_deref().unwrap_or("none")
+            ),
+            format!("{} questions", self.questions),
+            String::new(),
+        ];
+        for RagRecall { k, recall } in &self.recall {
+            let label = format!("recall@{k}");
+            let note = match *k == self.top_k {
+                true => "  (top_k)",
+                false => "",
+            };
+            lines.push(format!("{label:<12}{}{note}", format_percent(*recall)));
+        }
+        if self.answers > 0 {
+            lines.push(format!(
+                "{:<12}{} ({}/{})",
+                "answers",
+                format_percent(self.answer_hits as f64 / self.answers as f64),
+                self.answer_hits,
+                self.answers
+            ));
+        }
+        if !self.misses.is_empty() {
+            lines.push(String::new());
+            lines.push(format!("Missed at top_k {}:", self.top_k));
+            for miss in &self.misses {
+                lines.push(format!("- {}", miss.question));
+                if !miss.missing_sources.is_empty() {
+                    lines.push(format!("  missing: {}", miss.missing_sources.join(", ")));
+                    lines.push(format!("  retrieved: {}", miss.retrieved.join(", ")));
+                }
+                if miss.answer_missed {
+                    lines.push("  the answer doesn't contain the expected text".into());
+                }
+            }
+        }
+        let hints = self.hints();
+        if !hints.is_empty() {
+            lines.push(String::new());
+            lines.extend(hints.into_iter().map(|v| format!("Hint: {v}")));
+        }
+        lines.join("\n")
+    }
+
+    /// Suggests a `top_k` from how recall grows with the cutoff.
+    fn hints(&self) -> Vec<String> {
+        let mut hints = vec![];
+        let recall_at = |k: usize| self.recall.iter().find(|v| v.k == k).map(|v| v.recall);
+        let Some(current) = recall_at(self.top_k) else {
+            return hints;
+        };
+        let best = self.recall.iter().map(|v| v.recall).fold(0.0, f64::max);
+        if best - current > 0.05 {
+            if let Some(better) = self.recall.iter().find(|v| v.recall >= best) {
+                hints.push(format!(
+                    "top_k {} finds {} of the expected sources, against {} at top_k {}",
+                    better.k,
+                    format_percent(better.recall),
+                    format_percent(current),
+                    self.top_k
+                ));
+            }
+        } else if let Some(smaller) = self
+            .recall
+            .iter()
+            .find(|v| v.k < self.top_k && v.recall >= current)
+        {
+            hints.push(format!(
+                "top_k {} finds as many of the expected sources as top_k {}, with less context",
+                smaller.k, self.top_k
+            ));
+        }
+        if best < 0.8 {
+            hints.push(
+                "sources are missed at every cutoff; rebuild with another chunk size and compare"
+                    .into(),
+            );
+        }
+        hints
+    }
+}
+
+/// The cutoffs to report recall at, always including the RAG's `top_k`.
+fn eval_cutoffs(top_k: &[usize], rag_top_k: usize) -> Vec<usize> {
+    let top_k = match top_k.is_empty() {
+        true => &DEFAULT_EVAL_TOP_K[..],
+        false => top_k,
+    };
+    let mut cutoffs: Vec<usize> = top_k.iter().copied().filter(|v| *v > 0).collect();
+    cutoffs.push(rag_top_k);
+    cutoffs.sort_unstable();
+    cutoffs.dedup();
+    cutoffs
+}
+
+/// The expected sources among the retrieved paths. A source matches a path that equals it or
+/// ends with it, so `config.md` finds `/home/me/docs/config.md`.
+fn found_sources<'a>(sources: &'a [String], paths: &[&str]) -> HashSet<&'a str> {
+    sources
+        .iter()
+        .map(|v| v.as_str())
+        .filter(|source| {
+            let source = source.trim_start_matches("./");
+            paths
+                .iter()
+                .any(|path| *path == source || path.ends_with(&format!("/{source}")))
+        })
+        .collect()
+}
+
+fn format_percent(value: f64) -> String {
+    format!("{:.1}%", value * 100.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eval_cutoffs() {
+        assert_eq!(eval_cutoffs(&[], 4), [1, 3, 4, 5, 10]);
+        assert_eq!(eval_cutoffs(&[5, 0, 2], 5), [2, 5]);
+    }
+
+    #[test]
+    fn test_found_sources() {
+        let sources = vec!["docs/config.md".to_string(), "./intro.md".to_string()];
+        let found = found_sources(&sources, &["/home/me/docs/config.md", "/home/me/dintro.md"]);
+        assert_eq!(found, HashSet::from(["docs/config.md"]));
+        let found = found_sources(&sources, &["https://example.com/intro.md"]);
+        assert_eq!(found, HashSet::from(["./intro.md"]));
+    }
+}
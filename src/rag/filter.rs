@@ -0,0 +1,220 @@
+use super::*;
+
+use globset::{GlobBuilder, GlobMatcher};
+use std::fmt;
+
+pub const EXTENSION_KEY: &str = "extension";
+pub const MTIME_KEY: &str = "mtime";
+pub const TAGS_KEY: &str = "tags";
+
+/// Filtered searches fetch this many times `top_k` candidates before dropping the filtered out.
+pub const FILTER_OVERFETCH: usize = 10;
+
+/// A query-time restriction on which RAG files may be retrieved, e.g.
+/// `path:docs/api/** ext:md !tag:draft mtime>=2024-06-01`.
+///
+/// Conditions are separated by whitespace and must all hold. `key:glob` matches a glob,
+/// `key>value`, `key>=value`, `key<value` and `key<=value` compare strings, and a leading `!`
+/// negates the condition. Keys are `path`, `ext`, `tag`, `mtime` or any other metadata key.
+#[derive(Debug, Clone)]
+pub struct RagFilter {
+    expr: String,
+    conditions: Vec<Condition>,
+}
+
+#[derive(Debug, Clone)]
+struct Condition {
+    key: String,
+    op: FilterOp,
+    negated: bool,
+}
+
+#[derive(Debug, Clone)]
+enum FilterOp {
+    Glob(GlobMatcher),
+    Gt(String),
+    Ge(String),
+    Lt(String),
+    Le(String),
+}
+
+impl RagFilter {
+    pub fn parse(expr: &str) -> Result<Self> {
+        let mut conditions = vec![];
+        for term in expr.split_whitespace() {
+            let (negated, term) = match term.strip_prefix('!') {
+                Some(term) => (true, term),
+                None => (false, term),
+            };
+            let Some(index) = term.find([':', '<', '>']) else {
+                bail!("Invalid filter '{term}', expected <key>:<glob> or <key><op><value>");
+            };
+            let (key, rest) = term.split_at(index);
+            let key = match key {
+                "ext" => EXTENSION_KEY,
+                "tag" => TAGS_KEY,
+                _ => key,
+            };
+            if key.is_empty() {
+                bail!("Invalid filter '{term}', missing key");
+            }
+            let op = if let Some(pattern) = rest.strip_prefix(':') {
+                FilterOp::Glob(build_glob(key, pattern)?)
+            } else if let Some(value) = rest.strip_prefix(">=") {
+                FilterOp::Ge(value.to_string())
+            } else if let Some(value) = rest.strip_prefix("<=") {
+                FilterOp::Le(value.to_string())
+            } else if let Some(value) = rest.strip_prefix('>') {
+                FilterOp::Gt(value.to_string())
+            } else if let Some(value) = rest.strip_prefix('<') {
+                FilterOp::Lt(value.to_string())
+            } else {
+                bail!("Invalid filter '{term}'");
+            };
+            conditions.push(Condition {
+                key: key.to_string(),
+                op,
+                negated,
+            });
+        }
+        if conditions.is_empty() {
+            bail!("Empty filter");
+        }
+        Ok(Self {
+            expr: expr.trim().to_string(),
+            conditions,
+        })
+    }
+
+    pub fn matches(&self, path: &str, metadata: &DocumentMetadata) -> bool {
+        self.conditions.iter().all(|condition| {
+            let values: Vec<&str> = match condition.key.as_str() {
+                "path" => vec![path],
+                EXTENSION_KEY => metadata
+                    .get(EXTENSION_KEY)
+                    .map(|v| v.as_str())
+                    .or_else(|| Path::new(path).extension().and_then(|v| v.to_str()))
+                    .into_iter()
+                    .collect(),
+                TAGS_KEY => metadata
+                    .get(TAGS_KEY)
+                    .map(|v| v.split(',').map(|v| v.trim()).collect())
+                    .unwrap_or_default(),
+                key => metadata.get(key).map(|v| v.as_str()).into_iter().collect(),
+            };
+            let matched = values.iter().any(|value| condition.op.matches(value));
+            matched != condition.negated
+        })
+    }
+}
+
+impl fmt::Display for RagFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.expr)
+    }
+}
+
+impl FilterOp {
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            FilterOp::Glob(matcher) => matcher.is_match(value),
+            FilterOp::Gt(v) => value > v.as_str(),
+            FilterOp::Ge(v) => value >= v.as_str(),
+            FilterOp::Lt(v) => value < v.as_str(),
+            FilterOp::Le(v) => value <= v.as_str(),
+        }
+    }
+}
+
+/// Relative path globs match anywhere below the RAG's document roots, so `docs/api/**`
+/// matches `/home/me/project/docs/api/index.md`.
+pub(super) fn build_glob(key: &str, pattern: &str) -> Result<GlobMatcher> {
+    let is_path = key == "path";
+    let pattern = if is_path
+        && !pattern.starts_with(['/', '*'])
+        && !pattern.contains("://")
+        && !Path::new(pattern).is_absolute()
+    {
+        format!("**/{pattern}")
+    } else {
+        pattern.to_string()
+    };
+    let glob = GlobBuilder::new(&pattern)
+        .literal_separator(is_path)
+        .case_insensitive(!is_path)
+        .build()
+        .with_context(|| format!("Invalid glob '{pattern}'"))?;
+    Ok(glob.compile_matcher())
+}
+
+/// Reads `tags` from a markdown front matter block as a comma-separated list.
+pub fn front_matter_tags(contents: &str) -> Option<String> {
+    let rest = contents.strip_prefix("---\n")?;
+    let end = rest.find("\n---")?;
+    let front_matter: Value = serde_yaml::from_str(&rest[..end]).ok()?;
+    let tags: Vec<String> = match front_matter.get("tags")? {
+        Value::String(v) => v.split(',').map(|v| v.trim().to_string()).collect(),
+        Value::Array(list) => list
+            .iter()
+            .filter_map(|v| match v {
+                Value::String(v) => Some(v.clone()),
+                Value::Number(v) => Some(v.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => return None,
+    };
+    let tags: Vec<String> = tags.into_iter().filter(|v| !v.is_empty()).collect();
+    if tags.is_empty() {
+        None
+    } else {
+        Some(tags.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(pairs: &[(&str, &str)]) -> DocumentMetadata {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_filter_path() {
+        let filter = RagFilter::parse("path:docs/api/**").unwrap();
+        let empty = DocumentMetadata::new();
+        assert!(filter.matches("/home/me/project/docs/api/v1/users.md", &empty));
+        assert!(!filter.matches("/home/me/project/docs/guide/intro.md", &empty));
+        let filter = RagFilter::parse("path:docs/*.md").unwrap();
+        assert!(!filter.matches("/project/docs/api/users.md", &empty));
+    }
+
+    #[test]
+    fn test_filter_metadata() {
+        let meta = metadata(&[("tags", "api,draft"), ("mtime", "2024-06-15T08:00:00Z")]);
+        let path = "/project/notes.MD";
+        assert!(RagFilter::parse("ext:md tag:api")
+            .unwrap()
+            .matches(path, &meta));
+        assert!(!RagFilter::parse("!tag:draft").unwrap().matches(path, &meta));
+        assert!(RagFilter::parse("mtime>=2024-06-01")
+            .unwrap()
+            .matches(path, &meta));
+        assert!(!RagFilter::parse("mtime<2024-06-01")
+            .unwrap()
+            .matches(path, &meta));
+        assert!(!RagFilter::parse("author:*").unwrap().matches(path, &meta));
+        assert!(RagFilter::parse("path").is_err());
+    }
+
+    #[test]
+    fn test_front_matter_tags() {
+        let contents = "---\ntitle: Users\ntags: [api, v1]\n---\n# Users\n";
+        assert_eq!(front_matter_tags(contents), Some("api,v1".into()));
+        assert_eq!(front_matter_tags("# No front matter"), None);
+    }
+}
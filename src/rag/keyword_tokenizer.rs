@@ -0,0 +1,113 @@
+use bm25::{DefaultTokenizer, Language, Tokenizer};
+
+/// BM25 tokenizer that keeps identifiers and error codes searchable.
+///
+/// The default tokenizer stems words and splits on most punctuation, so `ERR_CONN_RESET`,
+/// `E0502` or `std::io::Error` either lose their exact form or never match their parts. This
+/// one emits every identifier-like term verbatim, lowercased and unstemmed, along with the
+/// stemmed parts of its snake_case, camelCase and dotted segments.
+#[derive(Debug)]
+pub struct KeywordTokenizer {
+    inner: DefaultTokenizer,
+}
+
+impl Default for KeywordTokenizer {
+    fn default() -> Self {
+        Self {
+            inner: DefaultTokenizer::new(Language::English),
+        }
+    }
+}
+
+impl Tokenizer for KeywordTokenizer {
+    fn tokenize(&self, input_text: &str) -> Vec<String> {
+        let mut tokens = self.inner.tokenize(input_text);
+        for term in identifier_terms(input_text) {
+            let parts = split_identifier(term);
+            if parts.len() > 1 {
+                tokens.extend(self.inner.tokenize(&parts.join(" ")));
+            }
+            tokens.push(term.to_lowercase());
+        }
+        tokens
+    }
+}
+
+/// Whether the text mentions anything that looks like an identifier or error code.
+pub fn has_identifiers(text: &str) -> bool {
+    identifier_terms(text).next().is_some()
+}
+
+fn identifier_terms(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !(c.is_alphanumeric() || is_joiner(c)))
+        .map(|term| term.trim_matches(is_joiner))
+        .filter(|term| is_identifier(term))
+}
+
+fn is_joiner(c: char) -> bool {
+    matches!(c, '_' | '-' | '.' | ':')
+}
+
+fn is_identifier(term: &str) -> bool {
+    if term.chars().count() < 2 {
+        return false;
+    }
+    let has_letter = term.chars().any(|c| c.is_alphabetic());
+    let has_digit = term.chars().any(|c| c.is_ascii_digit());
+    let has_joiner = term.chars().any(|c| matches!(c, '_' | ':' | '.'));
+    let has_inner_upper =
+        term.chars().skip(1).any(|c| c.is_uppercase()) && term.chars().any(|c| c.is_lowercase());
+    (has_letter && (has_digit || has_joiner)) || has_inner_upper
+}
+
+fn split_identifier(term: &str) -> Vec<String> {
+    let mut parts = vec![];
+    for segment in term.split(is_joiner).filter(|v| !v.is_empty()) {
+        let mut part = String::new();
+        let mut prev: Option<char> = None;
+        for c in segment.chars() {
+            if let Some(prev) = prev {
+                if c.is_uppercase() && prev.is_lowercase() {
+                    parts.push(std::mem::take(&mut part));
+                }
+            }
+            part.push(c);
+            prev = Some(c);
+        }
+        parts.push(part);
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identifier_terms() {
+        let terms: Vec<&str> = identifier_terms(
+            "Got ERR_CONN_RESET (E0502) from std::io::Error in parseConfig. Retry later.",
+        )
+        .collect();
+        assert_eq!(
+            terms,
+            ["ERR_CONN_RESET", "E0502", "std::io::Error", "parseConfig"]
+        );
+        assert!(!has_identifiers("How do I retry a request?"));
+    }
+
+    #[test]
+    fn test_split_identifier() {
+        assert_eq!(split_identifier("parseHTTPConfig"), ["parse", "HTTPConfig"]);
+        assert_eq!(split_identifier("std::io::Error"), ["std", "io", "Error"]);
+        assert_eq!(split_identifier("E0502"), ["E0502"]);
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let tokens = KeywordTokenizer::default().tokenize("Failed with ERR_CONN_RESET");
+        assert!(tokens.contains(&"err_conn_reset".to_string()));
+        assert!(tokens.contains(&"conn".to_string()));
+        assert!(tokens.contains(&"reset".to_string()));
+    }
+}
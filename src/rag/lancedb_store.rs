@@ -0,0 +1,229 @@
+use super::*;
+
+#[cfg(feature = "lancedb")]
+use futures_util::TryStreamExt;
+#[cfg(feature = "lancedb")]
+use lancedb::{
+    arrow::{
+        arrow_array::{
+            cast::AsArray,
+            types::{Float32Type, Int64Type},
+            Array, FixedSizeListArray, Int64Array, RecordBatch, StringArray,
+        },
+        arrow_schema::{DataType, Field, Schema},
+    },
+    index::{vector::IvfHnswSqIndexBuilder, Index},
+    query::{ExecutableQuery, QueryBase, Select},
+    table::OptimizeAction,
+    Connection, DistanceType, Table,
+};
+#[cfg(feature = "lancedb")]
+use std::{path::PathBuf, sync::Arc};
+#[cfg(feature = "lancedb")]
+use tokio::sync::OnceCell;
+
+/// Below this many vectors a full scan beats an ANN index, so none is built.
+#[cfg(feature = "lancedb")]
+const LANCEDB_INDEX_MIN_ROWS: usize = 10_000;
+
+#[cfg(feature = "lancedb")]
+const LANCEDB_TABLE: &str = "documents";
+
+/// Opens the LanceDB directory beside the RAG file, e.g. `rags/docs.lance` for `rags/docs.yaml`.
+#[cfg(feature = "lancedb")]
+pub fn open_lancedb_store(rag_path: &Path) -> Result<Box<dyn VectorStore>> {
+    Ok(Box::new(LancedbStore::new(
+        &rag_path.with_extension("lance"),
+    )))
+}
+
+#[cfg(not(feature = "lancedb"))]
+pub fn open_lancedb_store(_rag_path: &Path) -> Result<Box<dyn VectorStore>> {
+    bail!("The lancedb vector store is unavailable; rebuild aichat with `--features lancedb`")
+}
+
+/// Chunks and their vectors in an embedded LanceDB dataset. Once it holds enough vectors it
+/// gets an IVF-HNSW index, which later updates extend instead of rebuilding.
+#[cfg(feature = "lancedb")]
+#[derive(Clone)]
+pub struct LancedbStore {
+    path: PathBuf,
+    conn: Arc<OnceCell<Connection>>,
+}
+
+#[cfg(feature = "lancedb")]
+impl LancedbStore {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            conn: Default::default(),
+        }
+    }
+
+    async fn connection(&self) -> Result<&Connection> {
+        self.conn
+            .get_or_try_init(|| async {
+                lancedb::connect(&self.path.display().to_string())
+                    .execute()
+                    .await
+                    .with_context(|| format!("Failed to open '{}'", self.path.display()))
+            })
+            .await
+    }
+
+    async fn table(&self) -> Result<Option<Table>> {
+        let conn = self.connection().await?;
+        match conn.open_table(LANCEDB_TABLE).execute().await {
+            Ok(table) => Ok(Some(table)),
+            Err(lancedb::Error::TableNotFound { .. }) => Ok(None),
+            Err(err) => {
+                Err(err).with_context(|| format!("Failed to open '{}'", self.path.display()))
+            }
+        }
+    }
+
+    async fn update_index(&self, table: &Table) -> Result<()> {
+        if !table.list_indices().await?.is_empty() {
+            table.optimize(OptimizeAction::All).await?;
+        } else if table.count_rows(None).await? >= LANCEDB_INDEX_MIN_ROWS {
+            let index = IvfHnswSqIndexBuilder::default().distance_type(DistanceType::Cosine);
+            table
+                .create_index(&["vector"], Index::IvfHnswSq(index))
+                .execute()
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "lancedb")]
+fn record_batch(data: &RagData, added: &[(DocumentId, Vec<f32>)]) -> Result<RecordBatch> {
+    let dimensions = added.first().map(|(_, v)| v.len()).unwrap_or_default();
+    let mut ids = vec![];
+    let mut paths = vec![];
+    let mut contents = vec![];
+    let mut vectors = vec![];
+    for (id, vector) in added {
+        let (file_id, _) = id.split();
+        let (Some(file), Some(document)) = (data.files.get(&file_id), data.get(*id)) else {
+            continue;
+        };
+        ids.push(id.0 as i64);
+        paths.push(file.path.clone());
+        contents.push(document.page_content.clone());
+        vectors.push(Some(vector.iter().map(|v| Some(*v)).collect::<Vec<_>>()));
+    }
+    let schema = Schema::new(vec![
+        Field::new("id", DataType::Int64, false),
+        Field::new("path", DataType::Utf8, false),
+        Field::new("content", DataType::Utf8, false),
+        Field::new(
+            "vector",
+            DataType::FixedSizeList(
+                Arc::new(Field::new("item", DataType::Float32, true)),
+                dimensions as i32,
+            ),
+            false,
+        ),
+    ]);
+    let batch = RecordBatch::try_new(
+        Arc::new(schema),
+        vec![
+            Arc::new(Int64Array::from(ids)),
+            Arc::new(StringArray::from(paths)),
+            Arc::new(StringArray::from(contents)),
+            Arc::new(
+                FixedSizeListArray::from_iter_primitive::<Float32Type, _, _>(
+                    vectors,
+                    dimensions as i32,
+                ),
+            ),
+        ],
+    )?;
+    Ok(batch)
+}
+
+#[cfg(feature = "lancedb")]
+#[async_trait::async_trait]
+impl VectorStore for LancedbStore {
+    fn describe(&self) -> String {
+        format!("lancedb ({})", self.path.display())
+    }
+
+    async fn update(
+        &mut self,
+        data: &RagData,
+        deleted: &[DocumentId],
+        added: &[(DocumentId, Vec<f32>)],
+    ) -> Result<()> {
+        let err = || format!("Failed to update '{}'", self.path.display());
+        let table = self.table().await?;
+        if let Some(table) = &table {
+            for chunk in deleted.chunks(1000) {
+                let ids: Vec<String> = chunk.iter().map(|v| v.0.to_string()).collect();
+                let predicate = format!("id IN ({})", ids.join(", "));
+                table.delete(&predicate).await.with_context(err)?;
+            }
+        }
+        if added.is_empty() {
+            return Ok(());
+        }
+        let batch = record_batch(data, added)?;
+        let table = match table {
+            Some(table) => {
+                table.add(batch).execute().await.with_context(err)?;
+                table
+            }
+            None => self
+                .connection()
+                .await?
+                .create_table(LANCEDB_TABLE, batch)
+                .execute()
+                .await
+                .with_context(err)?,
+        };
+        self.update_index(&table).await.with_context(err)?;
+        Ok(())
+    }
+
+    async fn search(&self, queries: &[Vec<f32>], top_k: usize) -> Result<Vec<(DocumentId, f32)>> {
+        let Some(table) = self.table().await? else {
+            return Ok(vec![]);
+        };
+        let err = || format!("Failed to search '{}'", self.path.display());
+        let mut output = vec![];
+        for vector in queries {
+            let batches: Vec<RecordBatch> = table
+                .query()
+                .nearest_to(vector.as_slice())?
+                .distance_type(DistanceType::Cosine)
+                .select(Select::columns(&["id"]))
+                .limit(top_k)
+                .execute()
+                .await
+                .with_context(err)?
+                .try_collect()
+                .await
+                .with_context(err)?;
+            for batch in batches {
+                let (Some(ids), Some(distances)) = (
+                    batch.column_by_name("id"),
+                    batch.column_by_name("_distance"),
+                ) else {
+                    continue;
+                };
+                let ids = ids.as_primitive::<Int64Type>();
+                let distances = distances.as_primitive::<Float32Type>();
+                for index in 0..ids.len() {
+                    let id = DocumentId(ids.value(index) as usize);
+                    output.push((id, 1.0 - distances.value(index)));
+                }
+            }
+        }
+        Ok(output)
+    }
+
+    fn boxed_clone(&self, _data: &RagData) -> Box<dyn VectorStore> {
+        Box::new(self.clone())
+    }
+}
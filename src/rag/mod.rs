@@ -1,32 +1,91 @@
+use self::sources::list_source_files;
 use self::splitter::*;
 
 use crate::client::*;
 use crate::config::*;
 use crate::utils::*;
 
+mod composite;
+mod compression;
+mod embedding_cache;
+mod eval;
+mod filter;
+mod keyword_tokenizer;
+mod lancedb_store;
+mod ocr;
+mod overrides;
+mod pack;
+mod pgvector_store;
+mod prune;
+mod query;
+mod query_expansion;
+mod recency;
 mod serde_vectors;
+mod sources;
 mod splitter;
+mod sqlite_store;
+mod stats;
+mod summaries;
+mod vector_store;
+mod watch;
+
+pub use self::composite::*;
+pub use self::embedding_cache::EmbeddingCache;
+pub use self::filter::*;
+pub use self::keyword_tokenizer::*;
+pub use self::lancedb_store::*;
+pub use self::overrides::RagOverrides;
+pub use self::pgvector_store::*;
+pub use self::prune::*;
+pub use self::query_expansion::QueryExpansion;
+pub use self::sources::RagSourceFilter;
+pub use self::sqlite_store::*;
+pub use self::summaries::RagSummary;
+pub use self::vector_store::*;
+pub use self::watch::*;
 
 use anyhow::{anyhow, bail, Context, Result};
-use bm25::{Language, SearchEngine, SearchEngineBuilder};
+use bm25::{SearchEngine, SearchEngineBuilder};
+use futures_util::{stream, StreamExt};
 use hnsw_rs::prelude::*;
 use indexmap::{IndexMap, IndexSet};
 use inquire::{required, validator::Validation, Confirm, Select, Text};
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::{collections::HashMap, env, fmt::Debug, fs, hash::Hash, path::Path, time::Duration};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    fmt::Debug,
+    fs,
+    hash::Hash,
+    path::Path,
+    time::{Duration, Instant},
+};
 use tokio::time::sleep;
 
+/// How `sync_documents` treats the files already in the RAG.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RagRefresh {
+    /// Keep them and only load the paths not added yet
+    Off,
+    /// Load them again, except local files unchanged since they were indexed
+    Changed,
+    /// Load all of them again
+    All,
+}
+
 pub struct Rag {
     config: GlobalConfig,
     name: String,
     path: String,
     embedding_model: Model,
-    hnsw: Hnsw<'static, f32, DistCosine>,
-    bm25: SearchEngine<DocumentId>,
+    store: Box<dyn VectorStore>,
+    bm25: KeywordIndex,
     data: RagData,
     last_sources: RwLock<Option<String>>,
+    members: Vec<RagMember>,
+    overrides: RagOverrides,
 }
 
 impl Debug for Rag {
@@ -36,6 +95,7 @@ impl Debug for Rag {
             .field("path", &self.path)
             .field("embedding_model", &self.embedding_model)
             .field("data", &self.data)
+            .field("members", &self.member_names())
             .finish()
     }
 }
@@ -47,10 +107,15 @@ impl Clone for Rag {
             name: self.name.clone(),
             path: self.path.clone(),
             embedding_model: self.embedding_model.clone(),
-            hnsw: self.data.build_hnsw(),
-            bm25: self.data.build_bm25(),
+            store: self.store.boxed_clone(&self.data),
+            bm25: match self.is_composite() {
+                true => empty_keyword_index(),
+                false => self.data.build_bm25(),
+            },
             data: self.data.clone(),
             last_sources: RwLock::new(None),
+            members: self.members.clone(),
+            overrides: self.overrides.clone(),
         }
     }
 }
@@ -68,11 +133,25 @@ impl Rag {
         }
         println!("⚙ Initializing RAG...");
         let (embedding_model, chunk_size, chunk_overlap) = Self::create_config(config)?;
-        let (reranker_model, top_k) = {
+        let (
+            reranker_model,
+            top_k,
+            vector_store,
+            query_expansion,
+            query_expansion_model,
+            recency_half_life,
+        ) = {
             let config = config.read();
-            (config.rag_reranker_model.clone(), config.rag_top_k)
+            (
+                config.rag_reranker_model.clone(),
+                config.rag_top_k,
+                config.rag_vector_store.clone(),
+                config.rag_query_expansion,
+                config.rag_query_expansion_model.clone(),
+                config.rag_recency_half_life,
+            )
         };
-        let data = RagData::new(
+        let mut data = RagData::new(
             embedding_model.id(),
             chunk_size,
             chunk_overlap,
@@ -80,6 +159,14 @@ impl Rag {
             top_k,
             embedding_model.max_batch_size(),
         );
+        if name != TEMP_RAG_NAME {
+            data.vector_store = vector_store;
+        }
+        data.query_expansion = query_expansion;
+        data.query_expansion_model = query_expansion_model;
+        data.recency_half_life = recency_half_life;
+        data.source_filter = Some(RagSourceFilter::from_config(&config.read()));
+        data.summary_model = config.read().rag_summary_model.clone();
         let mut rag = Self::create(config, name, save_path, data)?;
         let mut paths = doc_paths.to_vec();
         if paths.is_empty() {
@@ -88,7 +175,7 @@ impl Rag {
         let loaders = config.read().document_loaders.clone();
         let (spinner, spinner_rx) = Spinner::create("");
         abortable_run_with_spinner_rx(
-            rag.sync_documents(&paths, true, loaders, Some(spinner)),
+            rag.sync_documents(&paths, RagRefresh::All, loaders, Some(spinner)),
             spinner_rx,
             abort_signal,
         )
@@ -100,14 +187,29 @@ impl Rag {
     }
 
     pub fn load(config: &GlobalConfig, name: &str, path: &Path) -> Result<Self> {
+        if name.contains(',') {
+            let rags = name.split(',').map(|v| v.trim().to_string()).collect();
+            let definition = CompositeRagData {
+                rags,
+                top_k: None,
+                reranker_model: None,
+            };
+            return Self::combine(config, name, path, definition);
+        }
         let err = || format!("Failed to load rag '{name}' at '{}'", path.display());
         let content = fs::read_to_string(path).with_context(err)?;
-        let data: RagData = serde_yaml::from_str(&content).with_context(err)?;
+        let data: RagData = match serde_yaml::from_str(&content) {
+            Ok(data) => data,
+            Err(data_err) => match serde_yaml::from_str::<CompositeRagData>(&content) {
+                Ok(definition) => return Self::combine(config, name, path, definition),
+                Err(_) => return Err(data_err).with_context(err),
+            },
+        };
         Self::create(config, name, path, data)
     }
 
     pub fn create(config: &GlobalConfig, name: &str, path: &Path, data: RagData) -> Result<Self> {
-        let hnsw = data.build_hnsw();
+        let store = open_vector_store(config, name, path, &data)?;
         let bm25 = data.build_bm25();
         let embedding_model =
             Model::retrieve_model(&config.read(), &data.embedding_model, ModelType::Embedding)?;
@@ -117,9 +219,11 @@ impl Rag {
             path: path.display().to_string(),
             data,
             embedding_model,
-            hnsw,
+            store,
             bm25,
             last_sources: RwLock::new(None),
+            members: vec![],
+            overrides: RagOverrides::default(),
         };
         Ok(rag)
     }
@@ -128,13 +232,24 @@ impl Rag {
         &self.data.document_paths
     }
 
+    /// The form `path` takes among the document paths, local paths are made absolute.
+    pub fn document_source(&self, path: &str) -> Result<String> {
+        let path = path.trim();
+        let loaders = self.config.read().document_loaders.clone();
+        if is_url(path) || is_git_path(path) || is_loader_protocol(&loaders, path) {
+            return Ok(path.to_string());
+        }
+        to_absolute_path(&resolve_home_dir(path)).with_context(|| format!("Invalid path '{path}'"))
+    }
+
     pub async fn refresh_document_paths(
         &mut self,
         document_paths: &[String],
-        refresh: bool,
+        refresh: RagRefresh,
         config: &GlobalConfig,
         abort_signal: AbortSignal,
     ) -> Result<()> {
+        self.ensure_documents()?;
         let loaders = config.read().document_loaders.clone();
         let (spinner, spinner_rx) = Spinner::create("");
         abortable_run_with_spinner_rx(
@@ -196,13 +311,55 @@ impl Rag {
     }
 
     pub fn get_config(&self) -> (Option<String>, usize) {
-        (self.data.reranker_model.clone(), self.data.top_k)
+        let reranker_model = match self.overrides.rerank {
+            Some(false) => None,
+            Some(true) => self
+                .data
+                .reranker_model
+                .clone()
+                .or_else(|| self.config.read().rag_reranker_model.clone()),
+            None => self.data.reranker_model.clone(),
+        };
+        (
+            reranker_model,
+            self.overrides.top_k.unwrap_or(self.data.top_k),
+        )
     }
 
     pub fn get_last_sources(&self) -> Option<String> {
         self.last_sources.read().clone()
     }
 
+    /// Lists the chunks as numbered sources, matching the numbers of `cited_context`.
+    pub fn set_last_citations(&self, ids: &[DocumentId]) {
+        let sources: Vec<String> = ids
+            .iter()
+            .enumerate()
+            .filter_map(|(i, id)| Some(format!("[{}] {}", i + 1, self.source_label(*id)?)))
+            .collect();
+        *self.last_sources.write() = (!sources.is_empty()).then(|| sources.join("\n"));
+    }
+
+    /// Numbers the chunks so the model can cite them, e.g. `[1] docs/intro.md:10-42`.
+    pub fn cited_context(&self, results: &[(DocumentId, String)]) -> String {
+        if results.is_empty() {
+            return String::new();
+        }
+        let mut blocks = vec![CITATION_INSTRUCTION.to_string()];
+        for (i, (id, content)) in results.iter().enumerate() {
+            let label = self.source_label(*id).unwrap_or_default();
+            blocks.push(format!("[{}] {label}\n{content}", i + 1));
+        }
+        blocks.join("\n\n")
+    }
+
+    /// Where a chunk came from, with its page range for PDFs or its line range when known.
+    pub fn source_label(&self, id: DocumentId) -> Option<String> {
+        let (file_index, document_index) = id.split();
+        let file = self.data.files.get(&file_index)?;
+        Some(file.source_label(document_index))
+    }
+
     pub fn set_last_sources(&self, ids: &[DocumentId]) {
         let mut sources: IndexMap<String, Vec<String>> = IndexMap::new();
         for id in ids {
@@ -241,14 +398,18 @@ impl Rag {
     }
 
     pub fn save(&self) -> Result<bool> {
-        if self.is_temp() {
+        // A composite RAG named after its members, e.g. `docs,code`, only lives for the session
+        if self.is_temp() || self.name.contains(',') {
             return Ok(false);
         }
         let path = Path::new(&self.path);
         ensure_parent_exists(path)?;
 
-        let content = serde_yaml::to_string(&self.data)
-            .with_context(|| format!("Failed to serde rag '{}'", self.name))?;
+        let content = match self.is_composite() {
+            true => serde_yaml::to_string(&self.composite_data()),
+            false => serde_yaml::to_string(&self.data),
+        }
+        .with_context(|| format!("Failed to serde rag '{}'", self.name))?;
         fs::write(path, content).with_context(|| {
             format!("Failed to save rag '{}' to '{}'", self.name, path.display())
         })?;
@@ -257,6 +418,13 @@ impl Rag {
     }
 
     pub fn export(&self) -> Result<String> {
+        let data = self.export_value();
+        let output = serde_yaml::to_string(&data)
+            .with_context(|| format!("Unable to show info about rag '{}'", self.name))?;
+        Ok(output)
+    }
+
+    pub fn export_value(&self) -> Value {
         let files: Vec<_> = self
             .data
             .files
@@ -268,7 +436,16 @@ impl Rag {
                 })
             })
             .collect();
-        let data = json!({
+        if self.is_composite() {
+            return json!({
+                "path": self.path,
+                "rags": self.member_names(),
+                "reranker_model": self.data.reranker_model,
+                "top_k": self.data.top_k,
+                "files": files,
+            });
+        }
+        json!({
             "path": self.path,
             "embedding_model": self.embedding_model.id(),
             "chunk_size": self.data.chunk_size,
@@ -276,12 +453,16 @@ impl Rag {
             "reranker_model": self.data.reranker_model,
             "top_k": self.data.top_k,
             "batch_size": self.data.batch_size,
+            "query_expansion": self.data.query_expansion,
+            "query_expansion_model": self.data.query_expansion_model,
+            "recency_half_life": self.data.recency_half_life,
+            "source_filter": self.data.source_filter,
+            "summary_model": self.data.summary_model,
+            "summaries": self.data.summaries.len(),
+            "vector_store": self.store.describe(),
             "document_paths": self.data.document_paths,
             "files": files,
-        });
-        let output = serde_yaml::to_string(&data)
-            .with_context(|| format!("Unable to show info about rag '{}'", self.name))?;
-        Ok(output)
+        })
     }
 
     pub fn name(&self) -> &str {
@@ -297,23 +478,74 @@ impl Rag {
         text: &str,
         top_k: usize,
         rerank_model: Option<&str>,
+        filter: Option<&RagFilter>,
         abort_signal: AbortSignal,
     ) -> Result<(String, Vec<DocumentId>)> {
-        let ret = abortable_run_with_spinner(
-            self.hybird_search(text, top_k, rerank_model),
-            "Searching",
-            abort_signal,
-        )
-        .await;
+        let ret = self
+            .search_documents(text, top_k, rerank_model, filter, abort_signal)
+            .await;
         let (ids, documents): (Vec<_>, Vec<_>) = ret?.into_iter().unzip();
         let embeddings = documents.join("\n\n");
         Ok((embeddings, ids))
     }
 
+    pub async fn search_documents(
+        &self,
+        text: &str,
+        top_k: usize,
+        rerank_model: Option<&str>,
+        filter: Option<&RagFilter>,
+        abort_signal: AbortSignal,
+    ) -> Result<Vec<(DocumentId, String)>> {
+        let search = async {
+            let documents = self
+                .hybird_search(text, top_k, rerank_model, filter)
+                .await?;
+            self.compress_documents(text, documents).await
+        };
+        abortable_run_with_spinner(search, "Searching", abort_signal).await
+    }
+
+    pub fn document_path(&self, id: DocumentId) -> Option<&str> {
+        let (file_index, _) = id.split();
+        self.data.files.get(&file_index).map(|v| v.path.as_str())
+    }
+
+    /// Deletes the files the options pick out, then compacts the store. Returns their paths and
+    /// the number of chunks removed.
+    pub async fn prune(&mut self, options: &RagPruneOptions) -> Result<(Vec<String>, usize)> {
+        self.ensure_documents()?;
+        let loaders = self.config.read().document_loaders.clone();
+        let now = std::time::SystemTime::now();
+        let file_ids: Vec<FileId> = self
+            .data
+            .files
+            .iter()
+            .filter(|(_, file)| options.matches(file, &loaders, now))
+            .map(|(file_id, _)| *file_id)
+            .collect();
+        if file_ids.is_empty() {
+            return Ok((vec![], 0));
+        }
+        if file_ids.len() == self.data.files.len() {
+            bail!("Pruning would remove every document of the RAG");
+        }
+        let paths: Vec<String> = file_ids
+            .iter()
+            .map(|v| self.data.files[v].path.clone())
+            .collect();
+        let deleted_ids = self.data.del(file_ids);
+        self.data.document_paths.retain(|v| !paths.contains(v));
+        self.store.update(&self.data, &deleted_ids, &[]).await?;
+        self.store.compact().await?;
+        self.bm25 = self.data.build_bm25();
+        Ok((paths, deleted_ids.len()))
+    }
+
     pub async fn sync_documents(
         &mut self,
         paths: &[String],
-        refresh: bool,
+        refresh: RagRefresh,
         loaders: HashMap<String, String>,
         spinner: Option<Spinner>,
     ) -> Result<()> {
@@ -321,10 +553,19 @@ impl Rag {
             let _ = spinner.set_message(String::new());
         }
         let (document_paths, mut recursive_urls, mut urls, mut protocol_paths, mut local_paths) =
-            resolve_paths(&loaders, paths).await?;
+            resolve_paths(&loaders, paths, &self.source_filter()).await?;
         let mut to_deleted: IndexMap<String, Vec<FileId>> = Default::default();
-        if refresh {
+        let mut num_unchanged = 0;
+        if refresh != RagRefresh::Off {
             for (file_id, file) in &self.data.files {
+                if refresh == RagRefresh::Changed
+                    && local_paths.contains(&file.path)
+                    && is_unchanged(file)
+                {
+                    local_paths.swap_remove(&file.path);
+                    num_unchanged += 1;
+                    continue;
+                }
                 to_deleted
                     .entry(file.hash.clone())
                     .or_default()
@@ -356,7 +597,7 @@ impl Rag {
                             .or_default()
                             .push(*file_id);
                     }
-                } else if is_loader_protocol(&loaders, &file.path) {
+                } else if is_git_path(&file.path) || is_loader_protocol(&loaders, &file.path) {
                     if !match_protocol_path(&file.path) {
                         to_deleted
                             .entry(file.hash.clone())
@@ -380,10 +621,11 @@ impl Rag {
             println!("{}", warning_text(&format!("⚠️ {error}")));
             *has_error = true;
         };
+        let crawler = self.config.read().rag_crawler.clone();
         for start_url in recursive_urls {
             index += 1;
             println!("Load {start_url}** [{index}/{total}]");
-            match load_recursive_url(&loaders, &start_url).await {
+            match load_recursive_url(&loaders, &crawler, &start_url).await {
                 Ok(v) => loaded_documents.extend(v),
                 Err(err) => handle_error(err, &mut has_error),
             }
@@ -399,7 +641,12 @@ impl Rag {
         for protocol_path in protocol_paths {
             index += 1;
             println!("Load {protocol_path} [{index}/{total}]");
-            match load_protocol_path(&loaders, &protocol_path) {
+            let ret = if is_git_path(&protocol_path) {
+                load_git_repo(&loaders, &protocol_path, &Config::git_repos_dir()).await
+            } else {
+                load_protocol_path(&loaders, &protocol_path)
+            };
+            match ret {
                 Ok(v) => loaded_documents.extend(v),
                 Err(err) => handle_error(err, &mut has_error),
             }
@@ -407,7 +654,7 @@ impl Rag {
         for local_path in local_paths {
             index += 1;
             println!("Load {local_path} [{index}/{total}]");
-            match load_file(&loaders, &local_path).await {
+            match self.load_local_file(&loaders, &local_path).await {
                 Ok(v) => loaded_documents.push(v),
                 Err(err) => handle_error(err, &mut has_error),
             }
@@ -445,29 +692,65 @@ impl Rag {
                     } else {
                         file_ids.remove(i);
                     }
+                    num_unchanged += 1;
                     continue;
                 }
             }
             let extension = metadata
                 .swap_remove(EXTENSION_METADATA)
                 .unwrap_or_else(|| DEFAULT_EXTENSION.into());
-            let separator = get_separators(&extension);
-            let splitter = RecursiveCharacterTextSplitter::new(
+            metadata.insert(EXTENSION_KEY.into(), extension.clone());
+            if let Ok(mtime) = fs::metadata(&path).and_then(|v| v.modified()) {
+                let mtime = chrono::DateTime::<chrono::Utc>::from(mtime)
+                    .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+                metadata.insert(MTIME_KEY.into(), mtime);
+            }
+            if let Some(tags) = front_matter_tags(&contents) {
+                metadata.entry(TAGS_KEY.into()).or_insert(tags);
+            }
+            let split_documents = match split_code(
+                &extension,
+                &path,
+                &contents,
                 self.data.chunk_size,
                 self.data.chunk_overlap,
-                &separator,
-            );
-
-            let split_options = SplitterChunkHeaderOptions::default();
-            let document = RagDocument::new(contents);
-            let split_documents = splitter.split_documents(&[document], &split_options);
+            ) {
+                Some(documents) => documents,
+                None => {
+                    let separator = get_separators(&extension);
+                    let splitter = RecursiveCharacterTextSplitter::new(
+                        self.data.chunk_size,
+                        self.data.chunk_overlap,
+                        &separator,
+                    );
+                    let split_options = SplitterChunkHeaderOptions::default();
+                    let document = RagDocument::new(contents);
+                    splitter.split_documents(&[document], &split_options)
+                }
+            };
             rag_files.push(RagFile {
                 hash: hash.clone(),
                 path,
+                metadata,
                 documents: split_documents,
             });
         }
 
+        if refresh != RagRefresh::Off && !self.data.files.is_empty() {
+            let new_paths: HashSet<&str> = rag_files.iter().map(|v| v.path.as_str()).collect();
+            let old_paths: HashSet<&str> = to_deleted
+                .values()
+                .flatten()
+                .map(|v| self.data.files[v].path.as_str())
+                .collect();
+            let num_changed = old_paths.intersection(&new_paths).count();
+            println!(
+                "Unchanged {num_unchanged}, changed {num_changed}, added {}, removed {} files",
+                new_paths.len() - num_changed,
+                old_paths.len() - num_changed,
+            );
+        }
+
         let mut next_file_id = self.data.next_file_id;
         let mut files = vec![];
         let mut document_ids = vec![];
@@ -490,9 +773,14 @@ impl Rag {
                 .await?;
         }
 
+        let vectors: Vec<(DocumentId, Vec<f32>)> =
+            document_ids.into_iter().zip(embeddings).collect();
         let to_delete_file_ids: Vec<_> = to_deleted.values().flatten().copied().collect();
-        self.data.del(to_delete_file_ids);
-        self.data.add(next_file_id, files, document_ids, embeddings);
+        let deleted_ids = self.data.del(to_delete_file_ids);
+        self.data.add(next_file_id, files);
+        if self.store.is_local() {
+            self.data.vectors.extend(vectors.iter().cloned());
+        }
         self.data.document_paths = document_paths.into_iter().collect();
 
         if self.data.files.is_empty() {
@@ -500,8 +788,14 @@ impl Rag {
         }
 
         progress(&spinner, "Building store".into());
-        self.hnsw = self.data.build_hnsw();
+        self.store
+            .update(&self.data, &deleted_ids, &vectors)
+            .await?;
         self.bm25 = self.data.build_bm25();
+        let missing_summaries = self.summary_model().is_some() && self.data.summaries.is_empty();
+        if !deleted_ids.is_empty() || !vectors.is_empty() || missing_summaries {
+            self.build_summaries(&spinner).await?;
+        }
 
         Ok(())
     }
@@ -511,67 +805,158 @@ impl Rag {
         query: &str,
         top_k: usize,
         rerank_model: Option<&str>,
+        filter: Option<&RagFilter>,
     ) -> Result<Vec<(DocumentId, String)>> {
+        let scores = self
+            .scored_search(query, top_k, rerank_model, filter)
+            .await?;
+        let ids = scores.into_iter().map(|(id, _)| id).collect();
+        Ok(self.documents_of(ids))
+    }
+
+    /// The `top_k` best documents with their fused or reranked scores, best first.
+    async fn scored_search(
+        &self,
+        query: &str,
+        top_k: usize,
+        rerank_model: Option<&str>,
+        filter: Option<&RagFilter>,
+    ) -> Result<Vec<(DocumentId, f32)>> {
+        if self.is_composite() {
+            return self
+                .composite_search(query, top_k, rerank_model, filter)
+                .await;
+        }
+        let limit = match filter {
+            Some(_) => top_k * FILTER_OVERFETCH,
+            None => top_k,
+        };
+        let expanded_queries = self.expand_query(query).await?;
+        let (vector_search_ids, keyword_search_ids) =
+            self.search_ids(query, limit, top_k, filter, true).await?;
+        let keyword_weight = if has_identifiers(query) { 1.25 } else { 1.0 };
+        let mut list_of_ids = vec![vector_search_ids, keyword_search_ids];
+        let mut list_of_weights = vec![1.125, keyword_weight];
+        // A hypothetical answer only helps the vector search, rephrasings help both
+        let with_keywords = self.data.query_expansion == Some(QueryExpansion::Variants);
+        for text in &expanded_queries {
+            let (vector_search_ids, keyword_search_ids) = self
+                .search_ids(text, limit, top_k, filter, with_keywords)
+                .await?;
+            list_of_ids.push(vector_search_ids);
+            list_of_weights.push(1.0);
+            if with_keywords {
+                list_of_ids.push(keyword_search_ids);
+                list_of_weights.push(1.0);
+            }
+        }
+        if !self.data.summaries.is_empty() {
+            list_of_ids.push(self.summary_search(query, top_k, filter).await?);
+            list_of_weights.push(1.0);
+        }
+
+        match rerank_model {
+            Some(model_id) => {
+                let ids: IndexSet<DocumentId> = list_of_ids.concat().into_iter().collect();
+                self.rerank(query, ids, model_id, top_k).await
+            }
+            None => {
+                let scores = reciprocal_rank_fusion_scores(list_of_ids, list_of_weights, top_k);
+                let scores = self.rank_by_recency(scores, top_k);
+                debug!("rrf_scores: {scores:?}");
+                Ok(scores)
+            }
+        }
+    }
+
+    /// The `top_k` ids of the vector search and, unless `with_keywords` is off, the keyword
+    /// search for a text, keeping the documents the filter allows.
+    async fn search_ids(
+        &self,
+        text: &str,
+        limit: usize,
+        top_k: usize,
+        filter: Option<&RagFilter>,
+        with_keywords: bool,
+    ) -> Result<(Vec<DocumentId>, Vec<DocumentId>)> {
+        let keyword_search = async {
+            match with_keywords {
+                true => self.keyword_search(text, limit, 0.0).await,
+                false => Ok(vec![]),
+            }
+        };
         let (vector_search_results, keyword_search_results) = tokio::join!(
-            self.vector_search(query, top_k, 0.0),
-            self.keyword_search(query, top_k, 0.0),
+            self.vector_search(text, limit, self.min_score()),
+            keyword_search
         );
+        let is_allowed = |id: &DocumentId| match filter {
+            Some(filter) => self.data.file_matches(*id, filter),
+            None => true,
+        };
 
         let vector_search_results = vector_search_results?;
         debug!("vector_search_results: {vector_search_results:?}",);
-        let vector_search_ids: Vec<DocumentId> =
-            vector_search_results.into_iter().map(|(v, _)| v).collect();
+        let vector_search_ids: Vec<DocumentId> = vector_search_results
+            .into_iter()
+            .map(|(v, _)| v)
+            .filter(is_allowed)
+            .take(top_k)
+            .collect();
 
         let keyword_search_results = keyword_search_results?;
         debug!("keyword_search_results: {keyword_search_results:?}",);
-        let keyword_search_ids: Vec<DocumentId> =
-            keyword_search_results.into_iter().map(|(v, _)| v).collect();
+        let keyword_search_ids: Vec<DocumentId> = keyword_search_results
+            .into_iter()
+            .map(|(v, _)| v)
+            .filter(is_allowed)
+            .take(top_k)
+            .collect();
+        Ok((vector_search_ids, keyword_search_ids))
+    }
 
-        let ids = match rerank_model {
-            Some(model_id) => {
-                let model =
-                    Model::retrieve_model(&self.config.read(), model_id, ModelType::Reranker)?;
-                let client = init_client(&self.config, Some(model))?;
-                let ids: IndexSet<DocumentId> = [vector_search_ids, keyword_search_ids]
-                    .concat()
-                    .into_iter()
-                    .collect();
-                let mut documents = vec![];
-                let mut documents_ids = vec![];
-                for id in ids {
-                    if let Some(document) = self.data.get(id) {
-                        documents_ids.push(id);
-                        documents.push(document.page_content.to_string());
-                    }
-                }
-                let data = RerankData::new(query.to_string(), documents, top_k);
-                let list = client.rerank(&data).await.context("Failed to rerank")?;
-                let ids: Vec<_> = list
-                    .into_iter()
-                    .take(top_k)
-                    .filter_map(|item| documents_ids.get(item.index).cloned())
-                    .collect();
-                debug!("rerank_ids: {ids:?}");
-                ids
-            }
-            None => {
-                let ids = reciprocal_rank_fusion(
-                    vec![vector_search_ids, keyword_search_ids],
-                    vec![1.125, 1.0],
-                    top_k,
-                );
-                debug!("rrf_ids: {ids:?}");
-                ids
+    async fn rerank(
+        &self,
+        query: &str,
+        ids: IndexSet<DocumentId>,
+        model_id: &str,
+        top_k: usize,
+    ) -> Result<Vec<(DocumentId, f32)>> {
+        let model = Model::retrieve_model(&self.config.read(), model_id, ModelType::Reranker)?;
+        let client = init_client(&self.config, Some(model))?;
+        let mut documents = vec![];
+        let mut documents_ids = vec![];
+        for id in ids {
+            if let Some(document) = self.data.get(id) {
+                documents_ids.push(id);
+                documents.push(document.page_content.to_string());
             }
+        }
+        // Older documents may drop out of the top_k once decayed, so rank them all
+        let top_n = match self.data.recency_half_life {
+            Some(_) => documents.len(),
+            None => top_k,
         };
-        let output = ids
+        let data = RerankData::new(query.to_string(), documents, top_n);
+        let list = client.rerank(&data).await.context("Failed to rerank")?;
+        let scores: Vec<_> = list
             .into_iter()
+            .filter_map(|item| {
+                let id = documents_ids.get(item.index)?;
+                Some((*id, item.relevance_score as f32))
+            })
+            .collect();
+        let scores = self.rank_by_recency(scores, top_k);
+        debug!("rerank_scores: {scores:?}");
+        Ok(scores)
+    }
+
+    fn documents_of(&self, ids: Vec<DocumentId>) -> Vec<(DocumentId, String)> {
+        ids.into_iter()
             .filter_map(|id| {
                 let document = self.data.get(id)?;
                 Some((id, document.page_content.clone()))
             })
-            .collect();
-        Ok(output)
+            .collect()
     }
 
     async fn vector_search(
@@ -589,21 +974,11 @@ impl Rag {
         let embeddings_data = EmbeddingsData::new(texts, true);
         let embeddings = self.create_embeddings(embeddings_data, None).await?;
         let output = self
-            .hnsw
-            .parallel_search(&embeddings, top_k, 30)
+            .store
+            .search(&embeddings, top_k)
+            .await?
             .into_iter()
-            .flat_map(|list| {
-                list.into_iter()
-                    .filter_map(|v| {
-                        let score = 1.0 - v.distance;
-                        if score > min_score {
-                            Some((DocumentId(v.d_id), score))
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<Vec<_>>()
-            })
+            .filter(|(_, score)| *score > min_score)
             .collect();
         Ok(output)
     }
@@ -633,6 +1008,41 @@ impl Rag {
         &self,
         data: EmbeddingsData,
         spinner: Option<Spinner>,
+    ) -> Result<EmbeddingsOutput> {
+        if data.query || !self.config.read().rag_embeddings_cache {
+            return self.embed_texts(data, spinner, None).await;
+        }
+        let EmbeddingsData { texts, .. } = data;
+        let mut cache = EmbeddingCache::load(&self.data.embedding_model)?;
+        let missing: IndexSet<String> = texts
+            .iter()
+            .filter(|text| cache.get(text).is_none())
+            .cloned()
+            .collect();
+        if !missing.is_empty() {
+            let missing: Vec<String> = missing.into_iter().collect();
+            let embeddings_data = EmbeddingsData::new(missing, false);
+            // Batches land in the cache as they arrive and are kept even if a later one fails
+            self.embed_texts(embeddings_data, spinner, Some(&mut cache))
+                .await?;
+            cache.save()?;
+        }
+        texts
+            .iter()
+            .map(|text| {
+                cache
+                    .get(text)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("Missing embedding for chunk"))
+            })
+            .collect()
+    }
+
+    async fn embed_texts(
+        &self,
+        data: EmbeddingsData,
+        spinner: Option<Spinner>,
+        mut cache: Option<&mut EmbeddingCache>,
     ) -> Result<EmbeddingsOutput> {
         let embedding_client = init_client(&self.config, Some(self.embedding_model.clone()))?;
         let EmbeddingsData { texts, query } = data;
@@ -650,40 +1060,63 @@ impl Rag {
             }
             None => batch_size.unwrap_or(1),
         };
-        let mut output = vec![];
-        let batch_chunks = texts.chunks(batch_size.max(1));
-        let batch_chunks_len = batch_chunks.len();
         let retry_limit = env::var(get_env_name("embeddings_retry_limit"))
             .ok()
             .and_then(|v| v.parse::<u32>().ok())
             .unwrap_or(2);
-        for (index, texts) in batch_chunks.enumerate() {
-            progress(
-                &spinner,
-                format!("Creating embeddings [{}/{batch_chunks_len}]", index + 1),
-            );
-            let chunk_data = EmbeddingsData {
-                texts: texts.to_vec(),
-                query,
-            };
-            let mut retry = 0;
-            let chunk_output = loop {
-                retry += 1;
-                match embedding_client.embeddings(&chunk_data).await {
-                    Ok(v) => break v,
-                    Err(e) if retry < retry_limit => {
-                        debug!("retry {retry} failed: {e}");
-                        sleep(Duration::from_secs(2u64.pow(retry - 1))).await;
-                        continue;
-                    }
-                    Err(e) => {
-                        return Err(e).with_context(|| {
-                            format!("Failed to create embedding after {retry_limit} attempts")
-                        })?
+        let concurrency = self.config.read().rag_embedding_concurrency.max(1);
+        let embedding_client = &embedding_client;
+        let tasks: Vec<_> = texts
+            .chunks(batch_size.max(1))
+            .map(|texts| {
+                let chunk_data = EmbeddingsData {
+                    texts: texts.to_vec(),
+                    query,
+                };
+                async move {
+                    let mut retry = 0;
+                    loop {
+                        retry += 1;
+                        match embedding_client.embeddings(&chunk_data).await {
+                            Ok(v) => break Ok((chunk_data.texts, v)),
+                            Err(e) if retry < retry_limit => {
+                                debug!("retry {retry} failed: {e}");
+                                sleep(Duration::from_secs(2u64.pow(retry - 1))).await;
+                            }
+                            Err(e) => {
+                                break Err(e).with_context(|| {
+                                    format!(
+                                        "Failed to create embedding after {retry_limit} attempts"
+                                    )
+                                })
+                            }
+                        }
                     }
                 }
-            };
+            })
+            .collect();
+        // `buffered` keeps the batches in order and holds back new requests until one of the
+        // `concurrency` in flight finishes
+        let mut batches = stream::iter(tasks).buffered(concurrency);
+        let total = texts.len();
+        let start = Instant::now();
+        let mut output = Vec::with_capacity(total);
+        progress(&spinner, format!("Creating embeddings [0/{total}]"));
+        while let Some(ret) = batches.next().await {
+            let (texts, chunk_output) = ret?;
+            if let Some(cache) = cache.as_deref_mut() {
+                for (text, vector) in texts.iter().zip(&chunk_output) {
+                    cache.insert(text, vector.clone());
+                }
+            }
             output.extend(chunk_output);
+            progress(
+                &spinner,
+                format!(
+                    "Creating embeddings [{}]",
+                    render_embedding_progress(output.len(), total, start.elapsed())
+                ),
+            );
         }
         Ok(output)
     }
@@ -702,6 +1135,20 @@ pub struct RagData {
     pub files: IndexMap<FileId, RagFile>,
     #[serde(with = "serde_vectors")]
     pub vectors: IndexMap<DocumentId, Vec<f32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vector_store: Option<VectorStoreConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query_expansion: Option<QueryExpansion>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query_expansion_model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recency_half_life: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_filter: Option<RagSourceFilter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary_model: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub summaries: Vec<RagSummary>,
 }
 
 impl Debug for RagData {
@@ -716,6 +1163,13 @@ impl Debug for RagData {
             .field("next_file_id", &self.next_file_id)
             .field("document_paths", &self.document_paths)
             .field("files", &self.files)
+            .field("vector_store", &self.vector_store)
+            .field("query_expansion", &self.query_expansion)
+            .field("query_expansion_model", &self.query_expansion_model)
+            .field("recency_half_life", &self.recency_half_life)
+            .field("source_filter", &self.source_filter)
+            .field("summary_model", &self.summary_model)
+            .field("summaries", &self.summaries.len())
             .finish()
     }
 }
@@ -740,6 +1194,13 @@ impl RagData {
             document_paths: Default::default(),
             files: Default::default(),
             vectors: Default::default(),
+            vector_store: None,
+            query_expansion: None,
+            query_expansion_model: None,
+            recency_half_life: None,
+            source_filter: None,
+            summary_model: None,
+            summaries: vec![],
         }
     }
 
@@ -750,38 +1211,35 @@ impl RagData {
         Some(document)
     }
 
-    pub fn del(&mut self, file_ids: Vec<FileId>) {
+    /// Removes the files and their vectors, returning the ids of their documents.
+    pub fn del(&mut self, file_ids: Vec<FileId>) -> Vec<DocumentId> {
+        let mut document_ids = vec![];
         for file_id in file_ids {
             if let Some(file) = self.files.swap_remove(&file_id) {
                 for (document_index, _) in file.documents.iter().enumerate() {
                     let document_id = DocumentId::new(file_id, document_index);
                     self.vectors.swap_remove(&document_id);
+                    document_ids.push(document_id);
                 }
             }
         }
+        document_ids
     }
 
-    pub fn add(
-        &mut self,
-        next_file_id: FileId,
-        files: Vec<(FileId, RagFile)>,
-        document_ids: Vec<DocumentId>,
-        embeddings: EmbeddingsOutput,
-    ) {
+    pub fn add(&mut self, next_file_id: FileId, files: Vec<(FileId, RagFile)>) {
         self.next_file_id = next_file_id;
         self.files.extend(files);
-        self.vectors
-            .extend(document_ids.into_iter().zip(embeddings));
     }
 
-    pub fn build_hnsw(&self) -> Hnsw<'static, f32, DistCosine> {
-        let hnsw = Hnsw::new(32, self.vectors.len(), 16, 200, DistCosine {});
-        let list: Vec<_> = self.vectors.iter().map(|(k, v)| (v, k.0)).collect();
-        hnsw.parallel_insert(&list);
-        hnsw
+    pub fn file_matches(&self, id: DocumentId, filter: &RagFilter) -> bool {
+        let (file_index, _) = id.split();
+        match self.files.get(&file_index) {
+            Some(file) => filter.matches(&file.path, &file.metadata),
+            None => false,
+        }
     }
 
-    pub fn build_bm25(&self) -> SearchEngine<DocumentId> {
+    pub fn build_bm25(&self) -> KeywordIndex {
         let mut documents = vec![];
         for (file_index, file) in self.files.iter() {
             for (document_index, document) in file.documents.iter().enumerate() {
@@ -789,7 +1247,7 @@ impl RagData {
                 documents.push(bm25::Document::new(id, &document.page_content))
             }
         }
-        SearchEngineBuilder::<DocumentId>::with_documents(Language::English, documents)
+        SearchEngineBuilder::with_tokenizer_and_documents(KeywordTokenizer::default(), documents)
             .k1(1.5)
             .b(0.75)
             .build()
@@ -800,9 +1258,39 @@ impl RagData {
 pub struct RagFile {
     hash: String,
     path: String,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    metadata: DocumentMetadata,
     documents: Vec<RagDocument>,
 }
 
+impl RagFile {
+    fn source_label(&self, document_index: usize) -> String {
+        let lines = self
+            .documents
+            .get(document_index)
+            .and_then(|v| v.metadata.get(LINES_METADATA));
+        let Some((start, end)) = lines.map(|v| v.split_once('-').unwrap_or((v, v))) else {
+            return self.path.clone();
+        };
+        match self.metadata.get(PAGES_METADATA) {
+            Some(pages) => {
+                let page_starts: Vec<usize> =
+                    pages.split(',').filter_map(|v| v.parse().ok()).collect();
+                let page_of = |line: &str| {
+                    let line: usize = line.parse().unwrap_or(1);
+                    page_starts.partition_point(|v| *v <= line).max(1)
+                };
+                match (page_of(start), page_of(end)) {
+                    (start, end) if start == end => format!("{} p.{start}", self.path),
+                    (start, end) => format!("{} p.{start}-{end}", self.path),
+                }
+            }
+            None if start == end => format!("{}:{start}", self.path),
+            None => format!("{}:{start}-{end}", self.path),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RagDocument {
     pub page_content: String,
@@ -829,6 +1317,10 @@ impl Default for RagDocument {
 
 pub type FileId = usize;
 
+const CITATION_INSTRUCTION: &str = "The sources below are numbered. Cite the sources that support each statement inline by their numbers, like [1] or [2][3].";
+
+pub type KeywordIndex = SearchEngine<DocumentId, u32, KeywordTokenizer>;
+
 #[derive(Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct DocumentId(usize);
 
@@ -852,6 +1344,29 @@ impl DocumentId {
         let high = value >> (usize::BITS / 2);
         (high, low)
     }
+
+    /// Reads the `<file>-<chunk>` form ids are written in.
+    pub fn parse(value: &str) -> Option<Self> {
+        let (file_index, document_index) = value.split_once('-')?;
+        Some(Self::new(
+            file_index.parse().ok()?,
+            document_index.parse().ok()?,
+        ))
+    }
+}
+
+impl Serialize for DocumentId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{self:?}"))
+    }
+}
+
+impl<'de> Deserialize<'de> for DocumentId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Self::parse(&value)
+            .ok_or_else(|| serde::de::Error::custom(format!("Invalid document id '{value}'")))
+    }
 }
 
 fn select_embedding_model(models: &[&Model]) -> Result<String> {
@@ -920,7 +1435,9 @@ fn set_chunk_overlay(default_value: usize) -> Result<usize> {
 fn add_documents() -> Result<Vec<String>> {
     let text = Text::new("Add documents:")
         .with_validator(required!("This field is required"))
-        .with_help_message("e.g. file;dir/;dir/**/*.{md,mdx};loader:resource;url;website/**")
+        .with_help_message(
+            "e.g. file;dir/;dir/**/*.{md,mdx};loader:resource;url;website/**;git:repo-url#branch",
+        )
         .prompt()?;
     let paths = text
         .split(';')
@@ -939,6 +1456,7 @@ fn add_documents() -> Result<Vec<String>> {
 async fn resolve_paths<T: AsRef<str>>(
     loaders: &HashMap<String, String>,
     paths: &[T],
+    filter: &RagSourceFilter,
 ) -> Result<(
     IndexSet<String>,
     IndexSet<String>,
@@ -960,7 +1478,7 @@ async fn resolve_paths<T: AsRef<str>>(
                 urls.insert(path.to_string());
             }
             document_paths.insert(path.to_string());
-        } else if is_loader_protocol(loaders, path) {
+        } else if is_git_path(path) || is_loader_protocol(loaders, path) {
             protocol_paths.insert(path.to_string());
             document_paths.insert(path.to_string());
         } else {
@@ -971,7 +1489,7 @@ async fn resolve_paths<T: AsRef<str>>(
             document_paths.insert(absolute_path);
         }
     }
-    let local_paths = expand_glob_paths(&absolute_paths, false).await?;
+    let local_paths = list_source_files(&absolute_paths, filter)?;
     Ok((
         document_paths,
         recursive_urls,
@@ -987,27 +1505,97 @@ fn progress(spinner: &Option<Spinner>, message: String) {
     }
 }
 
-fn reciprocal_rank_fusion(
+/// `done/total` chunks with the rate so far and, once it's known, the time left.
+fn render_embedding_progress(done: usize, total: usize, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs_f64();
+    if done == 0 || secs <= 0.0 {
+        return format!("{done}/{total}");
+    }
+    let rate = done as f64 / secs;
+    let eta = ((total - done) as f64 / rate).ceil() as u64;
+    match eta {
+        0 => format!("{done}/{total}, {rate:.1} chunks/s"),
+        _ => format!(
+            "{done}/{total}, {rate:.1} chunks/s, ETA {}",
+            format_eta(eta)
+        ),
+    }
+}
+
+fn format_eta(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+/// All the fused documents with their scores, best first.
+fn reciprocal_rank_fusion_scores(
     list_of_document_ids: Vec<Vec<DocumentId>>,
     list_of_weights: Vec<f32>,
     top_k: usize,
-) -> Vec<DocumentId> {
+) -> Vec<(DocumentId, f32)> {
     let rrf_k = top_k * 2;
     let mut map: IndexMap<DocumentId, f32> = IndexMap::new();
-    for (document_ids, weight) in list_of_document_ids
-        .into_iter()
-        .zip(list_of_weights.into_iter())
-    {
+    for (document_ids, weight) in list_of_document_ids.into_iter().zip(list_of_weights) {
         for (index, &item) in document_ids.iter().enumerate() {
             *map.entry(item).or_default() += (1.0 / ((rrf_k + index + 1) as f32)) * weight;
         }
     }
     let mut sorted_items: Vec<(DocumentId, f32)> = map.into_iter().collect();
     sorted_items.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
-
     sorted_items
-        .into_iter()
-        .take(top_k)
-        .map(|(v, _)| v)
-        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_embedding_progress() {
+        assert_eq!(
+            render_embedding_progress(0, 100, Duration::from_secs(0)),
+            "0/100"
+        );
+        assert_eq!(
+            render_embedding_progress(20, 100, Duration::from_secs(4)),
+            "20/100, 5.0 chunks/s, ETA 16s"
+        );
+        assert_eq!(
+            render_embedding_progress(10, 10000, Duration::from_secs(10)),
+            "10/10000, 1.0 chunks/s, ETA 2h46m"
+        );
+        assert_eq!(
+            render_embedding_progress(100, 100, Duration::from_secs(4)),
+            "100/100, 25.0 chunks/s"
+        );
+    }
+
+    #[test]
+    fn test_source_label() {
+        let document = |lines: &str| {
+            let mut document = RagDocument::new("");
+            document
+                .metadata
+                .insert(LINES_METADATA.into(), lines.into());
+            document
+        };
+        let mut file = RagFile {
+            hash: String::new(),
+            path: "docs/intro.md".into(),
+            metadata: Default::default(),
+            documents: vec![document("10-42"), document("7-7"), RagDocument::new("")],
+        };
+        assert_eq!(file.source_label(0), "docs/intro.md:10-42");
+        assert_eq!(file.source_label(1), "docs/intro.md:7");
+        assert_eq!(file.source_label(2), "docs/intro.md");
+
+        file.path = "report.pdf".into();
+        file.metadata.insert(PAGES_METADATA.into(), "1,9,20".into());
+        file.documents = vec![document("3-8"), document("5-12"), document("20-31")];
+        assert_eq!(file.source_label(0), "report.pdf p.1");
+        assert_eq!(file.source_label(1), "report.pdf p.1-2");
+        assert_eq!(file.source_label(2), "report.pdf p.3");
+    }
 }
@@ -0,0 +1,159 @@
+use super::*;
+
+const OCR_PROMPT: &str = "Transcribe all the text in the image you're given in reading order, keeping headings, lists and tables as Markdown. Reply with the text only, or with NONE when the image has no text.";
+
+const MAX_CONCURRENT_OCR: usize = 4;
+
+impl Rag {
+    /// Loads a local document, transcribing images and PDFs without a text layer with the
+    /// `rag_ocr_model` when it's set and no document loader handles their extension.
+    pub(super) async fn load_local_file(
+        &self,
+        loaders: &HashMap<String, String>,
+        path: &str,
+    ) -> Result<LoadedDocument> {
+        let Some(model_id) = self.config.read().rag_ocr_model.clone() else {
+            return load_file(loaders, path).await;
+        };
+        let extension = get_patch_extension(path).unwrap_or_default();
+        if loaders.contains_key(&extension) {
+            return load_file(loaders, path).await;
+        }
+        let images = if IMAGE_EXTS.contains(&extension.as_str()) {
+            let bytes = tokio::fs::read(path).await?;
+            vec![image_data_url(&extension, &bytes)?]
+        } else if extension == "pdf" {
+            let bytes = tokio::fs::read(path).await?;
+            match pdf_pages(&bytes) {
+                Ok(pages) => return Ok(paged_document(path, &pages, DEFAULT_EXTENSION)),
+                Err(_) => pdf_page_scans(&bytes)?,
+            }
+        } else {
+            return load_file(loaders, path).await;
+        };
+        let model = Model::retrieve_model(&self.config.read(), &model_id, ModelType::Chat)?;
+        if !model.data().supports_vision {
+            bail!(
+                "Model '{model_id}' doesn't support images, set a vision model as `rag_ocr_model`"
+            );
+        }
+        let tasks: Vec<_> = images
+            .into_iter()
+            .map(|image| {
+                let mut role = Role::new("", OCR_PROMPT);
+                role.set_model(model.clone());
+                let mut input = Input::from_str(&self.config, "Transcribe this image.", Some(role));
+                input.set_medias(vec![image]);
+                async move { input.fetch_chat_text().await }
+            })
+            .collect();
+        let results: Vec<_> = stream::iter(tasks)
+            .buffered(MAX_CONCURRENT_OCR)
+            .collect()
+            .await;
+        let mut pages = vec![];
+        for ret in results {
+            let text = ret.with_context(|| format!("Failed to transcribe '{path}'"))?;
+            let text = text.trim();
+            if text.trim_end_matches('.').eq_ignore_ascii_case("none") {
+                pages.push(String::new());
+            } else {
+                pages.push(text.to_string());
+            }
+        }
+        if pages.iter().all(|v| v.is_empty()) {
+            bail!("No text found in '{path}'");
+        }
+        if extension == "pdf" {
+            return Ok(paged_document(path, &pages, "md"));
+        }
+        Ok(loaded_document(path, pages.join("\n\n"), "md"))
+    }
+}
+
+fn loaded_document(path: &str, contents: String, extension: &str) -> LoadedDocument {
+    let mut metadata: DocumentMetadata = Default::default();
+    metadata.insert(EXTENSION_METADATA.into(), extension.into());
+    LoadedDocument::new(path.into(), contents, metadata)
+}
+
+/// The JPEG images of a scanned PDF as data URLs, page by page.
+fn pdf_page_scans(bytes: &[u8]) -> Result<Vec<String>> {
+    let document = lopdf::Document::load_mem(bytes).context("Unable to parse the PDF")?;
+    let mut images = vec![];
+    for page_id in document.get_pages().into_values() {
+        let Ok(page_images) = document.get_page_images(page_id) else {
+            continue;
+        };
+        for image in page_images {
+            let is_jpeg = image
+                .filters
+                .as_ref()
+                .is_some_and(|v| v.len() == 1 && v[0] == "DCTDecode");
+            if is_jpeg {
+                images.push(image_data_url("jpeg", image.content)?);
+            }
+        }
+    }
+    if images.is_empty() {
+        bail!("No text or JPEG scans found in the PDF; it needs a `pdf` document loader with OCR")
+    }
+    Ok(images)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a one-page PDF showing a JPEG whose bytes are `jpeg`.
+    fn build_scanned_pdf(jpeg: &[u8]) -> Vec<u8> {
+        let draw = "q 612 0 0 792 0 0 cm /Im1 Do Q";
+        let objects: Vec<Vec<u8>> = vec![
+            b"<< /Type /Catalog /Pages 2 0 R >>".to_vec(),
+            b"<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_vec(),
+            b"<< /Type /Page /Parent 2 0 R /MediaBox [0 0 612 792] /Contents 4 0 R /Resources << /XObject << /Im1 5 0 R >> >> >>".to_vec(),
+            format!("<< /Length {} >>\nstream\n{draw}\nendstream", draw.len()).into_bytes(),
+            [
+                format!("<< /Type /XObject /Subtype /Image /Width 1 /Height 1 /ColorSpace /DeviceGray /BitsPerComponent 8 /Filter /DCTDecode /Length {} >>\nstream\n", jpeg.len()).as_bytes(),
+                jpeg,
+                b"\nendstream",
+            ]
+            .concat(),
+        ];
+        let mut pdf = b"%PDF-1.4\n".to_vec();
+        let mut offsets = vec![];
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            pdf.extend_from_slice(format!("{} 0 obj\n", i + 1).as_bytes());
+            pdf.extend_from_slice(object);
+            pdf.extend_from_slice(b"\nendobj\n");
+        }
+        let xref = pdf.len();
+        pdf.extend_from_slice(
+            format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes(),
+        );
+        for offset in offsets {
+            pdf.extend_from_slice(format!("{offset:010} 00000 n \n").as_bytes());
+        }
+        pdf.extend_from_slice(
+            format!(
+                "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{xref}\n%%EOF\n",
+                objects.len() + 1
+            )
+            .as_bytes(),
+        );
+        pdf
+    }
+
+    #[test]
+    fn test_pdf_page_scans() {
+        let jpeg = b"\xff\xd8\xff\xe0fake-jpeg\xff\xd9";
+        let pdf = build_scanned_pdf(jpeg);
+        assert!(pdf_to_text(&pdf).is_err());
+        assert_eq!(
+            pdf_page_scans(&pdf).unwrap(),
+            [image_data_url("jpeg", jpeg).unwrap()]
+        );
+        assert!(pdf_page_scans(b"%PDF-1.4\n").is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use super::*;
+
+/// Search settings given after the RAG name for one invocation, e.g.
+/// `--rag docs:top_k=12:rerank=off`. They aren't saved with the RAG.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RagOverrides {
+    pub top_k: Option<usize>,
+    /// The least similarity a vector search hit needs
+    pub min_score: Option<f32>,
+    /// Turns the reranker off, or on with `rag_reranker_model` when the RAG has none
+    pub rerank: Option<bool>,
+    /// Replaces `rag_template`, read from a file when given as `@path`
+    pub template: Option<String>,
+}
+
+impl RagOverrides {
+    /// Splits `name:key=value:...` into the RAG name and its overrides. A `:` not followed by
+    /// a key belongs to the value before it, so templates may contain colons.
+    pub fn parse(value: &str) -> Result<(&str, Self)> {
+        let Some((name, rest)) = value.split_once(':') else {
+            return Ok((value, Self::default()));
+        };
+        let mut pairs: Vec<String> = vec![];
+        for part in rest.split(':') {
+            match pairs.last_mut() {
+                Some(last) if !starts_with_key(part) => {
+                    last.push(':');
+                    last.push_str(part);
+                }
+                _ => pairs.push(part.to_string()),
+            }
+        }
+        let mut overrides = Self::default();
+        for pair in pairs {
+            let Some((key, value)) = pair.split_once('=') else {
+                bail!("Invalid RAG option '{pair}', use key=value");
+            };
+            let invalid = || format!("Invalid value '{value}' for '{key}'");
+            match key.trim() {
+                "top_k" => {
+                    let top_k: usize = value.trim().parse().with_context(invalid)?;
+                    if top_k == 0 {
+                        bail!("{}", invalid());
+                    }
+                    overrides.top_k = Some(top_k);
+                }
+                "min_score" => {
+                    overrides.min_score = Some(value.trim().parse().with_context(invalid)?)
+                }
+                "rerank" => {
+                    overrides.rerank = match value.trim() {
+                        "on" | "true" => Some(true),
+                        "off" | "false" => Some(false),
+                        _ => bail!("{}, use on or off", invalid()),
+                    }
+                }
+                "template" => {
+                    let template = match value.strip_prefix('@') {
+                        Some(path) => fs::read_to_string(resolve_home_dir(path))
+                            .with_context(|| format!("Failed to read template '{path}'"))?,
+                        None => value.to_string(),
+                    };
+                    overrides.template = Some(template);
+                }
+                _ => bail!("Unknown RAG option '{key}', use top_k, min_score, rerank or template"),
+            }
+        }
+        Ok((name, overrides))
+    }
+}
+
+fn starts_with_key(part: &str) -> bool {
+    match part.split_once('=') {
+        Some((key, _)) => {
+            !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        None => false,
+    }
+}
+
+impl Rag {
+    pub fn set_overrides(&mut self, overrides: RagOverrides) -> Result<()> {
+        if overrides.rerank == Some(true)
+            && self.data.reranker_model.is_none()
+            && self.config.read().rag_reranker_model.is_none()
+        {
+            bail!(
+                "No reranker for RAG '{}', set `rag_reranker_model` first",
+                self.name
+            );
+        }
+        // The members of a composite RAG run the vector searches
+        for member in &mut self.members {
+            member.rag.overrides.min_score = overrides.min_score;
+        }
+        self.overrides = overrides;
+        Ok(())
+    }
+
+    pub fn template_override(&self) -> Option<&str> {
+        self.overrides.template.as_deref()
+    }
+
+    pub(super) fn min_score(&self) -> f32 {
+        self.overrides.min_score.unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rag_overrides_parse() {
+        assert_eq!(
+            RagOverrides::parse("docs").unwrap(),
+            ("docs", RagOverrides::default())
+        );
+        let (name, overrides) = RagOverrides::parse(
+            "docs,code:top_k=12:rerank=off:template=Q: __INPUT__:min_score=0.3",
+        )
+        .unwrap();
+        assert_eq!(name, "docs,code");
+        assert_eq!(
+            overrides,
+            RagOverrides {
+                top_k: Some(12),
+                min_score: Some(0.3),
+                rerank: Some(false),
+                template: Some("Q: __INPUT__".into()),
+            }
+        );
+        assert!(RagOverrides::parse("docs:top_k=0").is_err());
+        assert!(RagOverrides::parse("docs:rerank=maybe").is_err());
+        assert!(RagOverrides::parse("docs:topk=3").is_err());
+        assert!(RagOverrides::parse("docs:12").is_err());
+    }
+}
@@ -0,0 +1,214 @@
+use super::*;
+
+use std::io::{Cursor, Read, Seek, Write};
+use std::path::PathBuf;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+const RAG_PACK_VERSION: u32 = 1;
+const MANIFEST_ENTRY: &str = "manifest.yaml";
+const DATA_ENTRY: &str = "rag.yaml";
+
+/// What a `.ragpack` holds besides the RAG file itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RagPackManifest {
+    pub version: u32,
+    pub name: String,
+    pub embedding_model: String,
+    pub files: usize,
+    pub chunks: usize,
+    pub created_at: String,
+}
+
+impl Rag {
+    /// Writes the chunks, vectors and settings of the RAG to a zip file that `--import` reads
+    /// on another machine. Vectors kept in a remote store are fetched from the embeddings cache
+    /// or embedded again.
+    pub async fn export_pack(&self, path: &Path, abort_signal: AbortSignal) -> Result<()> {
+        if self.is_composite() {
+            bail!(
+                "Can't export the composite RAG '{}', export its members instead",
+                self.name
+            );
+        }
+        let mut data = self.data.clone();
+        data.vector_store = None;
+        if !self.store.is_local() {
+            let (ids, texts): (Vec<DocumentId>, Vec<String>) = data
+                .files
+                .iter()
+                .flat_map(|(file_id, file)| {
+                    file.documents.iter().enumerate().map(|(index, v)| {
+                        (DocumentId::new(*file_id, index), v.page_content.clone())
+                    })
+                })
+                .unzip();
+            let (spinner, spinner_rx) = Spinner::create("");
+            let embeddings = abortable_run_with_spinner_rx(
+                self.create_embeddings(EmbeddingsData::new(texts, false), Some(spinner)),
+                spinner_rx,
+                abort_signal,
+            )
+            .await?;
+            data.vectors = ids.into_iter().zip(embeddings).collect();
+        }
+        let manifest = RagPackManifest {
+            version: RAG_PACK_VERSION,
+            name: self.name.clone(),
+            embedding_model: data.embedding_model.clone(),
+            files: data.files.len(),
+            chunks: data.files.values().map(|v| v.documents.len()).sum(),
+            created_at: now(),
+        };
+        ensure_parent_exists(path)?;
+        let file = fs::File::create(path)
+            .with_context(|| format!("Failed to create '{}'", path.display()))?;
+        write_pack(file, &manifest, &data)
+            .with_context(|| format!("Failed to write '{}'", path.display()))
+    }
+
+    /// Saves the RAG of a `.ragpack` under `name`, or the name it was exported with. The
+    /// vectors go to the `rag_vector_store` of this config. Returns the name and the path of
+    /// the new RAG file.
+    pub async fn import_pack(
+        config: &GlobalConfig,
+        path: &Path,
+        name: Option<&str>,
+    ) -> Result<(String, PathBuf)> {
+        let bytes =
+            fs::read(path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+        let (manifest, mut data) =
+            read_pack(&bytes).with_context(|| format!("Invalid rag pack '{}'", path.display()))?;
+        let name = name.unwrap_or(&manifest.name).to_string();
+        guard_rag_name(&name)?;
+        let save_path = config.read().rag_file(&name);
+        if save_path.exists() {
+            let replace = *IS_STDOUT_TERMINAL
+                && Confirm::new(&format!("RAG '{name}' already exists, replace it?"))
+                    .with_default(false)
+                    .prompt()?;
+            if !replace {
+                bail!("RAG '{name}' already exists");
+            }
+        }
+        Model::retrieve_model(&config.read(), &data.embedding_model, ModelType::Embedding)
+            .with_context(|| {
+                format!(
+                    "The RAG was built with '{}', which has to be configured to search it",
+                    data.embedding_model
+                )
+            })?;
+        data.vector_store = config.read().rag_vector_store.clone();
+        let vectors: Vec<(DocumentId, Vec<f32>)> = match data.vector_store.is_some() {
+            true => data.vectors.drain(..).collect(),
+            false => vec![],
+        };
+        let mut rag = Self::create(config, &name, &save_path, data)?;
+        if !vectors.is_empty() {
+            rag.store.update(&rag.data, &[], &vectors).await?;
+        }
+        rag.save()?;
+        Ok((name, save_path))
+    }
+}
+
+fn write_pack<W: Write + Seek>(
+    writer: W,
+    manifest: &RagPackManifest,
+    data: &RagData,
+) -> Result<()> {
+    let manifest = serde_yaml::to_string(manifest)?;
+    let data = serde_yaml::to_string(data)?;
+    let mut writer = ZipWriter::new(writer);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, contents) in [(MANIFEST_ENTRY, manifest), (DATA_ENTRY, data)] {
+        writer.start_file(name, options)?;
+        writer.write_all(contents.as_bytes())?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+fn read_pack(bytes: &[u8]) -> Result<(RagPackManifest, RagData)> {
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).context("Not a zip file")?;
+    let manifest: RagPackManifest =
+        serde_yaml::from_str(&read_entry(&mut archive, MANIFEST_ENTRY)?)
+            .with_context(|| format!("Invalid '{MANIFEST_ENTRY}'"))?;
+    if manifest.version > RAG_PACK_VERSION {
+        bail!(
+            "Made by a newer aichat (pack version {}), upgrade to import it",
+            manifest.version
+        );
+    }
+    guard_rag_name(&manifest.name).with_context(|| format!("Invalid '{MANIFEST_ENTRY}'"))?;
+    let data: RagData = serde_yaml::from_str(&read_entry(&mut archive, DATA_ENTRY)?)
+        .with_context(|| format!("Invalid '{DATA_ENTRY}'"))?;
+    Ok((manifest, data))
+}
+
+/// Rejects names that would put the RAG file outside the rags dir, such as `../config`.
+fn guard_rag_name(name: &str) -> Result<()> {
+    let path = Path::new(name);
+    if name.is_empty() || path.file_name() != Some(path.as_os_str()) || name.contains(['/', '\\']) {
+        bail!("Invalid RAG name '{name}'");
+    }
+    Ok(())
+}
+
+fn read_entry(archive: &mut ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<String> {
+    let mut file = archive
+        .by_name(name)
+        .with_context(|| format!("Missing '{name}'"))?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)
+        .with_context(|| format!("Invalid '{name}'"))?;
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rag_pack_roundtrip() {
+        let mut data = RagData::new("fake:e1".into(), 500, 20, None, 5, None);
+        data.vectors.insert(DocumentId::new(0, 1), vec![0.5, -0.25]);
+        let mut manifest = RagPackManifest {
+            version: RAG_PACK_VERSION,
+            name: "docs".into(),
+            embedding_model: "fake:e1".into(),
+            files: 0,
+            chunks: 0,
+            created_at: now(),
+        };
+        let mut bytes = Cursor::new(vec![]);
+        write_pack(&mut bytes, &manifest, &data).unwrap();
+        let (read_manifest, read_data) = read_pack(bytes.get_ref()).unwrap();
+        assert_eq!(read_manifest.name, "docs");
+        assert_eq!(read_data.chunk_size, 500);
+        assert_eq!(read_data.vectors, data.vectors);
+
+        manifest.version = RAG_PACK_VERSION + 1;
+        let mut bytes = Cursor::new(vec![]);
+        write_pack(&mut bytes, &manifest, &data).unwrap();
+        assert!(read_pack(bytes.get_ref()).is_err());
+        assert!(read_pack(b"docs: []").is_err());
+
+        manifest.version = RAG_PACK_VERSION;
+        for name in ["../config", "a/b", "..", ""] {
+            manifest.name = name.into();
+            let mut bytes = Cursor::new(vec![]);
+            write_pack(&mut bytes, &manifest, &data).unwrap();
+            assert!(read_pack(bytes.get_ref()).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_guard_rag_name() {
+        assert!(guard_rag_name("docs").is_ok());
+        assert!(guard_rag_name("my.docs").is_ok());
+        assert!(guard_rag_name("../config").is_err());
+        assert!(guard_rag_name("..\\config").is_err());
+        assert!(guard_rag_name("/etc/passwd").is_err());
+        assert!(guard_rag_name(".").is_err());
+    }
+}
@@ -0,0 +1,203 @@
+use super::*;
+
+#[cfg(feature = "pgvector")]
+use std::sync::Arc;
+#[cfg(feature = "pgvector")]
+use tokio::sync::OnceCell;
+#[cfg(feature = "pgvector")]
+use tokio_postgres::Client as PgClient;
+
+#[cfg(feature = "pgvector")]
+pub fn open_pgvector_store(
+    url: &str,
+    password: Option<String>,
+    table: &str,
+) -> Result<Box<dyn VectorStore>> {
+    Ok(Box::new(PgvectorStore::new(url, password, table)?))
+}
+
+#[cfg(not(feature = "pgvector"))]
+pub fn open_pgvector_store(
+    _url: &str,
+    _password: Option<String>,
+    _table: &str,
+) -> Result<Box<dyn VectorStore>> {
+    bail!("The pgvector store is unavailable; rebuild aichat with `--features pgvector`")
+}
+
+/// A table in a Postgres database with the pgvector extension. Rows keep the chunk text and
+/// path beside the vector, so a team can maintain one knowledge base and share its RAG file.
+#[cfg(feature = "pgvector")]
+#[derive(Clone)]
+pub struct PgvectorStore {
+    url: String,
+    password: Option<String>,
+    table: String,
+    client: Arc<OnceCell<PgClient>>,
+}
+
+#[cfg(feature = "pgvector")]
+impl PgvectorStore {
+    pub fn new(url: &str, password: Option<String>, table: &str) -> Result<Self> {
+        let valid = table.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            bail!("Invalid pgvector table '{table}', use letters, digits and underscores");
+        }
+        Ok(Self {
+            url: url.to_string(),
+            password,
+            table: table.to_string(),
+            client: Default::default(),
+        })
+    }
+
+    /// Connects on first use, since stores are opened outside the async runtime.
+    async fn client(&self) -> Result<&PgClient> {
+        self.client
+            .get_or_try_init(|| async {
+                let mut config: tokio_postgres::Config = self
+                    .url
+                    .parse()
+                    .with_context(|| format!("Invalid Postgres url '{}'", self.url))?;
+                if let Some(password) = &self.password {
+                    config.password(password);
+                }
+                let mut roots = rustls::RootCertStore::empty();
+                roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+                let tls = rustls::ClientConfig::builder()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+                let tls = tokio_postgres_rustls::MakeRustlsConnect::new(tls);
+                let (client, connection) = config
+                    .connect(tls)
+                    .await
+                    .with_context(|| format!("Failed to connect to Postgres at '{}'", self.url))?;
+                tokio::spawn(async move {
+                    if let Err(err) = connection.await {
+                        warn!("Postgres connection error: {err}");
+                    }
+                });
+                Ok(client)
+            })
+            .await
+    }
+
+    async fn ensure_table(&self, client: &PgClient, size: usize) -> Result<()> {
+        let table = &self.table;
+        client
+            .batch_execute(&format!(
+                "CREATE EXTENSION IF NOT EXISTS vector;
+                CREATE TABLE IF NOT EXISTS {table} (
+                    id BIGINT PRIMARY KEY,
+                    path TEXT NOT NULL,
+                    content TEXT NOT NULL,
+                    embedding vector({size}) NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS {table}_embedding_idx ON {table}
+                    USING hnsw (embedding vector_cosine_ops);"
+            ))
+            .await
+            .with_context(|| format!("Failed to create the pgvector table '{table}'"))?;
+        Ok(())
+    }
+}
+
+/// pgvector's text form of a vector, e.g. `[0.1,0.2]`.
+#[cfg(feature = "pgvector")]
+fn vector_text(vector: &[f32]) -> String {
+    let values: Vec<String> = vector.iter().map(|v| v.to_string()).collect();
+    format!("[{}]", values.join(","))
+}
+
+#[cfg(feature = "pgvector")]
+#[async_trait::async_trait]
+impl VectorStore for PgvectorStore {
+    fn describe(&self) -> String {
+        format!("pgvector ({})", self.table)
+    }
+
+    async fn update(
+        &mut self,
+        data: &RagData,
+        deleted: &[DocumentId],
+        added: &[(DocumentId, Vec<f32>)],
+    ) -> Result<()> {
+        let table = &self.table;
+        let client = self.client().await?;
+        if let Some((_, first)) = added.first() {
+            self.ensure_table(client, first.len()).await?;
+        }
+        if !deleted.is_empty() {
+            let ids: Vec<i64> = deleted.iter().map(|v| v.0 as i64).collect();
+            client
+                .execute(&format!("DELETE FROM {table} WHERE id = ANY($1)"), &[&ids])
+                .await
+                .with_context(|| format!("Failed to delete from '{table}'"))?;
+        }
+        let statement = client
+            .prepare(&format!(
+                "INSERT INTO {table} (id, path, content, embedding) VALUES ($1, $2, $3, $4::text::vector)
+                ON CONFLICT (id) DO UPDATE SET path = $2, content = $3, embedding = $4::text::vector"
+            ))
+            .await?;
+        for (id, vector) in added {
+            let (file_id, _) = id.split();
+            let (Some(file), Some(document)) = (data.files.get(&file_id), data.get(*id)) else {
+                continue;
+            };
+            client
+                .execute(
+                    &statement,
+                    &[
+                        &(id.0 as i64),
+                        &file.path,
+                        &document.page_content,
+                        &vector_text(vector),
+                    ],
+                )
+                .await
+                .with_context(|| format!("Failed to insert into '{table}'"))?;
+        }
+        Ok(())
+    }
+
+    async fn search(&self, queries: &[Vec<f32>], top_k: usize) -> Result<Vec<(DocumentId, f32)>> {
+        let table = &self.table;
+        let client = self.client().await?;
+        let statement = client
+            .prepare(&format!(
+                "SELECT id, 1 - (embedding <=> $1::text::vector) FROM {table}
+                ORDER BY embedding <=> $1::text::vector LIMIT $2"
+            ))
+            .await
+            .with_context(|| format!("Failed to search '{table}'"))?;
+        let mut output = vec![];
+        for vector in queries {
+            let rows = client
+                .query(&statement, &[&vector_text(vector), &(top_k as i64)])
+                .await
+                .with_context(|| format!("Failed to search '{table}'"))?;
+            for row in rows {
+                let id: i64 = row.get(0);
+                let score: f64 = row.get(1);
+                output.push((DocumentId(id as usize), score as f32));
+            }
+        }
+        Ok(output)
+    }
+
+    fn boxed_clone(&self, _data: &RagData) -> Box<dyn VectorStore> {
+        Box::new(self.clone())
+    }
+
+    async fn compact(&mut self) -> Result<()> {
+        let table = &self.table;
+        let client = self.client().await?;
+        client
+            .batch_execute(&format!("VACUUM ANALYZE {table}"))
+            .await
+            .with_context(|| format!("Failed to compact '{table}'"))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+use super::filter::build_glob;
+use super::*;
+
+use globset::GlobMatcher;
+use std::time::SystemTime;
+
+/// Which files `.prune rag` removes. A file is removed when it meets every given condition.
+#[derive(Debug, Clone, Default)]
+pub struct RagPruneOptions {
+    source: Option<GlobMatcher>,
+    /// Measured from the modification time the source had when it was indexed
+    older_than: Option<Duration>,
+    stale: bool,
+}
+
+impl RagPruneOptions {
+    /// Parses `--source <glob>`, `--older-than <age>` and `--stale`.
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut options = Self::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| anyhow!("Missing the value of '{arg}'"))
+            };
+            match arg.as_str() {
+                "--source" => options.source = Some(build_glob("path", value()?)?),
+                "--older-than" => options.older_than = Some(parse_duration(value()?)?),
+                "--stale" => options.stale = true,
+                _ => bail!("Unknown option '{arg}'"),
+            }
+        }
+        if options.source.is_none() && options.older_than.is_none() && !options.stale {
+            bail!("Nothing to prune, give --source, --older-than or --stale");
+        }
+        Ok(options)
+    }
+
+    pub fn matches(
+        &self,
+        file: &RagFile,
+        loaders: &HashMap<String, String>,
+        now: SystemTime,
+    ) -> bool {
+        if let Some(source) = &self.source {
+            if !source.is_match(&file.path) {
+                return false;
+            }
+        }
+        if let Some(older_than) = self.older_than {
+            let Some(mtime) = indexed_mtime(file) else {
+                return false;
+            };
+            if now.duration_since(mtime).unwrap_or_default() < older_than {
+                return false;
+            }
+        }
+        if self.stale && !is_stale(file, loaders) {
+            return false;
+        }
+        true
+    }
+}
+
+pub(super) fn indexed_mtime(file: &RagFile) -> Option<SystemTime> {
+    let mtime = file.metadata.get(MTIME_KEY)?;
+    let mtime = chrono::DateTime::parse_from_rfc3339(mtime).ok()?;
+    Some(mtime.with_timezone(&chrono::Utc).into())
+}
+
+/// A local file that was deleted or has changed since it was indexed. URLs, git sources and
+/// loader paths are never stale, they can't be checked without fetching them again.
+fn is_stale(file: &RagFile, loaders: &HashMap<String, String>) -> bool {
+    if is_url(&file.path) || is_git_path(&file.path) || is_loader_protocol(loaders, &file.path) {
+        return false;
+    }
+    match fs::metadata(&file.path).and_then(|v| v.modified()) {
+        Ok(modified) => match indexed_mtime(file) {
+            Some(mtime) => modified > mtime + Duration::from_secs(1),
+            None => false,
+        },
+        Err(_) => true,
+    }
+}
+
+/// A local file that still has the modification time it was indexed with, so loading it
+/// again can be skipped.
+pub(super) fn is_unchanged(file: &RagFile) -> bool {
+    match (
+        fs::metadata(&file.path).and_then(|v| v.modified()),
+        indexed_mtime(file),
+    ) {
+        (Ok(modified), Some(mtime)) => modified <= mtime + Duration::from_secs(1),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, mtime: Option<&str>) -> RagFile {
+        let mut metadata = DocumentMetadata::new();
+        if let Some(mtime) = mtime {
+            metadata.insert(MTIME_KEY.into(), mtime.into());
+        }
+        RagFile {
+            hash: String::new(),
+            path: path.into(),
+            metadata,
+            documents: vec![],
+        }
+    }
+
+    #[test]
+    fn test_prune_options() {
+        let args = |v: &str| v.split(' ').map(|v| v.to_string()).collect::<Vec<_>>();
+        let now: SystemTime = chrono::DateTime::parse_from_rfc3339("2024-06-30T00:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc)
+            .into();
+        let loaders = HashMap::new();
+        let old = file("/project/docs/old/a.md", Some("2024-01-01T00:00:00Z"));
+        let new = file("/project/docs/old/b.md", Some("2024-06-20T00:00:00Z"));
+        let other = file("/project/src/main.rs", Some("2024-01-01T00:00:00Z"));
+
+        let options = RagPruneOptions::parse(&args("--source docs/old/**")).unwrap();
+        assert!(options.matches(&old, &loaders, now) && options.matches(&new, &loaders, now));
+        assert!(!options.matches(&other, &loaders, now));
+
+        let options = RagPruneOptions::parse(&args("--source docs/** --older-than 30d")).unwrap();
+        assert!(options.matches(&old, &loaders, now));
+        assert!(!options.matches(&new, &loaders, now));
+        assert!(!options.matches(&other, &loaders, now));
+
+        let options = RagPruneOptions::parse(&args("--stale")).unwrap();
+        assert!(options.matches(&file("/nonexistent/a.md", None), &loaders, now));
+        assert!(!options.matches(&file("https://example.com/a", None), &loaders, now));
+
+        assert!(RagPruneOptions::parse(&[]).is_err());
+        assert!(RagPruneOptions::parse(&args("--older-than")).is_err());
+        assert!(RagPruneOptions::parse(&args("--all")).is_err());
+    }
+
+    #[test]
+    fn test_is_unchanged() {
+        let path = temp_file("-unchanged", ".md");
+        fs::write(&path, "# Notes\n").unwrap();
+        let path_str = path.display().to_string();
+        let mtime =
+            chrono::DateTime::<chrono::Utc>::from(fs::metadata(&path).unwrap().modified().unwrap())
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        assert!(is_unchanged(&file(&path_str, Some(&mtime))));
+        assert!(!is_unchanged(&file(
+            &path_str,
+            Some("2020-01-01T00:00:00Z")
+        )));
+        assert!(!is_unchanged(&file(&path_str, None)));
+        let _ = fs::remove_file(&path);
+        assert!(!is_unchanged(&file(&path_str, Some(&mtime))));
+    }
+}
@@ -0,0 +1,230 @@
+use super::*;
+
+/// A chunk a query matched, as the serve API returns it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RagMatch {
+    /// `<file>-<chunk>`, stable until the RAG is rebuilt
+    pub id: String,
+    pub source: String,
+    /// The reranker's relevance score, or the reciprocal rank fusion score without a reranker
+    pub score: f32,
+    pub content: String,
+    /// The metadata of the file overlaid with that of the chunk
+    pub metadata: DocumentMetadata,
+}
+
+impl Rag {
+    /// Searches like `search_documents`, keeping the score and metadata of each match.
+    pub async fn query(
+        &self,
+        text: &str,
+        filter: Option<&RagFilter>,
+        abort_signal: AbortSignal,
+    ) -> Result<Vec<RagMatch>> {
+        let (reranker_model, top_k) = self.get_config();
+        let search = async {
+            let scores = self
+                .scored_search(text, top_k, reranker_model.as_deref(), filter)
+                .await?;
+            let documents = self.documents_of(scores.iter().map(|(id, _)| *id).collect());
+            let documents = self.compress_documents(text, documents).await?;
+            let scores: HashMap<DocumentId, f32> = scores.into_iter().collect();
+            let matches = documents
+                .into_iter()
+                .filter_map(|(id, content)| self.rag_match(id, scores[&id], content))
+                .collect();
+            Ok(matches)
+        };
+        abortable_run_with_spinner(search, "Searching", abort_signal).await
+    }
+
+    fn rag_match(&self, id: DocumentId, score: f32, content: String) -> Option<RagMatch> {
+        let (file_index, document_index) = id.split();
+        let file = self.data.files.get(&file_index)?;
+        let document = file.documents.get(document_index)?;
+        let mut metadata = file.metadata.clone();
+        metadata.extend(document.metadata.clone());
+        Some(RagMatch {
+            id: format!("{id:?}"),
+            source: file.path.clone(),
+            score,
+            content,
+            metadata,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Cassette;
+
+    use std::sync::Arc;
+
+    fn rag_data(path: &str, chunks: &[(&str, Vec<f32>)]) -> RagData {
+        let mut data = RagData::new("mock:embed".into(), 1500, 75, None, 4, None);
+        let mut metadata: DocumentMetadata = Default::default();
+        metadata.insert(EXTENSION_KEY.into(), "md".into());
+        metadata.insert(LINES_METADATA.into(), "1-100".into());
+        let documents = chunks
+            .iter()
+            .enumerate()
+            .map(|(i, (content, vector))| {
+                data.vectors.insert(DocumentId::new(0, i), vector.clone());
+                let mut document = RagDocument::new(*content);
+                document
+                    .metadata
+                    .insert(LINES_METADATA.into(), format!("{}-{}", i + 1, i + 1));
+                document
+            })
+            .collect();
+        data.files.insert(
+            0,
+            RagFile {
+                hash: sha256(path),
+                path: path.into(),
+                metadata,
+                documents,
+            },
+        );
+        data.document_paths.push(path.into());
+        data.next_file_id = 1;
+        data
+    }
+
+    /// A config with an embedding model whose calls are replayed from a cassette.
+    fn mock_config(dir: &Path, query_embeddings: &[(&str, Vec<f32>)]) -> GlobalConfig {
+        let interactions: Vec<Value> = query_embeddings
+            .iter()
+            .map(|(text, vector)| {
+                json!({
+                    "api": "embeddings",
+                    "model": "mock:embed",
+                    "request": { "texts": [text], "query": true },
+                    "response": { "embeddings": [vector] },
+                })
+            })
+            .collect();
+        let cassette_path = dir.join("cassette.yaml");
+        let cassette = json!({ "version": 1, "interactions": interactions });
+        fs::write(&cassette_path, serde_yaml::to_string(&cassette).unwrap()).unwrap();
+        let mut config = Config::default();
+        config.clients = serde_yaml::from_str(
+            "- type: openai-compatible\n  name: mock\n  models: [{ name: embed, type: embedding }]",
+        )
+        .unwrap();
+        config.cassette =
+            Some(Cassette::replay(&config, &cassette_path.display().to_string()).unwrap());
+        Arc::new(RwLock::new(config))
+    }
+
+    /// Builds the RAG directly, the model list is cached for the process and may not have
+    /// the mock model.
+    fn mock_rag(config: &GlobalConfig, name: &str, data: RagData) -> Rag {
+        let mut embedding_model = Model::new("mock", "embed");
+        embedding_model.data_mut().model_type = ModelType::Embedding.to_string();
+        Rag {
+            config: config.clone(),
+            name: name.into(),
+            path: String::new(),
+            embedding_model,
+            store: Box::new(FileStore::new(&data)),
+            bm25: data.build_bm25(),
+            data,
+            last_sources: RwLock::new(None),
+            members: vec![],
+            overrides: RagOverrides::default(),
+        }
+    }
+
+    #[test]
+    fn test_rag_match() {
+        let dir = temp_file("-rag-match", "");
+        fs::create_dir_all(&dir).unwrap();
+        let config = mock_config(&dir, &[]);
+        let data = rag_data(
+            "guide.md",
+            &[("alpha", vec![1.0, 0.0]), ("beta", vec![0.0, 1.0])],
+        );
+        let rag = mock_rag(&config, "docs", data);
+        let output = rag
+            .rag_match(DocumentId::new(0, 1), 0.5, "beta".into())
+            .unwrap();
+        assert_eq!(output.id, "0-1");
+        assert_eq!(output.source, "guide.md");
+        assert_eq!(output.metadata[EXTENSION_KEY], "md");
+        assert_eq!(output.metadata[LINES_METADATA], "2-2");
+        assert!(rag
+            .rag_match(DocumentId::new(0, 2), 0.5, String::new())
+            .is_none());
+        assert!(rag
+            .rag_match(DocumentId::new(1, 0), 0.5, String::new())
+            .is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rag_match_serialization() {
+        let mut metadata: DocumentMetadata = Default::default();
+        metadata.insert(LINES_METADATA.into(), "3-8".into());
+        let output = RagMatch {
+            id: "1-2".into(),
+            source: "docs/intro.md".into(),
+            score: 0.5,
+            content: "Hello".into(),
+            metadata,
+        };
+        assert_eq!(
+            serde_json::to_value(&output).unwrap(),
+            json!({
+                "id": "1-2",
+                "source": "docs/intro.md",
+                "score": 0.5,
+                "content": "Hello",
+                "metadata": { "lines": "3-8" },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_composite_query() {
+        let dir = temp_file("-rag-query", "");
+        fs::create_dir_all(&dir).unwrap();
+        // Every member embeds the query once
+        let query = ("alpha", vec![1.0, 0.0]);
+        let config = mock_config(&dir, &[query.clone(), query]);
+        let docs = rag_data(
+            "guide.md",
+            &[
+                ("alpha setup", vec![1.0, 0.0]),
+                ("beta notes", vec![0.6, 0.8]),
+            ],
+        );
+        let code = rag_data("main.rs", &[("fn alpha() {}", vec![0.8, 0.6])]);
+        let definition = CompositeRagData {
+            rags: vec!["docs".into(), "code".into()],
+            top_k: Some(3),
+            reranker_model: None,
+        };
+        let members = vec![
+            mock_rag(&config, "docs", docs),
+            mock_rag(&config, "code", code),
+        ];
+        let rag = Rag::combine_members(&config, "docs,code", &dir, definition, members);
+        let output = rag
+            .query("alpha", None, create_abort_signal())
+            .await
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let ids: Vec<&str> = output.iter().map(|v| v.id.as_str()).collect();
+        let sources: Vec<&str> = output.iter().map(|v| v.source.as_str()).collect();
+        let contents: Vec<&str> = output.iter().map(|v| v.content.as_str()).collect();
+        assert_eq!(ids, ["0-0", "1-0", "0-1"]);
+        assert_eq!(sources, ["guide.md", "main.rs", "guide.md"]);
+        assert_eq!(contents, ["alpha setup", "fn alpha() {}", "beta notes"]);
+        // Members are fused by rank, so their best chunks tie ahead of the runner-up
+        assert_eq!(output[0].score, output[1].score);
+        assert!(output[1].score > output[2].score);
+    }
+}
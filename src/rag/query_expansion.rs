@@ -0,0 +1,131 @@
+use super::*;
+
+const HYDE_PROMPT: &str = "Write a short passage, as it would appear in documentation, that answers the question you're given. Make up plausible details where you don't know them. Reply with the passage only.";
+
+const VARIANTS_PROMPT: &str = "Rewrite the question you're given as 3 search queries that use different words for the same need, e.g. the terms the documents likely use. Reply with one query per line and nothing else.";
+
+const MAX_QUERY_VARIANTS: usize = 3;
+
+/// How a RAG rewrites a question with a chat model before searching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryExpansion {
+    /// Also search with the embedding of a hypothetical answer
+    Hyde,
+    /// Also search with rephrasings of the question
+    Variants,
+}
+
+impl QueryExpansion {
+    pub fn parse(value: &str) -> Result<Option<Self>> {
+        match value {
+            "hyde" => Ok(Some(Self::Hyde)),
+            "variants" => Ok(Some(Self::Variants)),
+            "null" | "none" | "" => Ok(None),
+            _ => bail!("Invalid query expansion '{value}', use hyde, variants or null"),
+        }
+    }
+}
+
+impl Rag {
+    pub fn query_expansion_config(&self) -> (Option<QueryExpansion>, Option<String>) {
+        (
+            self.data.query_expansion,
+            self.data.query_expansion_model.clone(),
+        )
+    }
+
+    pub fn set_query_expansion(&mut self, value: Option<QueryExpansion>) -> Result<()> {
+        self.data.query_expansion = value;
+        self.save()?;
+        Ok(())
+    }
+
+    pub fn set_query_expansion_model(&mut self, value: Option<String>) -> Result<()> {
+        self.data.query_expansion_model = value;
+        self.save()?;
+        Ok(())
+    }
+
+    /// The texts to search with besides the question: a hypothetical answer under `hyde`, or
+    /// rephrasings under `variants`. The `query_expansion_model` writes them, the current model
+    /// when unset.
+    pub(super) async fn expand_query(&self, query: &str) -> Result<Vec<String>> {
+        let Some(expansion) = self.data.query_expansion else {
+            return Ok(vec![]);
+        };
+        let model = match &self.data.query_expansion_model {
+            Some(model_id) => {
+                Model::retrieve_model(&self.config.read(), model_id, ModelType::Chat)?
+            }
+            None => self.config.read().current_model().clone(),
+        };
+        let prompt = match expansion {
+            QueryExpansion::Hyde => HYDE_PROMPT,
+            QueryExpansion::Variants => VARIANTS_PROMPT,
+        };
+        let mut role = Role::new("", prompt);
+        role.set_model(model);
+        let text = Input::from_str(&self.config, query, Some(role))
+            .fetch_chat_text()
+            .await
+            .context("Failed to expand the query")?;
+        let texts = match expansion {
+            QueryExpansion::Hyde => vec![text.trim().to_string()],
+            QueryExpansion::Variants => parse_query_variants(&text),
+        };
+        let texts: Vec<String> = texts
+            .into_iter()
+            .filter(|v| !v.is_empty() && v != query)
+            .collect();
+        debug!("expanded_queries: {texts:?}");
+        Ok(texts)
+    }
+}
+
+/// The queries of a `variants` reply, without list markers or quotes.
+fn parse_query_variants(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| {
+            let line = line.trim();
+            let line = match line.find(|c: char| !c.is_ascii_digit()) {
+                Some(index) if index > 0 && line[index..].starts_with(['.', ')']) => {
+                    &line[index + 1..]
+                }
+                _ => line,
+            };
+            let line = line.trim_start_matches(['-', '*']).trim();
+            line.trim_matches('"').trim().to_string()
+        })
+        .filter(|v| !v.is_empty())
+        .take(MAX_QUERY_VARIANTS)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_query_variants() {
+        let text = "1. configure streaming off\n\n- \"disable stream output\"\n* 2024 stream: false setting\n4) one too many";
+        assert_eq!(
+            parse_query_variants(text),
+            [
+                "configure streaming off",
+                "disable stream output",
+                "2024 stream: false setting"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_query_expansion_parse() {
+        assert_eq!(
+            QueryExpansion::parse("hyde").unwrap(),
+            Some(QueryExpansion::Hyde)
+        );
+        assert_eq!(QueryExpansion::parse("null").unwrap(), None);
+        assert!(QueryExpansion::parse("both").is_err());
+    }
+}
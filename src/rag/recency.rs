@@ -0,0 +1,68 @@
+use super::*;
+
+use std::time::SystemTime;
+
+const SECS_PER_DAY: f64 = 86400.0;
+
+impl Rag {
+    pub fn recency_half_life(&self) -> Option<u32> {
+        self.data.recency_half_life
+    }
+
+    pub fn set_recency_half_life(&mut self, value: Option<u32>) -> Result<()> {
+        self.data.recency_half_life = value;
+        self.save()?;
+        Ok(())
+    }
+
+    /// Ranks scored results with each score decayed by the age of its file under the
+    /// `recency_half_life`, keeping the `top_k` best. Files without a modification time, like
+    /// URLs, aren't decayed.
+    pub(super) fn rank_by_recency(
+        &self,
+        mut scores: Vec<(DocumentId, f32)>,
+        top_k: usize,
+    ) -> Vec<(DocumentId, f32)> {
+        if let Some(half_life) = self.data.recency_half_life {
+            let now = SystemTime::now();
+            for (id, score) in scores.iter_mut() {
+                let age = self
+                    .data
+                    .files
+                    .get(&id.split().0)
+                    .and_then(indexed_mtime)
+                    .and_then(|mtime| now.duration_since(mtime).ok());
+                if let Some(age) = age {
+                    *score = decay_score(*score, age.as_secs_f64(), half_life);
+                }
+            }
+            scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+            debug!("recency_scores: {scores:?}");
+        }
+        scores.truncate(top_k);
+        scores
+    }
+}
+
+/// Halves a score for every `half_life` days of age. Negative scores, which some rerankers
+/// return, move further down instead.
+fn decay_score(score: f32, age_secs: f64, half_life: u32) -> f32 {
+    let weight = 0.5_f64.powf(age_secs / SECS_PER_DAY / half_life.max(1) as f64) as f32;
+    match score >= 0.0 {
+        true => score * weight,
+        false => score / weight.max(f32::MIN_POSITIVE),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decay_score() {
+        assert_eq!(decay_score(0.8, 0.0, 30), 0.8);
+        assert!((decay_score(0.8, 30.0 * SECS_PER_DAY, 30) - 0.4).abs() < 1e-6);
+        assert!((decay_score(0.8, 60.0 * SECS_PER_DAY, 30) - 0.2).abs() < 1e-6);
+        assert!((decay_score(-0.5, 30.0 * SECS_PER_DAY, 30) + 1.0).abs() < 1e-6);
+    }
+}
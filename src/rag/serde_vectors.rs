@@ -14,13 +14,7 @@ where
         .iter()
         .map(|(id, vec)| {
             let (h, l) = id.split();
-            let byte_slice = unsafe {
-                std::slice::from_raw_parts(
-                    vec.as_ptr() as *const u8,
-                    vec.len() * std::mem::size_of::<f32>(),
-                )
-            };
-            (format!("{h}-{l}"), STANDARD.encode(byte_slice))
+            (format!("{h}-{l}"), encode_vector(vec))
         })
         .collect();
 
@@ -36,34 +30,41 @@ where
 
     let mut decoded_map = IndexMap::new();
     for (key, base64_str) in encoded_map {
-        let decoded_key: DocumentId = key
-            .split_once('-')
-            .and_then(|(h, l)| {
-                let h = h.parse::<usize>().ok()?;
-                let l = l.parse::<usize>().ok()?;
-                Some(DocumentId::new(h, l))
-            })
+        let decoded_key = DocumentId::parse(&key)
             .ok_or_else(|| de::Error::custom(format!("Invalid key '{key}'")))?;
 
-        let decoded_data = STANDARD.decode(&base64_str).map_err(de::Error::custom)?;
+        let vec_f32 = decode_vector(&base64_str)
+            .ok_or_else(|| de::Error::custom(format!("Invalid vector at '{key}'")))?;
 
-        if decoded_data.len() % std::mem::size_of::<f32>() != 0 {
-            return Err(de::Error::custom(format!("Invalid vector at '{key}'")));
-        }
+        decoded_map.insert(decoded_key, vec_f32);
+    }
+
+    Ok(decoded_map)
+}
 
-        let num_f32s = decoded_data.len() / std::mem::size_of::<f32>();
+pub fn encode_vector(vec: &[f32]) -> String {
+    let byte_slice = unsafe {
+        std::slice::from_raw_parts(vec.as_ptr() as *const u8, std::mem::size_of_val(vec))
+    };
+    STANDARD.encode(byte_slice)
+}
 
-        let mut vec_f32 = vec![0.0f32; num_f32s];
-        unsafe {
-            std::ptr::copy_nonoverlapping(
-                decoded_data.as_ptr(),
-                vec_f32.as_mut_ptr() as *mut u8,
-                decoded_data.len(),
-            );
-        }
+pub fn decode_vector(base64_str: &str) -> Option<Vec<f32>> {
+    let decoded_data = STANDARD.decode(base64_str).ok()?;
 
-        decoded_map.insert(decoded_key, vec_f32);
+    if decoded_data.len() % std::mem::size_of::<f32>() != 0 {
+        return None;
     }
 
-    Ok(decoded_map)
+    let num_f32s = decoded_data.len() / std::mem::size_of::<f32>();
+
+    let mut vec_f32 = vec![0.0f32; num_f32s];
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            decoded_data.as_ptr(),
+            vec_f32.as_mut_ptr() as *mut u8,
+            decoded_data.len(),
+        );
+    }
+    Some(vec_f32)
 }
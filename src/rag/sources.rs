@@ -0,0 +1,164 @@
+use super::*;
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+/// Which files of a directory source a RAG indexes. Globs match paths relative to the
+/// directory, e.g. `**/*.md` or `**/node_modules/**`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RagSourceFilter {
+    /// Index only the files matching one of these, all files when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// Skip the files matching one of these
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    /// Skip the files ignored by `.gitignore`, `.ignore` and `.git/info/exclude`, and `.git`
+    #[serde(default = "default_gitignore")]
+    pub gitignore: bool,
+}
+
+fn default_gitignore() -> bool {
+    true
+}
+
+impl RagSourceFilter {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            include: config.rag_include.clone(),
+            exclude: config.rag_exclude.clone(),
+            gitignore: config.rag_gitignore,
+        }
+    }
+}
+
+impl Rag {
+    /// The filter the RAG was created with, or the current config for older RAGs.
+    pub(super) fn source_filter(&self) -> RagSourceFilter {
+        match &self.data.source_filter {
+            Some(v) => v.clone(),
+            None => RagSourceFilter::from_config(&self.config.read()),
+        }
+    }
+}
+
+/// Lists the files of local sources, which may be files, directories or globs like
+/// `dir/**/*.{md,txt}`. Files in directories must pass the filter, files named directly are
+/// always kept.
+pub(super) fn list_source_files<T: AsRef<str>>(
+    paths: &[T],
+    filter: &RagSourceFilter,
+) -> Result<IndexSet<String>> {
+    let include = build_glob_set(&filter.include)?;
+    let exclude = build_glob_set(&filter.exclude)?;
+    let mut files = IndexSet::new();
+    for path in paths {
+        let (base, suffixes, current_only) = parse_glob(path.as_ref())?;
+        let root = Path::new(&base);
+        if root.is_file() {
+            files.insert(base);
+            continue;
+        }
+        if !root.is_dir() {
+            continue;
+        }
+        let gitignore = filter.gitignore;
+        let walker = ignore::WalkBuilder::new(root)
+            .standard_filters(false)
+            .git_ignore(gitignore)
+            .git_exclude(gitignore)
+            .ignore(gitignore)
+            .parents(gitignore)
+            .require_git(false)
+            .max_depth(current_only.then_some(1))
+            .filter_entry(move |entry| !(gitignore && entry.file_name() == ".git"))
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .build();
+        for entry in walker {
+            let entry = entry.with_context(|| format!("Failed to walk '{base}'"))?;
+            if !entry.file_type().is_some_and(|v| v.is_file()) {
+                continue;
+            }
+            let file_path = entry.path();
+            if let Some(suffixes) = &suffixes {
+                let extension = file_path
+                    .extension()
+                    .map(|v| v.to_string_lossy().to_string());
+                if !extension.is_some_and(|v| suffixes.contains(&v)) {
+                    continue;
+                }
+            }
+            let relative_path = file_path
+                .strip_prefix(root)
+                .unwrap_or(file_path)
+                .to_string_lossy()
+                .replace('\\', "/");
+            if (filter.include.is_empty() || include.is_match(&relative_path))
+                && !exclude.is_match(&relative_path)
+            {
+                files.insert(file_path.display().to_string());
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn build_glob_set(globs: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob).with_context(|| format!("Invalid glob '{glob}'"))?);
+    }
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_list_source_files() {
+        let dir = temp_file("-rag-sources-", "");
+        for path in [
+            "README.md",
+            "docs/guide.md",
+            "docs/notes.txt",
+            "node_modules/pkg/index.md",
+            "target/doc.md",
+            ".git/HEAD",
+        ] {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "text").unwrap();
+        }
+        fs::write(dir.join(".gitignore"), "target/\n").unwrap();
+        let root = dir.display().to_string();
+        let list = |filter: &RagSourceFilter, path: &str| -> Vec<String> {
+            list_source_files(&[path], filter)
+                .unwrap()
+                .into_iter()
+                .map(|v| v[root.len() + 1..].replace('\\', "/"))
+                .collect()
+        };
+        let mut filter = RagSourceFilter {
+            include: vec![],
+            exclude: vec!["**/node_modules/**".into()],
+            gitignore: true,
+        };
+        assert_eq!(
+            list(&filter, &root),
+            [".gitignore", "README.md", "docs/guide.md", "docs/notes.txt"]
+        );
+        filter.include = vec!["**/*.md".into()];
+        assert_eq!(list(&filter, &root), ["README.md", "docs/guide.md"]);
+        assert_eq!(
+            list(&filter, &format!("{root}/**/*.txt")),
+            Vec::<String>::new()
+        );
+        filter.include = vec![];
+        filter.gitignore = false;
+        assert_eq!(
+            list(&filter, &format!("{root}/**/*.md")),
+            ["README.md", "docs/guide.md", "target/doc.md"]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+use super::{get_separators, RagDocument, RecursiveCharacterTextSplitter, LINES_METADATA};
+
+use crate::utils::{get_ts_language, indent_signature};
+
+use tree_sitter::{Node, Parser};
+
+/// Splits a source file along its syntax tree: whole functions, classes and other top-level
+/// items are packed into chunks, and an item too large for one chunk is split into the members
+/// of its body. Each chunk starts with the file path and the signatures of the items it sits
+/// in, so a method still says which `impl` or class it belongs to.
+///
+/// Returns `None` for languages without a grammar or files that don't parse, which are left to
+/// the character splitter.
+pub fn split_code(
+    extension: &str,
+    path: &str,
+    contents: &str,
+    chunk_size: usize,
+    chunk_overlap: usize,
+) -> Option<Vec<RagDocument>> {
+    let language = get_ts_language(extension)?;
+    let mut parser = Parser::new();
+    parser.set_language(&language).ok()?;
+    let tree = parser.parse(contents, None)?;
+    let root = tree.root_node();
+    if root.has_error() {
+        return None;
+    }
+    let splitter = CodeSplitter {
+        extension,
+        path,
+        source: contents,
+        chunk_size,
+        chunk_overlap,
+    };
+    let mut pieces = vec![];
+    splitter.split_node(root, &[], &mut pieces);
+    Some(splitter.merge_pieces(pieces))
+}
+
+struct CodeSplitter<'a> {
+    extension: &'a str,
+    path: &'a str,
+    source: &'a str,
+    chunk_size: usize,
+    chunk_overlap: usize,
+}
+
+/// A byte range of the source and the signatures of the items enclosing it.
+#[derive(Debug)]
+struct Piece {
+    start: usize,
+    end: usize,
+    scope: Vec<String>,
+}
+
+impl CodeSplitter<'_> {
+    fn split_node(&self, node: Node, scope: &[String], pieces: &mut Vec<Piece>) {
+        let mut start = node.start_byte();
+        let mut cursor = node.walk();
+        for child in node.children(&mut cursor) {
+            let end = child.end_byte();
+            if self.fits(start, end, scope) {
+                self.push_piece(start, end, scope, pieces);
+            } else {
+                let definition = ["definition", "declaration"]
+                    .iter()
+                    .find_map(|v| child.child_by_field_name(v))
+                    .unwrap_or(child);
+                match definition.child_by_field_name("body") {
+                    Some(body) if body.child_count() > 0 => {
+                        // The head, e.g. doc comments and the signature, joins the first member
+                        let mut inner_scope = scope.to_vec();
+                        inner_scope.push(indent_signature(0, definition, self.source.as_bytes()));
+                        self.push_piece(start, body.start_byte(), &inner_scope, pieces);
+                        self.split_node(body, &inner_scope, pieces);
+                        self.push_piece(body.end_byte(), end, &inner_scope, pieces);
+                    }
+                    _ => self.split_text(start, end, scope, pieces),
+                }
+            }
+            start = end;
+        }
+    }
+
+    /// Falls back to the character splitter for a leaf too large for one chunk.
+    fn split_text(&self, start: usize, end: usize, scope: &[String], pieces: &mut Vec<Piece>) {
+        let chunk_size = self
+            .chunk_size
+            .saturating_sub(self.header(scope).len())
+            .max(1);
+        let splitter = RecursiveCharacterTextSplitter::new(
+            chunk_size,
+            self.chunk_overlap,
+            &get_separators(self.extension),
+        );
+        let text = &self.source[start..end];
+        let mut offset = 0;
+        for chunk in splitter.split_text(text) {
+            let Some(index) = text[offset..].find(&chunk).map(|v| v + offset) else {
+                continue;
+            };
+            self.push_piece(start + index, start + index + chunk.len(), scope, pieces);
+            offset = index + chunk.chars().next().map(|v| v.len_utf8()).unwrap_or(1);
+        }
+    }
+
+    fn push_piece(&self, start: usize, end: usize, scope: &[String], pieces: &mut Vec<Piece>) {
+        if self.source[start..end].trim().is_empty() {
+            return;
+        }
+        pieces.push(Piece {
+            start,
+            end,
+            scope: scope.to_vec(),
+        });
+    }
+
+    /// Joins neighbouring pieces of the same scope while they fit in a chunk. Closing brackets
+    /// and other bits without a word in them always join the piece before.
+    fn merge_pieces(&self, pieces: Vec<Piece>) -> Vec<RagDocument> {
+        let mut merged: Vec<Piece> = vec![];
+        for piece in pieces {
+            let wordless = !self.source[piece.start..piece.end]
+                .chars()
+                .any(|v| v.is_alphanumeric());
+            match merged.last_mut() {
+                Some(last)
+                    if last.end == piece.start
+                        && (wordless
+                            || last.scope == piece.scope
+                                && self.fits(last.start, piece.end, &piece.scope)) =>
+                {
+                    last.end = piece.end;
+                }
+                _ => merged.push(piece),
+            }
+        }
+        merged
+            .into_iter()
+            .map(|piece| self.create_document(piece))
+            .collect()
+    }
+
+    fn create_document(&self, piece: Piece) -> RagDocument {
+        let text = &self.source[piece.start..piece.end];
+        let start = piece.start + (text.len() - text.trim_start().len());
+        let text = text.trim();
+        let start_line = self.source[..start].matches('\n').count() + 1;
+        let end_line = start_line + text.matches('\n').count();
+        let mut document = RagDocument::new(format!("{}{text}", self.header(&piece.scope)));
+        document
+            .metadata
+            .insert(LINES_METADATA.into(), format!("{start_line}-{end_line}"));
+        document
+    }
+
+    fn header(&self, scope: &[String]) -> String {
+        let mut header = format!("File: {}\n", self.path);
+        if !scope.is_empty() {
+            header.push_str(&format!("In: {}\n", scope.join(" > ")));
+        }
+        header
+    }
+
+    fn fits(&self, start: usize, end: usize, scope: &[String]) -> bool {
+        self.header(scope).len() + self.source[start..end].trim().len() <= self.chunk_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_split_code() {
+        let contents = r#"use std::fmt;
+
+/// A point.
+pub struct Point {
+    x: i32,
+}
+
+impl Point {
+    pub fn new(x: i32) -> Self {
+        Self { x }
+    }
+
+    pub fn describe(&self) -> String {
+        let label = "point";
+        format!("{label} at {}", self.x)
+    }
+}
+"#;
+        let documents = split_code("rs", "src/point.rs", contents, 140, 0).unwrap();
+        let output: Vec<_> = documents
+            .iter()
+            .map(|v| (v.page_content.as_str(), v.metadata[LINES_METADATA].as_str()))
+            .collect();
+        assert_eq!(
+            output,
+            vec![
+                (
+                    "File: src/point.rs\nuse std::fmt;\n\n/// A point.\npub struct Point {\n    x: i32,\n}",
+                    "1-6"
+                ),
+                (
+                    "File: src/point.rs\nIn: impl Point\nimpl Point {\n    pub fn new(x: i32) -> Self {\n        Self { x }\n    }",
+                    "8-11"
+                ),
+                (
+                    "File: src/point.rs\nIn: impl Point > pub fn describe(&self) -> String\npub fn describe(&self) -> String {\n        let label = \"point\";",
+                    "13-14"
+                ),
+                (
+                    "File: src/point.rs\nIn: impl Point > pub fn describe(&self) -> String\nformat!(\"{label} at {}\", self.x)\n    }\n}",
+                    "15-17"
+                ),
+            ]
+        );
+        assert!(split_code("rs", "broken.rs", "fn main( {", 120, 0).is_none());
+        assert!(split_code("txt", "notes.txt", "hello", 120, 0).is_none());
+    }
+}
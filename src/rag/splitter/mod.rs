@@ -1,11 +1,16 @@
+mod code;
 mod language;
 
+pub use self::code::*;
 pub use self::language::*;
 
 use super::{DocumentMetadata, RagDocument};
 
 pub const DEFAULT_SEPARATES: [&str; 4] = ["\n\n", "\n", " ", ""];
 
+/// Chunk metadata holding the 1-based `start-end` line range of the chunk in its file.
+pub const LINES_METADATA: &str = "lines";
+
 pub fn get_separators(extension: &str) -> Vec<&'static str> {
     match extension {
         "c" | "cc" | "cpp" => Language::Cpp.separators(),
@@ -101,6 +106,7 @@ impl RecursiveCharacterTextSplitter {
         for (i, text) in texts.iter().enumerate() {
             let mut prev_chunk: Option<String> = None;
             let mut index_prev_chunk = -1;
+            let (mut counted_index, mut counted_lines) = (0, 0);
 
             for chunk in self.split_text(text) {
                 let mut page_content = chunk_header.clone();
@@ -126,7 +132,15 @@ impl RecursiveCharacterTextSplitter {
                     }
                 }
 
-                let metadata = metadatas[i].clone();
+                let mut metadata = metadatas[i].clone();
+                if index_chunk >= 0 {
+                    let index = index_chunk as usize;
+                    counted_lines += text[counted_index..index].matches('\n').count();
+                    counted_index = index;
+                    let start_line = counted_lines + 1;
+                    let end_line = start_line + chunk.trim_end().matches('\n').count();
+                    metadata.insert(LINES_METADATA.into(), format!("{start_line}-{end_line}"));
+                }
                 page_content += &chunk;
                 documents.push(RagDocument {
                     page_content,
@@ -311,8 +325,8 @@ mod tests {
     use pretty_assertions::assert_eq;
     use serde_json::{json, Value};
 
-    fn build_metadata(source: &str) -> Value {
-        json!({ "source": source })
+    fn build_metadata(source: &str, lines: &str) -> Value {
+        json!({ "source": source, "lines": lines })
     }
     #[test]
     fn test_split_text() {
@@ -345,20 +359,34 @@ mod tests {
             json!([
                 {
                     "page_content": "foo",
-                    "metadata": build_metadata("1"),
+                    "metadata": build_metadata("1", "1-1"),
                 },
                 {
                     "page_content": "bar",
-                    "metadata": build_metadata("1"),
+                    "metadata": build_metadata("1", "1-1"),
                 },
                 {
                     "page_content": "baz",
-                    "metadata": build_metadata("2"),
+                    "metadata": build_metadata("2", "1-1"),
                 },
             ])
         );
     }
 
+    #[test]
+    fn test_chunk_line_ranges() {
+        let splitter = RecursiveCharacterTextSplitter::new(8, 0, &["\n"]);
+        let output = splitter.split_documents(
+            &[RagDocument::new("foo\nbar\nbaz\nqux\nquux")],
+            &SplitterChunkHeaderOptions::default(),
+        );
+        let lines: Vec<_> = output
+            .iter()
+            .map(|v| v.metadata[LINES_METADATA].as_str())
+            .collect();
+        assert_eq!(lines, vec!["1-2", "3-4", "5-5"]);
+    }
+
     #[test]
     fn test_chunk_header() {
         let splitter = RecursiveCharacterTextSplitter::new(3, 0, &[" "]);
@@ -380,15 +408,15 @@ mod tests {
             json!([
                 {
                     "page_content": "SOURCE NAME: testing\n-----\nfoo",
-                    "metadata": build_metadata("1"),
+                    "metadata": build_metadata("1", "1-1"),
                 },
                 {
                     "page_content": "SOURCE NAME: testing\n-----\n(cont'd) bar",
-                    "metadata": build_metadata("1"),
+                    "metadata": build_metadata("1", "1-1"),
                 },
                 {
                     "page_content": "SOURCE NAME: testing\n-----\nbaz",
-                    "metadata": build_metadata("2"),
+                    "metadata": build_metadata("2", "1-1"),
                 },
             ])
         );
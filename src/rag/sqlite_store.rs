@@ -0,0 +1,140 @@
+use super::*;
+
+#[cfg(feature = "sqlite-vec")]
+use rusqlite::{params, Connection};
+#[cfg(feature = "sqlite-vec")]
+use std::{path::PathBuf, sync::Arc};
+
+/// Opens the SQLite file beside the RAG file, e.g. `rags/docs.sqlite` for `rags/docs.yaml`.
+#[cfg(feature = "sqlite-vec")]
+pub fn open_sqlite_store(rag_path: &Path) -> Result<Box<dyn VectorStore>> {
+    let path = rag_path.with_extension("sqlite");
+    Ok(Box::new(SqliteStore::open(&path)?))
+}
+
+#[cfg(not(feature = "sqlite-vec"))]
+pub fn open_sqlite_store(_rag_path: &Path) -> Result<Box<dyn VectorStore>> {
+    bail!("The sqlite vector store is unavailable; rebuild aichat with `--features sqlite-vec`")
+}
+
+/// The vectors of the chunks in a SQLite file, searched with the sqlite-vec extension. The
+/// chunks themselves stay in the RAG file. Updates only touch the rows of changed documents.
+#[cfg(feature = "sqlite-vec")]
+#[derive(Clone)]
+pub struct SqliteStore {
+    path: PathBuf,
+    conn: Arc<parking_lot::Mutex<Connection>>,
+}
+
+#[cfg(feature = "sqlite-vec")]
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| unsafe {
+            #[allow(clippy::missing_transmute_annotations)]
+            rusqlite::ffi::sqlite3_auto_extension(Some(std::mem::transmute(
+                sqlite_vec::sqlite3_vec_init as *const (),
+            )));
+        });
+        let err = || format!("Failed to open the sqlite store at '{}'", path.display());
+        ensure_parent_exists(path)?;
+        let conn = Connection::open(path).with_context(err)?;
+        // Older stores kept a copy of the chunks that nothing read
+        conn.execute_batch("DROP TABLE IF EXISTS documents;")
+            .with_context(err)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            conn: Arc::new(parking_lot::Mutex::new(conn)),
+        })
+    }
+}
+
+#[cfg(feature = "sqlite-vec")]
+fn has_vectors_table(conn: &Connection) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT count(*) FROM sqlite_master WHERE name = 'vectors'",
+        [],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|v| v > 0)
+}
+
+#[cfg(feature = "sqlite-vec")]
+fn vector_blob(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+#[cfg(feature = "sqlite-vec")]
+#[async_trait::async_trait]
+impl VectorStore for SqliteStore {
+    fn describe(&self) -> String {
+        format!("sqlite ({})", self.path.display())
+    }
+
+    async fn update(
+        &mut self,
+        data: &RagData,
+        deleted: &[DocumentId],
+        added: &[(DocumentId, Vec<f32>)],
+    ) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        if let Some((_, first)) = added.first() {
+            tx.execute_batch(&format!(
+                "CREATE VIRTUAL TABLE IF NOT EXISTS vectors USING vec0(embedding float[{}] distance_metric=cosine);",
+                first.len()
+            ))?;
+        }
+        let with_vectors = has_vectors_table(&tx)?;
+        if with_vectors {
+            for id in deleted.iter().chain(added.iter().map(|(id, _)| id)) {
+                tx.execute("DELETE FROM vectors WHERE rowid = ?1", params![id.0 as i64])?;
+            }
+        }
+        for (id, vector) in added {
+            if data.get(*id).is_none() {
+                continue;
+            }
+            tx.execute(
+                "INSERT INTO vectors (rowid, embedding) VALUES (?1, ?2)",
+                params![id.0 as i64, vector_blob(vector)],
+            )?;
+        }
+        tx.commit()
+            .with_context(|| format!("Failed to update '{}'", self.path.display()))?;
+        Ok(())
+    }
+
+    async fn search(&self, queries: &[Vec<f32>], top_k: usize) -> Result<Vec<(DocumentId, f32)>> {
+        let conn = self.conn.lock();
+        if !has_vectors_table(&conn)? {
+            return Ok(vec![]);
+        }
+        let mut stmt = conn.prepare(
+            "SELECT rowid, distance FROM vectors WHERE embedding MATCH ?1 AND k = ?2 ORDER BY distance",
+        )?;
+        let mut output = vec![];
+        for vector in queries {
+            let rows = stmt.query_map(params![vector_blob(vector), top_k as i64], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, f64>(1)?))
+            })?;
+            for row in rows {
+                let (id, distance) = row?;
+                output.push((DocumentId(id as usize), 1.0 - distance as f32));
+            }
+        }
+        Ok(output)
+    }
+
+    fn boxed_clone(&self, _data: &RagData) -> Box<dyn VectorStore> {
+        Box::new(self.clone())
+    }
+
+    async fn compact(&mut self) -> Result<()> {
+        self.conn
+            .lock()
+            .execute_batch("VACUUM;")
+            .with_context(|| format!("Failed to compact '{}'", self.path.display()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+use super::*;
+
+/// Counts of the files loaded from one document path, or of the whole RAG.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct SourceStats {
+    files: usize,
+    chunks: usize,
+    tokens: usize,
+}
+
+impl SourceStats {
+    fn add(&mut self, other: SourceStats) {
+        self.files += other.files;
+        self.chunks += other.chunks;
+        self.tokens += other.tokens;
+    }
+}
+
+impl Rag {
+    /// Document, chunk and token counts overall and per document path, with the estimated
+    /// cost of embedding the chunks and the size of the index on disk, for `.rag info --stats`.
+    pub fn stats(&self) -> String {
+        let mut sources: IndexMap<String, SourceStats> = self
+            .data
+            .document_paths
+            .iter()
+            .map(|v| (v.clone(), SourceStats::default()))
+            .collect();
+        let mut total = SourceStats::default();
+        for file in self.data.files.values() {
+            let stats = SourceStats {
+                files: 1,
+                chunks: file.documents.len(),
+                tokens: file
+                    .documents
+                    .iter()
+                    .map(|v| estimate_token_length(&v.page_content))
+                    .sum(),
+            };
+            let source = file_source(&self.data.document_paths, &file.path).unwrap_or(&file.path);
+            sources.entry(source.to_string()).or_default().add(stats);
+            total.add(stats);
+        }
+        let model_id = self.embedding_model.id();
+        let cost = match self.embedding_model.data().input_price {
+            Some(price) => format!(
+                "${:.4} ({model_id})",
+                total.tokens as f64 * price / 1_000_000.0
+            ),
+            None => format!("unknown, no input_price for '{model_id}'"),
+        };
+        let mut output = format!(
+            "documents: {}\nchunks: {}\ntokens: {} (estimated)\nembedding_cost: {cost}\ndisk_size: {}\nvector_store: {}\n",
+            total.files,
+            total.chunks,
+            total.tokens,
+            format_size(self.disk_usage()),
+            self.store.describe(),
+        );
+        let width = sources
+            .keys()
+            .map(|v| v.chars().count())
+            .max()
+            .unwrap_or_default()
+            .max("SOURCE".len());
+        output.push_str(&format!(
+            "\n{:<width$}  {:>6}  {:>7}  {:>9}\n",
+            "SOURCE", "FILES", "CHUNKS", "TOKENS"
+        ));
+        for (source, stats) in &sources {
+            output.push_str(&format!(
+                "{source:<width$}  {:>6}  {:>7}  {:>9}\n",
+                stats.files, stats.chunks, stats.tokens
+            ));
+        }
+        output.trim_end().to_string()
+    }
+
+    /// The bytes the RAG file and a SQLite or LanceDB store beside it take, including those
+    /// of the RAGs a composite RAG combines.
+    fn disk_usage(&self) -> u64 {
+        let path = Path::new(&self.path);
+        let mut size = [
+            path.to_path_buf(),
+            path.with_extension("sqlite"),
+            path.with_extension("lance"),
+        ]
+        .iter()
+        .map(|v| path_size(v))
+        .sum();
+        for member in &self.members {
+            size += member.rag.disk_usage();
+        }
+        size
+    }
+}
+
+/// The document path a file was loaded from: the path itself, or the directory, glob, URL
+/// prefix or loader path holding it. The most specific one wins.
+fn file_source<'a>(document_paths: &'a [String], path: &str) -> Option<&'a str> {
+    document_paths
+        .iter()
+        .filter(|source| is_source_of(source, path))
+        .max_by_key(|source| source.len())
+        .map(|v| v.as_str())
+}
+
+fn is_source_of(source: &str, path: &str) -> bool {
+    let recursive = source.ends_with("**");
+    let base = match parse_glob(source) {
+        Ok((base, _, _)) if !is_url(source) => base,
+        _ => source.trim_end_matches('*').to_string(),
+    };
+    let base = base.strip_prefix("./").unwrap_or(&base);
+    match path.strip_prefix(base) {
+        Some(rest) => {
+            rest.is_empty()
+                || recursive
+                || base.ends_with(['/', '\\', ':'])
+                || rest.starts_with(['/', '\\', '#', '?'])
+        }
+        None => false,
+    }
+}
+
+fn path_size(path: &Path) -> u64 {
+    let Ok(metadata) = fs::metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    match fs::read_dir(path) {
+        Ok(entries) => entries.flatten().map(|v| path_size(&v.path())).sum(),
+        Err(_) => 0,
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_source() {
+        let document_paths: Vec<String> = [
+            "/home/u/docs",
+            "/home/u/docs/api/**/*.md",
+            "/home/u/notes.md",
+            "https://example.com/guide/**",
+            "git:https://github.com/u/repo",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        let source = |path: &str| file_source(&document_paths, path);
+        assert_eq!(source("/home/u/docs/intro.md"), Some("/home/u/docs"));
+        assert_eq!(
+            source("/home/u/docs/api/v1/auth.md"),
+            Some("/home/u/docs/api/**/*.md")
+        );
+        assert_eq!(source("/home/u/notes.md"), Some("/home/u/notes.md"));
+        assert_eq!(source("/home/u/docs2/a.md"), None);
+        assert_eq!(
+            source("https://example.com/guide/setup"),
+            Some("https://example.com/guide/**")
+        );
+        assert_eq!(
+            source("git:https://github.com/u/repo/src/main.rs"),
+            Some("git:https://github.com/u/repo")
+        );
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MB");
+    }
+}
@@ -0,0 +1,369 @@
+use super::*;
+
+const SUMMARY_PROMPT: &str = "Summarize the passages you're given in one paragraph. Cover the topics they share and the key facts, names and terms of each, so the summary can stand in for them in a search. Reply with the summary only.";
+
+/// The number of chunks a cluster aims for
+const SUMMARY_CLUSTER_SIZE: usize = 8;
+/// How much of a cluster's text goes into its summary request
+const MAX_SUMMARY_INPUT_CHARS: usize = 16000;
+/// How many summaries a search drills into
+const SUMMARY_SEARCH_TOP_N: usize = 2;
+const MAX_CONCURRENT_SUMMARIES: usize = 4;
+/// The most clusters a RAG is summarized in, larger ones get bigger clusters
+const MAX_SUMMARY_CLUSTERS: usize = 256;
+/// How many vectors the seeds of the clusters are picked from
+const MAX_SEED_CANDIDATES: usize = 2048;
+const KMEANS_ITERATIONS: usize = 20;
+
+/// An LLM-written summary of a cluster of similar chunks. Broad questions match it better
+/// than any one of its chunks, so searching the summaries first finds the chunks that answer
+/// them together.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RagSummary {
+    pub text: String,
+    pub chunks: Vec<DocumentId>,
+    #[serde(with = "serde_vector")]
+    pub vector: Vec<f32>,
+}
+
+impl Rag {
+    /// The summary model the RAG was created with, or the current config for older RAGs.
+    pub(super) fn summary_model(&self) -> Option<String> {
+        self.data
+            .summary_model
+            .clone()
+            .or_else(|| self.config.read().rag_summary_model.clone())
+    }
+
+    /// Clusters the chunks by their vectors and has the summary model summarize each cluster.
+    /// The summaries whose chunks are all still there are kept, so only the new chunks and
+    /// those of changed clusters are summarized again.
+    pub(super) async fn build_summaries(&mut self, spinner: &Option<Spinner>) -> Result<()> {
+        let summaries = std::mem::take(&mut self.data.summaries);
+        let Some(model_id) = self.summary_model() else {
+            return Ok(());
+        };
+        if !self.store.is_local() {
+            warn!(
+                "Skip the summaries of RAG '{}', they need the file vector store",
+                self.name
+            );
+            return Ok(());
+        }
+        let mut kept = unchanged_summaries(summaries, &self.data.vectors);
+        let summarized: HashSet<DocumentId> =
+            kept.iter().flat_map(|v| v.chunks.iter().copied()).collect();
+        let (ids, vectors): (Vec<DocumentId>, Vec<Vec<f32>>) = self
+            .data
+            .vectors
+            .iter()
+            .filter(|(id, _)| !summarized.contains(id))
+            .map(|(id, vector)| (*id, vector.clone()))
+            .unzip();
+        if ids.is_empty() {
+            self.data.summaries = kept;
+            return Ok(());
+        }
+        let num_clusters = ids
+            .len()
+            .div_ceil(SUMMARY_CLUSTER_SIZE)
+            .min(MAX_SUMMARY_CLUSTERS - kept.len());
+        let labels = tokio::task::spawn_blocking(move || {
+            let vectors: Vec<&[f32]> = vectors.iter().map(|v| v.as_slice()).collect();
+            cluster_vectors(&vectors, num_clusters)
+        })
+        .await?;
+        let mut clusters = vec![vec![]; num_clusters];
+        for (id, label) in ids.into_iter().zip(labels) {
+            clusters[label].push(id);
+        }
+        clusters.retain(|v| !v.is_empty());
+        progress(spinner, format!("Summarizing {} clusters", clusters.len()));
+        let model = Model::retrieve_model(&self.config.read(), &model_id, ModelType::Chat)?;
+        let tasks: Vec<_> = clusters
+            .iter()
+            .map(|chunks| {
+                let mut role = Role::new("", SUMMARY_PROMPT);
+                role.set_model(model.clone());
+                let input = Input::from_str(&self.config, &self.cluster_text(chunks), Some(role));
+                async move { input.fetch_chat_text().await }
+            })
+            .collect();
+        let results: Vec<_> = stream::iter(tasks)
+            .buffered(MAX_CONCURRENT_SUMMARIES)
+            .collect()
+            .await;
+        let mut texts = vec![];
+        for ret in results {
+            let text = ret.context("Failed to summarize the chunks")?;
+            texts.push(text.trim().to_string());
+        }
+        let embeddings_data = EmbeddingsData::new(texts.clone(), false);
+        let embeddings = self
+            .create_embeddings(embeddings_data, spinner.clone())
+            .await?;
+        kept.extend(texts.into_iter().zip(clusters).zip(embeddings).map(
+            |((text, chunks), vector)| RagSummary {
+                text,
+                chunks,
+                vector,
+            },
+        ));
+        self.data.summaries = kept;
+        Ok(())
+    }
+
+    /// The chunks of the summaries nearest to the query, each summary's nearest first.
+    pub(super) async fn summary_search(
+        &self,
+        query: &str,
+        top_k: usize,
+        filter: Option<&RagFilter>,
+    ) -> Result<Vec<DocumentId>> {
+        let embeddings_data = EmbeddingsData::new(vec![query.to_string()], true);
+        let embeddings = self.create_embeddings(embeddings_data, None).await?;
+        let Some(query_vector) = embeddings.first() else {
+            return Ok(vec![]);
+        };
+        let mut summaries: Vec<(&RagSummary, f32)> = self
+            .data
+            .summaries
+            .iter()
+            .map(|v| (v, cosine_similarity(query_vector, &v.vector)))
+            .filter(|(_, score)| *score > self.min_score())
+            .collect();
+        summaries.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        debug!(
+            "summary_scores: {:?}",
+            summaries.iter().map(|(_, v)| v).collect::<Vec<_>>()
+        );
+        let mut ids = vec![];
+        for (summary, _) in summaries.into_iter().take(SUMMARY_SEARCH_TOP_N) {
+            let mut chunks: Vec<(DocumentId, f32)> = summary
+                .chunks
+                .iter()
+                .filter(|id| match filter {
+                    Some(filter) => self.data.file_matches(**id, filter),
+                    None => self.data.get(**id).is_some(),
+                })
+                .map(|id| {
+                    let score = self
+                        .data
+                        .vectors
+                        .get(id)
+                        .map(|v| cosine_similarity(query_vector, v))
+                        .unwrap_or_default();
+                    (*id, score)
+                })
+                .collect();
+            chunks.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+            ids.extend(chunks.into_iter().map(|(id, _)| id));
+        }
+        ids.truncate(top_k);
+        debug!("summary_search_ids: {ids:?}");
+        Ok(ids)
+    }
+
+    fn cluster_text(&self, chunks: &[DocumentId]) -> String {
+        let mut text = String::new();
+        for document in chunks.iter().filter_map(|id| self.data.get(*id)) {
+            if !text.is_empty() {
+                text.push_str("\n\n");
+            }
+            text.push_str(&document.page_content);
+        }
+        match text.char_indices().nth(MAX_SUMMARY_INPUT_CHARS) {
+            Some((index, _)) => text[..index].to_string(),
+            None => text,
+        }
+    }
+}
+
+/// The summaries that still cover only existing chunks. All of them are dropped once they
+/// reach the cluster limit, so the chunks are clustered over again.
+fn unchanged_summaries(
+    mut summaries: Vec<RagSummary>,
+    vectors: &IndexMap<DocumentId, Vec<f32>>,
+) -> Vec<RagSummary> {
+    summaries.retain(|v| v.chunks.iter().all(|id| vectors.contains_key(id)));
+    if summaries.len() >= MAX_SUMMARY_CLUSTERS {
+        summaries.clear();
+    }
+    summaries
+}
+
+/// Groups the vectors into at most `k` clusters by cosine similarity with k-means, returning
+/// the cluster of each. The seeds are picked deterministically from an even sample of the
+/// vectors, each the one least similar to the seeds before it, so rebuilds cluster alike.
+fn cluster_vectors(vectors: &[&[f32]], k: usize) -> Vec<usize> {
+    if vectors.is_empty() {
+        return vec![];
+    }
+    let step = vectors.len().div_ceil(MAX_SEED_CANDIDATES);
+    let candidates: Vec<&[f32]> = vectors.iter().step_by(step).copied().collect();
+    let k = k.clamp(1, candidates.len());
+    let mut centroids: Vec<Vec<f32>> = vec![candidates[0].to_vec()];
+    // The similarity of each candidate to its nearest seed so far
+    let mut nearest: Vec<f32> = candidates
+        .iter()
+        .map(|v| cosine_similarity(v, &centroids[0]))
+        .collect();
+    while centroids.len() < k {
+        let index = nearest
+            .iter()
+            .enumerate()
+            .min_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(index, _)| index)
+            .unwrap_or_default();
+        let seed = candidates[index].to_vec();
+        for (v, similarity) in candidates.iter().zip(nearest.iter_mut()) {
+            *similarity = similarity.max(cosine_similarity(v, &seed));
+        }
+        centroids.push(seed);
+    }
+    let mut labels: Vec<usize> = vec![];
+    for _ in 0..KMEANS_ITERATIONS {
+        let new_labels: Vec<usize> = vectors
+            .iter()
+            .map(|v| nearest_centroid(&centroids, v))
+            .collect();
+        if new_labels == labels {
+            break;
+        }
+        labels = new_labels;
+        let mut sums = vec![vec![0.0; centroids[0].len()]; k];
+        let mut counts = vec![0; k];
+        for (v, label) in vectors.iter().zip(&labels) {
+            for (sum, value) in sums[*label].iter_mut().zip(v.iter()) {
+                *sum += value;
+            }
+            counts[*label] += 1;
+        }
+        for ((centroid, sum), count) in centroids.iter_mut().zip(sums).zip(counts) {
+            if count > 0 {
+                *centroid = sum.into_iter().map(|v| v / count as f32).collect();
+            }
+        }
+    }
+    labels
+}
+
+fn nearest_centroid(centroids: &[Vec<f32>], vector: &[f32]) -> usize {
+    centroids
+        .iter()
+        .enumerate()
+        .map(|(index, c)| (index, cosine_similarity(vector, c)))
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(index, _)| index)
+        .unwrap_or_default()
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|v| v * v).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+mod serde_vector {
+    use super::super::serde_vectors::{decode_vector, encode_vector};
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(vector: &[f32], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encode_vector(vector))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<f32>, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        decode_vector(&value).ok_or_else(|| de::Error::custom("Invalid summary vector"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cluster_vectors() {
+        let vectors: Vec<&[f32]> = vec![
+            &[1.0, 0.0, 0.1],
+            &[0.0, 1.0, 0.0],
+            &[0.9, 0.1, 0.0],
+            &[0.1, 0.9, 0.1],
+            &[1.0, 0.1, 0.0],
+        ];
+        assert_eq!(cluster_vectors(&vectors, 2), [0, 1, 0, 1, 0]);
+        assert_eq!(cluster_vectors(&vectors, 1), [0; 5]);
+        assert_eq!(cluster_vectors(&vectors[..1], 3), [0]);
+        assert!(cluster_vectors(&[], 2).is_empty());
+    }
+
+    #[test]
+    fn test_cluster_vectors_samples_seeds() {
+        let vectors: Vec<Vec<f32>> = (0..5000)
+            .map(|i| {
+                if i % 2 == 0 {
+                    vec![1.0, 0.0]
+                } else {
+                    vec![0.0, 1.0]
+                }
+            })
+            .collect();
+        let vectors: Vec<&[f32]> = vectors.iter().map(|v| v.as_slice()).collect();
+        let labels = cluster_vectors(&vectors, 2);
+        assert_eq!(labels.len(), 5000);
+        assert!(labels.iter().step_by(2).all(|v| *v == labels[0]));
+        assert!(labels.iter().skip(1).step_by(2).all(|v| *v == labels[1]));
+        assert_ne!(labels[0], labels[1]);
+    }
+
+    #[test]
+    fn test_unchanged_summaries() {
+        let summary = |text: &str, chunks: Vec<DocumentId>| RagSummary {
+            text: text.into(),
+            chunks,
+            vector: vec![1.0],
+        };
+        let summaries = vec![
+            summary("kept", vec![DocumentId::new(0, 0), DocumentId::new(0, 1)]),
+            summary(
+                "changed",
+                vec![DocumentId::new(1, 0), DocumentId::new(2, 0)],
+            ),
+        ];
+        let vectors: IndexMap<DocumentId, Vec<f32>> = [(0, 0), (0, 1), (2, 0), (3, 0)]
+            .into_iter()
+            .map(|(file, chunk)| (DocumentId::new(file, chunk), vec![1.0]))
+            .collect();
+        let kept = unchanged_summaries(summaries, &vectors);
+        assert_eq!(
+            kept.iter().map(|v| v.text.as_str()).collect::<Vec<_>>(),
+            ["kept"]
+        );
+
+        let summaries = (0..MAX_SUMMARY_CLUSTERS)
+            .map(|_| summary("full", vec![DocumentId::new(0, 0)]))
+            .collect();
+        assert!(unchanged_summaries(summaries, &vectors).is_empty());
+    }
+
+    #[test]
+    fn test_rag_summary_serde() {
+        let summary = RagSummary {
+            text: "Pods and nodes".into(),
+            chunks: vec![DocumentId::new(0, 1), DocumentId::new(2, 0)],
+            vector: vec![0.5, -0.25],
+        };
+        let yaml = serde_yaml::to_string(&summary).unwrap();
+        assert!(yaml.contains("- 0-1\n- 2-0\n"));
+        let summary: RagSummary = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(
+            summary.chunks,
+            [DocumentId::new(0, 1), DocumentId::new(2, 0)]
+        );
+        assert_eq!(summary.vector, [0.5, -0.25]);
+    }
+}
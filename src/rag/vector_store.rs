@@ -0,0 +1,472 @@
+use super::*;
+
+use reqwest::{Client as ReqwestClient, RequestBuilder, Response, StatusCode};
+
+/// Qdrant takes upserts in batches of at most this many points.
+const QDRANT_BATCH_SIZE: usize = 256;
+
+/// Where the vectors of a RAG live. The default file store keeps them in the RAG file and
+/// indexes them in memory; remote stores keep them on a server so large document sets don't
+/// have to fit in memory.
+#[async_trait::async_trait]
+pub trait VectorStore: Send + Sync {
+    fn describe(&self) -> String;
+
+    /// Drops the vectors of deleted documents and adds the new ones. `data` already reflects
+    /// the change.
+    async fn update(
+        &mut self,
+        data: &RagData,
+        deleted: &[DocumentId],
+        added: &[(DocumentId, Vec<f32>)],
+    ) -> Result<()>;
+
+    /// Finds the `top_k` nearest documents of each query vector, with cosine similarity scores.
+    async fn search(&self, queries: &[Vec<f32>], top_k: usize) -> Result<Vec<(DocumentId, f32)>>;
+
+    fn boxed_clone(&self, data: &RagData) -> Box<dyn VectorStore>;
+
+    /// Reclaims the space left by deleted documents. Stores that compact on their own, or
+    /// rebuild on every update, have nothing to do.
+    async fn compact(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether the vectors are kept in the RAG file.
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum VectorStoreConfig {
+    Qdrant {
+        url: String,
+        /// Never written to the RAG file; looked up in the config or `QDRANT_API_KEY` instead
+        #[serde(default, skip_serializing)]
+        api_key: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        collection: Option<String>,
+    },
+    /// A SQLite file beside the RAG file, requires the `sqlite-vec` feature
+    Sqlite,
+    /// A LanceDB directory beside the RAG file, requires the `lancedb` feature
+    Lancedb,
+    /// A Postgres table, requires the `pgvector` feature
+    Pgvector {
+        url: String,
+        /// Never written to the RAG file; looked up in the config or `PGPASSWORD` instead
+        #[serde(default, skip_serializing)]
+        password: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        table: Option<String>,
+    },
+}
+
+impl VectorStoreConfig {
+    /// Fills in the secret of a store from the configured store with the same url.
+    fn with_credentials(&self, global: Option<&VectorStoreConfig>) -> Self {
+        match (self, global) {
+            (
+                Self::Qdrant {
+                    url,
+                    api_key: None,
+                    collection,
+                },
+                Some(Self::Qdrant {
+                    url: global_url,
+                    api_key: Some(api_key),
+                    ..
+                }),
+            ) if url == global_url => Self::Qdrant {
+                url: url.clone(),
+                api_key: Some(api_key.clone()),
+                collection: collection.clone(),
+            },
+            (
+                Self::Pgvector {
+                    url,
+                    password: None,
+                    table,
+                },
+                Some(Self::Pgvector {
+                    url: global_url,
+                    password: Some(password),
+                    ..
+                }),
+            ) if url == global_url => Self::Pgvector {
+                url: url.clone(),
+                password: Some(password.clone()),
+                table: table.clone(),
+            },
+            _ => self.clone(),
+        }
+    }
+}
+
+pub fn open_vector_store(
+    config: &GlobalConfig,
+    name: &str,
+    path: &Path,
+    data: &RagData,
+) -> Result<Box<dyn VectorStore>> {
+    let Some(store) = &data.vector_store else {
+        return Ok(Box::new(FileStore::new(data)));
+    };
+    let store = store.with_credentials(config.read().rag_vector_store.as_ref());
+    match store {
+        VectorStoreConfig::Qdrant {
+            url,
+            api_key,
+            collection,
+        } => {
+            let collection = collection.unwrap_or_else(|| default_collection_name(name));
+            let api_key = api_key.or_else(|| env::var("QDRANT_API_KEY").ok());
+            Ok(Box::new(QdrantStore::new(&url, api_key, &collection)?))
+        }
+        VectorStoreConfig::Sqlite => open_sqlite_store(path),
+        VectorStoreConfig::Lancedb => open_lancedb_store(path),
+        VectorStoreConfig::Pgvector {
+            url,
+            password,
+            table,
+        } => {
+            let table = table.unwrap_or_else(|| default_collection_name(name).replace('-', "_"));
+            let password = password.or_else(|| env::var("PGPASSWORD").ok());
+            open_pgvector_store(&url, password, &table)
+        }
+    }
+}
+
+fn default_collection_name(rag_name: &str) -> String {
+    let name: String = rag_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    format!("aichat-{name}")
+}
+
+/// The vectors of `RagData` in an in-memory HNSW index.
+pub struct FileStore {
+    hnsw: Hnsw<'static, f32, DistCosine>,
+}
+
+impl FileStore {
+    pub fn new(data: &RagData) -> Self {
+        let hnsw = Hnsw::new(32, data.vectors.len(), 16, 200, DistCosine {});
+        let list: Vec<_> = data.vectors.iter().map(|(k, v)| (v, k.0)).collect();
+        hnsw.parallel_insert(&list);
+        Self { hnsw }
+    }
+}
+
+#[async_trait::async_trait]
+impl VectorStore for FileStore {
+    fn describe(&self) -> String {
+        "file".into()
+    }
+
+    async fn update(
+        &mut self,
+        data: &RagData,
+        _deleted: &[DocumentId],
+        _added: &[(DocumentId, Vec<f32>)],
+    ) -> Result<()> {
+        *self = Self::new(data);
+        Ok(())
+    }
+
+    async fn search(&self, queries: &[Vec<f32>], top_k: usize) -> Result<Vec<(DocumentId, f32)>> {
+        let output = self
+            .hnsw
+            .parallel_search(queries, top_k, 30)
+            .into_iter()
+            .flatten()
+            .map(|v| (DocumentId(v.d_id), 1.0 - v.distance))
+            .collect();
+        Ok(output)
+    }
+
+    fn boxed_clone(&self, data: &RagData) -> Box<dyn VectorStore> {
+        Box::new(Self::new(data))
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+/// A collection on a Qdrant server, spoken to over its REST API.
+#[derive(Clone)]
+pub struct QdrantStore {
+    client: ReqwestClient,
+    url: String,
+    api_key: Option<String>,
+    collection: String,
+}
+
+impl QdrantStore {
+    pub fn new(url: &str, api_key: Option<String>, collection: &str) -> Result<Self> {
+        let builder = ReqwestClient::builder().timeout(Duration::from_secs(60));
+        let client = apply_proxy(builder, None)?
+            .build()
+            .context("Failed to create the Qdrant client")?;
+        Ok(Self {
+            client,
+            url: url.trim_end_matches('/').to_string(),
+            api_key,
+            collection: collection.to_string(),
+        })
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        let url = format!("{}/collections/{}{path}", self.url, self.collection);
+        let mut builder = self.client.request(method, url);
+        if let Some(api_key) = &self.api_key {
+            builder = builder.header("api-key", api_key);
+        }
+        builder
+    }
+
+    async fn send(&self, builder: RequestBuilder) -> Result<Value> {
+        let res = builder
+            .send()
+            .await
+            .with_context(|| format!("Failed to reach Qdrant at '{}'", self.url))?;
+        self.read_response(res).await
+    }
+
+    async fn read_response(&self, res: Response) -> Result<Value> {
+        let status = res.status();
+        let data: Value = res.json().await.unwrap_or_default();
+        if !status.is_success() {
+            let message = data["status"]["error"].as_str().unwrap_or_default();
+            bail!("Qdrant error {status} on '{}': {message}", self.collection);
+        }
+        Ok(data)
+    }
+
+    async fn ensure_collection(&self, size: usize) -> Result<()> {
+        let res = self
+            .request(reqwest::Method::GET, "")
+            .send()
+            .await
+            .with_context(|| format!("Failed to reach Qdrant at '{}'", self.url))?;
+        if res.status() != StatusCode::NOT_FOUND {
+            self.read_response(res).await?;
+            return Ok(());
+        }
+        let body = json!({ "vectors": { "size": size, "distance": "Cosine" } });
+        self.send(self.request(reqwest::Method::PUT, "").json(&body))
+            .await?;
+        Ok(())
+    }
+
+    async fn drop_collection(&self) -> Result<()> {
+        let res = self
+            .request(reqwest::Method::DELETE, "")
+            .send()
+            .await
+            .with_context(|| format!("Failed to reach Qdrant at '{}'", self.url))?;
+        if res.status() != StatusCode::NOT_FOUND {
+            self.read_response(res).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl VectorStore for QdrantStore {
+    fn describe(&self) -> String {
+        format!("qdrant ({}/collections/{})", self.url, self.collection)
+    }
+
+    async fn update(
+        &mut self,
+        data: &RagData,
+        deleted: &[DocumentId],
+        added: &[(DocumentId, Vec<f32>)],
+    ) -> Result<()> {
+        // Every vector is new on a full rebuild, so points left from an earlier build of the
+        // collection (ids are reused) would otherwise linger in searches
+        if !added.is_empty() && added.len() == data.vectors.len() {
+            self.drop_collection().await?;
+        } else if !deleted.is_empty() {
+            let ids: Vec<usize> = deleted.iter().map(|v| v.0).collect();
+            self.send(
+                self.request(reqwest::Method::POST, "/points/delete?wait=true")
+                    .json(&json!({ "points": ids })),
+            )
+            .await?;
+        }
+        let Some((_, first)) = added.first() else {
+            return Ok(());
+        };
+        self.ensure_collection(first.len()).await?;
+        for batch in added.chunks(QDRANT_BATCH_SIZE) {
+            let points: Vec<Value> = batch
+                .iter()
+                .map(|(id, vector)| json!({ "id": id.0, "vector": vector }))
+                .collect();
+            self.send(
+                self.request(reqwest::Method::PUT, "/points?wait=true")
+                    .json(&json!({ "points": points })),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn search(&self, queries: &[Vec<f32>], top_k: usize) -> Result<Vec<(DocumentId, f32)>> {
+        let mut output = vec![];
+        for vector in queries {
+            let body = json!({ "vector": vector, "limit": top_k, "with_payload": false });
+            let data = self
+                .send(
+                    self.request(reqwest::Method::POST, "/points/search")
+                        .json(&body),
+                )
+                .await?;
+            let Some(list) = data["result"].as_array() else {
+                bail!("Invalid Qdrant search response: {data}");
+            };
+            for item in list {
+                if let (Some(id), Some(score)) = (item["id"].as_u64(), item["score"].as_f64()) {
+                    output.push((DocumentId(id as usize), score as f32));
+                }
+            }
+        }
+        Ok(output)
+    }
+
+    fn boxed_clone(&self, _data: &RagData) -> Box<dyn VectorStore> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Serves the responses in order over plain HTTP, logging `<method> <path> <body>` of
+    /// every request.
+    async fn mock_qdrant(responses: Vec<(u16, Value)>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let log = Arc::new(Mutex::new(vec![]));
+        let requests = log.clone();
+        tokio::spawn(async move {
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![];
+                let mut chunk = [0; 4096];
+                let (head, body_start) = loop {
+                    let n = stream.read(&mut chunk).await.unwrap();
+                    buf.extend_from_slice(&chunk[..n]);
+                    let text = String::from_utf8_lossy(&buf).to_string();
+                    if let Some(i) = text.find("\r\n\r\n") {
+                        break (text[..i].to_string(), i + 4);
+                    }
+                };
+                let content_length = head
+                    .lines()
+                    .find_map(|v| {
+                        let (name, value) = v.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse::<usize>().ok())?
+                    })
+                    .unwrap_or_default();
+                while buf.len() < body_start + content_length {
+                    let n = stream.read(&mut chunk).await.unwrap();
+                    buf.extend_from_slice(&chunk[..n]);
+                }
+                let request_line = head.lines().next().unwrap_or_default();
+                let mut parts = request_line.split(' ');
+                let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+                let request_body = String::from_utf8_lossy(&buf[body_start..]);
+                requests
+                    .lock()
+                    .push(format!("{method} {path} {request_body}").trim().to_string());
+                let body = body.to_string();
+                let response = format!(
+                    "HTTP/1.1 {status} OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, log)
+    }
+
+    fn rag_data(ids: &[usize]) -> RagData {
+        let mut data = RagData::new("mock:embed".into(), 1500, 75, None, 4, None);
+        for id in ids {
+            data.vectors.insert(DocumentId(*id), vec![1.0, 0.0]);
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn test_qdrant_full_rebuild() {
+        let ok = json!({ "status": "ok", "result": true });
+        let not_found = json!({ "status": { "error": "Not found" } });
+        let (url, log) = mock_qdrant(vec![
+            (200, ok.clone()),
+            (404, not_found),
+            (200, ok.clone()),
+            (200, ok),
+        ])
+        .await;
+        let mut store = QdrantStore::new(&url, None, "docs").unwrap();
+        let data = rag_data(&[0, 1]);
+        let added = vec![
+            (DocumentId(0), vec![1.0, 0.0]),
+            (DocumentId(1), vec![0.0, 1.0]),
+        ];
+        store.update(&data, &[], &added).await.unwrap();
+        assert_eq!(
+            *log.lock(),
+            [
+                "DELETE /collections/docs",
+                "GET /collections/docs",
+                r#"PUT /collections/docs {"vectors":{"size":2,"distance":"Cosine"}}"#,
+                r#"PUT /collections/docs/points?wait=true {"points":[{"id":0,"vector":[1.0,0.0]},{"id":1,"vector":[0.0,1.0]}]}"#,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_qdrant_incremental_update() {
+        let ok = json!({ "status": "ok", "result": true });
+        let (url, log) = mock_qdrant(vec![(200, ok.clone()), (200, ok.clone()), (200, ok)]).await;
+        let mut store = QdrantStore::new(&url, Some("secret".into()), "docs").unwrap();
+        let data = rag_data(&[0, 2]);
+        let added = vec![(DocumentId(2), vec![1.0, 0.0])];
+        store.update(&data, &[DocumentId(1)], &added).await.unwrap();
+        assert_eq!(
+            *log.lock(),
+            [
+                r#"POST /collections/docs/points/delete?wait=true {"points":[1]}"#,
+                "GET /collections/docs",
+                r#"PUT /collections/docs/points?wait=true {"points":[{"id":2,"vector":[1.0,0.0]}]}"#,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_qdrant_search() {
+        let result = json!({ "result": [{ "id": 3, "score": 0.75 }, { "id": 1, "score": 0.5 }] });
+        let error = json!({ "status": { "error": "Collection missing" } });
+        let (url, _) = mock_qdrant(vec![(200, result), (404, error)]).await;
+        let store = QdrantStore::new(&url, None, "docs").unwrap();
+        let output = store.search(&[vec![1.0, 0.0]], 2).await.unwrap();
+        assert_eq!(output, [(DocumentId(3), 0.75), (DocumentId(1), 0.5)]);
+        let err = store.search(&[vec![1.0, 0.0]], 2).await.unwrap_err();
+        assert!(err.to_string().contains("Collection missing"));
+    }
+}
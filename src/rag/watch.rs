@@ -0,0 +1,127 @@
+use super::*;
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::PathBuf;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+/// Changes that arrive within this long of each other are handled as one.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Watches the local document paths of a RAG. URLs, git sources and loader paths can't be
+/// watched and are left out.
+pub struct RagWatcher {
+    _watcher: RecommendedWatcher,
+    dirs: Vec<(PathBuf, bool)>,
+    files: Vec<PathBuf>,
+    rx: UnboundedReceiver<notify::Result<Event>>,
+}
+
+impl RagWatcher {
+    pub fn new(document_paths: &[String], loaders: &HashMap<String, String>) -> Result<Self> {
+        let (tx, rx) = unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })
+        .context("Failed to create the file watcher")?;
+        let (mut dirs, mut files) = (vec![], vec![]);
+        for path in document_paths {
+            if is_url(path) || is_git_path(path) || is_loader_protocol(loaders, path) {
+                continue;
+            }
+            let (base_path, _, current_only) = parse_glob(path)?;
+            let base_path = PathBuf::from(base_path);
+            // Watch the parent of a single file, editors often replace the file on save
+            let (target, mode) = if base_path.is_file() {
+                let Some(parent) = base_path.parent().map(|v| v.to_path_buf()) else {
+                    continue;
+                };
+                files.push(base_path);
+                (parent, RecursiveMode::NonRecursive)
+            } else {
+                dirs.push((base_path.clone(), !current_only));
+                match current_only {
+                    true => (base_path, RecursiveMode::NonRecursive),
+                    false => (base_path, RecursiveMode::Recursive),
+                }
+            };
+            watcher
+                .watch(&target, mode)
+                .with_context(|| format!("Failed to watch '{}'", target.display()))?;
+        }
+        if dirs.is_empty() && files.is_empty() {
+            bail!("No local document paths to watch");
+        }
+        Ok(Self {
+            _watcher: watcher,
+            dirs,
+            files,
+            rx,
+        })
+    }
+
+    /// Waits for documents to change, then for the changes to settle.
+    pub async fn changed(&mut self) -> Result<()> {
+        loop {
+            let event = self.next_event().await?;
+            if self.is_relevant(&event) {
+                break;
+            }
+        }
+        while let Ok(event) = tokio::time::timeout(DEBOUNCE, self.next_event()).await {
+            event?;
+        }
+        Ok(())
+    }
+
+    async fn next_event(&mut self) -> Result<Event> {
+        match self.rx.recv().await {
+            Some(event) => event.context("Failed to watch the documents"),
+            None => bail!("The file watcher stopped"),
+        }
+    }
+
+    fn is_relevant(&self, event: &Event) -> bool {
+        if matches!(event.kind, EventKind::Access(_)) {
+            return false;
+        }
+        event.paths.iter().any(|path| {
+            if path.components().any(|v| v.as_os_str() == ".git") {
+                return false;
+            }
+            self.files.contains(path)
+                || self.dirs.iter().any(|(dir, recursive)| match recursive {
+                    true => path.starts_with(dir),
+                    false => path.parent() == Some(dir.as_path()),
+                })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rag_watcher() {
+        let dir = temp_file("-rag-watch", "");
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        fs::write(dir.join("docs/a.md"), "a").unwrap();
+        fs::write(dir.join("notes.md"), "notes").unwrap();
+        fs::write(dir.join("other.md"), "other").unwrap();
+        let paths = [
+            format!("{}/docs/**/*.md", dir.display()),
+            dir.join("notes.md").display().to_string(),
+            "https://example.com/".to_string(),
+        ];
+        let mut watcher = RagWatcher::new(&paths, &HashMap::new()).unwrap();
+        let wait = Duration::from_secs(3);
+
+        fs::write(dir.join("other.md"), "changed").unwrap();
+        assert!(tokio::time::timeout(wait, watcher.changed()).await.is_err());
+        fs::write(dir.join("docs/b.md"), "b").unwrap();
+        assert!(tokio::time::timeout(wait, watcher.changed()).await.is_ok());
+        fs::write(dir.join("notes.md"), "changed").unwrap();
+        assert!(tokio::time::timeout(wait, watcher.changed()).await.is_ok());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -60,6 +60,10 @@ pub async fn raw_stream(
                         stdout().flush()?;
                     }
                 }
+                SseEvent::Notice(text) => {
+                    // Kept off stdout, which may be piped somewhere
+                    eprintln!("{}", text.trim());
+                }
                 SseEvent::Done => {
                     if hide_thinking && !buffer.is_empty() {
                         // Process accumulated text and strip think tags
@@ -103,7 +107,7 @@ async fn markdown_stream_inner(
             }
 
             match reply_event {
-                SseEvent::Text(mut text) => {
+                SseEvent::Text(mut text) | SseEvent::Notice(mut text) => {
                     if hide_thinking {
                         // Accumulate all text for filtering at the end
                         full_buffer.push_str(&text);
@@ -197,7 +201,8 @@ async fn gather_events(rx: &mut UnboundedReceiver<SseEvent>) -> Vec<SseEvent> {
         _ = async {
             while let Some(reply_event) = rx.recv().await {
                 match reply_event {
-                    SseEvent::Text(v) => texts.push(v),
+                    // The terminal shows notices in line with the reply
+                    SseEvent::Text(v) | SseEvent::Notice(v) => texts.push(v),
                     SseEvent::Done => {
                         done = true;
                         break;
@@ -1,23 +1,42 @@
 use super::REPL_COMMANDS;
 
-use crate::{config::GlobalConfig, utils::NO_COLOR};
+use crate::{
+    config::GlobalConfig,
+    utils::{estimate_token_length, NO_COLOR},
+};
 
 use nu_ansi_term::{Color, Style};
 use reedline::{Highlighter, StyledText};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
 
 const DEFAULT_COLOR: Color = Color::Default;
 const MATCH_COLOR: Color = Color::Green;
 
-pub struct ReplHighlighter;
+pub struct ReplHighlighter {
+    pending_input_tokens: Arc<AtomicUsize>,
+}
 
 impl ReplHighlighter {
-    pub fn new(_config: &GlobalConfig) -> Self {
-        Self
+    pub fn new(config: &GlobalConfig) -> Self {
+        Self {
+            pending_input_tokens: config.read().pending_input_tokens.clone(),
+        }
     }
 }
 
 impl Highlighter for ReplHighlighter {
     fn highlight(&self, line: &str, _cursor: usize) -> StyledText {
+        // The prompt is painted right after, so it shows the size of the pending input
+        let tokens = if line.starts_with('.') {
+            0
+        } else {
+            estimate_token_length(line)
+        };
+        self.pending_input_tokens.store(tokens, Ordering::Relaxed);
+
         let mut styled_text = StyledText::new();
 
         if *NO_COLOR {
@@ -47,3 +66,25 @@ impl Highlighter for ReplHighlighter {
         styled_text
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    use parking_lot::RwLock;
+
+    #[test]
+    fn test_pending_input_tokens() {
+        let config: GlobalConfig = Arc::new(RwLock::new(Config::default()));
+        let highlighter = ReplHighlighter::new(&config);
+        highlighter.highlight("Summarize the history of Rome", 0);
+        let tokens = config.read().pending_input_tokens.load(Ordering::Relaxed);
+        assert!(tokens > 0);
+        highlighter.highlight(".session", 0);
+        assert_eq!(
+            config.read().pending_input_tokens.load(Ordering::Relaxed),
+            0
+        );
+    }
+}
@@ -1,24 +1,29 @@
 mod completer;
 mod highlighter;
 mod prompt;
+mod tui;
 
 use self::completer::ReplCompleter;
 use self::highlighter::ReplHighlighter;
 use self::prompt::ReplPrompt;
+pub use self::tui::Tui;
 
 use crate::client::{call_chat_completions, call_chat_completions_streaming};
 use crate::config::{
-    macro_execute, AgentVariables, AssertState, Config, GlobalConfig, Input, LastMessage,
-    StateFlags,
+    apply_file_edits, macro_execute, AgentVariables, AssertState, Config, GlobalConfig, Input,
+    LastMessage, StateFlags,
 };
+use crate::rag::{EmbeddingCache, RagPruneOptions};
 use crate::render::render_error;
 use crate::utils::{
-    abortable_run_with_spinner, create_abort_signal, dimmed_text, set_text, temp_file, AbortSignal,
+    abortable_run_with_spinner, color_text, create_abort_signal, did_you_mean, dimmed_text,
+    edit_file, run_command, set_text, temp_file, AbortSignal,
 };
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use crossterm::cursor::SetCursorStyle;
 use fancy_regex::Regex;
+use inquire::{Confirm, Select};
 use reedline::CursorConfig;
 use reedline::{
     default_emacs_keybindings, default_vi_insert_keybindings, default_vi_normal_keybindings,
@@ -26,15 +31,21 @@ use reedline::{
     ReedlineEvent, ReedlineMenu, ValidationResult, Validator, Vi,
 };
 use reedline::{MenuBuilder, Signal};
+use similar::{ChangeTag, TextDiff};
 use std::sync::LazyLock;
-use std::{env, process};
+use std::{env, fs, process};
 
 const MENU_NAME: &str = "completion_menu";
 
-static REPL_COMMANDS: LazyLock<[ReplCommand; 36]> = LazyLock::new(|| {
+static REPL_COMMANDS: LazyLock<[ReplCommand; 53]> = LazyLock::new(|| {
     [
         ReplCommand::new(".help", "Show this help guide", AssertState::pass()),
-        ReplCommand::new(".info", "Show system info", AssertState::pass()),
+        ReplCommand::new(
+            ".info",
+            "Show system info, append --json for JSON output",
+            AssertState::pass(),
+        ),
+        ReplCommand::new(".info model", "Show model info", AssertState::pass()),
         ReplCommand::new(
             ".edit config",
             "Modify configuration file",
@@ -79,11 +90,36 @@ static REPL_COMMANDS: LazyLock<[ReplCommand; 36]> = LazyLock::new(|| {
             "Start or switch to a session",
             AssertState::False(StateFlags::SESSION_EMPTY | StateFlags::SESSION),
         ),
+        ReplCommand::new(
+            ".session fork",
+            "Continue the conversation in a copy of the session",
+            AssertState::True(StateFlags::SESSION),
+        ),
         ReplCommand::new(
             ".empty session",
             "Clear session messages",
             AssertState::True(StateFlags::SESSION),
         ),
+        ReplCommand::new(
+            ".checkpoint",
+            "Snapshot the session under a name",
+            AssertState::True(StateFlags::SESSION),
+        ),
+        ReplCommand::new(
+            ".restore",
+            "Roll the session back to a checkpoint",
+            AssertState::True(StateFlags::SESSION),
+        ),
+        ReplCommand::new(
+            ".lock session",
+            "Make the session read-only",
+            AssertState::True(StateFlags::SESSION),
+        ),
+        ReplCommand::new(
+            ".unlock session",
+            "Allow changes to the session again",
+            AssertState::True(StateFlags::SESSION),
+        ),
         ReplCommand::new(
             ".compress session",
             "Compress session messages",
@@ -130,11 +166,36 @@ static REPL_COMMANDS: LazyLock<[ReplCommand; 36]> = LazyLock::new(|| {
             "Leave agent",
             AssertState::True(StateFlags::AGENT),
         ),
+        ReplCommand::new(
+            ".vars",
+            "View or update role and agent variables",
+            AssertState::True(StateFlags::ROLE | StateFlags::AGENT),
+        ),
         ReplCommand::new(
             ".rag",
             "Initialize or access RAG",
             AssertState::False(StateFlags::AGENT),
         ),
+        ReplCommand::new(
+            ".rag sources",
+            "List the document paths of RAG",
+            AssertState::True(StateFlags::RAG),
+        ),
+        ReplCommand::new(
+            ".rag info",
+            "View RAG info, --stats for counts, tokens, cost and size",
+            AssertState::True(StateFlags::RAG),
+        ),
+        ReplCommand::new(
+            ".rag add",
+            "Index more documents into RAG",
+            AssertState::TrueFalse(StateFlags::RAG, StateFlags::AGENT),
+        ),
+        ReplCommand::new(
+            ".rag remove",
+            "Remove document paths from RAG",
+            AssertState::TrueFalse(StateFlags::RAG, StateFlags::AGENT),
+        ),
         ReplCommand::new(
             ".edit rag-docs",
             "Add or remove documents from an existing RAG",
@@ -142,7 +203,12 @@ static REPL_COMMANDS: LazyLock<[ReplCommand; 36]> = LazyLock::new(|| {
         ),
         ReplCommand::new(
             ".rebuild rag",
-            "Rebuild RAG for document changes",
+            "Rebuild RAG for document changes, --force to reload unchanged files",
+            AssertState::True(StateFlags::RAG),
+        ),
+        ReplCommand::new(
+            ".prune rag",
+            "Remove documents by source, age or staleness",
             AssertState::True(StateFlags::RAG),
         ),
         ReplCommand::new(
@@ -160,12 +226,27 @@ static REPL_COMMANDS: LazyLock<[ReplCommand; 36]> = LazyLock::new(|| {
             "Leave RAG",
             AssertState::TrueFalse(StateFlags::RAG, StateFlags::AGENT),
         ),
+        ReplCommand::new(
+            ".info embeddings-cache",
+            "Show embeddings cache statistics",
+            AssertState::pass(),
+        ),
+        ReplCommand::new(
+            ".prune embeddings-cache",
+            "Remove cached embeddings not used by any RAG",
+            AssertState::pass(),
+        ),
         ReplCommand::new(".macro", "Execute a macro", AssertState::pass()),
         ReplCommand::new(
             ".file",
             "Include files, directories, URLs or commands",
             AssertState::pass(),
         ),
+        ReplCommand::new(
+            ".improve-prompt",
+            "Critique and rewrite a prompt before sending it",
+            AssertState::pass(),
+        ),
         ReplCommand::new(
             ".continue",
             "Continue previous response",
@@ -177,6 +258,16 @@ static REPL_COMMANDS: LazyLock<[ReplCommand; 36]> = LazyLock::new(|| {
             AssertState::pass(),
         ),
         ReplCommand::new(".copy", "Copy last response", AssertState::pass()),
+        ReplCommand::new(
+            ".apply edits",
+            "Preview and apply the file edits in the last response",
+            AssertState::pass(),
+        ),
+        ReplCommand::new(
+            ".open response",
+            "Open a response in the editor and send back the edit",
+            AssertState::pass(),
+        ),
         ReplCommand::new(".set", "Modify runtime settings", AssertState::pass()),
         ReplCommand::new(
             ".delete",
@@ -376,6 +467,7 @@ pub async fn run_repl_command(
     abort_signal: AbortSignal,
     mut line: &str,
 ) -> Result<bool> {
+    config.write().start_max_time();
     if let Ok(Some(captures)) = MULTILINE_RE.captures(line) {
         if let Some(text_match) = captures.get(1) {
             line = text_match.as_str();
@@ -386,29 +478,48 @@ pub async fn run_repl_command(
             ".help" => {
                 dump_repl_help();
             }
-            ".info" => match args {
-                Some("role") => {
-                    let info = config.read().role_info()?;
-                    print!("{info}");
-                }
-                Some("session") => {
-                    let info = config.read().session_info()?;
-                    print!("{info}");
-                }
-                Some("rag") => {
-                    let info = config.read().rag_info()?;
-                    print!("{info}");
-                }
-                Some("agent") => {
-                    let info = config.read().agent_info()?;
-                    print!("{info}");
-                }
-                Some(_) => unknown_command()?,
-                None => {
-                    let output = config.read().sysinfo()?;
-                    print!("{output}");
+            ".info" => {
+                let (args, json) = match args.map(|v| v.strip_suffix("--json")) {
+                    Some(Some(v)) => (Some(v.trim()).filter(|v| !v.is_empty()), true),
+                    _ => (args, false),
+                };
+                if json {
+                    let value = config.read().info_value(args.unwrap_or("config"))?;
+                    println!("{}", serde_json::to_string_pretty(&value)?);
+                } else {
+                    match args {
+                        Some("model") => {
+                            let value = config.read().current_model().export_value();
+                            print!("{}", serde_yaml::to_string(&value)?);
+                        }
+                        Some("role") => {
+                            let info = config.read().role_info()?;
+                            print!("{info}");
+                        }
+                        Some("session") => {
+                            let info = config.read().session_info()?;
+                            print!("{info}");
+                        }
+                        Some("rag") => {
+                            let info = config.read().rag_info()?;
+                            print!("{info}");
+                        }
+                        Some("agent") => {
+                            let info = config.read().agent_info()?;
+                            print!("{info}");
+                        }
+                        Some("embeddings-cache") => {
+                            let info = EmbeddingCache::stats()?;
+                            print!("{info}");
+                        }
+                        Some("config") | None => {
+                            let output = config.read().sysinfo()?;
+                            print!("{output}");
+                        }
+                        Some(_) => unknown_command(line.trim())?,
+                    }
                 }
-            },
+            }
             ".model" => match args {
                 Some(name) => {
                     config.write().set_model(name)?;
@@ -434,6 +545,7 @@ pub async fn run_repl_command(
                             config.write().new_role(name)?;
                         }
                         config.write().use_role(name)?;
+                        Config::use_role_rag(config)?;
                     }
                 },
                 None => println!(
@@ -442,13 +554,53 @@ pub async fn run_repl_command(
     .role <name> [text]...          # Temporarily switch to the role, send the text, and switch back"#
                 ),
             },
-            ".session" => {
-                config.write().use_session(args)?;
-                Config::maybe_autoname_session(config.clone());
-            }
-            ".rag" => {
-                Config::use_rag(config, args, abort_signal.clone()).await?;
-            }
+            ".session" => match split_first_arg(args) {
+                Some(("fork", name)) => {
+                    let name = config.write().fork_session(name)?;
+                    println!("✓ Forked the session into '{name}'.");
+                }
+                _ => {
+                    config.write().use_session(args)?;
+                    Config::use_role_rag(config)?;
+                    Config::maybe_autoname_session(config.clone());
+                }
+            },
+            ".rag" => match split_first_arg(args) {
+                Some(("sources", None)) => {
+                    let output = Config::rag_document_paths(config)?;
+                    println!("{output}");
+                }
+                Some(("info", args)) => {
+                    let output = match args.map(|v| v.trim()) {
+                        None => config.read().rag_info()?,
+                        Some("--stats") => config.read().rag_stats()?,
+                        Some(_) => bail!("Usage: .rag info [--stats]"),
+                    };
+                    print!("{output}");
+                    if !output.ends_with('\n') {
+                        println!();
+                    }
+                }
+                Some(("add", Some(args))) => {
+                    let (paths, _) = split_args_text(args, cfg!(windows));
+                    Config::add_rag_documents(config, &paths, abort_signal.clone()).await?;
+                }
+                Some(("remove", Some(args))) => {
+                    let (sources, _) = split_args_text(args, cfg!(windows));
+                    Config::remove_rag_documents(config, &sources, abort_signal.clone()).await?;
+                }
+                Some(("add" | "remove", None)) => {
+                    println!(
+                        r#"Usage:
+    .rag <name>                     # Switch to the RAG, creating it if it doesn't exist
+    .rag sources                    # List the document paths of the RAG
+    .rag info [--stats]             # View the RAG, or its counts, tokens, cost and size
+    .rag add <path|url>...          # Index more documents into the RAG
+    .rag remove <source>...         # Remove document paths and their documents from the RAG"#
+                    )
+                }
+                _ => Config::use_rag(config, args, abort_signal.clone()).await?,
+            },
             ".agent" => match split_first_arg(args) {
                 Some((agent_name, args)) => {
                     let (new_args, _) = split_args_text(args.unwrap_or_default(), cfg!(windows));
@@ -504,6 +656,24 @@ pub async fn run_repl_command(
                     config.read().print_markdown(&banner)?;
                 }
             },
+            ".vars" => match args {
+                Some(args) => {
+                    let (pairs, _) = split_args_text(args, cfg!(windows));
+                    let variables: AgentVariables = pairs
+                        .iter()
+                        .filter_map(|v| v.split_once('='))
+                        .map(|(key, value)| (key.to_string(), value.to_string()))
+                        .collect();
+                    if variables.len() != pairs.len() {
+                        bail!("Some variable values are not key=value pairs");
+                    }
+                    config.write().update_variables(variables)?;
+                }
+                None => {
+                    let info = config.read().variables_info()?;
+                    println!("{info}");
+                }
+            },
             ".save" => match split_first_arg(args) {
                 Some(("role", name)) => {
                     config.write().save_role(name)?;
@@ -554,6 +724,24 @@ pub async fn run_repl_command(
                     println!(r#"Usage: .compress session"#)
                 }
             },
+            ".lock" => match args {
+                Some("session") => {
+                    config.write().lock_session(true)?;
+                    println!("✓ Locked the session.");
+                }
+                _ => {
+                    println!(r#"Usage: .lock session"#)
+                }
+            },
+            ".unlock" => match args {
+                Some("session") => {
+                    config.write().lock_session(false)?;
+                    println!("✓ Unlocked the session.");
+                }
+                _ => {
+                    println!(r#"Usage: .unlock session"#)
+                }
+            },
             ".empty" => match args {
                 Some("session") => {
                     config.write().empty_session()?;
@@ -562,12 +750,36 @@ pub async fn run_repl_command(
                     println!(r#"Usage: .empty session"#)
                 }
             },
+            ".checkpoint" => match args {
+                Some(name) => {
+                    config.write().checkpoint_session(name)?;
+                    println!("✓ Saved checkpoint '{name}'.");
+                }
+                None => {
+                    let checkpoints = config.read().list_checkpoints();
+                    if checkpoints.is_empty() {
+                        println!(r#"Usage: .checkpoint <name>"#)
+                    } else {
+                        println!("{}", checkpoints.join("\n"));
+                    }
+                }
+            },
+            ".restore" => match args {
+                Some(name) => {
+                    config.write().restore_checkpoint(name)?;
+                    println!("✓ Restored checkpoint '{name}'.");
+                }
+                _ => {
+                    println!(r#"Usage: .restore <name>"#)
+                }
+            },
             ".rebuild" => match args {
-                Some("rag") => {
-                    Config::rebuild_rag(config, abort_signal.clone()).await?;
+                Some(args @ ("rag" | "rag --force")) => {
+                    let force = args.ends_with("--force");
+                    Config::rebuild_rag(config, force, abort_signal.clone()).await?;
                 }
                 _ => {
-                    println!(r#"Usage: .rebuild rag"#)
+                    println!(r#"Usage: .rebuild rag [--force]"#)
                 }
             },
             ".sources" => match args {
@@ -579,6 +791,24 @@ pub async fn run_repl_command(
                     println!(r#"Usage: .sources rag"#)
                 }
             },
+            ".prune" => match args {
+                Some("embeddings-cache") => {
+                    let removed = EmbeddingCache::prune()?;
+                    println!("✓ Removed {removed} cached embeddings.");
+                }
+                Some(args) if args == "rag" || args.starts_with("rag ") => {
+                    let (args, _) = split_args_text(&args[3..], cfg!(windows));
+                    let options = RagPruneOptions::parse(&args)?;
+                    Config::prune_rag(config, &options).await?;
+                }
+                _ => {
+                    println!(
+                        r#"Usage:
+    .prune embeddings-cache                                      # Remove cached embeddings not used by any RAG
+    .prune rag [--source <glob>] [--older-than <age>] [--stale]  # Remove RAG documents matching every condition"#
+                    )
+                }
+            },
             ".macro" => match split_first_arg(args) {
                 Some((name, extra)) => {
                     if !Config::has_macro(name) && extra.is_none() {
@@ -610,10 +840,39 @@ pub async fn run_repl_command(
 .file https://example.com/file.txt -- summarize
 .file https://example.com/image.png -- recognize text
 .file `git diff` -- Generate git commit message
+.file "cmd:kubectl get pods -A" -- why are pods crashing
 .file jina:https://example.com
 .file %% -- translate last reply to english"#
                 ),
             },
+            ".improve-prompt" => match args {
+                Some(draft) => {
+                    let (critique, prompt) = abortable_run_with_spinner(
+                        Config::improve_prompt(config, draft),
+                        "Improving",
+                        abort_signal.clone(),
+                    )
+                    .await?;
+                    if !critique.is_empty() {
+                        println!("{}\n", dimmed_text(&critique));
+                    }
+                    println!("{}", render_prompt_diff(draft, &prompt));
+                    let choices = vec!["Send improved", "Send original", "Copy improved", "Cancel"];
+                    let text = match Select::new("Which prompt?", choices).prompt()? {
+                        "Send improved" => prompt.as_str(),
+                        "Send original" => draft,
+                        "Copy improved" => {
+                            set_text(&prompt)?;
+                            println!("{}", dimmed_text("✓ Copied the improved prompt."));
+                            return Ok(false);
+                        }
+                        _ => return Ok(false),
+                    };
+                    let input = Input::from_str(config, text, None);
+                    ask(config, abort_signal.clone(), input, true).await?;
+                }
+                None => println!("Usage: .improve-prompt <text>..."),
+            },
             ".continue" => {
                 let LastMessage {
                     mut input, output, ..
@@ -673,6 +932,34 @@ pub async fn run_repl_command(
                 };
                 set_text(&output).context("Failed to copy the last chat response")?;
             }
+            ".apply" => match args {
+                Some("edits") => {
+                    let output = match config
+                        .read()
+                        .last_message
+                        .as_ref()
+                        .filter(|v| !v.output.is_empty())
+                        .map(|v| v.output.clone())
+                    {
+                        Some(v) => v,
+                        None => bail!("No chat response to apply"),
+                    };
+                    apply_file_edits(&output, false)?;
+                }
+                _ => println!("Usage: .apply edits"),
+            },
+            ".open" => match split_first_arg(args) {
+                Some(("response", args)) => {
+                    open_response(config, args, abort_signal.clone()).await?;
+                }
+                _ => {
+                    println!(
+                        r#"Usage:
+    .open response [n]              # Edit the nth last response and optionally send the edit as the next message
+    .open response [n] --pager      # View the nth last response in $PAGER"#
+                    )
+                }
+            },
             ".exit" => match args {
                 Some("role") => {
                     config.write().exit_role()?;
@@ -690,7 +977,7 @@ pub async fn run_repl_command(
                 Some("agent") => {
                     config.write().exit_agent()?;
                 }
-                Some(_) => unknown_command()?,
+                Some(_) => unknown_command(line.trim())?,
                 None => {
                     return Ok(true);
                 }
@@ -699,9 +986,9 @@ pub async fn run_repl_command(
                 Some("messages") => {
                     bail!("Use '.empty session' instead");
                 }
-                _ => unknown_command()?,
+                _ => unknown_command(line.trim())?,
             },
-            _ => unknown_command()?,
+            _ => unknown_command(cmd)?,
         },
         None => {
             let input = Input::from_str(config, line, None);
@@ -728,10 +1015,32 @@ async fn ask(
     }
     if with_embeddings {
         input.use_embeddings(abort_signal.clone()).await?;
+        if let Some(repeated) = Config::dedup_question(config, &mut input).await? {
+            config.write().before_chat_completion(&input)?;
+            println!(
+                "{}",
+                dimmed_text(&format!(
+                    "Answered before (similarity {:.2}), reusing that answer:",
+                    repeated.similarity
+                ))
+            );
+            config.read().print_markdown(&repeated.answer)?;
+            config
+                .write()
+                .after_chat_completion(&input, &repeated.answer, &[])?;
+            Config::warn_sink_failures(
+                Config::send_to_sinks(config, &input, &repeated.answer).await,
+            );
+            return Ok(());
+        }
     }
     while config.read().is_compressing_session() {
         tokio::time::sleep(std::time::Duration::from_millis(100)).await;
     }
+    Config::compress_session_to_fit(config, &input).await?;
+    if !Config::preview_input(config, &mut input, abort_signal.clone()).await? {
+        return Ok(());
+    }
 
     let client = input.create_client()?;
     config.write().before_chat_completion(&input)?;
@@ -752,14 +1061,94 @@ async fn ask(
         )
         .await
     } else {
+        Config::warn_sink_failures(Config::send_to_sinks(config, &input, &output).await);
+        if let Some(sources) = input.rag_citation_sources() {
+            println!("{}\n", dimmed_text(&format!("Sources:\n{sources}")));
+        }
         Config::maybe_autoname_session(config.clone());
         Config::maybe_compress_session(config.clone());
         Ok(())
     }
 }
 
-fn unknown_command() -> Result<()> {
-    bail!(r#"Unknown command. Type ".help" for additional help."#);
+/// Writes a response to a temp file and opens it in the editor. When it comes back changed,
+/// the edited text can be sent as the next message.
+async fn open_response(
+    config: &GlobalConfig,
+    args: Option<&str>,
+    abort_signal: AbortSignal,
+) -> Result<()> {
+    let (args, _) = split_args_text(args.unwrap_or_default(), cfg!(windows));
+    let mut n = 1;
+    let mut pager = false;
+    for arg in &args {
+        match arg.as_str() {
+            "--pager" => pager = true,
+            value => {
+                n = value
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid response number '{value}'"))?
+            }
+        }
+    }
+    let output = config.read().nth_response(n)?;
+    let path = temp_file("-response-", ".md");
+    fs::write(&path, &output)
+        .with_context(|| format!("Failed to write to '{}'", path.display()))?;
+    if pager {
+        let pager = env::var("PAGER").unwrap_or_else(|_| "less".into());
+        let mut words = pager.split_whitespace();
+        let ret = match words.next() {
+            Some(cmd) => {
+                let mut pager_args: Vec<String> = words.map(|v| v.to_string()).collect();
+                pager_args.push(path.display().to_string());
+                run_command(cmd, &pager_args, None)
+            }
+            None => Ok(0),
+        };
+        let _ = fs::remove_file(&path);
+        ret.with_context(|| format!("Failed to run the pager '{pager}'"))?;
+        return Ok(());
+    }
+    let editor = config.read().editor()?;
+    edit_file(&editor, &path)?;
+    let edited = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read '{}'", path.display()))?;
+    let _ = fs::remove_file(&path);
+    let edited = edited.trim();
+    if edited.is_empty() || edited == output.trim() {
+        println!("No changes");
+        return Ok(());
+    }
+    let send = Confirm::new("Send the edited text as the next message?")
+        .with_default(true)
+        .prompt()?;
+    if send {
+        let input = Input::from_str(config, edited, None);
+        ask(config, abort_signal, input, true).await?;
+    }
+    Ok(())
+}
+
+fn render_prompt_diff(old: &str, new: &str) -> String {
+    let diff = TextDiff::from_lines(old, new);
+    let mut output = String::new();
+    for change in diff.iter_all_changes() {
+        let line = change.value().trim_end_matches('\n');
+        let line = match change.tag() {
+            ChangeTag::Delete => color_text(&format!("- {line}"), nu_ansi_term::Color::Red),
+            ChangeTag::Insert => color_text(&format!("+ {line}"), nu_ansi_term::Color::Green),
+            ChangeTag::Equal => format!("  {line}"),
+        };
+        output.push_str(&line);
+        output.push('\n');
+    }
+    output
+}
+
+fn unknown_command(command: &str) -> Result<()> {
+    let suggestion = did_you_mean(command, REPL_COMMANDS.iter().map(|v| v.name));
+    bail!(r#"Unknown command{suggestion}. Type ".help" for additional help."#);
 }
 
 fn dump_repl_help() {
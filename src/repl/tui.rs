@@ -0,0 +1,729 @@
+use super::{parse_command, run_repl_command};
+
+use crate::client::{
+    close_truncated_output, stream_chat_completions, SseEvent, StreamStats, StreamedOutput,
+};
+use crate::config::{Config, GlobalConfig, Input, RoleLike};
+use crate::function::eval_tool_calls;
+use crate::render::render_error;
+use crate::utils::{create_abort_signal, dimmed_text, pretty_error, strip_think_tag, AbortSignal};
+
+use anyhow::Result;
+use crossterm::event::{
+    DisableBracketedPaste, EnableBracketedPaste, Event, EventStream, KeyCode, KeyEvent,
+    KeyEventKind, KeyModifiers,
+};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use futures_util::StreamExt;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph, Wrap};
+use ratatui::{Frame, Terminal};
+use std::borrow::Cow;
+use std::io::{self, Stdout, Write};
+use tokio::sync::mpsc::UnboundedReceiver;
+use unicode_width::UnicodeWidthStr;
+
+type TuiTerminal = Terminal<CrosstermBackend<Stdout>>;
+
+const SIDEBAR_WIDTH: u16 = 34;
+const MAX_INPUT_LINES: u16 = 8;
+const HELP_NOTICE: &str = "Enter to send, Ctrl+J for a new line, PgUp/PgDn to scroll, Ctrl+C to cancel, Ctrl+D to exit. Lines starting with '.' run REPL commands.";
+
+enum EntryKind {
+    User,
+    /// Holds the model that replied, unknown for the replies loaded from a session
+    Assistant(Option<String>),
+    Notice,
+    Error,
+}
+
+struct Entry {
+    kind: EntryKind,
+    text: String,
+}
+
+impl Entry {
+    fn new(kind: EntryKind, text: &str) -> Self {
+        Self {
+            kind,
+            text: text.to_string(),
+        }
+    }
+}
+
+enum KeyAction {
+    None,
+    Submit(String),
+    Exit,
+}
+
+/// The full-screen REPL: a scrollable conversation pane, an input box and a status sidebar.
+pub struct Tui {
+    config: GlobalConfig,
+    abort_signal: AbortSignal,
+    entries: Vec<Entry>,
+    input: String,
+    /// Byte offset of the cursor in `input`
+    cursor: usize,
+    history: Vec<String>,
+    history_index: Option<usize>,
+    /// First visible line of the conversation, `None` to follow the latest output
+    scroll: Option<usize>,
+    /// Line count and height of the conversation pane at the last draw
+    pane_lines: usize,
+    pane_height: usize,
+    busy: bool,
+    last_stats: Option<StreamStats>,
+}
+
+impl Tui {
+    pub fn init(config: &GlobalConfig) -> Result<Self> {
+        let mut tui = Self {
+            config: config.clone(),
+            abort_signal: create_abort_signal(),
+            entries: vec![],
+            input: String::new(),
+            cursor: 0,
+            history: vec![],
+            history_index: None,
+            scroll: None,
+            pane_lines: 0,
+            pane_height: 0,
+            busy: false,
+            last_stats: None,
+        };
+        tui.load_session_entries();
+        tui.entries.push(Entry::new(EntryKind::Notice, HELP_NOTICE));
+        Ok(tui)
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        let mut terminal = enter_terminal()?;
+        let ret = self.run_loop(&mut terminal).await;
+        leave_terminal(&mut terminal)?;
+        ret?;
+        self.config.write().exit_session()?;
+        Ok(())
+    }
+
+    async fn run_loop(&mut self, terminal: &mut TuiTerminal) -> Result<()> {
+        let mut events = EventStream::new();
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let Some(event) = events.next().await else {
+                break;
+            };
+            let action = match event? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => self.handle_key(key),
+                Event::Paste(text) => {
+                    self.insert_text(&text.replace('\r', ""));
+                    KeyAction::None
+                }
+                _ => KeyAction::None,
+            };
+            match action {
+                KeyAction::None => {}
+                KeyAction::Exit => break,
+                KeyAction::Submit(line) => {
+                    if self.submit(terminal, &mut events, &line).await? {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn submit(
+        &mut self,
+        terminal: &mut TuiTerminal,
+        events: &mut EventStream,
+        line: &str,
+    ) -> Result<bool> {
+        self.abort_signal.reset();
+        self.scroll = None;
+        if parse_command(line).is_some() {
+            return self.run_command(terminal, line).await;
+        }
+        self.entries.push(Entry::new(EntryKind::User, line));
+        let input = Input::from_str(&self.config, line, None);
+        self.config.write().start_max_time();
+        self.busy = true;
+        let ret = self.ask(terminal, events, input).await;
+        self.busy = false;
+        if let Err(err) = ret {
+            self.entries
+                .push(Entry::new(EntryKind::Error, &pretty_error(&err)));
+        }
+        Ok(false)
+    }
+
+    /// Runs a REPL command on the normal screen, since commands print and prompt freely.
+    async fn run_command(&mut self, terminal: &mut TuiTerminal, line: &str) -> Result<bool> {
+        leave_terminal(terminal)?;
+        println!("{}", dimmed_text(&format!("> {line}")));
+        let exit = match run_repl_command(&self.config, self.abort_signal.clone(), line).await {
+            Ok(exit) => exit,
+            Err(err) => {
+                render_error(err);
+                false
+            }
+        };
+        if !exit {
+            print!("{}", dimmed_text("Press Enter to return"));
+            io::stdout().flush()?;
+            io::stdin().read_line(&mut String::new())?;
+        }
+        resume_terminal(terminal)?;
+        if self.config.read().session.is_some() {
+            self.entries.clear();
+            self.load_session_entries();
+        }
+        Ok(exit)
+    }
+
+    async fn ask(
+        &mut self,
+        terminal: &mut TuiTerminal,
+        events: &mut EventStream,
+        mut input: Input,
+    ) -> Result<()> {
+        if input.is_empty() {
+            return Ok(());
+        }
+        if self.config.read().rag.is_some() {
+            input.use_embeddings(self.abort_signal.clone()).await?;
+            // The search spinner draws over the screen
+            terminal.clear()?;
+        }
+        if let Some(repeated) = Config::dedup_question(&self.config, &mut input).await? {
+            self.config.write().before_chat_completion(&input)?;
+            self.entries.push(Entry::new(
+                EntryKind::Notice,
+                &format!(
+                    "Answered before (similarity {:.2}), reusing that answer:",
+                    repeated.similarity
+                ),
+            ));
+            let model_id = input.role().model().id();
+            self.entries.push(Entry::new(
+                EntryKind::Assistant(Some(model_id)),
+                &repeated.answer,
+            ));
+            self.config
+                .write()
+                .after_chat_completion(&input, &repeated.answer, &[])?;
+            self.send_to_sinks(&input, &repeated.answer).await;
+            return Ok(());
+        }
+        loop {
+            while self.config.read().is_compressing_session() {
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+            let client = input.create_client()?;
+            self.config.write().before_chat_completion(&input)?;
+            self.entries.push(Entry::new(
+                EntryKind::Assistant(Some(client.model().id())),
+                "",
+            ));
+            let abort_signal = self.abort_signal.clone();
+            let (output, ret) =
+                stream_chat_completions(&input, client.as_ref(), abort_signal, |rx| {
+                    self.follow_stream(terminal, events, rx)
+                })
+                .await;
+            ret?;
+            let StreamedOutput {
+                text: mut output,
+                tool_calls,
+                stats,
+                timed_out,
+            } = output;
+            self.last_stats = Some(stats);
+            let tool_results = if timed_out {
+                // The tool calls of a cut-off reply may be incomplete, so they are dropped
+                let output_len = output.len();
+                leave_terminal(terminal)?;
+                let ret = close_truncated_output(
+                    &input,
+                    client.as_ref(),
+                    &mut output,
+                    self.abort_signal.clone(),
+                )
+                .await;
+                resume_terminal(terminal)?;
+                let note = ret?;
+                if let Some(entry) = self.entries.last_mut() {
+                    entry.text.push_str(&output[output_len..]);
+                }
+                self.entries.push(Entry::new(EntryKind::Notice, &note));
+                vec![]
+            } else if tool_calls.is_empty() {
+                vec![]
+            } else {
+                if output.is_empty() {
+                    self.entries.pop();
+                }
+                let names: Vec<&str> = tool_calls.iter().map(|v| v.name.as_str()).collect();
+                self.entries.push(Entry::new(
+                    EntryKind::Notice,
+                    &format!("Calling {}", names.join(", ")),
+                ));
+                // Tools print their output and may ask for confirmation
+                leave_terminal(terminal)?;
+                let ret =
+                    eval_tool_calls(&self.config, tool_calls, self.abort_signal.clone()).await;
+                resume_terminal(terminal)?;
+                ret?
+            };
+            self.config
+                .write()
+                .after_chat_completion(&input, &output, &tool_results)?;
+            if tool_results.is_empty() {
+                self.send_to_sinks(&input, &output).await;
+                Config::maybe_autoname_session(self.config.clone());
+                Config::maybe_compress_session(self.config.clone());
+                return Ok(());
+            }
+            input = input.merge_tool_results(output, tool_results);
+        }
+    }
+
+    /// Sends the reply to the output sinks, noting the ones that failed in the conversation.
+    async fn send_to_sinks(&mut self, input: &Input, output: &str) {
+        for failure in Config::send_to_sinks(&self.config, input, output).await {
+            self.entries.push(Entry::new(EntryKind::Notice, &failure));
+        }
+    }
+
+    /// Appends the reply to the last entry as it arrives, keeping the keys for scrolling and
+    /// cancelling live.
+    async fn follow_stream(
+        &mut self,
+        terminal: &mut TuiTerminal,
+        events: &mut EventStream,
+        mut rx: UnboundedReceiver<SseEvent>,
+    ) -> Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            tokio::select! {
+                event = rx.recv() => match event {
+                    Some(SseEvent::Text(text)) => {
+                        if let Some(entry) = self.entries.last_mut() {
+                            entry.text.push_str(&text);
+                        }
+                    }
+                    Some(SseEvent::Notice(text)) => {
+                        // Kept above the reply, which goes on streaming into the last entry
+                        let at = self.entries.len().saturating_sub(1);
+                        self.entries.insert(at, Entry::new(EntryKind::Notice, text.trim()));
+                    }
+                    Some(SseEvent::Done) | None => break,
+                },
+                event = events.next() => match event {
+                    Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                        let ctrl_c = key.code == KeyCode::Char('c')
+                            && key.modifiers.contains(KeyModifiers::CONTROL);
+                        if ctrl_c || key.code == KeyCode::Esc {
+                            self.abort_signal.set_ctrlc();
+                        } else {
+                            self.handle_scroll_key(key);
+                        }
+                    }
+                    Some(Err(err)) => return Err(err.into()),
+                    _ => {}
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn load_session_entries(&mut self) {
+        let config = self.config.read();
+        let Some(session) = &config.session else {
+            return;
+        };
+        for (question, answer) in session.question_answer_pairs() {
+            self.entries.push(Entry::new(EntryKind::User, question));
+            self.entries
+                .push(Entry::new(EntryKind::Assistant(None), answer));
+        }
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> KeyAction {
+        if self.handle_scroll_key(key) {
+            return KeyAction::None;
+        }
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c') if ctrl => {
+                if self.input.is_empty() {
+                    self.entries.push(Entry::new(
+                        EntryKind::Notice,
+                        "(To exit, press Ctrl+D or enter \".exit\")",
+                    ));
+                    self.scroll = None;
+                } else {
+                    self.set_input(String::new());
+                }
+            }
+            KeyCode::Char('d') if ctrl => return KeyAction::Exit,
+            KeyCode::Char('j') if ctrl => self.insert_text("\n"),
+            KeyCode::Char('u') if ctrl => self.set_input(String::new()),
+            KeyCode::Enter
+                if key
+                    .modifiers
+                    .intersects(KeyModifiers::ALT | KeyModifiers::SHIFT) =>
+            {
+                self.insert_text("\n")
+            }
+            KeyCode::Enter => {
+                let line = std::mem::take(&mut self.input);
+                self.cursor = 0;
+                self.history_index = None;
+                if line.trim().is_empty() {
+                    return KeyAction::None;
+                }
+                if self.history.last() != Some(&line) {
+                    self.history.push(line.clone());
+                }
+                return KeyAction::Submit(line);
+            }
+            KeyCode::Char(c)
+                if matches!(key.modifiers, KeyModifiers::NONE | KeyModifiers::SHIFT) =>
+            {
+                self.insert_text(c.encode_utf8(&mut [0; 4]))
+            }
+            KeyCode::Tab => self.insert_text("    "),
+            KeyCode::Backspace => {
+                if let Some(c) = self.input[..self.cursor].chars().next_back() {
+                    self.cursor -= c.len_utf8();
+                    self.input.remove(self.cursor);
+                }
+            }
+            KeyCode::Delete if self.cursor < self.input.len() => {
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Left => {
+                if let Some(c) = self.input[..self.cursor].chars().next_back() {
+                    self.cursor -= c.len_utf8();
+                }
+            }
+            KeyCode::Right => {
+                if let Some(c) = self.input[self.cursor..].chars().next() {
+                    self.cursor += c.len_utf8();
+                }
+            }
+            KeyCode::Home => {
+                self.cursor = self.input[..self.cursor].rfind('\n').map_or(0, |i| i + 1);
+            }
+            KeyCode::End => {
+                self.cursor += self.input[self.cursor..]
+                    .find('\n')
+                    .unwrap_or(self.input.len() - self.cursor);
+            }
+            KeyCode::Up => self.recall_history(true),
+            KeyCode::Down => self.recall_history(false),
+            _ => {}
+        }
+        KeyAction::None
+    }
+
+    /// PgUp/PgDn move a page, Shift+Up/Down a line and Ctrl+End back to the latest output.
+    fn handle_scroll_key(&mut self, key: KeyEvent) -> bool {
+        let page = self.pane_height.saturating_sub(1).max(1) as isize;
+        match key.code {
+            KeyCode::PageUp => self.scroll_by(-page),
+            KeyCode::PageDown => self.scroll_by(page),
+            KeyCode::Up if key.modifiers.contains(KeyModifiers::SHIFT) => self.scroll_by(-1),
+            KeyCode::Down if key.modifiers.contains(KeyModifiers::SHIFT) => self.scroll_by(1),
+            KeyCode::Home if key.modifiers.contains(KeyModifiers::CONTROL) => self.scroll = Some(0),
+            KeyCode::End if key.modifiers.contains(KeyModifiers::CONTROL) => self.scroll = None,
+            _ => return false,
+        }
+        true
+    }
+
+    fn scroll_by(&mut self, delta: isize) {
+        let max_top = self.max_top();
+        let top = self.top_line().saturating_add_signed(delta).min(max_top);
+        self.scroll = if top >= max_top { None } else { Some(top) };
+    }
+
+    fn max_top(&self) -> usize {
+        self.pane_lines.saturating_sub(self.pane_height)
+    }
+
+    fn top_line(&self) -> usize {
+        let max_top = self.max_top();
+        self.scroll.map_or(max_top, |v| v.min(max_top))
+    }
+
+    fn insert_text(&mut self, text: &str) {
+        self.input.insert_str(self.cursor, text);
+        self.cursor += text.len();
+    }
+
+    fn set_input(&mut self, text: String) {
+        self.cursor = text.len();
+        self.input = text;
+    }
+
+    fn recall_history(&mut self, older: bool) {
+        if self.history.is_empty() {
+            return;
+        }
+        let index = match (self.history_index, older) {
+            (None, true) => self.history.len() - 1,
+            (None, false) => return,
+            (Some(i), true) => i.saturating_sub(1),
+            (Some(i), false) if i + 1 < self.history.len() => i + 1,
+            (Some(_), false) => {
+                self.history_index = None;
+                self.set_input(String::new());
+                return;
+            }
+        };
+        self.history_index = Some(index);
+        self.set_input(self.history[index].clone());
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, sidebar] =
+            Layout::horizontal([Constraint::Min(20), Constraint::Length(SIDEBAR_WIDTH)])
+                .areas(frame.area());
+        let input_lines = self.input.split('\n').count() as u16;
+        let [pane, input] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(input_lines.min(MAX_INPUT_LINES) + 2),
+        ])
+        .areas(main);
+        self.draw_conversation(frame, pane);
+        self.draw_input(frame, input);
+        self.draw_sidebar(frame, sidebar);
+    }
+
+    fn draw_conversation(&mut self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(" Conversation ");
+        let inner = block.inner(area);
+        let mut lines = self.conversation_lines(inner.width as usize);
+        self.pane_lines = lines.len();
+        self.pane_height = inner.height as usize;
+        let top = self.top_line();
+        lines.drain(..top);
+        lines.truncate(self.pane_height);
+        frame.render_widget(Paragraph::new(lines).block(block), area);
+    }
+
+    fn conversation_lines(&self, width: usize) -> Vec<Line<'static>> {
+        let hide_thinking = self.config.read().hide_thinking;
+        let mut lines = vec![];
+        for entry in &self.entries {
+            let (header, style) = match &entry.kind {
+                EntryKind::User => (Some("You"), Style::new().fg(Color::Cyan)),
+                EntryKind::Assistant(model_id) => (
+                    Some(model_id.as_deref().unwrap_or("Assistant")),
+                    Style::new().fg(Color::Green),
+                ),
+                EntryKind::Notice => (None, Style::new().fg(Color::DarkGray)),
+                EntryKind::Error => (None, Style::new().fg(Color::Red)),
+            };
+            let text = match entry.kind {
+                EntryKind::Assistant(_) if hide_thinking => strip_think_tag(&entry.text),
+                _ => Cow::Borrowed(entry.text.as_str()),
+            };
+            if let Some(header) = header {
+                lines.push(Line::styled(
+                    header.to_string(),
+                    style.add_modifier(Modifier::BOLD),
+                ));
+            }
+            let body_style = if header.is_some() {
+                Style::new()
+            } else {
+                style
+            };
+            for line in text.replace('\t', "    ").lines() {
+                for part in textwrap::wrap(line, width.max(1)) {
+                    lines.push(Line::styled(part.into_owned(), body_style));
+                }
+            }
+            lines.push(Line::default());
+        }
+        lines
+    }
+
+    fn draw_input(&self, frame: &mut Frame, area: Rect) {
+        let title = if self.busy {
+            " Waiting for the reply, Ctrl+C to cancel "
+        } else {
+            " Input "
+        };
+        let block = Block::bordered().title(title);
+        let inner = block.inner(area);
+        if inner.width == 0 || inner.height == 0 {
+            return;
+        }
+        let before = &self.input[..self.cursor];
+        let row = before.matches('\n').count();
+        let col = before.rsplit('\n').next().unwrap_or_default().width();
+        let top = row.saturating_sub(inner.height as usize - 1);
+        let left = col.saturating_sub(inner.width as usize - 1);
+        let lines: Vec<Line> = self.input.split('\n').skip(top).map(Line::raw).collect();
+        let paragraph = Paragraph::new(lines)
+            .block(block)
+            .scroll((0, left.min(u16::MAX as usize) as u16));
+        frame.render_widget(paragraph, area);
+        if !self.busy {
+            frame
+                .set_cursor_position((inner.x + (col - left) as u16, inner.y + (row - top) as u16));
+        }
+    }
+
+    fn draw_sidebar(&self, frame: &mut Frame, area: Rect) {
+        let config = self.config.read();
+        let role = config.extract_role();
+        let mut lines = vec![field("Model", role.model().id())];
+        if !role.is_derived() {
+            lines.push(field("Role", role.name().to_string()));
+        }
+        match &config.session {
+            Some(session) => {
+                lines.push(field("Session", session.name().to_string()));
+                let (tokens, percent) = session.tokens_usage();
+                lines.push(field("Tokens", format!("{tokens} ({percent}%)")));
+                lines.push(field("Messages", session.user_messages_len().to_string()));
+            }
+            None => lines.push(field("Session", "-".to_string())),
+        }
+        if let Some(rag) = &config.rag {
+            lines.push(field("RAG", rag.name().to_string()));
+        }
+        if let Some(agent) = &config.agent {
+            lines.push(field("Agent", agent.name().to_string()));
+        }
+        if let Some(stats) = &self.last_stats {
+            lines.push(Line::default());
+            lines.push(field("Last reply", stats.to_string()));
+        }
+        let paragraph = Paragraph::new(lines)
+            .block(Block::bordered().title(" Status "))
+            .wrap(Wrap { trim: false });
+        frame.render_widget(paragraph, area);
+    }
+}
+
+fn field(name: &str, value: String) -> Line<'static> {
+    Line::from(vec![
+        Span::styled(format!("{name}: "), Style::new().fg(Color::DarkGray)),
+        Span::raw(value),
+    ])
+}
+
+fn enter_terminal() -> Result<TuiTerminal> {
+    enable_raw_mode()?;
+    execute!(io::stdout(), EnterAlternateScreen, EnableBracketedPaste)?;
+    Ok(Terminal::new(CrosstermBackend::new(io::stdout()))?)
+}
+
+fn leave_terminal(terminal: &mut TuiTerminal) -> Result<()> {
+    disable_raw_mode()?;
+    execute!(
+        terminal.backend_mut(),
+        DisableBracketedPaste,
+        LeaveAlternateScreen
+    )?;
+    terminal.show_cursor()?;
+    Ok(())
+}
+
+fn resume_terminal(terminal: &mut TuiTerminal) -> Result<()> {
+    enable_raw_mode()?;
+    execute!(
+        terminal.backend_mut(),
+        EnterAlternateScreen,
+        EnableBracketedPaste
+    )?;
+    terminal.clear()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use parking_lot::RwLock;
+    use std::sync::Arc;
+
+    fn tui() -> Tui {
+        let config: GlobalConfig = Arc::new(RwLock::new(Config::default()));
+        Tui::init(&config).unwrap()
+    }
+
+    fn press(tui: &mut Tui, code: KeyCode, modifiers: KeyModifiers) -> KeyAction {
+        tui.handle_key(KeyEvent::new(code, modifiers))
+    }
+
+    #[test]
+    fn test_edit_input() {
+        let mut tui = tui();
+        press(&mut tui, KeyCode::Char('a'), KeyModifiers::NONE);
+        press(&mut tui, KeyCode::Char('B'), KeyModifiers::SHIFT);
+        press(&mut tui, KeyCode::Char('x'), KeyModifiers::ALT);
+        press(&mut tui, KeyCode::Char('j'), KeyModifiers::CONTROL);
+        tui.insert_text("héllo");
+        assert_eq!(tui.input, "aB\nhéllo");
+        press(&mut tui, KeyCode::Home, KeyModifiers::NONE);
+        assert_eq!(tui.cursor, 3);
+        press(&mut tui, KeyCode::Right, KeyModifiers::NONE);
+        press(&mut tui, KeyCode::Right, KeyModifiers::NONE);
+        press(&mut tui, KeyCode::Backspace, KeyModifiers::NONE);
+        assert_eq!(tui.input, "aB\nhllo");
+        press(&mut tui, KeyCode::End, KeyModifiers::NONE);
+        assert_eq!(tui.cursor, tui.input.len());
+        let action = press(&mut tui, KeyCode::Enter, KeyModifiers::NONE);
+        assert!(matches!(action, KeyAction::Submit(ref v) if v == "aB\nhllo"));
+        assert!(tui.input.is_empty());
+    }
+
+    #[test]
+    fn test_recall_history() {
+        let mut tui = tui();
+        tui.history = vec!["one".into(), "two".into()];
+        tui.recall_history(true);
+        assert_eq!(tui.input, "two");
+        tui.recall_history(true);
+        tui.recall_history(true);
+        assert_eq!(tui.input, "one");
+        tui.recall_history(false);
+        assert_eq!(tui.input, "two");
+        tui.recall_history(false);
+        assert!(tui.input.is_empty() && tui.history_index.is_none());
+    }
+
+    #[test]
+    fn test_scroll() {
+        let mut tui = tui();
+        tui.pane_lines = 30;
+        tui.pane_height = 10;
+        assert_eq!(tui.top_line(), 20);
+        tui.scroll_by(-5);
+        assert_eq!(tui.scroll, Some(15));
+        tui.scroll_by(-100);
+        assert_eq!(tui.scroll, Some(0));
+        tui.scroll_by(100);
+        assert_eq!(tui.scroll, None);
+        press(&mut tui, KeyCode::PageUp, KeyModifiers::NONE);
+        assert_eq!(tui.top_line(), 11);
+        press(&mut tui, KeyCode::End, KeyModifiers::CONTROL);
+        assert_eq!(tui.scroll, None);
+        tui.pane_lines = 5;
+        assert_eq!(tui.top_line(), 0);
+    }
+}
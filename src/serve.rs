@@ -3,6 +3,7 @@ use crate::{client::*, config::*, function::*, rag::*, utils::*};
 use anyhow::{anyhow, bail, Result};
 use bytes::Bytes;
 use chrono::{Timelike, Utc};
+use fancy_regex::Regex;
 use futures_util::StreamExt;
 use http::{Method, Response, StatusCode};
 use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
@@ -11,16 +12,20 @@ use hyper::{
     service::service_fn,
 };
 use hyper_util::rt::{TokioExecutor, TokioIo};
-use parking_lot::RwLock;
-use serde::Deserialize;
+use indexmap::IndexMap;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::{
+    collections::HashMap,
     convert::Infallible,
     net::IpAddr,
+    path::Path,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc,
     },
+    time::{Duration, Instant},
 };
 use tokio::{
     net::TcpListener,
@@ -33,8 +38,12 @@ use tokio_graceful::Shutdown;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
 const DEFAULT_MODEL_NAME: &str = "default";
+const UPSTREAM_MAX_FAILURES: usize = 3;
+const UPSTREAM_COOLDOWN: u64 = 30;
 const PLAYGROUND_HTML: &[u8] = include_bytes!("../assets/playground.html");
 const ARENA_HTML: &[u8] = include_bytes!("../assets/arena.html");
+const SESSIONS_HTML: &[u8] = include_bytes!("../assets/sessions.html");
+const SESSION_PREVIEW_CHARS: usize = 160;
 
 type AppResponse = Response<BoxBody<Bytes, Infallible>>;
 
@@ -51,7 +60,7 @@ pub async fn run(config: GlobalConfig, addr: Option<String>) -> Result<()> {
         }
         None => config.read().serve_addr(),
     };
-    let server = Arc::new(Server::new(&config));
+    let server = Arc::new(Server::new(&config)?);
     let listener = TcpListener::bind(&addr).await?;
     let stop_server = server.run(listener).await?;
     println!("Chat Completions API: http://{addr}/v1/chat/completions");
@@ -59,6 +68,7 @@ pub async fn run(config: GlobalConfig, addr: Option<String>) -> Result<()> {
     println!("Rerank API:           http://{addr}/v1/rerank");
     println!("LLM Playground:       http://{addr}/playground");
     println!("LLM Arena:            http://{addr}/arena?num=2");
+    println!("Sessions:             http://{addr}/sessions");
     shutdown_signal().await;
     let _ = stop_server.send(());
     Ok(())
@@ -66,20 +76,23 @@ pub async fn run(config: GlobalConfig, addr: Option<String>) -> Result<()> {
 
 struct Server {
     config: Config,
+    upstreams: Vec<Upstream>,
+    rules: Vec<RequestRule>,
     models: Vec<Value>,
     roles: Vec<Role>,
     rags: Vec<String>,
 }
 
 impl Server {
-    fn new(config: &GlobalConfig) -> Self {
+    fn new(config: &GlobalConfig) -> Result<Self> {
         let mut config = config.read().clone();
         config.functions = Functions::default();
-        let mut models = list_all_models(&config);
+        let all_models = list_all_models(&config);
+        let mut models = all_models.clone();
         let mut default_model = config.model.clone();
         default_model.data_mut().name = DEFAULT_MODEL_NAME.into();
         models.insert(0, &default_model);
-        let models: Vec<Value> = models
+        let mut models: Vec<Value> = models
             .into_iter()
             .enumerate()
             .map(|(i, model)| {
@@ -98,12 +111,43 @@ impl Server {
                 value
             })
             .collect();
-        Self {
+        let client_names = list_client_names(&config);
+        let mut upstreams = vec![];
+        for (name, upstream) in config.serve_upstreams.iter() {
+            if let Some(v) = upstream.clients.iter().find(|v| !client_names.contains(v)) {
+                bail!("Unknown client '{v}' in serve upstream '{name}'");
+            }
+            if !client_names.contains(&name) {
+                if let Some(first) = upstream.clients.first() {
+                    for model in all_models.iter().filter(|v| v.client_name() == first) {
+                        let mut value = json!(model.data());
+                        if let Some(value_obj) = value.as_object_mut() {
+                            let id = format!("{name}:{}", model.name());
+                            value_obj.insert("id".into(), id.into());
+                            value_obj.insert("object".into(), "model".into());
+                            value_obj.insert("owned_by".into(), name.as_str().into());
+                            value_obj.remove("name");
+                        }
+                        models.push(value);
+                    }
+                }
+            }
+            upstreams.push(Upstream::new(name, upstream));
+        }
+        upstreams.sort_by(|a, b| a.name.cmp(&b.name));
+        let rules = config
+            .serve_rules
+            .iter()
+            .map(RequestRule::new)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
             config,
+            upstreams,
+            rules,
             models,
             roles: Config::all_roles(),
             rags: Config::list_rags(),
-        }
+        })
     }
 
     async fn run(self: Arc<Self>, listener: TcpListener) -> Result<oneshot::Sender<()>> {
@@ -146,11 +190,15 @@ impl Server {
         let method = req.method().clone();
         let uri = req.uri().clone();
         let path = uri.path();
+        // Saved sessions are private, so only the same-origin sessions page may read them
+        let cors = !is_session_path(path);
 
         if method == Method::OPTIONS {
             let mut res = Response::default();
             *res.status_mut() = StatusCode::NO_CONTENT;
-            set_cors_header(&mut res);
+            if cors {
+                set_cors_header(&mut res);
+            }
             return Ok(res);
         }
 
@@ -167,12 +215,25 @@ impl Server {
             self.list_roles()
         } else if path == "/v1/rags" {
             self.list_rags()
+        } else if path == "/v1/upstreams" {
+            self.list_upstreams()
         } else if path == "/v1/rags/search" {
             self.search_rag(req).await
+        } else if let Some(name) = path
+            .strip_prefix("/v1/rags/")
+            .and_then(|v| v.strip_suffix("/query"))
+        {
+            self.query_rag(name, req).await
         } else if path == "/playground" || path == "/playground.html" {
             self.playground_page()
         } else if path == "/arena" || path == "/arena.html" {
             self.arena_page()
+        } else if path == "/sessions" || path == "/sessions.html" {
+            self.sessions_page()
+        } else if path == "/v1/sessions" {
+            self.list_sessions(uri.query())
+        } else if let Some(name) = path.strip_prefix("/v1/sessions/") {
+            self.get_session(name)
         } else {
             status = StatusCode::NOT_FOUND;
             Err(anyhow!("Not Found"))
@@ -191,7 +252,9 @@ impl Server {
             }
         };
         *res.status_mut() = status;
-        set_cors_header(&mut res);
+        if cors {
+            set_cors_header(&mut res);
+        }
         Ok(res)
     }
 
@@ -209,6 +272,71 @@ impl Server {
         Ok(res)
     }
 
+    fn sessions_page(&self) -> Result<AppResponse> {
+        let res = Response::builder()
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Full::new(Bytes::from(SESSIONS_HTML)).boxed())?;
+        Ok(res)
+    }
+
+    fn session_names(&self) -> Vec<String> {
+        let mut names = self.config.list_sessions();
+        names.extend(
+            self.config
+                .list_autoname_sessions()
+                .into_iter()
+                .map(|v| format!("_/{v}")),
+        );
+        names
+    }
+
+    fn list_sessions(&self, query: Option<&str>) -> Result<AppResponse> {
+        let params = parse_query(query.unwrap_or_default());
+        let search = params
+            .get("q")
+            .map(|v| v.trim().to_lowercase())
+            .filter(|v| !v.is_empty());
+        let tag = params.get("tag").filter(|v| !v.is_empty());
+        let sessions_dir = self.config.sessions_dir();
+        let mut sessions = vec![];
+        for name in self.session_names() {
+            let session = match SessionView::load(&sessions_dir, &name) {
+                Ok(session) => session,
+                Err(err) => {
+                    warn!("Skip session '{name}', {err}");
+                    continue;
+                }
+            };
+            if tag.is_some_and(|tag| !session.tags.contains(tag)) {
+                continue;
+            }
+            if search.as_ref().is_some_and(|v| !session.contains(v)) {
+                continue;
+            }
+            sessions.push(session);
+        }
+        sessions.sort_by(|a, b| b.updated.cmp(&a.updated));
+        let data: Vec<Value> = sessions.iter().map(|v| v.summary()).collect();
+        let data = json!({ "data": data });
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    fn get_session(&self, name: &str) -> Result<AppResponse> {
+        let name = urlencoding::decode(name)?;
+        if !self.session_names().iter().any(|v| v == &name) {
+            bail!("Unknown session '{name}'");
+        }
+        let session = SessionView::load(&self.config.sessions_dir(), &name)?;
+        let data = json!({ "data": session });
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
     fn list_models(&self) -> Result<AppResponse> {
         let data = json!({ "data": self.models });
         let res = Response::builder()
@@ -233,16 +361,30 @@ impl Server {
         Ok(res)
     }
 
+    fn list_upstreams(&self) -> Result<AppResponse> {
+        let data: Vec<Value> = self.upstreams.iter().map(|v| v.status()).collect();
+        let data = json!({ "data": data });
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
     async fn search_rag(&self, req: hyper::Request<Incoming>) -> Result<AppResponse> {
         let req_body = req.collect().await?.to_bytes();
         let req_body: Value = serde_json::from_slice(&req_body)
             .map_err(|err| anyhow!("Invalid request json, {err}"))?;
 
         debug!("search rag request: {req_body}");
-        let SearchRagReqBody { name, input } = serde_json::from_value(req_body)
+        let SearchRagReqBody {
+            name,
+            input,
+            filter,
+        } = serde_json::from_value(req_body)
             .map_err(|err| anyhow!("Invalid request body, {err}"))?;
 
         let config = Arc::new(RwLock::new(self.config.clone()));
+        config.write().rag_filter = filter.as_deref().map(RagFilter::parse).transpose()?;
 
         let abort_signal = create_abort_signal();
 
@@ -258,11 +400,58 @@ impl Server {
         Ok(res)
     }
 
-    async fn chat_completions(&self, req: hyper::Request<Incoming>) -> Result<AppResponse> {
+    async fn query_rag(&self, name: &str, req: hyper::Request<Incoming>) -> Result<AppResponse> {
+        let name = urlencoding::decode(name)?;
+        if !Config::list_rags().iter().any(|v| v == &name) {
+            bail!("Unknown RAG '{name}'");
+        }
         let req_body = req.collect().await?.to_bytes();
         let req_body: Value = serde_json::from_slice(&req_body)
             .map_err(|err| anyhow!("Invalid request json, {err}"))?;
 
+        debug!("query rag request: {req_body}");
+        let QueryRagReqBody {
+            input,
+            top_k,
+            min_score,
+            rerank,
+            filter,
+        } = serde_json::from_value(req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+        if top_k == Some(0) {
+            bail!("Invalid top_k, it must be at least 1");
+        }
+
+        let config = Arc::new(RwLock::new(self.config.clone()));
+        let filter = filter.as_deref().map(RagFilter::parse).transpose()?;
+
+        let rag_path = config.read().rag_file(&name);
+        let mut rag = Rag::load(&config, &name, &rag_path)?;
+        rag.set_overrides(RagOverrides {
+            top_k,
+            min_score,
+            rerank,
+            template: None,
+        })?;
+
+        let matches = rag
+            .query(&input, filter.as_ref(), create_abort_signal())
+            .await?;
+
+        // Serialized directly, as a `Value` would widen the f32 scores to noisy f64s
+        let data = format!(r#"{{"data":{}}}"#, serde_json::to_string(&matches)?);
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data)).boxed())?;
+        Ok(res)
+    }
+
+    async fn chat_completions(&self, req: hyper::Request<Incoming>) -> Result<AppResponse> {
+        let req_body = req.collect().await?.to_bytes();
+        let mut req_body: Value = serde_json::from_slice(&req_body)
+            .map_err(|err| anyhow!("Invalid request json, {err}"))?;
+        apply_rules(&self.rules, &mut req_body);
+
         debug!("chat completions request: {req_body}");
         let req_body = serde_json::from_value(req_body)
             .map_err(|err| anyhow!("Invalid request body, {err}"))?;
@@ -273,36 +462,87 @@ impl Server {
             temperature,
             top_p,
             max_tokens,
+            logprobs,
+            top_logprobs,
+            seed,
+            stop,
             stream,
             tools,
+            documents,
+            google_search,
+            guided,
+            metadata,
         } = req_body;
 
-        let mut messages =
+        let stop = stop.map(|v| match v {
+            ChatCompletionsReqBodyStop::Single(v) => vec![v],
+            ChatCompletionsReqBodyStop::Multiple(v) => v,
+        });
+
+        let messages =
             parse_messages(messages).map_err(|err| anyhow!("Invalid request body, {err}"))?;
 
         let functions = parse_tools(tools).map_err(|err| anyhow!("Invalid request body, {err}"))?;
 
-        let config = self.config.clone();
+        let documents =
+            parse_documents(documents).map_err(|err| anyhow!("Invalid request body, {err}"))?;
 
-        let default_model = config.model.clone();
-
-        let config = Arc::new(RwLock::new(config));
-
-        let (model_name, change) = if model == DEFAULT_MODEL_NAME {
-            (default_model.id(), true)
-        } else if default_model.id() == model {
-            (model, false)
+        let model_name = if model == DEFAULT_MODEL_NAME {
+            self.config.model.id()
         } else {
-            (model, true)
+            model
         };
 
-        if change {
-            config.write().set_model(&model_name)?;
+        let data = ChatCompletionsData {
+            messages,
+            temperature,
+            top_p,
+            frequency_penalty: None,
+            presence_penalty: None,
+            logprobs,
+            top_logprobs,
+            seed,
+            stop,
+            functions,
+            stream,
+            google_search,
+            guided: (!guided.is_empty()).then_some(guided),
+            documents,
+        };
+
+        self.balance(&model_name, |model_id| {
+            self.send_chat_completions(model_id, &model_name, max_tokens, &metadata, data.clone())
+        })
+        .await
+    }
+
+    async fn send_chat_completions(
+        &self,
+        model_id: String,
+        model_name: &str,
+        max_tokens: Option<isize>,
+        metadata: &IndexMap<String, String>,
+        mut data: ChatCompletionsData,
+    ) -> Result<AppResponse> {
+        let config = Arc::new(RwLock::new(self.config.clone()));
+        config.write().tags.extend(metadata.clone());
+
+        if config.read().model.id() != model_id {
+            config.write().set_model(&model_id)?;
         }
 
         let mut client = init_client(&config, None)?;
-        if max_tokens.is_some() {
-            client.model_mut().set_max_tokens(max_tokens, true);
+        if data.documents.is_some()
+            && client_type(&config.read(), client.name()) != Some(CohereClient::NAME)
+        {
+            bail!("Model '{model_id}' doesn't support documents");
+        }
+        if let Some(value) = max_tokens {
+            let model = client.model_mut();
+            model.set_max_tokens(Some(value));
+            if model.clamp_max_tokens(value) < value {
+                warn!("Clamped max_tokens {value} to the output limit of '{model_id}'");
+            }
         }
         let abort_signal = create_abort_signal();
         let http_client = client.build_client()?;
@@ -310,19 +550,9 @@ impl Server {
         let completion_id = generate_completion_id();
         let created = Utc::now().timestamp();
 
-        patch_messages(&mut messages, client.model());
+        patch_messages(&mut data.messages, client.model());
 
-        let data: ChatCompletionsData = ChatCompletionsData {
-            messages,
-            temperature,
-            top_p,
-            frequency_penalty: None,
-            presence_penalty: None,
-            functions,
-            stream,
-        };
-
-        if stream {
+        if data.stream {
             let (tx, mut rx) = unbounded_channel();
             tokio::spawn(async move {
                 let is_first = Arc::new(AtomicBool::new(true));
@@ -342,6 +572,9 @@ impl Server {
                             SseEvent::Text(text) => {
                                 let _ = tx.send(ResEvent::Text(text));
                             }
+                            SseEvent::Notice(text) => {
+                                debug!("stream notice: {}", text.trim());
+                            }
                             SseEvent::Done => {
                                 let _ = tx.send(ResEvent::Done);
                                 sse_rx.close();
@@ -357,9 +590,22 @@ impl Server {
                     tx: &UnboundedSender<ResEvent>,
                     is_first: Arc<AtomicBool>,
                 ) {
-                    if client.model().no_stream() {
+                    // Response middleware must see the whole reply before any of it is sent
+                    let config = client.global_config();
+                    if client.model().no_stream() || has_response_middleware(config, client.model())
+                    {
                         data.stream = false;
-                        let ret = client.chat_completions_inner(http_client, data).await;
+                        let ret = client
+                            .chat_completions_with_timeout(http_client, data)
+                            .await;
+                        let ret = match ret {
+                            Ok(mut output) => {
+                                apply_response_middleware(config, client.model(), &mut output)
+                                    .await
+                                    .map(|_| output)
+                            }
+                            Err(err) => Err(err),
+                        };
                         match ret {
                             Ok(output) => {
                                 let ChatCompletionsOutput {
@@ -379,7 +625,7 @@ impl Server {
                         };
                     } else {
                         let ret = client
-                            .chat_completions_streaming_inner(http_client, handler, data)
+                            .chat_completions_streaming_with_timeout(http_client, handler, data)
                             .await;
                         let first = match ret {
                             Ok(()) => None,
@@ -415,8 +661,12 @@ impl Server {
                 bail!("{err}");
             }
 
-            let shared: Arc<(String, String, i64, AtomicBool)> =
-                Arc::new((completion_id, model_name, created, AtomicBool::new(false)));
+            let shared: Arc<(String, String, i64, AtomicBool)> = Arc::new((
+                completion_id,
+                model_name.to_string(),
+                created,
+                AtomicBool::new(false),
+            ));
             let stream = UnboundedReceiverStream::new(rx);
             let stream = stream.filter_map(move |res_event| {
                 let shared = shared.clone();
@@ -453,17 +703,14 @@ impl Server {
                 .body(BodyExt::boxed(StreamBody::new(stream)))?;
             Ok(res)
         } else {
-            let output = client.chat_completions_inner(&http_client, data).await?;
+            let mut output = client
+                .chat_completions_with_timeout(&http_client, data)
+                .await?;
+            apply_response_middleware(&config, client.model(), &mut output).await?;
             let res = Response::builder()
                 .header("Content-Type", "application/json")
                 .body(
-                    Full::new(ret_non_stream(
-                        &completion_id,
-                        &model_name,
-                        created,
-                        &output,
-                    ))
-                    .boxed(),
+                    Full::new(ret_non_stream(&completion_id, model_name, created, &output)).boxed(),
                 )?;
             Ok(res)
         }
@@ -471,8 +718,9 @@ impl Server {
 
     async fn embeddings(&self, req: hyper::Request<Incoming>) -> Result<AppResponse> {
         let req_body = req.collect().await?.to_bytes();
-        let req_body: Value = serde_json::from_slice(&req_body)
+        let mut req_body: Value = serde_json::from_slice(&req_body)
             .map_err(|err| anyhow!("Invalid request json, {err}"))?;
+        apply_rules(&self.rules, &mut req_body);
 
         debug!("embeddings request: {req_body}");
         let req_body = serde_json::from_value(req_body)
@@ -483,20 +731,17 @@ impl Server {
             model: embedding_model_id,
         } = req_body;
 
-        let config = Arc::new(RwLock::new(self.config.clone()));
-
-        let embedding_model =
-            Model::retrieve_model(&config.read(), &embedding_model_id, ModelType::Embedding)?;
-
         let texts = match input {
             EmbeddingsReqBodyInput::Single(v) => vec![v],
             EmbeddingsReqBodyInput::Multiple(v) => v,
         };
-        let client = init_client(&config, Some(embedding_model))?;
-        let data = client
-            .embeddings(&EmbeddingsData {
-                query: false,
-                texts,
+        let data = EmbeddingsData {
+            query: false,
+            texts,
+        };
+        let data = self
+            .balance(&embedding_model_id, |model_id| {
+                self.send_embeddings(model_id, &data)
             })
             .await?;
         let data: Vec<_> = data
@@ -527,8 +772,9 @@ impl Server {
 
     async fn rerank(&self, req: hyper::Request<Incoming>) -> Result<AppResponse> {
         let req_body = req.collect().await?.to_bytes();
-        let req_body: Value = serde_json::from_slice(&req_body)
+        let mut req_body: Value = serde_json::from_slice(&req_body)
             .map_err(|err| anyhow!("Invalid request json, {err}"))?;
+        apply_rules(&self.rules, &mut req_body);
 
         debug!("rerank request: {req_body}");
         let req_body = serde_json::from_value(req_body)
@@ -543,17 +789,14 @@ impl Server {
 
         let top_n = top_n.unwrap_or(documents.len());
 
-        let config = Arc::new(RwLock::new(self.config.clone()));
-
-        let reranker_model =
-            Model::retrieve_model(&config.read(), &reranker_model_id, ModelType::Reranker)?;
-
-        let client = init_client(&config, Some(reranker_model))?;
-        let data = client
-            .rerank(&RerankData {
-                query,
-                documents: documents.clone(),
-                top_n,
+        let data = RerankData {
+            query,
+            documents: documents.clone(),
+            top_n,
+        };
+        let data = self
+            .balance(&reranker_model_id, |model_id| {
+                self.send_rerank(model_id, &data)
             })
             .await?;
 
@@ -576,12 +819,256 @@ impl Server {
             .body(Full::new(Bytes::from(output.to_string())).boxed())?;
         Ok(res)
     }
+
+    async fn send_embeddings(
+        &self,
+        model_id: String,
+        data: &EmbeddingsData,
+    ) -> Result<EmbeddingsOutput> {
+        let config = Arc::new(RwLock::new(self.config.clone()));
+        let embedding_model =
+            Model::retrieve_model(&config.read(), &model_id, ModelType::Embedding)?;
+        let client = init_client(&config, Some(embedding_model))?;
+        client.embeddings(data).await
+    }
+
+    async fn send_rerank(&self, model_id: String, data: &RerankData) -> Result<RerankOutput> {
+        let config = Arc::new(RwLock::new(self.config.clone()));
+        let reranker_model = Model::retrieve_model(&config.read(), &model_id, ModelType::Reranker)?;
+        let client = init_client(&config, Some(reranker_model))?;
+        client.rerank(data).await
+    }
+
+    /// Runs `send` against the clients of the upstream `model_id` belongs to, falling through to
+    /// the next client on failure. Models outside any upstream are sent as-is.
+    async fn balance<T, F, Fut>(&self, model_id: &str, send: F) -> Result<T>
+    where
+        F: Fn(String) -> Fut,
+        Fut: std::future::Future<Output = Result<T>>,
+    {
+        let (upstream_name, model_name) = match model_id.split_once(':') {
+            Some((upstream_name, model_name)) => (upstream_name, Some(model_name)),
+            None => (model_id, None),
+        };
+        let Some(upstream) = self.upstreams.iter().find(|v| v.name == upstream_name) else {
+            return send(model_id.to_string()).await;
+        };
+        let mut last_err = None;
+        for index in upstream.candidates() {
+            let client_name = &upstream.clients[index];
+            let member_model_id = match model_name {
+                Some(model_name) => format!("{client_name}:{model_name}"),
+                None => client_name.to_string(),
+            };
+            match send(member_model_id).await {
+                Ok(output) => {
+                    upstream.report(index, true);
+                    return Ok(output);
+                }
+                Err(err) => {
+                    warn!("Upstream {upstream_name}: client {client_name} failed, {err}");
+                    upstream.report(index, false);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow!("No clients in upstream '{upstream_name}'")))
+    }
+}
+
+/// A group of clients that serve mode spreads requests across in round-robin order.
+/// Consecutive failures eject a client for a cooldown, after which it gets one trial request.
+#[derive(Debug)]
+struct Upstream {
+    name: String,
+    clients: Vec<String>,
+    max_failures: usize,
+    cooldown: Duration,
+    cursor: AtomicUsize,
+    health: Mutex<Vec<UpstreamHealth>>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct UpstreamHealth {
+    failures: usize,
+    ejected_until: Option<Instant>,
+}
+
+impl Upstream {
+    fn new(name: &str, upstream: &ServeUpstream) -> Self {
+        Self {
+            name: name.to_string(),
+            clients: upstream.clients.clone(),
+            max_failures: upstream
+                .max_failures
+                .unwrap_or(UPSTREAM_MAX_FAILURES)
+                .max(1),
+            cooldown: Duration::from_secs(upstream.cooldown.unwrap_or(UPSTREAM_COOLDOWN)),
+            cursor: AtomicUsize::new(0),
+            health: Mutex::new(vec![UpstreamHealth::default(); upstream.clients.len()]),
+        }
+    }
+
+    /// Client indexes to try, in order. Falls back to the ejected clients if none is available.
+    fn candidates(&self) -> Vec<usize> {
+        let len = self.clients.len();
+        if len == 0 {
+            return vec![];
+        }
+        let start = self.cursor.fetch_add(1, Ordering::Relaxed) % len;
+        let now = Instant::now();
+        let health = self.health.lock();
+        let (available, ejected): (Vec<usize>, Vec<usize>) = (0..len)
+            .map(|i| (start + i) % len)
+            .partition(|&i| health[i].ejected_until.is_none_or(|v| v <= now));
+        if available.is_empty() {
+            ejected
+        } else {
+            available
+        }
+    }
+
+    fn report(&self, index: usize, ok: bool) {
+        let mut health = self.health.lock();
+        let state = &mut health[index];
+        if ok {
+            *state = UpstreamHealth::default();
+            return;
+        }
+        state.failures += 1;
+        if state.failures >= self.max_failures {
+            state.ejected_until = Some(Instant::now() + self.cooldown);
+            warn!(
+                "Upstream {}: ejected client {} for {}s",
+                self.name,
+                self.clients[index],
+                self.cooldown.as_secs()
+            );
+        }
+    }
+
+    fn status(&self) -> Value {
+        let now = Instant::now();
+        let health = self.health.lock();
+        let clients: Vec<Value> = self
+            .clients
+            .iter()
+            .zip(health.iter())
+            .map(|(name, state)| {
+                let ejected_for = state
+                    .ejected_until
+                    .and_then(|v| v.checked_duration_since(now))
+                    .map(|v| v.as_secs());
+                json!({
+                    "name": name,
+                    "healthy": ejected_for.is_none(),
+                    "failures": state.failures,
+                    "ejected_for": ejected_for,
+                })
+            })
+            .collect();
+        json!({
+            "name": self.name,
+            "clients": clients,
+        })
+    }
+}
+
+/// A `serve_rules` entry with its model pattern compiled.
+#[derive(Debug)]
+struct RequestRule {
+    pattern: Option<Regex>,
+    rule: ServeRule,
+}
+
+impl RequestRule {
+    fn new(rule: &ServeRule) -> Result<Self> {
+        let pattern = match &rule.model_match {
+            Some(v) => Some(
+                Regex::new(&format!("^(?:{v})$"))
+                    .map_err(|err| anyhow!("Invalid serve rule match '{v}', {err}"))?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            pattern,
+            rule: rule.clone(),
+        })
+    }
+
+    fn matches(&self, model: &str) -> bool {
+        self.pattern
+            .as_ref()
+            .is_none_or(|v| v.is_match(model).unwrap_or_default())
+    }
+}
+
+/// Rewrites a request body with the rules in order, each seeing the model the earlier ones
+/// mapped to.
+fn apply_rules(rules: &[RequestRule], body: &mut Value) {
+    let Some(body) = body.as_object_mut() else {
+        return;
+    };
+    for (i, rule) in rules.iter().enumerate() {
+        let model = body
+            .get("model")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_string();
+        if !rule.matches(&model) {
+            continue;
+        }
+        debug!("serve rule #{i} applies to '{model}'");
+        let rule = &rule.rule;
+        for key in &rule.strip {
+            body.remove(key);
+        }
+        if let Some(model) = &rule.model {
+            body.insert("model".into(), model.as_str().into());
+        }
+        let Some(messages) = body.get_mut("messages").and_then(|v| v.as_array_mut()) else {
+            continue;
+        };
+        if let Some(prompt) = &rule.system_prompt {
+            match messages.first_mut() {
+                Some(first) if first["role"] == "system" && first["content"].is_string() => {
+                    let content = first["content"].as_str().unwrap_or_default();
+                    first["content"] = format!("{prompt}\n\n{content}").into();
+                }
+                _ => messages.insert(0, json!({ "role": "system", "content": prompt })),
+            }
+        }
+        if let Some(cap) = rule.max_tokens {
+            let capped = |v: Option<&Value>| {
+                v.and_then(|v| v.as_i64())
+                    .map_or(cap, |v| cap.min(v as isize))
+            };
+            if let Some(value) = body.get("max_completion_tokens") {
+                let value = capped(Some(value));
+                body.insert("max_completion_tokens".into(), value.into());
+            }
+            if body.contains_key("max_tokens") || !body.contains_key("max_completion_tokens") {
+                let value = capped(body.get("max_tokens"));
+                body.insert("max_tokens".into(), value.into());
+            }
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 struct SearchRagReqBody {
     name: String,
     input: String,
+    filter: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryRagReqBody {
+    input: String,
+    top_k: Option<usize>,
+    min_score: Option<f32>,
+    rerank: Option<bool>,
+    filter: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -592,8 +1079,28 @@ struct ChatCompletionsReqBody {
     top_p: Option<f64>,
     max_tokens: Option<isize>,
     #[serde(default)]
+    logprobs: bool,
+    top_logprobs: Option<usize>,
+    seed: Option<u64>,
+    stop: Option<ChatCompletionsReqBodyStop>,
+    #[serde(default)]
     stream: bool,
     tools: Option<Vec<Value>>,
+    documents: Option<Vec<Value>>,
+    #[serde(default)]
+    google_search: bool,
+    #[serde(flatten)]
+    guided: GuidedDecoding,
+    /// Tags for the audit log, added to the server's `--tag` ones
+    #[serde(default)]
+    metadata: IndexMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ChatCompletionsReqBodyStop {
+    Single(String),
+    Multiple(Vec<String>),
 }
 
 #[derive(Debug, Deserialize)]
@@ -617,6 +1124,147 @@ struct RerankReqBody {
     top_n: Option<usize>,
 }
 
+/// A saved session as the read-only sessions view shows it.
+#[derive(Debug, Serialize)]
+struct SessionView {
+    name: String,
+    model: String,
+    role: Option<String>,
+    tags: Vec<String>,
+    updated: Option<String>,
+    messages: Vec<SessionViewMessage>,
+}
+
+#[derive(Debug, Serialize)]
+struct SessionViewMessage {
+    role: MessageRole,
+    content: String,
+    /// The image urls of a multimodal message, `blob:<sha256>` for stored ones
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SessionFile {
+    #[serde(default)]
+    model: String,
+    role_name: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    compressed_messages: Vec<Message>,
+    #[serde(default)]
+    messages: Vec<Message>,
+}
+
+impl SessionView {
+    fn load(sessions_dir: &Path, name: &str) -> Result<Self> {
+        let path = sessions_dir.join(format!("{name}.yaml"));
+        let content = std::fs::read_to_string(&path)?;
+        let file: SessionFile = serde_yaml::from_str(&content)?;
+        let updated = std::fs::metadata(&path)
+            .and_then(|v| v.modified())
+            .ok()
+            .map(|v| {
+                chrono::DateTime::<Utc>::from(v).to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+            });
+        let mut tags = file.tags;
+        if let Some(role) = &file.role_name {
+            if !tags.contains(role) {
+                tags.push(role.clone());
+            }
+        }
+        let messages = file
+            .compressed_messages
+            .iter()
+            .chain(file.messages.iter())
+            .filter(|v| !v.role.is_system())
+            .map(|message| {
+                let content = match &message.content {
+                    MessageContent::ToolCalls(calls) => {
+                        let mut lines: Vec<String> = calls
+                            .tool_results
+                            .iter()
+                            .map(|v| format!("`{}({})`", v.call.name, v.call.arguments))
+                            .collect();
+                        if !calls.text.is_empty() {
+                            lines.insert(0, calls.text.clone());
+                        }
+                        lines.join("\n\n")
+                    }
+                    content => content.to_text(),
+                };
+                let images = match &message.content {
+                    MessageContent::Array(list) => list
+                        .iter()
+                        .filter_map(|v| match v {
+                            MessageContentPart::ImageUrl { image_url } => {
+                                Some(image_url.url.clone())
+                            }
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => vec![],
+                };
+                SessionViewMessage {
+                    role: message.role,
+                    content,
+                    images,
+                }
+            })
+            .collect();
+        Ok(Self {
+            name: name.to_string(),
+            model: file.model,
+            role: file.role_name,
+            tags,
+            updated,
+            messages,
+        })
+    }
+
+    fn contains(&self, search: &str) -> bool {
+        self.name.to_lowercase().contains(search)
+            || self
+                .messages
+                .iter()
+                .any(|v| v.content.to_lowercase().contains(search))
+    }
+
+    fn summary(&self) -> Value {
+        let preview: String = self
+            .messages
+            .iter()
+            .find(|v| v.role.is_user())
+            .map(|v| v.content.chars().take(SESSION_PREVIEW_CHARS).collect())
+            .unwrap_or_default();
+        json!({
+            "name": self.name,
+            "model": self.model,
+            "role": self.role,
+            "tags": self.tags,
+            "updated": self.updated,
+            "messages": self.messages.len(),
+            "preview": preview,
+        })
+    }
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let decode = |v: &str| {
+                urlencoding::decode(&v.replace('+', " "))
+                    .ok()
+                    .map(|v| v.into_owned())
+            };
+            Some((decode(key)?, decode(value)?))
+        })
+        .collect()
+}
+
 #[derive(Debug)]
 enum ResEvent {
     First(Option<String>),
@@ -636,6 +1284,10 @@ fn generate_completion_id() -> String {
     format!("chatcmpl-{random_id}")
 }
 
+fn is_session_path(path: &str) -> bool {
+    path == "/v1/sessions" || path.starts_with("/v1/sessions/")
+}
+
 fn set_cors_header(res: &mut AppResponse) {
     res.headers_mut().insert(
         hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN,
@@ -746,6 +1398,10 @@ fn ret_non_stream(id: &str, model: &str, created: i64, output: &ChatCompletionsO
     let input_tokens = output.input_tokens.unwrap_or_default();
     let output_tokens = output.output_tokens.unwrap_or_default();
     let total_tokens = input_tokens + output_tokens;
+    let logprobs = match &output.logprobs {
+        Some(v) => json!({ "content": v }),
+        None => Value::Null,
+    };
     let choice = if output.tool_calls.is_empty() {
         json!({
             "index": 0,
@@ -753,7 +1409,7 @@ fn ret_non_stream(id: &str, model: &str, created: i64, output: &ChatCompletionsO
                 "role": "assistant",
                 "content": output.text,
             },
-            "logprobs": null,
+            "logprobs": logprobs,
             "finish_reason": "stop",
         })
     } else {
@@ -783,7 +1439,7 @@ fn ret_non_stream(id: &str, model: &str, created: i64, output: &ChatCompletionsO
                 "content": content,
                 "tool_calls": tool_calls,
             },
-            "logprobs": null,
+            "logprobs": logprobs,
             "finish_reason": "tool_calls",
         })
     };
@@ -793,6 +1449,7 @@ fn ret_non_stream(id: &str, model: &str, created: i64, output: &ChatCompletionsO
         "created": created,
         "model": model,
         "choices": [choice],
+        "system_fingerprint": output.system_fingerprint,
         "usage": {
             "prompt_tokens": input_tokens,
             "completion_tokens": output_tokens,
@@ -888,7 +1545,7 @@ fn parse_messages(message: Vec<Value>) -> Result<Vec<Message>> {
                     if tool_calls.len() == tool_values.len() {
                         let mut list = vec![];
                         for ((id, name, arguments), (value, tool_call_id)) in
-                            tool_calls.into_iter().zip(tool_values.into_iter())
+                            tool_calls.into_iter().zip(tool_values)
                         {
                             if id != tool_call_id {
                                 return Err(err());
@@ -919,6 +1576,36 @@ fn parse_messages(message: Vec<Value>) -> Result<Vec<Message>> {
     Ok(output)
 }
 
+/// Accepts Cohere-style documents: plain strings, or objects whose fields may be nested under `data`.
+fn parse_documents(documents: Option<Vec<Value>>) -> Result<Option<Vec<ChatDocument>>> {
+    let documents = match documents {
+        Some(v) if !v.is_empty() => v,
+        _ => return Ok(None),
+    };
+    let mut output = vec![];
+    for (i, document) in documents.into_iter().enumerate() {
+        let id = document["id"]
+            .as_str()
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| format!("doc-{i}"));
+        let data = if document["data"].is_object() {
+            &document["data"]
+        } else {
+            &document
+        };
+        let text = match data.as_str() {
+            Some(text) => text.to_string(),
+            None => match data["text"].as_str().or_else(|| data["snippet"].as_str()) {
+                Some(text) => text.to_string(),
+                None => bail!("Failed to parse '.documents[{i}]'"),
+            },
+        };
+        let title = data["title"].as_str().map(|v| v.to_string());
+        output.push(ChatDocument { id, title, text });
+    }
+    Ok(Some(output))
+}
+
 fn parse_tools(tools: Option<Vec<Value>>) -> Result<Option<Vec<FunctionDeclaration>>> {
     let tools = match tools {
         Some(v) => v,
@@ -939,3 +1626,108 @@ fn parse_tools(tools: Option<Vec<Value>>) -> Result<Option<Vec<FunctionDeclarati
     }
     Ok(Some(functions))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sessions_html_sanitizes_messages() {
+        let html = String::from_utf8_lossy(SESSIONS_HTML);
+        assert!(html.contains("/purify.min.js"));
+        assert!(html.contains(r#"x-html="renderMessage(message)""#));
+        let render = html.split("function renderMessage(").nth(1).unwrap();
+        let render = &render[..render.find("\n    }").unwrap()];
+        assert!(render.contains("return DOMPurify.sanitize("));
+    }
+
+    #[test]
+    fn test_upstream_ejection() {
+        let upstream = Upstream::new(
+            "openai",
+            &ServeUpstream {
+                clients: vec!["a".into(), "b".into()],
+                max_failures: Some(2),
+                cooldown: Some(60),
+            },
+        );
+        assert_eq!(upstream.candidates(), vec![0, 1]);
+        assert_eq!(upstream.candidates(), vec![1, 0]);
+
+        upstream.report(0, false);
+        assert_eq!(upstream.candidates(), vec![0, 1]);
+        upstream.report(0, false);
+        assert_eq!(upstream.candidates(), vec![1]);
+        assert_eq!(upstream.candidates(), vec![1]);
+
+        upstream.report(1, false);
+        upstream.report(1, false);
+        assert_eq!(upstream.candidates(), vec![1, 0]);
+
+        upstream.report(0, true);
+        assert_eq!(upstream.candidates(), vec![0]);
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let params = parse_query("q=connection+reset&tag=rust%2Fasync&empty");
+        assert_eq!(params["q"], "connection reset");
+        assert_eq!(params["tag"], "rust/async");
+        assert_eq!(params["empty"], "");
+    }
+
+    #[test]
+    fn test_apply_rules() {
+        let rules: Vec<RequestRule> = [
+            ServeRule {
+                model_match: Some("gpt-4.*".into()),
+                model: Some("openai:gpt-4o-mini".into()),
+                strip: vec!["seed".into()],
+                ..Default::default()
+            },
+            ServeRule {
+                system_prompt: Some("Be safe".into()),
+                max_tokens: Some(100),
+                ..Default::default()
+            },
+        ]
+        .iter()
+        .map(|v| RequestRule::new(v).unwrap())
+        .collect();
+
+        let mut body = json!({
+            "model": "gpt-4o",
+            "seed": 1,
+            "max_tokens": 500,
+            "messages": [{ "role": "system", "content": "Hi" }],
+        });
+        apply_rules(&rules, &mut body);
+        assert_eq!(
+            body,
+            json!({
+                "model": "openai:gpt-4o-mini",
+                "max_tokens": 100,
+                "messages": [{ "role": "system", "content": "Be safe\n\nHi" }],
+            })
+        );
+
+        let mut body = json!({ "model": "my-gpt-4", "input": "text" });
+        apply_rules(&rules, &mut body);
+        assert_eq!(body, json!({ "model": "my-gpt-4", "input": "text" }));
+
+        let mut body = json!({ "messages": [] });
+        apply_rules(&rules, &mut body);
+        assert_eq!(
+            body,
+            json!({
+                "max_tokens": 100,
+                "messages": [{ "role": "system", "content": "Be safe" }],
+            })
+        );
+
+        let mut body = json!({ "model": "o3", "max_completion_tokens": 500, "messages": [] });
+        apply_rules(&rules, &mut body);
+        assert_eq!(body["max_completion_tokens"], 100);
+        assert!(body.get("max_tokens").is_none());
+    }
+}
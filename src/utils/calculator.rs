@@ -0,0 +1,441 @@
+use super::*;
+
+/// Evaluates an arithmetic expression: `+ - * / %`, `^` or `**` for powers, parentheses,
+/// the constants `pi` and `e`, and functions such as `sqrt`, `ln`, `log`, `sin`, `round`,
+/// `min` and `max`.
+pub fn evaluate_expression(expression: &str) -> Result<f64> {
+    let tokens = tokenize(expression)?;
+    let mut parser = ExprParser { tokens, pos: 0 };
+    let value = parser.parse_sum()?;
+    if let Some(token) = parser.peek() {
+        bail!("Unexpected '{token}' in '{expression}'");
+    }
+    if !value.is_finite() {
+        bail!("'{expression}' has no finite value");
+    }
+    Ok(value)
+}
+
+/// Renders a computed number without float noise, e.g. `0.30000000000000004` as `0.3`.
+pub fn format_number(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        return format!("{}", value as i64);
+    }
+    let text = format!("{value:.12}");
+    let text = text.trim_end_matches('0').trim_end_matches('.');
+    if text == "0" || text == "-0" {
+        format!("{value:e}")
+    } else {
+        text.to_string()
+    }
+}
+
+/// Converts between units of length, mass, time, volume, area, speed, data, energy,
+/// pressure and temperature.
+pub fn convert_unit(value: f64, from: &str, to: &str) -> Result<f64> {
+    let (from_kind, from_factor) = find_unit(from)?;
+    let (to_kind, to_factor) = find_unit(to)?;
+    if from_kind != to_kind {
+        bail!("Cannot convert {from_kind} '{from}' to {to_kind} '{to}'");
+    }
+    if from_kind == "temperature" {
+        let kelvin = match from_factor as u8 {
+            0 => value + 273.15,
+            1 => (value - 32.0) * 5.0 / 9.0 + 273.15,
+            _ => value,
+        };
+        let output = match to_factor as u8 {
+            0 => kelvin - 273.15,
+            1 => (kelvin - 273.15) * 9.0 / 5.0 + 32.0,
+            _ => kelvin,
+        };
+        return Ok(output);
+    }
+    Ok(value * from_factor / to_factor)
+}
+
+/// Unit names by kind, with the size of the unit in the base unit of the kind. Temperatures
+/// are converted by formula, their factor only tells the scale apart.
+const UNITS: &[(&str, &[&str], f64)] = &[
+    ("length", &["m", "meter", "meters", "metre", "metres"], 1.0),
+    (
+        "length",
+        &["km", "kilometer", "kilometers", "kilometre"],
+        1e3,
+    ),
+    (
+        "length",
+        &["cm", "centimeter", "centimeters", "centimetre"],
+        1e-2,
+    ),
+    (
+        "length",
+        &["mm", "millimeter", "millimeters", "millimetre"],
+        1e-3,
+    ),
+    ("length", &["um", "µm", "micrometer", "micrometers"], 1e-6),
+    ("length", &["nm", "nanometer", "nanometers"], 1e-9),
+    ("length", &["in", "inch", "inches"], 0.0254),
+    ("length", &["ft", "foot", "feet"], 0.3048),
+    ("length", &["yd", "yard", "yards"], 0.9144),
+    ("length", &["mi", "mile", "miles"], 1609.344),
+    (
+        "length",
+        &["nmi", "nautical mile", "nautical miles"],
+        1852.0,
+    ),
+    ("mass", &["kg", "kilogram", "kilograms"], 1.0),
+    ("mass", &["g", "gram", "grams"], 1e-3),
+    ("mass", &["mg", "milligram", "milligrams"], 1e-6),
+    ("mass", &["t", "tonne", "tonnes", "metric ton"], 1e3),
+    ("mass", &["lb", "lbs", "pound", "pounds"], 0.45359237),
+    ("mass", &["oz", "ounce", "ounces"], 0.028349523125),
+    ("mass", &["st", "stone", "stones"], 6.35029318),
+    ("time", &["s", "sec", "second", "seconds"], 1.0),
+    ("time", &["ms", "millisecond", "milliseconds"], 1e-3),
+    ("time", &["min", "minute", "minutes"], 60.0),
+    ("time", &["h", "hr", "hour", "hours"], 3600.0),
+    ("time", &["d", "day", "days"], 86400.0),
+    ("time", &["wk", "week", "weeks"], 604800.0),
+    ("time", &["yr", "year", "years"], 31557600.0),
+    ("volume", &["l", "liter", "liters", "litre", "litres"], 1.0),
+    (
+        "volume",
+        &["ml", "milliliter", "milliliters", "millilitre"],
+        1e-3,
+    ),
+    ("volume", &["m3", "cubic meter", "cubic meters"], 1e3),
+    ("volume", &["gal", "gallon", "gallons"], 3.785411784),
+    ("volume", &["qt", "quart", "quarts"], 0.946352946),
+    ("volume", &["pt", "pint", "pints"], 0.473176473),
+    ("volume", &["cup", "cups"], 0.2365882365),
+    (
+        "volume",
+        &["floz", "fl oz", "fluid ounce", "fluid ounces"],
+        0.0295735295625,
+    ),
+    (
+        "volume",
+        &["tbsp", "tablespoon", "tablespoons"],
+        0.01478676478125,
+    ),
+    (
+        "volume",
+        &["tsp", "teaspoon", "teaspoons"],
+        0.00492892159375,
+    ),
+    ("area", &["m2", "square meter", "square meters"], 1.0),
+    (
+        "area",
+        &["km2", "square kilometer", "square kilometers"],
+        1e6,
+    ),
+    (
+        "area",
+        &["cm2", "square centimeter", "square centimeters"],
+        1e-4,
+    ),
+    ("area", &["ha", "hectare", "hectares"], 1e4),
+    ("area", &["acre", "acres"], 4046.8564224),
+    ("area", &["ft2", "square foot", "square feet"], 0.09290304),
+    (
+        "area",
+        &["mi2", "square mile", "square miles"],
+        2589988.110336,
+    ),
+    ("speed", &["m/s", "mps"], 1.0),
+    ("speed", &["km/h", "kmh", "kph"], 1.0 / 3.6),
+    ("speed", &["mph", "mi/h"], 0.44704),
+    ("speed", &["kn", "knot", "knots"], 1852.0 / 3600.0),
+    ("speed", &["ft/s", "fps"], 0.3048),
+    ("data", &["bit", "bits"], 0.125),
+    ("data", &["b", "byte", "bytes"], 1.0),
+    ("data", &["kb", "kilobyte", "kilobytes"], 1e3),
+    ("data", &["mb", "megabyte", "megabytes"], 1e6),
+    ("data", &["gb", "gigabyte", "gigabytes"], 1e9),
+    ("data", &["tb", "terabyte", "terabytes"], 1e12),
+    ("data", &["kib", "kibibyte", "kibibytes"], 1024.0),
+    ("data", &["mib", "mebibyte", "mebibytes"], 1048576.0),
+    ("data", &["gib", "gibibyte", "gibibytes"], 1073741824.0),
+    ("data", &["tib", "tebibyte", "tebibytes"], 1099511627776.0),
+    ("energy", &["j", "joule", "joules"], 1.0),
+    ("energy", &["kj", "kilojoule", "kilojoules"], 1e3),
+    ("energy", &["cal", "calorie", "calories"], 4.184),
+    ("energy", &["kcal", "kilocalorie", "kilocalories"], 4184.0),
+    ("energy", &["wh", "watt hour", "watt hours"], 3600.0),
+    ("energy", &["kwh", "kilowatt hour", "kilowatt hours"], 3.6e6),
+    ("pressure", &["pa", "pascal", "pascals"], 1.0),
+    ("pressure", &["kpa", "kilopascal", "kilopascals"], 1e3),
+    ("pressure", &["bar", "bars"], 1e5),
+    ("pressure", &["atm", "atmosphere", "atmospheres"], 101325.0),
+    ("pressure", &["psi"], 6894.757293168),
+    ("pressure", &["mmhg"], 133.322387415),
+    ("temperature", &["c", "°c", "celsius"], 0.0),
+    ("temperature", &["f", "°f", "fahrenheit"], 1.0),
+    ("temperature", &["k", "kelvin"], 2.0),
+];
+
+fn find_unit(name: &str) -> Result<(&'static str, f64)> {
+    let name = name.trim().to_lowercase();
+    UNITS
+        .iter()
+        .find(|(_, names, _)| names.contains(&name.as_str()))
+        .map(|(kind, _, factor)| (*kind, *factor))
+        .ok_or_else(|| anyhow!("Unknown unit '{name}'"))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Op(char),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Number(v) => write!(f, "{v}"),
+            Token::Ident(v) => write!(f, "{v}"),
+            Token::Op(v) => write!(f, "{v}"),
+        }
+    }
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() || c == '_' {
+            i += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || matches!(chars[i], '.' | '_')) {
+                i += 1;
+            }
+            if i < chars.len() && matches!(chars[i], 'e' | 'E') {
+                let next = chars.get(i + 1).copied();
+                let after_sign = chars.get(i + 2).copied();
+                if next.is_some_and(|v| v.is_ascii_digit())
+                    || (matches!(next, Some('+' | '-'))
+                        && after_sign.is_some_and(|v| v.is_ascii_digit()))
+                {
+                    i += 2;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            let text: String = chars[start..i].iter().filter(|v| **v != '_').collect();
+            let value = text
+                .parse()
+                .map_err(|_| anyhow!("Invalid number '{text}'"))?;
+            tokens.push(Token::Number(value));
+        } else if c.is_alphabetic() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let name: String = chars[start..i].iter().collect();
+            tokens.push(Token::Ident(name.to_lowercase()));
+        } else if c == '*' && chars.get(i + 1) == Some(&'*') {
+            tokens.push(Token::Op('^'));
+            i += 2;
+        } else if "+-*/%^(),×÷".contains(c) {
+            let op = match c {
+                '×' => '*',
+                '÷' => '/',
+                _ => c,
+            };
+            tokens.push(Token::Op(op));
+            i += 1;
+        } else {
+            bail!("Unexpected '{c}' in '{expression}'");
+        }
+    }
+    Ok(tokens)
+}
+
+struct ExprParser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl ExprParser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, op: char) -> bool {
+        if self.peek() == Some(&Token::Op(op)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_sum(&mut self) -> Result<f64> {
+        let mut value = self.parse_product()?;
+        loop {
+            if self.eat('+') {
+                value += self.parse_product()?;
+            } else if self.eat('-') {
+                value -= self.parse_product()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn parse_product(&mut self) -> Result<f64> {
+        let mut value = self.parse_unary()?;
+        loop {
+            if self.eat('*') {
+                value *= self.parse_unary()?;
+            } else if self.eat('/') {
+                let divisor = self.parse_unary()?;
+                if divisor == 0.0 {
+                    bail!("Division by zero");
+                }
+                value /= divisor;
+            } else if self.eat('%') {
+                let divisor = self.parse_unary()?;
+                if divisor == 0.0 {
+                    bail!("Division by zero");
+                }
+                value %= divisor;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<f64> {
+        if self.eat('-') {
+            return Ok(-self.parse_unary()?);
+        }
+        if self.eat('+') {
+            return self.parse_unary();
+        }
+        self.parse_power()
+    }
+
+    /// Powers bind tighter than a leading minus and group to the right, so `-2^2^3` is
+    /// `-(2^(2^3))`.
+    fn parse_power(&mut self) -> Result<f64> {
+        let base = self.parse_atom()?;
+        if self.eat('^') {
+            let exponent = self.parse_unary()?;
+            return Ok(base.powf(exponent));
+        }
+        Ok(base)
+    }
+
+    fn parse_atom(&mut self) -> Result<f64> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(value),
+            Some(Token::Op('(')) => {
+                let value = self.parse_sum()?;
+                if !self.eat(')') {
+                    bail!("Missing ')'");
+                }
+                Ok(value)
+            }
+            Some(Token::Ident(name)) => {
+                if !self.eat('(') {
+                    return match name.as_str() {
+                        "pi" => Ok(std::f64::consts::PI),
+                        "e" => Ok(std::f64::consts::E),
+                        "tau" => Ok(std::f64::consts::TAU),
+                        _ => bail!("Unknown constant '{name}'"),
+                    };
+                }
+                let mut args = vec![self.parse_sum()?];
+                while self.eat(',') {
+                    args.push(self.parse_sum()?);
+                }
+                if !self.eat(')') {
+                    bail!("Missing ')' after the arguments of '{name}'");
+                }
+                call_function(&name, &args)
+            }
+            Some(token) => bail!("Unexpected '{token}'"),
+            None => bail!("Unexpected end of expression"),
+        }
+    }
+}
+
+fn call_function(name: &str, args: &[f64]) -> Result<f64> {
+    let unary = |f: fn(f64) -> f64| match args {
+        [x] => Ok(f(*x)),
+        _ => bail!("'{name}' takes 1 argument"),
+    };
+    match name {
+        "sqrt" => unary(f64::sqrt),
+        "cbrt" => unary(f64::cbrt),
+        "abs" => unary(f64::abs),
+        "exp" => unary(f64::exp),
+        "ln" => unary(f64::ln),
+        "log" | "log10" => unary(f64::log10),
+        "log2" => unary(f64::log2),
+        "sin" => unary(f64::sin),
+        "cos" => unary(f64::cos),
+        "tan" => unary(f64::tan),
+        "asin" => unary(f64::asin),
+        "acos" => unary(f64::acos),
+        "atan" => unary(f64::atan),
+        "floor" => unary(f64::floor),
+        "ceil" => unary(f64::ceil),
+        "round" => unary(f64::round),
+        "pow" => match args {
+            [x, y] => Ok(x.powf(*y)),
+            _ => bail!("'pow' takes 2 arguments"),
+        },
+        "min" if !args.is_empty() => Ok(args.iter().copied().fold(f64::INFINITY, f64::min)),
+        "max" if !args.is_empty() => Ok(args.iter().copied().fold(f64::NEG_INFINITY, f64::max)),
+        _ => bail!("Unknown function '{name}'"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate_expression() {
+        let eval = |v| format_number(evaluate_expression(v).unwrap());
+        assert_eq!(eval("1 + 2 * 3"), "7");
+        assert_eq!(eval("(1 + 2) * 3"), "9");
+        assert_eq!(eval("0.1 + 0.2"), "0.3");
+        assert_eq!(eval("-2^2"), "-4");
+        assert_eq!(eval("2 ** 3 ^ 2"), "512");
+        assert_eq!(eval("17 % 5"), "2");
+        assert_eq!(eval("1_234 * 1e3"), "1234000");
+        assert_eq!(eval("sqrt(16) + max(1, 5, 3)"), "9");
+        assert_eq!(eval("round(pi * 100) / 100"), "3.14");
+        assert!(evaluate_expression("1 / 0").is_err());
+        assert!(evaluate_expression("2 +").is_err());
+        assert!(evaluate_expression("foo(1)").is_err());
+        assert!(evaluate_expression("(1 + 2").is_err());
+    }
+
+    #[test]
+    fn test_convert_unit() {
+        let convert = |v, from, to| format_number(convert_unit(v, from, to).unwrap());
+        assert_eq!(convert(1.0, "mi", "km"), "1.609344");
+        assert_eq!(convert(100.0, "C", "F"), "212");
+        assert_eq!(convert(0.0, "kelvin", "celsius"), "-273.15");
+        assert_eq!(convert(1.0, "GiB", "MB"), "1073.741824");
+        assert_eq!(convert(2.0, "hours", "min"), "120");
+        assert!(convert_unit(1.0, "kg", "m").is_err());
+        assert!(convert_unit(1.0, "parsec", "m").is_err());
+    }
+}
@@ -0,0 +1,209 @@
+use anyhow::{bail, Result};
+use std::str::FromStr;
+
+/// Which fenced code blocks `--code` keeps.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum CodeSelector {
+    #[default]
+    First,
+    All,
+    /// 1-based position of the block
+    Index(usize),
+    Language(String),
+}
+
+impl FromStr for CodeSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let value = value.trim();
+        if value.is_empty() || value == "first" {
+            return Ok(Self::First);
+        }
+        if value == "all" {
+            return Ok(Self::All);
+        }
+        if let Ok(index) = value.parse::<usize>() {
+            if index == 0 {
+                bail!("Code block index starts at 1");
+            }
+            return Ok(Self::Index(index));
+        }
+        Ok(Self::Language(value.to_lowercase()))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CodeBlock<'a> {
+    /// The fence's language tag, or one inferred from a shebang line
+    pub language: Option<String>,
+    pub code: &'a str,
+}
+
+const LANGUAGE_ALIASES: [(&str, &str); 12] = [
+    ("rs", "rust"),
+    ("py", "python"),
+    ("python3", "python"),
+    ("js", "javascript"),
+    ("ts", "typescript"),
+    ("sh", "bash"),
+    ("shell", "bash"),
+    ("zsh", "bash"),
+    ("console", "bash"),
+    ("yml", "yaml"),
+    ("golang", "go"),
+    ("c++", "cpp"),
+];
+
+/// The first fenced code block, or the whole text when there is none.
+pub fn extract_code_block(text: &str) -> &str {
+    match parse_code_blocks(text).first() {
+        Some(block) => block.code,
+        None => text,
+    }
+}
+
+/// Keeps the code blocks chosen by `selector`, joined by blank lines.
+pub fn select_code_blocks(text: &str, selector: &CodeSelector) -> Result<String> {
+    let blocks = parse_code_blocks(text);
+    if blocks.is_empty() {
+        return Ok(text.to_string());
+    }
+    let output = match selector {
+        CodeSelector::First => blocks[0].code.to_string(),
+        CodeSelector::All => join_blocks(blocks.iter()),
+        CodeSelector::Index(index) => match blocks.get(index - 1) {
+            Some(block) => block.code.to_string(),
+            None => bail!(
+                "No code block #{index} in the response, it has {}",
+                blocks.len()
+            ),
+        },
+        CodeSelector::Language(language) => {
+            let language = normalize_language(language);
+            let matched: Vec<_> = blocks
+                .iter()
+                .filter(|v| v.language.as_deref() == Some(language))
+                .collect();
+            if matched.is_empty() {
+                let found: Vec<_> = blocks
+                    .iter()
+                    .map(|v| v.language.as_deref().unwrap_or("untagged"))
+                    .collect();
+                bail!(
+                    "No '{language}' code block in the response, found: {}",
+                    found.join(", ")
+                );
+            }
+            join_blocks(matched.into_iter())
+        }
+    };
+    Ok(output)
+}
+
+pub fn parse_code_blocks(text: &str) -> Vec<CodeBlock<'_>> {
+    let mut blocks = vec![];
+    let mut open: Option<(usize, &str, Option<String>)> = None;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+        let trimmed = line.trim();
+        let backticks = trimmed.len() - trimmed.trim_start_matches('`').len();
+        if backticks < 3 {
+            continue;
+        }
+        let fence = &trimmed[..backticks];
+        match &open {
+            None => {
+                let tag = trimmed[backticks..]
+                    .split_whitespace()
+                    .next()
+                    .map(|v| v.trim_matches(|c| c == '{' || c == '}').to_lowercase())
+                    .filter(|v| !v.is_empty());
+                open = Some((offset, fence, tag));
+            }
+            Some((code_start, open_fence, tag)) => {
+                if trimmed == fence && fence.len() >= open_fence.len() {
+                    let code = text[*code_start..start].trim_end_matches(['\n', '\r']);
+                    let language = tag
+                        .as_deref()
+                        .map(|v| normalize_language(v).to_string())
+                        .or_else(|| infer_language(code));
+                    blocks.push(CodeBlock { language, code });
+                    open = None;
+                }
+            }
+        }
+    }
+    blocks
+}
+
+fn join_blocks<'a, 'b: 'a>(blocks: impl Iterator<Item = &'a CodeBlock<'b>>) -> String {
+    blocks.map(|v| v.code).collect::<Vec<_>>().join("\n\n")
+}
+
+fn normalize_language(language: &str) -> &str {
+    LANGUAGE_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == language)
+        .map(|(_, name)| *name)
+        .unwrap_or(language)
+}
+
+/// Guesses the language of an untagged block from its shebang line.
+fn infer_language(code: &str) -> Option<String> {
+    let shebang = code.lines().next()?.strip_prefix("#!")?;
+    let mut parts = shebang.split_whitespace();
+    let mut program = parts.next()?.rsplit('/').next()?;
+    if program == "env" {
+        program = parts.find(|v| !v.starts_with('-'))?;
+    }
+    let program = program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    Some(normalize_language(program).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "Here you go:\n\n```rust\nfn main() {}\n```\n\nand\n\n```py\nprint(1)\n```\n\n```\n#!/usr/bin/env python3\nprint(2)\n```\n";
+
+    #[test]
+    fn test_parse_code_blocks() {
+        let blocks = parse_code_blocks(TEXT);
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0].language.as_deref(), Some("rust"));
+        assert_eq!(blocks[0].code, "fn main() {}");
+        assert_eq!(blocks[1].language.as_deref(), Some("python"));
+        assert_eq!(blocks[2].language.as_deref(), Some("python"));
+        assert_eq!(extract_code_block(TEXT), "fn main() {}");
+        assert_eq!(extract_code_block("no code"), "no code");
+    }
+
+    #[test]
+    fn test_nested_fence() {
+        let text = "````md\n```sh\nls\n```\n````\n";
+        let blocks = parse_code_blocks(text);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].code, "```sh\nls\n```");
+    }
+
+    #[test]
+    fn test_select_code_blocks() {
+        let select = |v: &str| select_code_blocks(TEXT, &v.parse().unwrap());
+        assert_eq!(select("2").unwrap(), "print(1)");
+        assert_eq!(
+            select("python").unwrap(),
+            "print(1)\n\n#!/usr/bin/env python3\nprint(2)"
+        );
+        assert_eq!(select("rs").unwrap(), "fn main() {}");
+        assert_eq!(
+            select("all").unwrap(),
+            "fn main() {}\n\nprint(1)\n\n#!/usr/bin/env python3\nprint(2)"
+        );
+        assert!(select("4").is_err());
+        assert!(select("go").is_err());
+        assert!("0".parse::<CodeSelector>().is_err());
+    }
+}
@@ -0,0 +1,240 @@
+use anyhow::{Context, Result};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use zip::ZipArchive;
+
+/// Converts a .docx file to markdown, keeping headings, list items and tables so the text
+/// chunks along the document's own structure.
+pub fn docx_to_md(bytes: &[u8]) -> Result<String> {
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).context("Invalid docx file")?;
+    let document = read_zip_entry(&mut archive, "word/document.xml")?;
+    let heading_styles = match read_zip_entry(&mut archive, "word/styles.xml") {
+        Ok(styles) => parse_heading_styles(&styles),
+        Err(_) => HashMap::new(),
+    };
+    convert_document(&document, &heading_styles)
+}
+
+pub(super) fn read_zip_entry(
+    archive: &mut ZipArchive<Cursor<&[u8]>>,
+    name: &str,
+) -> Result<String> {
+    let mut file = archive
+        .by_name(name)
+        .with_context(|| format!("Missing '{name}'"))?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)
+        .with_context(|| format!("Invalid '{name}'"))?;
+    Ok(contents)
+}
+
+/// Maps paragraph style ids to heading levels, using the style names since the ids are
+/// localized (`Heading1`, `berschrift1`, ...).
+fn parse_heading_styles(xml: &str) -> HashMap<String, usize> {
+    let mut output = HashMap::new();
+    let mut reader = Reader::from_str(xml);
+    let mut style_id: Option<String> = None;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) if e.local_name().as_ref() == b"style" => {
+                style_id = xml_attr(&e, b"styleId");
+            }
+            Ok(Event::Empty(e)) if e.local_name().as_ref() == b"name" => {
+                if let (Some(id), Some(name)) = (style_id.as_ref(), xml_attr(&e, b"val")) {
+                    if let Some(level) = heading_level(&name) {
+                        output.insert(id.clone(), level);
+                    }
+                }
+            }
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+    }
+    output
+}
+
+fn heading_level(style: &str) -> Option<usize> {
+    let style = style.to_lowercase().replace(' ', "");
+    if style == "title" {
+        return Some(1);
+    }
+    let level: usize = style.strip_prefix("heading")?.parse().ok()?;
+    (1..=6).contains(&level).then_some(level)
+}
+
+#[derive(Debug, Default)]
+struct Paragraph {
+    text: String,
+    heading: Option<usize>,
+    list_level: Option<usize>,
+}
+
+fn convert_document(xml: &str, heading_styles: &HashMap<String, usize>) -> Result<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut blocks: Vec<String> = vec![];
+    let mut paragraph = Paragraph::default();
+    let mut in_text = false;
+    let mut table_depth = 0;
+    let mut rows: Vec<Vec<String>> = vec![];
+    let mut cell: Vec<String> = vec![];
+    loop {
+        let event = reader.read_event().context("Invalid docx document")?;
+        match event {
+            Event::Start(e) => match e.local_name().as_ref() {
+                b"p" => paragraph = Paragraph::default(),
+                b"t" => in_text = true,
+                b"tbl" => {
+                    table_depth += 1;
+                    if table_depth == 1 {
+                        rows.clear();
+                    }
+                }
+                b"tr" if table_depth == 1 => rows.push(vec![]),
+                b"tc" if table_depth == 1 => cell.clear(),
+                _ => {}
+            },
+            Event::Empty(e) => match e.local_name().as_ref() {
+                b"pStyle" => {
+                    if let Some(style) = xml_attr(&e, b"val") {
+                        paragraph.heading = heading_styles
+                            .get(&style)
+                            .copied()
+                            .or_else(|| heading_level(&style));
+                    }
+                }
+                b"ilvl" => {
+                    paragraph.list_level = xml_attr(&e, b"val").and_then(|v| v.parse().ok());
+                }
+                b"numId" => {
+                    paragraph.list_level.get_or_insert(0);
+                }
+                b"tab" => paragraph.text.push('\t'),
+                b"br" | b"cr" => paragraph.text.push('\n'),
+                _ => {}
+            },
+            Event::Text(e) if in_text => {
+                paragraph
+                    .text
+                    .push_str(&e.unescape().context("Invalid docx text")?);
+            }
+            Event::End(e) => match e.local_name().as_ref() {
+                b"t" => in_text = false,
+                b"p" => {
+                    let paragraph = std::mem::take(&mut paragraph);
+                    if table_depth > 0 {
+                        cell.push(paragraph.text.trim().replace('\n', " "));
+                    } else if let Some(block) = render_paragraph(paragraph) {
+                        blocks.push(block);
+                    }
+                }
+                b"tc" if table_depth == 1 => {
+                    let text = cell.iter().filter(|v| !v.is_empty()).cloned();
+                    let text = text.collect::<Vec<_>>().join(" ").replace('|', "\\|");
+                    if let Some(row) = rows.last_mut() {
+                        row.push(text);
+                    }
+                }
+                b"tbl" => {
+                    table_depth -= 1;
+                    if table_depth == 0 {
+                        blocks.push(render_table(&rows));
+                    }
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(join_blocks(&blocks))
+}
+
+fn render_paragraph(paragraph: Paragraph) -> Option<String> {
+    let text = paragraph.text.trim();
+    if text.is_empty() {
+        return None;
+    }
+    let block = if let Some(level) = paragraph.heading {
+        format!("{} {}", "#".repeat(level), text.replace('\n', " "))
+    } else if let Some(level) = paragraph.list_level {
+        format!("{}- {text}", "  ".repeat(level))
+    } else {
+        text.to_string()
+    };
+    Some(block)
+}
+
+fn render_table(rows: &[Vec<String>]) -> String {
+    let columns = rows.iter().map(|v| v.len()).max().unwrap_or_default();
+    let mut lines = vec![];
+    for (index, row) in rows.iter().enumerate() {
+        let mut cells = row.clone();
+        cells.resize(columns, String::new());
+        lines.push(format!("| {} |", cells.join(" | ")));
+        if index == 0 {
+            lines.push(format!("|{}", "---|".repeat(columns)));
+        }
+    }
+    lines.join("\n")
+}
+
+/// Separates blocks with blank lines, except between items of the same list.
+fn join_blocks(blocks: &[String]) -> String {
+    let is_item = |v: &str| v.trim_start().starts_with("- ");
+    let mut output = String::new();
+    for (index, block) in blocks.iter().enumerate() {
+        if index > 0 {
+            if is_item(block) && is_item(&blocks[index - 1]) {
+                output.push('\n');
+            } else {
+                output.push_str("\n\n");
+            }
+        }
+        output.push_str(block);
+    }
+    output
+}
+
+pub(super) fn xml_attr(e: &BytesStart, name: &[u8]) -> Option<String> {
+    e.attributes()
+        .flatten()
+        .find(|v| v.key.local_name().as_ref() == name)
+        .and_then(|v| v.unescape_value().ok().map(|v| v.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_document() {
+        let xml = r#"<w:document xmlns:w="w"><w:body>
+<w:p><w:pPr><w:pStyle w:val="Heading1"/></w:pPr><w:r><w:t>Release Notes</w:t></w:r></w:p>
+<w:p><w:r><w:t xml:space="preserve">Fixes &amp; </w:t></w:r><w:r><w:t>changes.</w:t></w:r></w:p>
+<w:p><w:pPr><w:numPr><w:ilvl w:val="0"/><w:numId w:val="1"/></w:numPr></w:pPr><w:r><w:t>First</w:t></w:r></w:p>
+<w:p><w:pPr><w:numPr><w:ilvl w:val="1"/><w:numId w:val="1"/></w:numPr></w:pPr><w:r><w:t>Nested</w:t></w:r></w:p>
+<w:tbl><w:tr><w:tc><w:p><w:r><w:t>Key</w:t></w:r></w:p></w:tc><w:tc><w:p><w:r><w:t>Value</w:t></w:r></w:p></w:tc></w:tr>
+<w:tr><w:tc><w:p><w:r><w:t>a</w:t></w:r></w:p></w:tc><w:tc><w:p><w:r><w:t>1</w:t></w:r></w:p></w:tc></w:tr></w:tbl>
+</w:body></w:document>"#;
+        let styles = HashMap::from([("Heading1".to_string(), 1)]);
+        assert_eq!(
+            convert_document(xml, &styles).unwrap(),
+            "# Release Notes\n\nFixes & changes.\n\n- First\n  - Nested\n\n| Key | Value |\n|---|---|\n| a | 1 |"
+        );
+    }
+
+    #[test]
+    fn test_parse_heading_styles() {
+        let xml = r#"<w:styles xmlns:w="w">
+<w:style w:type="paragraph" w:styleId="berschrift2"><w:name w:val="heading 2"/></w:style>
+<w:style w:type="paragraph" w:styleId="Titel"><w:name w:val="Title"/></w:style>
+<w:style w:type="paragraph" w:styleId="Normal"><w:name w:val="Normal"/></w:style>
+</w:styles>"#;
+        let styles = parse_heading_styles(xml);
+        assert_eq!(styles.get("berschrift2"), Some(&2));
+        assert_eq!(styles.get("Titel"), Some(&1));
+        assert_eq!(styles.get("Normal"), None);
+    }
+}
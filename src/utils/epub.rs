@@ -0,0 +1,100 @@
+use super::{html_to_md, read_zip_entry, xml_attr};
+
+use anyhow::{Context, Result};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::io::Cursor;
+use zip::ZipArchive;
+
+/// Converts an .epub book to markdown, one chapter after another in reading order.
+pub fn epub_to_md(bytes: &[u8]) -> Result<String> {
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).context("Invalid epub file")?;
+    let container = read_zip_entry(&mut archive, "META-INF/container.xml")?;
+    let opf_path = parse_rootfile(&container).context("Missing the epub package document")?;
+    let opf = read_zip_entry(&mut archive, &opf_path)?;
+    let base_dir = match opf_path.rsplit_once('/') {
+        Some((dir, _)) => format!("{dir}/"),
+        None => String::new(),
+    };
+    let mut chapters = vec![];
+    for href in parse_spine(&opf) {
+        let href = href.split('#').next().unwrap_or_default();
+        let href = urlencoding::decode(href).map(|v| v.to_string());
+        let path = format!("{base_dir}{}", href.unwrap_or_default());
+        let Ok(html) = read_zip_entry(&mut archive, &path) else {
+            continue;
+        };
+        let markdown = html_to_md(&html);
+        let markdown = markdown.trim();
+        if !markdown.is_empty() {
+            chapters.push(markdown.to_string());
+        }
+    }
+    Ok(chapters.join("\n\n"))
+}
+
+fn parse_rootfile(xml: &str) -> Option<String> {
+    let mut reader = Reader::from_str(xml);
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e) | Event::Empty(e)) if e.local_name().as_ref() == b"rootfile" => {
+                return xml_attr(&e, b"full-path");
+            }
+            Ok(Event::Eof) | Err(_) => return None,
+            _ => {}
+        }
+    }
+}
+
+/// The hrefs of the (x)html documents listed in the spine, in reading order.
+fn parse_spine(xml: &str) -> Vec<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut manifest: HashMap<String, String> = HashMap::new();
+    let mut spine = vec![];
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e) | Event::Empty(e)) => match e.local_name().as_ref() {
+                b"item" => {
+                    let media_type = xml_attr(&e, b"media-type").unwrap_or_default();
+                    if let (Some(id), Some(href), true) = (
+                        xml_attr(&e, b"id"),
+                        xml_attr(&e, b"href"),
+                        media_type.contains("html"),
+                    ) {
+                        manifest.insert(id, href);
+                    }
+                }
+                b"itemref" => {
+                    if let Some(idref) = xml_attr(&e, b"idref") {
+                        spine.push(idref);
+                    }
+                }
+                _ => {}
+            },
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+    }
+    spine
+        .into_iter()
+        .filter_map(|id| manifest.get(&id).cloned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_package() {
+        let container = r#"<container><rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles></container>"#;
+        assert_eq!(parse_rootfile(container), Some("OEBPS/content.opf".into()));
+        let opf = r#"<package><manifest>
+<item id="css" href="style.css" media-type="text/css"/>
+<item id="ch2" href="text/ch%202.xhtml" media-type="application/xhtml+xml"/>
+<item id="ch1" href="text/ch1.xhtml" media-type="application/xhtml+xml"/>
+</manifest><spine><itemref idref="ch1"/><itemref idref="ch2"/><itemref idref="css"/></spine></package>"#;
+        assert_eq!(parse_spine(opf), ["text/ch1.xhtml", "text/ch%202.xhtml"]);
+    }
+}
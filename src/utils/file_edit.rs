@@ -0,0 +1,348 @@
+use super::*;
+
+use indexmap::IndexMap;
+use similar::TextDiff;
+use std::fs;
+use std::path::Path;
+
+static SEARCH_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^<{5,9} SEARCH\s*$").unwrap());
+static DIVIDER_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^={5,9}\s*$").unwrap());
+static REPLACE_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^>{5,9} REPLACE\s*$").unwrap());
+
+/// A search/replace block of the edit protocol:
+///
+/// ```text
+/// path/to/file.rs
+/// <<<<<<< SEARCH
+/// the lines to find
+/// =======
+/// the lines to put in their place
+/// >>>>>>> REPLACE
+/// ```
+///
+/// An empty SEARCH section creates a new file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileEdit {
+    pub path: String,
+    pub search: String,
+    pub replace: String,
+}
+
+/// The new contents of a file after all its edits, `old` is `None` for a new file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileChange {
+    pub path: String,
+    pub old: Option<String>,
+    pub new: String,
+}
+
+/// Reads the edit blocks in a response. The path is the last line before a block that holds a
+/// single word, and a block without one edits the same file as the block before it.
+pub fn parse_file_edits(text: &str) -> Result<Vec<FileEdit>> {
+    let mut edits = vec![];
+    let mut path: Option<String> = None;
+    let text = strip_think_tag(text);
+    let mut lines = text.lines().enumerate();
+    while let Some((index, line)) = lines.next() {
+        if !is_edit_marker(&SEARCH_RE, line) {
+            let line = line.trim();
+            if line.starts_with("```") {
+                continue;
+            }
+            let line = clean_path_line(line);
+            if !line.is_empty() && !line.contains(char::is_whitespace) {
+                path = Some(line.to_string());
+            }
+            continue;
+        }
+        let Some(path) = path.clone() else {
+            bail!("No file path before the SEARCH block at line {}", index + 1);
+        };
+        let mut search = vec![];
+        let mut replace = vec![];
+        let mut in_replace = false;
+        let mut finished = false;
+        for (_, line) in lines.by_ref() {
+            if !in_replace && is_edit_marker(&DIVIDER_RE, line) {
+                in_replace = true;
+            } else if in_replace && is_edit_marker(&REPLACE_RE, line) {
+                finished = true;
+                break;
+            } else if in_replace {
+                replace.push(line);
+            } else {
+                search.push(line);
+            }
+        }
+        if !finished {
+            bail!("Unterminated edit block for '{path}'");
+        }
+        edits.push(FileEdit {
+            path,
+            search: join_lines(&search),
+            replace: join_lines(&replace),
+        });
+    }
+    Ok(edits)
+}
+
+/// Applies the edits to the files under `root` in memory, failing before anything is written
+/// when a file is outside of `root` or a SEARCH section doesn't match exactly one place.
+pub fn plan_file_edits(edits: &[FileEdit], root: &Path) -> Result<Vec<FileChange>> {
+    let mut files: IndexMap<&str, (Option<String>, Option<String>)> = IndexMap::new();
+    for edit in edits {
+        let path = edit.path.as_str();
+        if !files.contains_key(path) {
+            let file_path = resolve_edit_path(root, path)?;
+            let old = match file_path.exists() {
+                true => Some(
+                    fs::read_to_string(&file_path)
+                        .with_context(|| format!("Failed to read '{path}'"))?,
+                ),
+                false => None,
+            };
+            files.insert(path, (old.clone(), old));
+        }
+        let (_, contents) = files.get_mut(path).expect("file is inserted");
+        *contents = match (contents.take(), edit.search.is_empty()) {
+            (None, true) => Some(edit.replace.clone()),
+            (None, false) => bail!("'{path}' not found"),
+            (Some(_), true) => bail!("'{path}' already exists, its SEARCH section can't be empty"),
+            (Some(text), false) => Some(replace_once(&text, &edit.search, &edit.replace, path)?),
+        };
+    }
+    let changes = files
+        .into_iter()
+        .filter_map(|(path, (old, new))| {
+            let new = new?;
+            if old.as_ref() == Some(&new) {
+                return None;
+            }
+            Some(FileChange {
+                path: path.to_string(),
+                old,
+                new,
+            })
+        })
+        .collect();
+    Ok(changes)
+}
+
+/// Renders the changes as a colored unified diff.
+pub fn render_file_changes(changes: &[FileChange]) -> String {
+    let mut output = String::new();
+    for change in changes {
+        let old = change.old.as_deref().unwrap_or_default();
+        let old_header = match change.old {
+            Some(_) => format!("a/{}", change.path),
+            None => "/dev/null".into(),
+        };
+        let new_header = format!("b/{}", change.path);
+        let diff = TextDiff::from_lines(old, &change.new);
+        let diff = diff
+            .unified_diff()
+            .header(&old_header, &new_header)
+            .to_string();
+        for line in diff.lines() {
+            let line = if line.starts_with("---") || line.starts_with("+++") {
+                line.to_string()
+            } else if line.starts_with("@@") {
+                color_text(line, nu_ansi_term::Color::Cyan)
+            } else if line.starts_with('+') {
+                color_text(line, nu_ansi_term::Color::Green)
+            } else if line.starts_with('-') {
+                color_text(line, nu_ansi_term::Color::Red)
+            } else {
+                line.to_string()
+            };
+            output.push_str(&line);
+            output.push('\n');
+        }
+    }
+    output
+}
+
+pub fn write_file_changes(changes: &[FileChange], root: &Path) -> Result<()> {
+    for change in changes {
+        let path = resolve_edit_path(root, &change.path)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create '{}'", parent.display()))?;
+        }
+        fs::write(&path, &change.new)
+            .with_context(|| format!("Failed to write '{}'", change.path))?;
+    }
+    Ok(())
+}
+
+fn is_edit_marker(re: &Regex, line: &str) -> bool {
+    re.is_match(line.trim()).unwrap_or_default()
+}
+
+fn clean_path_line(line: &str) -> &str {
+    line.trim_start_matches('#')
+        .trim()
+        .trim_end_matches(':')
+        .trim_matches(|c| c == '`' || c == '*')
+}
+
+fn join_lines(lines: &[&str]) -> String {
+    match lines.is_empty() {
+        true => String::new(),
+        false => format!("{}\n", lines.join("\n")),
+    }
+}
+
+fn resolve_edit_path(root: &Path, path: &str) -> Result<PathBuf> {
+    let relative = Path::new(path)
+        .strip_prefix(root)
+        .unwrap_or_else(|_| Path::new(path));
+    match safe_join_path(root, relative) {
+        Some(v) => Ok(v),
+        None => bail!("'{path}' is outside of the working directory"),
+    }
+}
+
+/// Replaces the only place `search` matches, comparing lines without their surrounding
+/// whitespace when there is no exact match.
+fn replace_once(text: &str, search: &str, replace: &str, path: &str) -> Result<String> {
+    let count = text.matches(search).count();
+    if count == 1 {
+        return Ok(text.replacen(search, replace, 1));
+    }
+    let text_lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let search_lines: Vec<&str> = search.lines().map(|v| v.trim()).collect();
+    let starts: Vec<usize> = match count {
+        0 => text_lines
+            .windows(search_lines.len())
+            .enumerate()
+            .filter(|(_, window)| {
+                window
+                    .iter()
+                    .zip(&search_lines)
+                    .all(|(line, search)| line.trim() == *search)
+            })
+            .map(|(index, _)| index)
+            .collect(),
+        _ => vec![],
+    };
+    match starts.as_slice() {
+        [start] => {
+            let end = start + search_lines.len();
+            Ok(format!(
+                "{}{replace}{}",
+                text_lines[..*start].concat(),
+                text_lines[end..].concat()
+            ))
+        }
+        [] if count == 0 => {
+            let first_line = search.lines().next().unwrap_or_default().trim();
+            bail!("The SEARCH section starting with '{first_line}' doesn't match '{path}'")
+        }
+        _ => bail!(
+            "The SEARCH section matches {} places in '{path}', it needs more lines to be unique",
+            count.max(starts.len())
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_file_edits() {
+        let text = r#"Rename the function and add a test file.
+
+src/lib.rs
+```rust
+<<<<<<< SEARCH
+fn old() {}
+=======
+fn new() {}
+>>>>>>> REPLACE
+```
+
+```rust
+<<<<<<< SEARCH
+old();
+=======
+>>>>>>> REPLACE
+```
+
+**tests/new.rs**
+```rust
+<<<<<<< SEARCH
+=======
+#[test]
+fn it_works() {}
+>>>>>>> REPLACE
+```
+"#;
+        let edits = parse_file_edits(text).unwrap();
+        assert_eq!(
+            edits,
+            vec![
+                FileEdit {
+                    path: "src/lib.rs".into(),
+                    search: "fn old() {}\n".into(),
+                    replace: "fn new() {}\n".into(),
+                },
+                FileEdit {
+                    path: "src/lib.rs".into(),
+                    search: "old();\n".into(),
+                    replace: "".into(),
+                },
+                FileEdit {
+                    path: "tests/new.rs".into(),
+                    search: "".into(),
+                    replace: "#[test]\nfn it_works() {}\n".into(),
+                },
+            ]
+        );
+        assert!(parse_file_edits("<<<<<<< SEARCH\na\n=======\nb\n>>>>>>> REPLACE").is_err());
+        assert!(parse_file_edits("a.rs\n<<<<<<< SEARCH\na\n=======\nb\n").is_err());
+    }
+
+    #[test]
+    fn test_plan_file_edits() {
+        let root = temp_file("-file-edit", "");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.rs"), "fn a() {\n    one();\n}\n\nfn b() {}\n").unwrap();
+        let edit = |path: &str, search: &str, replace: &str| FileEdit {
+            path: path.into(),
+            search: search.into(),
+            replace: replace.into(),
+        };
+
+        let changes = plan_file_edits(
+            &[
+                edit("a.rs", "fn b() {}\n", "fn b() {\n    two();\n}\n"),
+                edit("a.rs", "fn a() {\none();\n", "fn a() {\n    three();\n"),
+                edit("new.rs", "", "fn c() {}\n"),
+            ],
+            &root,
+        )
+        .unwrap();
+        assert_eq!(
+            changes[0].new,
+            "fn a() {\n    three();\n}\n\nfn b() {\n    two();\n}\n"
+        );
+        assert_eq!(changes[1].old, None);
+        assert_eq!(changes[1].new, "fn c() {}\n");
+
+        assert!(plan_file_edits(&[edit("a.rs", "}\n", "")], &root).is_err());
+        assert!(plan_file_edits(&[edit("a.rs", "fn x()\n", "")], &root).is_err());
+        assert!(plan_file_edits(&[edit("a.rs", "", "")], &root).is_err());
+        assert!(plan_file_edits(&[edit("b.rs", "fn a()\n", "")], &root).is_err());
+        assert!(plan_file_edits(&[edit("../a.rs", "", "")], &root).is_err());
+
+        write_file_changes(&changes, &root).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("new.rs")).unwrap(),
+            "fn c() {}\n"
+        );
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,253 @@
+use super::*;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+pub const GIT_PROTOCOL: &str = "git";
+/// The commit a document was loaded from, kept in the RAG file metadata so it can be told
+/// apart from newer revisions of the repository.
+pub const GIT_COMMIT_METADATA: &str = "commit";
+
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+pub fn is_git_path(path: &str) -> bool {
+    path.split_once(':')
+        .map(|(protocol, _)| protocol == GIT_PROTOCOL)
+        .unwrap_or_default()
+}
+
+/// A `git:<repo>[?ext=rs,md][#<ref>]` document source. The repo is a URL or a local path;
+/// the ref is a branch, tag or commit and defaults to the remote HEAD.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GitSource {
+    pub repo: String,
+    pub reference: Option<String>,
+    pub extensions: Vec<String>,
+}
+
+impl GitSource {
+    pub fn parse(path: &str) -> Result<Self> {
+        let source = path
+            .strip_prefix(GIT_PROTOCOL)
+            .and_then(|v| v.strip_prefix(':'))
+            .ok_or_else(|| anyhow!("Invalid git source '{path}'"))?;
+        let (mut repo, mut reference, mut query) = (source, None, None);
+        if let Some((rest, value)) = repo.rsplit_once('#') {
+            repo = rest;
+            reference = Some(value);
+        }
+        if let Some((rest, value)) = repo.split_once('?') {
+            repo = rest;
+            query = Some(value);
+        }
+        // Also accept the ref ahead of the query, e.g. `#main?ext=rs`
+        if let Some((value, rest)) = reference.and_then(|v| v.split_once('?')) {
+            reference = Some(value);
+            query = Some(rest);
+        }
+        let mut extensions = vec![];
+        for pair in query
+            .unwrap_or_default()
+            .split('&')
+            .filter(|v| !v.is_empty())
+        {
+            match pair.split_once('=') {
+                Some(("ext", value)) => extensions.extend(
+                    value
+                        .split(',')
+                        .map(|v| v.trim().trim_start_matches('.').to_lowercase())
+                        .filter(|v| !v.is_empty()),
+                ),
+                _ => bail!("Unknown option '{pair}' in git source '{path}'"),
+            }
+        }
+        if repo.is_empty() {
+            bail!("Missing the repository of git source '{path}'");
+        }
+        // Either would reach git as an option otherwise
+        if repo.starts_with('-') || reference.is_some_and(|v| v.starts_with('-')) {
+            bail!("Invalid git source '{path}', the repository and ref can't start with '-'");
+        }
+        let repo = if is_url(repo) || repo.contains('@') {
+            repo.to_string()
+        } else {
+            to_absolute_path(&resolve_home_dir(repo))?
+        };
+        Ok(Self {
+            repo,
+            reference: reference.filter(|v| !v.is_empty()).map(|v| v.to_string()),
+            extensions,
+        })
+    }
+
+    fn matches(&self, file: &str) -> bool {
+        if self.extensions.is_empty() {
+            return true;
+        }
+        get_patch_extension(file).is_some_and(|v| self.extensions.contains(&v))
+    }
+}
+
+/// Clones the repository of a `git:` source into `repos_dir`, or updates the earlier clone,
+/// and loads its tracked files. Ignored files are never tracked, so `.gitignore` is respected;
+/// binary and oversized files are skipped.
+pub async fn load_git_repo(
+    loaders: &HashMap<String, String>,
+    path: &str,
+    repos_dir: &Path,
+) -> Result<Vec<LoadedDocument>> {
+    let source = GitSource::parse(path)?;
+    let reference = source.reference.as_deref().unwrap_or("HEAD");
+    let name = source
+        .repo
+        .trim_end_matches('/')
+        .trim_end_matches(".git")
+        .rsplit(['/', ':'])
+        .next()
+        .unwrap_or_default();
+    let hash = sha256(&format!("{}#{reference}", source.repo));
+    let dir = repos_dir.join(format!("{name}-{}", &hash[..12]));
+    let (commit, files) = {
+        let (repo, reference, dir) = (source.repo.clone(), reference.to_string(), dir.clone());
+        tokio::task::spawn_blocking(move || sync_git_repo(&repo, &reference, &dir)).await??
+    };
+    let commit = commit.trim();
+
+    let mut output = vec![];
+    for file in files.split('\0').filter(|v| !v.is_empty()) {
+        if !source.matches(file) {
+            continue;
+        }
+        let file_path = dir.join(file);
+        match fs::metadata(&file_path) {
+            Ok(v) if v.is_file() && v.len() <= MAX_FILE_SIZE => {}
+            _ => continue,
+        }
+        let Ok(mut document) = load_file(loaders, &file_path.display().to_string()).await else {
+            continue;
+        };
+        document.path = format!("{path}/{file}");
+        document
+            .metadata
+            .insert(GIT_COMMIT_METADATA.into(), commit.to_string());
+        output.push(document);
+    }
+    if output.is_empty() {
+        bail!("No files to load from '{path}'");
+    }
+    Ok(output)
+}
+
+/// Fetches `reference` of `repo` into the clone at `dir`, creating it first if needed, and
+/// checks it out. Returns the commit and the NUL-separated tracked files.
+fn sync_git_repo(repo: &str, reference: &str, dir: &Path) -> Result<(String, String)> {
+    let dir_arg = dir.display().to_string();
+    if !dir.join(".git").exists() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create '{}'", dir.display()))?;
+        run_git(&["init", "-q", &dir_arg])?;
+        run_git_in(&dir_arg, &["remote", "add", "--", "origin", repo])?;
+    }
+    run_git_in(
+        &dir_arg,
+        &["fetch", "-q", "--depth", "1", "--", "origin", reference],
+    )?;
+    run_git_in(
+        &dir_arg,
+        &["checkout", "-q", "--force", "--detach", "FETCH_HEAD"],
+    )?;
+    let commit = run_git_in(&dir_arg, &["rev-parse", "HEAD"])?;
+    let files = run_git_in(&dir_arg, &["ls-files", "-z"])?;
+    Ok((commit, files))
+}
+
+fn run_git_in(dir: &str, args: &[&str]) -> Result<String> {
+    let args: Vec<&str> = ["-C", dir].iter().chain(args).copied().collect();
+    run_git(&args)
+}
+
+fn run_git(args: &[&str]) -> Result<String> {
+    let (success, stdout, stderr) = run_command_with_output("git", args, None)
+        .context("Failed to run git, is it installed?")?;
+    if !success {
+        bail!("`git {}` failed: {}", args.join(" "), stderr.trim());
+    }
+    Ok(stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_git_source() {
+        let source =
+            GitSource::parse("git:https://github.com/sigoden/aichat?ext=rs,.MD#v0.30.0").unwrap();
+        assert_eq!(
+            source,
+            GitSource {
+                repo: "https://github.com/sigoden/aichat".into(),
+                reference: Some("v0.30.0".into()),
+                extensions: vec!["rs".into(), "md".into()],
+            }
+        );
+        let source = GitSource::parse("git:git@github.com:sigoden/aichat.git#main?ext=rs").unwrap();
+        assert_eq!(source.repo, "git@github.com:sigoden/aichat.git");
+        assert_eq!(source.reference.as_deref(), Some("main"));
+        assert!(source.matches("src/main.rs") && !source.matches("README.md"));
+        assert!(GitSource::parse("git:").is_err());
+        assert!(GitSource::parse("git:https://github.com/a/b?depth=1").is_err());
+        assert!(GitSource::parse("git:https://github.com/a/b#--upload-pack=touch x").is_err());
+        assert!(GitSource::parse("git:--upload-pack=touch x").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_load_git_repo() {
+        let root = temp_file("-git-loader", "");
+        let repo = root.join("repo");
+        let repo_arg = repo.display().to_string();
+        fs::create_dir_all(repo.join("src")).unwrap();
+        fs::write(repo.join(".gitignore"), "*.log\n").unwrap();
+        fs::write(repo.join("README.md"), "# Demo\n").unwrap();
+        fs::write(repo.join("src/lib.rs"), "pub fn demo() {}\n").unwrap();
+        fs::write(repo.join("debug.log"), "noise\n").unwrap();
+        run_git(&["init", "-q", &repo_arg]).unwrap();
+        run_git_in(&repo_arg, &["add", "."]).unwrap();
+        run_git_in(
+            &repo_arg,
+            &[
+                "-c",
+                "user.name=test",
+                "-c",
+                "user.email=test@example.com",
+                "commit",
+                "-qm",
+                "init",
+            ],
+        )
+        .unwrap();
+        let head = run_git_in(&repo_arg, &["rev-parse", "HEAD"]).unwrap();
+
+        let path = format!("git:{repo_arg}");
+        let repos_dir = root.join("repos");
+        let loaders = HashMap::new();
+        let documents = load_git_repo(&loaders, &path, &repos_dir).await.unwrap();
+        let mut paths: Vec<_> = documents.iter().map(|v| v.path.as_str()).collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                format!("{path}/.gitignore"),
+                format!("{path}/README.md"),
+                format!("{path}/src/lib.rs")
+            ]
+        );
+        assert_eq!(documents[0].metadata[GIT_COMMIT_METADATA], head.trim());
+
+        let path = format!("{path}?ext=rs");
+        let documents = load_git_repo(&loaders, &path, &repos_dir).await.unwrap();
+        assert_eq!(documents.len(), 1);
+        assert_eq!(documents[0].contents, "pub fn demo() {}\n");
+        let _ = fs::remove_dir_all(&root);
+    }
+}
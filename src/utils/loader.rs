@@ -1,11 +1,13 @@
 use super::*;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 pub const EXTENSION_METADATA: &str = "__extension__";
+/// The line each page of a PDF starts on in the extracted text, comma-separated.
+pub const PAGES_METADATA: &str = "pages";
 
 pub type DocumentMetadata = IndexMap<String, String>;
 
@@ -29,6 +31,7 @@ impl LoadedDocument {
 
 pub async fn load_recursive_url(
     loaders: &HashMap<String, String>,
+    crawler: &CrawlerConfig,
     path: &str,
 ) -> Result<Vec<LoadedDocument>> {
     let extension = RECURSIVE_URL_LOADER;
@@ -38,7 +41,7 @@ pub async fn load_recursive_url(
             serde_json::from_str(&contents).context(r#"The crawler response is invalid. It should follow the JSON format: `[{"path":"...", "text":"..."}]`."#)?
         }
         None => {
-            let options = CrawlOptions::preset(path);
+            let options = CrawlOptions::preset(path).with_config(crawler)?;
             crawl_website(path, options).await?
         }
     };
@@ -58,6 +61,7 @@ pub async fn load_file(loaders: &HashMap<String, String>, path: &str) -> Result<
     let extension = get_patch_extension(path).unwrap_or_else(|| DEFAULT_EXTENSION.into());
     match loaders.get(&extension) {
         Some(loader_command) => load_with_command(path, &extension, loader_command),
+        None if is_builtin_document(&extension) => load_builtin_document(path, &extension).await,
         None => load_plain(path, &extension).await,
     }
 }
@@ -76,6 +80,74 @@ async fn load_plain(path: &str, extension: &str) -> Result<LoadedDocument> {
     Ok(LoadedDocument::new(path.into(), contents, metadata))
 }
 
+async fn load_builtin_document(path: &str, extension: &str) -> Result<LoadedDocument> {
+    let bytes = tokio::fs::read(path).await?;
+    if extension == "pdf" {
+        return Ok(paged_document(path, &pdf_pages(&bytes)?, DEFAULT_EXTENSION));
+    }
+    let (contents, extension) = convert_builtin_document(extension, &bytes)?;
+    let mut metadata: DocumentMetadata = Default::default();
+    metadata.insert(EXTENSION_METADATA.into(), extension.to_string());
+    Ok(LoadedDocument::new(path.into(), contents, metadata))
+}
+
+/// Whether files of this extension load without a configured document loader.
+pub fn is_builtin_document(extension: &str) -> bool {
+    matches!(extension, "pdf" | "docx" | "epub")
+}
+
+/// Converts a built-in document format to text, returning the extension the text is in.
+pub fn convert_builtin_document(extension: &str, bytes: &[u8]) -> Result<(String, &'static str)> {
+    match extension {
+        "pdf" => Ok((pdf_to_text(bytes)?, DEFAULT_EXTENSION)),
+        "docx" => Ok((docx_to_md(bytes)?, "md")),
+        "epub" => Ok((epub_to_md(bytes)?, "md")),
+        _ => bail!("Unsupported document format '{extension}'"),
+    }
+}
+
+/// A document made of pages joined by blank lines, recording where each page starts.
+pub fn paged_document(path: &str, pages: &[String], extension: &str) -> LoadedDocument {
+    let mut metadata: DocumentMetadata = Default::default();
+    metadata.insert(EXTENSION_METADATA.into(), extension.to_string());
+    metadata.insert(PAGES_METADATA.into(), page_start_lines(pages));
+    LoadedDocument::new(path.into(), pages.join("\n\n"), metadata)
+}
+
+fn page_start_lines(pages: &[String]) -> String {
+    let mut start = 1;
+    let mut starts = vec![];
+    for page in pages {
+        starts.push(start.to_string());
+        start += page.matches('\n').count() + 2;
+    }
+    starts.join(",")
+}
+
+/// Extracts the text of a PDF page by page, for when no `pdf` document loader is configured.
+pub fn pdf_to_text(bytes: &[u8]) -> Result<String> {
+    let pages = pdf_pages(bytes)?;
+    let pages: Vec<&str> = pages
+        .iter()
+        .map(|v| v.as_str())
+        .filter(|v| !v.is_empty())
+        .collect();
+    Ok(pages.join("\n\n"))
+}
+
+/// The trimmed pages of a PDF's text layer, blank ones kept so page numbers line up.
+pub fn pdf_pages(bytes: &[u8]) -> Result<Vec<String>> {
+    // The parser panics on some malformed files rather than returning an error
+    let pages = std::panic::catch_unwind(|| pdf_extract::extract_text_from_mem_by_pages(bytes))
+        .map_err(|_| anyhow!("Unable to parse the PDF"))?
+        .context("Unable to parse the PDF")?;
+    let pages: Vec<String> = pages.iter().map(|v| v.trim().to_string()).collect();
+    if pages.iter().all(|v| v.is_empty()) {
+        bail!("No text found in the PDF; it may be a scan, which needs a `pdf` document loader with OCR")
+    }
+    Ok(pages)
+}
+
 fn load_with_command(path: &str, extension: &str, loader_command: &str) -> Result<LoadedDocument> {
     let contents = run_loader_command(path, extension, loader_command)?;
     let mut metadata: DocumentMetadata = Default::default();
@@ -123,3 +195,80 @@ pub fn load_protocol_path(
     };
     Ok(output)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a PDF with one page of Helvetica text per item of `pages`.
+    fn build_pdf(pages: &[&str]) -> Vec<u8> {
+        let font_id = 3 + pages.len() * 2;
+        let kids: Vec<String> = (0..pages.len())
+            .map(|i| format!("{} 0 R", 3 + i * 2))
+            .collect();
+        let mut objects = vec![
+            "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+            format!(
+                "<< /Type /Pages /Kids [{}] /Count {} >>",
+                kids.join(" "),
+                pages.len()
+            ),
+        ];
+        for (i, text) in pages.iter().enumerate() {
+            objects.push(format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 612 792] /Contents {} 0 R /Resources << /Font << /F1 {font_id} 0 R >> >> >>",
+                4 + i * 2
+            ));
+            let stream = format!("BT /F1 12 Tf 72 720 Td ({text}) Tj ET");
+            objects.push(format!(
+                "<< /Length {} >>\nstream\n{stream}\nendstream",
+                stream.len()
+            ));
+        }
+        objects.push(
+            "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>"
+                .to_string(),
+        );
+        let mut pdf = String::from("%PDF-1.4\n");
+        let mut offsets = vec![];
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            pdf.push_str(&format!("{} 0 obj\n{object}\nendobj\n", i + 1));
+        }
+        let xref = pdf.len();
+        pdf.push_str(&format!(
+            "xref\n0 {}\n0000000000 65535 f \n",
+            objects.len() + 1
+        ));
+        for offset in offsets {
+            pdf.push_str(&format!("{offset:010} 00000 n \n"));
+        }
+        pdf.push_str(&format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{xref}\n%%EOF\n",
+            objects.len() + 1
+        ));
+        pdf.into_bytes()
+    }
+
+    #[test]
+    fn test_pdf_to_text() {
+        let text = pdf_to_text(&build_pdf(&["Quarterly report", "Revenue grew"])).unwrap();
+        assert_eq!(text, "Quarterly report\n\nRevenue grew");
+        assert!(pdf_to_text(&build_pdf(&[""])).is_err());
+        assert!(pdf_to_text(b"not a pdf").is_err());
+    }
+
+    #[test]
+    fn test_paged_document() {
+        let pages = [
+            "Intro".to_string(),
+            "Line one\nLine two".to_string(),
+            "End".into(),
+        ];
+        let document = paged_document("report.pdf", &pages, DEFAULT_EXTENSION);
+        assert_eq!(document.metadata[PAGES_METADATA], "1,3,6");
+        let lines: Vec<&str> = document.contents.lines().collect();
+        assert_eq!(lines[2], "Line one");
+        assert_eq!(lines[5], "End");
+    }
+}
@@ -1,40 +1,63 @@
 mod abort_signal;
+mod calculator;
 mod clipboard;
+mod code_block;
 mod command;
 mod crypto;
+mod docx;
+mod epub;
+mod file_edit;
+mod git_loader;
 mod html_to_md;
 mod input;
 mod loader;
 mod path;
+mod readability;
 mod render_prompt;
+mod repo_map;
 mod request;
+mod sandbox;
 mod spinner;
 mod variables;
 
 pub use self::abort_signal::*;
+pub use self::calculator::*;
 pub use self::clipboard::set_text;
+pub use self::code_block::*;
 pub use self::command::*;
 pub use self::crypto::*;
+pub use self::docx::*;
+pub use self::epub::*;
+pub use self::file_edit::*;
+pub use self::git_loader::*;
 pub use self::html_to_md::*;
 pub use self::input::*;
 pub use self::loader::*;
 pub use self::path::*;
+pub use self::readability::*;
 pub use self::render_prompt::render_prompt;
+pub use self::repo_map::*;
 pub use self::request::*;
+pub use self::sandbox::*;
 pub use self::spinner::*;
 pub use self::variables::*;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use fancy_regex::Regex;
 use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
 use is_terminal::IsTerminal;
+use parking_lot::Mutex;
 use std::borrow::Cow;
-use std::sync::LazyLock;
-use std::{env, path::PathBuf, process};
+use std::cmp::Reverse;
+use std::sync::{LazyLock, OnceLock};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, Instant},
+};
 use unicode_segmentation::UnicodeSegmentation;
 
-pub static CODE_BLOCK_RE: LazyLock<Regex> =
-    LazyLock::new(|| Regex::new(r"(?ms)```\w*(.*)```").unwrap());
 pub static THINK_TAG_RE: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r"(?s)^\s*<think>.*?</think>(\s*|$)").unwrap());
 pub static IS_STDOUT_TERMINAL: LazyLock<bool> = LazyLock::new(|| std::io::stdout().is_terminal());
@@ -70,6 +93,39 @@ pub fn parse_bool(value: &str) -> Option<bool> {
     }
 }
 
+/// Parses a duration like `30s`, `2m`, `1h`, `7d` or `500ms`; a bare number is seconds.
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let value = value.trim();
+    let index = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(index);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid duration '{value}'"))?;
+    let secs = match unit {
+        "" | "s" => number,
+        "ms" => number / 1000.0,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        "d" => number * 86400.0,
+        "w" => number * 604800.0,
+        _ => bail!("Invalid duration '{value}', use a unit of ms, s, m, h, d or w"),
+    };
+    Duration::try_from_secs_f64(secs)
+        .map_err(|_| anyhow!("Invalid duration '{value}', it is too long"))
+}
+
+/// Splits a `--tag` value of the form `key=value`.
+pub fn parse_tag(value: &str) -> Result<(String, String)> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        }
+        _ => bail!("Invalid tag '{value}', expected KEY=VALUE"),
+    }
+}
+
 pub fn estimate_token_length(text: &str) -> usize {
     let words: Vec<&str> = text.unicode_words().collect();
     let mut output: f32 = 0.0;
@@ -92,14 +148,6 @@ pub fn strip_think_tag(text: &str) -> Cow<'_, str> {
     THINK_TAG_RE.replace_all(text, "")
 }
 
-pub fn extract_code_block(text: &str) -> &str {
-    CODE_BLOCK_RE
-        .captures(text)
-        .ok()
-        .and_then(|v| v?.get(1).map(|v| v.as_str().trim()))
-        .unwrap_or(text)
-}
-
 pub fn convert_option_string(value: &str) -> Option<String> {
     if value.is_empty() {
         None
@@ -120,10 +168,78 @@ where
             Some((v, score))
         })
         .collect();
-    list.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+    list.sort_unstable_by_key(|v| std::cmp::Reverse(v.1));
     list.into_iter().map(|(v, _)| v).collect()
 }
 
+/// Suggests the known values closest to a mistyped one, e.g. ` (did you mean 'coder'?)`, or
+/// nothing if none is close. Values within a small edit distance match, as do those the fuzzy
+/// matcher finds with a similar length, e.g. a missing or extra letter. Ids like
+/// `openai:gpt-4o` also match on the part after the colon, and builtin names like `%shell%`
+/// without the percent signs.
+pub fn did_you_mean<I, S>(value: &str, candidates: I) -> String
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let matcher = SkimMatcherV2::default();
+    let max_distance = (value.chars().count() / 3).max(2);
+    let score = |name: &str| {
+        let distance = edit_distance(value, name);
+        let fuzzy = if value.chars().count().abs_diff(name.chars().count()) <= max_distance {
+            matcher
+                .fuzzy_match(name, value)
+                .max(matcher.fuzzy_match(value, name))
+        } else {
+            None
+        };
+        (distance <= max_distance || fuzzy.is_some()).then_some((distance, Reverse(fuzzy)))
+    };
+    let mut matches: Vec<_> = candidates
+        .into_iter()
+        .filter_map(|candidate| {
+            let candidate = candidate.as_ref();
+            let mut names = vec![candidate, candidate.trim_matches('%')];
+            if !value.contains(':') {
+                if let Some((_, name)) = candidate.split_once(':') {
+                    names.push(name);
+                }
+            }
+            let best = names.into_iter().filter_map(score).min()?;
+            Some((best, candidate.to_string()))
+        })
+        .collect();
+    matches.sort();
+    matches.dedup_by(|a, b| a.1 == b.1);
+    let names: Vec<String> = matches
+        .into_iter()
+        .take(3)
+        .map(|(_, v)| format!("'{v}'"))
+        .collect();
+    match names.split_last() {
+        None => String::new(),
+        Some((last, [])) => format!(" (did you mean {last}?)"),
+        Some((last, rest)) => format!(" (did you mean {} or {last}?)", rest.join(", ")),
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let value = (prev + (ca != *cb) as usize)
+                .min(row[j] + 1)
+                .min(row[j + 1] + 1);
+            prev = row[j + 1];
+            row[j + 1] = value;
+        }
+    }
+    row[b.len()]
+}
+
 pub fn pretty_error(err: &anyhow::Error) -> String {
     let mut output = vec![];
     output.push(format!("Error: {err}"));
@@ -200,18 +316,100 @@ pub fn temp_file(prefix: &str, suffix: &str) -> PathBuf {
     ))
 }
 
+/// How long the branch shown in the prompt is reused before `.git/HEAD` is read again.
+const GIT_BRANCH_TTL: Duration = Duration::from_secs(2);
+
+static GIT_BRANCH_CACHE: Mutex<Option<(PathBuf, Instant, Option<String>)>> = Mutex::new(None);
+
+/// The branch checked out in the git repository containing the current directory,
+/// or the short commit hash when HEAD is detached. Cached briefly since the prompt
+/// asks on every render.
+pub fn git_branch() -> Option<String> {
+    let cwd = env::current_dir().ok()?;
+    let mut cache = GIT_BRANCH_CACHE.lock();
+    if let Some((dir, time, branch)) = cache.as_ref() {
+        if *dir == cwd && time.elapsed() < GIT_BRANCH_TTL {
+            return branch.clone();
+        }
+    }
+    let branch = read_git_branch(&cwd);
+    *cache = Some((cwd, Instant::now(), branch.clone()));
+    branch
+}
+
+fn read_git_branch(dir: &Path) -> Option<String> {
+    let dot_git = dir
+        .ancestors()
+        .map(|v| v.join(".git"))
+        .find(|v| v.exists())?;
+    let git_dir = if dot_git.is_file() {
+        let content = std::fs::read_to_string(&dot_git).ok()?;
+        let path = content.trim().strip_prefix("gitdir:")?.trim();
+        dot_git.parent()?.join(path)
+    } else {
+        dot_git
+    };
+    let head = std::fs::read_to_string(git_dir.join("HEAD")).ok()?;
+    let head = head.trim();
+    match head.strip_prefix("ref: ") {
+        Some(v) => Some(v.strip_prefix("refs/heads/").unwrap_or(v).to_string()),
+        None => head.get(..7).map(|v| v.to_string()),
+    }
+}
+
 pub fn is_url(path: &str) -> bool {
     path.starts_with("http://") || path.starts_with("https://")
 }
 
+static PROXY_OVERRIDE: OnceLock<String> = OnceLock::new();
+
+const PROXY_ENV_NAMES: [&str; 6] = [
+    "HTTPS_PROXY",
+    "https_proxy",
+    "HTTP_PROXY",
+    "http_proxy",
+    "ALL_PROXY",
+    "all_proxy",
+];
+
+/// Set the proxy given by `--proxy`, which takes precedence over any configured proxy.
+pub fn set_proxy_override(proxy: &str) {
+    let _ = PROXY_OVERRIDE.set(proxy.to_string());
+}
+
+/// The proxy requests go through: `--proxy`, then the client's `extra.proxy`, then the environment.
+/// Returns `None` when proxies are disabled with `-`.
+pub fn effective_proxy(client_proxy: Option<&str>) -> Option<String> {
+    match PROXY_OVERRIDE.get().map(|v| v.as_str()).or(client_proxy) {
+        Some(proxy) if proxy.is_empty() || proxy == "-" => None,
+        Some(proxy) => Some(proxy.to_string()),
+        None => PROXY_ENV_NAMES
+            .iter()
+            .find_map(|name| env::var(name).ok().filter(|v| !v.is_empty())),
+    }
+}
+
+pub fn apply_proxy(
+    builder: reqwest::ClientBuilder,
+    client_proxy: Option<&str>,
+) -> Result<reqwest::ClientBuilder> {
+    match PROXY_OVERRIDE.get().map(|v| v.as_str()).or(client_proxy) {
+        Some(proxy) => set_proxy(builder, proxy),
+        // reqwest picks up HTTP(S)_PROXY, ALL_PROXY and NO_PROXY on its own
+        None => Ok(builder),
+    }
+}
+
 pub fn set_proxy(
     mut builder: reqwest::ClientBuilder,
     proxy: &str,
 ) -> Result<reqwest::ClientBuilder> {
     builder = builder.no_proxy();
     if !proxy.is_empty() && proxy != "-" {
-        builder = builder
-            .proxy(reqwest::Proxy::all(proxy).with_context(|| format!("Invalid proxy `{proxy}`"))?);
+        let proxy = reqwest::Proxy::all(proxy)
+            .with_context(|| format!("Invalid proxy `{proxy}`"))?
+            .no_proxy(reqwest::NoProxy::from_env());
+        builder = builder.proxy(proxy);
     };
     Ok(builder)
 }
@@ -225,6 +423,87 @@ pub fn decode_bin<T: serde::de::DeserializeOwned>(data: &[u8]) -> Result<T> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_did_you_mean() {
+        let names = ["coder", "code", "%shell%", "openai:gpt-4o", ".session"];
+        assert_eq!(
+            did_you_mean("codr", names),
+            " (did you mean 'coder' or 'code'?)"
+        );
+        assert_eq!(
+            did_you_mean("cod", names),
+            " (did you mean 'code' or 'coder'?)"
+        );
+        assert_eq!(did_you_mean("cdoer", names), " (did you mean 'coder'?)");
+        assert_eq!(
+            did_you_mean("gpt-4p", names),
+            " (did you mean 'openai:gpt-4o'?)"
+        );
+        assert_eq!(did_you_mean("sehll", names), " (did you mean '%shell%'?)");
+        assert_eq!(
+            did_you_mean(".sessoin", names),
+            " (did you mean '.session'?)"
+        );
+        assert_eq!(
+            did_you_mean("gpt-4", names),
+            " (did you mean 'openai:gpt-4o'?)"
+        );
+        assert_eq!(did_you_mean("shel", names), " (did you mean '%shell%'?)");
+        assert_eq!(did_you_mean("translator", names), "");
+    }
+
+    #[test]
+    fn test_interpolate_env_variables() {
+        let lookup = |name: &str| match name {
+            "BASE" => Some("http://localhost:8080".to_string()),
+            "KEY" => Some("a#b: c\nd".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        let mut value: serde_yaml::Value = serde_yaml::from_str(
+            "api_base: ${BASE}/v1\n# ${UNSET}\nkey: ${KEY}\nfallback: ${UNSET:-none}\nempty: ${EMPTY:-a}|${EMPTY-b}\nraw: $${BASE}\nprompt: echo $${UNSET} ${1}\nlist: [\"${BASE}\", 1]\nmax_tokens: ${TOKENS:-1024}\nstream: ${STREAM-false}\nlabel: v${TOKENS:-2}\n",
+        )
+        .unwrap();
+        interpolate_env_variables(&mut value, &lookup).unwrap();
+        let expected: serde_yaml::Value = serde_yaml::from_str(
+            "api_base: http://localhost:8080/v1\nkey: \"a#b: c\\nd\"\nfallback: none\nempty: a|\nraw: ${BASE}\nprompt: echo ${UNSET} ${1}\nlist: [\"http://localhost:8080\", 1]\nmax_tokens: 1024\nstream: false\nlabel: v2\n",
+        )
+        .unwrap();
+        assert_eq!(value, expected);
+
+        let mut value: serde_yaml::Value =
+            serde_yaml::from_str("clients:\n  - api_key: ${API_KEY}\n").unwrap();
+        let err = interpolate_env_variables(&mut value, &lookup).unwrap_err();
+        assert!(err.to_string().contains("'API_KEY' is not set"));
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_duration("1.5h").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration("2d").unwrap(), Duration::from_secs(172800));
+        assert!(parse_duration("5 days").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("99999999999999999999999w").is_err());
+    }
+
+    #[test]
+    fn test_parse_tag() {
+        assert_eq!(
+            parse_tag("project=billing").unwrap(),
+            ("project".into(), "billing".into())
+        );
+        assert_eq!(
+            parse_tag("ticket=OPS-1=2").unwrap(),
+            ("ticket".into(), "OPS-1=2".into())
+        );
+        assert!(parse_tag("project").is_err());
+        assert!(parse_tag("=billing").is_err());
+    }
+
     #[test]
     #[cfg(not(target_os = "windows"))]
     fn test_safe_join_path() {
@@ -246,4 +525,30 @@ mod tests {
         assert!(safe_join_path("C:\\Users\\user\\dir1", "/files/file1").is_none());
         assert!(safe_join_path("C:\\Users\\user\\dir1", "../file1").is_none());
     }
+
+    #[test]
+    fn test_read_git_branch() {
+        let dir = temp_file("-git-branch", "");
+        let repo = dir.join("repo");
+        std::fs::create_dir_all(repo.join(".git")).unwrap();
+        std::fs::write(repo.join(".git/HEAD"), "ref: refs/heads/feature/x\n").unwrap();
+        assert_eq!(read_git_branch(&repo).as_deref(), Some("feature/x"));
+        std::fs::create_dir_all(repo.join("src")).unwrap();
+        assert_eq!(
+            read_git_branch(&repo.join("src")).as_deref(),
+            Some("feature/x")
+        );
+
+        std::fs::write(repo.join(".git/HEAD"), "0123456789abcdef\n").unwrap();
+        assert_eq!(read_git_branch(&repo).as_deref(), Some("0123456"));
+
+        // A worktree points to its git dir from a `.git` file
+        let worktree = dir.join("worktree");
+        std::fs::create_dir_all(dir.join("gitdir")).unwrap();
+        std::fs::create_dir_all(&worktree).unwrap();
+        std::fs::write(dir.join("gitdir/HEAD"), "ref: refs/heads/main\n").unwrap();
+        std::fs::write(worktree.join(".git"), "gitdir: ../gitdir\n").unwrap();
+        assert_eq!(read_git_branch(&worktree).as_deref(), Some("main"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -86,7 +86,7 @@ pub fn resolve_home_dir(path: &str) -> String {
     path
 }
 
-fn parse_glob(path_str: &str) -> Result<(String, Option<Vec<String>>, bool)> {
+pub fn parse_glob(path_str: &str) -> Result<(String, Option<Vec<String>>, bool)> {
     let glob_result =
         if let Some(start) = path_str.find("/**/*.").or_else(|| path_str.find(r"\**\*.")) {
             Some((start, 6, false))
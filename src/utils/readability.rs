@@ -0,0 +1,226 @@
+use super::html_to_md;
+
+use fancy_regex::Regex;
+use scraper::{ElementRef, Html, Node, Selector};
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+/// Pages whose main content has less text than this are converted whole.
+const MIN_CONTENT_CHARS: usize = 200;
+
+static POSITIVE_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)article|body|content|entry|main|page|post|text|blog|story").unwrap()
+});
+static NEGATIVE_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\b(nav|navbar|menu|sidebar|footer|header|masthead|breadcrumbs?|comments?|share|social|related|promo|sponsor|advert|ad-|ads|banner|cookie|newsletter|subscribe|popup|modal|widget)\b").unwrap()
+});
+
+const SKIPPED_TAGS: [&str; 12] = [
+    "script", "style", "noscript", "template", "nav", "header", "footer", "aside", "form",
+    "iframe", "button", "svg",
+];
+const CONTAINER_TAGS: [&str; 6] = ["div", "section", "ul", "ol", "table", "span"];
+
+/// Converts a web page to markdown, keeping only its main content so navigation, sidebars and
+/// footers don't end up in RAG chunks.
+pub fn html_to_readable_md(html: &str) -> String {
+    match extract_main_content(html) {
+        Some(content) => html_to_md(&content),
+        None => html_to_md(html),
+    }
+}
+
+/// Finds the element holding the article, scored like Readability: paragraphs credit their
+/// parent and grandparent by length and commas, discounted by link density and nudged by
+/// class/id hints. Returns its cleaned HTML, titled when it has no heading of its own.
+fn extract_main_content(html: &str) -> Option<String> {
+    let document = Html::parse_document(html);
+    let root = preferred_root(&document).unwrap_or_else(|| document.root_element());
+    let paragraphs = Selector::parse("p, pre, td, blockquote").ok()?;
+    let mut scores = HashMap::new();
+    for paragraph in root.select(&paragraphs) {
+        let text = collapse_whitespace(&paragraph.text().collect::<String>());
+        let len = text.chars().count();
+        if len < 25 {
+            continue;
+        }
+        let score = 1.0 + text.matches(',').count() as f64 + (len as f64 / 100.0).min(3.0);
+        let mut ancestors = paragraph.ancestors().filter_map(ElementRef::wrap);
+        if let Some(parent) = ancestors.next() {
+            *scores
+                .entry(parent.id())
+                .or_insert_with(|| class_weight(parent)) += score;
+        }
+        if let Some(grandparent) = ancestors.next() {
+            *scores
+                .entry(grandparent.id())
+                .or_insert_with(|| class_weight(grandparent)) += score / 2.0;
+        }
+    }
+    let top = scores
+        .into_iter()
+        .filter_map(|(id, score)| {
+            let element = ElementRef::wrap(document.tree.get(id)?)?;
+            Some((element, score * (1.0 - link_density(element))))
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(element, _)| element)
+        .unwrap_or(root);
+
+    let mut output = String::new();
+    serialize_clean(top, &mut output);
+    let text_len = collapse_whitespace(&top.text().collect::<String>()).len();
+    if text_len < MIN_CONTENT_CHARS {
+        return None;
+    }
+    let has_heading = Selector::parse("h1")
+        .ok()
+        .is_some_and(|v| top.select(&v).next().is_some());
+    if !has_heading {
+        if let Some(title) = page_title(&document) {
+            output = format!("<h1>{}</h1>{output}", escape_html(&title));
+        }
+    }
+    Some(output)
+}
+
+/// An `<article>` or `<main>` element, which pages use to mark their main content.
+fn preferred_root(document: &Html) -> Option<ElementRef<'_>> {
+    let selector = Selector::parse("article, main, [role=main]").ok()?;
+    document.select(&selector).max_by_key(|v| {
+        v.text()
+            .map(|text| text.trim().chars().count())
+            .sum::<usize>()
+    })
+}
+
+fn page_title(document: &Html) -> Option<String> {
+    let selector = Selector::parse(r#"meta[property="og:title"], title"#).ok()?;
+    document.select(&selector).find_map(|v| {
+        let title = match v.value().name() {
+            "meta" => v.value().attr("content")?.to_string(),
+            _ => v.text().collect(),
+        };
+        let title = collapse_whitespace(&title);
+        (!title.is_empty()).then_some(title)
+    })
+}
+
+fn class_weight(element: ElementRef) -> f64 {
+    let hints = hints(element);
+    let mut weight = 0.0;
+    if let Ok(true) = NEGATIVE_RE.is_match(&hints) {
+        weight -= 25.0;
+    }
+    if let Ok(true) = POSITIVE_RE.is_match(&hints) {
+        weight += 25.0;
+    }
+    weight
+}
+
+fn hints(element: ElementRef) -> String {
+    let value = element.value();
+    format!(
+        "{} {}",
+        value.attr("class").unwrap_or_default(),
+        value.attr("id").unwrap_or_default()
+    )
+}
+
+fn link_density(element: ElementRef) -> f64 {
+    let text_len = element.text().map(|v| v.trim().len()).sum::<usize>();
+    if text_len == 0 {
+        return 0.0;
+    }
+    let Ok(links) = Selector::parse("a") else {
+        return 0.0;
+    };
+    let link_len: usize = element
+        .select(&links)
+        .flat_map(|v| v.text())
+        .map(|v| v.trim().len())
+        .sum();
+    link_len as f64 / text_len as f64
+}
+
+/// Writes the element back out as HTML without page chrome: scripts, navigation, forms and
+/// containers whose class or id mark them as sidebars, share bars, comments and the like.
+fn serialize_clean(element: ElementRef, output: &mut String) {
+    for child in element.children() {
+        match child.value() {
+            Node::Text(text) => output.push_str(&escape_html(text)),
+            Node::Element(value) => {
+                let Some(child) = ElementRef::wrap(child) else {
+                    continue;
+                };
+                let name = value.name();
+                if SKIPPED_TAGS.contains(&name)
+                    || (CONTAINER_TAGS.contains(&name)
+                        && NEGATIVE_RE.is_match(&hints(child)).unwrap_or_default())
+                {
+                    continue;
+                }
+                output.push('<');
+                output.push_str(name);
+                for attr in ["href", "src", "alt", "title", "class"] {
+                    if let Some(value) = value.attr(attr) {
+                        output.push_str(&format!(r#" {attr}="{}""#, escape_html(value)));
+                    }
+                }
+                output.push('>');
+                serialize_clean(child, output);
+                output.push_str(&format!("</{name}>"));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: &str = r#"<html><head><title>Tuning the cache</title></head><body>
+<div id="nav"><a href="/">Home</a> <a href="/docs">Docs</a> <a href="/blog">Blog</a></div>
+<div class="layout">
+  <div class="sidebar"><p>Sign up for our newsletter to get weekly updates, tips and offers.</p></div>
+  <div class="post-content">
+    <p>The cache keeps recently used entries in memory, so repeated lookups skip the disk.</p>
+    <p>Raise the capacity when the hit rate drops, and lower it when memory gets tight.</p>
+    <p>Entries expire after the TTL, which defaults to five minutes, or when evicted early.</p>
+    <div class="share-buttons"><p>Share this post on Twitter, Facebook and LinkedIn today.</p></div>
+  </div>
+</div>
+<footer><p>Copyright 2024 Example Inc. All rights reserved, terms and privacy apply.</p></footer>
+</body></html>"#;
+
+    #[test]
+    fn test_extract_main_content() {
+        let content = extract_main_content(PAGE).unwrap();
+        assert!(content.starts_with("<h1>Tuning the cache</h1>"));
+        assert!(content.contains("repeated lookups skip the disk"));
+        assert!(content.contains("defaults to five minutes"));
+        assert!(!content.contains("newsletter"));
+        assert!(!content.contains("Share this post"));
+        assert!(!content.contains("Copyright"));
+        assert!(!content.contains("Docs"));
+    }
+
+    #[test]
+    fn test_short_page_falls_back() {
+        let html = "<html><body><p>Just a short note.</p></body></html>";
+        assert_eq!(extract_main_content(html), None);
+        assert!(html_to_readable_md(html).contains("Just a short note."));
+    }
+}
@@ -0,0 +1,468 @@
+use super::*;
+
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use tree_sitter::{Language as TsLanguage, Node, Parser};
+
+pub const REPO_MAP_PROTOCOL: &str = "repo-map";
+
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+const MAX_SIGNATURE_WIDTH: usize = 160;
+
+pub fn is_repo_map_path(path: &str) -> bool {
+    path.split_once(':')
+        .map(|(protocol, _)| protocol == REPO_MAP_PROTOCOL)
+        .unwrap_or_default()
+}
+
+/// Build a compact structural summary of a codebase: the file tree, line counts
+/// and the public symbols of each source file, trimmed to fit `max_tokens`.
+pub fn build_repo_map(path: &str, max_tokens: usize) -> Result<String> {
+    let dir = match path.split_once(':') {
+        Some((_, v)) if !v.is_empty() => v,
+        _ => ".",
+    };
+    let dir = resolve_home_dir(dir);
+    let root = Path::new(&dir);
+    if !root.is_dir() {
+        bail!("'{dir}' is not a directory");
+    }
+
+    let mut files = vec![];
+    for entry in ignore::WalkBuilder::new(root)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build()
+    {
+        let entry = entry.with_context(|| format!("Failed to walk '{dir}'"))?;
+        if !entry.file_type().map(|v| v.is_file()).unwrap_or_default() {
+            continue;
+        }
+        let file_path = entry.path();
+        let relative_path = file_path
+            .strip_prefix(root)
+            .unwrap_or(file_path)
+            .to_string_lossy()
+            .replace('\\', "/");
+        let contents = match entry.metadata() {
+            Ok(v) if v.len() > MAX_FILE_SIZE => Err(FileStatus::TooLarge),
+            Ok(_) => match std::fs::read(file_path) {
+                Ok(bytes) => String::from_utf8(bytes).map_err(|_| FileStatus::Binary),
+                Err(_) => Err(FileStatus::Unreadable),
+            },
+            Err(_) => Err(FileStatus::Unreadable),
+        };
+        let (status, symbols) = match contents {
+            Ok(contents) => {
+                let symbols = get_patch_extension(&relative_path)
+                    .and_then(|ext| extract_symbols(&ext, &contents))
+                    .unwrap_or_default();
+                (FileStatus::Lines(contents.lines().count()), symbols)
+            }
+            Err(status) => (status, vec![]),
+        };
+        files.push(RepoMapFile {
+            path: relative_path,
+            status,
+            symbols,
+        });
+    }
+
+    Ok(render_repo_map(&dir, &files, max_tokens))
+}
+
+#[derive(Debug)]
+struct RepoMapFile {
+    path: String,
+    status: FileStatus,
+    symbols: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FileStatus {
+    Lines(usize),
+    /// Over `MAX_FILE_SIZE`, so not read
+    TooLarge,
+    /// Not valid UTF-8
+    Binary,
+    Unreadable,
+}
+
+impl RepoMapFile {
+    fn header(&self) -> String {
+        match self.status {
+            FileStatus::Lines(lines) => format!("{} ({lines} lines)", self.path),
+            FileStatus::TooLarge => format!("{} (too large)", self.path),
+            FileStatus::Binary => format!("{} (binary)", self.path),
+            FileStatus::Unreadable => format!("{} (unreadable)", self.path),
+        }
+    }
+}
+
+fn render_repo_map(dir: &str, files: &[RepoMapFile], max_tokens: usize) -> String {
+    let title = format!("Repository map of '{dir}' ({} files)", files.len());
+    let mut used_tokens = estimate_token_length(&title);
+
+    // The file tree takes priority; symbols are added while the budget allows.
+    let mut headers = vec![];
+    for file in files {
+        let header = file.header();
+        let tokens = estimate_token_length(&header);
+        if used_tokens + tokens > max_tokens {
+            break;
+        }
+        used_tokens += tokens;
+        headers.push(header);
+    }
+
+    let mut output = vec![title];
+    for (file, header) in files.iter().zip(headers.iter()) {
+        output.push(header.clone());
+        if file.symbols.is_empty() {
+            continue;
+        }
+        let symbols = file
+            .symbols
+            .iter()
+            .map(|v| format!("  {v}"))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let tokens = estimate_token_length(&symbols);
+        if used_tokens + tokens <= max_tokens {
+            used_tokens += tokens;
+            output.push(symbols);
+        }
+    }
+    let omitted = files.len() - headers.len();
+    if omitted > 0 {
+        output.push(format!("... ({omitted} more files omitted)"));
+    }
+    output.join("\n")
+}
+
+pub fn get_ts_language(extension: &str) -> Option<TsLanguage> {
+    let language = match extension {
+        "rs" => tree_sitter_rust::LANGUAGE.into(),
+        "py" => tree_sitter_python::LANGUAGE.into(),
+        "js" | "mjs" | "cjs" | "jsx" => tree_sitter_javascript::LANGUAGE.into(),
+        "ts" | "mts" | "cts" => tree_sitter_typescript::LANGUAGE_TYPESCRIPT.into(),
+        "tsx" => tree_sitter_typescript::LANGUAGE_TSX.into(),
+        "go" => tree_sitter_go::LANGUAGE.into(),
+        _ => return None,
+    };
+    Some(language)
+}
+
+fn extract_symbols(extension: &str, contents: &str) -> Option<Vec<String>> {
+    let language = get_ts_language(extension)?;
+    let mut parser = Parser::new();
+    parser.set_language(&language).ok()?;
+    let tree = parser.parse(contents, None)?;
+    let source = contents.as_bytes();
+    let mut symbols = vec![];
+    match extension {
+        "rs" => collect_rust_symbols(tree.root_node(), source, 0, &mut symbols),
+        "py" => collect_python_symbols(tree.root_node(), source, 0, &mut symbols),
+        "go" => collect_go_symbols(tree.root_node(), source, &mut symbols),
+        _ => collect_js_symbols(tree.root_node(), source, &mut symbols),
+    }
+    Some(symbols)
+}
+
+fn collect_rust_symbols(node: Node, source: &[u8], depth: usize, symbols: &mut Vec<String>) {
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        let is_public = has_child_kind(child, "visibility_modifier");
+        match child.kind() {
+            "function_item"
+            | "function_signature_item"
+            | "struct_item"
+            | "enum_item"
+            | "trait_item"
+            | "type_item"
+            | "const_item"
+            | "static_item"
+            | "union_item"
+                if is_public =>
+            {
+                symbols.push(indent_signature(depth, child, source));
+            }
+            "macro_definition" if depth == 0 => {
+                symbols.push(indent_signature(depth, child, source));
+            }
+            "mod_item" if is_public => {
+                symbols.push(indent_signature(depth, child, source));
+                if let Some(body) = child.child_by_field_name("body") {
+                    collect_rust_symbols(body, source, depth + 1, symbols);
+                }
+            }
+            "impl_item" => {
+                let is_trait_impl = child.child_by_field_name("trait").is_some();
+                let mut methods = vec![];
+                if let Some(body) = child.child_by_field_name("body") {
+                    let mut body_cursor = body.walk();
+                    for item in body.children(&mut body_cursor) {
+                        if item.kind() == "function_item"
+                            && (is_trait_impl || has_child_kind(item, "visibility_modifier"))
+                        {
+                            methods.push(indent_signature(depth + 1, item, source));
+                        }
+                    }
+                }
+                if is_trait_impl || !methods.is_empty() {
+                    symbols.push(indent_signature(depth, child, source));
+                    if !is_trait_impl {
+                        symbols.extend(methods);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn collect_python_symbols(node: Node, source: &[u8], depth: usize, symbols: &mut Vec<String>) {
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        let definition = if child.kind() == "decorated_definition" {
+            match child.child_by_field_name("definition") {
+                Some(v) => v,
+                None => continue,
+            }
+        } else {
+            child
+        };
+        match definition.kind() {
+            "function_definition" | "class_definition" => {
+                let name = node_field_text(definition, "name", source).unwrap_or_default();
+                if name.starts_with('_') && name != "__init__" {
+                    continue;
+                }
+                symbols.push(indent_signature(depth, definition, source));
+                if definition.kind() == "class_definition" {
+                    if let Some(body) = definition.child_by_field_name("body") {
+                        collect_python_symbols(body, source, depth + 1, symbols);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn collect_go_symbols(node: Node, source: &[u8], symbols: &mut Vec<String>) {
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        match child.kind() {
+            "function_declaration" | "method_declaration"
+                if is_go_exported(node_field_text(child, "name", source)) =>
+            {
+                symbols.push(indent_signature(0, child, source));
+            }
+            "type_declaration" => {
+                let mut spec_cursor = child.walk();
+                for spec in child.children(&mut spec_cursor) {
+                    if spec.kind() == "type_spec"
+                        && is_go_exported(node_field_text(spec, "name", source))
+                    {
+                        let name = node_field_text(spec, "name", source).unwrap_or_default();
+                        let kind = spec
+                            .child_by_field_name("type")
+                            .map(|v| v.kind().trim_end_matches("_type"))
+                            .unwrap_or("type");
+                        symbols.push(format!("type {name} {kind}"));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn collect_js_symbols(node: Node, source: &[u8], symbols: &mut Vec<String>) {
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        if child.kind() != "export_statement" {
+            continue;
+        }
+        match child.child_by_field_name("declaration") {
+            Some(declaration) => {
+                symbols.push(indent_signature(0, declaration, source));
+                if declaration.kind() == "class_declaration" {
+                    if let Some(body) = declaration.child_by_field_name("body") {
+                        let mut body_cursor = body.walk();
+                        for member in body.children(&mut body_cursor) {
+                            if member.kind() == "method_definition"
+                                && !node_field_text(member, "name", source)
+                                    .unwrap_or_default()
+                                    .starts_with('#')
+                            {
+                                symbols.push(indent_signature(1, member, source));
+                            }
+                        }
+                    }
+                }
+            }
+            None => symbols.push(indent_signature(0, child, source)),
+        }
+    }
+}
+
+fn has_child_kind(node: Node, kind: &str) -> bool {
+    let mut cursor = node.walk();
+    let found = node.children(&mut cursor).any(|v| v.kind() == kind);
+    found
+}
+
+fn node_field_text<'a>(node: Node, field: &str, source: &'a [u8]) -> Option<&'a str> {
+    node.child_by_field_name(field)?.utf8_text(source).ok()
+}
+
+fn is_go_exported(name: Option<&str>) -> bool {
+    name.and_then(|v| v.chars().next())
+        .map(|v| v.is_uppercase())
+        .unwrap_or_default()
+}
+
+/// Render the declaration head of a node (everything before its body) on a single line.
+pub fn indent_signature(depth: usize, node: Node, source: &[u8]) -> String {
+    let end = node
+        .child_by_field_name("body")
+        .or_else(|| node.child_by_field_name("value"))
+        .map(|v| v.start_byte())
+        .unwrap_or_else(|| node.end_byte());
+    let text = String::from_utf8_lossy(&source[node.start_byte()..end]);
+    let mut signature = text
+        .lines()
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty() && !v.starts_with("#[") && !v.starts_with('@'))
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .replace("( ", "(")
+        .replace(", )", ")");
+    let trimmed_len = signature.trim_end_matches([' ', '{', ':', ';', '=']).len();
+    signature.truncate(trimmed_len);
+    if signature.chars().count() > MAX_SIGNATURE_WIDTH {
+        signature = signature.chars().take(MAX_SIGNATURE_WIDTH - 3).collect();
+        signature.push_str("...");
+    }
+    format!("{}{signature}", "  ".repeat(depth))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_rust_symbols() {
+        let contents = r#"
+use std::fmt;
+
+pub struct Foo {
+    bar: usize,
+}
+
+struct Private;
+
+impl Foo {
+    pub fn new(bar: usize) -> Self {
+        Self { bar }
+    }
+
+    fn hidden(&self) {}
+}
+
+impl fmt::Display for Foo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.bar)
+    }
+}
+
+#[derive(Debug)]
+pub enum Kind { A, B }
+"#;
+        assert_eq!(
+            extract_symbols("rs", contents).unwrap(),
+            vec![
+                "pub struct Foo",
+                "impl Foo",
+                "  pub fn new(bar: usize) -> Self",
+                "impl fmt::Display for Foo",
+                "pub enum Kind",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_python_symbols() {
+        let contents = r#"
+def public(a, b):
+    return a + b
+
+def _private():
+    pass
+
+class Foo:
+    def __init__(self):
+        pass
+
+    def bar(self) -> int:
+        return 1
+"#;
+        assert_eq!(
+            extract_symbols("py", contents).unwrap(),
+            vec![
+                "def public(a, b)",
+                "class Foo",
+                "  def __init__(self)",
+                "  def bar(self) -> int",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_repo_map_budget() {
+        let files = vec![
+            RepoMapFile {
+                path: "src/main.rs".into(),
+                status: FileStatus::Lines(10),
+                symbols: vec!["pub fn main()".into()],
+            },
+            RepoMapFile {
+                path: "src/lib.rs".into(),
+                status: FileStatus::Lines(20),
+                symbols: vec![],
+            },
+        ];
+        let output = render_repo_map(".", &files, 1000);
+        assert!(output.contains("src/main.rs (10 lines)\n  pub fn main()"));
+        let budget = estimate_token_length("Repository map of '.' (2 files)")
+            + estimate_token_length("src/main.rs (10 lines)");
+        let output = render_repo_map(".", &files, budget);
+        assert!(output.contains("src/main.rs (10 lines)"));
+        assert!(!output.contains("pub fn main()"));
+        assert!(output.ends_with("(1 more files omitted)"));
+    }
+
+    #[test]
+    fn test_build_repo_map_labels() {
+        let dir = temp_file("-repo-map", "");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "one\ntwo\n").unwrap();
+        std::fs::write(dir.join("b.bin"), [0xff, 0xfe, 0x00]).unwrap();
+        let large = vec![b'x'; MAX_FILE_SIZE as usize + 1];
+        std::fs::write(dir.join("c.log"), large).unwrap();
+        let path = format!("{REPO_MAP_PROTOCOL}:{}", dir.display());
+        let output = build_repo_map(&path, 1000).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(output.contains("a.txt (2 lines)"));
+        assert!(output.contains("b.bin (binary)"));
+        assert!(output.contains("c.log (too large)"));
+
+        let file = RepoMapFile {
+            path: "secret.txt".into(),
+            status: FileStatus::Unreadable,
+            symbols: vec![],
+        };
+        assert_eq!(file.header(), "secret.txt (unreadable)");
+    }
+}
@@ -4,18 +4,17 @@ use anyhow::{anyhow, bail, Context, Result};
 use fancy_regex::Regex;
 use futures_util::{stream, StreamExt};
 use http::header::CONTENT_TYPE;
+use quick_xml::events::Event;
 use reqwest::Url;
 use scraper::{Html, Selector};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::sync::LazyLock;
 use std::{
     collections::{HashMap, HashSet},
-    sync::Arc,
     time::Duration,
 };
 use tokio::io::AsyncWriteExt;
-use tokio::sync::Semaphore;
 
 pub const URL_LOADER: &str = "url";
 pub const RECURSIVE_URL_LOADER: &str = "recursive_url";
@@ -24,12 +23,13 @@ pub const MEDIA_URL_EXTENSION: &str = "media_url";
 pub const DEFAULT_EXTENSION: &str = "txt";
 
 const MAX_CRAWLS: usize = 5;
+const MAX_SITEMAPS: usize = 50;
 const BREAK_ON_ERROR: bool = false;
 const USER_AGENT: &str = "curl/8.6.0";
 
 static CLIENT: LazyLock<Result<reqwest::Client>> = LazyLock::new(|| {
     let builder = reqwest::ClientBuilder::new().timeout(Duration::from_secs(16));
-    let client = builder.build()?;
+    let client = apply_proxy(builder, None)?.build()?;
     Ok(client)
 });
 
@@ -57,16 +57,6 @@ static EXTENSION_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\.[^.]+$").
 static GITHUB_REPO_RE: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r"^https://github\.com/([^/]+)/([^/]+)/tree/([^/]+)").unwrap());
 
-pub async fn fetch(url: &str) -> Result<String> {
-    let client = match *CLIENT {
-        Ok(ref client) => client,
-        Err(ref err) => bail!("{err}"),
-    };
-    let res = client.get(url).send().await?;
-    let output = res.text().await?;
-    Ok(output)
-}
-
 pub async fn fetch_with_loaders(
     loaders: &HashMap<String, String>,
     path: &str,
@@ -111,6 +101,7 @@ pub async fn fetch_with_loaders(
         "application/vnd.oasis.opendocument.spreadsheet" => "ods".into(),
         "application/vnd.oasis.opendocument.presentation" => "odp".into(),
         "application/rtf" => "rtf".into(),
+        "application/epub+zip" => "epub".into(),
         "text/javascript" => "js".into(),
         "text/html" => "html".into(),
         _ => content_type
@@ -153,10 +144,15 @@ pub async fn fetch_with_loaders(
                 };
                 (contents, DEFAULT_EXTENSION.into())
             }
+            None if is_builtin_document(&extension) => {
+                let bytes = res.bytes().await?;
+                let (contents, extension) = convert_builtin_document(&extension, &bytes)?;
+                (contents, extension.into())
+            }
             None => {
                 let contents = res.text().await?;
                 if extension == "html" {
-                    (html_to_md(&contents), "md".into())
+                    (html_to_readable_md(&contents), "md".into())
                 } else {
                     (contents, extension)
                 }
@@ -192,11 +188,69 @@ pub async fn fetch_models(api_base: &str, api_key: Option<&str>) -> Result<Vec<S
     Ok(result)
 }
 
-#[derive(Debug, Clone, Default)]
+/// Limits of the built-in crawler behind `https://.../**` RAG documents, set by `rag_crawler`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CrawlerConfig {
+    /// How many links away from the start page to follow, unlimited when unset
+    pub max_depth: Option<usize>,
+    /// Stop queueing pages past this many
+    pub max_pages: Option<usize>,
+    /// How many pages to fetch at once
+    pub concurrency: usize,
+    /// Hosts other than the start page's whose links are followed
+    pub allowed_domains: Vec<String>,
+    /// Only follow urls matching one of these regexes
+    pub include: Vec<String>,
+    /// Never follow urls matching one of these regexes
+    pub exclude: Vec<String>,
+    /// Seed the crawl with the pages listed in the site's `/sitemap.xml`
+    pub sitemap: bool,
+}
+
+impl Default for CrawlerConfig {
+    fn default() -> Self {
+        Self {
+            max_depth: None,
+            max_pages: None,
+            concurrency: MAX_CRAWLS,
+            allowed_domains: vec![],
+            include: vec![],
+            exclude: vec![],
+            sitemap: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct CrawlOptions {
     extract: Option<String>,
     exclude: Vec<String>,
     no_log: bool,
+    max_depth: Option<usize>,
+    max_pages: Option<usize>,
+    concurrency: usize,
+    allowed_domains: Vec<String>,
+    include_patterns: Vec<Regex>,
+    exclude_patterns: Vec<Regex>,
+    sitemap: bool,
+}
+
+impl Default for CrawlOptions {
+    fn default() -> Self {
+        Self {
+            extract: None,
+            exclude: vec![],
+            no_log: false,
+            max_depth: None,
+            max_pages: None,
+            concurrency: MAX_CRAWLS,
+            allowed_domains: vec![],
+            include_patterns: vec![],
+            exclude_patterns: vec![],
+            sitemap: false,
+        }
+    }
 }
 
 impl CrawlOptions {
@@ -208,12 +262,42 @@ impl CrawlOptions {
         }
         CrawlOptions::default()
     }
+
+    pub fn with_config(mut self, config: &CrawlerConfig) -> Result<Self> {
+        let compile = |patterns: &[String]| -> Result<Vec<Regex>> {
+            patterns
+                .iter()
+                .map(|v| Regex::new(v).with_context(|| format!("Invalid crawler regex '{v}'")))
+                .collect()
+        };
+        self.max_depth = config.max_depth;
+        self.max_pages = config.max_pages;
+        self.concurrency = config.concurrency.max(1);
+        self.allowed_domains = config.allowed_domains.clone();
+        self.include_patterns = compile(&config.include)?;
+        self.exclude_patterns = compile(&config.exclude)?;
+        self.sitemap = config.sitemap;
+        Ok(self)
+    }
+
+    /// Whether a discovered link is crawled: it has to stay below the start url or on an
+    /// allowed domain, and pass the exclude names and the include/exclude regexes.
+    fn should_follow(&self, scope: &Url, url: &Url) -> bool {
+        let in_scope = url.as_str().starts_with(scope.as_str())
+            || url
+                .host_str()
+                .is_some_and(|host| self.allowed_domains.iter().any(|v| v == host));
+        let is_match = |re: &Regex| re.is_match(url.as_str()).unwrap_or_default();
+        in_scope
+            && !should_exclude_link(url.path(), &self.exclude)
+            && (self.include_patterns.is_empty() || self.include_patterns.iter().any(is_match))
+            && !self.exclude_patterns.iter().any(is_match)
+    }
 }
 
 pub async fn crawl_website(start_url: &str, options: CrawlOptions) -> Result<Vec<Page>> {
     let start_url = Url::parse(start_url)?;
-    let mut paths = vec![start_url.path().to_string()];
-    let normalized_start_url = normalize_start_url(&start_url);
+    let scope = normalize_start_url(&start_url);
     if !options.no_log {
         println!(
             "Start crawling url={start_url} exclude={} extract={}",
@@ -222,60 +306,97 @@ pub async fn crawl_website(start_url: &str, options: CrawlOptions) -> Result<Vec
         );
     }
 
-    if let Ok(true) = GITHUB_REPO_RE.is_match(start_url.as_str()) {
-        paths = crawl_gh_tree(&start_url, &options.exclude)
+    let mut queue: Vec<(Url, usize)> = vec![];
+    let is_gh_tree = matches!(GITHUB_REPO_RE.is_match(start_url.as_str()), Ok(true));
+    if is_gh_tree {
+        let urls = crawl_gh_tree(&start_url, &options.exclude)
             .await
             .with_context(|| "Failed to craw github repo".to_string())?;
+        queue.extend(
+            urls.iter()
+                .filter_map(|v| Url::parse(v).ok())
+                .map(|v| (v, 0)),
+        );
+    } else if start_url.path().ends_with(".xml") {
+        let urls = crawl_sitemap(&start_url)
+            .await
+            .with_context(|| format!("Failed to read the sitemap {start_url}"))?;
+        queue.extend(urls.into_iter().map(|v| (v, 0)));
+    } else {
+        queue.push((start_url.clone(), 0));
+        if options.sitemap {
+            if let Ok(sitemap_url) = start_url.join("/sitemap.xml") {
+                match crawl_sitemap(&sitemap_url).await {
+                    Ok(urls) => queue.extend(urls.into_iter().map(|v| (v, 1))),
+                    Err(err) if !options.no_log => {
+                        println!("Skip sitemap {sitemap_url}: {err}");
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+    let mut seen = HashSet::new();
+    queue.retain(|(url, _)| {
+        (is_gh_tree || url == &start_url || options.should_follow(&scope, url))
+            && seen.insert(link_key(url))
+    });
+    if let Some(max_pages) = options.max_pages {
+        queue.truncate(max_pages);
     }
 
-    let semaphore = Arc::new(Semaphore::new(MAX_CRAWLS));
+    let concurrency = options.concurrency;
     let mut result_pages = Vec::new();
 
     let mut index = 0;
-    while index < paths.len() {
-        let batch = paths[index..std::cmp::min(index + MAX_CRAWLS, paths.len())].to_vec();
+    while index < queue.len() {
+        let batch = queue[index..std::cmp::min(index + concurrency, queue.len())].to_vec();
 
         let tasks: Vec<_> = batch
             .iter()
-            .map(|path| {
-                let options = options.clone();
-                let permit = semaphore.clone().acquire_owned(); // acquire a permit for concurrency control
-                let normalized_start_url = normalized_start_url.clone();
-                let path = path.clone();
-
+            .map(|(url, depth)| {
+                let options = &options;
                 async move {
-                    let _permit = permit.await?;
-                    let url = normalized_start_url
-                        .join(&path)
-                        .map_err(|_| anyhow!("Invalid crawl page at {}", path))?;
-                    let mut page = crawl_page(&normalized_start_url, &path, options)
+                    let ret = crawl_page(url, options)
                         .await
-                        .with_context(|| format!("Failed to crawl {}", url.as_str()))?;
-                    page.0 = url.as_str().to_string();
-                    Ok(page)
+                        .with_context(|| format!("Failed to crawl {}", url.as_str()));
+                    (url, *depth, ret)
                 }
             })
             .collect();
 
         let results = stream::iter(tasks)
-            .buffer_unordered(MAX_CRAWLS)
+            .buffer_unordered(concurrency)
             .collect::<Vec<_>>()
             .await;
 
-        let mut new_paths = Vec::new();
+        let mut new_urls = Vec::new();
 
-        for res in results {
+        for (url, depth, res) in results {
             match res {
-                Ok((path, text, links)) => {
+                Ok((text, links)) => {
                     if !options.no_log {
-                        println!("Crawled {path}");
+                        println!("Crawled {url}");
                     }
                     if !text.is_empty() {
-                        result_pages.push(Page { path, text });
+                        result_pages.push(Page {
+                            path: url.to_string(),
+                            text,
+                        });
+                    }
+                    if options.max_depth.is_some_and(|v| depth >= v) {
+                        continue;
                     }
                     for link in links {
-                        if !paths.iter().any(|p| match_link(p, &link)) {
-                            new_paths.push(link);
+                        // Links past the page limit would be dropped, so they aren't kept
+                        if options
+                            .max_pages
+                            .is_some_and(|v| queue.len() + new_urls.len() >= v)
+                        {
+                            break;
+                        }
+                        if options.should_follow(&scope, &link) && seen.insert(link_key(&link)) {
+                            new_urls.push((link, depth + 1));
                         }
                     }
                 }
@@ -288,7 +409,10 @@ pub async fn crawl_website(start_url: &str, options: CrawlOptions) -> Result<Vec
                 }
             }
         }
-        paths.extend(new_paths);
+        queue.extend(new_urls);
+        if let Some(max_pages) = options.max_pages {
+            queue.truncate(max_pages);
+        }
 
         index += batch.len();
     }
@@ -302,6 +426,72 @@ pub struct Page {
     pub text: String,
 }
 
+/// The page urls listed by a sitemap, following sitemap indexes.
+async fn crawl_sitemap(sitemap_url: &Url) -> Result<Vec<Url>> {
+    let client = match *CLIENT {
+        Ok(ref client) => client,
+        Err(ref err) => bail!("{err}"),
+    };
+    let mut pending = vec![sitemap_url.clone()];
+    let mut output = vec![];
+    let mut fetched = 0;
+    while let Some(url) = pending.pop() {
+        fetched += 1;
+        if fetched > MAX_SITEMAPS {
+            break;
+        }
+        let res = client
+            .get(url.as_str())
+            .header("User-Agent", USER_AGENT)
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            bail!("Invalid status: {}", res.status());
+        }
+        let (is_index, locations) = parse_sitemap(&res.text().await?);
+        let urls = locations.iter().filter_map(|v| Url::parse(v).ok());
+        if is_index {
+            pending.extend(urls);
+        } else {
+            output.extend(urls);
+        }
+    }
+    Ok(output)
+}
+
+/// Reads the `<loc>` entries of a sitemap, and whether it's a sitemap index.
+fn parse_sitemap(xml: &str) -> (bool, Vec<String>) {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut is_index = false;
+    let mut in_loc = false;
+    let mut locations = vec![];
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) => match e.local_name().as_ref() {
+                b"sitemapindex" => is_index = true,
+                b"loc" => in_loc = true,
+                _ => {}
+            },
+            Ok(Event::End(e)) if e.local_name().as_ref() == b"loc" => in_loc = false,
+            Ok(Event::Text(e)) if in_loc => {
+                if let Ok(text) = e.unescape() {
+                    let text = text.trim();
+                    if !text.is_empty() {
+                        locations.push(text.to_string());
+                    }
+                }
+            }
+            Ok(Event::CData(e)) if in_loc => {
+                let text = String::from_utf8_lossy(&e);
+                locations.push(text.trim().to_string());
+            }
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+    }
+    (is_index, locations)
+}
+
 async fn crawl_gh_tree(start_url: &Url, exclude: &[String]) -> Result<Vec<String>> {
     let path_segs: Vec<&str> = start_url.path().split('/').collect();
     if path_segs.len() < 4 {
@@ -368,44 +558,39 @@ async fn crawl_gh_tree(start_url: &Url, exclude: &[String]) -> Result<Vec<String
     Ok(paths)
 }
 
-async fn crawl_page(
-    start_url: &Url,
-    path: &str,
-    options: CrawlOptions,
-) -> Result<(String, String, Vec<String>)> {
+async fn crawl_page(url: &Url, options: &CrawlOptions) -> Result<(String, Vec<Url>)> {
     let client = match *CLIENT {
         Ok(ref client) => client,
         Err(ref err) => bail!("{err}"),
     };
-    let location = start_url.join(path)?;
     let response = client
-        .get(location.as_str())
+        .get(url.as_str())
         .header("User-Agent", USER_AGENT)
         .send()
         .await?;
+    if !response.status().is_success() {
+        bail!("Invalid status: {}", response.status());
+    }
     let body = response.text().await?;
 
-    if let Ok(true) = GITHUB_REPO_RE.is_match(start_url.as_str()) {
-        return Ok((path.to_string(), body, vec![]));
+    if url.host_str() == Some("raw.githubusercontent.com") {
+        return Ok((body, vec![]));
     }
 
-    let mut links = HashSet::new();
+    let mut links = vec![];
     let document = Html::parse_document(&body);
     let selector = Selector::parse("a").map_err(|err| anyhow!("Invalid link selector, {}", err))?;
 
     for element in document.select(&selector) {
-        if let Some(href) = element.value().attr("href") {
-            let href = Url::parse(href).ok().or_else(|| location.join(href).ok());
-            match href {
-                None => continue,
-                Some(href) => {
-                    if href.as_str().starts_with(location.as_str())
-                        && !should_exclude_link(href.path(), &options.exclude)
-                    {
-                        links.insert(href.path().to_string());
-                    }
-                }
-            }
+        let Some(href) = element.value().attr("href") else {
+            continue;
+        };
+        let Some(mut href) = Url::parse(href).ok().or_else(|| url.join(href).ok()) else {
+            continue;
+        };
+        if matches!(href.scheme(), "http" | "https") {
+            href.set_fragment(None);
+            links.push(href);
         }
     }
 
@@ -418,10 +603,10 @@ async fn crawl_page(
             .collect::<Vec<String>>()
             .join("\n\n")
     } else {
-        html_to_md(&body)
+        html_to_readable_md(&body)
     };
 
-    Ok((path.to_string(), text, links.into_iter().collect()))
+    Ok((text, links))
 }
 
 fn should_exclude_link(link: &str, exclude: &[String]) -> bool {
@@ -455,10 +640,101 @@ fn normalize_start_url(start_url: &Url) -> Url {
     start_url
 }
 
-fn match_link(path: &str, link: &str) -> bool {
-    path == link
-        || path
-            == link
-                .trim_end_matches("/index.html")
-                .trim_end_matches("/index.htm")
+/// Identifies a page regardless of its query, fragment, trailing slash or `index.html`.
+fn link_key(url: &Url) -> String {
+    let path = url
+        .path()
+        .trim_end_matches("index.html")
+        .trim_end_matches("index.htm")
+        .trim_end_matches('/');
+    format!("{}{path}", url.origin().ascii_serialization())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sitemap() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <url><loc>https://docs.example.com/guide/</loc><lastmod>2024-06-01</lastmod></url>
+  <url><loc> https://docs.example.com/api?a=1&amp;b=2 </loc></url>
+</urlset>"#;
+        assert_eq!(
+            parse_sitemap(xml),
+            (
+                false,
+                vec![
+                    "https://docs.example.com/guide/".to_string(),
+                    "https://docs.example.com/api?a=1&b=2".to_string()
+                ]
+            )
+        );
+        let xml = r#"<sitemapindex><sitemap><loc>https://example.com/s1.xml</loc></sitemap></sitemapindex>"#;
+        assert_eq!(
+            parse_sitemap(xml),
+            (true, vec!["https://example.com/s1.xml".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_link_key() {
+        let key = |v: &str| link_key(&Url::parse(v).unwrap());
+        let page = "https://example.com/docs/guide";
+        assert_eq!(key("https://example.com/docs/guide/"), page);
+        assert_eq!(key("https://example.com/docs/guide/index.html"), page);
+        assert_eq!(key("https://example.com/docs/guide?utm_source=x"), page);
+        assert_eq!(key("https://example.com/docs/guide#install"), page);
+        assert_ne!(key("http://example.com/docs/guide"), page);
+    }
+
+    #[tokio::test]
+    async fn test_crawl_page_follows_fragment_links() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4096];
+            let _ = stream.read(&mut buf).await.unwrap();
+            let body = r##"<a href="#top">Top</a><a href="guide#install">Install</a>"##;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        let url = Url::parse(&format!("http://{addr}/docs/")).unwrap();
+        let (_, links) = crawl_page(&url, &CrawlOptions::default()).await.unwrap();
+        let links: Vec<_> = links.iter().map(|v| v.path()).collect();
+        assert_eq!(links, ["/docs/", "/docs/guide"]);
+    }
+
+    #[test]
+    fn test_should_follow() {
+        let config = CrawlerConfig {
+            allowed_domains: vec!["api.example.com".into()],
+            exclude: vec![r"/v1/".into()],
+            ..Default::default()
+        };
+        let options = CrawlOptions::default().with_config(&config).unwrap();
+        let scope =
+            normalize_start_url(&Url::parse("https://example.com/docs/index.html").unwrap());
+        let follow = |v: &str| options.should_follow(&scope, &Url::parse(v).unwrap());
+        assert!(follow("https://example.com/docs/guide/intro"));
+        assert!(!follow("https://example.com/blog/post"));
+        assert!(follow("https://api.example.com/reference"));
+        assert!(!follow("https://example.com/docs/v1/old"));
+        assert!(!follow("https://other.com/docs/guide"));
+
+        let config = CrawlerConfig {
+            include: vec![r"/guide/".into()],
+            ..Default::default()
+        };
+        let options = CrawlOptions::default().with_config(&config).unwrap();
+        let follow = |v: &str| options.should_follow(&scope, &Url::parse(v).unwrap());
+        assert!(follow("https://example.com/docs/guide/a"));
+        assert!(!follow("https://example.com/docs/faq"));
+    }
 }
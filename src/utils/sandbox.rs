@@ -0,0 +1,360 @@
+use super::*;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::process::Stdio;
+use tokio::process::Command;
+
+const MAX_DIFF_SIZE: u64 = 64 * 1024;
+const MAX_OUTPUT_LINES: usize = 40;
+const MAX_SNAPSHOT_FILES: usize = 20_000;
+const MAX_SNAPSHOT_SIZE: u64 = 512 * 1024 * 1024;
+
+type Snapshot = BTreeMap<String, SnapshotEntry>;
+
+/// The throwaway container `-e` tries a generated command in before running it for real, set
+/// by `shell_sandbox`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShellSandboxConfig {
+    /// `docker` or `podman`, the first one on PATH when unset
+    pub runtime: Option<String>,
+    /// The image to run the command in, it needs `sh` and `cp`
+    pub image: String,
+    /// Let the command reach the network
+    pub network: bool,
+}
+
+impl Default for ShellSandboxConfig {
+    fn default() -> Self {
+        Self {
+            runtime: None,
+            image: "debian:stable-slim".into(),
+            network: false,
+        }
+    }
+}
+
+/// What a command did in the sandbox.
+#[derive(Debug, Clone)]
+pub struct SandboxReport {
+    pub code: i32,
+    pub stdout: String,
+    pub stderr: String,
+    pub changes: Vec<SandboxChange>,
+    /// Diffs of the text files among the changes
+    pub diffs: Vec<FileChange>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SandboxChange {
+    Added(String),
+    Removed(String),
+    Modified(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum SnapshotEntry {
+    Dir,
+    File(String),
+    Symlink(PathBuf),
+}
+
+/// Runs a shell command in a container holding a copy of `dir`, mounted read-only and copied
+/// into a scratch directory the command works in, and compares the copy with `dir` afterwards.
+pub async fn run_in_sandbox(
+    sandbox: &ShellSandboxConfig,
+    command: &str,
+    dir: &Path,
+) -> Result<SandboxReport> {
+    let runtime = match &sandbox.runtime {
+        Some(v) => v.clone(),
+        None => ["docker", "podman"]
+            .into_iter()
+            .find(|v| which::which(v).is_ok())
+            .map(|v| v.to_string())
+            .ok_or_else(|| {
+                anyhow!(
+                    "No container runtime for the sandbox, install docker or podman or set `shell_sandbox.runtime`"
+                )
+            })?,
+    };
+    let old_entries = {
+        let dir = dir.to_path_buf();
+        tokio::task::spawn_blocking(move || snapshot_dir(&dir)).await??
+    };
+    let work_dir = temp_file("-sandbox-", "");
+    fs::create_dir_all(&work_dir)
+        .with_context(|| format!("Failed to create '{}'", work_dir.display()))?;
+    let ret = match run_container(&runtime, sandbox, command, dir, &work_dir).await {
+        Ok((code, stdout, stderr)) => {
+            let (dir, new_dir) = (dir.to_path_buf(), work_dir.clone());
+            tokio::task::spawn_blocking(move || compare_dirs(&dir, &old_entries, &new_dir))
+                .await?
+                .map(|(changes, diffs)| SandboxReport {
+                    code,
+                    stdout,
+                    stderr,
+                    changes,
+                    diffs,
+                })
+        }
+        Err(err) => Err(err),
+    };
+    if let Err(err) = fs::remove_dir_all(&work_dir) {
+        debug!("Failed to remove '{}': {err}", work_dir.display());
+    }
+    ret
+}
+
+async fn run_container(
+    runtime: &str,
+    sandbox: &ShellSandboxConfig,
+    command: &str,
+    dir: &Path,
+    work_dir: &Path,
+) -> Result<(i32, String, String)> {
+    let mut args: Vec<String> = vec!["run".into(), "--rm".into()];
+    if !sandbox.network {
+        args.extend(["--network".into(), "none".into()]);
+    }
+    // Files the command creates in the scratch directory should be ours to clean up
+    if runtime.ends_with("podman") {
+        args.push("--userns=keep-id".into());
+    } else {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let metadata = fs::metadata(work_dir)?;
+            args.extend([
+                "--user".into(),
+                format!("{}:{}", metadata.uid(), metadata.gid()),
+            ]);
+        }
+    }
+    args.extend([
+        "-v".into(),
+        format!("{}:/src:ro", dir.display()),
+        "-v".into(),
+        format!("{}:/work", work_dir.display()),
+        "-w".into(),
+        "/work".into(),
+        sandbox.image.clone(),
+        "sh".into(),
+        "-c".into(),
+        r#"cp -a /src/. /work/ && sh -c "$1""#.into(),
+        "sh".into(),
+        command.into(),
+    ]);
+    debug!("{runtime} {args:?}");
+    let output = Command::new(runtime)
+        .args(&args)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .with_context(|| format!("Failed to run `{runtime}`"))?;
+    Ok((
+        output.status.code().unwrap_or_default(),
+        String::from_utf8_lossy(&output.stdout).to_string(),
+        String::from_utf8_lossy(&output.stderr).to_string(),
+    ))
+}
+
+/// The files added, removed or modified in `new` compared with `old`, snapshotted before as
+/// `old_entries`, with diffs of the changed text files.
+fn compare_dirs(
+    old: &Path,
+    old_entries: &Snapshot,
+    new: &Path,
+) -> Result<(Vec<SandboxChange>, Vec<FileChange>)> {
+    let new_entries = snapshot_dir(new)?;
+    let mut changes = vec![];
+    let mut diffs = vec![];
+    for (path, entry) in old_entries {
+        match new_entries.get(path) {
+            None => changes.push(SandboxChange::Removed(path.clone())),
+            Some(new_entry) if new_entry != entry && *entry != SnapshotEntry::Dir => {
+                changes.push(SandboxChange::Modified(path.clone()));
+                if let Some(new_text) = read_small_text(&new.join(path)) {
+                    let old_text = read_small_text(&old.join(path));
+                    diffs.push(FileChange {
+                        path: path.clone(),
+                        old: old_text,
+                        new: new_text,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    for (path, entry) in &new_entries {
+        if old_entries.contains_key(path) {
+            continue;
+        }
+        changes.push(SandboxChange::Added(path.clone()));
+        if let (SnapshotEntry::File(_), Some(new_text)) = (entry, read_small_text(&new.join(path)))
+        {
+            diffs.push(FileChange {
+                path: path.clone(),
+                old: None,
+                new: new_text,
+            });
+        }
+    }
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok((changes, diffs))
+}
+
+/// Hashes the files under `root`, refusing directories too large to copy into the sandbox.
+fn snapshot_dir(root: &Path) -> Result<Snapshot> {
+    let mut entries = BTreeMap::new();
+    let mut size = 0;
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let list =
+            fs::read_dir(&dir).with_context(|| format!("Failed to read '{}'", dir.display()))?;
+        for entry in list {
+            let path = entry?.path();
+            let Ok(relative) = path.strip_prefix(root) else {
+                continue;
+            };
+            let relative = relative.to_string_lossy().replace('\\', "/");
+            let metadata = fs::symlink_metadata(&path)?;
+            let entry = if metadata.is_symlink() {
+                SnapshotEntry::Symlink(fs::read_link(&path)?)
+            } else if metadata.is_dir() {
+                pending.push(path);
+                SnapshotEntry::Dir
+            } else {
+                size += metadata.len();
+                if entries.len() >= MAX_SNAPSHOT_FILES || size > MAX_SNAPSHOT_SIZE {
+                    bail!(
+                        "'{}' is too large for the sandbox (over {MAX_SNAPSHOT_FILES} files or {} MiB), run from a smaller directory",
+                        root.display(),
+                        MAX_SNAPSHOT_SIZE / 1024 / 1024
+                    );
+                }
+                let data = fs::read(&path)
+                    .with_context(|| format!("Failed to read '{}'", path.display()))?;
+                SnapshotEntry::File(hex_encode(&Sha256::digest(&data)))
+            };
+            entries.insert(relative, entry);
+        }
+    }
+    Ok(entries)
+}
+
+fn read_small_text(path: &Path) -> Option<String> {
+    let metadata = fs::symlink_metadata(path).ok()?;
+    if !metadata.is_file() || metadata.len() > MAX_DIFF_SIZE {
+        return None;
+    }
+    String::from_utf8(fs::read(path).ok()?).ok()
+}
+
+impl SandboxChange {
+    pub fn path(&self) -> &str {
+        match self {
+            SandboxChange::Added(v) | SandboxChange::Removed(v) | SandboxChange::Modified(v) => v,
+        }
+    }
+}
+
+impl SandboxReport {
+    pub fn render(&self) -> String {
+        let mut output = format!("Exited with code {} in the sandbox.\n", self.code);
+        for (name, text) in [("stdout", &self.stdout), ("stderr", &self.stderr)] {
+            if text.trim().is_empty() {
+                continue;
+            }
+            output.push_str(&format!("{name}:\n{}\n", truncate_lines(text.trim_end())));
+        }
+        if self.changes.is_empty() {
+            output.push_str("No files in the directory would change.\n");
+            return output;
+        }
+        output.push_str("Files that would change:\n");
+        for change in &self.changes {
+            let line = match change {
+                SandboxChange::Added(v) => {
+                    color_text(&format!("  + {v}"), nu_ansi_term::Color::Green)
+                }
+                SandboxChange::Removed(v) => {
+                    color_text(&format!("  - {v}"), nu_ansi_term::Color::Red)
+                }
+                SandboxChange::Modified(v) => {
+                    color_text(&format!("  ~ {v}"), nu_ansi_term::Color::Yellow)
+                }
+            };
+            output.push_str(&line);
+            output.push('\n');
+        }
+        if !self.diffs.is_empty() {
+            output.push('\n');
+            output.push_str(&render_file_changes(&self.diffs));
+        }
+        output
+    }
+}
+
+fn truncate_lines(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    if lines.len() <= MAX_OUTPUT_LINES {
+        return text.to_string();
+    }
+    format!(
+        "{}\n... {} more lines",
+        lines[..MAX_OUTPUT_LINES].join("\n"),
+        lines.len() - MAX_OUTPUT_LINES
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_dirs() {
+        let old = temp_file("-sandbox-old-", "");
+        let new = temp_file("-sandbox-new-", "");
+        for dir in [&old, &new] {
+            fs::create_dir_all(dir.join("src")).unwrap();
+            fs::write(dir.join("src/main.rs"), "fn main() {}\n").unwrap();
+        }
+        fs::write(old.join("notes.txt"), "old\n").unwrap();
+        fs::write(old.join("keep.txt"), "keep\n").unwrap();
+        fs::write(new.join("keep.txt"), "kept\n").unwrap();
+        fs::write(new.join("src/lib.rs"), "pub fn f() {}\n").unwrap();
+        let old_entries = snapshot_dir(&old).unwrap();
+        let (changes, diffs) = compare_dirs(&old, &old_entries, &new).unwrap();
+        assert_eq!(
+            changes,
+            [
+                SandboxChange::Modified("keep.txt".into()),
+                SandboxChange::Removed("notes.txt".into()),
+                SandboxChange::Added("src/lib.rs".into()),
+            ]
+        );
+        assert_eq!(
+            diffs,
+            [
+                FileChange {
+                    path: "keep.txt".into(),
+                    old: Some("keep\n".into()),
+                    new: "kept\n".into(),
+                },
+                FileChange {
+                    path: "src/lib.rs".into(),
+                    old: None,
+                    new: "pub fn f() {}\n".into(),
+                },
+            ]
+        );
+        fs::remove_dir_all(old).unwrap();
+        fs::remove_dir_all(new).unwrap();
+    }
+}
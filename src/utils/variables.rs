@@ -1,6 +1,6 @@
 use super::*;
 use fancy_regex::{Captures, Regex};
-use std::sync::LazyLock;
+use std::{borrow::Cow, sync::LazyLock};
 
 pub static RE_VARIABLE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\{\{(\w+)\}\}").unwrap());
 pub fn interpolate_variables(text: &mut String) {
@@ -30,3 +30,67 @@ pub fn interpolate_variables(text: &mut String) {
         })
         .to_string();
 }
+
+static RE_ENV_VARIABLE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\$(\$)?\{([A-Za-z_][A-Za-z0-9_]*)(?:(:?-)([^}]*))?\}").unwrap());
+
+/// Replaces `${VAR}` in the string values of a parsed YAML document with the value `lookup`
+/// finds, `${VAR:-default}` falls back when it is unset or empty and `${VAR-default}` only when
+/// unset. `$${VAR}` stays as `${VAR}`. A value that is just one reference takes the type of
+/// what it expands to, so `max_tokens: ${MAX_TOKENS}` gives a number. Fails on the first
+/// reference to an unset variable without a default.
+pub fn interpolate_env_variables(
+    value: &mut serde_yaml::Value,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<()> {
+    match value {
+        serde_yaml::Value::String(text) => {
+            let mut missing = None;
+            let output = RE_ENV_VARIABLE.replace_all(text, |caps: &Captures<'_>| {
+                if caps.get(1).is_some() {
+                    return caps[0][1..].to_string();
+                }
+                let default = caps.get(4).map(|v| v.as_str()).unwrap_or_default();
+                match (caps.get(3).map(|v| v.as_str()), lookup(&caps[2])) {
+                    (Some(":-"), Some(value)) if value.is_empty() => default.to_string(),
+                    (_, Some(value)) => value,
+                    (Some(_), None) => default.to_string(),
+                    (None, None) => {
+                        missing.get_or_insert_with(|| caps[2].to_string());
+                        caps[0].to_string()
+                    }
+                }
+            });
+            if let Some(name) = missing {
+                bail!("Environment variable '{name}' is not set, give it a default with `${{{name}:-<default>}}` or write `$${{{name}}}` to keep it as is");
+            }
+            let Cow::Owned(output) = output else {
+                return Ok(());
+            };
+            let whole = RE_ENV_VARIABLE.find(text).ok().flatten().is_some_and(|v| {
+                v.start() == 0 && v.end() == text.len() && !text.starts_with("$$")
+            });
+            *value = match serde_yaml::from_str(&output) {
+                Ok(parsed @ (serde_yaml::Value::Bool(_) | serde_yaml::Value::Number(_)))
+                    if whole =>
+                {
+                    parsed
+                }
+                _ => serde_yaml::Value::String(output),
+            };
+        }
+        serde_yaml::Value::Sequence(items) => {
+            for item in items {
+                interpolate_env_variables(item, lookup)?;
+            }
+        }
+        serde_yaml::Value::Mapping(map) => {
+            for item in map.values_mut() {
+                interpolate_env_variables(item, lookup)?;
+            }
+        }
+        serde_yaml::Value::Tagged(tagged) => interpolate_env_variables(&mut tagged.value, lookup)?,
+        _ => {}
+    }
+    Ok(())
+}